@@ -53,6 +53,7 @@
             packages.default = pkgs.rustPlatform.buildRustPackage {
               inherit name version;
               src = ./.;
+              MUSIUM_VERSION = version;
               cargoLock = {
                 lockFile = ./Cargo.lock;
                 outputHashes = {
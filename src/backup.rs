@@ -0,0 +1,424 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Export and import of user data as a single JSON file.
+//!
+//! The file holds the data that the user entered and that cannot be recovered
+//! by scanning the library: ratings, favorite artists, labels, notes,
+//! playlists, and saved queues. Tracks, albums, and artists are identified by their ids, which
+//! are derived from MusicBrainz ids, so the file can be imported on a different
+//! machine with a different library database. The listening history is not part
+//! of it, see `musium export` for that.
+
+use std::io::{self, Read, Write};
+
+use serde_json::{json, Value};
+
+use crate::database as db;
+use crate::error::{Error, Result};
+use crate::prim::{AlbumId, ArtistId, TrackId};
+use crate::user_data::{is_valid_label, MAX_NOTE_LEN};
+
+/// Version of the file format, bumped on incompatible changes.
+const VERSION: i64 = 1;
+
+/// Write all user data as JSON.
+pub fn export_user_data<W: Write>(tx: &mut db::Transaction, w: W) -> Result<()> {
+    let mut ratings = Vec::new();
+    for rating in db::iter_ratings(tx)? {
+        let rating = rating?;
+        ratings.push(json!({
+            "track_id": TrackId(rating.track_id as u64).to_string(),
+            "rating": rating.rating,
+            "created_at": rating.created_at,
+            "source": rating.source,
+        }));
+    }
+
+    let mut album_ratings = Vec::new();
+    for rating in db::iter_album_ratings(tx)? {
+        let rating = rating?;
+        album_ratings.push(json!({
+            "album_id": AlbumId(rating.album_id as u64).to_string(),
+            "rating": rating.rating,
+            "created_at": rating.created_at,
+            "source": rating.source,
+        }));
+    }
+
+    let mut favorite_artists = Vec::new();
+    for favorite in db::iter_favorite_artists(tx)? {
+        let favorite = favorite?;
+        favorite_artists.push(json!({
+            "artist_id": ArtistId(favorite.artist_id as u64).to_string(),
+            "created_at": favorite.created_at,
+        }));
+    }
+
+    let mut track_labels = Vec::new();
+    for label in db::iter_track_labels(tx)? {
+        let label = label?;
+        track_labels.push(json!({
+            "label": label.label,
+            "track_id": TrackId(label.track_id as u64).to_string(),
+            "created_at": label.created_at,
+        }));
+    }
+
+    let mut album_labels = Vec::new();
+    for label in db::iter_album_labels(tx)? {
+        let label = label?;
+        album_labels.push(json!({
+            "label": label.label,
+            "album_id": AlbumId(label.album_id as u64).to_string(),
+            "created_at": label.created_at,
+        }));
+    }
+
+    let mut track_notes = Vec::new();
+    for note in db::iter_track_notes(tx)? {
+        let note = note?;
+        track_notes.push(json!({
+            "track_id": TrackId(note.track_id as u64).to_string(),
+            "note": note.note,
+            "updated_at": note.updated_at,
+        }));
+    }
+
+    let mut album_notes = Vec::new();
+    for note in db::iter_album_notes(tx)? {
+        let note = note?;
+        album_notes.push(json!({
+            "album_id": AlbumId(note.album_id as u64).to_string(),
+            "note": note.note,
+            "updated_at": note.updated_at,
+        }));
+    }
+
+    let mut smart_playlists = Vec::new();
+    for playlist in db::iter_smart_playlists(tx)? {
+        let playlist = playlist?;
+        smart_playlists.push(json!({
+            "name": playlist.name,
+            "query": playlist.query,
+            "updated_at": playlist.updated_at,
+        }));
+    }
+
+    let mut playlists = Vec::new();
+    for playlist in db::iter_playlists(tx)?.collect::<db::Result<Vec<_>>>()? {
+        let mut tracks = Vec::new();
+        for entry in db::iter_playlist_tracks(tx, playlist.id)? {
+            tracks.push(TrackId(entry?.track_id as u64).to_string());
+        }
+        playlists.push(json!({
+            "name": playlist.name,
+            "created_at": playlist.created_at,
+            "updated_at": playlist.updated_at,
+            "tracks": tracks,
+        }));
+    }
+
+    let mut saved_queues = Vec::new();
+    for saved_queue in db::iter_saved_queues(tx)?.collect::<db::Result<Vec<_>>>()? {
+        let mut tracks = Vec::new();
+        for entry in db::iter_saved_queue_tracks(tx, saved_queue.id)? {
+            tracks.push(TrackId(entry?.track_id as u64).to_string());
+        }
+        saved_queues.push(json!({
+            "name": saved_queue.name,
+            "created_at": saved_queue.created_at,
+            "tracks": tracks,
+        }));
+    }
+
+    let data = json!({
+        "version": VERSION,
+        "ratings": ratings,
+        "album_ratings": album_ratings,
+        "favorite_artists": favorite_artists,
+        "track_labels": track_labels,
+        "album_labels": album_labels,
+        "track_notes": track_notes,
+        "album_notes": album_notes,
+        "smart_playlists": smart_playlists,
+        "playlists": playlists,
+        "saved_queues": saved_queues,
+    });
+
+    serde_json::to_writer_pretty(w, &data).map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// Counts of the entries that an import processed.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ImportStats {
+    /// Entries that were well-formed. They may have been left out when the
+    /// database already had the same or newer data.
+    pub n_read: u32,
+    /// Entries that were malformed, or held values we would not accept from
+    /// the user, such as an invalid label.
+    pub n_malformed: u32,
+}
+
+fn get_str<'a>(entry: &'a Value, key: &str) -> Option<&'a str> {
+    entry.get(key)?.as_str()
+}
+
+fn get_rating(entry: &Value) -> Option<i64> {
+    entry.get("rating")?.as_i64().filter(|r| (-1..=2).contains(r))
+}
+
+fn get_note(entry: &Value) -> Option<&str> {
+    get_str(entry, "note").filter(|n| !n.is_empty() && n.len() <= MAX_NOTE_LEN)
+}
+
+fn get_track_ids(entry: &Value) -> Option<Vec<TrackId>> {
+    entry
+        .get("tracks")?
+        .as_array()?
+        .iter()
+        .map(|t| TrackId::parse(t.as_str()?))
+        .collect()
+}
+
+/// Call `f` on every entry of the array `key`.
+///
+/// The function returns `None` when the entry is malformed. A missing array
+/// counts as empty, so files from newer versions that add sections stay
+/// importable.
+fn for_each_entry<F>(
+    tx: &mut db::Transaction,
+    data: &Value,
+    key: &'static str,
+    stats: &mut ImportStats,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(&mut db::Transaction, &Value) -> Option<db::Result<()>>,
+{
+    let entries = match data.get(key) {
+        None => return Ok(()),
+        Some(Value::Array(entries)) => entries,
+        Some(_) => return Err(Error::InvalidUserData(key)),
+    };
+    for entry in entries {
+        match f(tx, entry) {
+            Some(result) => {
+                result?;
+                stats.n_read += 1;
+            }
+            None => stats.n_malformed += 1,
+        }
+    }
+    Ok(())
+}
+
+/// Load user data that was exported with [`export_user_data`].
+///
+/// Importing merges the data into the database. Ratings and labels that we
+/// already have are left alone, as are manual playlists and saved queues that
+/// already exist. For
+/// notes and smart playlists the most recently edited version wins. Importing
+/// the same file twice is therefore harmless.
+pub fn import_user_data<R: Read>(tx: &mut db::Transaction, r: R) -> Result<ImportStats> {
+    let data: Value = serde_json::from_reader(r).map_err(io::Error::from)?;
+    if data.get("version").and_then(Value::as_i64) != Some(VERSION) {
+        return Err(Error::InvalidUserData("version"));
+    }
+
+    let mut stats = ImportStats::default();
+
+    for_each_entry(tx, &data, "ratings", &mut stats, |tx, entry| {
+        let track_id = TrackId::parse(get_str(entry, "track_id")?)?;
+        let rating = get_rating(entry)?;
+        let created_at = get_str(entry, "created_at")?;
+        let source = get_str(entry, "source")?;
+        Some(db::insert_rating_if_free(tx, track_id.0 as i64, created_at, rating, source))
+    })?;
+
+    for_each_entry(tx, &data, "album_ratings", &mut stats, |tx, entry| {
+        let album_id = AlbumId::parse(get_str(entry, "album_id")?)?;
+        let rating = get_rating(entry)?;
+        let created_at = get_str(entry, "created_at")?;
+        let source = get_str(entry, "source")?;
+        Some(db::insert_album_rating_if_free(tx, album_id.0 as i64, created_at, rating, source))
+    })?;
+
+    for_each_entry(tx, &data, "favorite_artists", &mut stats, |tx, entry| {
+        let artist_id = ArtistId::parse(get_str(entry, "artist_id")?)?;
+        let created_at = get_str(entry, "created_at")?;
+        Some(db::insert_favorite_artist(tx, artist_id.0 as i64, created_at))
+    })?;
+
+    for_each_entry(tx, &data, "track_labels", &mut stats, |tx, entry| {
+        let label = get_str(entry, "label").filter(|l| is_valid_label(l))?;
+        let track_id = TrackId::parse(get_str(entry, "track_id")?)?;
+        let created_at = get_str(entry, "created_at")?;
+        Some(db::insert_track_label(tx, label, track_id.0 as i64, created_at))
+    })?;
+
+    for_each_entry(tx, &data, "album_labels", &mut stats, |tx, entry| {
+        let label = get_str(entry, "label").filter(|l| is_valid_label(l))?;
+        let album_id = AlbumId::parse(get_str(entry, "album_id")?)?;
+        let created_at = get_str(entry, "created_at")?;
+        Some(db::insert_album_label(tx, label, album_id.0 as i64, created_at))
+    })?;
+
+    for_each_entry(tx, &data, "track_notes", &mut stats, |tx, entry| {
+        let track_id = TrackId::parse(get_str(entry, "track_id")?)?;
+        let note = get_note(entry)?;
+        let updated_at = get_str(entry, "updated_at")?;
+        Some(db::insert_track_note_if_newer(tx, track_id.0 as i64, note, updated_at))
+    })?;
+
+    for_each_entry(tx, &data, "album_notes", &mut stats, |tx, entry| {
+        let album_id = AlbumId::parse(get_str(entry, "album_id")?)?;
+        let note = get_note(entry)?;
+        let updated_at = get_str(entry, "updated_at")?;
+        Some(db::insert_album_note_if_newer(tx, album_id.0 as i64, note, updated_at))
+    })?;
+
+    for_each_entry(tx, &data, "smart_playlists", &mut stats, |tx, entry| {
+        let name = get_str(entry, "name").filter(|n| crate::playlist::is_valid_name(n))?;
+        let query = get_str(entry, "query")?;
+        let updated_at = get_str(entry, "updated_at")?;
+        Some(db::insert_smart_playlist_if_newer(tx, name, query, updated_at))
+    })?;
+
+    for_each_entry(tx, &data, "playlists", &mut stats, |tx, entry| {
+        let name = get_str(entry, "name").filter(|n| crate::playlist::is_valid_name(n))?;
+        let created_at = get_str(entry, "created_at")?;
+        let updated_at = get_str(entry, "updated_at")?;
+        let tracks = get_track_ids(entry)?;
+        Some(import_playlist(tx, name, created_at, updated_at, &tracks))
+    })?;
+
+    for_each_entry(tx, &data, "saved_queues", &mut stats, |tx, entry| {
+        let name = get_str(entry, "name").filter(|n| crate::playlist::is_valid_name(n))?;
+        let created_at = get_str(entry, "created_at")?;
+        let tracks = get_track_ids(entry)?;
+        Some(import_saved_queue(tx, name, created_at, &tracks))
+    })?;
+
+    Ok(stats)
+}
+
+fn import_playlist(
+    tx: &mut db::Transaction,
+    name: &str,
+    created_at: &str,
+    updated_at: &str,
+    tracks: &[TrackId],
+) -> db::Result<()> {
+    let exists = db::select_playlist_id(tx, name)?.is_some()
+        || db::select_smart_playlist_query(tx, name)?.is_some();
+    if exists {
+        return Ok(())
+    }
+    db::insert_playlist(tx, name, created_at)?;
+    let playlist_id = db::select_playlist_id(tx, name)?.expect("We just inserted it.");
+    for (position, track_id) in tracks.iter().enumerate() {
+        db::insert_playlist_track(tx, playlist_id, position as i64, track_id.0 as i64, updated_at)?;
+    }
+    db::update_playlist_updated_at(tx, playlist_id, updated_at)
+}
+
+fn import_saved_queue(
+    tx: &mut db::Transaction,
+    name: &str,
+    created_at: &str,
+    tracks: &[TrackId],
+) -> db::Result<()> {
+    if db::select_saved_queue_id(tx, name)?.is_some() {
+        return Ok(())
+    }
+    db::insert_saved_queue(tx, name, created_at)?;
+    let id = db::select_saved_queue_id(tx, name)?.expect("We just inserted it.");
+    for (position, track_id) in tracks.iter().enumerate() {
+        db::insert_saved_queue_track(tx, id, position as i64, track_id.0 as i64)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database as db;
+    use super::{export_user_data, import_user_data, ImportStats};
+
+    fn new_database(connection: &sqlite::Connection) -> db::Connection<'_> {
+        let mut db = db::Connection::new(connection);
+        let mut tx = db.begin().unwrap();
+        db::ensure_schema_exists(&mut tx).unwrap();
+        tx.commit().unwrap();
+        db
+    }
+
+    #[test]
+    fn import_user_data_round_trips_export() {
+        let connection = sqlite::open(":memory:").unwrap();
+        let mut db = new_database(&connection);
+        let mut tx = db.begin().unwrap();
+        let t0 = "2024-03-01T12:00:00.000Z";
+        let t1 = "2024-03-02T12:00:00.000Z";
+        db::insert_rating(&mut tx, 0x1f, t0, 2, "musium").unwrap();
+        db::insert_album_rating_if_free(&mut tx, 0x2a, t1, -1, "musium").unwrap();
+        db::insert_favorite_artist(&mut tx, 0x3b, t0).unwrap();
+        db::insert_track_label(&mut tx, "workout", 0x1f, t0).unwrap();
+        db::insert_album_label(&mut tx, "vinyl-owned", 0x2a, t1).unwrap();
+        db::insert_or_replace_track_note(&mut tx, 0x1f, "Skip the \"intro\".", t0).unwrap();
+        db::insert_or_replace_album_note(&mut tx, 0x2a, "Gift from Anna.", t1).unwrap();
+        db::insert_or_replace_smart_playlist(&mut tx, "jazz", "genre:jazz", t0).unwrap();
+        db::insert_playlist(&mut tx, "road-trip", t0).unwrap();
+        db::insert_playlist_track(&mut tx, 1, 0, 0x1f, t1).unwrap();
+        db::insert_playlist_track(&mut tx, 1, 1, 0x1e, t1).unwrap();
+        db::update_playlist_updated_at(&mut tx, 1, t1).unwrap();
+        db::insert_saved_queue(&mut tx, "party", t1).unwrap();
+        db::insert_saved_queue_track(&mut tx, 1, 0, 0x1e).unwrap();
+        let mut exported = Vec::new();
+        export_user_data(&mut tx, &mut exported).unwrap();
+        tx.commit().unwrap();
+
+        let connection = sqlite::open(":memory:").unwrap();
+        let mut db = new_database(&connection);
+        let mut tx = db.begin().unwrap();
+        let stats = import_user_data(&mut tx, &exported[..]).unwrap();
+        assert_eq!(stats, ImportStats { n_read: 10, n_malformed: 0 });
+
+        // Importing a second time should not duplicate anything.
+        import_user_data(&mut tx, &exported[..]).unwrap();
+        let mut reexported = Vec::new();
+        export_user_data(&mut tx, &mut reexported).unwrap();
+        tx.commit().unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&reexported).unwrap(),
+            std::str::from_utf8(&exported).unwrap(),
+        );
+    }
+
+    #[test]
+    fn import_user_data_skips_malformed_entries() {
+        let connection = sqlite::open(":memory:").unwrap();
+        let mut db = new_database(&connection);
+        let mut tx = db.begin().unwrap();
+        let data = r#"{
+            "version": 1,
+            "ratings": [
+                {"track_id": "1f", "rating": 3, "created_at": "2024-03-01T12:00:00Z", "source": "musium"},
+                {"track_id": "xyz", "rating": 1, "created_at": "2024-03-01T12:00:01Z", "source": "musium"}
+            ],
+            "track_labels": [
+                {"label": "Not Valid", "track_id": "1f", "created_at": "2024-03-01T12:00:00Z"},
+                {"label": "valid", "track_id": "1f", "created_at": "2024-03-01T12:00:00Z"}
+            ]
+        }"#;
+        let stats = import_user_data(&mut tx, data.as_bytes()).unwrap();
+        assert_eq!(stats, ImportStats { n_read: 1, n_malformed: 3 });
+        tx.commit().unwrap();
+    }
+}
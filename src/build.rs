@@ -11,7 +11,7 @@ use std::str::FromStr;
 
 use crate::database::{FileMetadata, Transaction, self as db};
 use crate::prim::{AlbumId, Album, AlbumArtistsRef, ArtistId, Artist, FileId, Instant, TrackId, Track, Date, Lufs, FilenameRef, StringRef};
-use crate::string_utils::{StringDeduper, normalize_words};
+use crate::string_utils::{StringDeduper, normalize_words, transliterate};
 use crate::word_index::WordMeta;
 
 pub enum BuildError {
@@ -83,6 +83,17 @@ impl IssueDetail {
             detail: self,
         }
     }
+
+    /// The album that the issue is about, for issues about an album.
+    pub fn album_id(&self) -> Option<AlbumId> {
+        match *self {
+            IssueDetail::AlbumTitleMismatch(id, ..) => Some(id),
+            IssueDetail::AlbumReleaseDateMismatch(id, ..) => Some(id),
+            IssueDetail::AlbumArtistMismatch(id, ..) => Some(id),
+            IssueDetail::AlbumLoudnessMismatch(id, ..) => Some(id),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -368,6 +379,13 @@ pub struct BuildMetaIndex {
     pub words_album: BTreeSet<(String, AlbumId, WordMeta)>,
     pub words_track: BTreeSet<(String, TrackId, WordMeta)>,
 
+    /// Whether `insert_full` fills the `words_*` sets.
+    ///
+    /// When the word indexes can be loaded from the database, there is no need
+    /// to tokenize all titles and names again. When they can be updated from a
+    /// previous index, `insert_album_words` tokenizes only the changed albums.
+    pub collect_words: bool,
+
     /// The maximum file id of all files in the album.
     ///
     /// This is used to invalidate any existing album loudness, in case a
@@ -397,6 +415,29 @@ pub struct FileTask {
   duration_seconds: u16,
 }
 
+/// Insert a word into a word index.
+///
+/// If the word is written in a non-Latin script, also insert its
+/// transliteration at the same position, so it can be found by typing Latin
+/// characters.
+fn insert_word<T: Copy + Ord>(
+    index: &mut BTreeSet<(String, T, WordMeta)>,
+    word: &str,
+    item: T,
+    meta: WordMeta,
+) {
+    if let Some(latin) = transliterate(word) {
+        let meta_latin = WordMeta::new(
+            latin.len(),
+            meta.total_len() as usize,
+            meta.index() as usize,
+            meta.rank() as u8,
+        );
+        index.insert((latin, item, meta_latin));
+    }
+    index.insert((word.to_string(), item, meta));
+}
+
 impl BuildMetaIndex {
     pub fn new() -> BuildMetaIndex {
         BuildMetaIndex {
@@ -411,6 +452,7 @@ impl BuildMetaIndex {
             words_artist: BTreeSet::new(),
             words_album: BTreeSet::new(),
             words_track: BTreeSet::new(),
+            collect_words: true,
             // Initially we set this to a sentinel value even though we don't
             // have a backing file yet; dereferencing this should not happen.
             current_filename: FilenameRef(0),
@@ -587,6 +629,7 @@ impl BuildMetaIndex {
                 "artists" => continue, // Currently unused.
                 "date" => tag_date = Some(value),
                 "discnumber" => tag_discnumber = Some(value),
+                "genre" => continue, // Only used for search filters.
                 "musicbrainz_albumartistid" => tag_musicbrainz_albumartistid.push(value),
                 "musicbrainz_albumid" => tag_musicbrainz_albumid = Some(value),
                 "musicbrainz_trackid" => continue, // Currently unused.
@@ -698,90 +741,23 @@ impl BuildMetaIndex {
 
         // Split the title, album, and album artist, on words, and add those to
         // the indexes, to allow finding the track/album/artist later by word.
-        let mut words = Vec::new();
-        let mut words_album_artist = Vec::new();
-        let mut all_words_album_artist = Vec::new();
-
-        {
-            // First we process all album artists individually.
-            for &(artist_id, album_artist_i, _) in &album_artists {
-                let album_artist_name = &self.strings.get(album_artist_i.0);
-                // Fill the indexes with the words that occur in the name.
-                // The artist is also present in the album and track indexes,
-                // but with rank 0, such that including the artist in the search
-                // terms would not make the intersection empty. For albums by
-                // multiple artists, we make an exception and bump the rank,
-                // such that you can still find the album by searching only for
-                // the name of one of the artists.
-                let k = if album_artists.len() == 1 { 0 } else { 1 };
-                words_album_artist.clear();
-                normalize_words(album_artist_name, &mut words_album_artist);
-                for (i, w) in words_album_artist.drain(..).enumerate() {
-                    let meta_rank_2 = WordMeta::new(w.len(), album_artist_name.len(), i, 2);
-                    let meta_rank_k = WordMeta::new(w.len(), album_artist_name.len(), i, k);
-                    let meta_rank_0 = WordMeta::new(w.len(), album_artist_name.len(), i, 0);
-                    self.words_artist.insert((w.clone(), artist_id, meta_rank_2));
-                    self.words_album.insert((w.clone(),  album_id,  meta_rank_k));
-                    self.words_track.insert((w.clone(),  track_id,  meta_rank_0));
-                    all_words_album_artist.push(w);
-                }
-            }
-
-            // If the album has multiple artists, then the album artist as
-            // credited may differ from the individual artists. For example,
-            // the album artist can be "John Leged and The Roots" and the
-            // individual album artists are "John Legend" and "The Roots". Then
-            // the word "and" occurs in the album artist, but not in the
-            // individual album artist names. We should insert the additional
-            // word into the album and track list indexes, so that adding that
-            // word to the search query does not exclude the album. And even
-            // when there is a single artist, sometimes I prefer to to merge
-            // multiple artists (e.g. "Robert Glasper" and "The Robert Glasper
-            // Experiment") under the same mbid, but we can preserve the name as
-            // credited on the album and make it searchable.
-            let album_artist_full = self.strings.get(album_artist);
-            normalize_words(album_artist_full, &mut words);
-            for (i, w) in words.iter().enumerate() {
-                if !all_words_album_artist.contains(w) {
-                    let meta_rank_0 = WordMeta::new(w.len(), album_artist_full.len(), i, 0);
-                    self.words_album.insert((w.clone(), album_id, meta_rank_0));
-                    self.words_track.insert((w.clone(), track_id, meta_rank_0));
-                }
-            }
-            // Add the words to the all collection only afterwards; if it
-            // occurs twice in the album artist then it should be in the
-            // index twice.
-            all_words_album_artist.append(&mut words);
-
-            let track_title = &self.strings.get(title);
-            let album_title = &self.strings.get(album);
-            let track_artist = &self.strings.get(track_artist);
-
-            normalize_words(album_title, &mut words);
-            for (i, w) in words.drain(..).enumerate() {
-                let meta_rank_2 = WordMeta::new(w.len(), album_title.len(), i, 2);
-                self.words_album.insert((w, album_id, meta_rank_2));
-            }
-            normalize_words(track_title, &mut words);
-            for (i, w) in words.drain(..).enumerate() {
-                let meta_rank_2 = WordMeta::new(w.len(), track_title.len(), i, 2);
-                self.words_track.insert((w, track_id, meta_rank_2));
-            }
-
-            // Extend the track index with the words that occur uniquely in the
-            // track artist, and not in the album artist. For example, feat.
-            // artists, but also the full artist on compilation albums. These
-            // get rank 1 to set them apart from album artist words (rank 0) and
-            // title words (rank 2).
-            normalize_words(track_artist, &mut words);
-            for (i, w) in words.drain(..).enumerate() {
-                if !words_album_artist.contains(&w) {
-                    let meta_rank_1 = WordMeta::new(w.len(), track_artist.len(), i, 1);
-                    self.words_track.insert((w, track_id, meta_rank_1));
-                }
-            }
+        if self.collect_words {
+            let album_artist_names: Vec<(ArtistId, StringRef)> = album_artists
+                .iter()
+                .map(|&(artist_id, name, _)| (artist_id, name))
+                .collect();
+            self.insert_track_words(
+                track_id,
+                &album_artist_names,
+                StringRef(album_artist),
+                StringRef(album),
+                StringRef(title),
+                StringRef(track_artist),
+            );
         }
 
+        let mut words = Vec::new();
+
         // Normalize the sort artist too. Generally, the only thing it is useful
         // for is to turn e.g. "The Who" into "Who, The". (Data from Musicbrainz
         // also puts the last name first for artists who use their real name,
@@ -799,11 +775,6 @@ impl BuildMetaIndex {
             words.clear();
         }
 
-        // TODO: It's inefficient to query the database once per track for the
-        // album loudness.
-        let track_loudness = db::select_track_loudness_lufs(tx, track_id.0 as i64)?.map(Lufs::from_f64);
-        let album_loudness = db::select_album_loudness_lufs(tx, album_id.0 as i64)?.map(Lufs::from_f64);
-
         // Insert all the album artists if no artist with the given id existed
         // yet. If one did exist, verify consistency. Also fill the vector of
         // album artists so the album can refer to this.
@@ -835,7 +806,8 @@ impl BuildMetaIndex {
             artist: StringRef(track_artist),
             duration_seconds: file.duration_seconds,
             filename: file.filename,
-            loudness: track_loudness,
+            // Filled in afterwards by `insert_loudness`.
+            loudness: None,
         };
         let mut album = Album {
             artist_ids: album_artists_ref,
@@ -843,7 +815,7 @@ impl BuildMetaIndex {
             title: StringRef(album),
             original_release_date: release_date,
             first_seen: file.mtime,
-            loudness: album_loudness,
+            loudness: None,
         };
 
         let mut add_album = true;
@@ -884,6 +856,153 @@ impl BuildMetaIndex {
         Ok(())
     }
 
+    /// Insert the words of the tracks in the selected albums into the `words_*` sets.
+    ///
+    /// When `collect_words` is false, `insert_full` leaves the sets empty, and
+    /// this can fill them afterwards for only the albums that need it. It takes
+    /// the artist names from the inserted artists rather than from the tags of
+    /// every file, those are the same unless we reported an issue.
+    pub fn insert_album_words<F: FnMut(AlbumId) -> bool>(&mut self, mut include: F) {
+        let tracks: Vec<(TrackId, StringRef, StringRef)> = self
+            .tracks
+            .iter()
+            .filter(|(track_id, _)| include(track_id.album_id()))
+            .map(|(&track_id, track)| (track_id, track.title, track.artist))
+            .collect();
+
+        let mut album_artists = Vec::new();
+        for (track_id, title, track_artist) in tracks {
+            let album = &self.albums[&track_id.album_id()];
+            let (album_artist, album_title) = (album.artist, album.title);
+            album_artists.clear();
+            album_artists.extend(
+                self.album_artists
+                    .get(album.artist_ids)
+                    .iter()
+                    .map(|artist_id| (*artist_id, self.artists[artist_id].name))
+            );
+            self.insert_track_words(track_id, &album_artists, album_artist, album_title, title, track_artist);
+        }
+    }
+
+    /// Insert the words of a track into the `words_*` sets.
+    ///
+    /// The album artists are the individual artists with their names, the
+    /// other arguments are the full album artist as credited, the album title,
+    /// the track title, and the track artist.
+    fn insert_track_words(
+        &mut self,
+        track_id: TrackId,
+        album_artists: &[(ArtistId, StringRef)],
+        album_artist: StringRef,
+        album: StringRef,
+        title: StringRef,
+        track_artist: StringRef,
+    ) {
+        let album_id = track_id.album_id();
+        let mut words = Vec::new();
+        let mut words_album_artist = Vec::new();
+        let mut all_words_album_artist = Vec::new();
+
+        // First we process all album artists individually.
+        for &(artist_id, album_artist_i) in album_artists {
+            let album_artist_name = &self.strings.get(album_artist_i.0);
+            // Fill the indexes with the words that occur in the name.
+            // The artist is also present in the album and track indexes,
+            // but with rank 0, such that including the artist in the search
+            // terms would not make the intersection empty. For albums by
+            // multiple artists, we make an exception and bump the rank,
+            // such that you can still find the album by searching only for
+            // the name of one of the artists.
+            let k = if album_artists.len() == 1 { 0 } else { 1 };
+            words_album_artist.clear();
+            normalize_words(album_artist_name, &mut words_album_artist);
+            for (i, w) in words_album_artist.drain(..).enumerate() {
+                let meta_rank_2 = WordMeta::new(w.len(), album_artist_name.len(), i, 2);
+                let meta_rank_k = WordMeta::new(w.len(), album_artist_name.len(), i, k);
+                let meta_rank_0 = WordMeta::new(w.len(), album_artist_name.len(), i, 0);
+                insert_word(&mut self.words_artist, &w, artist_id, meta_rank_2);
+                insert_word(&mut self.words_album,  &w, album_id,  meta_rank_k);
+                insert_word(&mut self.words_track,  &w, track_id,  meta_rank_0);
+                all_words_album_artist.push(w);
+            }
+        }
+
+        // If the album has multiple artists, then the album artist as
+        // credited may differ from the individual artists. For example,
+        // the album artist can be "John Leged and The Roots" and the
+        // individual album artists are "John Legend" and "The Roots". Then
+        // the word "and" occurs in the album artist, but not in the
+        // individual album artist names. We should insert the additional
+        // word into the album and track list indexes, so that adding that
+        // word to the search query does not exclude the album. And even
+        // when there is a single artist, sometimes I prefer to to merge
+        // multiple artists (e.g. "Robert Glasper" and "The Robert Glasper
+        // Experiment") under the same mbid, but we can preserve the name as
+        // credited on the album and make it searchable.
+        let album_artist_full = self.strings.get(album_artist.0);
+        normalize_words(album_artist_full, &mut words);
+        for (i, w) in words.iter().enumerate() {
+            if !all_words_album_artist.contains(w) {
+                let meta_rank_0 = WordMeta::new(w.len(), album_artist_full.len(), i, 0);
+                insert_word(&mut self.words_album, w, album_id, meta_rank_0);
+                insert_word(&mut self.words_track, w, track_id, meta_rank_0);
+            }
+        }
+        // Add the words to the all collection only afterwards; if it
+        // occurs twice in the album artist then it should be in the
+        // index twice.
+        all_words_album_artist.append(&mut words);
+
+        let track_title = &self.strings.get(title.0);
+        let album_title = &self.strings.get(album.0);
+        let track_artist = &self.strings.get(track_artist.0);
+
+        normalize_words(album_title, &mut words);
+        for (i, w) in words.drain(..).enumerate() {
+            let meta_rank_2 = WordMeta::new(w.len(), album_title.len(), i, 2);
+            insert_word(&mut self.words_album, &w, album_id, meta_rank_2);
+        }
+        normalize_words(track_title, &mut words);
+        for (i, w) in words.drain(..).enumerate() {
+            let meta_rank_2 = WordMeta::new(w.len(), track_title.len(), i, 2);
+            insert_word(&mut self.words_track, &w, track_id, meta_rank_2);
+        }
+
+        // Extend the track index with the words that occur uniquely in the
+        // track artist, and not in the album artist. For example, feat.
+        // artists, but also the full artist on compilation albums. These
+        // get rank 1 to set them apart from album artist words (rank 0) and
+        // title words (rank 2).
+        normalize_words(track_artist, &mut words);
+        for (i, w) in words.drain(..).enumerate() {
+            if !words_album_artist.contains(&w) {
+                let meta_rank_1 = WordMeta::new(w.len(), track_artist.len(), i, 1);
+                insert_word(&mut self.words_track, &w, track_id, meta_rank_1);
+            }
+        }
+    }
+
+    /// Load track and album loudness from the database.
+    ///
+    /// This must be called after inserting all files. Loading the tables in
+    /// one pass avoids two queries per file.
+    pub fn insert_loudness(&mut self, tx: &mut Transaction) -> db::Result<()> {
+        for row in db::iter_track_loudness(tx)? {
+            let (track_id, lufs) = row?;
+            if let Some(track) = self.tracks.get_mut(&TrackId(track_id as u64)) {
+                track.loudness = Some(Lufs::from_f64(lufs));
+            }
+        }
+        for row in db::iter_album_loudness(tx)? {
+            let (album_id, lufs) = row?;
+            if let Some(album) = self.albums.get_mut(&AlbumId(album_id as u64)) {
+                album.loudness = Some(Lufs::from_f64(lufs));
+            }
+        }
+        Ok(())
+    }
+
     /// Load the album's first listens from the `listens` table.
     pub fn insert_first_listens(&mut self, tx: &mut Transaction) -> db::Result<()> {
         // This does do a full table scan over all listens. But since I don't
@@ -0,0 +1,377 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Consistency checks for the database and the configuration.
+//!
+//! Besides SQLite's own integrity check, this finds rows that refer to tracks,
+//! albums, artists, or files that no longer exist. These are left behind when
+//! files are removed from the library, or when their MusicBrainz ids change.
+//!
+//! For the configuration, this checks that the paths in it exist, and that the
+//! external programs that Musium needs are present, so problems show up before
+//! starting the server rather than in the middle of a scan.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::database as db;
+use crate::database_utils;
+use crate::prim::{AlbumId, ArtistId, TrackId};
+use crate::tape::TapeFormat;
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// What a row refers to.
+#[derive(Copy, Clone, Debug)]
+enum Reference {
+    Track(TrackId),
+    Album(AlbumId),
+    Artist(ArtistId),
+}
+
+/// Rows in one table that refer to something that does not exist.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Dangling {
+    pub table: String,
+    /// What the rows refer to: "track", "album", "artist", or "file".
+    pub target: &'static str,
+    /// Number of distinct tracks, albums, or artists, or the number of rows
+    /// for files.
+    pub count: u64,
+    pub pruned: bool,
+}
+
+/// Run SQLite's integrity check, return the problems it found, if any.
+pub fn check_integrity(tx: &mut db::Transaction) -> db::Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for row in db::iter_integrity_check(tx)? {
+        let row = row?;
+        if row != "ok" {
+            problems.push(row);
+        }
+    }
+    Ok(problems)
+}
+
+/// Delete the rows in `table` for `reference`.
+///
+/// Returns false for tables that we do not prune: listens keep a copy of the
+/// metadata so they can be corrected later, and removing playlist entries
+/// would silently change the playlist.
+fn delete_reference(
+    tx: &mut db::Transaction,
+    table: &str,
+    reference: Reference,
+) -> db::Result<bool> {
+    match (table, reference) {
+        ("ratings", Reference::Track(id)) => db::delete_ratings_for_track(tx, id.0 as i64)?,
+        ("track_labels", Reference::Track(id)) => db::delete_track_labels_for_track(tx, id.0 as i64)?,
+        ("track_notes", Reference::Track(id)) => db::delete_track_note(tx, id.0 as i64)?,
+        ("track_loudness", Reference::Track(id)) => db::delete_track_loudness(tx, id.0 as i64)?,
+        ("waveforms", Reference::Track(id)) => db::delete_waveform(tx, id.0 as i64)?,
+        ("spectrograms", Reference::Track(id)) => db::delete_spectrogram(tx, id.0 as i64)?,
+        ("album_ratings", Reference::Album(id)) => db::delete_album_ratings_for_album(tx, id.0 as i64)?,
+        ("album_labels", Reference::Album(id)) => db::delete_album_labels_for_album(tx, id.0 as i64)?,
+        ("album_notes", Reference::Album(id)) => db::delete_album_note(tx, id.0 as i64)?,
+        ("album_loudness", Reference::Album(id)) => db::delete_album_loudness(tx, id.0 as i64)?,
+        ("thumbnails", Reference::Album(id)) => db::delete_thumbnail(tx, id.0 as i64)?,
+        ("favorite_artists", Reference::Artist(id)) => db::delete_favorite_artist(tx, id.0 as i64)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Find rows that refer to tracks, albums, or artists not in the index, or to
+/// files not in the database. If `prune` is set, delete the ones we can.
+pub fn find_dangling(
+    index: &MemoryMetaIndex,
+    tx: &mut db::Transaction,
+    prune: bool,
+) -> db::Result<Vec<Dangling>> {
+    // Count these before pruning the references below, which may delete some
+    // of the same rows.
+    let mut file_orphans = Vec::new();
+    for row in db::iter_file_orphan_counts(tx)? {
+        let (table, count) = row?;
+        if count > 0 {
+            file_orphans.push((table, count as u64));
+        }
+    }
+
+    let mut references = Vec::new();
+    for row in db::iter_track_references(tx)? {
+        let (table, id) = row?;
+        references.push((table, Reference::Track(TrackId(id as u64))));
+    }
+    for row in db::iter_album_references(tx)? {
+        let (table, id) = row?;
+        references.push((table, Reference::Album(AlbumId(id as u64))));
+    }
+    for row in db::iter_artist_references(tx)? {
+        let (table, id) = row?;
+        references.push((table, Reference::Artist(ArtistId(id as u64))));
+    }
+
+    // Key on the table, and then the target, so the report is sorted.
+    let mut dangling: BTreeMap<(String, &'static str), (u64, bool)> = BTreeMap::new();
+
+    for (table, reference) in references {
+        let (exists, target) = match reference {
+            Reference::Track(id) => (index.get_track(id).is_some(), "track"),
+            Reference::Album(id) => (index.get_album(id).is_some(), "album"),
+            Reference::Artist(id) => (index.get_artist(id).is_some(), "artist"),
+        };
+        if exists {
+            continue;
+        }
+        let pruned = prune && delete_reference(tx, &table, reference)?;
+        let entry = dangling.entry((table, target)).or_insert((0, pruned));
+        entry.0 += 1;
+    }
+
+    if prune && !file_orphans.is_empty() {
+        db::delete_file_orphans(tx)?;
+    }
+    for (table, count) in file_orphans {
+        dangling.insert((table, "file"), (count, prune));
+    }
+
+    let result = dangling
+        .into_iter()
+        .map(|((table, target), (count, pruned))| Dangling { table, target, count, pruned })
+        .collect();
+
+    Ok(result)
+}
+
+/// Return whether the current user can write to `path`.
+fn is_writable(path: &Path) -> bool {
+    let path_cstr = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    unsafe { libc::access(path_cstr.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Return whether `path` is a file that the owner can execute.
+fn is_executable(path: &Path) -> bool {
+    match path.metadata() {
+        Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o100 != 0,
+        Err(_) => false,
+    }
+}
+
+/// Find an executable in one of the directories in `PATH`.
+fn find_executable(name: &str) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+/// Check the library and database paths, and the exec hooks in the config.
+///
+/// Returns a description of every problem found, with what to do about it.
+pub fn check_config_paths(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if !config.library_path.is_dir() {
+        problems.push(format!(
+            "The library path {} is not a directory. Check library_path in the config.",
+            config.library_path.to_string_lossy(),
+        ));
+    } else if let Err(err) = std::fs::read_dir(&config.library_path) {
+        problems.push(format!(
+            "Cannot read the library path {}: {}. Check the permissions.",
+            config.library_path.to_string_lossy(),
+            err,
+        ));
+    }
+
+    let db_path = &config.db_path;
+    if db_path.exists() {
+        // Take the write lock and release it right away, this does not change
+        // the database.
+        let result = database_utils::connect_read_write(db_path, &config.db_pragmas)
+            .and_then(|connection| connection.execute("BEGIN IMMEDIATE; ROLLBACK;"));
+        if let Err(err) = result {
+            problems.push(format!(
+                "Cannot write to the database {}: {}. Check the permissions of the file and its directory.",
+                db_path.to_string_lossy(),
+                err,
+            ));
+        }
+    } else {
+        // The database does not exist yet, it will be created, but the
+        // directory must exist for that.
+        let dir = match db_path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+            Some(dir) => dir,
+            None => Path::new("/"),
+        };
+        if !dir.is_dir() {
+            problems.push(format!(
+                "The directory {} for the database does not exist. Create it, or change db_path in the config.",
+                dir.to_string_lossy(),
+            ));
+        } else if !is_writable(dir) {
+            problems.push(format!(
+                "Cannot create the database in {}, the directory is not writable. Check the permissions.",
+                dir.to_string_lossy(),
+            ));
+        }
+    }
+
+    let hooks = [
+        ("pre-playback", "exec_pre_playback_path", &config.exec_pre_playback_path),
+        ("post-idle", "exec_post_idle_path", &config.exec_post_idle_path),
+        ("now-playing", "exec_now_playing_path", &config.exec_now_playing_path),
+    ];
+    let output_dirs = [
+        ("covers", "thumb_covers_path", &config.thumb_covers_path),
+        ("tape", "tape_path", &config.tape_path),
+    ];
+    for (name, key, dir) in output_dirs.iter() {
+        if let Some(dir) = dir {
+            if !dir.is_dir() {
+                problems.push(format!(
+                    "The {} path {} is not a directory. Create it, or change {} in the config.",
+                    name,
+                    dir.to_string_lossy(),
+                    key,
+                ));
+            } else if !is_writable(dir) {
+                problems.push(format!(
+                    "Cannot write to the {} path {}, the directory is not writable. Check the permissions.",
+                    name,
+                    dir.to_string_lossy(),
+                ));
+            }
+        }
+    }
+
+    for (stage_name, key, hook) in hooks.iter() {
+        if let Some(path) = hook {
+            if !is_executable(path) {
+                problems.push(format!(
+                    "The {} program {} does not exist or is not executable. Check {} in the config.",
+                    stage_name,
+                    path.to_string_lossy(),
+                    key,
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Check that the external programs used to generate thumbnails are present.
+///
+/// When fetching covers from the Cover Art Archive is enabled, this includes
+/// `curl`, and when recording to flac, `flac`.
+pub fn check_tools(config: &Config) -> Vec<String> {
+    let mut tools = vec![
+        ("magick", "ImageMagick"),
+        ("cjpegli", "jpegli"),
+    ];
+    if config.thumb_covers_path.is_some() {
+        tools.push(("curl", "curl"));
+    }
+    let mut problems: Vec<String> = tools
+        .iter()
+        .filter(|(name, _)| find_executable(name).is_none())
+        .map(|(name, package)| format!(
+            "Could not find '{}' from {} on the PATH. Musium needs it to generate thumbnails during a scan.",
+            name, package,
+        ))
+        .collect();
+    if config.tape_path.is_some() && config.tape_format == TapeFormat::Flac && find_executable("flac").is_none() {
+        problems.push(
+            "Could not find 'flac' on the PATH. Musium needs it to compress recordings, because tape_format is flac.".to_string()
+        );
+    }
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+    use crate::database as db;
+    use crate::database_utils;
+    use crate::MemoryMetaIndex;
+    use super::{check_config_paths, check_integrity, find_dangling, Dangling};
+
+    #[test]
+    fn find_dangling_reports_and_prunes_missing_targets() {
+        let connection = sqlite::open(":memory:").unwrap();
+        let mut db = db::Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        db::ensure_schema_exists(&mut tx).unwrap();
+        database_utils::apply_migrations(&mut tx, database_utils::MIGRATIONS).unwrap();
+
+        // The library is empty, so everything below is dangling.
+        let (index, _builder) = MemoryMetaIndex::from_database(&mut tx, None).unwrap();
+        let now = "2024-03-01T12:00:00.000Z";
+        db::insert_or_replace_rating(&mut tx, 42, now, 2).unwrap();
+        db::insert_favorite_artist(&mut tx, 7, now).unwrap();
+        db::insert_playlist(&mut tx, "mix", now).unwrap();
+        let playlist_id = db::select_playlist_id(&mut tx, "mix").unwrap().unwrap();
+        db::insert_playlist_track(&mut tx, playlist_id, 0, 42, now).unwrap();
+        // The test connection does not enforce foreign keys, so this inserts.
+        db::insert_album_thumbnail(&mut tx, 9, 1, &[]).unwrap();
+
+        assert_eq!(check_integrity(&mut tx).unwrap(), Vec::<String>::new());
+
+        let dangling = |table: &str, target, count, pruned| Dangling {
+            table: table.to_string(),
+            target,
+            count,
+            pruned,
+        };
+        let expected = vec![
+            dangling("favorite_artists", "artist", 1, true),
+            dangling("playlist_tracks", "track", 1, false),
+            dangling("ratings", "track", 1, true),
+            dangling("thumbnails", "album", 1, true),
+            dangling("thumbnails", "file", 1, true),
+        ];
+        assert_eq!(find_dangling(&index, &mut tx, true).unwrap(), expected);
+
+        // After pruning, only the playlist entry is left.
+        let expected = vec![dangling("playlist_tracks", "track", 1, false)];
+        assert_eq!(find_dangling(&index, &mut tx, false).unwrap(), expected);
+    }
+
+    #[test]
+    fn check_config_paths_reports_missing_paths() {
+        let mut config = Config::parse([
+            "library_path = /nonexistent/music",
+            "db_path = /nonexistent/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+            "exec_post_idle_path = /nonexistent/bin/post-idle",
+        ]).unwrap();
+        let problems = check_config_paths(&config);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("/nonexistent/music"));
+        assert!(problems[1].contains("/nonexistent/musium"));
+        assert!(problems[2].starts_with("The post-idle program"));
+
+        // The database is created in the working directory here, which exists.
+        config.db_path = "db.sqlite3".into();
+        assert_eq!(check_config_paths(&config).len(), 2);
+
+        config.thumb_covers_path = Some("/nonexistent/covers".into());
+        let problems = check_config_paths(&config);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[1].contains("/nonexistent/covers"));
+    }
+}
@@ -0,0 +1,1185 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Command-line argument parsing.
+//!
+//! Every command takes the config file as its first positional argument,
+//! followed by the command's own arguments. Options start with `--`, they can
+//! go anywhere after the command, and each command declares which options it
+//! accepts and how many values they take.
+
+use std::collections::VecDeque;
+
+use log::LevelFilter;
+
+use crate::matcher::ImportSource;
+use crate::playcount::{Chart, CountOptions, ExportFormat, Scope};
+
+/// The version to print for `--version`, set by the Nix build.
+pub const VERSION: &str = match option_env!("MUSIUM_VERSION") {
+    Some(version) => version,
+    None => "unknown",
+};
+
+/// A command, its options, and its help text.
+struct CommandSpec {
+    name: &'static str,
+    /// Heading of the section in the help text.
+    heading: &'static str,
+    /// One-line description, for shell completions.
+    summary: &'static str,
+    /// Words that can follow the config file, for shell completions.
+    words: &'static [&'static str],
+    /// Options that the command accepts, with the number of values they take.
+    options: &'static [(&'static str, usize)],
+    /// Usage lines, without the leading `musium`.
+    usage: &'static [&'static str],
+    /// Help text, we indent it when printing.
+    description: &'static str,
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "init",
+        heading: "INIT",
+        summary: "Write a starter config and create the database",
+        words: &[],
+        options: &[],
+        usage: &["init musium.conf <library_path> [<db_path>]"],
+        description: "\
+Write a commented starter config to musium.conf, with the first audio device
+that has a volume control, and create the database. The database defaults to
+musium.sqlite3 next to the config. Does not overwrite an existing config.",
+    },
+    CommandSpec {
+        name: "scan",
+        heading: "SCAN",
+        summary: "Scan the library and generate thumbnails",
+        words: &[],
+        options: &[],
+        usage: &["scan musium.conf"],
+        description: "\
+Update the file database, generate album art thumbnails.",
+    },
+    CommandSpec {
+        name: "serve",
+        heading: "SERVE",
+        summary: "Start the server",
+        words: &[],
+        options: &[],
+        usage: &["serve musium.conf"],
+        description: "\
+Start the server. Requires running a scan first for serving an up-to-date
+library.",
+    },
+    CommandSpec {
+        name: "check",
+        heading: "CHECK",
+        summary: "Check the configuration",
+        words: &[],
+        options: &[],
+        usage: &["check musium.conf"],
+        description: "\
+Check the configuration: that the library path exists, the database can be
+written, the audio device and volume control exist, and the programs needed
+for thumbnails are installed. Exits with status 1 if there are problems.",
+    },
+    CommandSpec {
+        name: "info",
+        heading: "INFO",
+        summary: "Print what is known about a file, track, or album",
+        words: &[],
+        options: &[],
+        usage: &["info musium.conf <file>|<track_id>|<album_id>"],
+        description: "\
+Print everything that Musium knows about a file, track, or album: the tags,
+ids, loudness, waveform, thumbnail, rating, listens, and any issues found while
+building the library. Useful to find out why a track does not show up.",
+    },
+    CommandSpec {
+        name: "match",
+        heading: "MATCH",
+        summary: "Match listens to tracks",
+        words: &[],
+        options: &[],
+        usage: &["match musium.conf [--json]"],
+        description: "\
+Match listens (see process_listens.py) to tracks.",
+    },
+    CommandSpec {
+        name: "import",
+        heading: "IMPORT",
+        summary: "Import listens from Last.fm or Listenbrainz",
+        words: &["lastfm", "listenbrainz"],
+        options: &[],
+        usage: &[
+            "import musium.conf lastfm [<export.csv>]",
+            "import musium.conf listenbrainz",
+        ],
+        description: "\
+Match the listens that tools/scrobble.py imported from Last.fm or Listenbrainz
+to tracks, and add the matched listens to the listening history. For Last.fm,
+first load the listens from the given CSV export, if any, and afterwards rate
+the tracks loved on Last.fm as loved.",
+    },
+    CommandSpec {
+        name: "resolve",
+        heading: "RESOLVE",
+        summary: "Resolve imported listens that could not be matched",
+        words: &["lastfm", "listenbrainz"],
+        options: &[],
+        usage: &["resolve musium.conf lastfm|listenbrainz"],
+        description: "\
+For the imported listens that IMPORT could not match, show candidate tracks,
+and ask which one to pick. Picked listens get added to the listening history,
+skipped listens are not asked about again.",
+    },
+    CommandSpec {
+        name: "count",
+        heading: "COUNT",
+        summary: "Print listen count statistics",
+        words: &[],
+        options: &[
+            ("--export", 2),
+            ("--limit", 1),
+            ("--scope", 1),
+            ("--score", 1),
+            ("--timescale", 1),
+        ],
+        usage: &[
+            "count musium.conf [--score top|trending|falling|now] [--timescale <0-4>]",
+            "                  [--scope artists|albums|tracks] [--limit <n>] [--json]",
+            "count musium.conf --export csv|json <path>",
+        ],
+        description: "\
+Print listen count statistics. By default, print the top artists, albums, and
+tracks at every timescale, and the trending and falling ones. --score picks one
+of those charts, or 'now', for what we tend to listen to at this time of the
+day and week. --timescale picks a timescale for the top chart, from 0 (longest
+half-life) to 4 (shortest). --scope prints only artists, albums, or tracks, and
+--limit sets the length of the rankings. With --export, write the counts and
+ranks of all artists, albums, and tracks to the file at <path> instead.",
+    },
+    CommandSpec {
+        name: "remote",
+        heading: "REMOTE",
+        summary: "Control a running server",
+        words: &["status", "enqueue", "skip", "volume"],
+        options: &[],
+        usage: &[
+            "remote musium.conf [status]",
+            "remote musium.conf enqueue <query>",
+            "remote musium.conf skip",
+            "remote musium.conf volume [+<db>|-<db>]",
+        ],
+        description: "\
+Control the server that runs with the given config, through its API on the
+'listen' address. Status prints the current track, the queue length, and the
+volume. Enqueue adds the best matching track for the search query. Skip skips
+to the next track. Volume prints the volume, or changes it by the given number
+of decibels.",
+    },
+    CommandSpec {
+        name: "wrapped",
+        heading: "WRAPPED",
+        summary: "Print a report of the listens in a year",
+        words: &[],
+        options: &[],
+        usage: &["wrapped musium.conf <year>"],
+        description: "\
+Print a report of the listens in the given calendar year.",
+    },
+    CommandSpec {
+        name: "export",
+        heading: "EXPORT",
+        summary: "Export the listening history",
+        words: &["csv", "json"],
+        options: &[],
+        usage: &["export musium.conf csv|json <path>"],
+        description: "\
+Write the entire listening history to the file at <path>.",
+    },
+    CommandSpec {
+        name: "export-index",
+        heading: "EXPORT-INDEX",
+        summary: "Export the library to JSON",
+        words: &[],
+        options: &[],
+        usage: &["export-index musium.conf <path>"],
+        description: "\
+Write all artists, albums, and tracks in the library to the JSON file at
+<path>, for analysis with other tools. See docs/running.md for the format.",
+    },
+    CommandSpec {
+        name: "export-userdata",
+        heading: "EXPORT-USERDATA",
+        summary: "Export ratings and other user data to JSON",
+        words: &[],
+        options: &[],
+        usage: &["export-userdata musium.conf <path>"],
+        description: "\
+Write ratings, favorite artists, labels, notes, playlists, and saved queues
+to the JSON file at <path>, for moving them to a different library database.",
+    },
+    CommandSpec {
+        name: "import-userdata",
+        heading: "IMPORT-USERDATA",
+        summary: "Import user data from JSON",
+        words: &[],
+        options: &[],
+        usage: &["import-userdata musium.conf <path>"],
+        description: "\
+Merge user data that EXPORT-USERDATA wrote into the database. Restart the
+server afterwards to pick up the changes.",
+    },
+    CommandSpec {
+        name: "backup",
+        heading: "BACKUP",
+        summary: "Write a copy of the database",
+        words: &[],
+        options: &[],
+        usage: &["backup musium.conf <path>"],
+        description: "\
+Write a consistent copy of the database, including the listening history,
+user data, loudness analysis, and thumbnails, to a new file at <path>. This
+is safe to run while the server is running.",
+    },
+    CommandSpec {
+        name: "db",
+        heading: "DB",
+        summary: "Check and maintain the database",
+        words: &["check", "vacuum", "analyze", "checkpoint", "size"],
+        options: &[("--prune", 0)],
+        usage: &[
+            "db musium.conf check [--prune]",
+            "db musium.conf vacuum|analyze|checkpoint|size",
+        ],
+        description: "\
+Check runs SQLite's integrity check, and reports rows that refer to tracks,
+albums, or artists that are no longer in the library, or to files that no
+longer exist. With --prune, delete those rows, except for listens and playlist
+entries. Only prune when the full library is available, or user data for the
+missing part will be lost.
+
+Vacuum rebuilds the database file to reclaim the space of deleted rows, analyze
+updates the statistics that SQLite uses to plan queries, and checkpoint writes
+the write-ahead log into the database file. Size prints how many bytes every
+table takes up, including its indexes. Vacuum fails when the server is writing
+at the same time, so stop the server first.",
+    },
+    CommandSpec {
+        name: "playlist",
+        heading: "PLAYLIST",
+        summary: "List, save, or delete smart playlists",
+        words: &["list", "set", "delete"],
+        options: &[],
+        usage: &[
+            "playlist musium.conf [list]",
+            "playlist musium.conf set <name> <query>",
+            "playlist musium.conf delete <name>",
+        ],
+        description: "\
+List, save, or delete smart playlists. A smart playlist is a search query
+with filters, for example 'genre:jazz rating:>=1 played:>90', or an
+expression that starts with 'where', for example
+'where genre = \"jazz\" and last_played < now - 90d'. Names consist
+of lowercase letters, digits, and dashes. Listing includes the manual
+playlists, which are edited through the API.",
+    },
+    CommandSpec {
+        name: "completions",
+        heading: "COMPLETIONS",
+        summary: "Print a shell completion script",
+        words: &["bash", "zsh", "fish"],
+        options: &[],
+        usage: &["completions bash|zsh|fish"],
+        description: "\
+Print a script that completes commands, options, and their values for the given
+shell. For bash, source it from .bashrc, for zsh, save it as _musium in a
+directory in $fpath, and for fish, save it as ~/.config/fish/completions/musium.fish.",
+    },
+    // Matches listens with the matcher that import uses, not in the usage text.
+    CommandSpec {
+        name: "match2",
+        heading: "",
+        summary: "",
+        words: &[],
+        options: &[],
+        usage: &[],
+        description: "",
+    },
+];
+
+const OPTIONS_HELP: &str = "\
+OPTIONS
+
+  --help      Print this help. After a command, print the help for that command.
+  --version   Print the version.
+  --json      Print json instead of text, for the count and match commands.
+  --verbose   Print debug messages too, regardless of the log level in the config.
+  --quiet     Print only warnings and errors, regardless of the config.";
+
+/// Options that all commands accept, see [`split_args`].
+const GLOBAL_OPTIONS: &[&str] = &["--json", "--verbose", "-v", "--quiet", "-q"];
+
+/// Values for options that take a fixed set of values, for shell completions.
+const OPTION_VALUES: &[(&str, &[&str])] = &[
+    ("--export", &["csv", "json"]),
+    ("--scope", &["artists", "albums", "tracks"]),
+    ("--score", &["top", "trending", "falling", "now"]),
+    ("--timescale", &["0", "1", "2", "3", "4"]),
+];
+
+impl CommandSpec {
+    /// Whether the first positional argument is the config file.
+    fn takes_config(&self) -> bool {
+        self.name != "completions"
+    }
+
+    /// Whether the command accepts the global `--json` option.
+    fn supports_json(&self) -> bool {
+        matches!(self.name, "count" | "match")
+    }
+
+    /// Whether to show the command in the help and in completions.
+    fn is_documented(&self) -> bool {
+        !self.usage.is_empty()
+    }
+}
+
+fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+fn print_indented(text: &str) {
+    for line in text.lines() {
+        if line.is_empty() {
+            println!();
+        } else {
+            println!("  {}", line);
+        }
+    }
+}
+
+/// Print the usage and help text of all commands.
+pub fn print_usage() {
+    println!("Usage:\n");
+    for command in COMMANDS {
+        for usage in command.usage {
+            println!("  musium {}", usage);
+        }
+    }
+    println!("  musium --help\n  musium --version");
+    for command in COMMANDS.iter().filter(|c| c.is_documented()) {
+        println!("\n{}\n", command.heading);
+        print_indented(command.description);
+    }
+    println!("\n{}", OPTIONS_HELP);
+}
+
+/// Print the usage and help text of a single command.
+pub fn print_command_usage(name: &str) {
+    let command = match find_command(name) {
+        Some(command) if command.is_documented() => command,
+        _ => return print_usage(),
+    };
+    println!("Usage:\n");
+    for usage in command.usage {
+        println!("  musium {}", usage);
+    }
+    println!();
+    print_indented(command.description);
+}
+
+/// A shell to print a completion script for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn parse(shell: &str) -> Option<Shell> {
+        match shell {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// Format the options of the command, including `--help`, separated by spaces.
+fn format_options(command: &CommandSpec) -> String {
+    let mut options: Vec<&str> = command.options.iter().map(|(name, _)| *name).collect();
+    if command.supports_json() {
+        options.push("--json");
+    }
+    if command.takes_config() {
+        options.extend(["--verbose", "--quiet"]);
+    }
+    options.push("--help");
+    options.join(" ")
+}
+
+/// Return the completion script for bash.
+///
+/// For bash and zsh, we complete by position. With the config file as the
+/// first argument, the fixed words of a command are the third word after
+/// `musium`, and everything else completes file names.
+fn completions_bash() -> String {
+    let commands: Vec<&str> = COMMANDS.iter().filter(|c| c.is_documented()).map(|c| c.name).collect();
+    let mut out = String::new();
+    out.push_str("_musium() {\n");
+    out.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\"\n");
+    out.push_str("    local prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    out.push_str("    local cmd=\"${COMP_WORDS[1]}\"\n");
+    out.push_str("    if [ \"$COMP_CWORD\" -eq 1 ]; then\n");
+    out.push_str(&format!(
+        "        COMPREPLY=($(compgen -W \"{} --help --version\" -- \"$cur\"))\n",
+        commands.join(" "),
+    ));
+    out.push_str("        return\n    fi\n");
+
+    out.push_str("    case \"$prev\" in\n");
+    for (option, values) in OPTION_VALUES {
+        out.push_str(&format!(
+            "        {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;\n",
+            option, values.join(" "),
+        ));
+    }
+    out.push_str("    esac\n");
+
+    out.push_str("    local opts=\"--help\" words=\"\" words_cword=3\n");
+    out.push_str("    case \"$cmd\" in\n");
+    for command in COMMANDS.iter().filter(|c| c.is_documented()) {
+        out.push_str(&format!("        {}) opts=\"{}\"", command.name, format_options(command)));
+        if !command.words.is_empty() {
+            out.push_str(&format!(" words=\"{}\"", command.words.join(" ")));
+        }
+        if !command.takes_config() {
+            out.push_str(" words_cword=2");
+        }
+        out.push_str(" ;;\n");
+    }
+    out.push_str("    esac\n");
+
+    out.push_str("    if [[ \"$cur\" == -* ]]; then\n");
+    out.push_str("        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n");
+    out.push_str("    elif [ \"$COMP_CWORD\" -eq \"$words_cword\" ] && [ -n \"$words\" ]; then\n");
+    out.push_str("        COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))\n");
+    out.push_str("    else\n");
+    out.push_str("        COMPREPLY=($(compgen -f -- \"$cur\"))\n");
+    out.push_str("    fi\n");
+    out.push_str("}\n");
+    out.push_str("complete -o filenames -F _musium musium\n");
+    out
+}
+
+/// Return the completion script for zsh, see also [`completions_bash`].
+fn completions_zsh() -> String {
+    let mut out = String::new();
+    out.push_str("#compdef musium\n\n");
+    out.push_str("_musium() {\n");
+    out.push_str("    local cmd=${words[2]} prev=${words[CURRENT-1]}\n");
+    out.push_str("    if (( CURRENT == 2 )); then\n");
+    out.push_str("        local -a commands\n");
+    out.push_str("        commands=(\n");
+    for command in COMMANDS.iter().filter(|c| c.is_documented()) {
+        out.push_str(&format!("            '{}:{}'\n", command.name, command.summary));
+    }
+    out.push_str("        )\n");
+    out.push_str("        _describe command commands\n");
+    out.push_str("        compadd -- --help --version\n");
+    out.push_str("        return\n    fi\n");
+
+    out.push_str("    case $prev in\n");
+    for (option, values) in OPTION_VALUES {
+        out.push_str(&format!("        {}) compadd -- {}; return ;;\n", option, values.join(" ")));
+    }
+    out.push_str("    esac\n");
+
+    out.push_str("    local opts=--help cmd_words= words_current=4\n");
+    out.push_str("    case $cmd in\n");
+    for command in COMMANDS.iter().filter(|c| c.is_documented()) {
+        out.push_str(&format!("        {}) opts=\"{}\"", command.name, format_options(command)));
+        if !command.words.is_empty() {
+            out.push_str(&format!(" cmd_words=\"{}\"", command.words.join(" ")));
+        }
+        if !command.takes_config() {
+            out.push_str(" words_current=3");
+        }
+        out.push_str(" ;;\n");
+    }
+    out.push_str("    esac\n");
+
+    out.push_str("    if [[ $PREFIX == -* ]]; then\n");
+    out.push_str("        compadd -- ${=opts}\n");
+    out.push_str("    elif (( CURRENT == words_current )) && [[ -n $cmd_words ]]; then\n");
+    out.push_str("        compadd -- ${=cmd_words}\n");
+    out.push_str("    else\n");
+    out.push_str("        _files\n");
+    out.push_str("    fi\n");
+    out.push_str("}\n\n");
+    out.push_str("if [ \"$funcstack[1]\" = \"_musium\" ]; then\n");
+    out.push_str("    _musium \"$@\"\n");
+    out.push_str("else\n");
+    out.push_str("    compdef _musium musium\n");
+    out.push_str("fi\n");
+    out
+}
+
+/// Return the completion script for fish.
+fn completions_fish() -> String {
+    let mut out = String::new();
+    out.push_str("complete -c musium -f\n");
+    out.push_str("complete -c musium -n __fish_use_subcommand -l help -d 'Print the help'\n");
+    out.push_str("complete -c musium -n __fish_use_subcommand -l version -d 'Print the version'\n");
+    for command in COMMANDS.iter().filter(|c| c.is_documented()) {
+        let seen = format!("__fish_seen_subcommand_from {}", command.name);
+        out.push_str(&format!(
+            "complete -c musium -n __fish_use_subcommand -a {} -d '{}'\n",
+            command.name, command.summary,
+        ));
+        out.push_str(&format!("complete -c musium -n '{}' -l help -d 'Print the help'\n", seen));
+        if command.supports_json() {
+            out.push_str(&format!("complete -c musium -n '{}' -l json -d 'Print json'\n", seen));
+        }
+        if command.takes_config() {
+            out.push_str(&format!("complete -c musium -n '{}' -s v -l verbose -d 'Print debug messages'\n", seen));
+            out.push_str(&format!("complete -c musium -n '{}' -s q -l quiet -d 'Print only warnings and errors'\n", seen));
+        }
+        for (option, arity) in command.options {
+            let values = OPTION_VALUES.iter().find(|(o, _)| o == option).map(|(_, vs)| vs.join(" "));
+            out.push_str(&format!("complete -c musium -n '{}' -l {}", seen, &option[2..]));
+            match values {
+                Some(values) => out.push_str(&format!(" -x -a '{}'", values)),
+                None if *arity > 0 => out.push_str(" -r"),
+                None => {}
+            }
+            out.push('\n');
+        }
+        if !command.words.is_empty() {
+            out.push_str(&format!(
+                "complete -c musium -n '{}' -a '{}'\n",
+                seen, command.words.join(" "),
+            ));
+        }
+        if command.takes_config() {
+            out.push_str(&format!("complete -c musium -n '{}' -F\n", seen));
+        }
+    }
+    out
+}
+
+/// Print the completion script for the given shell.
+pub fn print_completions(shell: Shell) {
+    let script = match shell {
+        Shell::Bash => completions_bash(),
+        Shell::Zsh => completions_zsh(),
+        Shell::Fish => completions_fish(),
+    };
+    print!("{}", script);
+}
+
+/// A command to run, with its arguments.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    Init { library_path: String, db_path: Option<String> },
+    Scan,
+    Serve,
+    Check,
+    Info { target: String },
+    Match,
+    Match2,
+    Import { source: ImportSource, csv_path: Option<String> },
+    Resolve { source: ImportSource },
+    Count { export: Option<(ExportFormat, String)>, options: CountOptions },
+    Wrapped { year: i32 },
+    Export { format: ExportFormat, path: String },
+    ExportIndex { path: String },
+    ExportUserdata { path: String },
+    ImportUserdata { path: String },
+    Backup { path: String },
+    Db(DbCommand),
+    Playlist(PlaylistCommand),
+    Remote(RemoteCommand),
+}
+
+/// A subcommand of the `remote` command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RemoteCommand {
+    Status,
+    Enqueue { query: String },
+    Skip,
+    Volume { change_db: Option<i32> },
+}
+
+/// A subcommand of the `db` command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DbCommand {
+    Check { prune: bool },
+    Vacuum,
+    Analyze,
+    Checkpoint,
+    Size,
+}
+
+/// A subcommand of the `playlist` command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PlaylistCommand {
+    List,
+    Set { name: String, query: String },
+    Delete { name: String },
+}
+
+/// The result of parsing the command line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Cli {
+    /// Print the help, of a single command if one is given.
+    Help(Option<&'static str>),
+    Version,
+    Completions(Shell),
+    /// Run the command, print json instead of text when `json` is set.
+    ///
+    /// The log level, when set, overrides the one in the config.
+    Run { config_path: String, command: Command, json: bool, log_level: Option<LevelFilter> },
+}
+
+/// The arguments of a command, split into positional arguments and options.
+struct Args {
+    command: &'static str,
+    positional: VecDeque<String>,
+    options: Vec<(&'static str, Vec<String>)>,
+    /// Whether the global `--json` option was given.
+    json: bool,
+    /// The log level set by the global `--verbose` or `--quiet` options.
+    log_level: Option<LevelFilter>,
+}
+
+impl Args {
+    fn required(&mut self, what: &str) -> Result<String, String> {
+        self.positional.pop_front().ok_or_else(|| {
+            format!("Missing argument {} for command '{}'.", what, self.command)
+        })
+    }
+
+    fn optional(&mut self) -> Option<String> {
+        self.positional.pop_front()
+    }
+
+    /// Return the values of the option, if it was given.
+    fn option(&mut self, name: &str) -> Option<Vec<String>> {
+        let i = self.options.iter().position(|(n, _)| *n == name)?;
+        Some(self.options.remove(i).1)
+    }
+
+    /// Report an error if there are positional arguments left.
+    fn finish(self) -> Result<(), String> {
+        match self.positional.front() {
+            None => Ok(()),
+            Some(arg) => Err(format!(
+                "Unexpected argument '{}' for command '{}'.", arg, self.command,
+            )),
+        }
+    }
+}
+
+fn parse_export_format(format: &str) -> Result<ExportFormat, String> {
+    ExportFormat::parse(format).ok_or_else(|| {
+        format!("Invalid export format '{}', expected 'csv' or 'json'.", format)
+    })
+}
+
+fn parse_import_source(source: &str) -> Result<ImportSource, String> {
+    ImportSource::parse(source).ok_or_else(|| {
+        format!("Invalid source '{}', expected 'lastfm' or 'listenbrainz'.", source)
+    })
+}
+
+fn parse_count_options(args: &mut Args) -> Result<CountOptions, String> {
+    let mut options = CountOptions::default();
+
+    let timescale = match args.option("--timescale") {
+        None => None,
+        Some(values) => match values[0].parse() {
+            Ok(t) if t < 5 => Some(t),
+            _ => return Err(format!("Invalid timescale '{}', must be in 0..4.", values[0])),
+        },
+    };
+    let score = args.option("--score").map(|values| values[0].clone());
+    options.charts = match (score.as_deref(), timescale) {
+        (None, None) => options.charts,
+        (None | Some("top"), Some(t)) => vec![Chart::Top(t)],
+        (Some("top"), None) => (0..5).map(Chart::Top).collect(),
+        (Some(_), Some(..)) => {
+            return Err("Option '--timescale' only applies to '--score top'.".to_string());
+        }
+        (Some("trending"), None) => vec![Chart::Trending],
+        (Some("falling"), None) => vec![Chart::Falling],
+        (Some("now"), None) => vec![Chart::Now],
+        (Some(score), None) => return Err(format!(
+            "Invalid score '{}', expected 'top', 'trending', 'falling', or 'now'.", score,
+        )),
+    };
+
+    if let Some(values) = args.option("--limit") {
+        match values[0].parse() {
+            Ok(n) => options.limit = Some(n),
+            Err(..) => return Err(format!("Invalid limit '{}', must be a number.", values[0])),
+        }
+    }
+
+    if let Some(values) = args.option("--scope") {
+        match Scope::parse(&values[0]) {
+            Some(scope) => options.scope = Some(scope),
+            None => return Err(format!(
+                "Invalid scope '{}', expected 'artists', 'albums', or 'tracks'.", values[0],
+            )),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Split the arguments after the command name into positional ones and options.
+///
+/// Returns `None` when the arguments ask for help.
+fn split_args(
+    spec: &'static CommandSpec,
+    args: Vec<String>,
+) -> Result<Option<Args>, String> {
+    let mut result = Args {
+        command: spec.name,
+        positional: VecDeque::new(),
+        options: Vec::new(),
+        json: false,
+        log_level: None,
+    };
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            result.positional.extend(args);
+            break;
+        }
+        if arg == "--help" || arg == "-h" {
+            return Ok(None);
+        }
+        if arg == "--json" {
+            result.json = true;
+            continue;
+        }
+        let log_level = match &arg[..] {
+            "--verbose" | "-v" => Some(LevelFilter::Debug),
+            "--quiet" | "-q" => Some(LevelFilter::Warn),
+            _ => None,
+        };
+        if let Some(level) = log_level {
+            if matches!(result.log_level, Some(other) if other != level) {
+                return Err("Options '--verbose' and '--quiet' cannot be combined.".to_string());
+            }
+            result.log_level = Some(level);
+            continue;
+        }
+        // Negative numbers, such as the volume change "-2", are not options.
+        if !arg.starts_with('-') || arg == "-" || arg[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            result.positional.push_back(arg);
+            continue;
+        }
+
+        let (name, arity) = match spec.options.iter().find(|(name, _)| *name == arg) {
+            Some(&option) => option,
+            None => return Err(format!(
+                "Unknown option '{}' for command '{}'.", arg, spec.name,
+            )),
+        };
+        if result.options.iter().any(|(n, _)| *n == name) {
+            return Err(format!("Option '{}' given more than once.", name));
+        }
+        let values: Vec<String> = args.by_ref().take(arity).collect();
+        if values.len() < arity {
+            return Err(format!(
+                "Option '{}' expects {} value{}.",
+                name, arity, if arity == 1 { "" } else { "s" },
+            ));
+        }
+        result.options.push((name, values));
+    }
+
+    Ok(Some(result))
+}
+
+/// Parse the command line, excluding the program name.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Cli, String> {
+    let mut args = args.into_iter();
+
+    // Global options can go before the command as well, we handle them
+    // together with the ones after it.
+    let mut global_args = Vec::new();
+    let command_name = loop {
+        match args.next() {
+            None => return Err("Missing command.".to_string()),
+            Some(arg) if GLOBAL_OPTIONS.contains(&&arg[..]) => global_args.push(arg),
+            Some(arg) => break arg,
+        }
+    };
+    let spec = match &command_name[..] {
+        "--help" | "-h" | "help" => {
+            let topic = args.next().and_then(|name| find_command(&name)).map(|c| c.name);
+            return Ok(Cli::Help(topic));
+        }
+        "--version" => return Ok(Cli::Version),
+        name if name.starts_with('-') => return Err(format!("Unknown option '{}'.", name)),
+        name => match find_command(name) {
+            Some(spec) => spec,
+            None => return Err(format!("Unknown command '{}'.", name)),
+        },
+    };
+
+    global_args.extend(args);
+    let mut args = match split_args(spec, global_args)? {
+        Some(args) => args,
+        None => return Ok(Cli::Help(Some(spec.name))),
+    };
+    let json = args.json;
+    if json && !spec.supports_json() {
+        return Err(format!("Option '--json' is not supported by command '{}'.", spec.name));
+    }
+
+    if !spec.takes_config() {
+        let shell = args.required("bash|zsh|fish")?;
+        let shell = match Shell::parse(&shell) {
+            Some(shell) => shell,
+            None => return Err(format!("Unknown shell '{}', expected bash, zsh, or fish.", shell)),
+        };
+        args.finish()?;
+        return Ok(Cli::Completions(shell));
+    }
+
+    let config_path = args.required("musium.conf")?;
+
+    let command = match spec.name {
+        "init" => Command::Init {
+            library_path: args.required("<library_path>")?,
+            db_path: args.optional(),
+        },
+        "scan" => Command::Scan,
+        "serve" => Command::Serve,
+        "check" => Command::Check,
+        "info" => Command::Info { target: args.required("<file>|<track_id>|<album_id>")? },
+        "match" => Command::Match,
+        "match2" => Command::Match2,
+        "import" => {
+            let source = parse_import_source(&args.required("lastfm|listenbrainz")?)?;
+            let csv_path = match source {
+                ImportSource::Lastfm => args.optional(),
+                ImportSource::Listenbrainz => None,
+            };
+            Command::Import { source, csv_path }
+        }
+        "resolve" => Command::Resolve {
+            source: parse_import_source(&args.required("lastfm|listenbrainz")?)?,
+        },
+        "count" => {
+            let export = match args.option("--export") {
+                None => None,
+                Some(values) => Some((parse_export_format(&values[0])?, values[1].clone())),
+            };
+            let options = parse_count_options(&mut args)?;
+            if export.is_some() && (json || options != CountOptions::default()) {
+                return Err("Option '--export' cannot be combined with other options.".to_string());
+            }
+            Command::Count { export, options }
+        }
+        "wrapped" => {
+            let year = args.required("<year>")?;
+            match year.parse() {
+                Ok(year) => Command::Wrapped { year },
+                Err(..) => return Err(format!("Invalid year '{}'.", year)),
+            }
+        }
+        "export" => Command::Export {
+            format: parse_export_format(&args.required("csv|json")?)?,
+            path: args.required("<path>")?,
+        },
+        "export-index" => Command::ExportIndex { path: args.required("<path>")? },
+        "export-userdata" => Command::ExportUserdata { path: args.required("<path>")? },
+        "import-userdata" => Command::ImportUserdata { path: args.required("<path>")? },
+        "backup" => Command::Backup { path: args.required("<path>")? },
+        "db" => {
+            let sub = args.required("check|vacuum|analyze|checkpoint|size")?;
+            let prune = args.option("--prune").is_some();
+            if prune && sub != "check" {
+                return Err("Option '--prune' only applies to 'db check'.".to_string());
+            }
+            Command::Db(match &sub[..] {
+                "check" => DbCommand::Check { prune },
+                "vacuum" => DbCommand::Vacuum,
+                "analyze" => DbCommand::Analyze,
+                "checkpoint" => DbCommand::Checkpoint,
+                "size" => DbCommand::Size,
+                _ => return Err(format!("Unknown subcommand '{}' for command 'db'.", sub)),
+            })
+        }
+        "playlist" => Command::Playlist(match args.optional().as_deref() {
+            None | Some("list") => PlaylistCommand::List,
+            Some("set") => PlaylistCommand::Set {
+                name: args.required("<name>")?,
+                query: args.required("<query>")?,
+            },
+            Some("delete") => PlaylistCommand::Delete { name: args.required("<name>")? },
+            Some(sub) => return Err(format!("Unknown subcommand '{}' for command 'playlist'.", sub)),
+        }),
+        "remote" => Command::Remote(match args.optional().as_deref() {
+            None | Some("status") => RemoteCommand::Status,
+            Some("enqueue") => {
+                let words: Vec<String> = args.positional.drain(..).collect();
+                if words.is_empty() {
+                    return Err("Missing argument <query> for command 'remote'.".to_string());
+                }
+                RemoteCommand::Enqueue { query: words.join(" ") }
+            }
+            Some("skip") => RemoteCommand::Skip,
+            Some("volume") => match args.optional() {
+                None => RemoteCommand::Volume { change_db: None },
+                Some(change) => match change.parse() {
+                    Ok(change_db) => RemoteCommand::Volume { change_db: Some(change_db) },
+                    Err(..) => return Err(format!(
+                        "Invalid volume change '{}', expected for example +2 or -2.", change,
+                    )),
+                },
+            },
+            Some(sub) => return Err(format!("Unknown subcommand '{}' for command 'remote'.", sub)),
+        }),
+        _ => unreachable!("All commands in COMMANDS are handled."),
+    };
+
+    let log_level = args.log_level;
+    args.finish()?;
+
+    Ok(Cli::Run { config_path, command, json, log_level })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{completions_bash, completions_fish, completions_zsh, parse};
+    use super::{Cli, Command, DbCommand, LevelFilter, PlaylistCommand, RemoteCommand, Shell};
+    use crate::matcher::ImportSource;
+    use crate::playcount::{Chart, CountOptions, ExportFormat, Scope};
+
+    fn parse_str(args: &[&str]) -> Result<Cli, String> {
+        parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn run(command: Command) -> Result<Cli, String> {
+        Ok(Cli::Run { config_path: "musium.conf".to_string(), command, json: false, log_level: None })
+    }
+
+    fn run_json(command: Command) -> Result<Cli, String> {
+        Ok(Cli::Run { config_path: "musium.conf".to_string(), command, json: true, log_level: None })
+    }
+
+    #[test]
+    fn parse_parses_commands() {
+        assert_eq!(parse_str(&["serve", "musium.conf"]), run(Command::Serve));
+        assert_eq!(
+            parse_str(&["import", "musium.conf", "lastfm", "export.csv"]),
+            run(Command::Import {
+                source: ImportSource::Lastfm,
+                csv_path: Some("export.csv".to_string()),
+            }),
+        );
+        assert_eq!(
+            parse_str(&["wrapped", "musium.conf", "2023"]),
+            run(Command::Wrapped { year: 2023 }),
+        );
+        assert_eq!(
+            parse_str(&["playlist", "musium.conf", "set", "jazz", "genre:jazz"]),
+            run(Command::Playlist(PlaylistCommand::Set {
+                name: "jazz".to_string(),
+                query: "genre:jazz".to_string(),
+            })),
+        );
+    }
+
+    #[test]
+    fn parse_parses_options_anywhere_after_the_command() {
+        let expected = run(Command::Count {
+            export: Some((ExportFormat::Json, "counts.json".to_string())),
+            options: CountOptions::default(),
+        });
+        assert_eq!(parse_str(&["count", "musium.conf", "--export", "json", "counts.json"]), expected);
+        assert_eq!(parse_str(&["count", "--export", "json", "counts.json", "musium.conf"]), expected);
+        assert_eq!(
+            parse_str(&["count", "musium.conf"]),
+            run(Command::Count { export: None, options: CountOptions::default() }),
+        );
+
+        let db_check = |prune| run(Command::Db(DbCommand::Check { prune }));
+        assert_eq!(parse_str(&["db", "musium.conf", "check", "--prune"]), db_check(true));
+        assert_eq!(parse_str(&["db", "--prune", "musium.conf", "check"]), db_check(true));
+        assert_eq!(parse_str(&["db", "musium.conf", "check"]), db_check(false));
+        assert_eq!(parse_str(&["db", "musium.conf", "size"]), run(Command::Db(DbCommand::Size)));
+        assert_eq!(
+            parse_str(&["db", "musium.conf", "vacuum", "--prune"]),
+            Err("Option '--prune' only applies to 'db check'.".to_string()),
+        );
+    }
+
+    #[test]
+    fn parse_parses_global_json_option() {
+        assert_eq!(parse_str(&["match", "musium.conf", "--json"]), run_json(Command::Match));
+        assert_eq!(parse_str(&["--json", "match", "musium.conf"]), run_json(Command::Match));
+        assert_eq!(
+            parse_str(&["count", "--json", "musium.conf", "--limit", "3"]),
+            run_json(Command::Count {
+                export: None,
+                options: CountOptions { limit: Some(3), ..CountOptions::default() },
+            }),
+        );
+        assert_eq!(
+            parse_str(&["--json", "serve", "musium.conf"]),
+            Err("Option '--json' is not supported by command 'serve'.".to_string()),
+        );
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--export", "csv", "counts.csv", "--json"]),
+            Err("Option '--export' cannot be combined with other options.".to_string()),
+        );
+    }
+
+    #[test]
+    fn parse_parses_global_log_level_options() {
+        let serve = |log_level| Ok(Cli::Run {
+            config_path: "musium.conf".to_string(),
+            command: Command::Serve,
+            json: false,
+            log_level,
+        });
+        assert_eq!(parse_str(&["serve", "musium.conf", "--verbose"]), serve(Some(LevelFilter::Debug)));
+        assert_eq!(parse_str(&["-q", "serve", "musium.conf"]), serve(Some(LevelFilter::Warn)));
+        assert_eq!(
+            parse_str(&["--verbose", "serve", "musium.conf", "-q"]),
+            Err("Options '--verbose' and '--quiet' cannot be combined.".to_string()),
+        );
+    }
+
+    #[test]
+    fn parse_parses_count_options() {
+        let count = |charts, limit, scope| run(Command::Count {
+            export: None,
+            options: CountOptions { charts, limit, scope },
+        });
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--timescale", "2", "--scope", "albums"]),
+            count(vec![Chart::Top(2)], None, Some(Scope::Albums)),
+        );
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--score", "top"]),
+            count((0..5).map(Chart::Top).collect(), None, None),
+        );
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--score", "now", "--limit", "10"]),
+            count(vec![Chart::Now], Some(10), None),
+        );
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--score", "trending", "--timescale", "1"]),
+            Err("Option '--timescale' only applies to '--score top'.".to_string()),
+        );
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--timescale", "5"]),
+            Err("Invalid timescale '5', must be in 0..4.".to_string()),
+        );
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--export", "csv", "counts.csv", "--limit", "5"]),
+            Err("Option '--export' cannot be combined with other options.".to_string()),
+        );
+    }
+
+    #[test]
+    fn parse_handles_help_and_version() {
+        assert_eq!(parse_str(&["--help"]), Ok(Cli::Help(None)));
+        assert_eq!(parse_str(&["help", "count"]), Ok(Cli::Help(Some("count"))));
+        assert_eq!(parse_str(&["count", "--help"]), Ok(Cli::Help(Some("count"))));
+        assert_eq!(parse_str(&["count", "musium.conf", "-h"]), Ok(Cli::Help(Some("count"))));
+        assert_eq!(parse_str(&["--version"]), Ok(Cli::Version));
+        assert_eq!(parse_str(&["completions", "fish"]), Ok(Cli::Completions(Shell::Fish)));
+    }
+
+    #[test]
+    fn parse_parses_remote_commands() {
+        assert_eq!(
+            parse_str(&["remote", "musium.conf", "enqueue", "daft", "punk"]),
+            run(Command::Remote(RemoteCommand::Enqueue { query: "daft punk".to_string() })),
+        );
+        assert_eq!(
+            parse_str(&["remote", "musium.conf", "volume", "+2"]),
+            run(Command::Remote(RemoteCommand::Volume { change_db: Some(2) })),
+        );
+        assert_eq!(
+            parse_str(&["remote", "musium.conf", "volume", "-3"]),
+            run(Command::Remote(RemoteCommand::Volume { change_db: Some(-3) })),
+        );
+        assert_eq!(
+            parse_str(&["remote", "musium.conf"]),
+            run(Command::Remote(RemoteCommand::Status)),
+        );
+    }
+
+    #[test]
+    fn parse_treats_arguments_after_double_dash_as_positional() {
+        assert_eq!(
+            parse_str(&["backup", "musium.conf", "--", "--weird-name"]),
+            run(Command::Backup { path: "--weird-name".to_string() }),
+        );
+    }
+
+    #[test]
+    fn completions_include_commands_options_and_values() {
+        for script in [completions_bash(), completions_zsh(), completions_fish()] {
+            assert!(script.contains("export-userdata"));
+            assert!(script.contains("timescale"));
+            assert!(script.contains("trending"));
+            assert!(script.contains("listenbrainz"));
+            assert!(!script.contains("match2"));
+        }
+    }
+
+    #[test]
+    fn parse_rejects_bad_arguments() {
+        assert_eq!(parse_str(&[]), Err("Missing command.".to_string()));
+        assert_eq!(parse_str(&["play"]), Err("Unknown command 'play'.".to_string()));
+        assert_eq!(parse_str(&["--loud"]), Err("Unknown option '--loud'.".to_string()));
+        assert_eq!(parse_str(&["--verbose"]), Err("Missing command.".to_string()));
+        assert_eq!(
+            parse_str(&["scan"]),
+            Err("Missing argument musium.conf for command 'scan'.".to_string()),
+        );
+        assert_eq!(
+            parse_str(&["scan", "musium.conf", "--prune"]),
+            Err("Unknown option '--prune' for command 'scan'.".to_string()),
+        );
+        assert_eq!(
+            parse_str(&["backup", "musium.conf", "a.db", "b.db"]),
+            Err("Unexpected argument 'b.db' for command 'backup'.".to_string()),
+        );
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--export", "csv"]),
+            Err("Option '--export' expects 2 values.".to_string()),
+        );
+        assert_eq!(
+            parse_str(&["count", "musium.conf", "--export", "xml", "counts.xml"]),
+            Err("Invalid export format 'xml', expected 'csv' or 'json'.".to_string()),
+        );
+        assert_eq!(
+            parse_str(&["import", "musium.conf", "listenbrainz", "export.csv"]),
+            Err("Unexpected argument 'export.csv' for command 'import'.".to_string()),
+        );
+        assert_eq!(parse_str(&["wrapped", "musium.conf", "last"]), Err("Invalid year 'last'.".to_string()));
+    }
+}
@@ -11,25 +11,99 @@
 
 //! Configuration file parser.
 
+use std::collections::HashSet;
 use std::fmt;
+use std::iter;
 use std::path::PathBuf;
 use std::str::FromStr;
 
+use log::LevelFilter;
+
+use crate::database_utils::{Pragmas, SYNCHRONOUS_VALUES, TEMP_STORE_VALUES};
 use crate::error::{Error, Result};
+use crate::logger::{LogFilter, LogFormat};
+use crate::playcount::PlaycountConfig;
 use crate::prim::Hertz;
+use crate::mqtt::MqttConfig;
+use crate::shuffle::ShuffleConfig;
+use crate::tape::TapeFormat;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub listen: String,
     pub library_path: PathBuf,
     pub db_path: PathBuf,
+    pub db_pragmas: Pragmas,
     // TODO: Make this optional; pick the first one by default.
     pub audio_device: String,
     pub audio_volume_control: String,
     pub high_pass_cutoff: Hertz,
     pub exec_pre_playback_path: Option<PathBuf>,
     pub exec_post_idle_path: Option<PathBuf>,
+    pub exec_now_playing_path: Option<PathBuf>,
     pub idle_timeout_seconds: u64,
+    /// Exit after this many minutes without playback or requests, when
+    /// systemd passed the listening socket.
+    pub idle_exit_minutes: Option<u64>,
+    pub listenbrainz_user_token: Option<String>,
+    pub match_min_confidence: f32,
+    pub favorite_artist_boost: f32,
+    pub playcount: PlaycountConfig,
+    /// Minimum gaps between tracks by the same artist or album in a shuffle.
+    pub shuffle: ShuffleConfig,
+    /// Number of threads that read tags during a scan.
+    pub scan_reader_threads: usize,
+    /// Number of threads for loudness analysis and thumbnail generation.
+    pub scan_analysis_threads: usize,
+    /// Whether the loudness analysis also computes a spectrogram per track.
+    pub scan_spectrograms: bool,
+    /// Number of thumbnails to keep in memory, or all of them when not set.
+    pub thumb_cache_size: Option<usize>,
+    /// Whether to try to generate a missing thumbnail when it is requested.
+    pub thumb_generate_missing: bool,
+    /// Where to store cover art from the Cover Art Archive, fetching is
+    /// disabled when not set.
+    pub thumb_covers_path: Option<PathBuf>,
+    /// The broker and topics to publish the playback state to.
+    pub mqtt: MqttConfig,
+    /// Directory to record playback to, recording is disabled when not set.
+    pub tape_path: Option<PathBuf>,
+    pub tape_format: TapeFormat,
+    /// Discord application id to show the playing track as Rich Presence,
+    /// disabled when not set.
+    pub discord_client_id: Option<String>,
+    /// Url where Discord can reach this server, for album art.
+    pub discord_public_url: Option<String>,
+    /// Which log messages to print, by module.
+    pub log_level: LogFilter,
+    pub log_format: LogFormat,
+}
+
+/// Parse a comma-separated list of exactly five non-negative numbers.
+fn parse_five_floats(value: &str) -> Option<[f32; 5]> {
+    let mut result = [0.0; 5];
+    let mut parts = value.split(',');
+    for x in result.iter_mut() {
+        *x = f32::from_str(parts.next()?.trim()).ok()?;
+        if !(*x >= 0.0 && x.is_finite()) {
+            return None
+        }
+    }
+    match parts.next() {
+        None => Some(result),
+        Some(_) => None,
+    }
+}
+
+/// Return the element of `choices` equal to `value`, if any.
+fn parse_choice(value: &str, choices: &[&'static str]) -> Option<&'static str> {
+    choices.iter().find(|c| **c == value).cloned()
+}
+
+/// Format a list of numbers in the format accepted by `parse_five_floats`.
+fn format_floats(xs: &[f32]) -> String {
+    let strs: Vec<String> = xs.iter().map(|x| format!("{:.2}", x)).collect();
+    strs.join(", ")
 }
 
 impl fmt::Display for Config {
@@ -37,6 +111,22 @@ impl fmt::Display for Config {
         writeln!(f, "  listen                 = {}", self.listen)?;
         writeln!(f, "  library_path           = {}", self.library_path.to_string_lossy())?;
         writeln!(f, "  db_path                = {}", self.db_path.to_string_lossy())?;
+        match self.db_pragmas.cache_size {
+            Some(n) => writeln!(f, "  db_cache_size          = {}", n)?,
+            None => writeln!(f, "  db_cache_size          is not set")?,
+        }
+        match self.db_pragmas.mmap_size {
+            Some(n) => writeln!(f, "  db_mmap_size           = {}", n)?,
+            None => writeln!(f, "  db_mmap_size           is not set")?,
+        }
+        match self.db_pragmas.synchronous {
+            Some(value) => writeln!(f, "  db_synchronous         = {}", value)?,
+            None => writeln!(f, "  db_synchronous         is not set")?,
+        }
+        match self.db_pragmas.temp_store {
+            Some(value) => writeln!(f, "  db_temp_store          = {}", value)?,
+            None => writeln!(f, "  db_temp_store          is not set")?,
+        }
         writeln!(f, "  audio_device           = {}", self.audio_device)?;
         writeln!(f, "  audio_volume_control   = {}", self.audio_volume_control)?;
         writeln!(f, "  high_pass_cutoff       = {}", self.high_pass_cutoff)?;
@@ -48,121 +138,751 @@ impl fmt::Display for Config {
             Some(path) => writeln!(f, "  exec_post_idle_path    = {}", path.to_string_lossy())?,
             None => writeln!(f, "  exec_post_idle_path    is not set")?,
         }
-        write!(f, "  idle_timeout_seconds   = {}", self.idle_timeout_seconds)?;
+        match self.exec_now_playing_path.as_ref() {
+            Some(path) => writeln!(f, "  exec_now_playing_path  = {}", path.to_string_lossy())?,
+            None => writeln!(f, "  exec_now_playing_path  is not set")?,
+        }
+        writeln!(f, "  idle_timeout_seconds   = {}", self.idle_timeout_seconds)?;
+        match self.idle_exit_minutes {
+            Some(n) => writeln!(f, "  idle_exit_minutes      = {}", n)?,
+            None => writeln!(f, "  idle_exit_minutes      is not set")?,
+        }
+        // We don't print the token itself, it's a secret.
+        match self.listenbrainz_user_token {
+            Some(..) => writeln!(f, "  listenbrainz_user_token is set")?,
+            None => writeln!(f, "  listenbrainz_user_token is not set")?,
+        }
+        writeln!(f, "  match_min_confidence   = {}", self.match_min_confidence)?;
+        writeln!(f, "  favorite_artist_boost  = {}", self.favorite_artist_boost)?;
+        writeln!(f, "  playcount_half_lives   = {}", format_floats(&self.playcount.half_life_days))?;
+        writeln!(f, "  trending_weights       = {}", format_floats(&self.playcount.trending_weights))?;
+        writeln!(f, "  falling_recent_weights = {}", format_floats(&self.playcount.falling_recent_weights))?;
+        writeln!(f, "  rating_weight          = {}", self.playcount.rating_weight)?;
+        writeln!(f, "  shuffle_min_artist_gap = {}", self.shuffle.min_artist_gap)?;
+        writeln!(f, "  shuffle_min_album_gap  = {}", self.shuffle.min_album_gap)?;
+        writeln!(f, "  scan_reader_threads    = {}", self.scan_reader_threads)?;
+        writeln!(f, "  scan_analysis_threads  = {}", self.scan_analysis_threads)?;
+        writeln!(f, "  scan_spectrograms      = {}", self.scan_spectrograms)?;
+        match self.thumb_cache_size {
+            Some(n) => writeln!(f, "  thumb_cache_size       = {}", n)?,
+            None => writeln!(f, "  thumb_cache_size       is not set")?,
+        }
+        writeln!(f, "  thumb_generate_missing = {}", self.thumb_generate_missing)?;
+        match self.thumb_covers_path.as_ref() {
+            Some(path) => writeln!(f, "  thumb_covers_path      = {}", path.to_string_lossy())?,
+            None => writeln!(f, "  thumb_covers_path      is not set")?,
+        }
+        match self.mqtt.address.as_ref() {
+            Some(address) => writeln!(f, "  mqtt_address           = {}", address)?,
+            None => writeln!(f, "  mqtt_address           is not set")?,
+        }
+        match self.mqtt.username.as_ref() {
+            Some(username) => writeln!(f, "  mqtt_username          = {}", username)?,
+            None => writeln!(f, "  mqtt_username          is not set")?,
+        }
+        // We don't print the password itself, it's a secret.
+        match self.mqtt.password {
+            Some(..) => writeln!(f, "  mqtt_password          is set")?,
+            None => writeln!(f, "  mqtt_password          is not set")?,
+        }
+        writeln!(f, "  mqtt_topic_prefix      = {}", self.mqtt.topic_prefix)?;
+        writeln!(f, "  mqtt_discovery_prefix  = {}", self.mqtt.discovery_prefix)?;
+        writeln!(f, "  mqtt_commands          = {}", self.mqtt.commands)?;
+        match self.tape_path.as_ref() {
+            Some(path) => writeln!(f, "  tape_path              = {}", path.to_string_lossy())?,
+            None => writeln!(f, "  tape_path              is not set")?,
+        }
+        writeln!(f, "  tape_format            = {}", self.tape_format.as_str())?;
+        match self.discord_client_id.as_ref() {
+            Some(id) => writeln!(f, "  discord_client_id      = {}", id)?,
+            None => writeln!(f, "  discord_client_id      is not set")?,
+        }
+        match self.discord_public_url.as_ref() {
+            Some(url) => writeln!(f, "  discord_public_url     = {}", url)?,
+            None => writeln!(f, "  discord_public_url     is not set")?,
+        }
+        writeln!(f, "  log_level              = {}", self.log_level)?;
+        write!(f, "  log_format             = {}", self.log_format.as_str())?;
 
         Ok(())
     }
 }
 
-impl Config {
-    pub fn parse<I, S>(lines: I) -> Result<Config>
-    where
-        I: IntoIterator<Item = S>,
-        S: AsRef<str>,
-    {
-        let mut listen = None;
-        let mut library_path = None;
-        let mut db_path = None;
-        let mut audio_device = None;
-        let mut audio_volume_control = None;
-        let mut high_pass_cutoff = None;
-        let mut exec_pre_playback_path = None;
-        let mut exec_post_idle_path = None;
-        let mut idle_timeout_seconds = 180;
-
-        for (lineno, line_raw) in lines.into_iter().enumerate() {
-            let line = line_raw.as_ref();
+/// Keys in the TOML format, and the corresponding key in the line-based format.
+///
+/// Keys inside a section are written as `section.key`.
+const TOML_KEYS: &[(&str, &str)] = &[
+    ("listen", "listen"),
+    ("idle_timeout_seconds", "idle_timeout_seconds"),
+    ("idle_exit_minutes", "idle_exit_minutes"),
+    ("library.path", "library_path"),
+    ("database.path", "db_path"),
+    ("database.cache_size", "db_cache_size"),
+    ("database.mmap_size", "db_mmap_size"),
+    ("database.synchronous", "db_synchronous"),
+    ("database.temp_store", "db_temp_store"),
+    ("audio.device", "audio_device"),
+    ("audio.volume_control", "audio_volume_control"),
+    ("audio.high_pass_cutoff", "high_pass_cutoff"),
+    ("exec.pre_playback_path", "exec_pre_playback_path"),
+    ("exec.post_idle_path", "exec_post_idle_path"),
+    ("exec.now_playing_path", "exec_now_playing_path"),
+    ("listenbrainz.user_token", "listenbrainz_user_token"),
+    ("import.match_min_confidence", "match_min_confidence"),
+    ("search.favorite_artist_boost", "favorite_artist_boost"),
+    ("playcount.half_lives", "playcount_half_lives"),
+    ("playcount.trending_weights", "trending_weights"),
+    ("playcount.falling_recent_weights", "falling_recent_weights"),
+    ("playcount.rating_weight", "rating_weight"),
+    ("shuffle.min_artist_gap", "shuffle_min_artist_gap"),
+    ("shuffle.min_album_gap", "shuffle_min_album_gap"),
+    ("scan.reader_threads", "scan_reader_threads"),
+    ("scan.analysis_threads", "scan_analysis_threads"),
+    ("scan.spectrograms", "scan_spectrograms"),
+    ("thumb.cache_size", "thumb_cache_size"),
+    ("thumb.generate_missing", "thumb_generate_missing"),
+    ("thumb.covers_path", "thumb_covers_path"),
+    ("mqtt.address", "mqtt_address"),
+    ("mqtt.username", "mqtt_username"),
+    ("mqtt.password", "mqtt_password"),
+    ("mqtt.topic_prefix", "mqtt_topic_prefix"),
+    ("mqtt.discovery_prefix", "mqtt_discovery_prefix"),
+    ("mqtt.commands", "mqtt_commands"),
+    ("tape.path", "tape_path"),
+    ("tape.format", "tape_format"),
+    ("discord.client_id", "discord_client_id"),
+    ("discord.public_url", "discord_public_url"),
+    ("log.level", "log_level"),
+    ("log.format", "log_format"),
+];
 
-            // Allow empty lines in the config file.
-            if line.len() == 0 {
-                continue
-            }
+/// Return whether the config is in TOML format, rather than the older
+/// line-based format.
+///
+/// The old format has no section headers, and its values are never quoted,
+/// while in TOML, every string value is quoted.
+fn is_toml<S: AsRef<str>>(lines: &[S]) -> bool {
+    lines.iter().any(|line_raw| {
+        let line = line_raw.as_ref().trim_start();
+        if line.starts_with('[') {
+            return true
+        }
+        match line.find('=') {
+            Some(n) if !line.starts_with('#') => line[n + 1..].trim_start().starts_with(&['"', '\''][..]),
+            _ => false,
+        }
+    })
+}
 
-            // Skip lines starting with '#' to allow comments.
-            if line.starts_with('#') {
-                continue
+/// Return the part of `s` before a `#` comment, if any.
+fn strip_toml_comment(s: &str) -> &str {
+    match s.find('#') {
+        Some(n) => &s[..n],
+        None => s,
+    }
+}
+
+/// Parse a TOML string at the start of `s`, return it and the remainder.
+fn parse_toml_string(s: &str) -> std::result::Result<(String, &str), &'static str> {
+    let mut chars = s.char_indices();
+    let quote = match chars.next() {
+        Some((_, q)) => q,
+        None => return Err("Expected a string."),
+    };
+    let mut result = String::new();
+
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            _ if ch == quote => return Ok((result, &s[i + 1..])),
+            // Literal strings in single quotes have no escape sequences.
+            '\\' if quote == '"' => match chars.next() {
+                Some((_, '\\')) => result.push('\\'),
+                Some((_, '"')) => result.push('"'),
+                Some((_, 'n')) => result.push('\n'),
+                Some((_, 't')) => result.push('\t'),
+                _ => return Err("Unsupported escape sequence, expected \\\\, \\\", \\n, or \\t."),
+            },
+            _ => result.push(ch),
+        }
+    }
+
+    Err("Unterminated string, expected a closing quote.")
+}
+
+/// Parse a TOML number or boolean, which we pass on unchanged.
+fn parse_toml_bare(s: &str) -> std::result::Result<String, &'static str> {
+    let is_number = s.starts_with(|ch: char| ch.is_ascii_digit() || "+-.".contains(ch))
+        && s.chars().all(|ch| ch.is_ascii_alphanumeric() || "+-._".contains(ch));
+    let is_valid = is_number || s == "true" || s == "false";
+    if !is_valid {
+        return Err("Expected a string in double quotes, a number, or an array of numbers.")
+    }
+    // Underscores are allowed as digit separators, like in Rust.
+    Ok(s.replace('_', ""))
+}
+
+/// Parse the value of a TOML key-value pair into the value of the line-based
+/// format, so we can validate both formats in the same way.
+///
+/// We support the subset of TOML that the config needs: strings, numbers, and
+/// single-line arrays of numbers, which become comma-separated lists.
+fn parse_toml_value(value: &str) -> std::result::Result<String, &'static str> {
+    if value.starts_with(&['"', '\''][..]) {
+        let (result, remainder) = parse_toml_string(value)?;
+        return match strip_toml_comment(remainder).trim() {
+            "" => Ok(result),
+            _ => Err("Unexpected content after the closing quote."),
+        }
+    }
+
+    let value = strip_toml_comment(value).trim();
+    match value.strip_prefix('[') {
+        Some(elems) => match elems.strip_suffix(']') {
+            Some(elems) => {
+                let elems = elems.trim().trim_end_matches(',');
+                let parts: std::result::Result<Vec<String>, _> = elems
+                    .split(',')
+                    .map(|elem| parse_toml_bare(elem.trim()))
+                    .collect();
+                Ok(parts?.join(", "))
             }
+            None => Err("Unterminated array, expected a closing ']' on the same line."),
+        },
+        None => parse_toml_bare(value),
+    }
+}
 
-            if let Some(n) = line.find('=') {
-                let key = line[..n].trim();
-                let value = line[n + 1..].trim();
-                match key {
-                    "listen" => listen = Some(String::from(value)),
-                    "library_path" => library_path = Some(PathBuf::from(value)),
-                    "db_path" => db_path = Some(PathBuf::from(value)),
-                    "audio_device" => audio_device = Some(String::from(value)),
-                    "audio_volume_control" => audio_volume_control = Some(String::from(value)),
-                    "high_pass_cutoff" => match Hertz::from_str(value) {
-                        Ok(hz) => high_pass_cutoff = Some(hz),
-                        Err(msg) => return Err(Error::InvalidConfig(lineno, msg)),
-                    }
-                    "exec_pre_playback_path" => exec_pre_playback_path = Some(PathBuf::from(value)),
-                    "exec_post_idle_path" => exec_post_idle_path = Some(PathBuf::from(value)),
-                    "idle_timeout_seconds" => match u64::from_str(value) {
-                        Ok(seconds) => idle_timeout_seconds = seconds,
-                        Err(_) => {
-                            let msg = "Invalid idle_timeout_seconds value, must be an integer.";
-                            return Err(Error::InvalidConfig(lineno, msg));
-                        }
-                    }
-                    _ => {
-                        let msg = "Unknown key. See the configuration docs for supported keys.";
-                        return Err(Error::InvalidConfig(lineno, msg))
-                    }
-                }
-            } else {
-                let msg = "Line contains no '='. \
-                    Expected key-value pair like 'audio_device = UCM404HD 192k'.";
-                return Err(Error::InvalidConfig(lineno, msg))
+/// Format a string as a TOML basic string, with quotes.
+fn format_toml_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Return a commented config file with the given required settings, for
+/// `musium init`. The optional settings are included, commented out.
+pub fn format_starter_config(
+    library_path: &str,
+    db_path: &str,
+    audio_device: &str,
+    audio_volume_control: &str,
+) -> String {
+    format!(
+        r#"# Musium configuration, see docs/configuration.md for all settings.
+# Run 'musium check' with this file to verify it.
+
+# The address to serve the webinterface on. Use "localhost:8233" to listen
+# only on loopback.
+listen = "0.0.0.0:8233"
+
+[library]
+# The directory to recursively scan for flac files.
+path = {}
+
+[database]
+# Where to store the database with metadata, listens, and thumbnails. The
+# directory must exist.
+path = {}
+
+[audio]
+# The name of the ALSA card to play on, and its volume control. When these
+# are wrong, 'musium check' lists the valid options.
+device = {}
+volume_control = {}
+# high_pass_cutoff = "30 Hz"
+
+[exec]
+# Programs to run before playback starts, after playback has been idle for
+# idle_timeout_seconds, and when a track starts playing.
+# pre_playback_path = "/usr/local/bin/musium-pre-playback"
+# post_idle_path = "/usr/local/bin/musium-post-idle"
+# now_playing_path = "/usr/local/bin/musium-now-playing"
+
+[listenbrainz]
+# The token for tools/scrobble.py, see docs/listenbrainz.md.
+# user_token = ""
+"#,
+        format_toml_string(library_path),
+        format_toml_string(db_path),
+        format_toml_string(audio_device),
+        format_toml_string(audio_volume_control),
+    )
+}
+
+/// Settings collected while parsing, before we check that the required ones
+/// are present.
+struct PartialConfig {
+    listen: Option<String>,
+    library_path: Option<PathBuf>,
+    db_path: Option<PathBuf>,
+    db_pragmas: Pragmas,
+    audio_device: Option<String>,
+    audio_volume_control: Option<String>,
+    high_pass_cutoff: Option<Hertz>,
+    exec_pre_playback_path: Option<PathBuf>,
+    exec_post_idle_path: Option<PathBuf>,
+    exec_now_playing_path: Option<PathBuf>,
+    idle_timeout_seconds: u64,
+    idle_exit_minutes: Option<u64>,
+    listenbrainz_user_token: Option<String>,
+    match_min_confidence: f32,
+    favorite_artist_boost: f32,
+    playcount: PlaycountConfig,
+    shuffle: ShuffleConfig,
+    scan_reader_threads: usize,
+    scan_analysis_threads: Option<usize>,
+    scan_spectrograms: bool,
+    thumb_cache_size: Option<usize>,
+    thumb_generate_missing: bool,
+    thumb_covers_path: Option<PathBuf>,
+    mqtt: MqttConfig,
+    tape_path: Option<PathBuf>,
+    tape_format: TapeFormat,
+    discord_client_id: Option<String>,
+    discord_public_url: Option<String>,
+    log_level: LogFilter,
+    log_format: LogFormat,
+}
+
+impl PartialConfig {
+    fn new() -> PartialConfig {
+        PartialConfig {
+            listen: None,
+            library_path: None,
+            db_path: None,
+            db_pragmas: Pragmas::default(),
+            audio_device: None,
+            audio_volume_control: None,
+            high_pass_cutoff: None,
+            exec_pre_playback_path: None,
+            exec_post_idle_path: None,
+            exec_now_playing_path: None,
+            idle_timeout_seconds: 180,
+            idle_exit_minutes: None,
+            listenbrainz_user_token: None,
+            match_min_confidence: 0.75,
+            favorite_artist_boost: 2.0,
+            playcount: PlaycountConfig::default(),
+            shuffle: ShuffleConfig::default(),
+            scan_reader_threads: 64,
+            scan_analysis_threads: None,
+            scan_spectrograms: false,
+            thumb_cache_size: None,
+            thumb_generate_missing: false,
+            thumb_covers_path: None,
+            mqtt: MqttConfig::default(),
+            tape_path: None,
+            tape_format: TapeFormat::Wav,
+            discord_client_id: None,
+            discord_public_url: None,
+            log_level: LogFilter::new(LevelFilter::Info),
+            log_format: LogFormat::Text,
+        }
+    }
+
+    /// Set the key of the line-based format to `value`.
+    ///
+    /// Returns a message about what is wrong if the key or value is invalid.
+    fn set(&mut self, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+        match key {
+            "listen" => self.listen = Some(String::from(value)),
+            "library_path" => self.library_path = Some(PathBuf::from(value)),
+            "db_path" => self.db_path = Some(PathBuf::from(value)),
+            "db_cache_size" => match i64::from_str(value) {
+                Ok(n) => self.db_pragmas.cache_size = Some(n),
+                Err(_) => return Err("Invalid value, must be an integer."),
+            }
+            "db_mmap_size" => match i64::from_str(value) {
+                Ok(n) if n >= 0 => self.db_pragmas.mmap_size = Some(n),
+                _ => return Err("Invalid value, must be a non-negative number of bytes."),
+            }
+            "db_synchronous" => match parse_choice(value, SYNCHRONOUS_VALUES) {
+                Some(value) => self.db_pragmas.synchronous = Some(value),
+                None => return Err("Invalid value, must be off, normal, full, or extra."),
+            }
+            "db_temp_store" => match parse_choice(value, TEMP_STORE_VALUES) {
+                Some(value) => self.db_pragmas.temp_store = Some(value),
+                None => return Err("Invalid value, must be default, file, or memory."),
+            }
+            "audio_device" => self.audio_device = Some(String::from(value)),
+            "audio_volume_control" => self.audio_volume_control = Some(String::from(value)),
+            "high_pass_cutoff" => self.high_pass_cutoff = Some(Hertz::from_str(value)?),
+            "exec_pre_playback_path" => self.exec_pre_playback_path = Some(PathBuf::from(value)),
+            "exec_post_idle_path" => self.exec_post_idle_path = Some(PathBuf::from(value)),
+            "exec_now_playing_path" => self.exec_now_playing_path = Some(PathBuf::from(value)),
+            "idle_timeout_seconds" => match u64::from_str(value) {
+                Ok(seconds) => self.idle_timeout_seconds = seconds,
+                Err(_) => return Err("Invalid value, must be an integer."),
+            }
+            "idle_exit_minutes" => match u64::from_str(value) {
+                Ok(n) if n > 0 => self.idle_exit_minutes = Some(n),
+                _ => return Err("Invalid value, must be a positive integer."),
+            }
+            "listenbrainz_user_token" => self.listenbrainz_user_token = Some(String::from(value)),
+            "match_min_confidence" => match f32::from_str(value) {
+                Ok(c) if (0.0..=1.0).contains(&c) => self.match_min_confidence = c,
+                _ => return Err("Invalid value, must be a number between 0 and 1."),
+            }
+            "favorite_artist_boost" => match f32::from_str(value) {
+                Ok(b) if b >= 0.0 => self.favorite_artist_boost = b,
+                _ => return Err("Invalid value, must be a non-negative number."),
+            }
+            "playcount_half_lives" => match parse_five_floats(value) {
+                Some(days) if days.iter().all(|t| *t > 0.0) => self.playcount.half_life_days = days,
+                _ => return Err("Invalid value, must be five positive numbers of days."),
+            }
+            "trending_weights" => match parse_five_floats(value) {
+                Some(weights) => self.playcount.trending_weights = weights,
+                None => return Err("Invalid value, must be five non-negative numbers."),
+            }
+            "falling_recent_weights" => match parse_five_floats(value) {
+                Some(weights) => self.playcount.falling_recent_weights = weights,
+                None => return Err("Invalid value, must be five non-negative numbers."),
+            }
+            "rating_weight" => match f32::from_str(value) {
+                Ok(w) if w >= 0.0 => self.playcount.rating_weight = w,
+                _ => return Err("Invalid value, must be a non-negative number."),
+            }
+            "shuffle_min_artist_gap" => match usize::from_str(value) {
+                Ok(n) => self.shuffle.min_artist_gap = n,
+                Err(_) => return Err("Invalid value, must be a non-negative integer."),
+            }
+            "shuffle_min_album_gap" => match usize::from_str(value) {
+                Ok(n) => self.shuffle.min_album_gap = n,
+                Err(_) => return Err("Invalid value, must be a non-negative integer."),
+            }
+            "scan_reader_threads" => match usize::from_str(value) {
+                Ok(n) if n > 0 => self.scan_reader_threads = n,
+                _ => return Err("Invalid value, must be a positive integer."),
+            }
+            "scan_analysis_threads" => match usize::from_str(value) {
+                Ok(n) if n > 0 => self.scan_analysis_threads = Some(n),
+                _ => return Err("Invalid value, must be a positive integer."),
             }
+            "scan_spectrograms" => match value {
+                "true" => self.scan_spectrograms = true,
+                "false" => self.scan_spectrograms = false,
+                _ => return Err("Invalid value, must be true or false."),
+            }
+            "thumb_cache_size" => match usize::from_str(value) {
+                Ok(n) if n > 0 => self.thumb_cache_size = Some(n),
+                _ => return Err("Invalid value, must be a positive integer."),
+            }
+            "thumb_generate_missing" => match value {
+                "true" => self.thumb_generate_missing = true,
+                "false" => self.thumb_generate_missing = false,
+                _ => return Err("Invalid value, must be true or false."),
+            }
+            "thumb_covers_path" => self.thumb_covers_path = Some(PathBuf::from(value)),
+            "mqtt_address" => self.mqtt.address = Some(String::from(value)),
+            "mqtt_username" => self.mqtt.username = Some(String::from(value)),
+            "mqtt_password" => self.mqtt.password = Some(String::from(value)),
+            "mqtt_topic_prefix" => match value {
+                "" => return Err("Invalid value, the prefix must not be empty."),
+                _ => self.mqtt.topic_prefix = String::from(value),
+            }
+            "mqtt_discovery_prefix" => match value {
+                "" => return Err("Invalid value, the prefix must not be empty."),
+                _ => self.mqtt.discovery_prefix = String::from(value),
+            }
+            "mqtt_commands" => match value {
+                "true" => self.mqtt.commands = true,
+                "false" => self.mqtt.commands = false,
+                _ => return Err("Invalid value, must be true or false."),
+            }
+            "tape_path" => self.tape_path = Some(PathBuf::from(value)),
+            "tape_format" => match TapeFormat::parse(value) {
+                Some(format) => self.tape_format = format,
+                None => return Err("Invalid value, must be wav or flac."),
+            }
+            "discord_client_id" => self.discord_client_id = Some(String::from(value)),
+            "discord_public_url" => self.discord_public_url = Some(String::from(value)),
+            "log_level" => match LogFilter::parse(value) {
+                Some(filter) => self.log_level = filter,
+                None => return Err(
+                    "Invalid value, must be a level (off, error, warn, info, debug, or trace), \
+                    optionally followed by module=level pairs, separated by commas."
+                ),
+            }
+            "log_format" => match LogFormat::parse(value) {
+                Some(format) => self.log_format = format,
+                None => return Err("Invalid value, must be text or json."),
+            }
+            _ => return Err("Unknown key. See the configuration docs for supported keys."),
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Config> {
+        // MQTT 3.1.1 does not allow a password without a user name.
+        if self.mqtt.password.is_some() && self.mqtt.username.is_none() {
+            return Err(Error::IncompleteConfig(
+                "MQTT password set without user name. Expected 'username' in [mqtt], \
+                or an 'mqtt_username ='-line in the old format."
+            ))
         }
 
         let config = Config {
-            listen: match listen {
+            listen: match self.listen {
                 Some(b) => b,
                 None => String::from("0.0.0.0:8233"),
             },
-            library_path: match library_path {
+            library_path: match self.library_path {
                 Some(p) => p,
                 None => return Err(Error::IncompleteConfig(
-                    "Library path not set. Expected 'library_path ='-line."
+                    "Library path not set. Expected 'path' in [library], \
+                    or a 'library_path ='-line in the old format."
                 )),
             },
-            db_path: match db_path {
+            db_path: match self.db_path {
                 Some(p) => p,
                 None => return Err(Error::IncompleteConfig(
-                    "Database path not set. Expected 'db_path ='-line."
+                    "Database path not set. Expected 'path' in [database], \
+                    or a 'db_path ='-line in the old format."
                 )),
             },
-            audio_device: match audio_device {
+            db_pragmas: self.db_pragmas,
+            audio_device: match self.audio_device {
                 Some(d) => d,
                 None => return Err(Error::IncompleteConfig(
-                    "Audio device not set. Expected 'audio_device ='-line."
+                    "Audio device not set. Expected 'device' in [audio], \
+                    or an 'audio_device ='-line in the old format."
                 )),
             },
-            audio_volume_control: match audio_volume_control {
+            audio_volume_control: match self.audio_volume_control {
                 Some(d) => d,
                 None => return Err(Error::IncompleteConfig(
-                    "Audio volume control not set. Expected 'audio_volume_control ='-line."
+                    "Audio volume control not set. Expected 'volume_control' in [audio], \
+                    or an 'audio_volume_control ='-line in the old format."
                 )),
             },
-            high_pass_cutoff: match high_pass_cutoff {
+            high_pass_cutoff: match self.high_pass_cutoff {
                 Some(hz) => hz,
                 None => Hertz(0),
             },
-            exec_pre_playback_path: exec_pre_playback_path,
-            exec_post_idle_path: exec_post_idle_path,
-            idle_timeout_seconds: idle_timeout_seconds,
+            exec_pre_playback_path: self.exec_pre_playback_path,
+            exec_post_idle_path: self.exec_post_idle_path,
+            exec_now_playing_path: self.exec_now_playing_path,
+            idle_timeout_seconds: self.idle_timeout_seconds,
+            idle_exit_minutes: self.idle_exit_minutes,
+            listenbrainz_user_token: self.listenbrainz_user_token,
+            match_min_confidence: self.match_min_confidence,
+            favorite_artist_boost: self.favorite_artist_boost,
+            playcount: self.playcount,
+            shuffle: self.shuffle,
+            scan_reader_threads: self.scan_reader_threads,
+            scan_analysis_threads: match self.scan_analysis_threads {
+                Some(n) => n,
+                None => num_cpus::get(),
+            },
+            scan_spectrograms: self.scan_spectrograms,
+            thumb_cache_size: self.thumb_cache_size,
+            thumb_generate_missing: self.thumb_generate_missing,
+            thumb_covers_path: self.thumb_covers_path,
+            mqtt: self.mqtt,
+            tape_path: self.tape_path,
+            tape_format: self.tape_format,
+            discord_client_id: self.discord_client_id,
+            discord_public_url: self.discord_public_url,
+            log_level: self.log_level,
+            log_format: self.log_format,
         };
 
         Ok(config)
     }
 }
 
+impl Config {
+    /// Return a copy of this config, with the settings that can change while
+    /// Musium runs taken from `new`.
+    ///
+    /// These are the settings that are read at the moment they are used. The
+    /// other settings are used once at startup, so changing them requires a
+    /// restart, see also `changes_needing_restart`.
+    pub fn with_reloadable_from(&self, new: &Config) -> Config {
+        let mut result = self.clone();
+        result.exec_pre_playback_path = new.exec_pre_playback_path.clone();
+        result.exec_post_idle_path = new.exec_post_idle_path.clone();
+        result.exec_now_playing_path = new.exec_now_playing_path.clone();
+        result.idle_timeout_seconds = new.idle_timeout_seconds;
+        result.idle_exit_minutes = new.idle_exit_minutes;
+        result.listenbrainz_user_token = new.listenbrainz_user_token.clone();
+        result.match_min_confidence = new.match_min_confidence;
+        result.favorite_artist_boost = new.favorite_artist_boost;
+        result.shuffle = new.shuffle;
+        result.scan_reader_threads = new.scan_reader_threads;
+        result.scan_analysis_threads = new.scan_analysis_threads;
+        result.scan_spectrograms = new.scan_spectrograms;
+        result.thumb_generate_missing = new.thumb_generate_missing;
+        result
+    }
+
+    /// Return the keys of the settings that differ between the two configs,
+    /// but that `with_reloadable_from` does not apply.
+    pub fn changes_needing_restart(&self, new: &Config) -> Vec<&'static str> {
+        let mut result = Vec::new();
+        if self.listen != new.listen { result.push("listen") }
+        if self.library_path != new.library_path { result.push("library_path") }
+        if self.db_path != new.db_path { result.push("db_path") }
+        if self.db_pragmas != new.db_pragmas { result.push("db_pragmas") }
+        if self.audio_device != new.audio_device { result.push("audio_device") }
+        if self.audio_volume_control != new.audio_volume_control { result.push("audio_volume_control") }
+        if self.high_pass_cutoff != new.high_pass_cutoff { result.push("high_pass_cutoff") }
+        if self.playcount != new.playcount { result.push("playcount") }
+        if self.thumb_cache_size != new.thumb_cache_size { result.push("thumb_cache_size") }
+        if self.thumb_covers_path != new.thumb_covers_path { result.push("thumb_covers_path") }
+        if self.mqtt != new.mqtt { result.push("mqtt") }
+        if self.tape_path != new.tape_path { result.push("tape_path") }
+        if self.tape_format != new.tape_format { result.push("tape_format") }
+        if self.discord_client_id != new.discord_client_id { result.push("discord_client_id") }
+        if self.discord_public_url != new.discord_public_url { result.push("discord_public_url") }
+        if self.log_level != new.log_level { result.push("log_level") }
+        if self.log_format != new.log_format { result.push("log_format") }
+        result
+    }
+
+    /// Parse a config file, either in TOML format, or in the older line-based
+    /// format of `key = value` lines without sections.
+    pub fn parse<I, S>(lines: I) -> Result<Config>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Config::parse_with_env(lines, iter::empty())
+    }
+
+    /// Parse a config file like `parse`, then apply overrides from `MUSIUM_*`
+    /// environment variables in `env`.
+    ///
+    /// The variable for a setting is its key in the old format in uppercase,
+    /// for example `MUSIUM_DB_PATH` for `db_path`. Other `MUSIUM_*` variables
+    /// are ignored; Musium sets some of those itself for the exec hooks.
+    pub fn parse_with_env<I, S, E>(lines: I, env: E) -> Result<Config>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let lines: Vec<S> = lines.into_iter().collect();
+        let mut config = PartialConfig::new();
+
+        if is_toml(&lines) {
+            Config::parse_toml(&lines, &mut config)?;
+        } else {
+            Config::parse_lines(&lines, &mut config)?;
+        }
+
+        for (var, value) in env {
+            let key = match var.strip_prefix("MUSIUM_") {
+                Some(name) => name.to_ascii_lowercase(),
+                None => continue,
+            };
+            if !TOML_KEYS.iter().any(|(_, old_key)| *old_key == key) {
+                continue
+            }
+            if let Err(msg) = config.set(&key, value.trim()) {
+                return Err(Error::InvalidConfigEnv(var, msg))
+            }
+        }
+
+        config.finish()
+    }
+
+    fn parse_lines<S: AsRef<str>>(lines: &[S], config: &mut PartialConfig) -> Result<()> {
+        for (i, line_raw) in lines.iter().enumerate() {
+            let lineno = i + 1;
+            let line = line_raw.as_ref();
+
+            // Allow empty lines in the config file.
+            if line.len() == 0 {
+                continue
+            }
+
+            // Skip lines starting with '#' to allow comments.
+            if line.starts_with('#') {
+                continue
+            }
+
+            if let Some(n) = line.find('=') {
+                let key = line[..n].trim();
+                let value = line[n + 1..].trim();
+                if let Err(msg) = config.set(key, value) {
+                    return Err(Error::InvalidConfigValue(lineno, key.to_string(), msg))
+                }
+            } else {
+                let msg = "Line contains no '='. \
+                    Expected key-value pair like 'audio_device = UCM404HD 192k'.";
+                return Err(Error::InvalidConfig(lineno, msg))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_toml<S: AsRef<str>>(lines: &[S], config: &mut PartialConfig) -> Result<()> {
+        let mut section = String::new();
+        let mut keys_seen = HashSet::new();
+
+        for (i, line_raw) in lines.iter().enumerate() {
+            let lineno = i + 1;
+            let line = line_raw.as_ref().trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let name = match strip_toml_comment(header).trim().strip_suffix(']') {
+                    Some(name) => name.trim(),
+                    None => return Err(Error::InvalidConfig(
+                        lineno,
+                        "Invalid section header, expected a closing ']'.",
+                    )),
+                };
+                let prefix = format!("{}.", name);
+                if !TOML_KEYS.iter().any(|(k, _)| k.starts_with(&prefix)) {
+                    return Err(Error::InvalidConfig(
+                        lineno,
+                        "Unknown section. See the configuration docs for supported sections.",
+                    ))
+                }
+                section = prefix;
+                continue
+            }
+
+            let n = match line.find('=') {
+                Some(n) => n,
+                None => return Err(Error::InvalidConfig(
+                    lineno,
+                    "Line contains no '='. Expected key-value pair like 'device = \"UMC404HD 192k\"'.",
+                )),
+            };
+            let key = format!("{}{}", section, line[..n].trim());
+            let old_key = match TOML_KEYS.iter().find(|(k, _)| *k == key) {
+                Some((_, old_key)) => *old_key,
+                None => return Err(Error::InvalidConfigValue(
+                    lineno,
+                    key,
+                    "Unknown key. See the configuration docs for supported keys.",
+                )),
+            };
+            if !keys_seen.insert(old_key) {
+                return Err(Error::InvalidConfigValue(lineno, key, "Key is set more than once."))
+            }
+            let result = parse_toml_value(line[n + 1..].trim())
+                .and_then(|value| config.set(old_key, &value));
+            if let Err(msg) = result {
+                return Err(Error::InvalidConfigValue(lineno, key, msg))
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::path::Path;
-    use super::{Config, Hertz};
+    use std::path::{Path, PathBuf};
+    use crate::error::Error;
+    use super::{format_starter_config, Config, Hertz, LevelFilter, LogFilter, LogFormat, PlaycountConfig, TapeFormat};
 
     #[test]
     pub fn config_can_be_parsed() {
@@ -183,5 +903,366 @@ mod test {
         assert_eq!(&config.audio_device[..], "UCM404HD 192k");
         assert_eq!(&config.audio_volume_control[..], "UMC404HD 192k Output");
         assert_eq!(config.high_pass_cutoff, Hertz(50));
+        assert_eq!(config.listenbrainz_user_token, None);
+        assert_eq!(config.playcount, PlaycountConfig::default());
+        assert_eq!(config.idle_exit_minutes, None);
+    }
+
+    #[test]
+    pub fn config_parses_playcount_settings() {
+        let config_lines = [
+            "library_path = /home/user/music",
+            "db_path = /home/user/.local/share/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+            "playcount_half_lives = 3650, 365, 90, 30, 7",
+            "trending_weights = 0, 0, 0, 1, 2.5",
+            "rating_weight = 0.25",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(config.playcount.half_life_days, [3650.0, 365.0, 90.0, 30.0, 7.0]);
+        assert_eq!(config.playcount.trending_weights, [0.0, 0.0, 0.0, 1.0, 2.5]);
+        assert_eq!(config.playcount.rating_weight, 0.25);
+        assert_eq!(
+            config.playcount.falling_recent_weights,
+            PlaycountConfig::default().falling_recent_weights,
+        );
+
+        let bad_lines = [
+            "playcount_half_lives = 3650, 365, 90, 30",
+            "playcount_half_lives = 3650, 365, 90, 30, 0",
+            "trending_weights = 0, 0, 0, 1, -2",
+            "trending_weights = 0, 0, 0, 1, 2, 3",
+            "rating_weight = -1",
+        ];
+        for bad_line in bad_lines {
+            assert!(Config::parse([bad_line]).is_err());
+        }
+    }
+
+    #[test]
+    pub fn config_parses_favorite_artist_boost() {
+        let config_lines = [
+            "library_path = /home/user/music",
+            "db_path = /home/user/.local/share/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+            "favorite_artist_boost = 1.5",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(config.favorite_artist_boost, 1.5);
+        assert!(Config::parse(["favorite_artist_boost = -1"]).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_shuffle_gaps() {
+        let config_lines = [
+            "[library]",
+            "path = \"/home/user/music\"",
+            "[database]",
+            "path = \"/home/user/.local/share/musium/db.sqlite3\"",
+            "[audio]",
+            "device = \"UCM404HD 192k\"",
+            "volume_control = \"UMC404HD 192k Output\"",
+            "[shuffle]",
+            "min_artist_gap = 3",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(config.shuffle.min_artist_gap, 3);
+        assert_eq!(config.shuffle.min_album_gap, 0);
+        assert!(Config::parse(["shuffle_min_album_gap = -1"]).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_mqtt_settings() {
+        let config_lines = [
+            "[library]",
+            "path = \"/home/user/music\"",
+            "[database]",
+            "path = \"/home/user/.local/share/musium/db.sqlite3\"",
+            "[audio]",
+            "device = \"UCM404HD 192k\"",
+            "volume_control = \"UMC404HD 192k Output\"",
+            "[mqtt]",
+            "address = \"localhost:1883\"",
+            "username = \"musium\"",
+            "password = \"hunter2\"",
+            "commands = true",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(config.mqtt.address.as_deref(), Some("localhost:1883"));
+        assert_eq!(config.mqtt.username.as_deref(), Some("musium"));
+        assert_eq!(config.mqtt.password.as_deref(), Some("hunter2"));
+        assert_eq!(config.mqtt.topic_prefix, "musium");
+        assert_eq!(config.mqtt.discovery_prefix, "homeassistant");
+        assert!(config.mqtt.commands);
+        assert!(!config.to_string().contains("hunter2"));
+        assert!(Config::parse(["mqtt_topic_prefix = "]).is_err());
+        assert!(Config::parse(["mqtt_commands = yes"]).is_err());
+
+        // A password is only allowed together with a user name.
+        let without_username: Vec<_> = config_lines.iter().filter(|line| !line.starts_with("username")).collect();
+        assert!(Config::parse(without_username).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_tape_settings() {
+        let mut config_lines = vec![
+            "library_path = /home/user/music",
+            "db_path = /home/user/.local/share/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.tape_path, None);
+        assert_eq!(config.tape_format, TapeFormat::Wav);
+
+        config_lines.extend(["tape_path = /home/user/tapes", "tape_format = flac"]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.tape_path, Some(PathBuf::from("/home/user/tapes")));
+        assert_eq!(config.tape_format, TapeFormat::Flac);
+        assert!(Config::parse(["tape_format = mp3"]).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_discord_settings() {
+        let config_lines = [
+            "[library]",
+            "path = \"/home/user/music\"",
+            "[database]",
+            "path = \"/home/user/.local/share/musium/db.sqlite3\"",
+            "[audio]",
+            "device = \"UCM404HD 192k\"",
+            "volume_control = \"UMC404HD 192k Output\"",
+            "[discord]",
+            "client_id = \"1234567890\"",
+            "public_url = \"https://music.example.com\"",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(config.discord_client_id.as_deref(), Some("1234567890"));
+        assert_eq!(config.discord_public_url.as_deref(), Some("https://music.example.com"));
+    }
+
+    #[test]
+    pub fn config_parses_scan_threads() {
+        let config_lines = [
+            "[library]",
+            "path = \"/home/user/music\"",
+            "[database]",
+            "path = \"/home/user/.local/share/musium/db.sqlite3\"",
+            "[audio]",
+            "device = \"UCM404HD 192k\"",
+            "volume_control = \"UMC404HD 192k Output\"",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(config.scan_reader_threads, 64);
+        assert!(config.scan_analysis_threads > 0);
+
+        let mut config_lines = config_lines.to_vec();
+        config_lines.extend(["[scan]", "reader_threads = 4", "analysis_threads = 2"]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.scan_reader_threads, 4);
+        assert_eq!(config.scan_analysis_threads, 2);
+        assert_eq!(config.thumb_cache_size, None);
+        assert!(!config.scan_spectrograms);
+
+        config_lines.extend(["spectrograms = true", "[thumb]", "cache_size = 500"]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert!(config.scan_spectrograms);
+        assert_eq!(config.thumb_cache_size, Some(500));
+        assert!(!config.thumb_generate_missing);
+
+        config_lines.push("generate_missing = true");
+        let config = Config::parse(&config_lines).unwrap();
+        assert!(config.thumb_generate_missing);
+        assert_eq!(config.thumb_covers_path, None);
+
+        config_lines.push("covers_path = \"/var/lib/musium/covers\"");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.thumb_covers_path, Some(PathBuf::from("/var/lib/musium/covers")));
+
+        assert!(Config::parse(["scan_reader_threads = 0"]).is_err());
+        assert!(Config::parse(["scan_analysis_threads = many"]).is_err());
+        assert!(Config::parse(["thumb_cache_size = 0"]).is_err());
+        assert!(Config::parse(["scan_spectrograms = yes"]).is_err());
+        assert!(Config::parse(["thumb_generate_missing = 1"]).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_log_level() {
+        let config_lines = [
+            "[library]",
+            "path = \"/home/user/music\"",
+            "[database]",
+            "path = \"/home/user/.local/share/musium/db.sqlite3\"",
+            "[audio]",
+            "device = \"UCM404HD 192k\"",
+            "volume_control = \"UMC404HD 192k Output\"",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(config.log_level, LogFilter::new(LevelFilter::Info));
+        assert_eq!(config.log_format, LogFormat::Text);
+
+        let mut config_lines = config_lines.to_vec();
+        config_lines.extend(["[log]", "level = \"warn, player=debug\"", "format = \"json\""]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.log_level.default, LevelFilter::Warn);
+        assert_eq!(config.log_level.level_for("musium::player"), LevelFilter::Debug);
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        assert!(Config::parse(["log_level = loud"]).is_err());
+        assert!(Config::parse(["log_format = xml"]).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_db_pragmas() {
+        let config_lines = [
+            "library_path = /home/user/music",
+            "db_path = /home/user/.local/share/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+            "db_cache_size = -16000",
+            "db_synchronous = normal",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(config.db_pragmas.cache_size, Some(-16000));
+        assert_eq!(config.db_pragmas.mmap_size, None);
+        assert_eq!(config.db_pragmas.synchronous, Some("normal"));
+        assert_eq!(config.db_pragmas.temp_store, None);
+
+        let bad_lines = [
+            "db_cache_size = lots",
+            "db_mmap_size = -1",
+            "db_synchronous = sometimes",
+            "db_temp_store = normal; drop table listens",
+        ];
+        for bad_line in bad_lines {
+            assert!(Config::parse([bad_line]).is_err());
+        }
+    }
+
+    #[test]
+    pub fn config_can_be_parsed_from_toml() {
+        let config_lines = [
+            "# This is a comment.",
+            "listen = \"localhost:8000\"",
+            "idle_exit_minutes = 30",
+            "",
+            "[library]",
+            "path = \"/home/user/music\"",
+            "",
+            "[database]",
+            "path = '/home/user/.local/share/musium/db.sqlite3'",
+            "cache_size = -16_000 # KiB",
+            "",
+            "[audio]",
+            "device = \"UCM404HD 192k\"",
+            "volume_control = \"UMC404HD 192k Output\"",
+            "high_pass_cutoff = \"50 Hz\"",
+            "",
+            "[playcount]",
+            "half_lives = [3650, 365, 90, 30, 7]",
+        ];
+        let config = Config::parse(config_lines).unwrap();
+        assert_eq!(&config.listen[..], "localhost:8000");
+        assert_eq!(config.library_path.as_path(), Path::new("/home/user/music"));
+        assert_eq!(config.db_path.as_path(), Path::new("/home/user/.local/share/musium/db.sqlite3"));
+        assert_eq!(config.db_pragmas.cache_size, Some(-16000));
+        assert_eq!(&config.audio_device[..], "UCM404HD 192k");
+        assert_eq!(&config.audio_volume_control[..], "UMC404HD 192k Output");
+        assert_eq!(config.high_pass_cutoff, Hertz(50));
+        assert_eq!(config.playcount.half_life_days, [3650.0, 365.0, 90.0, 30.0, 7.0]);
+        assert_eq!(config.idle_exit_minutes, Some(30));
+        assert!(Config::parse(["idle_exit_minutes = 0"]).is_err());
+    }
+
+    #[test]
+    pub fn config_reports_line_and_key_of_toml_errors() {
+        let err = |lines: &[&str]| match Config::parse(lines) {
+            Err(Error::InvalidConfig(line, _)) => (line, String::new()),
+            Err(Error::InvalidConfigValue(line, key, _)) => (line, key),
+            _ => panic!("Expected an invalid config error."),
+        };
+        assert_eq!(err(&["[audio]", "", "device = UMC404HD"]), (3, "audio.device".to_string()));
+        assert_eq!(err(&["[audio]", "cutoff = \"50 Hz\""]), (2, "audio.cutoff".to_string()));
+        assert_eq!(err(&["[audio", "device = \"x\""]), (1, String::new()));
+        assert_eq!(err(&["[sound]", "device = \"x\""]), (1, String::new()));
+        assert_eq!(err(&["[database]", "mmap_size = -1"]), (2, "database.mmap_size".to_string()));
+        assert_eq!(err(&["listen = \"a\"", "listen = \"b\""]), (2, "listen".to_string()));
+        assert_eq!(err(&["listen = \"a"]), (1, "listen".to_string()));
+
+        // The old format reports the key too.
+        assert_eq!(err(&["", "rating_weight = -1"]), (2, "rating_weight".to_string()));
+    }
+
+    #[test]
+    pub fn config_applies_env_overrides() {
+        let config_lines = [
+            "listen = \"localhost:8000\"",
+            "[library]",
+            "path = \"/home/user/music\"",
+            "[audio]",
+            "device = \"UCM404HD 192k\"",
+            "volume_control = \"UMC404HD 192k Output\"",
+        ];
+        let env = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let config = Config::parse_with_env(config_lines, env(&[
+            ("MUSIUM_LISTEN", "0.0.0.0:80"),
+            ("MUSIUM_DB_PATH", "/var/lib/musium/db.sqlite3"),
+            ("MUSIUM_AUDIO_VOLUME_CONTROL", "Master"),
+            ("MUSIUM_TRACK_ID", "42"),
+            ("HOME", "/home/user"),
+        ])).unwrap();
+        assert_eq!(&config.listen[..], "0.0.0.0:80");
+        assert_eq!(config.db_path.as_path(), Path::new("/var/lib/musium/db.sqlite3"));
+        assert_eq!(&config.audio_volume_control[..], "Master");
+        assert_eq!(&config.audio_device[..], "UCM404HD 192k");
+
+        match Config::parse_with_env(config_lines, env(&[("MUSIUM_DB_MMAP_SIZE", "lots")])) {
+            Err(Error::InvalidConfigEnv(var, _)) => assert_eq!(var, "MUSIUM_DB_MMAP_SIZE"),
+            _ => panic!("Expected an invalid config error."),
+        }
+    }
+
+    #[test]
+    pub fn config_reload_applies_only_reloadable_settings() {
+        let old = Config::parse([
+            "library_path = /home/user/music",
+            "db_path = /home/user/.local/share/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+        ]).unwrap();
+        let new = Config::parse([
+            "library_path = /home/user/music",
+            "db_path = /var/lib/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+            "exec_now_playing_path = /usr/local/bin/now-playing",
+            "favorite_artist_boost = 3",
+        ]).unwrap();
+
+        let reloaded = old.with_reloadable_from(&new);
+        assert_eq!(reloaded.db_path, old.db_path);
+        assert_eq!(reloaded.exec_now_playing_path, new.exec_now_playing_path);
+        assert_eq!(reloaded.favorite_artist_boost, 3.0);
+        assert_eq!(old.changes_needing_restart(&new), vec!["db_path"]);
+    }
+
+    #[test]
+    pub fn starter_config_can_be_parsed() {
+        let config_str = format_starter_config(
+            "/home/user/music \"flac\"",
+            "/home/user/musium.sqlite3",
+            "HDA Intel PCH",
+            "Master",
+        );
+        let config = Config::parse(config_str.lines()).unwrap();
+        assert_eq!(config.library_path.as_path(), Path::new("/home/user/music \"flac\""));
+        assert_eq!(config.db_path.as_path(), Path::new("/home/user/musium.sqlite3"));
+        assert_eq!(&config.audio_device[..], "HDA Intel PCH");
+        assert_eq!(&config.audio_volume_control[..], "Master");
+        assert_eq!(config.exec_now_playing_path, None);
     }
 }
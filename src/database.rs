@@ -82,7 +82,7 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         , started_at       string  not null unique
         
         -- ISO-8601 time with UTC offset at which we finished playing.
-        -- NULL if the track is still playing.
+        -- NULL if the track is still playing, or if it was skipped.
         , completed_at     string  null     check (started_at < completed_at)
         
         -- References a file from the files table, but there is no foreign key. We want
@@ -108,12 +108,15 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         , disc_number      integer null
         
         -- Source of the listen. Should be either 'musium' if we produced the
-        -- listen, or 'listenbrainz' if we backfilled it from Listenbrainz.
+        -- listen, or 'lastfm' or 'listenbrainz' if we backfilled it from there.
         , source           string  not null
         
         -- ISO-8601 time with UTC offset at which we scrobbled the track to Last.fm.
         -- NULL if the track has not been scrobbled by us.
         , scrobbled_at     string  null     check (started_at < scrobbled_at)
+        
+        -- Migrations 5 through 7 add the `skipped_at`, `listenbrainz_submitted_at`,
+        -- and `utc_offset_seconds` columns.
         );
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
@@ -168,6 +171,239 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         Done => {}
     }
 
+    let sql = r#"
+        -- Listens imported from Listenbrainz, before matching them to tracks. The
+        -- `album_mbid` is the empty string if Listenbrainz does not know the release.
+        create table if not exists listenbrainz_listens
+        ( -- Seconds since epoch.
+          started_at       integer primary key
+        , title            string  not null
+        , track_artist     string  not null
+        , album            string  not null
+        , album_mbid       string  not null
+        -- Duration of the track, if the submitting player included it.
+        , duration_seconds integer null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Decisions that the user made about imported listens that the matcher could
+        -- not match by itself, so we don't ask about them again. When the user picked
+        -- a track, the listen is also in the `listens` table, when they skipped the
+        -- listen, `track_id` is null.
+        create table if not exists match_decisions
+        ( source     string  not null
+          -- Seconds since epoch, the `started_at` of the imported listen.
+        , started_at integer not null
+        , track_id   integer null
+          -- ISO-8601 time with UTC offset at which the user decided.
+        , decided_at string  not null
+        , primary key (source, started_at)
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- For every source that we import listens from, the newest listen up to which
+        -- we fetched the full history. `tools/scrobble.py` only moves this forward
+        -- after a completed fetch, so an interrupted import does not leave a gap.
+        create table if not exists import_progress
+        ( -- Either 'lastfm' or 'listenbrainz'.
+          source         string  primary key
+          -- Seconds since epoch.
+        , imported_until integer not null
+          -- ISO-8601 time with UTC offset at which we last moved `imported_until`.
+        , updated_at     string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Tracks loved on Last.fm, before matching them to tracks. `tools/scrobble.py`
+        -- replaces the contents on every import, so tracks that are no longer loved
+        -- disappear.
+        create table if not exists lastfm_loved_tracks
+        ( title        string  not null
+        , track_artist string  not null
+          -- Seconds since epoch at which the track was loved.
+        , loved_at     integer not null
+        , primary key (title, track_artist)
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- For every track whose loved state we synced with Last.fm, whether it is loved
+        -- there. `tools/scrobble.py` uses this to push only the rating changes, and
+        -- `musium import` records loves that it pulled, so we don't push them back.
+        create table if not exists lastfm_loved_sync
+        ( track_id  integer primary key
+        , loved     integer not null check ((loved = 0) or (loved = 1))
+          -- ISO-8601 time with UTC offset at which we last changed `loved`.
+        , synced_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Smart playlists: a name and a search query with filters, which we evaluate
+        -- against the library whenever the playlist is requested.
+        create table if not exists smart_playlists
+        ( name       string primary key
+        , query      string not null
+          -- ISO-8601 time with UTC offset at which the playlist was last saved.
+        , updated_at string not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Playlists that the user composed by hand, as opposed to smart playlists.
+        -- Names share a namespace with smart playlists.
+        create table if not exists playlists
+        ( id         integer primary key
+        , name       string  not null unique
+          -- ISO-8601 time with UTC offset at which the user created the playlist.
+        , created_at string  not null
+          -- ISO-8601 time with UTC offset at which the user last edited the playlist.
+        , updated_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- The tracks of the playlists. A track can occur in a playlist more than once,
+        -- entries are identified by their position instead. Positions of a playlist
+        -- are consecutive from 0, but we don't enforce uniqueness, because shifting
+        -- positions would temporarily violate it. As for ratings, we don't enforce a
+        -- foreign key to the track.
+        create table if not exists playlist_tracks
+        ( playlist_id integer not null references playlists (id) on delete cascade
+        , position    integer not null
+        , track_id    integer not null
+          -- ISO-8601 time with UTC offset at which the user added the track.
+        , created_at  string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_playlist_tracks_playlist_id_position
+        on playlist_tracks (playlist_id, position);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Named snapshots of the play queue, that the user can restore later.
+        create table if not exists saved_queues
+        ( id         integer primary key
+        , name       string  not null unique
+          -- ISO-8601 time with UTC offset at which the user saved the queue.
+        , created_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create table if not exists saved_queue_tracks
+        ( saved_queue_id integer not null references saved_queues (id) on delete cascade
+          -- Position in the queue, 0 is the track that was playing when saved.
+        , position       integer not null
+        , track_id       integer not null
+        , primary key (saved_queue_id, position)
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
     let sql = r#"
         create table if not exists ratings
         ( id          integer primary key
@@ -209,6 +445,136 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         Done => {}
     }
 
+    let sql = r#"
+        -- Ratings for albums as a whole, independent of the ratings of their tracks.
+        -- See `ratings` for the meaning of the columns.
+        create table if not exists album_ratings
+        ( id          integer primary key
+        , created_at  string  not null unique
+        , album_id    integer not null
+        , rating      integer not null check ((rating >= -1) and (rating <= 2))
+        , source      string not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create unique index if not exists ix_album_ratings_unique_second
+        on album_ratings (cast(strftime('%s', created_at) as integer));
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Artists that the user marked as favorite. Like for ratings, we don't enforce
+        -- a foreign key, so a re-import of the artist does not lose the flag.
+        create table if not exists favorite_artists
+        ( artist_id  integer primary key
+          -- ISO-8601 time with UTC offset at which the user marked the artist.
+        , created_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- User-defined labels such as "workout" or "vinyl-owned", on tracks and on
+        -- albums. As for ratings, we don't enforce a foreign key.
+        create table if not exists track_labels
+        ( label      string  not null
+        , track_id   integer not null
+          -- ISO-8601 time with UTC offset at which the user added the label.
+        , created_at string  not null
+        , primary key (label, track_id)
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create table if not exists album_labels
+        ( label      string  not null
+        , album_id   integer not null
+        , created_at string  not null
+        , primary key (label, album_id)
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Free-text notes on tracks and albums, such as "skip the intro". As for
+        -- ratings, we don't enforce a foreign key.
+        create table if not exists track_notes
+        ( track_id   integer primary key
+        , note       string  not null
+          -- ISO-8601 time with UTC offset at which the user last edited the note.
+        , updated_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create table if not exists album_notes
+        ( album_id   integer primary key
+        , note       string  not null
+        , updated_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
     let sql = r#"
         create table if not exists files
         -- First an id, and properties about the file, but not its contents.
@@ -341,6 +707,27 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- The versions of the schema that we migrated to, see `database_utils`. The
+        -- tables above are version 0, every migration adds a row. Do not change the
+        -- tables above in ways that existing databases would need to follow, add a
+        -- migration instead.
+        create table if not exists schema_version
+        ( version    integer primary key
+          -- ISO-8601 time with UTC offset at which we applied the migration.
+        , applied_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
     let result = match statement.next()? {
         Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
         Done => (),
@@ -348,7 +735,303 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
     Ok(result)
 }
 
-#[derive(Debug)]
+/// Return the version of the schema, 0 if we never applied any migrations.
+pub fn select_schema_version(tx: &mut Transaction) -> Result<i64> {
+    let sql = r#"
+        select coalesce(max(version), 0) from schema_version;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_schema_version' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_schema_version' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn insert_schema_version(tx: &mut Transaction, version: i64, applied_at: &str) -> Result<()> {
+    let sql = r#"
+        insert into schema_version (version, applied_at) values (:version, :applied_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, version)?;
+    statement.bind(2, applied_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_schema_version' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Migration 1: Add a table to persist the word indexes, so we don't have to
+/// rebuild them at startup when the library did not change.
+pub fn migrate_word_indexes(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table word_indexes
+        ( name        string primary key
+          -- Identifies the files that the index was built from, see `MemoryMetaIndex`.
+        , fingerprint string not null
+        , data        blob   not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'migrate_word_indexes' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Return the number of files and the most recent import time. Files are only
+/// ever inserted with a new import time, so together these change whenever the
+/// set of files changes.
+pub fn select_files_fingerprint(tx: &mut Transaction) -> Result<(i64, String)> {
+    let sql = r#"
+        select count(*), coalesce(max(imported_at), '') from files;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_files_fingerprint' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_files_fingerprint' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn select_word_index(tx: &mut Transaction, name: &str, fingerprint: &str) -> Result<Option<Vec<u8>>> {
+    let sql = r#"
+        select data from word_indexes where name = :name and fingerprint = :fingerprint;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, fingerprint)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_word_index' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn insert_or_replace_word_index(tx: &mut Transaction, name: &str, fingerprint: &str, data: &[u8]) -> Result<()> {
+    let sql = r#"
+        insert or replace into word_indexes (name, fingerprint, data)
+        values (:name, :fingerprint, :data);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, fingerprint)?;
+    statement.bind(3, data)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_word_index' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Migration 2: Add a table to persist the full index, so we can load it at
+/// startup instead of building it from the tags, when the library did not change.
+pub fn migrate_index_cache(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table index_cache
+          -- There is only one cached index, the id is always 0.
+        ( id          integer primary key check (id = 0)
+          -- Identifies the files that the index was built from, see `MemoryMetaIndex`.
+        , fingerprint string  not null
+        , data        blob    not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'migrate_index_cache' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn select_index_cache(tx: &mut Transaction, fingerprint: &str) -> Result<Option<Vec<u8>>> {
+    let sql = r#"
+        select data from index_cache where id = 0 and fingerprint = :fingerprint;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, fingerprint)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_index_cache' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn insert_or_replace_index_cache(tx: &mut Transaction, fingerprint: &str, data: &[u8]) -> Result<()> {
+    let sql = r#"
+        insert or replace into index_cache (id, fingerprint, data)
+        values (0, :fingerprint, :data);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, fingerprint)?;
+    statement.bind(2, data)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_index_cache' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Migration 3: Store a detailed waveform next to the overview, see waveform.rs
+/// for the data format. It is null for tracks analyzed before this migration.
+pub fn migrate_waveform_detail(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        alter table waveforms add column detail blob null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'migrate_waveform_detail' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Migration 4: Add a table for mel spectrograms per track, see spectrogram.rs
+/// for the data format. The data is empty for tracks that are too short.
+pub fn migrate_spectrograms(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table spectrograms
+        ( track_id integer primary key
+        , file_id  integer not null references files (id) on delete cascade
+        , data     blob    not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'migrate_spectrograms' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Migration 5: Record the ISO-8601 time with UTC offset at which the track was
+/// skipped, if it was skipped before it played long enough to count as a listen.
+pub fn migrate_listens_skipped_at(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        alter table listens add column skipped_at string null check (started_at < skipped_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'migrate_listens_skipped_at' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Migration 6: Record the ISO-8601 time with UTC offset at which we submitted
+/// the listen to Listenbrainz. NULL if the listen has not been submitted by us.
+/// This is separate from `scrobbled_at`, so we can submit to both services.
+pub fn migrate_listens_listenbrainz_submitted_at(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        alter table listens add column listenbrainz_submitted_at string null
+          check (started_at < listenbrainz_submitted_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'migrate_listens_listenbrainz_submitted_at' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Migration 7: Record the offset of the local time from UTC when the listen
+/// started, in seconds. The timestamps are in UTC, this records what the local
+/// time was, which matters for the time of day. NULL for listens recorded
+/// before this migration, and for imported listens.
+pub fn migrate_listens_utc_offset(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        alter table listens add column utc_offset_seconds integer null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'migrate_listens_utc_offset' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
 pub struct InsertFile<'a> {
     pub filename: &'a str,
     pub mtime: i64,
@@ -521,43 +1204,96 @@ pub fn iter_files<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i
     Ok(result)
 }
 
-/// Iterate all `(field_name, value)` pairs for the given file.
-pub fn iter_file_tags<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, file_id: i64) -> Result<Iter<'i, 'a, (String, String)>> {
+#[derive(Debug)]
+pub struct FileInfo {
+    pub id: i64,
+    pub filename: String,
+    pub mtime: i64,
+    pub streaminfo_channels: i64,
+    pub streaminfo_bits_per_sample: i64,
+    pub streaminfo_num_samples: Option<i64>,
+    pub streaminfo_sample_rate: i64,
+}
+
+pub fn select_file_by_filename(tx: &mut Transaction, filename: &str) -> Result<Option<FileInfo>> {
     let sql = r#"
         select
-          field_name, value
+            id
+          , filename
+          , mtime
+          , streaminfo_channels
+          , streaminfo_bits_per_sample
+          , streaminfo_num_samples
+          , streaminfo_sample_rate
         from
-          tags
+          files
         where
-          file_id = :file_id
-        order by
-          -- We have to order by id, which is increasing with insert order, because some
-          -- tags can occur multiple times, and we have to preserve the order in which
-          -- we found them in the file.
-          id asc;
+          filename = :filename;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, file_id)?;
-    let decode_row = |statement: &Statement| Ok((
-        statement.read(0)?,
-        statement.read(1)?,
-));
-    let result = Iter { statement, decode_row };
-    Ok(result)
-}
-
-pub fn insert_album_thumbnail(tx: &mut Transaction, album_id: i64, file_id: i64, data: &[u8]) -> Result<()> {
-    let sql = r#"
-        insert into thumbnails (album_id, file_id, data)
-        values (:album_id, :file_id, :data)
-        on conflict (album_id) do update set data = :data;
-        "#;
-    let statement = match tx.statements.entry(sql.as_ptr()) {
-        Occupied(entry) => entry.into_mut(),
+    statement.bind(1, filename)?;
+    let decode_row = |statement: &Statement| Ok(FileInfo {
+        id: statement.read(0)?,
+        filename: statement.read(1)?,
+        mtime: statement.read(2)?,
+        streaminfo_channels: statement.read(3)?,
+        streaminfo_bits_per_sample: statement.read(4)?,
+        streaminfo_num_samples: statement.read(5)?,
+        streaminfo_sample_rate: statement.read(6)?,
+    });
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_file_by_filename' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Iterate all `(field_name, value)` pairs for the given file.
+pub fn iter_file_tags<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, file_id: i64) -> Result<Iter<'i, 'a, (String, String)>> {
+    let sql = r#"
+        select
+          field_name, value
+        from
+          tags
+        where
+          file_id = :file_id
+        order by
+          -- We have to order by id, which is increasing with insert order, because some
+          -- tags can occur multiple times, and we have to preserve the order in which
+          -- we found them in the file.
+          id asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, file_id)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_album_thumbnail(tx: &mut Transaction, album_id: i64, file_id: i64, data: &[u8]) -> Result<()> {
+    let sql = r#"
+        insert into thumbnails (album_id, file_id, data)
+        values (:album_id, :file_id, :data)
+        on conflict (album_id) do update set data = :data;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
@@ -613,11 +1349,11 @@ pub fn insert_track_loudness(tx: &mut Transaction, track_id: i64, file_id: i64,
     Ok(result)
 }
 
-pub fn insert_track_waveform(tx: &mut Transaction, track_id: i64, file_id: i64, data: &[u8]) -> Result<()> {
+pub fn insert_track_waveform(tx: &mut Transaction, track_id: i64, file_id: i64, data: &[u8], detail: &[u8]) -> Result<()> {
     let sql = r#"
-        insert into waveforms (track_id, file_id, data)
-        values (:track_id, :file_id, :data)
-        on conflict (track_id) do update set data = :data;
+        insert into waveforms (track_id, file_id, data, detail)
+        values (:track_id, :file_id, :data, :detail)
+        on conflict (track_id) do update set data = :data, detail = :detail;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
@@ -627,6 +1363,7 @@ pub fn insert_track_waveform(tx: &mut Transaction, track_id: i64, file_id: i64,
     statement.bind(1, track_id)?;
     statement.bind(2, file_id)?;
     statement.bind(3, data)?;
+    statement.bind(4, detail)?;
     let result = match statement.next()? {
         Row => panic!("Query 'insert_track_waveform' unexpectedly returned a row."),
         Done => (),
@@ -649,6 +1386,7 @@ pub struct Listen<'a> {
     pub duration_seconds: i64,
     pub track_number: i64,
     pub disc_number: i64,
+    pub utc_offset_seconds: i64,
 }
 
 pub fn insert_listen_started(tx: &mut Transaction, listen: Listen) -> Result<i64> {
@@ -668,6 +1406,7 @@ pub fn insert_listen_started(tx: &mut Transaction, listen: Listen) -> Result<i64
           , duration_seconds
           , track_number
           , disc_number
+          , utc_offset_seconds
           , source
           )
         values
@@ -684,6 +1423,7 @@ pub fn insert_listen_started(tx: &mut Transaction, listen: Listen) -> Result<i64
           , :duration_seconds
           , :track_number
           , :disc_number
+          , :utc_offset_seconds
           , 'musium'
           )
         returning
@@ -707,6 +1447,7 @@ pub fn insert_listen_started(tx: &mut Transaction, listen: Listen) -> Result<i64
     statement.bind(11, listen.duration_seconds)?;
     statement.bind(12, listen.track_number)?;
     statement.bind(13, listen.disc_number)?;
+    statement.bind(14, listen.utc_offset_seconds)?;
     let decode_row = |statement: &Statement| Ok(statement.read(0)?);
     let result = match statement.next()? {
         Row => decode_row(statement)?,
@@ -718,6 +1459,91 @@ pub fn insert_listen_started(tx: &mut Transaction, listen: Listen) -> Result<i64
     Ok(result)
 }
 
+#[derive(Debug)]
+pub struct ImportedListen<'a> {
+    pub started_at: &'a str,
+    pub completed_at: &'a str,
+    pub file_id: i64,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub album_artist_id: i64,
+    pub track_title: &'a str,
+    pub track_artist: &'a str,
+    pub album_title: &'a str,
+    pub album_artist: &'a str,
+    pub duration_seconds: i64,
+    pub track_number: i64,
+    pub disc_number: i64,
+    pub source: &'a str,
+}
+
+/// Insert a listen from an external source, that is already complete.
+///
+/// Listens are unique on the second, if we already have a listen that started
+/// in the same second, then this does nothing.
+pub fn insert_listen_imported(tx: &mut Transaction, listen: ImportedListen) -> Result<()> {
+    let sql = r#"
+        insert into
+          listens
+          ( started_at
+          , completed_at
+          , file_id
+          , track_id
+          , album_id
+          , album_artist_id
+          , track_title
+          , track_artist
+          , album_title
+          , album_artist
+          , duration_seconds
+          , track_number
+          , disc_number
+          , source
+          )
+        values
+          ( :started_at
+          , :completed_at
+          , :file_id
+          , :track_id
+          , :album_id
+          , :album_artist_id
+          , :track_title
+          , :track_artist
+          , :album_title
+          , :album_artist
+          , :duration_seconds
+          , :track_number
+          , :disc_number
+          , :source
+          )
+        on conflict do nothing;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen.started_at)?;
+    statement.bind(2, listen.completed_at)?;
+    statement.bind(3, listen.file_id)?;
+    statement.bind(4, listen.track_id)?;
+    statement.bind(5, listen.album_id)?;
+    statement.bind(6, listen.album_artist_id)?;
+    statement.bind(7, listen.track_title)?;
+    statement.bind(8, listen.track_artist)?;
+    statement.bind(9, listen.album_title)?;
+    statement.bind(10, listen.album_artist)?;
+    statement.bind(11, listen.duration_seconds)?;
+    statement.bind(12, listen.track_number)?;
+    statement.bind(13, listen.disc_number)?;
+    statement.bind(14, listen.source)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_listen_imported' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
 pub fn update_listen_completed(tx: &mut Transaction, listen_id: i64, queue_id: i64, track_id: i64, completed_at: &str) -> Result<()> {
     let sql = r#"
         update listens
@@ -743,16 +1569,42 @@ pub fn update_listen_completed(tx: &mut Transaction, listen_id: i64, queue_id: i
     Ok(result)
 }
 
-pub fn select_album_loudness_lufs(tx: &mut Transaction, album_id: i64) -> Result<Option<f64>> {
+pub fn update_listen_skipped(tx: &mut Transaction, listen_id: i64, queue_id: i64, track_id: i64, skipped_at: &str) -> Result<()> {
     let sql = r#"
-        select bs17704_loudness_lufs from album_loudness where album_id = :album_id;
+        update listens
+          set skipped_at = :skipped_at
+        where
+          id = :listen_id
+          and queue_id = :queue_id
+          and track_id = :track_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, album_id)?;
+    statement.bind(1, skipped_at)?;
+    statement.bind(2, listen_id)?;
+    statement.bind(3, queue_id)?;
+    statement.bind(4, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_skipped' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Return the track id of the listen, to check whether the listen exists.
+pub fn select_listen_track_id(tx: &mut Transaction, listen_id: i64) -> Result<Option<i64>> {
+    let sql = r#"
+        select track_id from listens where id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen_id)?;
     let decode_row = |statement: &Statement| Ok(statement.read(0)?);
     let result = match statement.next()? {
         Row => Some(decode_row(statement)?),
@@ -760,22 +1612,99 @@ pub fn select_album_loudness_lufs(tx: &mut Transaction, album_id: i64) -> Result
     };
     if result.is_some() {
         if statement.next()? != Done {
-            panic!("Query 'select_album_loudness_lufs' should return at most one row.");
+            panic!("Query 'select_listen_track_id' should return at most one row.");
         }
     }
     Ok(result)
 }
 
-pub fn select_track_loudness_lufs(tx: &mut Transaction, track_id: i64) -> Result<Option<f64>> {
+pub fn delete_listen(tx: &mut Transaction, listen_id: i64) -> Result<()> {
     let sql = r#"
-        select bs17704_loudness_lufs from track_loudness where track_id = :track_id;
+        delete from listens where id = :listen_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, track_id)?;
+    statement.bind(1, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_listen' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ListenCorrection<'a> {
+    pub file_id: i64,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub album_artist_id: i64,
+    pub track_title: &'a str,
+    pub album_title: &'a str,
+    pub track_artist: &'a str,
+    pub album_artist: &'a str,
+    pub duration_seconds: i64,
+    pub track_number: i64,
+    pub disc_number: i64,
+    pub listen_id: i64,
+}
+
+/// Point the listen at a different track, for when we recorded or matched the
+/// wrong one. This replaces the track metadata, but not the timestamps.
+pub fn update_listen_track(tx: &mut Transaction, listen: ListenCorrection) -> Result<()> {
+    let sql = r#"
+        update listens
+          set file_id          = :file_id
+            , track_id         = :track_id
+            , album_id         = :album_id
+            , album_artist_id  = :album_artist_id
+            , track_title      = :track_title
+            , album_title      = :album_title
+            , track_artist     = :track_artist
+            , album_artist     = :album_artist
+            , duration_seconds = :duration_seconds
+            , track_number     = :track_number
+            , disc_number      = :disc_number
+        where
+          id = :listen_id
+        ;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen.file_id)?;
+    statement.bind(2, listen.track_id)?;
+    statement.bind(3, listen.album_id)?;
+    statement.bind(4, listen.album_artist_id)?;
+    statement.bind(5, listen.track_title)?;
+    statement.bind(6, listen.album_title)?;
+    statement.bind(7, listen.track_artist)?;
+    statement.bind(8, listen.album_artist)?;
+    statement.bind(9, listen.duration_seconds)?;
+    statement.bind(10, listen.track_number)?;
+    statement.bind(11, listen.disc_number)?;
+    statement.bind(12, listen.listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_track' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn select_album_loudness_lufs(tx: &mut Transaction, album_id: i64) -> Result<Option<f64>> {
+    let sql = r#"
+        select bs17704_loudness_lufs from album_loudness where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
     let decode_row = |statement: &Statement| Ok(statement.read(0)?);
     let result = match statement.next()? {
         Row => Some(decode_row(statement)?),
@@ -783,15 +1712,15 @@ pub fn select_track_loudness_lufs(tx: &mut Transaction, track_id: i64) -> Result
     };
     if result.is_some() {
         if statement.next()? != Done {
-            panic!("Query 'select_track_loudness_lufs' should return at most one row.");
+            panic!("Query 'select_album_loudness_lufs' should return at most one row.");
         }
     }
     Ok(result)
 }
 
-pub fn select_track_waveform(tx: &mut Transaction, track_id: i64) -> Result<Option<Vec<u8>>> {
+pub fn select_track_loudness_lufs(tx: &mut Transaction, track_id: i64) -> Result<Option<f64>> {
     let sql = r#"
-        select data from waveforms where track_id = :track_id;
+        select bs17704_loudness_lufs from track_loudness where track_id = :track_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
@@ -806,16 +1735,16 @@ pub fn select_track_waveform(tx: &mut Transaction, track_id: i64) -> Result<Opti
     };
     if result.is_some() {
         if statement.next()? != Done {
-            panic!("Query 'select_track_waveform' should return at most one row.");
+            panic!("Query 'select_track_loudness_lufs' should return at most one row.");
         }
     }
     Ok(result)
 }
 
-/// Return the sum of the sizes (in bytes) of all thumbnails.
-pub fn select_thumbnails_count_and_total_size(tx: &mut Transaction) -> Result<(i64, i64)> {
+/// Return the loudness of all albums that have one, ordered by album id.
+pub fn iter_album_loudness<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, f64)>> {
     let sql = r#"
-        select count(*), sum(length(data)) from thumbnails;
+        select album_id, bs17704_loudness_lufs from album_loudness order by album_id asc;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
@@ -826,63 +1755,295 @@ pub fn select_thumbnails_count_and_total_size(tx: &mut Transaction) -> Result<(i
         statement.read(0)?,
         statement.read(1)?,
 ));
-    let result = match statement.next()? {
-        Row => decode_row(statement)?,
-        Done => panic!("Query 'select_thumbnails_count_and_total_size' should return exactly one row."),
-    };
-    if statement.next()? != Done {
-        panic!("Query 'select_thumbnails_count_and_total_size' should return exactly one row.");
-    }
+    let result = Iter { statement, decode_row };
     Ok(result)
 }
 
-#[derive(Debug)]
-pub struct Thumbnail {
-    pub album_id: i64,
-    pub data: Vec<u8>,
-}
-
-pub fn iter_thumbnails<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, Thumbnail>> {
+/// Return the loudness of all tracks that have one, ordered by track id.
+pub fn iter_track_loudness<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, f64)>> {
     let sql = r#"
-        select album_id, data from thumbnails;
+        select track_id, bs17704_loudness_lufs from track_loudness order by track_id asc;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let decode_row = |statement: &Statement| Ok(Thumbnail {
-        album_id: statement.read(0)?,
-        data: statement.read(1)?,
-    });
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
     let result = Iter { statement, decode_row };
     Ok(result)
 }
 
-/// Return whether a thumbnail for the album exists (1 if it does, 0 otherwise).
-pub fn select_thumbnail_exists(tx: &mut Transaction, album_id: i64) -> Result<i64> {
+pub fn select_track_waveform(tx: &mut Transaction, track_id: i64) -> Result<Option<Vec<u8>>> {
     let sql = r#"
-        select count(*) from thumbnails where album_id = :album_id;
+        select data from waveforms where track_id = :track_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, album_id)?;
+    statement.bind(1, track_id)?;
     let decode_row = |statement: &Statement| Ok(statement.read(0)?);
     let result = match statement.next()? {
-        Row => decode_row(statement)?,
-        Done => panic!("Query 'select_thumbnail_exists' should return exactly one row."),
+        Row => Some(decode_row(statement)?),
+        Done => None,
     };
-    if statement.next()? != Done {
-        panic!("Query 'select_thumbnail_exists' should return exactly one row.");
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_track_waveform' should return at most one row.");
+        }
     }
     Ok(result)
 }
 
-/// For every album, return the earliest listen in the listens table.
-///
+/// Return the detailed waveform, if the track was analyzed after we added it.
+pub fn select_track_waveform_detail(tx: &mut Transaction, track_id: i64) -> Result<Option<Vec<u8>>> {
+    let sql = r#"
+        select detail from waveforms where track_id = :track_id and detail is not null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_track_waveform_detail' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn insert_track_spectrogram(tx: &mut Transaction, track_id: i64, file_id: i64, data: &[u8]) -> Result<()> {
+    let sql = r#"
+        insert into spectrograms (track_id, file_id, data)
+        values (:track_id, :file_id, :data)
+        on conflict (track_id) do update set data = :data;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, file_id)?;
+    statement.bind(3, data)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_track_spectrogram' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn select_track_spectrogram(tx: &mut Transaction, track_id: i64) -> Result<Option<Vec<u8>>> {
+    let sql = r#"
+        select data from spectrograms where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_track_spectrogram' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Return the sum of the sizes (in bytes) of all thumbnails.
+pub fn select_thumbnails_count_and_total_size(tx: &mut Transaction) -> Result<(i64, i64)> {
+    let sql = r#"
+        select count(*), sum(length(data)) from thumbnails;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_thumbnails_count_and_total_size' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_thumbnails_count_and_total_size' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct Thumbnail {
+    pub album_id: i64,
+    pub data: Vec<u8>,
+}
+
+pub fn iter_thumbnails<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, Thumbnail>> {
+    let sql = r#"
+        select album_id, data from thumbnails;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(Thumbnail {
+        album_id: statement.read(0)?,
+        data: statement.read(1)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn select_thumbnail(tx: &mut Transaction, album_id: i64) -> Result<Option<Vec<u8>>> {
+    let sql = r#"
+        select data from thumbnails where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_thumbnail' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Return the size (in bytes) of the album's thumbnail, if it has one.
+pub fn select_thumbnail_size(tx: &mut Transaction, album_id: i64) -> Result<Option<i64>> {
+    let sql = r#"
+        select length(data) from thumbnails where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_thumbnail_size' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Return whether a thumbnail for the album exists (1 if it does, 0 otherwise).
+pub fn select_thumbnail_exists(tx: &mut Transaction, album_id: i64) -> Result<i64> {
+    let sql = r#"
+        select count(*) from thumbnails where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_thumbnail_exists' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_thumbnail_exists' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// Return the MusicBrainz release id from the tags of the file, if it has one.
+pub fn select_file_musicbrainz_albumid(tx: &mut Transaction, file_id: i64) -> Result<Option<String>> {
+    let sql = r#"
+        select value from tags
+        where file_id = :file_id and field_name = 'musicbrainz_albumid'
+        limit 1;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, file_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_file_musicbrainz_albumid' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Return the number of completed listens of the track, and the start time of
+/// the most recent one, as ISO-8601 string.
+pub fn select_track_listen_stats(tx: &mut Transaction, track_id: i64) -> Result<(i64, Option<String>)> {
+    let sql = r#"
+        select
+          count(*), max(started_at)
+        from
+          listens
+        where
+          track_id = :track_id
+          and completed_at is not null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_track_listen_stats' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_track_listen_stats' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// For every album, return the earliest listen in the listens table.
+///
 /// Yields tuples `(album_id, started_at_iso8601)`.
 pub fn iter_album_first_listens<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, String)>> {
     let sql = r#"
@@ -909,56 +2070,2004 @@ pub fn iter_album_first_listens<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) ->
     Ok(result)
 }
 
-#[derive(Debug)]
-pub struct ListenAt {
-    pub track_id: i64,
-    pub started_at_second: i64,
+#[derive(Debug)]
+pub struct ListenAt {
+    pub track_id: i64,
+    pub started_at_second: i64,
+    pub is_skipped: i64,
+    pub utc_offset_seconds: Option<i64>,
+}
+
+/// Iterate the completed and skipped listens in chronological order.
+///
+/// Visits only the listens whose timestamp is after the minimum start second
+/// (in POSIX time), exclusive, so this can be used for incremental import.
+/// `is_skipped` is 1 for skipped listens, and 0 for completed ones.
+pub fn iter_listens_since<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, min_started_second: i64) -> Result<Iter<'i, 'a, ListenAt>> {
+    let sql = r#"
+        select
+            track_id,
+            -- Note that we have an index on this expression, so this should be just an
+            -- index scan.
+            cast(strftime('%s', started_at) as integer) as started_at_second,
+            skipped_at is not null as is_skipped,
+            utc_offset_seconds
+        from
+            listens
+        where
+            (completed_at is not null or skipped_at is not null)
+            and (started_at_second > :min_started_second)
+        order by
+            started_at_second asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, min_started_second)?;
+    let decode_row = |statement: &Statement| Ok(ListenAt {
+        track_id: statement.read(0)?,
+        started_at_second: statement.read(1)?,
+        is_skipped: statement.read(2)?,
+        utc_offset_seconds: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ListenInRange {
+    pub track_id: i64,
+    pub album_id: i64,
+    pub started_at_second: i64,
+    pub duration_seconds: i64,
+}
+
+/// Iterate the completed listens that started in the given range, in
+/// chronological order.
+///
+/// The range is in POSIX seconds, the minimum is inclusive, the maximum is
+/// exclusive.
+pub fn iter_listens_between<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, min_started_second: i64, max_started_second: i64) -> Result<Iter<'i, 'a, ListenInRange>> {
+    let sql = r#"
+        select
+            track_id,
+            album_id,
+            cast(strftime('%s', started_at) as integer) as started_at_second,
+            duration_seconds
+        from
+            listens
+        where
+            completed_at is not null
+            and (started_at_second >= :min_started_second)
+            and (started_at_second < :max_started_second)
+        order by
+            started_at_second asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, min_started_second)?;
+    statement.bind(2, max_started_second)?;
+    let decode_row = |statement: &Statement| Ok(ListenInRange {
+        track_id: statement.read(0)?,
+        album_id: statement.read(1)?,
+        started_at_second: statement.read(2)?,
+        duration_seconds: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ListenOnDay {
+    pub year: i64,
+    pub started_at: String,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub track_title: String,
+    pub track_artist: String,
+    pub album_title: String,
+}
+
+/// Iterate the completed listens on the given day of the year in local time,
+/// in years before the given year, most recent year first.
+///
+/// The day is formatted as `MM-DD`. This does a full table scan, but with tens
+/// of thousands of listens, that is still fast enough to do on request.
+pub fn iter_listens_on_day<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, month_day: &str, before_year: i64) -> Result<Iter<'i, 'a, ListenOnDay>> {
+    let sql = r#"
+        select
+            cast(strftime('%Y', started_at, 'localtime') as integer) as year,
+            started_at,
+            track_id,
+            album_id,
+            track_title,
+            track_artist,
+            album_title
+        from
+            listens
+        where
+            completed_at is not null
+            and strftime('%m-%d', started_at, 'localtime') = :month_day
+            and year < :before_year
+        order by
+            year desc,
+            started_at asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, month_day)?;
+    statement.bind(2, before_year)?;
+    let decode_row = |statement: &Statement| Ok(ListenOnDay {
+        year: statement.read(0)?,
+        started_at: statement.read(1)?,
+        track_id: statement.read(2)?,
+        album_id: statement.read(3)?,
+        track_title: statement.read(4)?,
+        track_artist: statement.read(5)?,
+        album_title: statement.read(6)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ListenRecent {
+    pub id: i64,
+    pub started_at: String,
+    pub started_at_second: i64,
+    pub completed_at: Option<String>,
+    pub skipped_at: Option<String>,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub track_title: String,
+    pub album_title: String,
+    pub track_artist: String,
+    pub duration_seconds: i64,
+    pub source: String,
+}
+
+/// Iterate the most recent listens that started before the given second since
+/// epoch, most recent first. This includes skipped listens and the listen that
+/// is currently playing.
+pub fn iter_listens_before<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, before_second: i64, limit: i64) -> Result<Iter<'i, 'a, ListenRecent>> {
+    let sql = r#"
+        select
+            id,
+            started_at,
+            cast(strftime('%s', started_at) as integer) as started_at_second,
+            completed_at,
+            skipped_at,
+            track_id,
+            album_id,
+            track_title,
+            album_title,
+            track_artist,
+            duration_seconds,
+            source
+        from
+            listens
+        where
+            cast(strftime('%s', started_at) as integer) < :before_second
+        order by
+            cast(strftime('%s', started_at) as integer) desc
+        limit
+            :limit;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, before_second)?;
+    statement.bind(2, limit)?;
+    let decode_row = |statement: &Statement| Ok(ListenRecent {
+        id: statement.read(0)?,
+        started_at: statement.read(1)?,
+        started_at_second: statement.read(2)?,
+        completed_at: statement.read(3)?,
+        skipped_at: statement.read(4)?,
+        track_id: statement.read(5)?,
+        album_id: statement.read(6)?,
+        track_title: statement.read(7)?,
+        album_title: statement.read(8)?,
+        track_artist: statement.read(9)?,
+        duration_seconds: statement.read(10)?,
+        source: statement.read(11)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Insert a rating for a given track.
+///
+/// When the `created_at` timestamp is not unique, this replaces the previous
+/// rating that was present for that timestamp. This might happen when the user
+/// edits the rating in quick succession; then we only store the last write.
+pub fn insert_or_replace_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          ratings (track_id, created_at, rating, source)
+        values
+          (:track_id, :created_at, :rating, 'musium');
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_rating' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Backfill a rating for a given track.
+///
+/// The timestamp must be unique on the second.
+pub fn insert_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64, source: &str) -> Result<()> {
+    let sql = r#"
+        insert into
+          ratings (track_id, created_at, rating, source)
+        values
+          (:track_id, :created_at, :rating, :source);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    statement.bind(4, source)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_rating' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct TrackRating {
+    pub id: i64,
+    pub track_id: i64,
+    pub rating: i64,
+    pub created_at: String,
+    pub source: String,
+}
+
+pub fn iter_ratings<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, TrackRating>> {
+    let sql = r#"
+        select
+            id
+          , track_id
+          , rating
+          , created_at
+          , source
+        from
+          ratings
+        order by
+          -- Order by ascending creation time to ensure we can clamp to rating ranges,
+          -- should we need to. We have an index on this expression.
+          cast(strftime('%s', created_at) as integer) asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(TrackRating {
+        id: statement.read(0)?,
+        track_id: statement.read(1)?,
+        rating: statement.read(2)?,
+        created_at: statement.read(3)?,
+        source: statement.read(4)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Like `insert_or_replace_rating`, but for albums.
+pub fn insert_or_replace_album_rating(tx: &mut Transaction, album_id: i64, created_at: &str, rating: i64) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          album_ratings (album_id, created_at, rating, source)
+        values
+          (:album_id, :created_at, :rating, 'musium');
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_album_rating' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct AlbumRating {
+    pub id: i64,
+    pub album_id: i64,
+    pub rating: i64,
+    pub created_at: String,
+    pub source: String,
+}
+
+pub fn iter_album_ratings<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, AlbumRating>> {
+    let sql = r#"
+        select
+            id
+          , album_id
+          , rating
+          , created_at
+          , source
+        from
+          album_ratings
+        order by
+          cast(strftime('%s', created_at) as integer) asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(AlbumRating {
+        id: statement.read(0)?,
+        album_id: statement.read(1)?,
+        rating: statement.read(2)?,
+        created_at: statement.read(3)?,
+        source: statement.read(4)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_favorite_artist(tx: &mut Transaction, artist_id: i64, created_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          favorite_artists (artist_id, created_at)
+        values
+          (:artist_id, :created_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, artist_id)?;
+    statement.bind(2, created_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_favorite_artist' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_favorite_artist(tx: &mut Transaction, artist_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from favorite_artists where artist_id = :artist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, artist_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_favorite_artist' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct FavoriteArtist {
+    pub artist_id: i64,
+    pub created_at: String,
+}
+
+pub fn iter_favorite_artists<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, FavoriteArtist>> {
+    let sql = r#"
+        select
+            artist_id
+          , created_at
+        from
+          favorite_artists
+        order by
+          created_at asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(FavoriteArtist {
+        artist_id: statement.read(0)?,
+        created_at: statement.read(1)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_track_label(tx: &mut Transaction, label: &str, track_id: i64, created_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          track_labels (label, track_id, created_at)
+        values
+          (:label, :track_id, :created_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, label)?;
+    statement.bind(2, track_id)?;
+    statement.bind(3, created_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_track_label' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_track_label(tx: &mut Transaction, label: &str, track_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from track_labels where label = :label and track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, label)?;
+    statement.bind(2, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_track_label' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct TrackLabel {
+    pub label: String,
+    pub track_id: i64,
+    pub created_at: String,
+}
+
+pub fn iter_track_labels<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, TrackLabel>> {
+    let sql = r#"
+        select
+            label
+          , track_id
+          , created_at
+        from
+          track_labels;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(TrackLabel {
+        label: statement.read(0)?,
+        track_id: statement.read(1)?,
+        created_at: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_album_label(tx: &mut Transaction, label: &str, album_id: i64, created_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          album_labels (label, album_id, created_at)
+        values
+          (:label, :album_id, :created_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, label)?;
+    statement.bind(2, album_id)?;
+    statement.bind(3, created_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_album_label' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_album_label(tx: &mut Transaction, label: &str, album_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from album_labels where label = :label and album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, label)?;
+    statement.bind(2, album_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_album_label' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct AlbumLabel {
+    pub label: String,
+    pub album_id: i64,
+    pub created_at: String,
+}
+
+pub fn iter_album_labels<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, AlbumLabel>> {
+    let sql = r#"
+        select
+            label
+          , album_id
+          , created_at
+        from
+          album_labels;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(AlbumLabel {
+        label: statement.read(0)?,
+        album_id: statement.read(1)?,
+        created_at: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_or_replace_track_note(tx: &mut Transaction, track_id: i64, note: &str, updated_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          track_notes (track_id, note, updated_at)
+        values
+          (:track_id, :note, :updated_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, note)?;
+    statement.bind(3, updated_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_track_note' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_track_note(tx: &mut Transaction, track_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from track_notes where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_track_note' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct TrackNote {
+    pub track_id: i64,
+    pub note: String,
+    pub updated_at: String,
+}
+
+pub fn iter_track_notes<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, TrackNote>> {
+    let sql = r#"
+        select
+            track_id
+          , note
+          , updated_at
+        from
+          track_notes;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(TrackNote {
+        track_id: statement.read(0)?,
+        note: statement.read(1)?,
+        updated_at: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_or_replace_album_note(tx: &mut Transaction, album_id: i64, note: &str, updated_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          album_notes (album_id, note, updated_at)
+        values
+          (:album_id, :note, :updated_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, note)?;
+    statement.bind(3, updated_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_album_note' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_album_note(tx: &mut Transaction, album_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from album_notes where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_album_note' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct AlbumNote {
+    pub album_id: i64,
+    pub note: String,
+    pub updated_at: String,
+}
+
+pub fn iter_album_notes<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, AlbumNote>> {
+    let sql = r#"
+        select
+            album_id
+          , note
+          , updated_at
+        from
+          album_notes;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(AlbumNote {
+        album_id: statement.read(0)?,
+        note: statement.read(1)?,
+        updated_at: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Like `insert_rating_if_free`, but for albums.
+pub fn insert_album_rating_if_free(tx: &mut Transaction, album_id: i64, created_at: &str, rating: i64, source: &str) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          album_ratings (album_id, created_at, rating, source)
+        values
+          (:album_id, :created_at, :rating, :source);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    statement.bind(4, source)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_album_rating_if_free' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Insert a note imported from elsewhere, unless we already have a note for
+/// the track that was edited at the same time or later.
+pub fn insert_track_note_if_newer(tx: &mut Transaction, track_id: i64, note: &str, updated_at: &str) -> Result<()> {
+    let sql = r#"
+        insert into track_notes (track_id, note, updated_at)
+        values (:track_id, :note, :updated_at)
+        on conflict (track_id) do update set note = :note, updated_at = :updated_at
+        where julianday(updated_at) < julianday(:updated_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, note)?;
+    statement.bind(3, updated_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_track_note_if_newer' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Like `insert_track_note_if_newer`, but for albums.
+pub fn insert_album_note_if_newer(tx: &mut Transaction, album_id: i64, note: &str, updated_at: &str) -> Result<()> {
+    let sql = r#"
+        insert into album_notes (album_id, note, updated_at)
+        values (:album_id, :note, :updated_at)
+        on conflict (album_id) do update set note = :note, updated_at = :updated_at
+        where julianday(updated_at) < julianday(:updated_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, note)?;
+    statement.bind(3, updated_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_album_note_if_newer' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct LatestRating {
+    pub rating: i64,
+    pub created_at_second: i64,
+}
+
+/// Return the most recent rating of the track, if it has one.
+pub fn select_latest_rating(tx: &mut Transaction, track_id: i64) -> Result<Option<LatestRating>> {
+    let sql = r#"
+        select
+            rating
+          , cast(strftime('%s', created_at) as integer) as created_at_second
+        from
+          ratings
+        where
+          track_id = :track_id
+        order by
+          cast(strftime('%s', created_at) as integer) desc
+        limit
+          1;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok(LatestRating {
+        rating: statement.read(0)?,
+        created_at_second: statement.read(1)?,
+    });
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_latest_rating' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Insert a rating imported from elsewhere, unless we already have a rating in
+/// that second.
+pub fn insert_rating_if_free(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64, source: &str) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          ratings (track_id, created_at, rating, source)
+        values
+          (:track_id, :created_at, :rating, :source);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    statement.bind(4, source)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_rating_if_free' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ListenExport {
+    pub id: i64,
+    pub started_at: String,
+    pub completed_at: Option<String>,
+    pub skipped_at: Option<String>,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub album_artist_id: i64,
+    pub track_title: String,
+    pub album_title: String,
+    pub track_artist: String,
+    pub album_artist: String,
+    pub duration_seconds: i64,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub source: String,
+    pub scrobbled_at: Option<String>,
+    pub listenbrainz_submitted_at: Option<String>,
+}
+
+/// Iterate the entire listening history, for exporting it.
+pub fn iter_listens_export<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, ListenExport>> {
+    let sql = r#"
+        select
+            id
+          , started_at
+          , completed_at
+          , skipped_at
+          , track_id
+          , album_id
+          , album_artist_id
+          , track_title
+          , album_title
+          , track_artist
+          , album_artist
+          , duration_seconds
+          , track_number
+          , disc_number
+          , source
+          , scrobbled_at
+          , listenbrainz_submitted_at
+        from
+          listens
+        order by
+          id asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(ListenExport {
+        id: statement.read(0)?,
+        started_at: statement.read(1)?,
+        completed_at: statement.read(2)?,
+        skipped_at: statement.read(3)?,
+        track_id: statement.read(4)?,
+        album_id: statement.read(5)?,
+        album_artist_id: statement.read(6)?,
+        track_title: statement.read(7)?,
+        album_title: statement.read(8)?,
+        track_artist: statement.read(9)?,
+        album_artist: statement.read(10)?,
+        duration_seconds: statement.read(11)?,
+        track_number: statement.read(12)?,
+        disc_number: statement.read(13)?,
+        source: statement.read(14)?,
+        scrobbled_at: statement.read(15)?,
+        listenbrainz_submitted_at: statement.read(16)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct LastfmListenInsert<'a> {
+    pub started_at: i64,
+    pub title: &'a str,
+    pub track_artist: &'a str,
+    pub album: &'a str,
+    pub album_mbid: &'a str,
+}
+
+/// Insert a listen into the `lastfm_listens` staging table, if we don't have
+/// one at that time already.
+pub fn insert_lastfm_listen(tx: &mut Transaction, listen: LastfmListenInsert) -> Result<()> {
+    let sql = r#"
+        insert into
+          lastfm_listens
+          ( started_at
+          , title
+          , track_artist
+          , album
+          , album_mbid
+          )
+        values
+          ( :started_at
+          , :title
+          , :track_artist
+          , :album
+          , :album_mbid
+          )
+        on conflict do nothing;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen.started_at)?;
+    statement.bind(2, listen.title)?;
+    statement.bind(3, listen.track_artist)?;
+    statement.bind(4, listen.album)?;
+    statement.bind(5, listen.album_mbid)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_lastfm_listen' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Return the number of listens imported from the given source (`lastfm` or
+/// `listenbrainz`) that started at the given second since epoch.
+pub fn count_imported_listens_at(tx: &mut Transaction, source: &str, started_at: i64) -> Result<i64> {
+    let sql = r#"
+        select
+          (select count(*) from lastfm_listens
+           where :source = 'lastfm' and started_at = :started_at)
+          + (select count(*) from listenbrainz_listens
+           where :source = 'listenbrainz' and started_at = :started_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, source)?;
+    statement.bind(2, started_at)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'count_imported_listens_at' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'count_imported_listens_at' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct MatchDecision<'a> {
+    pub source: &'a str,
+    pub started_at: i64,
+    pub track_id: Option<i64>,
+    pub decided_at: &'a str,
+}
+
+pub fn insert_match_decision(tx: &mut Transaction, decision: MatchDecision) -> Result<()> {
+    let sql = r#"
+        insert into
+          match_decisions
+          ( source
+          , started_at
+          , track_id
+          , decided_at
+          )
+        values
+          ( :source
+          , :started_at
+          , :track_id
+          , :decided_at
+          )
+        on conflict (source, started_at) do update set
+          track_id = :track_id, decided_at = :decided_at;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, decision.source)?;
+    statement.bind(2, decision.started_at)?;
+    statement.bind(3, decision.track_id)?;
+    statement.bind(4, decision.decided_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_match_decision' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Return the number of listens that started in the given range of seconds
+/// since epoch, inclusive lower bound, exclusive upper bound.
+pub fn count_listens_between(tx: &mut Transaction, min_second: i64, max_second: i64) -> Result<i64> {
+    let sql = r#"
+        select
+          count(*)
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :min_second
+          and cast(strftime('%s', started_at) as integer) < :max_second;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, min_second)?;
+    statement.bind(2, max_second)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'count_listens_between' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'count_listens_between' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct LastfmLovedTrack {
+    pub title: String,
+    pub track_artist: String,
+    pub loved_at: i64,
+}
+
+pub fn iter_lastfm_loved_tracks<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, LastfmLovedTrack>> {
+    let sql = r#"
+        select
+            title
+          , track_artist
+          , loved_at
+        from
+          lastfm_loved_tracks
+        order by
+          loved_at asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(LastfmLovedTrack {
+        title: statement.read(0)?,
+        track_artist: statement.read(1)?,
+        loved_at: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn select_lastfm_loved(tx: &mut Transaction, track_id: i64) -> Result<Option<i64>> {
+    let sql = r#"
+        select loved from lastfm_loved_sync where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_lastfm_loved' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn insert_or_replace_lastfm_loved(tx: &mut Transaction, track_id: i64, loved: i64, synced_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          lastfm_loved_sync (track_id, loved, synced_at)
+        values
+          (:track_id, :loved, :synced_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, loved)?;
+    statement.bind(3, synced_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_lastfm_loved' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct LastfmListen {
+    pub started_at: i64,
+    pub title: String,
+    pub track_artist: String,
+    pub album: String,
+    pub album_mbid: String,
+}
+
+/// Iterate all listens that exist in the `lastfm_listens` table but not in the
+/// `listens` table itself, and that the user did not skip.
+pub fn iter_lastfm_missing_listens<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, LastfmListen>> {
+    let sql = r#"
+        select
+            started_at
+          , title
+          , track_artist
+          , album
+          , album_mbid
+        from
+          lastfm_listens
+        where
+          not exists (
+            select
+              1
+            from
+              listens
+            where
+              cast(strftime('%s', started_at) as integer) = lastfm_listens.started_at
+          )
+          and not exists (
+            select
+              1
+            from
+              match_decisions
+            where
+              source = 'lastfm' and started_at = lastfm_listens.started_at
+          )
+        order by
+          started_at desc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(LastfmListen {
+        started_at: statement.read(0)?,
+        title: statement.read(1)?,
+        track_artist: statement.read(2)?,
+        album: statement.read(3)?,
+        album_mbid: statement.read(4)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ListenbrainzListen {
+    pub started_at: i64,
+    pub title: String,
+    pub track_artist: String,
+    pub album: String,
+    pub album_mbid: String,
+    pub duration_seconds: Option<i64>,
+}
+
+/// Iterate all listens that exist in the `listenbrainz_listens` table but not
+/// in the `listens` table itself, and that the user did not skip.
+pub fn iter_listenbrainz_missing_listens<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, ListenbrainzListen>> {
+    let sql = r#"
+        select
+            started_at
+          , title
+          , track_artist
+          , album
+          , album_mbid
+          , duration_seconds
+        from
+          listenbrainz_listens
+        where
+          not exists (
+            select
+              1
+            from
+              listens
+            where
+              cast(strftime('%s', started_at) as integer) = listenbrainz_listens.started_at
+          )
+          and not exists (
+            select
+              1
+            from
+              match_decisions
+            where
+              source = 'listenbrainz' and started_at = listenbrainz_listens.started_at
+          )
+        order by
+          started_at desc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(ListenbrainzListen {
+        started_at: statement.read(0)?,
+        title: statement.read(1)?,
+        track_artist: statement.read(2)?,
+        album: statement.read(3)?,
+        album_mbid: statement.read(4)?,
+        duration_seconds: statement.read(5)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct SmartPlaylist {
+    pub name: String,
+    pub query: String,
+    pub updated_at: String,
+}
+
+pub fn iter_smart_playlists<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, SmartPlaylist>> {
+    let sql = r#"
+        select
+            name
+          , query
+          , updated_at
+        from
+          smart_playlists
+        order by
+          name asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(SmartPlaylist {
+        name: statement.read(0)?,
+        query: statement.read(1)?,
+        updated_at: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn select_smart_playlist_query(tx: &mut Transaction, name: &str) -> Result<Option<String>> {
+    let sql = r#"
+        select query from smart_playlists where name = :name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_smart_playlist_query' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn insert_or_replace_smart_playlist(tx: &mut Transaction, name: &str, query: &str, updated_at: &str) -> Result<()> {
+    let sql = r#"
+        insert into smart_playlists (name, query, updated_at)
+        values (:name, :query, :updated_at)
+        on conflict (name) do update set query = :query, updated_at = :updated_at;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, query)?;
+    statement.bind(3, updated_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_smart_playlist' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Like `insert_track_note_if_newer`, but for smart playlists.
+pub fn insert_smart_playlist_if_newer(tx: &mut Transaction, name: &str, query: &str, updated_at: &str) -> Result<()> {
+    let sql = r#"
+        insert into smart_playlists (name, query, updated_at)
+        values (:name, :query, :updated_at)
+        on conflict (name) do update set query = :query, updated_at = :updated_at
+        where julianday(updated_at) < julianday(:updated_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, query)?;
+    statement.bind(3, updated_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_smart_playlist_if_newer' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_smart_playlist(tx: &mut Transaction, name: &str) -> Result<()> {
+    let sql = r#"
+        delete from smart_playlists where name = :name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_smart_playlist' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct Playlist {
+    pub id: i64,
+    pub name: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub track_count: i64,
+}
+
+/// Iterate the playlists, with the number of tracks that they have.
+pub fn iter_playlists<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, Playlist>> {
+    let sql = r#"
+        select
+            playlists.id
+          , name
+          , playlists.created_at
+          , updated_at
+          , count(playlist_tracks.position) as track_count
+        from
+          playlists
+          left join playlist_tracks on playlist_tracks.playlist_id = playlists.id
+        group by
+          playlists.id
+        order by
+          name asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(Playlist {
+        id: statement.read(0)?,
+        name: statement.read(1)?,
+        created_at: statement.read(2)?,
+        updated_at: statement.read(3)?,
+        track_count: statement.read(4)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn select_playlist_id(tx: &mut Transaction, name: &str) -> Result<Option<i64>> {
+    let sql = r#"
+        select id from playlists where name = :name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_playlist_id' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn insert_playlist(tx: &mut Transaction, name: &str, created_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          playlists (name, created_at, updated_at)
+        values
+          (:name, :created_at, :created_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, created_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_playlist' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_playlist(tx: &mut Transaction, name: &str) -> Result<()> {
+    let sql = r#"
+        delete from playlists where name = :name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_playlist' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn update_playlist_updated_at(tx: &mut Transaction, playlist_id: i64, updated_at: &str) -> Result<()> {
+    let sql = r#"
+        update playlists set updated_at = :updated_at where id = :playlist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, updated_at)?;
+    statement.bind(2, playlist_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_playlist_updated_at' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct PlaylistTrack {
+    pub position: i64,
+    pub track_id: i64,
+}
+
+pub fn iter_playlist_tracks<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, playlist_id: i64) -> Result<Iter<'i, 'a, PlaylistTrack>> {
+    let sql = r#"
+        select
+            position
+          , track_id
+        from
+          playlist_tracks
+        where
+          playlist_id = :playlist_id
+        order by
+          position asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    let decode_row = |statement: &Statement| Ok(PlaylistTrack {
+        position: statement.read(0)?,
+        track_id: statement.read(1)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn count_playlist_tracks(tx: &mut Transaction, playlist_id: i64) -> Result<i64> {
+    let sql = r#"
+        select count(*) from playlist_tracks where playlist_id = :playlist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'count_playlist_tracks' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'count_playlist_tracks' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn update_playlist_track_position(tx: &mut Transaction, playlist_id: i64, old_position: i64, new_position: i64) -> Result<()> {
+    let sql = r#"
+        update playlist_tracks
+        set position = :new_position
+        where playlist_id = :playlist_id and position = :old_position;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, new_position)?;
+    statement.bind(2, playlist_id)?;
+    statement.bind(3, old_position)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_playlist_track_position' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Add `delta` to the positions of the entries at or after `position`, to make
+/// room for an insertion, or to close the gap after a removal.
+pub fn shift_playlist_tracks(tx: &mut Transaction, playlist_id: i64, position: i64, delta: i64) -> Result<()> {
+    let sql = r#"
+        update playlist_tracks
+        set position = position + :delta
+        where playlist_id = :playlist_id and position >= :position;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, delta)?;
+    statement.bind(2, playlist_id)?;
+    statement.bind(3, position)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'shift_playlist_tracks' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_playlist_track(tx: &mut Transaction, playlist_id: i64, position: i64, track_id: i64, created_at: &str) -> Result<()> {
+    let sql = r#"
+        insert into
+          playlist_tracks (playlist_id, position, track_id, created_at)
+        values
+          (:playlist_id, :position, :track_id, :created_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    statement.bind(2, position)?;
+    statement.bind(3, track_id)?;
+    statement.bind(4, created_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_playlist_track' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_playlist_track(tx: &mut Transaction, playlist_id: i64, position: i64) -> Result<()> {
+    let sql = r#"
+        delete from playlist_tracks where playlist_id = :playlist_id and position = :position;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    statement.bind(2, position)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_playlist_track' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct SavedQueue {
+    pub id: i64,
+    pub name: String,
+    pub created_at: String,
+    pub track_count: i64,
+}
+
+pub fn iter_saved_queues<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, SavedQueue>> {
+    let sql = r#"
+        select
+            saved_queues.id
+          , name
+          , created_at
+          , count(saved_queue_tracks.position) as track_count
+        from
+          saved_queues
+          left join saved_queue_tracks on saved_queue_tracks.saved_queue_id = saved_queues.id
+        group by
+          saved_queues.id
+        order by
+          name asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(SavedQueue {
+        id: statement.read(0)?,
+        name: statement.read(1)?,
+        created_at: statement.read(2)?,
+        track_count: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn select_saved_queue_id(tx: &mut Transaction, name: &str) -> Result<Option<i64>> {
+    let sql = r#"
+        select id from saved_queues where name = :name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_saved_queue_id' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn insert_saved_queue(tx: &mut Transaction, name: &str, created_at: &str) -> Result<()> {
+    let sql = r#"
+        insert into saved_queues (name, created_at) values (:name, :created_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, created_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_saved_queue' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_saved_queue(tx: &mut Transaction, name: &str) -> Result<()> {
+    let sql = r#"
+        delete from saved_queues where name = :name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_saved_queue' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_saved_queue_track(tx: &mut Transaction, saved_queue_id: i64, position: i64, track_id: i64) -> Result<()> {
+    let sql = r#"
+        insert into
+          saved_queue_tracks (saved_queue_id, position, track_id)
+        values
+          (:saved_queue_id, :position, :track_id);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, saved_queue_id)?;
+    statement.bind(2, position)?;
+    statement.bind(3, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_saved_queue_track' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct SavedQueueTrack {
+    pub track_id: i64,
+}
+
+pub fn iter_saved_queue_tracks<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, saved_queue_id: i64) -> Result<Iter<'i, 'a, SavedQueueTrack>> {
+    let sql = r#"
+        select
+          track_id
+        from
+          saved_queue_tracks
+        where
+          saved_queue_id = :saved_queue_id
+        order by
+          position asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, saved_queue_id)?;
+    let decode_row = |statement: &Statement| Ok(SavedQueueTrack {
+        track_id: statement.read(0)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Run SQLite's consistency check. Returns a single "ok" row if there are no
+/// problems.
+pub fn iter_integrity_check<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, String>> {
+    let sql = r#"
+        select integrity_check from pragma_integrity_check;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return the number of bytes that every table takes up, including its indexes,
+/// largest first. This requires SQLite to be built with SQLITE_ENABLE_DBSTAT_VTAB.
+pub fn iter_table_sizes<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (String, i64)>> {
+    let sql = r#"
+        select
+            coalesce(m.tbl_name, d.name) as table_name
+          , sum(d.pgsize) as size_bytes
+        from
+          dbstat as d
+          left join sqlite_master as m on m.name = d.name
+        group by
+          table_name
+        order by
+          size_bytes desc,
+          table_name asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return every table that refers to a track, with the track ids it refers to.
+pub fn iter_track_references<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (String, i64)>> {
+    let sql = r#"
+        select 'listens', track_id from listens where track_id is not null
+        union select 'ratings', track_id from ratings
+        union select 'track_labels', track_id from track_labels
+        union select 'track_notes', track_id from track_notes
+        union select 'playlist_tracks', track_id from playlist_tracks
+        union select 'saved_queue_tracks', track_id from saved_queue_tracks
+        union select 'track_loudness', track_id from track_loudness
+        union select 'waveforms', track_id from waveforms
+        union select 'spectrograms', track_id from spectrograms;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return every table that refers to an album, with the album ids it refers to.
+pub fn iter_album_references<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (String, i64)>> {
+    let sql = r#"
+        select 'album_ratings', album_id from album_ratings
+        union select 'album_labels', album_id from album_labels
+        union select 'album_notes', album_id from album_notes
+        union select 'album_loudness', album_id from album_loudness
+        union select 'thumbnails', album_id from thumbnails;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return every table that refers to an artist, with the artist ids it refers to.
+pub fn iter_artist_references<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (String, i64)>> {
+    let sql = r#"
+        select 'favorite_artists', artist_id from favorite_artists;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
 }
 
-/// Iterate the listens in chronological order.
-///
-/// Visits only the listens whose timestamp is after the minimum start second
-/// (in POSIX time), exclusive, so this can be used for incremental import.
-pub fn iter_listens_since<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, min_started_second: i64) -> Result<Iter<'i, 'a, ListenAt>> {
+/// Count the rows that refer to a file that no longer exists. The foreign keys
+/// delete these when the file is deleted, but only when foreign key enforcement
+/// was enabled at the time.
+pub fn iter_file_orphan_counts<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (String, i64)>> {
     let sql = r#"
-        select
-            track_id,
-            -- Note that we have an index on this expression, so this should be just an
-            -- index scan.
-            cast(strftime('%s', started_at) as integer) as started_at_second
-        from
-            listens
-        where
-            (completed_at is not null)
-            and (started_at_second > :min_started_second)
-        order by
-            started_at_second asc;
+        select 'track_loudness', count(*) from track_loudness where file_id not in (select id from files)
+        union all select 'album_loudness', count(*) from album_loudness where file_id not in (select id from files)
+        union all select 'waveforms', count(*) from waveforms where file_id not in (select id from files)
+        union all select 'spectrograms', count(*) from spectrograms where file_id not in (select id from files)
+        union all select 'thumbnails', count(*) from thumbnails where file_id not in (select id from files);
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, min_started_second)?;
-    let decode_row = |statement: &Statement| Ok(ListenAt {
-        track_id: statement.read(0)?,
-        started_at_second: statement.read(1)?,
-    });
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
     let result = Iter { statement, decode_row };
     Ok(result)
 }
 
-/// Insert a rating for a given track.
-///
-/// When the `created_at` timestamp is not unique, this replaces the previous
-/// rating that was present for that timestamp. This might happen when the user
-/// edits the rating in quick succession; then we only store the last write.
-pub fn insert_or_replace_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64) -> Result<()> {
+pub fn delete_file_orphans(tx: &mut Transaction) -> Result<()> {
     let sql = r#"
-        insert or replace into
-          ratings (track_id, created_at, rating, source)
-        values
-          (:track_id, :created_at, :rating, 'musium');
+        delete from track_loudness where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_file_orphans' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from album_loudness where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_file_orphans' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from waveforms where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_file_orphans' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from spectrograms where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_file_orphans' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from thumbnails where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_file_orphans' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_ratings_for_track(tx: &mut Transaction, track_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from ratings where track_id = :track_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
@@ -966,24 +4075,16 @@ pub fn insert_or_replace_rating(tx: &mut Transaction, track_id: i64, created_at:
     };
     statement.reset()?;
     statement.bind(1, track_id)?;
-    statement.bind(2, created_at)?;
-    statement.bind(3, rating)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_or_replace_rating' unexpectedly returned a row."),
+        Row => panic!("Query 'delete_ratings_for_track' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
-/// Backfill a rating for a given track.
-///
-/// The timestamp must be unique on the second.
-pub fn insert_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64, source: &str) -> Result<()> {
+pub fn delete_track_labels_for_track(tx: &mut Transaction, track_id: i64) -> Result<()> {
     let sql = r#"
-        insert into
-          ratings (track_id, created_at, rating, source)
-        values
-          (:track_id, :created_at, :rating, :source);
+        delete from track_labels where track_id = :track_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
@@ -991,96 +4092,129 @@ pub fn insert_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rati
     };
     statement.reset()?;
     statement.bind(1, track_id)?;
-    statement.bind(2, created_at)?;
-    statement.bind(3, rating)?;
-    statement.bind(4, source)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_rating' unexpectedly returned a row."),
+        Row => panic!("Query 'delete_track_labels_for_track' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
-#[derive(Debug)]
-pub struct TrackRating {
-    pub id: i64,
-    pub track_id: i64,
-    pub rating: i64,
+pub fn delete_track_loudness(tx: &mut Transaction, track_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from track_loudness where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_track_loudness' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
 }
 
-pub fn iter_ratings<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, TrackRating>> {
+pub fn delete_waveform(tx: &mut Transaction, track_id: i64) -> Result<()> {
     let sql = r#"
-        select
-            id
-          , track_id
-          , rating
-        from
-          ratings
-        order by
-          -- Order by ascending creation time to ensure we can clamp to rating ranges,
-          -- should we need to. We have an index on this expression.
-          cast(strftime('%s', created_at) as integer) asc;
+        delete from waveforms where track_id = :track_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let decode_row = |statement: &Statement| Ok(TrackRating {
-        id: statement.read(0)?,
-        track_id: statement.read(1)?,
-        rating: statement.read(2)?,
-    });
-    let result = Iter { statement, decode_row };
+    statement.bind(1, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_waveform' unexpectedly returned a row."),
+        Done => (),
+    };
     Ok(result)
 }
 
-#[derive(Debug)]
-pub struct LastfmListen {
-    pub started_at: i64,
-    pub title: String,
-    pub track_artist: String,
-    pub album: String,
-    pub album_mbid: String,
+pub fn delete_spectrogram(tx: &mut Transaction, track_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from spectrograms where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_spectrogram' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
 }
 
-/// Iterate all listens that exist in the `lastfm_listens` table but not in the
-/// `listens` table itself.
-pub fn iter_lastfm_missing_listens<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, LastfmListen>> {
+pub fn delete_album_ratings_for_album(tx: &mut Transaction, album_id: i64) -> Result<()> {
     let sql = r#"
-        select
-            started_at
-          , title
-          , track_artist
-          , album
-          , album_mbid
-        from
-          lastfm_listens
-        where
-          not exists (
-            select
-              1
-            from
-              listens
-            where
-              cast(strftime('%s', started_at) as integer) = lastfm_listens.started_at
-          )
-        order by
-          started_at desc;
+        delete from album_ratings where album_id = :album_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let decode_row = |statement: &Statement| Ok(LastfmListen {
-        started_at: statement.read(0)?,
-        title: statement.read(1)?,
-        track_artist: statement.read(2)?,
-        album: statement.read(3)?,
-        album_mbid: statement.read(4)?,
-    });
-    let result = Iter { statement, decode_row };
+    statement.bind(1, album_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_album_ratings_for_album' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_album_labels_for_album(tx: &mut Transaction, album_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from album_labels where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_album_labels_for_album' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_album_loudness(tx: &mut Transaction, album_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from album_loudness where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_album_loudness' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_thumbnail(tx: &mut Transaction, album_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from thumbnails where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_thumbnail' unexpectedly returned a row."),
+        Done => (),
+    };
     Ok(result)
 }
 
@@ -13,7 +13,7 @@ create table if not exists listens
 , started_at       string  not null unique
 
 -- ISO-8601 time with UTC offset at which we finished playing.
--- NULL if the track is still playing.
+-- NULL if the track is still playing, or if it was skipped.
 , completed_at     string  null     check (started_at < completed_at)
 
 -- References a file from the files table, but there is no foreign key. We want
@@ -39,12 +39,15 @@ create table if not exists listens
 , disc_number      integer null
 
 -- Source of the listen. Should be either 'musium' if we produced the
--- listen, or 'listenbrainz' if we backfilled it from Listenbrainz.
+-- listen, or 'lastfm' or 'listenbrainz' if we backfilled it from there.
 , source           string  not null
 
 -- ISO-8601 time with UTC offset at which we scrobbled the track to Last.fm.
 -- NULL if the track has not been scrobbled by us.
 , scrobbled_at     string  null     check (started_at < scrobbled_at)
+
+-- Migrations 5 through 7 add the `skipped_at`, `listenbrainz_submitted_at`,
+-- and `utc_offset_seconds` columns.
 );
 
 -- We can record timestamps in sub-second granularity, but external systems
@@ -67,6 +70,118 @@ create table if not exists lastfm_listens
 , album_mbid   string not null
 );
 
+-- Listens imported from Listenbrainz, before matching them to tracks. The
+-- `album_mbid` is the empty string if Listenbrainz does not know the release.
+create table if not exists listenbrainz_listens
+( -- Seconds since epoch.
+  started_at       integer primary key
+, title            string  not null
+, track_artist     string  not null
+, album            string  not null
+, album_mbid       string  not null
+-- Duration of the track, if the submitting player included it.
+, duration_seconds integer null
+);
+
+-- Decisions that the user made about imported listens that the matcher could
+-- not match by itself, so we don't ask about them again. When the user picked
+-- a track, the listen is also in the `listens` table, when they skipped the
+-- listen, `track_id` is null.
+create table if not exists match_decisions
+( source     string  not null
+  -- Seconds since epoch, the `started_at` of the imported listen.
+, started_at integer not null
+, track_id   integer null
+  -- ISO-8601 time with UTC offset at which the user decided.
+, decided_at string  not null
+, primary key (source, started_at)
+);
+
+-- For every source that we import listens from, the newest listen up to which
+-- we fetched the full history. `tools/scrobble.py` only moves this forward
+-- after a completed fetch, so an interrupted import does not leave a gap.
+create table if not exists import_progress
+( -- Either 'lastfm' or 'listenbrainz'.
+  source         string  primary key
+  -- Seconds since epoch.
+, imported_until integer not null
+  -- ISO-8601 time with UTC offset at which we last moved `imported_until`.
+, updated_at     string  not null
+);
+
+-- Tracks loved on Last.fm, before matching them to tracks. `tools/scrobble.py`
+-- replaces the contents on every import, so tracks that are no longer loved
+-- disappear.
+create table if not exists lastfm_loved_tracks
+( title        string  not null
+, track_artist string  not null
+  -- Seconds since epoch at which the track was loved.
+, loved_at     integer not null
+, primary key (title, track_artist)
+);
+
+-- For every track whose loved state we synced with Last.fm, whether it is loved
+-- there. `tools/scrobble.py` uses this to push only the rating changes, and
+-- `musium import` records loves that it pulled, so we don't push them back.
+create table if not exists lastfm_loved_sync
+( track_id  integer primary key
+, loved     integer not null check ((loved = 0) or (loved = 1))
+  -- ISO-8601 time with UTC offset at which we last changed `loved`.
+, synced_at string  not null
+);
+
+-- Smart playlists: a name and a search query with filters, which we evaluate
+-- against the library whenever the playlist is requested.
+create table if not exists smart_playlists
+( name       string primary key
+, query      string not null
+  -- ISO-8601 time with UTC offset at which the playlist was last saved.
+, updated_at string not null
+);
+
+-- Playlists that the user composed by hand, as opposed to smart playlists.
+-- Names share a namespace with smart playlists.
+create table if not exists playlists
+( id         integer primary key
+, name       string  not null unique
+  -- ISO-8601 time with UTC offset at which the user created the playlist.
+, created_at string  not null
+  -- ISO-8601 time with UTC offset at which the user last edited the playlist.
+, updated_at string  not null
+);
+
+-- The tracks of the playlists. A track can occur in a playlist more than once,
+-- entries are identified by their position instead. Positions of a playlist
+-- are consecutive from 0, but we don't enforce uniqueness, because shifting
+-- positions would temporarily violate it. As for ratings, we don't enforce a
+-- foreign key to the track.
+create table if not exists playlist_tracks
+( playlist_id integer not null references playlists (id) on delete cascade
+, position    integer not null
+, track_id    integer not null
+  -- ISO-8601 time with UTC offset at which the user added the track.
+, created_at  string  not null
+);
+
+create index if not exists ix_playlist_tracks_playlist_id_position
+on playlist_tracks (playlist_id, position);
+
+-- Named snapshots of the play queue, that the user can restore later.
+create table if not exists saved_queues
+( id         integer primary key
+, name       string  not null unique
+  -- ISO-8601 time with UTC offset at which the user saved the queue.
+, created_at string  not null
+);
+
+create table if not exists saved_queue_tracks
+( saved_queue_id integer not null references saved_queues (id) on delete cascade
+  -- Position in the queue, 0 is the track that was playing when saved.
+, position       integer not null
+, track_id       integer not null
+, primary key (saved_queue_id, position)
+);
+
 create table if not exists ratings
 ( id          integer primary key
 -- ISO-8601 time with UTC offset at which we rated the track.
@@ -86,6 +201,59 @@ create table if not exists ratings
 create unique index if not exists ix_ratings_unique_second
 on ratings (cast(strftime('%s', created_at) as integer));
 
+-- Ratings for albums as a whole, independent of the ratings of their tracks.
+-- See `ratings` for the meaning of the columns.
+create table if not exists album_ratings
+( id          integer primary key
+, created_at  string  not null unique
+, album_id    integer not null
+, rating      integer not null check ((rating >= -1) and (rating <= 2))
+, source      string not null
+);
+
+create unique index if not exists ix_album_ratings_unique_second
+on album_ratings (cast(strftime('%s', created_at) as integer));
+
+-- Artists that the user marked as favorite. Like for ratings, we don't enforce
+-- a foreign key, so a re-import of the artist does not lose the flag.
+create table if not exists favorite_artists
+( artist_id  integer primary key
+  -- ISO-8601 time with UTC offset at which the user marked the artist.
+, created_at string  not null
+);
+
+-- User-defined labels such as "workout" or "vinyl-owned", on tracks and on
+-- albums. As for ratings, we don't enforce a foreign key.
+create table if not exists track_labels
+( label      string  not null
+, track_id   integer not null
+  -- ISO-8601 time with UTC offset at which the user added the label.
+, created_at string  not null
+, primary key (label, track_id)
+);
+
+create table if not exists album_labels
+( label      string  not null
+, album_id   integer not null
+, created_at string  not null
+, primary key (label, album_id)
+);
+
+-- Free-text notes on tracks and albums, such as "skip the intro". As for
+-- ratings, we don't enforce a foreign key.
+create table if not exists track_notes
+( track_id   integer primary key
+, note       string  not null
+  -- ISO-8601 time with UTC offset at which the user last edited the note.
+, updated_at string  not null
+);
+
+create table if not exists album_notes
+( album_id   integer primary key
+, note       string  not null
+, updated_at string  not null
+);
+
 create table if not exists files
 -- First an id, and properties about the file, but not its contents.
 -- We can use this to see if a file needs to be re-scanned. The mtime
@@ -145,8 +313,107 @@ create table if not exists thumbnails
 , file_id  integer not null references files (id) on delete cascade
 , data     blob    not null
 );
+
+-- The versions of the schema that we migrated to, see `database_utils`. The
+-- tables above are version 0, every migration adds a row. Do not change the
+-- tables above in ways that existing databases would need to follow, add a
+-- migration instead.
+create table if not exists schema_version
+( version    integer primary key
+  -- ISO-8601 time with UTC offset at which we applied the migration.
+, applied_at string  not null
+);
+
 -- @end ensure_schema_exists
 
+-- Return the version of the schema, 0 if we never applied any migrations.
+-- @query select_schema_version() ->1 i64
+select coalesce(max(version), 0) from schema_version;
+
+-- @query insert_schema_version(version: i64, applied_at: str)
+insert into schema_version (version, applied_at) values (:version, :applied_at);
+
+-- Migration 1: Add a table to persist the word indexes, so we don't have to
+-- rebuild them at startup when the library did not change.
+-- @begin migrate_word_indexes()
+create table word_indexes
+( name        string primary key
+  -- Identifies the files that the index was built from, see `MemoryMetaIndex`.
+, fingerprint string not null
+, data        blob   not null
+);
+-- @end migrate_word_indexes
+
+-- Return the number of files and the most recent import time. Files are only
+-- ever inserted with a new import time, so together these change whenever the
+-- set of files changes.
+-- @query select_files_fingerprint() ->1 (i64, str)
+select count(*), coalesce(max(imported_at), '') from files;
+
+-- @query select_word_index(name: str, fingerprint: str) ->? bytes
+select data from word_indexes where name = :name and fingerprint = :fingerprint;
+
+-- @query insert_or_replace_word_index(name: str, fingerprint: str, data: bytes)
+insert or replace into word_indexes (name, fingerprint, data)
+values (:name, :fingerprint, :data);
+
+-- Migration 2: Add a table to persist the full index, so we can load it at
+-- startup instead of building it from the tags, when the library did not change.
+-- @begin migrate_index_cache()
+create table index_cache
+  -- There is only one cached index, the id is always 0.
+( id          integer primary key check (id = 0)
+  -- Identifies the files that the index was built from, see `MemoryMetaIndex`.
+, fingerprint string  not null
+, data        blob    not null
+);
+-- @end migrate_index_cache
+
+-- @query select_index_cache(fingerprint: str) ->? bytes
+select data from index_cache where id = 0 and fingerprint = :fingerprint;
+
+-- @query insert_or_replace_index_cache(fingerprint: str, data: bytes)
+insert or replace into index_cache (id, fingerprint, data)
+values (0, :fingerprint, :data);
+
+-- Migration 3: Store a detailed waveform next to the overview, see waveform.rs
+-- for the data format. It is null for tracks analyzed before this migration.
+-- @begin migrate_waveform_detail()
+alter table waveforms add column detail blob null;
+-- @end migrate_waveform_detail
+
+-- Migration 4: Add a table for mel spectrograms per track, see spectrogram.rs
+-- for the data format. The data is empty for tracks that are too short.
+-- @begin migrate_spectrograms()
+create table spectrograms
+( track_id integer primary key
+, file_id  integer not null references files (id) on delete cascade
+, data     blob    not null
+);
+-- @end migrate_spectrograms
+
+-- Migration 5: Record the ISO-8601 time with UTC offset at which the track was
+-- skipped, if it was skipped before it played long enough to count as a listen.
+-- @begin migrate_listens_skipped_at()
+alter table listens add column skipped_at string null check (started_at < skipped_at);
+-- @end migrate_listens_skipped_at
+
+-- Migration 6: Record the ISO-8601 time with UTC offset at which we submitted
+-- the listen to Listenbrainz. NULL if the listen has not been submitted by us.
+-- This is separate from `scrobbled_at`, so we can submit to both services.
+-- @begin migrate_listens_listenbrainz_submitted_at()
+alter table listens add column listenbrainz_submitted_at string null
+  check (started_at < listenbrainz_submitted_at);
+-- @end migrate_listens_listenbrainz_submitted_at
+
+-- Migration 7: Record the offset of the local time from UTC when the listen
+-- started, in seconds. The timestamps are in UTC, this records what the local
+-- time was, which matters for the time of day. NULL for listens recorded
+-- before this migration, and for imported listens.
+-- @begin migrate_listens_utc_offset()
+alter table listens add column utc_offset_seconds integer null;
+-- @end migrate_listens_utc_offset
+
 -- @query insert_file(metadata: InsertFile) ->1 i64
 insert into files
 ( filename
@@ -203,6 +470,20 @@ from
 order by
   filename asc;
 
+-- @query select_file_by_filename(filename: str) ->? FileInfo
+select
+    id                         -- :i64
+  , filename                   -- :str
+  , mtime                      -- :i64
+  , streaminfo_channels        -- :i64
+  , streaminfo_bits_per_sample -- :i64
+  , streaminfo_num_samples     -- :i64?
+  , streaminfo_sample_rate     -- :i64
+from
+  files
+where
+  filename = :filename;
+
 -- Iterate all `(field_name, value)` pairs for the given file.
 -- @query iter_file_tags(file_id: i64) ->* (str, str)
 select
@@ -232,10 +513,10 @@ insert into track_loudness (track_id, file_id, bs17704_loudness_lufs)
 values (:track_id, :file_id, :loudness)
 on conflict (track_id) do update set bs17704_loudness_lufs = :loudness;
 
--- @query insert_track_waveform(track_id: i64, file_id: i64, data: bytes)
-insert into waveforms (track_id, file_id, data)
-values (:track_id, :file_id, :data)
-on conflict (track_id) do update set data = :data;
+-- @query insert_track_waveform(track_id: i64, file_id: i64, data: bytes, detail: bytes)
+insert into waveforms (track_id, file_id, data, detail)
+values (:track_id, :file_id, :data, :detail)
+on conflict (track_id) do update set data = :data, detail = :detail;
 
 -- @query insert_listen_started(listen: Listen) ->1 i64
 insert into
@@ -253,6 +534,7 @@ insert into
   , duration_seconds
   , track_number
   , disc_number
+  , utc_offset_seconds
   , source
   )
 values
@@ -269,11 +551,52 @@ values
   , :duration_seconds -- :i64
   , :track_number     -- :i64
   , :disc_number      -- :i64
+  , :utc_offset_seconds -- :i64
   , 'musium'
   )
 returning
   id;
 
+-- Insert a listen from an external source, that is already complete.
+--
+-- Listens are unique on the second, if we already have a listen that started
+-- in the same second, then this does nothing.
+-- @query insert_listen_imported(listen: ImportedListen)
+insert into
+  listens
+  ( started_at
+  , completed_at
+  , file_id
+  , track_id
+  , album_id
+  , album_artist_id
+  , track_title
+  , track_artist
+  , album_title
+  , album_artist
+  , duration_seconds
+  , track_number
+  , disc_number
+  , source
+  )
+values
+  ( :started_at       -- :str
+  , :completed_at     -- :str
+  , :file_id          -- :i64
+  , :track_id         -- :i64
+  , :album_id         -- :i64
+  , :album_artist_id  -- :i64
+  , :track_title      -- :str
+  , :track_artist     -- :str
+  , :album_title      -- :str
+  , :album_artist     -- :str
+  , :duration_seconds -- :i64
+  , :track_number     -- :i64
+  , :disc_number      -- :i64
+  , :source           -- :str
+  )
+on conflict do nothing;
+
 -- @query update_listen_completed(
 --   listen_id: i64,
 --   queue_id: i64,
@@ -287,15 +610,74 @@ where
   and queue_id = :queue_id
   and track_id = :track_id;
 
+-- @query update_listen_skipped(
+--   listen_id: i64,
+--   queue_id: i64,
+--   track_id: i64,
+--   skipped_at: str,
+-- )
+update listens
+  set skipped_at = :skipped_at
+where
+  id = :listen_id
+  and queue_id = :queue_id
+  and track_id = :track_id;
+
+-- Return the track id of the listen, to check whether the listen exists.
+-- @query select_listen_track_id(listen_id: i64) ->? i64
+select track_id from listens where id = :listen_id;
+
+-- @query delete_listen(listen_id: i64)
+delete from listens where id = :listen_id;
+
+-- Point the listen at a different track, for when we recorded or matched the
+-- wrong one. This replaces the track metadata, but not the timestamps.
+-- @query update_listen_track(listen: ListenCorrection)
+update listens
+  set file_id          = :file_id          -- :i64
+    , track_id         = :track_id         -- :i64
+    , album_id         = :album_id         -- :i64
+    , album_artist_id  = :album_artist_id  -- :i64
+    , track_title      = :track_title      -- :str
+    , album_title      = :album_title      -- :str
+    , track_artist     = :track_artist     -- :str
+    , album_artist     = :album_artist     -- :str
+    , duration_seconds = :duration_seconds -- :i64
+    , track_number     = :track_number     -- :i64
+    , disc_number      = :disc_number      -- :i64
+where
+  id = :listen_id          -- :i64
+;
+
 -- @query select_album_loudness_lufs(album_id: i64) ->? f64
 select bs17704_loudness_lufs from album_loudness where album_id = :album_id;
 
 -- @query select_track_loudness_lufs(track_id: i64) ->? f64
 select bs17704_loudness_lufs from track_loudness where track_id = :track_id;
 
+-- Return the loudness of all albums that have one, ordered by album id.
+-- @query iter_album_loudness() ->* (i64, f64)
+select album_id, bs17704_loudness_lufs from album_loudness order by album_id asc;
+
+-- Return the loudness of all tracks that have one, ordered by track id.
+-- @query iter_track_loudness() ->* (i64, f64)
+select track_id, bs17704_loudness_lufs from track_loudness order by track_id asc;
+
 -- @query select_track_waveform(track_id: i64) ->? bytes
 select data from waveforms where track_id = :track_id;
 
+-- Return the detailed waveform, if the track was analyzed after we added it.
+-- @query select_track_waveform_detail(track_id: i64) ->? bytes
+select detail from waveforms where track_id = :track_id and detail is not null;
+
+-- @query insert_track_spectrogram(track_id: i64, file_id: i64, data: bytes)
+insert into spectrograms (track_id, file_id, data)
+values (:track_id, :file_id, :data)
+on conflict (track_id) do update set data = :data;
+
+-- @query select_track_spectrogram(track_id: i64) ->? bytes
+select data from spectrograms where track_id = :track_id;
+
 -- Return the sum of the sizes (in bytes) of all thumbnails.
 -- @query select_thumbnails_count_and_total_size() ->1 (i64, i64)
 select count(*), sum(length(data)) from thumbnails;
@@ -303,10 +685,34 @@ select count(*), sum(length(data)) from thumbnails;
 -- @query iter_thumbnails() ->* Thumbnail
 select album_id /*: i64 */, data /* :bytes */ from thumbnails;
 
+-- @query select_thumbnail(album_id: i64) ->? bytes
+select data from thumbnails where album_id = :album_id;
+
+-- Return the size (in bytes) of the album's thumbnail, if it has one.
+-- @query select_thumbnail_size(album_id: i64) ->? i64
+select length(data) from thumbnails where album_id = :album_id;
+
 -- Return whether a thumbnail for the album exists (1 if it does, 0 otherwise).
 -- @query select_thumbnail_exists(album_id: i64) ->1 i64
 select count(*) from thumbnails where album_id = :album_id;
 
+-- Return the MusicBrainz release id from the tags of the file, if it has one.
+-- @query select_file_musicbrainz_albumid(file_id: i64) ->? str
+select value from tags
+where file_id = :file_id and field_name = 'musicbrainz_albumid'
+limit 1;
+
+-- Return the number of completed listens of the track, and the start time of
+-- the most recent one, as ISO-8601 string.
+-- @query select_track_listen_stats(track_id: i64) ->1 (i64, str?)
+select
+  count(*), max(started_at)
+from
+  listens
+where
+  track_id = :track_id
+  and completed_at is not null;
+
 -- For every album, return the earliest listen in the listens table.
 --
 -- Yields tuples `(album_id, started_at_iso8601)`.
@@ -321,24 +727,97 @@ from
 group by
   album_id;
 
--- Iterate the listens in chronological order.
+-- Iterate the completed and skipped listens in chronological order.
 --
 -- Visits only the listens whose timestamp is after the minimum start second
 -- (in POSIX time), exclusive, so this can be used for incremental import.
+-- `is_skipped` is 1 for skipped listens, and 0 for completed ones.
 -- @query iter_listens_since(min_started_second: i64) ->* ListenAt
 select
     track_id /* :i64 */,
     -- Note that we have an index on this expression, so this should be just an
     -- index scan.
-    cast(strftime('%s', started_at) as integer) as started_at_second /* :i64 */
+    cast(strftime('%s', started_at) as integer) as started_at_second /* :i64 */,
+    skipped_at is not null as is_skipped /* :i64 */,
+    utc_offset_seconds /* :i64? */
 from
     listens
 where
-    (completed_at is not null)
+    (completed_at is not null or skipped_at is not null)
     and (started_at_second > :min_started_second)
 order by
     started_at_second asc;
 
+-- Iterate the completed listens that started in the given range, in
+-- chronological order.
+--
+-- The range is in POSIX seconds, the minimum is inclusive, the maximum is
+-- exclusive.
+-- @query iter_listens_between(min_started_second: i64, max_started_second: i64) ->* ListenInRange
+select
+    track_id /* :i64 */,
+    album_id /* :i64 */,
+    cast(strftime('%s', started_at) as integer) as started_at_second /* :i64 */,
+    duration_seconds /* :i64 */
+from
+    listens
+where
+    completed_at is not null
+    and (started_at_second >= :min_started_second)
+    and (started_at_second < :max_started_second)
+order by
+    started_at_second asc;
+
+-- Iterate the completed listens on the given day of the year in local time,
+-- in years before the given year, most recent year first.
+--
+-- The day is formatted as `MM-DD`. This does a full table scan, but with tens
+-- of thousands of listens, that is still fast enough to do on request.
+-- @query iter_listens_on_day(month_day: str, before_year: i64) ->* ListenOnDay
+select
+    cast(strftime('%Y', started_at, 'localtime') as integer) as year /* :i64 */,
+    started_at /* :str */,
+    track_id /* :i64 */,
+    album_id /* :i64 */,
+    track_title /* :str */,
+    track_artist /* :str */,
+    album_title /* :str */
+from
+    listens
+where
+    completed_at is not null
+    and strftime('%m-%d', started_at, 'localtime') = :month_day
+    and year < :before_year
+order by
+    year desc,
+    started_at asc;
+
+-- Iterate the most recent listens that started before the given second since
+-- epoch, most recent first. This includes skipped listens and the listen that
+-- is currently playing.
+-- @query iter_listens_before(before_second: i64, limit: i64) ->* ListenRecent
+select
+    id                 /* :i64 */,
+    started_at         /* :str */,
+    cast(strftime('%s', started_at) as integer) as started_at_second /* :i64 */,
+    completed_at       /* :str? */,
+    skipped_at         /* :str? */,
+    track_id           /* :i64 */,
+    album_id           /* :i64 */,
+    track_title        /* :str */,
+    album_title        /* :str */,
+    track_artist       /* :str */,
+    duration_seconds   /* :i64 */,
+    source             /* :str */
+from
+    listens
+where
+    cast(strftime('%s', started_at) as integer) < :before_second
+order by
+    cast(strftime('%s', started_at) as integer) desc
+limit
+    :limit;
+
 -- Insert a rating for a given track.
 --
 -- When the `created_at` timestamp is not unique, this replaces the previous
@@ -361,9 +840,11 @@ values
 
 -- @query iter_ratings() ->* TrackRating
 select
-    id       -- :i64
-  , track_id -- :i64
-  , rating   -- :i64
+    id         -- :i64
+  , track_id   -- :i64
+  , rating     -- :i64
+  , created_at -- :str
+  , source     -- :str
 from
   ratings
 order by
@@ -371,8 +852,258 @@ order by
   -- should we need to. We have an index on this expression.
   cast(strftime('%s', created_at) as integer) asc;
 
+-- Like `insert_or_replace_rating`, but for albums.
+-- @query insert_or_replace_album_rating(album_id: i64, created_at: str, rating: i64)
+insert or replace into
+  album_ratings (album_id, created_at, rating, source)
+values
+  (:album_id, :created_at, :rating, 'musium');
+
+-- @query iter_album_ratings() ->* AlbumRating
+select
+    id         -- :i64
+  , album_id   -- :i64
+  , rating     -- :i64
+  , created_at -- :str
+  , source     -- :str
+from
+  album_ratings
+order by
+  cast(strftime('%s', created_at) as integer) asc;
+
+-- @query insert_favorite_artist(artist_id: i64, created_at: str)
+insert or ignore into
+  favorite_artists (artist_id, created_at)
+values
+  (:artist_id, :created_at);
+
+-- @query delete_favorite_artist(artist_id: i64)
+delete from favorite_artists where artist_id = :artist_id;
+
+-- @query iter_favorite_artists() ->* FavoriteArtist
+select
+    artist_id  -- :i64
+  , created_at -- :str
+from
+  favorite_artists
+order by
+  created_at asc;
+
+-- @query insert_track_label(label: str, track_id: i64, created_at: str)
+insert or ignore into
+  track_labels (label, track_id, created_at)
+values
+  (:label, :track_id, :created_at);
+
+-- @query delete_track_label(label: str, track_id: i64)
+delete from track_labels where label = :label and track_id = :track_id;
+
+-- @query iter_track_labels() ->* TrackLabel
+select
+    label      -- :str
+  , track_id   -- :i64
+  , created_at -- :str
+from
+  track_labels;
+
+-- @query insert_album_label(label: str, album_id: i64, created_at: str)
+insert or ignore into
+  album_labels (label, album_id, created_at)
+values
+  (:label, :album_id, :created_at);
+
+-- @query delete_album_label(label: str, album_id: i64)
+delete from album_labels where label = :label and album_id = :album_id;
+
+-- @query iter_album_labels() ->* AlbumLabel
+select
+    label      -- :str
+  , album_id   -- :i64
+  , created_at -- :str
+from
+  album_labels;
+
+-- @query insert_or_replace_track_note(track_id: i64, note: str, updated_at: str)
+insert or replace into
+  track_notes (track_id, note, updated_at)
+values
+  (:track_id, :note, :updated_at);
+
+-- @query delete_track_note(track_id: i64)
+delete from track_notes where track_id = :track_id;
+
+-- @query iter_track_notes() ->* TrackNote
+select
+    track_id   -- :i64
+  , note       -- :str
+  , updated_at -- :str
+from
+  track_notes;
+
+-- @query insert_or_replace_album_note(album_id: i64, note: str, updated_at: str)
+insert or replace into
+  album_notes (album_id, note, updated_at)
+values
+  (:album_id, :note, :updated_at);
+
+-- @query delete_album_note(album_id: i64)
+delete from album_notes where album_id = :album_id;
+
+-- @query iter_album_notes() ->* AlbumNote
+select
+    album_id   -- :i64
+  , note       -- :str
+  , updated_at -- :str
+from
+  album_notes;
+
+-- Like `insert_rating_if_free`, but for albums.
+-- @query insert_album_rating_if_free(album_id: i64, created_at: str, rating: i64, source: str)
+insert or ignore into
+  album_ratings (album_id, created_at, rating, source)
+values
+  (:album_id, :created_at, :rating, :source);
+
+-- Insert a note imported from elsewhere, unless we already have a note for
+-- the track that was edited at the same time or later.
+-- @query insert_track_note_if_newer(track_id: i64, note: str, updated_at: str)
+insert into track_notes (track_id, note, updated_at)
+values (:track_id, :note, :updated_at)
+on conflict (track_id) do update set note = :note, updated_at = :updated_at
+where julianday(updated_at) < julianday(:updated_at);
+
+-- Like `insert_track_note_if_newer`, but for albums.
+-- @query insert_album_note_if_newer(album_id: i64, note: str, updated_at: str)
+insert into album_notes (album_id, note, updated_at)
+values (:album_id, :note, :updated_at)
+on conflict (album_id) do update set note = :note, updated_at = :updated_at
+where julianday(updated_at) < julianday(:updated_at);
+
+-- Return the most recent rating of the track, if it has one.
+-- @query select_latest_rating(track_id: i64) ->? LatestRating
+select
+    rating                                                         -- :i64
+  , cast(strftime('%s', created_at) as integer) as created_at_second -- :i64
+from
+  ratings
+where
+  track_id = :track_id
+order by
+  cast(strftime('%s', created_at) as integer) desc
+limit
+  1;
+
+-- Insert a rating imported from elsewhere, unless we already have a rating in
+-- that second.
+-- @query insert_rating_if_free(track_id: i64, created_at: str, rating: i64, source: str)
+insert or ignore into
+  ratings (track_id, created_at, rating, source)
+values
+  (:track_id, :created_at, :rating, :source);
+
+-- Iterate the entire listening history, for exporting it.
+-- @query iter_listens_export() ->* ListenExport
+select
+    id                        -- :i64
+  , started_at                -- :str
+  , completed_at              -- :str?
+  , skipped_at                -- :str?
+  , track_id                  -- :i64
+  , album_id                  -- :i64
+  , album_artist_id           -- :i64
+  , track_title               -- :str
+  , album_title               -- :str
+  , track_artist              -- :str
+  , album_artist              -- :str
+  , duration_seconds          -- :i64
+  , track_number              -- :i64?
+  , disc_number               -- :i64?
+  , source                    -- :str
+  , scrobbled_at              -- :str?
+  , listenbrainz_submitted_at -- :str?
+from
+  listens
+order by
+  id asc;
+
+-- Insert a listen into the `lastfm_listens` staging table, if we don't have
+-- one at that time already.
+-- @query insert_lastfm_listen(listen: LastfmListenInsert)
+insert into
+  lastfm_listens
+  ( started_at
+  , title
+  , track_artist
+  , album
+  , album_mbid
+  )
+values
+  ( :started_at   -- :i64
+  , :title        -- :str
+  , :track_artist -- :str
+  , :album        -- :str
+  , :album_mbid   -- :str
+  )
+on conflict do nothing;
+
+-- Return the number of listens imported from the given source (`lastfm` or
+-- `listenbrainz`) that started at the given second since epoch.
+-- @query count_imported_listens_at(source: str, started_at: i64) ->1 i64
+select
+  (select count(*) from lastfm_listens
+   where :source = 'lastfm' and started_at = :started_at)
+  + (select count(*) from listenbrainz_listens
+   where :source = 'listenbrainz' and started_at = :started_at);
+
+-- @query insert_match_decision(decision: MatchDecision)
+insert into
+  match_decisions
+  ( source
+  , started_at
+  , track_id
+  , decided_at
+  )
+values
+  ( :source     -- :str
+  , :started_at -- :i64
+  , :track_id   -- :i64?
+  , :decided_at -- :str
+  )
+on conflict (source, started_at) do update set
+  track_id = :track_id, decided_at = :decided_at;
+
+-- Return the number of listens that started in the given range of seconds
+-- since epoch, inclusive lower bound, exclusive upper bound.
+-- @query count_listens_between(min_second: i64, max_second: i64) ->1 i64
+select
+  count(*)
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :min_second
+  and cast(strftime('%s', started_at) as integer) < :max_second;
+
+-- @query iter_lastfm_loved_tracks() ->* LastfmLovedTrack
+select
+    title        -- :str
+  , track_artist -- :str
+  , loved_at     -- :i64
+from
+  lastfm_loved_tracks
+order by
+  loved_at asc;
+
+-- @query select_lastfm_loved(track_id: i64) ->? i64
+select loved from lastfm_loved_sync where track_id = :track_id;
+
+-- @query insert_or_replace_lastfm_loved(track_id: i64, loved: i64, synced_at: str)
+insert or replace into
+  lastfm_loved_sync (track_id, loved, synced_at)
+values
+  (:track_id, :loved, :synced_at);
+
 -- Iterate all listens that exist in the `lastfm_listens` table but not in the
--- `listens` table itself.
+-- `listens` table itself, and that the user did not skip.
 -- @query iter_lastfm_missing_listens() ->* LastfmListen
 select
     started_at   -- :i64
@@ -391,5 +1122,267 @@ where
     where
       cast(strftime('%s', started_at) as integer) = lastfm_listens.started_at
   )
+  and not exists (
+    select
+      1
+    from
+      match_decisions
+    where
+      source = 'lastfm' and started_at = lastfm_listens.started_at
+  )
+order by
+  started_at desc;
+
+-- Iterate all listens that exist in the `listenbrainz_listens` table but not
+-- in the `listens` table itself, and that the user did not skip.
+-- @query iter_listenbrainz_missing_listens() ->* ListenbrainzListen
+select
+    started_at       -- :i64
+  , title            -- :str
+  , track_artist     -- :str
+  , album            -- :str
+  , album_mbid       -- :str
+  , duration_seconds -- :i64?
+from
+  listenbrainz_listens
+where
+  not exists (
+    select
+      1
+    from
+      listens
+    where
+      cast(strftime('%s', started_at) as integer) = listenbrainz_listens.started_at
+  )
+  and not exists (
+    select
+      1
+    from
+      match_decisions
+    where
+      source = 'listenbrainz' and started_at = listenbrainz_listens.started_at
+  )
 order by
   started_at desc;
+
+-- @query iter_smart_playlists() ->* SmartPlaylist
+select
+    name       -- :str
+  , query      -- :str
+  , updated_at -- :str
+from
+  smart_playlists
+order by
+  name asc;
+
+-- @query select_smart_playlist_query(name: str) ->? str
+select query from smart_playlists where name = :name;
+
+-- @query insert_or_replace_smart_playlist(name: str, query: str, updated_at: str)
+insert into smart_playlists (name, query, updated_at)
+values (:name, :query, :updated_at)
+on conflict (name) do update set query = :query, updated_at = :updated_at;
+
+-- Like `insert_track_note_if_newer`, but for smart playlists.
+-- @query insert_smart_playlist_if_newer(name: str, query: str, updated_at: str)
+insert into smart_playlists (name, query, updated_at)
+values (:name, :query, :updated_at)
+on conflict (name) do update set query = :query, updated_at = :updated_at
+where julianday(updated_at) < julianday(:updated_at);
+
+-- @query delete_smart_playlist(name: str)
+delete from smart_playlists where name = :name;
+
+-- Iterate the playlists, with the number of tracks that they have.
+-- @query iter_playlists() ->* Playlist
+select
+    playlists.id                                   -- :i64
+  , name                                           -- :str
+  , playlists.created_at                           -- :str
+  , updated_at                                     -- :str
+  , count(playlist_tracks.position) as track_count -- :i64
+from
+  playlists
+  left join playlist_tracks on playlist_tracks.playlist_id = playlists.id
+group by
+  playlists.id
+order by
+  name asc;
+
+-- @query select_playlist_id(name: str) ->? i64
+select id from playlists where name = :name;
+
+-- @query insert_playlist(name: str, created_at: str)
+insert or ignore into
+  playlists (name, created_at, updated_at)
+values
+  (:name, :created_at, :created_at);
+
+-- @query delete_playlist(name: str)
+delete from playlists where name = :name;
+
+-- @query update_playlist_updated_at(playlist_id: i64, updated_at: str)
+update playlists set updated_at = :updated_at where id = :playlist_id;
+
+-- @query iter_playlist_tracks(playlist_id: i64) ->* PlaylistTrack
+select
+    position -- :i64
+  , track_id -- :i64
+from
+  playlist_tracks
+where
+  playlist_id = :playlist_id
+order by
+  position asc;
+
+-- @query count_playlist_tracks(playlist_id: i64) ->1 i64
+select count(*) from playlist_tracks where playlist_id = :playlist_id;
+
+-- @query update_playlist_track_position(playlist_id: i64, old_position: i64, new_position: i64)
+update playlist_tracks
+set position = :new_position
+where playlist_id = :playlist_id and position = :old_position;
+
+-- Add `delta` to the positions of the entries at or after `position`, to make
+-- room for an insertion, or to close the gap after a removal.
+-- @query shift_playlist_tracks(playlist_id: i64, position: i64, delta: i64)
+update playlist_tracks
+set position = position + :delta
+where playlist_id = :playlist_id and position >= :position;
+
+-- @query insert_playlist_track(playlist_id: i64, position: i64, track_id: i64, created_at: str)
+insert into
+  playlist_tracks (playlist_id, position, track_id, created_at)
+values
+  (:playlist_id, :position, :track_id, :created_at);
+
+-- @query delete_playlist_track(playlist_id: i64, position: i64)
+delete from playlist_tracks where playlist_id = :playlist_id and position = :position;
+
+-- @query iter_saved_queues() ->* SavedQueue
+select
+    saved_queues.id                                   -- :i64
+  , name                                              -- :str
+  , created_at                                        -- :str
+  , count(saved_queue_tracks.position) as track_count -- :i64
+from
+  saved_queues
+  left join saved_queue_tracks on saved_queue_tracks.saved_queue_id = saved_queues.id
+group by
+  saved_queues.id
+order by
+  name asc;
+
+-- @query select_saved_queue_id(name: str) ->? i64
+select id from saved_queues where name = :name;
+
+-- @query insert_saved_queue(name: str, created_at: str)
+insert into saved_queues (name, created_at) values (:name, :created_at);
+
+-- @query delete_saved_queue(name: str)
+delete from saved_queues where name = :name;
+
+-- @query insert_saved_queue_track(saved_queue_id: i64, position: i64, track_id: i64)
+insert into
+  saved_queue_tracks (saved_queue_id, position, track_id)
+values
+  (:saved_queue_id, :position, :track_id);
+
+-- @query iter_saved_queue_tracks(saved_queue_id: i64) ->* SavedQueueTrack
+select
+  track_id -- :i64
+from
+  saved_queue_tracks
+where
+  saved_queue_id = :saved_queue_id
+order by
+  position asc;
+
+-- Run SQLite's consistency check. Returns a single "ok" row if there are no
+-- problems.
+-- @query iter_integrity_check() ->* str
+select integrity_check from pragma_integrity_check;
+
+-- Return the number of bytes that every table takes up, including its indexes,
+-- largest first. This requires SQLite to be built with SQLITE_ENABLE_DBSTAT_VTAB.
+-- @query iter_table_sizes() ->* (str, i64)
+select
+    coalesce(m.tbl_name, d.name) as table_name
+  , sum(d.pgsize) as size_bytes
+from
+  dbstat as d
+  left join sqlite_master as m on m.name = d.name
+group by
+  table_name
+order by
+  size_bytes desc,
+  table_name asc;
+
+-- Return every table that refers to a track, with the track ids it refers to.
+-- @query iter_track_references() ->* (str, i64)
+select 'listens', track_id from listens where track_id is not null
+union select 'ratings', track_id from ratings
+union select 'track_labels', track_id from track_labels
+union select 'track_notes', track_id from track_notes
+union select 'playlist_tracks', track_id from playlist_tracks
+union select 'saved_queue_tracks', track_id from saved_queue_tracks
+union select 'track_loudness', track_id from track_loudness
+union select 'waveforms', track_id from waveforms
+union select 'spectrograms', track_id from spectrograms;
+
+-- Return every table that refers to an album, with the album ids it refers to.
+-- @query iter_album_references() ->* (str, i64)
+select 'album_ratings', album_id from album_ratings
+union select 'album_labels', album_id from album_labels
+union select 'album_notes', album_id from album_notes
+union select 'album_loudness', album_id from album_loudness
+union select 'thumbnails', album_id from thumbnails;
+
+-- Return every table that refers to an artist, with the artist ids it refers to.
+-- @query iter_artist_references() ->* (str, i64)
+select 'favorite_artists', artist_id from favorite_artists;
+
+-- Count the rows that refer to a file that no longer exists. The foreign keys
+-- delete these when the file is deleted, but only when foreign key enforcement
+-- was enabled at the time.
+-- @query iter_file_orphan_counts() ->* (str, i64)
+select 'track_loudness', count(*) from track_loudness where file_id not in (select id from files)
+union all select 'album_loudness', count(*) from album_loudness where file_id not in (select id from files)
+union all select 'waveforms', count(*) from waveforms where file_id not in (select id from files)
+union all select 'spectrograms', count(*) from spectrograms where file_id not in (select id from files)
+union all select 'thumbnails', count(*) from thumbnails where file_id not in (select id from files);
+
+-- @begin delete_file_orphans()
+delete from track_loudness where file_id not in (select id from files);
+delete from album_loudness where file_id not in (select id from files);
+delete from waveforms where file_id not in (select id from files);
+delete from spectrograms where file_id not in (select id from files);
+delete from thumbnails where file_id not in (select id from files);
+-- @end delete_file_orphans
+
+-- @query delete_ratings_for_track(track_id: i64)
+delete from ratings where track_id = :track_id;
+
+-- @query delete_track_labels_for_track(track_id: i64)
+delete from track_labels where track_id = :track_id;
+
+-- @query delete_track_loudness(track_id: i64)
+delete from track_loudness where track_id = :track_id;
+
+-- @query delete_waveform(track_id: i64)
+delete from waveforms where track_id = :track_id;
+
+-- @query delete_spectrogram(track_id: i64)
+delete from spectrograms where track_id = :track_id;
+
+-- @query delete_album_ratings_for_album(album_id: i64)
+delete from album_ratings where album_id = :album_id;
+
+-- @query delete_album_labels_for_album(album_id: i64)
+delete from album_labels where album_id = :album_id;
+
+-- @query delete_album_loudness(album_id: i64)
+delete from album_loudness where album_id = :album_id;
+
+-- @query delete_thumbnail(album_id: i64)
+delete from thumbnails where album_id = :album_id;
@@ -8,12 +8,39 @@
 //! Interaction with Musium's SQLite database.
 
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use log::{info, warn};
+
+use crate::database as db;
+use crate::error;
 
 pub type Result<T> = sqlite::Result<T>;
 
+/// Optional SQLite pragmas from the config file, set on every connection.
+///
+/// When a pragma is not set, we leave SQLite's default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Pragmas {
+    /// Page cache size, in pages when positive, in KiB when negative.
+    pub cache_size: Option<i64>,
+    /// Maximum number of bytes to access through memory-mapped IO.
+    pub mmap_size: Option<i64>,
+    /// One of `SYNCHRONOUS_VALUES`.
+    pub synchronous: Option<&'static str>,
+    /// One of `TEMP_STORE_VALUES`.
+    pub temp_store: Option<&'static str>,
+}
+
+pub const SYNCHRONOUS_VALUES: &[&str] = &["off", "normal", "full", "extra"];
+pub const TEMP_STORE_VALUES: &[&str] = &["default", "file", "memory"];
+
 fn connect_internal<P: AsRef<Path>>(
     path: P,
     flags: sqlite::OpenFlags,
+    pragmas: &Pragmas,
 ) -> Result<sqlite::Connection> {
     // We use set_no_mutex, because the the connection will not be shared among
     // different threads.
@@ -24,15 +51,285 @@ fn connect_internal<P: AsRef<Path>>(
     // Use the faster WAL mode, see https://www.sqlite.org/wal.html.
     connection.execute("PRAGMA journal_mode = WAL;")?;
     connection.execute("PRAGMA foreign_keys = ON;")?;
+
+    // The string values come from a fixed list, so they are safe to format.
+    if let Some(n) = pragmas.cache_size {
+        connection.execute(format!("PRAGMA cache_size = {};", n))?;
+    }
+    if let Some(n) = pragmas.mmap_size {
+        connection.execute(format!("PRAGMA mmap_size = {};", n))?;
+    }
+    if let Some(value) = pragmas.synchronous {
+        connection.execute(format!("PRAGMA synchronous = {};", value))?;
+    }
+    if let Some(value) = pragmas.temp_store {
+        connection.execute(format!("PRAGMA temp_store = {};", value))?;
+    }
+
     Ok(connection)
 }
 
-pub fn connect_readonly<P: AsRef<Path>>(path: P) -> Result<sqlite::Connection> {
+pub fn connect_readonly<P: AsRef<Path>>(path: P, pragmas: &Pragmas) -> Result<sqlite::Connection> {
     let flags = sqlite::OpenFlags::new().set_read_only();
-    connect_internal(path, flags)
+    connect_internal(path, flags, pragmas)
 }
 
-pub fn connect_read_write<P: AsRef<Path>>(path: P) -> Result<sqlite::Connection> {
+pub fn connect_read_write<P: AsRef<Path>>(path: P, pragmas: &Pragmas) -> Result<sqlite::Connection> {
     let flags = sqlite::OpenFlags::new().set_read_write().set_create();
-    connect_internal(path, flags)
+    connect_internal(path, flags, pragmas)
+}
+
+/// Write a consistent snapshot of the database to a new file at `dest`.
+///
+/// This uses `VACUUM INTO`, which reads the database in a single read
+/// transaction, so it is safe to run while the server is writing to it. The
+/// copy is compacted and contains no WAL, so it can be used as-is. SQLite
+/// refuses to overwrite an existing non-empty file.
+pub fn backup(connection: &sqlite::Connection, dest: &str) -> Result<()> {
+    let mut statement = connection.prepare("VACUUM INTO ?;")?;
+    statement.bind(1, dest)?;
+    while statement.next()? != sqlite::State::Done {}
+    Ok(())
+}
+
+/// Rebuild the database file, to reclaim the space of deleted rows.
+///
+/// This needs an exclusive lock, and writes a full copy of the database, so it
+/// can take a while, and it fails when the server is writing at the same time.
+pub fn vacuum(connection: &sqlite::Connection) -> Result<()> {
+    connection.execute("VACUUM;")
+}
+
+/// Gather statistics about the tables and indexes, for the query planner.
+pub fn analyze(connection: &sqlite::Connection) -> Result<()> {
+    connection.execute("ANALYZE;")
+}
+
+/// The result of a WAL checkpoint, see also [`checkpoint`].
+pub struct Checkpoint {
+    /// Whether a reader or writer prevented the checkpoint from completing.
+    pub is_busy: bool,
+    /// The number of pages in the WAL.
+    pub wal_pages: i64,
+    /// The number of pages in the WAL that were written to the database.
+    pub checkpointed_pages: i64,
+}
+
+/// Write the WAL into the database file, and truncate the WAL if possible.
+pub fn checkpoint(connection: &sqlite::Connection) -> Result<Checkpoint> {
+    let mut statement = connection.prepare("PRAGMA wal_checkpoint(TRUNCATE);")?;
+    match statement.next()? {
+        sqlite::State::Row => Ok(Checkpoint {
+            is_busy: statement.read::<i64>(0)? != 0,
+            wal_pages: statement.read(1)?,
+            checkpointed_pages: statement.read(2)?,
+        }),
+        sqlite::State::Done => panic!("PRAGMA wal_checkpoint should return a row."),
+    }
+}
+
+/// Return whether the error is `SQLITE_BUSY` (or one of its extended codes).
+fn is_busy(err: &sqlite::Error) -> bool {
+    const SQLITE_BUSY: isize = 5;
+    match err.code {
+        Some(code) => code & 0xff == SQLITE_BUSY,
+        None => false,
+    }
+}
+
+/// Return whether the error is `SQLITE_BUSY` or `SQLITE_LOCKED`.
+///
+/// These mean that another connection or transaction is in the way, so unlike
+/// other errors, trying again later may succeed.
+pub fn is_busy_or_locked(err: &sqlite::Error) -> bool {
+    const SQLITE_LOCKED: isize = 6;
+    match err.code {
+        Some(code) => is_busy(err) || code & 0xff == SQLITE_LOCKED,
+        None => false,
+    }
+}
+
+/// Run `f` in a transaction and commit it, retrying when the database is busy.
+///
+/// The busy timeout makes SQLite wait for locks held by other connections, but
+/// in WAL mode a transaction that started out reading can fail immediately with
+/// `SQLITE_BUSY` when it tries to write after another connection wrote. The
+/// only way out is to roll back and start over, so `f` may be called multiple
+/// times, and it should not have side effects outside of the transaction.
+pub fn with_write_transaction<T, F>(db: &mut db::Connection, mut f: F) -> Result<T>
+where
+    F: FnMut(&mut db::Transaction) -> Result<T>,
+{
+    let max_attempts = 5;
+    let mut backoff = Duration::from_millis(50);
+    let mut attempt = 1;
+
+    loop {
+        let mut tx = db.begin()?;
+        let err = match f(&mut tx) {
+            Ok(result) => {
+                tx.commit()?;
+                return Ok(result);
+            }
+            Err(err) => err,
+        };
+        tx.rollback()?;
+
+        if !is_busy(&err) || attempt == max_attempts {
+            return Err(err);
+        }
+
+        warn!("Database is busy, retrying in {:?} ...", backoff);
+        thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// A migration brings the schema from one version to the next.
+pub type Migration = fn(&mut db::Transaction) -> db::Result<()>;
+
+/// The migrations, in order.
+///
+/// The tables created by `ensure_schema_exists` are schema version 0, and
+/// migration `i` brings the schema from version `i` to version `i + 1`. Once a
+/// migration has been released, it must not be changed; to change the schema,
+/// append a new migration.
+pub const MIGRATIONS: &[Migration] = &[
+    db::migrate_word_indexes,
+    db::migrate_index_cache,
+    db::migrate_waveform_detail,
+    db::migrate_spectrograms,
+    db::migrate_listens_skipped_at,
+    db::migrate_listens_listenbrainz_submitted_at,
+    db::migrate_listens_utc_offset,
+];
+
+/// Create the schema if it does not exist, and apply any pending migrations.
+pub fn ensure_schema_up_to_date<P: AsRef<Path>>(path: P, pragmas: &Pragmas) -> error::Result<()> {
+    let connection = connect_read_write(path, pragmas)?;
+    let mut db = db::Connection::new(&connection);
+    let mut tx = db.begin()?;
+    db::ensure_schema_exists(&mut tx)?;
+    apply_migrations(&mut tx, MIGRATIONS)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Apply the migrations that have not been applied yet, in order.
+///
+/// Returns the new schema version.
+pub(crate) fn apply_migrations(tx: &mut db::Transaction, migrations: &[Migration]) -> error::Result<i64> {
+    let current_version = db::select_schema_version(tx)?;
+    let target_version = migrations.len() as i64;
+
+    if current_version > target_version {
+        return Err(error::Error::UnsupportedSchemaVersion(current_version));
+    }
+
+    for (i, migration) in migrations.iter().enumerate().skip(current_version as usize) {
+        let version = i as i64 + 1;
+        info!("Migrating database schema to version {} ...", version);
+        migration(tx)?;
+        let now_str = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        db::insert_schema_version(tx, version, &now_str)?;
+    }
+
+    Ok(target_version)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database as db;
+    use crate::error::Error;
+    use super::{apply_migrations, is_busy_or_locked, with_write_transaction, Migration, MIGRATIONS};
+
+    // Playlist names are unique, so these fail when applied twice.
+    fn add_foo(tx: &mut db::Transaction) -> db::Result<()> {
+        db::insert_playlist(tx, "foo", "2024-03-01T12:00:00.000Z")
+    }
+
+    fn add_bar(tx: &mut db::Transaction) -> db::Result<()> {
+        db::insert_playlist(tx, "bar", "2024-03-01T12:00:00.000Z")
+    }
+
+    #[test]
+    fn apply_migrations_applies_pending_migrations_once() {
+        let connection = sqlite::open(":memory:").unwrap();
+        let mut db = db::Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        db::ensure_schema_exists(&mut tx).unwrap();
+        assert_eq!(db::select_schema_version(&mut tx).unwrap(), 0);
+
+        let v1: &[Migration] = &[add_foo];
+        let v2: &[Migration] = &[add_foo, add_bar];
+
+        assert_eq!(apply_migrations(&mut tx, v1).unwrap(), 1);
+        // Applying again is a no-op.
+        assert_eq!(apply_migrations(&mut tx, v1).unwrap(), 1);
+        assert_eq!(apply_migrations(&mut tx, v2).unwrap(), 2);
+        assert_eq!(db::select_schema_version(&mut tx).unwrap(), 2);
+
+        match apply_migrations(&mut tx, v1) {
+            Err(Error::UnsupportedSchemaVersion(2)) => {}
+            _ => panic!("Expected an error for a schema that is too new."),
+        }
+    }
+
+    #[test]
+    fn migrations_add_listens_columns() {
+        let connection = sqlite::open(":memory:").unwrap();
+        let mut db = db::Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        db::ensure_schema_exists(&mut tx).unwrap();
+        assert!(connection.execute("select skipped_at from listens;").is_err());
+
+        apply_migrations(&mut tx, MIGRATIONS).unwrap();
+        connection.execute(
+            "select skipped_at, listenbrainz_submitted_at, utc_offset_seconds from listens;"
+        ).unwrap();
+    }
+
+    #[test]
+    fn is_busy_or_locked_matches_extended_codes() {
+        let error = |code| sqlite::Error { code: Some(code), message: None };
+        // SQLITE_BUSY, SQLITE_LOCKED, and SQLITE_LOCKED_SHAREDCACHE.
+        assert!(is_busy_or_locked(&error(5)));
+        assert!(is_busy_or_locked(&error(6)));
+        assert!(is_busy_or_locked(&error(262)));
+        // SQLITE_ERROR and SQLITE_CONSTRAINT_UNIQUE.
+        assert!(!is_busy_or_locked(&error(1)));
+        assert!(!is_busy_or_locked(&error(2067)));
+        assert!(!is_busy_or_locked(&sqlite::Error { code: None, message: None }));
+    }
+
+    #[test]
+    fn with_write_transaction_retries_when_busy() {
+        let connection = sqlite::open(":memory:").unwrap();
+        let mut db = db::Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        db::ensure_schema_exists(&mut tx).unwrap();
+        tx.commit().unwrap();
+
+        let mut attempts = 0;
+        with_write_transaction(&mut db, |tx| {
+            attempts += 1;
+            db::insert_playlist(tx, "foo", "2024-03-01T12:00:00.000Z")?;
+            if attempts < 3 {
+                // SQLITE_BUSY_SNAPSHOT, the insert above should be rolled back.
+                return Err(sqlite::Error { code: Some(517), message: None });
+            }
+            Ok(())
+        }).unwrap();
+        assert_eq!(attempts, 3);
+
+        // Other errors are not retried.
+        let mut attempts = 0;
+        let result = with_write_transaction(&mut db, |_tx| {
+            attempts += 1;
+            Err::<(), _>(sqlite::Error { code: Some(1), message: None })
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
 }
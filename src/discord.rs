@@ -0,0 +1,327 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Show the playing track as Discord Rich Presence.
+//!
+//! The Discord desktop client listens on a Unix socket for local programs that
+//! want to set the activity of the user. Every message on the socket is a frame
+//! with an opcode and a length, both little-endian `u32`, followed by that many
+//! bytes of json. We first send a handshake with the application id, and then
+//! a `SET_ACTIVITY` command whenever playback starts a track or ends.
+//!
+//! The history thread forwards the playback events to the presence thread, so
+//! a slow or missing Discord client never delays recording listens. When
+//! Discord is not running, we retry periodically while there is something to
+//! show.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::mvar::Var;
+use crate::{MemoryMetaIndex, MetaIndex, TrackId};
+
+const OP_HANDSHAKE: u32 = 0;
+const OP_FRAME: u32 = 1;
+const OP_CLOSE: u32 = 2;
+const OP_PING: u32 = 3;
+const OP_PONG: u32 = 4;
+
+/// Activity type for "Listening to", rather than the default "Playing".
+const ACTIVITY_LISTENING: u32 = 2;
+
+enum PresenceEvent {
+    Playing(TrackId, SystemTime),
+    Idle,
+}
+
+/// Handle for the history thread to send playback events to the presence thread.
+#[derive(Clone)]
+pub struct Presence {
+    sender: Sender<PresenceEvent>,
+}
+
+impl Presence {
+    /// Start the presence thread for the Discord application `client_id`.
+    ///
+    /// When `public_url` is set, the activity shows the album thumbnail from
+    /// `/api/thumb` under that url, which Discord needs to be able to reach.
+    pub fn new(
+        client_id: String,
+        public_url: Option<String>,
+        index_var: Var<MemoryMetaIndex>,
+    ) -> Presence {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("discord".to_string())
+            .spawn(move || main(&client_id, public_url.as_deref(), &index_var, receiver))
+            .expect("Failed to spawn discord thread.");
+        Presence { sender }
+    }
+
+    /// Show the track, which started playing just now.
+    pub fn playing(&self, track_id: TrackId) {
+        let event = PresenceEvent::Playing(track_id, SystemTime::now());
+        self.sender.send(event).expect("Discord thread runs indefinitely, sending does not fail.");
+    }
+
+    /// Clear the activity, playback stopped.
+    pub fn idle(&self) {
+        self.sender.send(PresenceEvent::Idle).expect("Discord thread runs indefinitely, sending does not fail.");
+    }
+}
+
+fn frame(op: u32, payload: &Value) -> Vec<u8> {
+    let body = payload.to_string();
+    let mut out = Vec::with_capacity(8 + body.len());
+    out.extend_from_slice(&op.to_le_bytes());
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body.as_bytes());
+    out
+}
+
+fn read_frame<R: Read>(mut r: R) -> io::Result<(u32, Value)> {
+    let mut header = [0_u8; 8];
+    r.read_exact(&mut header)?;
+    let op = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    // Replies are small, a large length means we are out of sync.
+    if len > 64 * 1024 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Discord frame is too large."));
+    }
+    let mut body = vec![0_u8; len as usize];
+    r.read_exact(&mut body)?;
+    let payload = serde_json::from_slice(&body)?;
+    Ok((op, payload))
+}
+
+/// Return the paths where the Discord client may listen.
+///
+/// The client listens on the first free one of `discord-ipc-0` through
+/// `discord-ipc-9`, in the runtime directory. The Flatpak and Snap packages
+/// put the socket in a subdirectory.
+fn socket_paths() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for var in ["XDG_RUNTIME_DIR", "TMPDIR"] {
+        if let Some(dir) = env::var_os(var) {
+            dirs.push(PathBuf::from(dir));
+        }
+    }
+    dirs.push(PathBuf::from("/tmp"));
+
+    let mut paths = Vec::new();
+    for dir in &dirs {
+        for subdir in ["", "app/com.discordapp.Discord", "snap.discord"] {
+            for i in 0..10 {
+                paths.push(dir.join(subdir).join(format!("discord-ipc-{}", i)));
+            }
+        }
+    }
+    paths
+}
+
+/// Truncate or pad the text to the 2 to 128 characters that Discord accepts.
+///
+/// Discord rejects the entire activity when one of the texts is out of range.
+fn activity_text(text: &str) -> String {
+    let mut result: String = text.chars().take(128).collect();
+    while result.chars().count() < 2 {
+        result.push(' ');
+    }
+    result
+}
+
+fn activity_json(
+    index: &MemoryMetaIndex,
+    track_id: TrackId,
+    started_at: SystemTime,
+    public_url: Option<&str>,
+) -> Option<Value> {
+    let track = index.get_track(track_id)?;
+    let album = index.get_album(track_id.album_id())?;
+    let start_ms = started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let end_ms = start_ms + track.duration_seconds as u64 * 1000;
+
+    let mut assets = json!({
+        "large_text": activity_text(index.get_string(album.title)),
+    });
+    if let Some(url) = public_url {
+        assets["large_image"] = format!(
+            "{}/api/thumb/{}",
+            url.trim_end_matches('/'),
+            track_id.album_id(),
+        ).into();
+    }
+
+    let activity = json!({
+        "type": ACTIVITY_LISTENING,
+        "details": activity_text(index.get_string(track.title)),
+        "state": activity_text(&format!("by {}", index.get_string(track.artist))),
+        "timestamps": { "start": start_ms, "end": end_ms },
+        "assets": assets,
+    });
+    Some(activity)
+}
+
+struct Session {
+    stream: UnixStream,
+    nonce: u64,
+}
+
+impl Session {
+    fn connect(client_id: &str) -> io::Result<Session> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "No Discord socket found.");
+        for path in socket_paths() {
+            match UnixStream::connect(&path) {
+                Ok(stream) => return Session::handshake(stream, client_id),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn handshake(mut stream: UnixStream, client_id: &str) -> io::Result<Session> {
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+        stream.write_all(&frame(OP_HANDSHAKE, &json!({ "v": 1, "client_id": client_id })))?;
+
+        // Discord replies with a ready event, or closes the connection when
+        // the client id is invalid.
+        match read_frame(&mut stream)? {
+            (OP_CLOSE, payload) => Err(closed_error(&payload)),
+            _ => Ok(Session { stream, nonce: 0 }),
+        }
+    }
+
+    /// Set the activity, or clear it when `activity` is null.
+    fn set_activity(&mut self, activity: &Value) -> io::Result<()> {
+        self.nonce += 1;
+        let nonce = self.nonce.to_string();
+        let command = json!({
+            "cmd": "SET_ACTIVITY",
+            "args": { "pid": std::process::id(), "activity": activity },
+            "nonce": nonce,
+        });
+        self.stream.write_all(&frame(OP_FRAME, &command))?;
+
+        loop {
+            match read_frame(&mut self.stream)? {
+                (OP_PING, payload) => self.stream.write_all(&frame(OP_PONG, &payload))?,
+                (OP_CLOSE, payload) => return Err(closed_error(&payload)),
+                (OP_FRAME, payload) if payload["nonce"] == nonce.as_str() => {
+                    // A rejected activity is not a connection problem, so we
+                    // report it, but keep the connection.
+                    if payload["evt"] == "ERROR" {
+                        warn!("Discord rejected the activity: {}", payload["data"]["message"]);
+                    }
+                    return Ok(())
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+fn closed_error(payload: &Value) -> io::Error {
+    let message = format!("Discord closed the connection: {}", payload["message"]);
+    io::Error::new(io::ErrorKind::ConnectionAborted, message)
+}
+
+/// Set the activity, and reconnect once when the connection was lost.
+fn update(session: &mut Option<Session>, client_id: &str, activity: &Value) -> io::Result<()> {
+    if let Some(s) = session.as_mut() {
+        match s.set_activity(activity) {
+            Ok(()) => return Ok(()),
+            // Discord may have restarted since the last update.
+            Err(..) => *session = None,
+        }
+    }
+    let mut s = Session::connect(client_id)?;
+    info!("Connected to Discord.");
+    s.set_activity(activity)?;
+    *session = Some(s);
+    Ok(())
+}
+
+fn main(
+    client_id: &str,
+    public_url: Option<&str>,
+    index_var: &Var<MemoryMetaIndex>,
+    events: Receiver<PresenceEvent>,
+) {
+    let retry_interval = Duration::from_secs(30);
+    let mut session: Option<Session> = None;
+    let mut activity = Value::Null;
+
+    // Whether Discord does not yet show `activity`, and we should retry.
+    let mut pending = false;
+
+    // When Discord is not running, that is likely to stay so for a while, so
+    // we only report it once, until we connect again.
+    let mut reported_failure = false;
+
+    loop {
+        let event = match pending {
+            true => events.recv_timeout(retry_interval),
+            false => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(PresenceEvent::Playing(track_id, started_at)) => {
+                let index = index_var.get();
+                activity = activity_json(&index, track_id, started_at, public_url).unwrap_or(Value::Null);
+            }
+            Ok(PresenceEvent::Idle) => activity = Value::Null,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        match update(&mut session, client_id, &activity) {
+            Ok(()) => {
+                pending = false;
+                reported_failure = false;
+            }
+            Err(err) => {
+                if !reported_failure {
+                    warn!("Failed to update Discord presence, retrying while playing: {}", err);
+                    reported_failure = true;
+                }
+                // Without a connection, there is no activity to clear.
+                pending = !activity.is_null();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use super::{activity_text, frame, read_frame};
+
+    #[test]
+    fn frame_has_little_endian_header() {
+        let payload = json!({ "v": 1 });
+        let bytes = frame(0, &payload);
+        assert_eq!(bytes, b"\x00\x00\x00\x00\x07\x00\x00\x00{\"v\":1}");
+        assert_eq!(read_frame(&bytes[..]).unwrap(), (0, payload));
+        assert!(read_frame(&bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn activity_text_fits_discord_limits() {
+        assert_eq!(activity_text("X"), "X ");
+        assert_eq!(activity_text("Around the World"), "Around the World");
+        let long = "é".repeat(200);
+        assert_eq!(activity_text(&long).chars().count(), 128);
+    }
+}
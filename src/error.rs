@@ -14,6 +14,13 @@ pub enum Error {
     /// Error in config file on a given line.
     InvalidConfig(usize, &'static str),
 
+    /// Invalid value for a config key, on a given line.
+    InvalidConfigValue(usize, String, &'static str),
+
+    /// Invalid value in a `MUSIUM_*` environment variable that overrides a
+    /// config key.
+    InvalidConfigEnv(String, &'static str),
+
     /// A key is missing in the config.
     IncompleteConfig(&'static str),
 
@@ -28,6 +35,16 @@ pub enum Error {
 
     /// Interaction with the SQLite database failed.
     DatabaseError(sqlite::Error),
+
+    /// A user data file to import has an unexpected value for the given key.
+    InvalidUserData(&'static str),
+
+    /// The database has a newer schema version than this version of Musium
+    /// knows about.
+    UnsupportedSchemaVersion(i64),
+
+    /// A request to a running server, for `musium remote`, failed.
+    RemoteError(String),
 }
 
 impl Error {
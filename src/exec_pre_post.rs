@@ -18,6 +18,9 @@
 //!   and execute the pre-play right away.
 //! * All events get processed in order. If playback resumes while we are
 //!   executing the post-idle command, that is not an issue.
+//!
+//! The programs receive the track that is about to play, or that played last,
+//! in environment variables, see [`track_env`].
 
 use std::path::Path;
 use std::sync::Arc;
@@ -27,27 +30,74 @@ use std::time::{Instant, Duration};
 use std::process::Command;
 
 use wait_timeout::ChildExt;
+use log::{error, info, warn};
 
 use crate::config::Config;
+use crate::mvar::Var;
+use crate::{MemoryMetaIndex, MetaIndex, TrackId};
 
 /// Events to send to the exec thread.
 pub enum QueueEvent {
     /// Playback just started.
     ///
     /// After the pre-playback program has finished, the exec thread will set
-    /// the mutex value to false and then signal the condvar.
-    StartPlayback(Arc<(Mutex<bool>, Condvar)>),
+    /// the mutex value to false and then signal the condvar. The track is the
+    /// one that is about to play.
+    StartPlayback(Arc<(Mutex<bool>, Condvar)>, Option<TrackId>),
+
+    /// Playback ended at the given instant, after playing the given track.
+    EndPlayback(Instant, Option<TrackId>),
+}
 
-    /// Playback ended at the given instant.
-    EndPlayback(Instant),
+/// Return the environment variables that describe the track, for the programs.
+///
+/// Returns no variables if the track is not in the index (any more).
+pub fn track_env(index: &MemoryMetaIndex, track_id: TrackId) -> Vec<(&'static str, String)> {
+    let track = match index.get_track(track_id) {
+        Some(track) => track,
+        None => return Vec::new(),
+    };
+    let album = match index.get_album(track_id.album_id()) {
+        Some(album) => album,
+        None => return Vec::new(),
+    };
+    vec![
+        ("MUSIUM_TRACK_ID", format!("{}", track_id)),
+        ("MUSIUM_FILE_ID", format!("{}", track.file_id.0)),
+        ("MUSIUM_TRACK_TITLE", index.get_string(track.title).to_string()),
+        ("MUSIUM_TRACK_ARTIST", index.get_string(track.artist).to_string()),
+        ("MUSIUM_ALBUM_TITLE", index.get_string(album.title).to_string()),
+        ("MUSIUM_ALBUM_ARTIST", index.get_string(album.artist).to_string()),
+        ("MUSIUM_TRACK_NUMBER", format!("{}", track_id.track_number())),
+        ("MUSIUM_DURATION_SECONDS", format!("{}", track.duration_seconds)),
+    ]
 }
 
-fn execute_program_with_timeout(exe_path: &Path, stage_name: &'static str) {
-    println!("Executing {} program {} ...", stage_name, exe_path.to_string_lossy());
-    let mut proc = match Command::new(exe_path).spawn() {
+/// Return the environment variables for the track, if there is one.
+fn optional_track_env(index_var: &Var<MemoryMetaIndex>, track_id: Option<TrackId>) -> Vec<(&'static str, String)> {
+    match track_id {
+        Some(id) => track_env(&index_var.get(), id),
+        None => Vec::new(),
+    }
+}
+
+/// Execute the program with the given extra environment variables.
+///
+/// This is also used by the history thread for the now-playing program.
+pub fn execute_program_with_timeout(
+    exe_path: &Path,
+    stage_name: &'static str,
+    env: &[(&str, String)],
+) {
+    info!("Executing {} program {} ...", stage_name, exe_path.to_string_lossy());
+    let mut cmd = Command::new(exe_path);
+    for (key, value) in env {
+        cmd.env(key, value);
+    }
+    let mut proc = match cmd.spawn() {
         Ok(proc) => proc,
         Err(err) => {
-            println!(
+            error!(
                 "Failed to spawn {} program {}: {}",
                 stage_name,
                 exe_path.to_string_lossy(),
@@ -62,29 +112,35 @@ fn execute_program_with_timeout(exe_path: &Path, stage_name: &'static str) {
     // is little more we can do then anyway.
     match proc.wait_timeout(Duration::from_secs(30)) {
         Ok(Some(_status)) => {
-            println!("The {} program exited.", stage_name);
+            info!("The {} program exited.", stage_name);
         }
         Ok(None) => {
-            println!("The {} program did not exit within 30 seconds, killing it ...", stage_name);
+            warn!("The {} program did not exit within 30 seconds, killing it ...", stage_name);
             let _ignored_result = proc.kill();
         }
         Err(err) => {
-            println!("Failed to wait for the {} program: {}", stage_name, err);
+            error!("Failed to wait for the {} program: {}", stage_name, err);
         }
     }
 }
 
-pub fn main(config: &Config, events: Receiver<QueueEvent>) -> ! {
+pub fn main(
+    config_var: &Var<Config>,
+    index_var: &Var<MemoryMetaIndex>,
+    events: Receiver<QueueEvent>,
+) -> ! {
     // Wait for playback to start.
     let mut start_event = events.recv().expect("QueueEvent sender should run indefinitely.");
     loop {
-        let is_running_condvar = match start_event {
-            QueueEvent::StartPlayback(arc) => arc,
+        let (is_running_condvar, next_track_id) = match start_event {
+            QueueEvent::StartPlayback(arc, track_id) => (arc, track_id),
             QueueEvent::EndPlayback(..) => panic!("Received EndPlayback before StartPlayback."),
         };
 
-        if let Some(exe) = config.exec_pre_playback_path.as_ref() {
-            execute_program_with_timeout(exe, "pre-playback");
+        // Read the config on every use, it can be reloaded at runtime.
+        if let Some(exe) = config_var.get().exec_pre_playback_path.as_ref() {
+            let env = optional_track_env(index_var, next_track_id);
+            execute_program_with_timeout(exe, "pre-playback", &env);
         }
 
         // Signal to the playback thread that it can continue.
@@ -93,16 +149,16 @@ pub fn main(config: &Config, events: Receiver<QueueEvent>) -> ! {
 
         // Now we wait for playback to end.
         let event = events.recv().expect("QueueEvent sender should run indefinitely.");
-        let playback_ended_at = match event {
+        let (playback_ended_at, last_track_id) = match event {
             QueueEvent::StartPlayback(..) => panic!("Received StartPlayback before EndPlayback."),
-            QueueEvent::EndPlayback(at) => at,
+            QueueEvent::EndPlayback(at, track_id) => (at, track_id),
         };
 
         // After playback ends, we need to wait for the idle timeout to expire.
         // However, if playback resumes, then we should stop waiting immediately
         // and execute the pre-playback command again. We can do both in one go
         // by waiting for the next event with a deadline.
-        let timeout = Duration::from_secs(config.idle_timeout_seconds);
+        let timeout = Duration::from_secs(config_var.get().idle_timeout_seconds);
         let deadline = playback_ended_at + timeout;
         if let Ok(next_event) = events.recv_timeout(deadline.duration_since(Instant::now())) {
             start_event = next_event;
@@ -111,8 +167,9 @@ pub fn main(config: &Config, events: Receiver<QueueEvent>) -> ! {
 
         // If we get here, then we waited for the full timeout, and playback did
         // not resume, which means we are idle now.
-        if let Some(exe) = config.exec_post_idle_path.as_ref() {
-            execute_program_with_timeout(exe, "post-idle");
+        if let Some(exe) = config_var.get().exec_post_idle_path.as_ref() {
+            let env = optional_track_env(index_var, last_track_id);
+            execute_program_with_timeout(exe, "post-idle", &env);
         }
 
         // Wait for playback to start again.
@@ -0,0 +1,505 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Boolean expressions over track properties.
+//!
+//! An expression combines comparisons of track fields with `and`, `or`, and
+//! `not`, for example
+//!
+//! ```text
+//! genre = "jazz" and (rating >= 1 or last_played < now - 90d)
+//! ```
+//!
+//! Comparisons compile to the [`Filter`]s of [`crate::query`], so they match
+//! the same way as the `key:value` filters in a search query. Dates are either
+//! `now` or a day like `2024-01-31` (midnight UTC), optionally followed by
+//! date math with a number of days (`d`), weeks (`w`), or years (`y`).
+
+use std::fmt;
+
+use chrono::NaiveDate;
+
+use crate::database as db;
+use crate::database::Transaction;
+use crate::prim::TrackId;
+use crate::query::Filter;
+use crate::string_utils::normalize_words;
+use crate::user_data::UserData;
+use crate::MetaIndex;
+
+/// An expression that tracks can match.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expr {
+    Filter(Filter),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+/// A problem with an expression, and where in the expression it is.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    /// Byte offset into the expression.
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at offset {})", self.message, self.offset)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    /// A field name or a keyword, lowercased.
+    Ident(String),
+    Number(i64),
+    Str(String),
+    /// A day, as the POSIX timestamp of its start.
+    Date(i64),
+    /// A length of time such as `30d`, in seconds.
+    Span(i64),
+    Op(Op),
+    Plus,
+    Minus,
+    LParen,
+    RParen,
+}
+
+const DAY_SECONDS: i64 = 24 * 3600;
+
+fn error<T>(offset: usize, message: &'static str) -> Result<T, ParseError> {
+    Err(ParseError { offset, message })
+}
+
+/// Parse a date of the form `2024-01-31` at the start of the input, if any.
+fn parse_date_literal(input: &str) -> Option<Result<i64, ()>> {
+    let bytes = input.as_bytes();
+    let is_date = bytes.len() >= 10
+        && bytes[..10].iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        });
+    if !is_date {
+        return None
+    }
+    let year = input[0..4].parse().unwrap();
+    let month = input[5..7].parse().unwrap();
+    let day = input[8..10].parse().unwrap();
+    let date = NaiveDate::from_ymd_opt(year, month, day).ok_or(());
+    Some(date.map(|d| d.and_hms(0, 0, 0).timestamp()))
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let start = i;
+        let next_is_eq = bytes.get(i + 1) == Some(&b'=');
+        let token = match bytes[i] {
+            b if b.is_ascii_whitespace() => {
+                i += 1;
+                continue
+            }
+            b'(' => { i += 1; Token::LParen }
+            b')' => { i += 1; Token::RParen }
+            b'+' => { i += 1; Token::Plus }
+            b'-' => { i += 1; Token::Minus }
+            b'=' => { i += 1; Token::Op(Op::Eq) }
+            b'!' if next_is_eq => { i += 2; Token::Op(Op::Ne) }
+            b'!' => return error(i, "Expected '=' after '!'."),
+            b'<' if next_is_eq => { i += 2; Token::Op(Op::Le) }
+            b'<' => { i += 1; Token::Op(Op::Lt) }
+            b'>' if next_is_eq => { i += 2; Token::Op(Op::Ge) }
+            b'>' => { i += 1; Token::Op(Op::Gt) }
+            b'"' => match input[i + 1..].find('"') {
+                Some(len) => {
+                    i += len + 2;
+                    Token::Str(input[start + 1..i - 1].to_string())
+                }
+                None => return error(i, "Unterminated string, expected a closing '\"'."),
+            },
+            b if b.is_ascii_digit() => {
+                if let Some(date) = parse_date_literal(&input[i..]) {
+                    i += 10;
+                    match date {
+                        Ok(t) => Token::Date(t),
+                        Err(()) => return error(start, "Invalid date, expected e.g. 2024-01-31."),
+                    }
+                } else {
+                    while i < bytes.len() && bytes[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                    let n: i64 = match input[start..i].parse() {
+                        Ok(n) => n,
+                        Err(_) => return error(start, "Number is too large."),
+                    };
+                    let unit_len = input[i..]
+                        .find(|ch: char| !ch.is_ascii_alphabetic())
+                        .unwrap_or(input.len() - i);
+                    let unit = &input[i..i + unit_len];
+                    i += unit_len;
+                    let scale = match unit {
+                        "" => None,
+                        "d" => Some(DAY_SECONDS),
+                        "w" => Some(7 * DAY_SECONDS),
+                        "y" => Some(365 * DAY_SECONDS),
+                        _ => return error(start, "Unknown unit, expected d (days), w (weeks), or y (years)."),
+                    };
+                    match scale {
+                        None => Token::Number(n),
+                        Some(s) => match n.checked_mul(s) {
+                            Some(seconds) => Token::Span(seconds),
+                            None => return error(start, "Number is too large."),
+                        },
+                    }
+                }
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                Token::Ident(input[start..i].to_ascii_lowercase())
+            }
+            _ => return error(i, "Unexpected character."),
+        };
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Offset of the end of the input, for errors at the end.
+    end: usize,
+    /// The current time as POSIX timestamp, the value of `now`.
+    now: i64,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(offset, _)| *offset)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(ident)) if ident == keyword => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut terms = vec![self.parse_and()?];
+        while self.eat_keyword("or") {
+            terms.push(self.parse_and()?);
+        }
+        match terms.len() {
+            1 => Ok(terms.pop().unwrap()),
+            _ => Ok(Expr::Or(terms)),
+        }
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut terms = vec![self.parse_not()?];
+        while self.eat_keyword("and") {
+            terms.push(self.parse_not()?);
+        }
+        match terms.len() {
+            1 => Ok(terms.pop().unwrap()),
+            _ => Ok(Expr::And(terms)),
+        }
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)))
+        }
+        self.parse_atom()
+    }
+
+    fn parse_atom(&mut self) -> Result<Expr, ParseError> {
+        let offset = self.offset();
+        match self.peek() {
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => error(offset, "Unbalanced '(', expected a closing ')'."),
+                }
+            }
+            Some(Token::Ident(..)) => self.parse_comparison(),
+            None => error(offset, "Expected a comparison, but the expression ended."),
+            Some(_) => error(offset, "Expected a field name such as 'year', or '('."),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, ParseError> {
+        let field_offset = self.offset();
+        let field = match self.next() {
+            Some(Token::Ident(field)) => field,
+            _ => unreachable!("We only get here for identifiers."),
+        };
+        let op_offset = self.offset();
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => return error(op_offset, "Expected a comparison operator: =, !=, <, <=, >, or >=."),
+        };
+
+        match &field[..] {
+            "year" => {
+                let (min, max) = self.parse_range(op, 0, u16::MAX as i64)?;
+                Ok(negate_if(op, Filter::Year(min as u16, max as u16)))
+            }
+            "rating" => {
+                let (min, max) = self.parse_range(op, -1, 2)?;
+                Ok(negate_if(op, Filter::Rating(min as i8, max as i8)))
+            }
+            "duration" => {
+                let (min, max) = self.parse_range(op, 0, u16::MAX as i64)?;
+                Ok(negate_if(op, Filter::Duration(min as u16, max as u16)))
+            }
+            "artist" | "genre" | "title" => {
+                if !matches!(op, Op::Eq | Op::Ne) {
+                    return error(op_offset, "Text can only be compared with = and !=.")
+                }
+                let words = self.parse_words()?;
+                let filter = match &field[..] {
+                    "artist" => Filter::Artist(words),
+                    "genre" => Filter::Genre(words),
+                    _ => Filter::Title(words),
+                };
+                Ok(negate_if(op, filter))
+            }
+            "last_played" => {
+                let t = self.parse_date()?;
+                match op {
+                    Op::Gt | Op::Ge => Ok(Expr::Filter(Filter::PlayedAfter(t))),
+                    Op::Lt | Op::Le => Ok(Expr::Not(Box::new(Expr::Filter(Filter::PlayedAfter(t))))),
+                    Op::Eq | Op::Ne => error(op_offset, "Dates can only be compared with <, <=, >, and >=."),
+                }
+            }
+            _ => error(
+                field_offset,
+                "Unknown field, expected year, rating, duration, artist, genre, title, or last_played.",
+            ),
+        }
+    }
+
+    /// Parse an integer, and return the inclusive range that the comparison
+    /// selects within `min..=max`. For `!=`, this is the range to exclude.
+    fn parse_range(&mut self, op: Op, min: i64, max: i64) -> Result<(i64, i64), ParseError> {
+        let offset = self.offset();
+        let negative = self.peek() == Some(&Token::Minus);
+        if negative {
+            self.pos += 1;
+        }
+        let n = match self.next() {
+            Some(Token::Number(n)) if negative => -n,
+            Some(Token::Number(n)) => n,
+            _ => return error(offset, "Expected a number."),
+        };
+        if n < min || n > max {
+            return error(offset, "Number is out of range for this field.")
+        }
+        let range = match op {
+            Op::Eq | Op::Ne => (n, n),
+            Op::Lt => (min, n - 1),
+            Op::Le => (min, n),
+            Op::Gt => (n + 1, max),
+            Op::Ge => (n, max),
+        };
+        match range.0 <= range.1 {
+            true => Ok(range),
+            false => error(offset, "This comparison can never match."),
+        }
+    }
+
+    fn parse_words(&mut self) -> Result<Vec<String>, ParseError> {
+        let offset = self.offset();
+        let text = match self.next() {
+            Some(Token::Str(text)) => text,
+            _ => return error(offset, "Expected text in double quotes."),
+        };
+        let mut words = Vec::new();
+        normalize_words(&text, &mut words);
+        match words.is_empty() {
+            true => error(offset, "Expected text with at least one word."),
+            false => Ok(words),
+        }
+    }
+
+    fn parse_date(&mut self) -> Result<i64, ParseError> {
+        let offset = self.offset();
+        let mut t = match self.next() {
+            Some(Token::Ident(ident)) if ident == "now" => self.now,
+            Some(Token::Date(t)) => t,
+            _ => return error(offset, "Expected a date, such as 'now' or 2024-01-31."),
+        };
+        loop {
+            let sign = match self.peek() {
+                Some(Token::Plus) => 1,
+                Some(Token::Minus) => -1,
+                _ => return Ok(t),
+            };
+            self.pos += 1;
+            let span_offset = self.offset();
+            match self.next() {
+                Some(Token::Span(seconds)) => t += sign * seconds,
+                _ => return error(span_offset, "Expected a length of time, such as 30d, 4w, or 1y."),
+            }
+        }
+    }
+}
+
+fn negate_if(op: Op, filter: Filter) -> Expr {
+    match op {
+        Op::Ne => Expr::Not(Box::new(Expr::Filter(filter))),
+        _ => Expr::Filter(filter),
+    }
+}
+
+impl Expr {
+    /// Parse an expression, evaluating `now` as the current time.
+    pub fn parse(input: &str) -> Result<Expr, ParseError> {
+        Expr::parse_at(input, chrono::Utc::now().timestamp())
+    }
+
+    fn parse_at(input: &str, now: i64) -> Result<Expr, ParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+            end: input.len(),
+            now,
+        };
+        let expr = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(Token::RParen) => error(parser.offset(), "Unbalanced ')', there is no matching '('."),
+            Some(_) => error(parser.offset(), "Expected 'and', 'or', or the end of the expression."),
+        }
+    }
+
+    pub fn matches_track(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        tx: &mut Transaction,
+        track_id: TrackId,
+    ) -> db::Result<bool> {
+        match self {
+            Expr::Filter(filter) => filter.matches_track(index, user_data, tx, track_id),
+            Expr::Not(expr) => Ok(!expr.matches_track(index, user_data, tx, track_id)?),
+            Expr::And(exprs) => {
+                for expr in exprs {
+                    if !expr.matches_track(index, user_data, tx, track_id)? {
+                        return Ok(false)
+                    }
+                }
+                Ok(true)
+            }
+            Expr::Or(exprs) => {
+                for expr in exprs {
+                    if expr.matches_track(index, user_data, tx, track_id)? {
+                        return Ok(true)
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::query::Filter;
+    use super::{Expr, ParseError};
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn parse(input: &str) -> Result<Expr, ParseError> {
+        Expr::parse_at(input, NOW)
+    }
+
+    fn not(expr: Expr) -> Expr {
+        Expr::Not(Box::new(expr))
+    }
+
+    #[test]
+    fn parse_respects_precedence() {
+        let expr = parse(r#"genre = "Jazz" and not rating < 1 or year >= 1990"#).unwrap();
+        assert_eq!(expr, Expr::Or(vec![
+            Expr::And(vec![
+                Expr::Filter(Filter::Genre(vec!["jazz".to_string()])),
+                not(Expr::Filter(Filter::Rating(-1, 0))),
+            ]),
+            Expr::Filter(Filter::Year(1990, u16::MAX)),
+        ]));
+
+        let expr = parse("year != 2001 and (duration > 600 or rating = -1)").unwrap();
+        assert_eq!(expr, Expr::And(vec![
+            not(Expr::Filter(Filter::Year(2001, 2001))),
+            Expr::Or(vec![
+                Expr::Filter(Filter::Duration(601, u16::MAX)),
+                Expr::Filter(Filter::Rating(-1, -1)),
+            ]),
+        ]));
+    }
+
+    #[test]
+    fn parse_evaluates_date_math() {
+        let expr = parse("last_played > now - 2w + 1d").unwrap();
+        assert_eq!(expr, Expr::Filter(Filter::PlayedAfter(NOW - 13 * 24 * 3600)));
+
+        let expr = parse("last_played < 2024-01-31").unwrap();
+        assert_eq!(expr, not(Expr::Filter(Filter::PlayedAfter(1_706_659_200))));
+    }
+
+    #[test]
+    fn parse_reports_error_offsets() {
+        let err = |input: &str| parse(input).unwrap_err().offset;
+        assert_eq!(err(""), 0);
+        assert_eq!(err("year"), 4);
+        assert_eq!(err("year = soon"), 7);
+        assert_eq!(err("rating > 2"), 9);
+        assert_eq!(err("tempo > 120"), 0);
+        assert_eq!(err(r#"title < "x""#), 6);
+        assert_eq!(err("(year = 1 or year = 2"), 0);
+        assert_eq!(err("year = 1 year = 2"), 9);
+        assert_eq!(err("last_played > now - 30"), 20);
+        assert_eq!(err("last_played > 2024-02-30"), 14);
+        assert_eq!(err(r#"artist = "abba"#), 9);
+    }
+}
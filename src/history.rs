@@ -7,25 +7,39 @@
 
 //! Logging of historical playback events.
 
-use std::path::Path;
+use std::io::{self, Write};
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
 
-use chrono::{SecondsFormat, Utc};
+use chrono::{Local, SecondsFormat, Utc};
+use log::error;
 
-use crate::database_utils;
+use crate::config::Config;
+use crate::database_utils::{self, with_write_transaction};
+use crate::discord::Presence;
+use crate::exec_pre_post;
+use crate::matcher::{self, ImportSource};
 use crate::database as db;
 use crate::database::{Connection, Listen, Result};
 use crate::mvar::Var;
 use crate::player::QueueId;
+use crate::playlist::{self, PlaylistEdit};
 use crate::{MetaIndex, MemoryMetaIndex, TrackId};
-use crate::user_data::{Rating, UserData};
-use crate::playcount::PlayCounter;
+use crate::prim::{AlbumId, ArtistId};
+use crate::runtime_stats::RuntimeStats;
+use crate::user_data::{LabelTarget, Rating, UserData};
+use crate::playcount::{ExportFormat, PlayCounter, write_csv_field};
 
 /// Changes in the playback state or library to be recorded.
+#[derive(Clone, Debug)]
 pub enum PlaybackEvent {
     Started(QueueId, TrackId),
     Completed(QueueId, TrackId),
+
+    /// The track was removed from the queue before it played long enough to
+    /// count as a listen.
+    Skipped(QueueId, TrackId),
+
     QueueEnded,
 
     /// The user modified the rating for the given track.
@@ -33,22 +47,182 @@ pub enum PlaybackEvent {
         track_id: TrackId,
         rating: Rating,
     },
+
+    /// The user marked the artist as favorite, or unmarked it.
+    ArtistFavorited {
+        artist_id: ArtistId,
+        favorite: bool,
+    },
+
+    /// The user added a label to a track or album, or removed it.
+    Labeled {
+        label: String,
+        target: LabelTarget,
+        labeled: bool,
+    },
+
+    /// The user edited the note of the track, `None` removes the note.
+    TrackNoteSet {
+        track_id: TrackId,
+        note: Option<String>,
+    },
+
+    /// The user edited the note of the album, `None` removes the note.
+    AlbumNoteSet {
+        album_id: AlbumId,
+        note: Option<String>,
+    },
+
+    /// The user modified the rating for the given album.
+    AlbumRated {
+        album_id: AlbumId,
+        rating: Rating,
+    },
+
+    /// The user edited the manual playlist with the given name.
+    PlaylistEdited {
+        name: String,
+        edit: PlaylistEdit,
+    },
+
+    /// The user saved the queue under the given name, replacing any queue
+    /// that was saved under that name before.
+    QueueSaved {
+        name: String,
+        tracks: Vec<TrackId>,
+    },
+
+    /// The user deleted the saved queue with the given name.
+    SavedQueueDeleted(String),
+
+    /// The user deleted the listen with the given id.
+    ListenDeleted(i64),
+
+    /// The user pointed the listen with the given id at a different track.
+    ListenCorrected {
+        listen_id: i64,
+        track_id: TrackId,
+    },
+
+    /// The user resolved an imported listen that did not match by itself,
+    /// either by picking a track, or by skipping it (`track_id` is `None`).
+    MatchResolved {
+        source: ImportSource,
+        started_at: i64,
+        track_id: Option<TrackId>,
+    },
+}
+
+/// Count new listens, and update the scores in the user data accordingly.
+fn update_playcounts(
+    db: &mut Connection,
+    index: &MemoryMetaIndex,
+    user_data: &Mutex<UserData>,
+    mut counter: PlayCounter,
+) -> Result<PlayCounter> {
+    let mut tx = db.begin()?;
+    counter.count_from_database(index, &mut tx)?;
+    tx.commit()?;
+    let counts = counter.into_counts();
+    let artist_user_data = counts.compute_artist_user_data();
+    {
+        // The album and track scores depend on the ratings, so
+        // we compute them while holding the lock.
+        let mut user_data = user_data.lock().unwrap();
+        let album_user_data = counts.compute_album_user_data(index, &user_data);
+        let track_user_data = counts.compute_track_user_data(&user_data);
+        user_data.set_albums(album_user_data);
+        user_data.set_tracks(track_user_data);
+        user_data.set_artists(artist_user_data);
+    }
+    Ok(counts.into_counter())
+}
+
+/// Run `f` in a write transaction, log and count the error if that fails.
+///
+/// Losing one event is bad, but stopping the history thread, and with it the
+/// server, is worse, so we carry on with the next event.
+fn save<T, F>(db: &mut Connection, stats: &RuntimeStats, f: F) -> Option<T>
+where
+    F: FnMut(&mut db::Transaction) -> Result<T>,
+{
+    match with_write_transaction(db, f) {
+        Ok(result) => Some(result),
+        Err(err) => {
+            error!("Failed to save playback event: {:?}", err);
+            stats.count_history_write_failure();
+            None
+        }
+    }
+}
+
+/// The events for the history thread, and what it remembers about past events.
+///
+/// When the history thread fails, it returns this, so the thread that replaces
+/// it can pick up where it left off.
+pub struct EventQueue {
+    events: Receiver<PlaybackEvent>,
+
+    /// An event that a previous thread failed to handle, to retry first.
+    pending: Option<PlaybackEvent>,
+
+    /// How many times handling the pending event failed.
+    pending_attempts: u32,
+
+    /// The listen that the last started track is recorded as. When recording
+    /// the start failed, this is `Some(None)`, then we can't record its end.
+    last_listen_id: Option<Option<i64>>,
+}
+
+impl EventQueue {
+    pub fn new(events: Receiver<PlaybackEvent>) -> EventQueue {
+        EventQueue {
+            events,
+            pending: None,
+            pending_attempts: 0,
+            last_listen_id: None,
+        }
+    }
+
+    /// Return how many times handling the pending event failed.
+    pub fn pending_attempts(&self) -> u32 {
+        self.pending_attempts
+    }
+
+    /// Give up on the pending event, and return it.
+    pub fn drop_pending(&mut self) -> Option<PlaybackEvent> {
+        self.pending_attempts = 0;
+        self.pending.take()
+    }
 }
 
 /// Main for the thread that logs historical playback events.
+///
+/// Returns `Ok` when the player is gone, or the error when the database fails.
+/// Either way, it also returns the queue, with the event that failed as the
+/// pending one.
 pub fn main(
-    db_path: &Path,
+    config_var: Var<Config>,
     index_var: Var<MemoryMetaIndex>,
     user_data: Arc<Mutex<UserData>>,
     mut counter: PlayCounter,
-    events: Receiver<PlaybackEvent>,
-) -> Result<()> {
-    let connection = database_utils::connect_read_write(db_path)?;
-    let mut db = Connection::new(&connection);
+    queue: EventQueue,
+    stats: &RuntimeStats,
+    presence: Option<&Presence>,
+) -> (EventQueue, Result<()>) {
+    let EventQueue { events, mut pending, pending_attempts, mut last_listen_id } = queue;
 
-    let mut last_listen_id = None;
+    // The database settings cannot be reloaded, so we read them only once.
+    let connection = {
+        let config = config_var.get();
+        match database_utils::connect_read_write(&config.db_path, &config.db_pragmas) {
+            Ok(connection) => connection,
+            Err(err) => return (EventQueue { events, pending, pending_attempts, last_listen_id }, Err(err)),
+        }
+    };
+    let mut db = Connection::new(&connection);
 
-    for event in events {
+    let mut handle_event = |event: PlaybackEvent, mut counter: PlayCounter| -> Result<PlayCounter> {
         let now = Utc::now();
         let use_zulu_suffix = true;
         let now_str = now.to_rfc3339_opts(SecondsFormat::Millis, use_zulu_suffix);
@@ -59,39 +233,67 @@ pub fn main(
                 let track = index.get_track(track_id).unwrap();
                 let album = index.get_album(track_id.album_id()).unwrap();
                 let album_artists = index.get_album_artists(album.artist_ids);
-                let listen = Listen {
-                    started_at: &now_str[..],
-                    file_id: track.file_id.0,
-                    queue_id: queue_id.0 as i64,
-                    track_id: track_id.0 as i64,
-                    album_id: track_id.album_id().0 as i64,
-                    // We record only the first album artist, to keep the
-                    // structure of the table simple.
-                    album_artist_id: album_artists[0].0 as i64,
-                    track_title: index.get_string(track.title),
-                    album_title: index.get_string(album.title),
-                    track_artist: index.get_string(track.artist),
-                    album_artist: index.get_string(album.artist),
-                    duration_seconds: track.duration_seconds as i64,
-                    track_number: track_id.track_number() as i64,
-                    disc_number: track_id.disc_number() as i64,
-                };
-                let mut tx = db.begin()?;
-                let result = db::insert_listen_started(&mut tx, listen)?;
-                tx.commit()?;
-                last_listen_id = Some(result);
+                let listen_id = save(&mut db, stats, |tx| {
+                    let listen = Listen {
+                        started_at: &now_str[..],
+                        file_id: track.file_id.0,
+                        queue_id: queue_id.0 as i64,
+                        track_id: track_id.0 as i64,
+                        album_id: track_id.album_id().0 as i64,
+                        // We record only the first album artist, to keep the
+                        // structure of the table simple.
+                        album_artist_id: album_artists[0].0 as i64,
+                        track_title: index.get_string(track.title),
+                        album_title: index.get_string(album.title),
+                        track_artist: index.get_string(track.artist),
+                        album_artist: index.get_string(album.artist),
+                        duration_seconds: track.duration_seconds as i64,
+                        track_number: track_id.track_number() as i64,
+                        disc_number: track_id.disc_number() as i64,
+                        utc_offset_seconds: Local::now().offset().local_minus_utc() as i64,
+                    };
+                    db::insert_listen_started(tx, listen)
+                });
+                last_listen_id = Some(listen_id);
+
+                if let Some(presence) = presence {
+                    presence.playing(track_id);
+                }
+
+                if let Some(exe) = config_var.get().exec_now_playing_path.clone() {
+                    let env = exec_pre_post::track_env(&index, track_id);
+                    // The program talks to external services, which can be
+                    // slow. Run it on a separate thread, so we don't delay
+                    // recording the next events.
+                    let spawn_result = std::thread::Builder::new()
+                        .name("now_playing".into())
+                        .spawn(move || exec_pre_post::execute_program_with_timeout(
+                            &exe,
+                            "now-playing",
+                            &env,
+                        ));
+                    if let Err(err) = spawn_result {
+                        error!("Failed to spawn now-playing thread: {:?}", err);
+                    }
+                }
             }
             PlaybackEvent::Completed(queue_id, track_id) => {
-                if let Some(listen_id) = last_listen_id {
-                    let mut tx = db.begin()?;
-                    db::update_listen_completed(
-                        &mut tx,
-                        listen_id,
-                        queue_id.0 as i64,
-                        track_id.0 as i64,
-                        &now_str[..],
-                    )?;
-                    tx.commit()?;
+                if let Some(listen_id_opt) = last_listen_id {
+                    // If we failed to record the start, that is already
+                    // counted, and there is nothing to update.
+                    let listen_id = match listen_id_opt {
+                        Some(id) => id,
+                        None => return Ok(counter),
+                    };
+                    save(&mut db, stats, |tx| {
+                        db::update_listen_completed(
+                            tx,
+                            listen_id,
+                            queue_id.0 as i64,
+                            track_id.0 as i64,
+                            &now_str[..],
+                        )
+                    });
                 } else {
                     panic!(
                         "Completed queue entry {}, track {}, before starting.",
@@ -99,6 +301,29 @@ pub fn main(
                     );
                 }
             }
+            PlaybackEvent::Skipped(queue_id, track_id) => {
+                if let Some(listen_id_opt) = last_listen_id {
+                    // As for completion, there is nothing to update.
+                    let listen_id = match listen_id_opt {
+                        Some(id) => id,
+                        None => return Ok(counter),
+                    };
+                    save(&mut db, stats, |tx| {
+                        db::update_listen_skipped(
+                            tx,
+                            listen_id,
+                            queue_id.0 as i64,
+                            track_id.0 as i64,
+                            &now_str[..],
+                        )
+                    });
+                } else {
+                    panic!(
+                        "Skipped queue entry {}, track {}, before starting.",
+                        queue_id, track_id,
+                    );
+                }
+            }
             PlaybackEvent::QueueEnded => {
                 // When the queue ends, flush the WAL. This is not really
                 // needed, but I back up my database with rsync once in a
@@ -107,6 +332,10 @@ pub fn main(
                 // the queue ends, before the post-playback program runs.
                 connection.execute("PRAGMA wal_checkpoint(PASSIVE);")?;
 
+                if let Some(presence) = presence {
+                    presence.idle();
+                }
+
                 // Recompute the playcounts as well. We load these from the
                 // database rather than updating the counts on the go for two
                 // reasons:
@@ -119,26 +348,286 @@ pub fn main(
                 // not, but that's fast enough anyway. (The full import +
                 // ranking is 140ms on a Raspberry Pi for ~22k tracks.)
                 let index = index_var.get();
-                let mut tx = db.begin()?;
-                counter.count_from_database(&index, &mut tx)?;
-                tx.commit()?;
-                let counts = counter.into_counts();
-                let album_user_data = counts.compute_album_user_data();
-                user_data.lock().unwrap().set_albums(album_user_data);
-                counter = counts.into_counter();
+                counter = update_playcounts(&mut db, &index, &user_data, counter)?;
             }
             PlaybackEvent::Rated { track_id, rating } => {
-                let mut tx = db.begin()?;
-                db::insert_or_replace_rating(
-                    &mut tx,
-                    track_id.0 as i64,
-                    &now_str,
-                    rating as i64,
-                )?;
-                tx.commit()?;
+                save(&mut db, stats, |tx| {
+                    db::insert_or_replace_rating(
+                        tx,
+                        track_id.0 as i64,
+                        &now_str,
+                        rating as i64,
+                    )
+                });
                 user_data.lock().unwrap().set_track_rating(track_id, rating);
             }
+            PlaybackEvent::ArtistFavorited { artist_id, favorite } => {
+                save(&mut db, stats, |tx| {
+                    if favorite {
+                        db::insert_favorite_artist(tx, artist_id.0 as i64, &now_str)?;
+                    } else {
+                        db::delete_favorite_artist(tx, artist_id.0 as i64)?;
+                    }
+                    Ok(())
+                });
+                user_data.lock().unwrap().set_artist_favorite(artist_id, favorite);
+            }
+            PlaybackEvent::Labeled { label, target, labeled } => {
+                save(&mut db, stats, |tx| {
+                    match (target, labeled) {
+                        (LabelTarget::Track(id), true) => db::insert_track_label(tx, &label, id.0 as i64, &now_str)?,
+                        (LabelTarget::Track(id), false) => db::delete_track_label(tx, &label, id.0 as i64)?,
+                        (LabelTarget::Album(id), true) => db::insert_album_label(tx, &label, id.0 as i64, &now_str)?,
+                        (LabelTarget::Album(id), false) => db::delete_album_label(tx, &label, id.0 as i64)?,
+                    }
+                    Ok(())
+                });
+                user_data.lock().unwrap().set_label(&label, target, labeled);
+            }
+            PlaybackEvent::TrackNoteSet { track_id, note } => {
+                save(&mut db, stats, |tx| {
+                    match note.as_ref() {
+                        Some(text) => db::insert_or_replace_track_note(tx, track_id.0 as i64, text, &now_str)?,
+                        None => db::delete_track_note(tx, track_id.0 as i64)?,
+                    }
+                    Ok(())
+                });
+                user_data.lock().unwrap().set_track_note(track_id, note);
+            }
+            PlaybackEvent::AlbumNoteSet { album_id, note } => {
+                save(&mut db, stats, |tx| {
+                    match note.as_ref() {
+                        Some(text) => db::insert_or_replace_album_note(tx, album_id.0 as i64, text, &now_str)?,
+                        None => db::delete_album_note(tx, album_id.0 as i64)?,
+                    }
+                    Ok(())
+                });
+                user_data.lock().unwrap().set_album_note(album_id, note);
+            }
+            PlaybackEvent::AlbumRated { album_id, rating } => {
+                save(&mut db, stats, |tx| {
+                    db::insert_or_replace_album_rating(
+                        tx,
+                        album_id.0 as i64,
+                        &now_str,
+                        rating as i64,
+                    )
+                });
+                user_data.lock().unwrap().set_album_rating(album_id, rating);
+            }
+            PlaybackEvent::PlaylistEdited { name, edit } => {
+                save(&mut db, stats, |tx| {
+                    playlist::apply_edit(tx, &name, &edit, &now_str)
+                });
+            }
+            PlaybackEvent::QueueSaved { name, tracks } => {
+                save(&mut db, stats, |tx| {
+                    db::delete_saved_queue(tx, &name)?;
+                    db::insert_saved_queue(tx, &name, &now_str)?;
+                    let id = db::select_saved_queue_id(tx, &name)?.expect("We just inserted it.");
+                    for (position, track_id) in tracks.iter().enumerate() {
+                        db::insert_saved_queue_track(tx, id, position as i64, track_id.0 as i64)?;
+                    }
+                    Ok(())
+                });
+            }
+            PlaybackEvent::SavedQueueDeleted(name) => {
+                save(&mut db, stats, |tx| {
+                    db::delete_saved_queue(tx, &name)
+                });
+            }
+            PlaybackEvent::ListenDeleted(listen_id) => {
+                save(&mut db, stats, |tx| {
+                    db::delete_listen(tx, listen_id)
+                });
+
+                // Counting is incremental, and the listen can be anywhere in
+                // the past, so we have to count everything from scratch.
+                let index = index_var.get();
+                counter = update_playcounts(&mut db, &index, &user_data, counter.cleared())?;
+            }
+            PlaybackEvent::ListenCorrected { listen_id, track_id } => {
+                let index = index_var.get();
+                let track = match index.get_track(track_id) {
+                    Some(track) => track,
+                    // The track can be gone if the library was rescanned in
+                    // the meantime, then there is nothing to point at.
+                    None => return Ok(counter),
+                };
+                let album = index.get_album(track_id.album_id()).unwrap();
+                let album_artists = index.get_album_artists(album.artist_ids);
+                save(&mut db, stats, |tx| {
+                    let correction = db::ListenCorrection {
+                        listen_id,
+                        file_id: track.file_id.0,
+                        track_id: track_id.0 as i64,
+                        album_id: track_id.album_id().0 as i64,
+                        album_artist_id: album_artists[0].0 as i64,
+                        track_title: index.get_string(track.title),
+                        album_title: index.get_string(album.title),
+                        track_artist: index.get_string(track.artist),
+                        album_artist: index.get_string(album.artist),
+                        duration_seconds: track.duration_seconds as i64,
+                        track_number: track_id.track_number() as i64,
+                        disc_number: track_id.disc_number() as i64,
+                    };
+                    db::update_listen_track(tx, correction)
+                });
+
+                // Like for deletion, recount from scratch.
+                counter = update_playcounts(&mut db, &index, &user_data, counter.cleared())?;
+            }
+            PlaybackEvent::MatchResolved { source, started_at, track_id } => {
+                let index = index_var.get();
+                if let Some(id) = track_id {
+                    // As for corrections, the track can be gone by now.
+                    if index.get_track(id).is_none() {
+                        return Ok(counter)
+                    }
+                }
+                save(&mut db, stats, |tx| {
+                    matcher::resolve_listen(&index, tx, source, started_at, track_id, &now_str)
+                });
+
+                // The listen can be anywhere in the past, recount from scratch.
+                if track_id.is_some() {
+                    counter = update_playcounts(&mut db, &index, &user_data, counter.cleared())?;
+                }
+            }
         }
+
+        Ok(counter)
+    };
+
+    loop {
+        let (event, attempts) = match pending.take() {
+            Some(event) => (event, pending_attempts),
+            None => match events.recv() {
+                Ok(event) => (event, 0),
+                // All senders are gone, so there will be no more events.
+                Err(_) => return (EventQueue { events, pending, pending_attempts: 0, last_listen_id }, Ok(())),
+            },
+        };
+        // Handling the event consumes the counter. If that fails, the counter
+        // may be gone, but the next thread starts with a new one anyway.
+        counter = match handle_event(event.clone(), counter) {
+            Ok(counter) => counter,
+            Err(err) => {
+                let queue = EventQueue {
+                    events,
+                    pending: Some(event),
+                    pending_attempts: attempts + 1,
+                    last_listen_id,
+                };
+                return (queue, Err(err));
+            }
+        };
+    }
+}
+
+/// Write one listen as a csv row, see also [`export_listens`].
+fn write_listen_csv<W: Write>(mut w: W, listen: &db::ListenExport) -> io::Result<()> {
+    write!(w, "{},{},", listen.id, listen.started_at)?;
+    for t in [&listen.completed_at, &listen.skipped_at] {
+        write!(w, "{},", t.as_deref().unwrap_or(""))?;
+    }
+    write!(
+        w,
+        "{},{},{},",
+        TrackId(listen.track_id as u64),
+        AlbumId(listen.album_id as u64),
+        ArtistId(listen.album_artist_id as u64),
+    )?;
+    for field in [&listen.track_title, &listen.album_title, &listen.track_artist, &listen.album_artist] {
+        write_csv_field(&mut w, field)?;
+        write!(w, ",")?;
+    }
+    write!(w, "{},", listen.duration_seconds)?;
+    for n in [listen.track_number, listen.disc_number] {
+        match n {
+            Some(n) => write!(w, "{},", n)?,
+            None => write!(w, ",")?,
+        }
+    }
+    writeln!(
+        w,
+        "{},{},{}",
+        listen.source,
+        listen.scrobbled_at.as_deref().unwrap_or(""),
+        listen.listenbrainz_submitted_at.as_deref().unwrap_or(""),
+    )
+}
+
+/// Write one listen as a json object, see also [`export_listens`].
+fn write_listen_json<W: Write>(mut w: W, listen: &db::ListenExport) -> io::Result<()> {
+    write!(w, r#"{{"id":{},"started_at":"#, listen.id)?;
+    serde_json::to_writer(&mut w, &listen.started_at)?;
+    write!(w, r#","completed_at":"#)?;
+    serde_json::to_writer(&mut w, &listen.completed_at)?;
+    write!(w, r#","skipped_at":"#)?;
+    serde_json::to_writer(&mut w, &listen.skipped_at)?;
+    write!(
+        w,
+        r#","track_id":"{}","album_id":"{}","album_artist_id":"{}","track_title":"#,
+        TrackId(listen.track_id as u64),
+        AlbumId(listen.album_id as u64),
+        ArtistId(listen.album_artist_id as u64),
+    )?;
+    serde_json::to_writer(&mut w, &listen.track_title)?;
+    write!(w, r#","album_title":"#)?;
+    serde_json::to_writer(&mut w, &listen.album_title)?;
+    write!(w, r#","track_artist":"#)?;
+    serde_json::to_writer(&mut w, &listen.track_artist)?;
+    write!(w, r#","album_artist":"#)?;
+    serde_json::to_writer(&mut w, &listen.album_artist)?;
+    write!(w, r#","duration_seconds":{},"track_number":"#, listen.duration_seconds)?;
+    serde_json::to_writer(&mut w, &listen.track_number)?;
+    write!(w, r#","disc_number":"#)?;
+    serde_json::to_writer(&mut w, &listen.disc_number)?;
+    write!(w, r#","source":"#)?;
+    serde_json::to_writer(&mut w, &listen.source)?;
+    write!(w, r#","scrobbled_at":"#)?;
+    serde_json::to_writer(&mut w, &listen.scrobbled_at)?;
+    write!(w, r#","listenbrainz_submitted_at":"#)?;
+    serde_json::to_writer(&mut w, &listen.listenbrainz_submitted_at)?;
+    write!(w, "}}")
+}
+
+/// Write the entire listening history, in the order in which we recorded it.
+///
+/// Track, album, and artist ids are formatted like in the API. Absent values
+/// are empty in csv and `null` in json.
+pub fn export_listens<W: Write>(
+    tx: &mut db::Transaction,
+    format: ExportFormat,
+    mut w: W,
+) -> crate::Result<()> {
+    match format {
+        ExportFormat::Csv => writeln!(
+            w,
+            "id,started_at,completed_at,skipped_at,track_id,album_id,album_artist_id,\
+            track_title,album_title,track_artist,album_artist,duration_seconds,\
+            track_number,disc_number,source,scrobbled_at,listenbrainz_submitted_at",
+        )?,
+        ExportFormat::Json => write!(w, "[")?,
+    }
+
+    let mut first = true;
+    for listen_opt in db::iter_listens_export(tx)? {
+        let listen = listen_opt?;
+        match format {
+            ExportFormat::Csv => write_listen_csv(&mut w, &listen)?,
+            ExportFormat::Json => {
+                if !first { write!(w, ",")?; }
+                write_listen_json(&mut w, &listen)?;
+            }
+        }
+        first = false;
+    }
+
+    match format {
+        ExportFormat::Csv => {}
+        ExportFormat::Json => writeln!(w, "]")?,
     }
 
     Ok(())
@@ -0,0 +1,230 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Loading Last.fm listening history from a CSV export.
+//!
+//! The listens get loaded into the `lastfm_listens` table, the same table that
+//! `tools/scrobble.py lastfm import` fills from the Last.fm API. From there,
+//! [`crate::matcher::import_listens`] matches them to tracks.
+
+use std::fs;
+
+use chrono::NaiveDateTime;
+
+use crate::database as db;
+use crate::error::Result;
+
+/// Split CSV data into records of fields.
+///
+/// Fields can be quoted, and quoted fields can contain commas, newlines, and
+/// doubled quotes. Empty lines are skipped.
+fn parse_csv(input: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match (in_quotes, ch) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => in_quotes = false,
+            (true, _) => field.push(ch),
+            (false, '"') => in_quotes = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') => {}
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (false, _) => field.push(ch),
+        }
+    }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records.retain(|r| r.len() > 1 || !r[0].is_empty());
+    records
+}
+
+/// The column layout of a Last.fm CSV export.
+#[derive(Debug, Eq, PartialEq)]
+enum CsvFormat {
+    /// Columns `artist,album,track,date` without header, as produced by
+    /// <https://benjaminbenben.com/lastfm-to-csv/>.
+    ///
+    /// The date is in UTC, formatted like `31 Jan 2021 12:34`, so it lacks
+    /// the seconds.
+    ArtistAlbumTrackDate,
+
+    /// A header row with named columns, as produced by
+    /// <https://lastfm.ghan.nl/export/>.
+    ///
+    /// We use the `uts` (seconds since epoch), `artist`, `album`, `track`, and
+    /// optionally `album_mbid` columns, the values are their indices.
+    Named {
+        uts: usize,
+        artist: usize,
+        album: usize,
+        track: usize,
+        album_mbid: Option<usize>,
+    },
+}
+
+impl CsvFormat {
+    /// Determine the format from the first record.
+    fn detect(first: &[String]) -> CsvFormat {
+        let column = |name: &str| first.iter().position(|c| c == name);
+        match (column("uts"), column("artist"), column("album"), column("track")) {
+            (Some(uts), Some(artist), Some(album), Some(track)) => CsvFormat::Named {
+                uts,
+                artist,
+                album,
+                track,
+                album_mbid: column("album_mbid"),
+            },
+            _ => CsvFormat::ArtistAlbumTrackDate,
+        }
+    }
+
+    fn has_header(&self) -> bool {
+        matches!(self, CsvFormat::Named { .. })
+    }
+
+    /// Whether the timestamps in this format have second precision.
+    fn has_seconds(&self) -> bool {
+        matches!(self, CsvFormat::Named { .. })
+    }
+
+    /// Parse a record into a listen, or `None` if the record is malformed.
+    fn parse<'a>(&self, record: &'a [String]) -> Option<db::LastfmListenInsert<'a>> {
+        match *self {
+            CsvFormat::ArtistAlbumTrackDate => {
+                if record.len() != 4 {
+                    return None
+                }
+                let date = NaiveDateTime::parse_from_str(&record[3], "%d %b %Y %H:%M").ok()?;
+                let result = db::LastfmListenInsert {
+                    started_at: date.timestamp(),
+                    title: &record[2],
+                    track_artist: &record[0],
+                    album: &record[1],
+                    album_mbid: "",
+                };
+                Some(result)
+            }
+            CsvFormat::Named { uts, artist, album, track, album_mbid } => {
+                let result = db::LastfmListenInsert {
+                    started_at: record.get(uts)?.parse().ok()?,
+                    title: record.get(track)?,
+                    track_artist: record.get(artist)?,
+                    album: record.get(album)?,
+                    album_mbid: match album_mbid {
+                        Some(i) => record.get(i)?,
+                        None => "",
+                    },
+                };
+                Some(result)
+            }
+        }
+    }
+}
+
+/// Load the listens from a Last.fm CSV export into the `lastfm_listens` table.
+///
+/// When the export lacks seconds, we cannot rely on the unique-second index to
+/// deduplicate listens that Musium produced and scrobbled itself, so then we
+/// skip listens that fall in a minute that already has a listen.
+pub fn load_lastfm_csv(tx: &mut db::Transaction, path: &str) -> Result<()> {
+    let input = fs::read_to_string(path)?;
+    let records = parse_csv(&input);
+
+    let format = match records.first() {
+        Some(first) => CsvFormat::detect(first),
+        None => {
+            println!("No listens in {}.", path);
+            return Ok(())
+        }
+    };
+    let skip = if format.has_header() { 1 } else { 0 };
+
+    let mut n_loaded = 0_u32;
+    let mut n_duplicate = 0_u32;
+    let mut n_malformed = 0_u32;
+
+    for record in records.iter().skip(skip) {
+        let listen = match format.parse(record) {
+            Some(listen) => listen,
+            None => {
+                n_malformed += 1;
+                println!("MALFORMED {:?}", record);
+                continue
+            }
+        };
+
+        if !format.has_seconds() {
+            let t = listen.started_at;
+            if db::count_listens_between(tx, t, t + 60)? > 0 {
+                n_duplicate += 1;
+                continue
+            }
+        }
+
+        db::insert_lastfm_listen(tx, listen)?;
+        n_loaded += 1;
+    }
+
+    println!(
+        "Loaded {} listens, skipped {} duplicates and {} malformed rows.",
+        n_loaded, n_duplicate, n_malformed,
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_csv, CsvFormat};
+
+    #[test]
+    fn parse_csv_handles_quotes() {
+        let input = "a,\"b, c\",\"say \"\"hi\"\"\"\r\n\nx,\"multi\nline\",\n";
+        let records = parse_csv(input);
+        assert_eq!(records, vec![
+            vec!["a".to_string(), "b, c".to_string(), "say \"hi\"".to_string()],
+            vec!["x".to_string(), "multi\nline".to_string(), "".to_string()],
+        ]);
+    }
+
+    #[test]
+    fn csv_format_parses_both_layouts() {
+        let records = parse_csv("Bonobo,Migration,Kerala,31 Jan 2021 12:34\n");
+        let format = CsvFormat::detect(&records[0]);
+        assert_eq!(format, CsvFormat::ArtistAlbumTrackDate);
+        let listen = format.parse(&records[0]).unwrap();
+        assert_eq!(listen.started_at, 1612096440);
+        assert_eq!(listen.title, "Kerala");
+        assert_eq!(listen.track_artist, "Bonobo");
+
+        let records = parse_csv(
+            "uts,utc_time,artist,artist_mbid,album,album_mbid,track,track_mbid\n\
+             1612096477,31 Jan 2021 12:34,Bonobo,,Migration,abc,Kerala,\n"
+        );
+        let format = CsvFormat::detect(&records[0]);
+        assert!(format.has_header());
+        let listen = format.parse(&records[1]).unwrap();
+        assert_eq!(listen.started_at, 1612096477);
+        assert_eq!(listen.album, "Migration");
+        assert_eq!(listen.album_mbid, "abc");
+    }
+}
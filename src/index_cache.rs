@@ -0,0 +1,364 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Serialization of the full `MemoryMetaIndex`, to load it at startup.
+//!
+//! Building the index means reading the tags of every file from the database,
+//! which on a Raspberry Pi with a large library takes a while. After a scan, we
+//! save the built index as a single blob, and at startup we load that instead,
+//! when the files did not change since.
+//!
+//! The format follows that of the word indexes: integers are little-endian,
+//! and every array is prefixed with its length. The word indexes are embedded
+//! as they are, with their own version. Bookmarks are not stored, they are
+//! cheap to rebuild from the ids.
+
+use std::num::NonZeroI16;
+
+use crate::{Bookmarks, MemoryMetaIndex};
+use crate::prim::{AlbumArtistsRef, Album, AlbumId, Artist, ArtistId, Date, FileId, FilenameRef, Instant};
+use crate::prim::{Lufs, StringRef, Track, TrackId};
+use crate::prim::{AlbumWithId, ArtistWithId, TrackWithId};
+use crate::string_utils::StringArena;
+use crate::word_index::{MemoryWordIndex, Reader};
+
+/// Version of the format that `to_bytes` writes.
+///
+/// Bump this when the format changes, or when the way we build the index
+/// changes, so we don't load an index that is stale.
+pub const SERIALIZATION_VERSION: u32 = 2;
+
+fn push_u32(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, x: u64) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn push_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    push_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Encode optional loudness, 0 is not a valid `Lufs`, so it means none.
+fn lufs_to_u32(lufs: Option<Lufs>) -> u32 {
+    lufs.map_or(0, |x| x.0.get()) as u16 as u32
+}
+
+fn lufs_from_u32(x: u32) -> Option<Lufs> {
+    NonZeroI16::new(x as u16 as i16).map(Lufs)
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self) -> Option<&'a [u8]> {
+        let n = self.read_len()?;
+        self.take(n)
+    }
+
+    fn read_arena(&mut self) -> Option<StringArena> {
+        let data = String::from_utf8(self.read_bytes()?.to_vec()).ok()?;
+        let n = self.read_array_len(4)?;
+        let offsets = (0..n).map(|_| self.read_u32()).collect::<Option<Vec<u32>>>()?;
+        StringArena::from_parts(data, offsets)
+    }
+
+    /// Read the length of an array of `size`-byte elements, and confirm that
+    /// the input is long enough to hold them.
+    fn read_array_len(&mut self, size: usize) -> Option<usize> {
+        let n = self.read_len()?;
+        if self.bytes.len() < n * size {
+            return None
+        }
+        Some(n)
+    }
+}
+
+/// Serialize the index, so it can be loaded with `from_bytes` later.
+pub fn to_bytes(index: &MemoryMetaIndex) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    push_u32(&mut out, SERIALIZATION_VERSION);
+
+    push_u32(&mut out, index.artists.len() as u32);
+    for kv in &index.artists {
+        push_u64(&mut out, kv.artist_id.0);
+        push_u32(&mut out, kv.artist.name.0);
+        push_u32(&mut out, kv.artist.name_for_sort.0);
+    }
+
+    push_u32(&mut out, index.albums.len() as u32);
+    for kv in &index.albums {
+        let album = &kv.album;
+        push_u64(&mut out, kv.album_id.0);
+        push_u32(&mut out, album.artist_ids.begin);
+        push_u32(&mut out, album.artist_ids.end);
+        push_u32(&mut out, album.artist.0);
+        push_u32(&mut out, album.title.0);
+        let date = album.original_release_date;
+        push_u32(&mut out, (date.year as u32) << 16 | (date.month as u32) << 8 | date.day as u32);
+        push_u32(&mut out, lufs_to_u32(album.loudness));
+        push_u64(&mut out, album.first_seen.posix_seconds_utc as u64);
+    }
+
+    push_u32(&mut out, index.tracks.len() as u32);
+    for kv in &index.tracks {
+        let track = &kv.track;
+        push_u64(&mut out, kv.track_id.0);
+        push_u64(&mut out, track.file_id.0 as u64);
+        push_u32(&mut out, track.title.0);
+        push_u32(&mut out, track.artist.0);
+        push_u32(&mut out, track.filename.0);
+        push_u32(&mut out, track.duration_seconds as u32);
+        push_u32(&mut out, lufs_to_u32(track.loudness));
+    }
+
+    push_u32(&mut out, index.albums_by_artist.len() as u32);
+    for (artist_id, album_id) in &index.albums_by_artist {
+        push_u64(&mut out, artist_id.0);
+        push_u64(&mut out, album_id.0);
+    }
+
+    push_u32(&mut out, index.album_artists.len() as u32);
+    for artist_id in &index.album_artists {
+        push_u64(&mut out, artist_id.0);
+    }
+
+    for arena in [&index.strings, &index.filenames] {
+        push_bytes(&mut out, arena.data().as_bytes());
+        push_u32(&mut out, arena.offsets().len() as u32);
+        for &offset in arena.offsets() {
+            push_u32(&mut out, offset);
+        }
+    }
+
+    push_bytes(&mut out, &index.words_artist.to_bytes());
+    push_bytes(&mut out, &index.words_album.to_bytes());
+    push_bytes(&mut out, &index.words_track.to_bytes());
+
+    out
+}
+
+/// Deserialize an index that `to_bytes` wrote.
+///
+/// Returns `None` if the data is not valid, or if it was written with a
+/// different version of the format.
+pub fn from_bytes(bytes: &[u8]) -> Option<MemoryMetaIndex> {
+    let mut r = Reader { bytes };
+
+    if r.read_u32()? != SERIALIZATION_VERSION {
+        return None
+    }
+
+    let n = r.read_array_len(16)?;
+    let mut artists = Vec::with_capacity(n);
+    for _ in 0..n {
+        artists.push(ArtistWithId {
+            artist_id: ArtistId(r.read_u64()?),
+            artist: Artist {
+                name: StringRef(r.read_u32()?),
+                name_for_sort: StringRef(r.read_u32()?),
+            },
+        });
+    }
+
+    let n = r.read_array_len(40)?;
+    let mut albums = Vec::with_capacity(n);
+    for _ in 0..n {
+        let album_id = AlbumId(r.read_u64()?);
+        let artist_ids = AlbumArtistsRef { begin: r.read_u32()?, end: r.read_u32()? };
+        let artist = StringRef(r.read_u32()?);
+        let title = StringRef(r.read_u32()?);
+        let date = r.read_u32()?;
+        let album = Album {
+            artist_ids,
+            artist,
+            title,
+            original_release_date: Date {
+                year: (date >> 16) as u16,
+                month: (date >> 8) as u8,
+                day: date as u8,
+            },
+            loudness: lufs_from_u32(r.read_u32()?),
+            first_seen: Instant { posix_seconds_utc: r.read_u64()? as i64 },
+        };
+        albums.push(AlbumWithId { album_id, album });
+    }
+
+    let n = r.read_array_len(36)?;
+    let mut tracks = Vec::with_capacity(n);
+    for _ in 0..n {
+        let track_id = TrackId(r.read_u64()?);
+        let track = Track {
+            file_id: FileId(r.read_u64()? as i64),
+            title: StringRef(r.read_u32()?),
+            artist: StringRef(r.read_u32()?),
+            filename: FilenameRef(r.read_u32()?),
+            duration_seconds: r.read_u32()? as u16,
+            loudness: lufs_from_u32(r.read_u32()?),
+        };
+        tracks.push(TrackWithId { track_id, track });
+    }
+
+    let n = r.read_array_len(16)?;
+    let mut albums_by_artist = Vec::with_capacity(n);
+    for _ in 0..n {
+        albums_by_artist.push((ArtistId(r.read_u64()?), AlbumId(r.read_u64()?)));
+    }
+
+    let n = r.read_array_len(8)?;
+    let mut album_artists = Vec::with_capacity(n);
+    for _ in 0..n {
+        album_artists.push(ArtistId(r.read_u64()?));
+    }
+
+    let strings = r.read_arena()?;
+    let filenames = r.read_arena()?;
+
+    let words_artist = MemoryWordIndex::from_bytes(r.read_bytes()?)?;
+    let words_album = MemoryWordIndex::from_bytes(r.read_bytes()?)?;
+    let words_track = MemoryWordIndex::from_bytes(r.read_bytes()?)?;
+
+    if !r.bytes.is_empty() {
+        return None
+    }
+
+    // Validate the references, so that lookups cannot go out of bounds, and
+    // the order, because the bookmarks and binary searches rely on it.
+    let string_valid = |sr: StringRef| (sr.0 as usize) < strings.len();
+    let artists_valid = artists.iter().all(|kv| {
+        string_valid(kv.artist.name) && string_valid(kv.artist.name_for_sort)
+    });
+    let albums_valid = albums.iter().all(|kv| {
+        let ids = kv.album.artist_ids;
+        string_valid(kv.album.artist)
+            && string_valid(kv.album.title)
+            && ids.begin <= ids.end
+            && (ids.end as usize) <= album_artists.len()
+    });
+    let tracks_valid = tracks.iter().all(|kv| {
+        string_valid(kv.track.title)
+            && string_valid(kv.track.artist)
+            && (kv.track.filename.0 as usize) < filenames.len()
+    });
+    let sorted_valid = artists.windows(2).all(|w| w[0].artist_id < w[1].artist_id)
+        && albums.windows(2).all(|w| w[0].album_id < w[1].album_id)
+        && tracks.windows(2).all(|w| w[0].track_id < w[1].track_id)
+        && albums_by_artist.windows(2).all(|w| w[0].0 <= w[1].0);
+    if !(artists_valid && albums_valid && tracks_valid && sorted_valid) {
+        return None
+    }
+
+    let index = MemoryMetaIndex {
+        artist_bookmarks: Bookmarks::new(artists.iter().map(|p| p.artist_id.0)),
+        album_bookmarks: Bookmarks::new(albums.iter().map(|p| p.album_id.for_bookmark())),
+        track_bookmarks: Bookmarks::new(tracks.iter().map(|p| p.track_id.0)),
+        albums_by_artist_bookmarks: Bookmarks::new(albums_by_artist.iter().map(|p| (p.0).0)),
+        artists,
+        albums,
+        tracks,
+        albums_by_artist,
+        strings,
+        filenames,
+        album_artists,
+        words_artist,
+        words_album,
+        words_track,
+    };
+
+    Some(index)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use crate::{MemoryMetaIndex, MetaIndex};
+    use crate::prim::{AlbumArtistsRef, Album, AlbumId, Artist, ArtistId, Date, FileId, FilenameRef, Instant};
+    use crate::prim::{Lufs, StringRef, Track, TrackId};
+    use crate::prim::{AlbumWithId, ArtistWithId, TrackWithId};
+    use crate::word_index::{MemoryWordIndex, WordMeta};
+    use super::{from_bytes, to_bytes};
+
+    fn make_index() -> MemoryMetaIndex {
+        let mut index = MemoryMetaIndex::new_empty();
+        let artist_id = ArtistId(7);
+        let album_id = AlbumId(1 << 40);
+        let track_id = TrackId::new(album_id, 1, 3);
+
+        for s in ["Röyksopp", "royksopp", "Melody A.M.", "Eple"] {
+            index.strings.push(s);
+        }
+        index.filenames.push("/music/Röyksopp/Melody A.M./03.flac");
+        index.album_artists = vec![artist_id];
+        index.artists = vec![ArtistWithId {
+            artist_id,
+            artist: Artist { name: StringRef(0), name_for_sort: StringRef(1) },
+        }];
+        index.albums = vec![AlbumWithId {
+            album_id,
+            album: Album {
+                artist_ids: AlbumArtistsRef { begin: 0, end: 1 },
+                artist: StringRef(0),
+                title: StringRef(2),
+                original_release_date: Date::new(2001, 9, 3),
+                loudness: Some(Lufs::new(-915)),
+                first_seen: Instant { posix_seconds_utc: 1_600_000_000 },
+            },
+        }];
+        index.tracks = vec![TrackWithId {
+            track_id,
+            track: Track {
+                file_id: FileId(42),
+                title: StringRef(3),
+                artist: StringRef(0),
+                filename: FilenameRef(0),
+                duration_seconds: 220,
+                loudness: None,
+            },
+        }];
+        index.albums_by_artist = vec![(artist_id, album_id)];
+
+        let meta = WordMeta::new(8, 8, 0, 0);
+        index.words_artist = MemoryWordIndex::new(&BTreeSet::from([("royksopp".to_string(), artist_id, meta)]));
+        index.words_album = MemoryWordIndex::new(&BTreeSet::from([("melody".to_string(), album_id, meta)]));
+        index.words_track = MemoryWordIndex::new(&BTreeSet::from([("eple".to_string(), track_id, meta)]));
+
+        // Round trip once, so the bookmarks match the data.
+        from_bytes(&to_bytes(&index)).unwrap()
+    }
+
+    #[test]
+    fn index_bytes_round_trip() {
+        let index = make_index();
+        let bytes = to_bytes(&index);
+        let loaded = from_bytes(&bytes).unwrap();
+        assert_eq!(to_bytes(&loaded), bytes);
+
+        let track_id = loaded.get_tracks()[0].track_id;
+        let track = loaded.get_track(track_id).unwrap();
+        assert_eq!(loaded.get_string(track.title), "Eple");
+        assert_eq!(loaded.get_filename(track.filename), "/music/Röyksopp/Melody A.M./03.flac");
+        let album = loaded.get_album(track_id.album_id()).unwrap();
+        assert_eq!(album.original_release_date, Date::new(2001, 9, 3));
+        assert_eq!(album.loudness, Some(Lufs::new(-915)));
+        assert_eq!(loaded.get_albums_by_artist(ArtistId(7)).len(), 1);
+
+        // Truncated or trailing data is rejected rather than loaded.
+        assert!(from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(from_bytes(&longer).is_none());
+    }
+
+    #[test]
+    fn index_from_bytes_rejects_out_of_bounds_references() {
+        let mut index = make_index();
+        index.tracks[0].track.filename = FilenameRef(1);
+        assert!(from_bytes(&to_bytes(&index)).is_none());
+    }
+}
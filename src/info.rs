@@ -0,0 +1,289 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Print everything we know about a file, track, or album.
+//!
+//! This is mostly useful for finding out why a track does not show up in the
+//! library, or shows up differently than expected.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::build::Issue;
+use crate::config::Config;
+use crate::database::{self as db, FileInfo, Transaction};
+use crate::database_utils::connect_readonly;
+use crate::prim::{AlbumId, FileId, Instant, TrackId};
+use crate::user_data::{LabelTarget, UserData};
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// Return the filenames that `target` could be stored as in the files table.
+///
+/// The files table stores the paths that we found when walking the library
+/// directory, so a path relative to the working directory, or a path that
+/// refers to the same file in a different way, does not match literally.
+fn filename_candidates(library_path: &Path, current_dir: &Path, target: &str) -> Vec<PathBuf> {
+    let mut result = vec![PathBuf::from(target), current_dir.join(target)];
+
+    if let (Ok(target_abs), Ok(library_abs)) = (
+        fs::canonicalize(current_dir.join(target)),
+        fs::canonicalize(library_path),
+    ) {
+        if let Ok(relative) = target_abs.strip_prefix(&library_abs) {
+            result.push(library_path.join(relative));
+        }
+        result.push(target_abs);
+    }
+
+    result.dedup();
+    result
+}
+
+fn find_file(tx: &mut Transaction, config: &Config, target: &str) -> crate::Result<Option<FileInfo>> {
+    let current_dir = std::env::current_dir()?;
+    for candidate in filename_candidates(&config.library_path, &current_dir, target) {
+        if let Some(filename) = candidate.to_str() {
+            if let Some(file) = db::select_file_by_filename(tx, filename)? {
+                return Ok(Some(file));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn print_issues<'a, I: Iterator<Item = &'a Issue>>(issues: I) {
+    println!("\nISSUES\n");
+    let mut any = false;
+    for issue in issues {
+        println!("  {}", issue.to_string().replace('\n', "\n  "));
+        any = true;
+    }
+    if !any {
+        println!("  None.");
+    }
+}
+
+fn print_labels_and_note(labels: Vec<&str>, note: Option<&str>) {
+    if !labels.is_empty() {
+        println!("  Labels:      {}", labels.join(", "));
+    }
+    if let Some(note) = note {
+        println!("  Note:        {}", note);
+    }
+}
+
+fn print_file(tx: &mut Transaction, file: &FileInfo) -> crate::Result<()> {
+    let mtime = Instant { posix_seconds_utc: file.mtime };
+    println!("\nFILE\n");
+    println!("  Path:        {}", file.filename);
+    println!("  File id:     {}", file.id);
+    println!("  Modified:    {}", mtime.format_iso8601());
+    println!("  Channels:    {}", file.streaminfo_channels);
+    println!("  Bit depth:   {}", file.streaminfo_bits_per_sample);
+    println!("  Sample rate: {} Hz", file.streaminfo_sample_rate);
+    match file.streaminfo_num_samples {
+        Some(n) => println!("  Samples:     {}", n),
+        None => println!("  Samples:     unknown"),
+    }
+
+    println!("\nTAGS\n");
+    for opt_pair in db::iter_file_tags(tx, file.id)? {
+        let (field_name, value) = opt_pair?;
+        println!("  {:26} {}", field_name, value);
+    }
+
+    Ok(())
+}
+
+fn print_track(
+    index: &MemoryMetaIndex,
+    user_data: &UserData,
+    tx: &mut Transaction,
+    track_id: TrackId,
+) -> crate::Result<()> {
+    let track = index.get_track(track_id).expect("Track must exist.");
+    let album_id = track_id.album_id();
+    let album = index.get_album(album_id).expect("Album of track must exist.");
+    let artist_ids: Vec<String> = index
+        .get_album_artists(album.artist_ids)
+        .iter()
+        .map(|id| id.to_string())
+        .collect();
+
+    println!("\nTRACK\n");
+    println!("  Track id:    {}", track_id);
+    println!("  Album id:    {}", album_id);
+    println!("  Artist ids:  {}", artist_ids.join(", "));
+    println!("  File:        {}", index.get_filename(track.filename));
+    println!("  Title:       {}", index.get_string(track.title));
+    println!("  Artist:      {}", index.get_string(track.artist));
+    println!("  Album:       {}", index.get_string(album.title));
+    println!("  Disc.track:  {}.{:02}", track_id.disc_number(), track_id.track_number());
+    println!("  Duration:    {}:{:02}", track.duration_seconds / 60, track.duration_seconds % 60);
+    match track.loudness {
+        Some(lufs) => println!("  Loudness:    {}", lufs),
+        None => println!("  Loudness:    not analyzed"),
+    }
+    match db::select_track_waveform(tx, track_id.0 as i64)? {
+        Some(data) => println!("  Waveform:    {} bytes", data.len()),
+        None => println!("  Waveform:    not analyzed"),
+    }
+    match db::select_thumbnail_size(tx, album_id.0 as i64)? {
+        Some(size) => println!("  Thumbnail:   {} bytes", size),
+        None => println!("  Thumbnail:   missing"),
+    }
+    println!("  Rating:      {:?}", user_data.get_track_rating(track_id));
+    match db::select_track_listen_stats(tx, track_id.0 as i64)? {
+        (n, Some(last)) => println!("  Listens:     {}, most recent at {}", n, last),
+        (n, None) => println!("  Listens:     {}", n),
+    }
+    print_labels_and_note(
+        user_data.get_target_labels(LabelTarget::Track(track_id)),
+        user_data.get_track_note(track_id),
+    );
+
+    Ok(())
+}
+
+fn print_album(
+    index: &MemoryMetaIndex,
+    user_data: &UserData,
+    tx: &mut Transaction,
+    album_id: AlbumId,
+) -> crate::Result<()> {
+    let album = index.get_album(album_id).expect("Album must exist.");
+    let artist_ids: Vec<String> = index
+        .get_album_artists(album.artist_ids)
+        .iter()
+        .map(|id| id.to_string())
+        .collect();
+
+    let mut n_listens = 0;
+    for kv in index.get_album_tracks(album_id) {
+        n_listens += db::select_track_listen_stats(tx, kv.track_id.0 as i64)?.0;
+    }
+
+    println!("\nALBUM\n");
+    println!("  Album id:    {}", album_id);
+    println!("  Artist ids:  {}", artist_ids.join(", "));
+    println!("  Title:       {}", index.get_string(album.title));
+    println!("  Artist:      {}", index.get_string(album.artist));
+    println!("  Released:    {}", album.original_release_date);
+    println!("  First seen:  {}", album.first_seen.format_iso8601());
+    match album.loudness {
+        Some(lufs) => println!("  Loudness:    {}", lufs),
+        None => println!("  Loudness:    not analyzed"),
+    }
+    match db::select_thumbnail_size(tx, album_id.0 as i64)? {
+        Some(size) => println!("  Thumbnail:   {} bytes", size),
+        None => println!("  Thumbnail:   missing"),
+    }
+    println!("  Rating:      {:?}", user_data.get_album_rating(album_id));
+    println!("  Listens:     {}", n_listens);
+    print_labels_and_note(
+        user_data.get_target_labels(LabelTarget::Album(album_id)),
+        user_data.get_album_note(album_id),
+    );
+
+    println!("\nTRACKS\n");
+    for kv in index.get_album_tracks(album_id) {
+        println!(
+            "  {} {}.{:02} {}",
+            kv.track_id,
+            kv.track_id.disc_number(),
+            kv.track_id.track_number(),
+            index.get_string(kv.track.title),
+        );
+    }
+
+    Ok(())
+}
+
+pub fn main(config: &Config, target: &str) -> crate::Result<()> {
+    let conn = connect_readonly(&config.db_path, &config.db_pragmas)?;
+    let mut db = db::Connection::new(&conn);
+    let mut tx = db.begin()?;
+
+    let (index, builder) = MemoryMetaIndex::from_database(&mut tx, None)?;
+    let (user_data, _counts) = UserData::load_from_database(&index, &mut tx, config.playcount.clone())?;
+
+    if let Some(file) = find_file(&mut tx, config, target)? {
+        print_file(&mut tx, &file)?;
+        let file_id = FileId(file.id);
+        let track = index.get_tracks().iter().find(|kv| kv.track.file_id == file_id);
+        match track {
+            Some(kv) => {
+                print_track(&index, &user_data, &mut tx, kv.track_id)?;
+                let album_id = kv.track_id.album_id();
+                print_issues(builder.issues.iter().filter(|issue| {
+                    issue.filename == file.filename || issue.detail.album_id() == Some(album_id)
+                }));
+            }
+            None => {
+                println!("\nThe file is not in the library, see the issues below.");
+                print_issues(builder.issues.iter().filter(|issue| issue.filename == file.filename));
+            }
+        }
+    } else if let Some(track_id) = TrackId::parse(target).filter(|id| index.get_track(*id).is_some()) {
+        print_track(&index, &user_data, &mut tx, track_id)?;
+        let filename = index.get_filename(index.get_track(track_id).unwrap().filename);
+        print_issues(builder.issues.iter().filter(|issue| {
+            issue.filename == filename || issue.detail.album_id() == Some(track_id.album_id())
+        }));
+    } else if let Some(album_id) = AlbumId::parse(target).filter(|id| index.get_album(*id).is_some()) {
+        print_album(&index, &user_data, &mut tx, album_id)?;
+        let filenames: Vec<&str> = index
+            .get_album_tracks(album_id)
+            .iter()
+            .map(|kv| index.get_filename(kv.track.filename))
+            .collect();
+        print_issues(builder.issues.iter().filter(|issue| {
+            filenames.contains(&&issue.filename[..]) || issue.detail.album_id() == Some(album_id)
+        }));
+    } else {
+        println!(
+            "'{}' is not a file in the database, nor a track or album id in the library.",
+            target,
+        );
+        println!("If it is a new file, run a scan first.");
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::filename_candidates;
+
+    #[test]
+    fn filename_candidates_includes_path_under_library_path_as_configured() {
+        let dir = std::env::temp_dir().join(format!("musium-info-test-{}", std::process::id()));
+        let library = dir.join("library");
+        fs::create_dir_all(&library).unwrap();
+        fs::write(library.join("a.flac"), b"").unwrap();
+
+        // The library path as configured need not be canonical, but the
+        // files table stores paths under it as-is.
+        let library_configured = dir.join("library").join("..").join("library");
+        let target = library.join("a.flac");
+        let candidates = filename_candidates(
+            &library_configured,
+            &dir,
+            target.to_str().unwrap(),
+        );
+        assert!(candidates.contains(&library_configured.join("a.flac")));
+
+        let candidates = filename_candidates(&library, &library, "a.flac");
+        assert_eq!(&candidates[..2], &[PathBuf::from("a.flac"), library.join("a.flac")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -21,6 +21,7 @@ extern crate alsa;
 extern crate claxon;
 extern crate crossbeam;
 extern crate libc;
+extern crate log;
 extern crate serde_json;
 extern crate unicode_normalization;
 extern crate bs1770;
@@ -29,38 +30,64 @@ mod album_table;
 mod build;
 mod exec_pre_post;
 mod filter;
+mod index_cache;
 mod loudness;
-mod search;
+mod spectrogram;
 mod waveform;
 mod word_index;
 
+pub mod backup;
+pub mod check;
+pub mod cli;
 pub mod config;
 pub mod database;
 pub mod database_utils;
+pub mod discord;
 pub mod error;
+pub mod expr;
 pub mod history;
+pub mod import;
+pub mod info;
+pub mod logger;
 pub mod matcher;
+pub mod mix;
+pub mod mqtt;
 pub mod mvar;
 pub mod playback;
 pub mod playcount;
 pub mod player;
+pub mod playlist;
 pub mod prim;
+pub mod query;
+pub mod radio;
+pub mod reload;
+pub mod remote;
+pub mod runtime_stats;
 pub mod scan;
+pub mod search;
 pub mod serialization;
 pub mod server;
 pub mod shuffle;
 pub mod string_utils;
 pub mod systemd;
+pub mod tape;
 pub mod thumb_cache;
 pub mod thumb_gen;
 pub mod user_data;
+pub mod wrapped;
+
+use std::collections::HashSet;
+use std::ops::Range;
 
 use crate::build::{AlbumArtistsDeduper, BuildMetaIndex, BuildError};
 use crate::error::{Error, Result};
+use crate::index_cache::SERIALIZATION_VERSION as INDEX_CACHE_VERSION;
 use crate::prim::{ArtistId, Artist, AlbumArtistsRef, AlbumId, Album, TrackId, Track, Lufs, StringRef, FilenameRef};
 use crate::prim::{ArtistWithId, AlbumWithId, TrackWithId};
-use crate::string_utils::StringDeduper;
-use crate::word_index::MemoryWordIndex;
+use crate::search::{SearchOptions, SearchResult};
+use crate::string_utils::{StringArena, StringDeduper};
+use crate::word_index::{MemoryWordIndex, WordIndex};
+use crate::word_index::SERIALIZATION_VERSION as WORD_INDEX_VERSION;
 
 pub trait MetaIndex {
     /// Return the number of tracks in the index.
@@ -116,10 +143,12 @@ pub trait MetaIndex {
     fn get_album_ids_ordered_by_artist(&self) -> &[(ArtistId, AlbumId)];
 
     /// Search for artists where the word occurs in the name.
-    fn search_artist(&self, words: &[String], into: &mut Vec<ArtistId>);
+    ///
+    /// See `search::search` for the meaning of the phrases and the results.
+    fn search_artist(&self, words: &[String], phrases: &[Range<usize>], options: SearchOptions, into: &mut Vec<SearchResult<ArtistId>>);
 
     /// Search for albums where the word occurs in the title or artist.
-    fn search_album(&self, words: &[String], into: &mut Vec<AlbumId>);
+    fn search_album(&self, words: &[String], phrases: &[Range<usize>], options: SearchOptions, into: &mut Vec<SearchResult<AlbumId>>);
 
     /// Search for tracks where the word occurs in the title or track artist.
     ///
@@ -127,7 +156,16 @@ pub trait MetaIndex {
     /// part of the album artist. That is, this search will not turn up all
     /// tracks by an artist, only those for which `search_album` would not
     /// already find the entire album.
-    fn search_track(&self, words: &[String], into: &mut Vec<TrackId>);
+    fn search_track(&self, words: &[String], phrases: &[Range<usize>], options: SearchOptions, into: &mut Vec<SearchResult<TrackId>>);
+
+    /// Push the words of album and track titles and artists that start with `prefix`.
+    ///
+    /// Pushes every matching word onto `words`, and a `(word, album_id)` pair
+    /// onto `into` for every occurrence of the word, where `word` is the index
+    /// into `words`. For words that occur in a track, the album is the album of
+    /// the track. Considers at most `max_words` words per index, the first ones
+    /// in lexicographic order.
+    fn search_completions(&self, prefix: &str, max_words: usize, words: &mut Vec<String>, into: &mut Vec<(usize, AlbumId)>);
 }
 
 /// Indices into a sorted array based on the most significant byte of an id.
@@ -147,6 +185,7 @@ pub trait MetaIndex {
 /// with that also the cache misses. Furthermore, because the bookmarks table is
 /// small unlike a full hash table, it is likely to be cached, so accessing it
 /// is essentially free.
+#[derive(Clone)]
 struct Bookmarks {
     bookmarks: Box<[u32; 257]>,
 }
@@ -183,6 +222,7 @@ impl Bookmarks {
     }
 }
 
+#[derive(Clone)]
 pub struct MemoryMetaIndex {
     artists: Vec<ArtistWithId>,
     albums: Vec<AlbumWithId>,
@@ -197,8 +237,8 @@ pub struct MemoryMetaIndex {
     track_bookmarks: Bookmarks,
     albums_by_artist_bookmarks: Bookmarks,
 
-    strings: Vec<String>,
-    filenames: Vec<String>,
+    strings: StringArena,
+    filenames: StringArena,
     album_artists: Vec<ArtistId>,
 
     // TODO: Don't make these pub, this is just for debug printing stats.
@@ -251,7 +291,7 @@ impl MemoryMetaIndex {
         let mut tracks: Vec<TrackWithId> = Vec::with_capacity(builder.tracks.len());
         let mut album_artists = AlbumArtistsDeduper::new();
         let mut strings = StringDeduper::new();
-        let mut filenames = Vec::new();
+        let mut filenames = StringArena::new();
 
         for (id, track) in builder.tracks.iter() {
             let (id, mut track) = (*id, track.clone());
@@ -263,8 +303,9 @@ impl MemoryMetaIndex {
             track.artist = StringRef(
                 strings.insert(builder.strings.get(track.artist.0))
             );
-            filenames.push(builder.filenames[track.filename.0 as usize].clone());
-            track.filename = FilenameRef(filenames.len() as u32 - 1);
+            track.filename = FilenameRef(
+                filenames.push(&builder.filenames[track.filename.0 as usize])
+            );
 
             tracks.push(TrackWithId { track_id: id, track });
         }
@@ -343,7 +384,7 @@ impl MemoryMetaIndex {
             albums: albums,
             tracks: tracks,
             albums_by_artist: albums_by_artist,
-            strings: strings.into_vec(),
+            strings: strings.into_arena(),
             filenames: filenames,
             album_artists: album_artists.into_vec(),
             words_artist: MemoryWordIndex::new(&builder.words_artist),
@@ -367,8 +408,8 @@ impl MemoryMetaIndex {
             tracks: Vec::new(),
             albums_by_artist: Vec::new(),
             album_artists: Vec::new(),
-            strings: Vec::new(),
-            filenames: Vec::new(),
+            strings: StringArena::new(),
+            filenames: StringArena::new(),
             words_artist: MemoryWordIndex::new(std::iter::empty()),
             words_album: MemoryWordIndex::new(std::iter::empty()),
             words_track: MemoryWordIndex::new(std::iter::empty()),
@@ -380,10 +421,41 @@ impl MemoryMetaIndex {
     /// Also returns the intermediate builder. It contains any issues
     /// discovered, and the mtimes per album, which can be used to check if any
     /// thumbnails need updating.
-    pub fn from_database(tx: &mut database::Transaction) -> Result<(MemoryMetaIndex, BuildMetaIndex)> {
+    ///
+    /// If the database has word indexes that were saved for the current set of
+    /// files, we load those rather than building them from scratch. Otherwise,
+    /// if there is a `previous` index, we update its word indexes for the
+    /// albums that changed. In both of the latter cases, `collect_words` of the
+    /// builder is true afterwards, so the caller knows to save the indexes.
+    pub fn from_database(
+        tx: &mut database::Transaction,
+        previous: Option<&MemoryMetaIndex>,
+    ) -> Result<(MemoryMetaIndex, BuildMetaIndex)> {
         let mut builder = BuildMetaIndex::new();
         let mut tasks = Vec::new();
 
+        let fingerprint = MemoryMetaIndex::word_index_fingerprint(tx)?;
+        let words_artist = database::select_word_index(tx, "artist", &fingerprint)?;
+        let words_album = database::select_word_index(tx, "album", &fingerprint)?;
+        let words_track = database::select_word_index(tx, "track", &fingerprint)?;
+        let saved_words = match (words_artist, words_album, words_track) {
+            (Some(artist), Some(album), Some(track)) => match (
+                MemoryWordIndex::from_bytes(&artist),
+                MemoryWordIndex::from_bytes(&album),
+                MemoryWordIndex::from_bytes(&track),
+            ) {
+                (Some(artist), Some(album), Some(track)) => Some((artist, album, track)),
+                _ => None,
+            },
+            _ => None,
+        };
+        // Updating an empty index is no cheaper than building one.
+        let previous = match saved_words {
+            None => previous.filter(|p| !p.albums.is_empty()),
+            Some(..) => None,
+        };
+        builder.collect_words = saved_words.is_none() && previous.is_none();
+
         for file in database::iter_files(tx)? {
             match builder.insert_meta(file?) {
                 Ok(task) => tasks.push(task),
@@ -400,12 +472,210 @@ impl MemoryMetaIndex {
             }
         }
 
+        builder.insert_loudness(tx)?;
         builder.insert_first_listens(tx)?;
 
-        let memory_index = MemoryMetaIndex::new(&builder);
+        let mut memory_index = MemoryMetaIndex::new(&builder);
+
+        if let Some((artist, album, track)) = saved_words {
+            memory_index.words_artist = artist;
+            memory_index.words_album = album;
+            memory_index.words_track = track;
+        }
+
+        if let Some(previous) = previous {
+            let (albums, artists) = changed_albums(previous, &builder);
+            builder.insert_album_words(|album_id| albums.contains(&album_id));
+            builder.collect_words = true;
+
+            memory_index.words_artist = previous.words_artist.update(
+                |artist_id| artists.contains(artist_id),
+                &builder.words_artist,
+            );
+            memory_index.words_album = previous.words_album.update(
+                |album_id| albums.contains(album_id),
+                &builder.words_album,
+            );
+            memory_index.words_track = previous.words_track.update(
+                |track_id| albums.contains(&track_id.album_id()),
+                &builder.words_track,
+            );
+        }
 
         Ok((memory_index, builder))
     }
+
+    /// Return a string that identifies the files that the word indexes are
+    /// built from, and the format of the saved indexes.
+    fn word_index_fingerprint(tx: &mut database::Transaction) -> database::Result<String> {
+        let (num_files, max_imported_at) = database::select_files_fingerprint(tx)?;
+        Ok(format!("v{} {} {}", WORD_INDEX_VERSION, num_files, max_imported_at))
+    }
+
+    /// Save the word indexes to the database, so `from_database` can load them.
+    ///
+    /// The index should have been built from the files that are currently in
+    /// the database, otherwise the saved indexes are stale.
+    pub fn save_word_indexes(&self, tx: &mut database::Transaction) -> database::Result<()> {
+        let fingerprint = MemoryMetaIndex::word_index_fingerprint(tx)?;
+        database::insert_or_replace_word_index(tx, "artist", &fingerprint, &self.words_artist.to_bytes())?;
+        database::insert_or_replace_word_index(tx, "album", &fingerprint, &self.words_album.to_bytes())?;
+        database::insert_or_replace_word_index(tx, "track", &fingerprint, &self.words_track.to_bytes())?;
+        Ok(())
+    }
+
+    /// Return a string that identifies the files that the cached index is
+    /// built from, and the format of the cache.
+    fn index_cache_fingerprint(tx: &mut database::Transaction) -> database::Result<String> {
+        let (num_files, max_imported_at) = database::select_files_fingerprint(tx)?;
+        Ok(format!(
+            "v{}.{} {} {}",
+            INDEX_CACHE_VERSION, WORD_INDEX_VERSION, num_files, max_imported_at,
+        ))
+    }
+
+    /// Load the index that `save_cache` saved, if there is one for the files
+    /// that are currently in the database.
+    ///
+    /// Loudness and listens can change without the files changing, so we apply
+    /// the latest ones from the database to the loaded index. Unlike
+    /// `from_database`, this does not report issues with the files; the scan
+    /// that built the index reported them already.
+    pub fn from_cache(tx: &mut database::Transaction) -> Result<Option<MemoryMetaIndex>> {
+        let fingerprint = MemoryMetaIndex::index_cache_fingerprint(tx)?;
+        let data = match database::select_index_cache(tx, &fingerprint)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let mut index = match index_cache::from_bytes(&data) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        index.reload_loudness(tx)?;
+
+        // Same as in `new`, correct the first seen time of albums that we
+        // listened to before we last modified their files.
+        let mut builder = BuildMetaIndex::new();
+        builder.insert_first_listens(tx)?;
+        for kv in index.albums.iter_mut() {
+            if let Some(first_listen) = builder.album_first_listens.get(&kv.album_id) {
+                kv.album.first_seen = kv.album.first_seen.min(*first_listen);
+            }
+        }
+
+        Ok(Some(index))
+    }
+
+    /// Save the full index to the database, so `from_cache` can load it.
+    ///
+    /// Like for `save_word_indexes`, the index should have been built from the
+    /// files that are currently in the database.
+    pub fn save_cache(&self, tx: &mut database::Transaction) -> database::Result<()> {
+        let fingerprint = MemoryMetaIndex::index_cache_fingerprint(tx)?;
+        database::insert_or_replace_index_cache(tx, &fingerprint, &index_cache::to_bytes(self))
+    }
+
+    /// Update the loudness of tracks and albums from the database.
+    ///
+    /// This is for after loudness analysis, which runs after the index is
+    /// built. We read all rows in one query per table, rather than querying
+    /// once per track and album, and look up every row in the index.
+    pub fn reload_loudness(&mut self, tx: &mut database::Transaction) -> Result<()> {
+        for row in database::iter_track_loudness(tx)? {
+            let (id, lufs) = row?;
+            set_track_loudness(&mut self.tracks, TrackId(id as u64), Lufs::from_f64(lufs));
+        }
+        for row in database::iter_album_loudness(tx)? {
+            let (id, lufs) = row?;
+            set_album_loudness(&mut self.albums, AlbumId(id as u64), Lufs::from_f64(lufs));
+        }
+        Ok(())
+    }
+}
+
+/// Set the loudness of the track, if it is in `tracks`.
+///
+/// We can't merge-join the loudness rows with the tracks: SQLite orders the ids
+/// as signed integers, so ids with the high bit set come first there, but last
+/// in the index. Instead we binary search every id.
+/// Return the albums whose words differ between `previous` and `builder`.
+///
+/// Also returns the artists whose entries in the artist word index should be
+/// replaced: the artists that are gone, and the artists of the changed albums,
+/// because `insert_album_words` inserts the words of those.
+fn changed_albums(
+    previous: &MemoryMetaIndex,
+    builder: &BuildMetaIndex,
+) -> (HashSet<AlbumId>, HashSet<ArtistId>) {
+    // An artist name also occurs in the album and track indexes, so when it
+    // changes, all albums of the artist change.
+    let name_changed = |artist_id: ArtistId| match (previous.get_artist(artist_id), builder.artists.get(&artist_id)) {
+        (Some(old), Some(new)) => previous.get_string(old.name) != builder.strings.get(new.name.0),
+        _ => true,
+    };
+
+    let mut albums: HashSet<AlbumId> = previous
+        .get_albums()
+        .iter()
+        .map(|a| a.album_id)
+        .filter(|album_id| !builder.albums.contains_key(album_id))
+        .collect();
+
+    for (&album_id, album) in &builder.albums {
+        let old = match previous.get_album(album_id) {
+            Some(old) => old,
+            None => {
+                albums.insert(album_id);
+                continue
+            }
+        };
+        let artist_ids = builder.album_artists.get(album.artist_ids);
+        let old_tracks = previous.get_album_tracks(album_id);
+        let new_tracks = builder.tracks.range(
+            TrackId::new(album_id, 0, 0)..=TrackId::new(album_id, 0xf, 0xff)
+        );
+        let is_same = previous.get_string(old.title) == builder.strings.get(album.title.0)
+            && previous.get_string(old.artist) == builder.strings.get(album.artist.0)
+            && previous.get_album_artists(old.artist_ids) == artist_ids
+            && !artist_ids.iter().any(|&artist_id| name_changed(artist_id))
+            && old_tracks.len() == new_tracks.clone().count()
+            && old_tracks.iter().zip(new_tracks).all(|(old, (&track_id, new))| {
+                old.track_id == track_id
+                    && previous.get_string(old.track.title) == builder.strings.get(new.title.0)
+                    && previous.get_string(old.track.artist) == builder.strings.get(new.artist.0)
+            });
+        if !is_same {
+            albums.insert(album_id);
+        }
+    }
+
+    let mut artists: HashSet<ArtistId> = previous
+        .get_artists()
+        .iter()
+        .map(|a| a.artist_id)
+        .filter(|artist_id| !builder.artists.contains_key(artist_id))
+        .collect();
+    for album_id in &albums {
+        if let Some(album) = builder.albums.get(album_id) {
+            artists.extend(builder.album_artists.get(album.artist_ids));
+        }
+    }
+
+    (albums, artists)
+}
+
+fn set_track_loudness(tracks: &mut [TrackWithId], track_id: TrackId, loudness: Lufs) {
+    if let Ok(i) = tracks.binary_search_by_key(&track_id, |kv| kv.track_id) {
+        tracks[i].track.loudness = Some(loudness);
+    }
+}
+
+/// Set the loudness of the album, if it is in `albums`, see `set_track_loudness`.
+fn set_album_loudness(albums: &mut [AlbumWithId], album_id: AlbumId, loudness: Lufs) {
+    if let Ok(i) = albums.binary_search_by_key(&album_id, |kv| kv.album_id) {
+        albums[i].album.loudness = Some(loudness);
+    }
 }
 
 impl MetaIndex for MemoryMetaIndex {
@@ -416,12 +686,12 @@ impl MetaIndex for MemoryMetaIndex {
 
     #[inline]
     fn get_string(&self, sr: StringRef) -> &str {
-        &self.strings[sr.0 as usize]
+        self.strings.get(sr.0)
     }
 
     #[inline]
     fn get_filename(&self, sr: FilenameRef) -> &str {
-        &self.filenames[sr.0 as usize]
+        self.filenames.get(sr.0)
     }
 
     #[inline]
@@ -547,15 +817,151 @@ impl MetaIndex for MemoryMetaIndex {
         &self.albums_by_artist[..]
     }
 
-    fn search_artist(&self, words: &[String], into: &mut Vec<ArtistId>) {
-        search::search(&self.words_artist, words, into);
+    fn search_artist(&self, words: &[String], phrases: &[Range<usize>], options: SearchOptions, into: &mut Vec<SearchResult<ArtistId>>) {
+        search::search(&self.words_artist, words, phrases, options, into);
+    }
+
+    fn search_album(&self, words: &[String], phrases: &[Range<usize>], options: SearchOptions, into: &mut Vec<SearchResult<AlbumId>>) {
+        search::search(&self.words_album, words, phrases, options, into);
+    }
+
+    fn search_track(&self, words: &[String], phrases: &[Range<usize>], options: SearchOptions, into: &mut Vec<SearchResult<TrackId>>) {
+        search::search(&self.words_track, words, phrases, options, into);
+    }
+
+    fn search_completions(&self, prefix: &str, max_words: usize, words: &mut Vec<String>, into: &mut Vec<(usize, AlbumId)>) {
+        self.words_album.search_prefix_keys(prefix, max_words, |word, values| {
+            for &album_id in self.words_album.get_values(values) {
+                into.push((words.len(), album_id));
+            }
+            words.push(word.to_string());
+        });
+        self.words_track.search_prefix_keys(prefix, max_words, |word, values| {
+            for &track_id in self.words_track.get_values(values) {
+                into.push((words.len(), track_id.album_id()));
+            }
+            words.push(word.to_string());
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database as db;
+    use crate::database_utils;
+    use crate::prim::{AlbumId, ArtistId, FileId, FilenameRef, Lufs, StringRef, Track, TrackId, TrackWithId};
+    use super::{MemoryMetaIndex, changed_albums, set_track_loudness};
+
+    #[test]
+    fn set_track_loudness_handles_ids_with_high_bit_set() {
+        let track = Track {
+            file_id: FileId(0),
+            title: StringRef(0),
+            artist: StringRef(0),
+            filename: FilenameRef(0),
+            duration_seconds: 1,
+            loudness: None,
+        };
+        let ids = [1, 2, (1 << 63) | 1, u64::MAX];
+        let mut tracks: Vec<TrackWithId> = ids
+            .iter()
+            .map(|&id| TrackWithId { track_id: TrackId(id), track: track.clone() })
+            .collect();
+
+        // SQLite returns the rows ordered as signed integers, so the ids with
+        // the high bit set come first.
+        let mut rows: Vec<i64> = ids.iter().map(|&id| id as i64).collect();
+        rows.sort();
+        assert_eq!(rows[0], (1_i64 << 63) | 1);
+        for (i, &id) in rows.iter().enumerate().filter(|&(i, _)| i != 2) {
+            set_track_loudness(&mut tracks, TrackId(id as u64), Lufs::from_f64(-10.0 - i as f64));
+        }
+
+        // Row 2 is track 1, which has no loudness.
+        let loudness: Vec<Option<Lufs>> = tracks.iter().map(|kv| kv.track.loudness).collect();
+        assert_eq!(loudness, vec![
+            None,
+            Some(Lufs::from_f64(-13.0)),
+            Some(Lufs::from_f64(-10.0)),
+            Some(Lufs::from_f64(-11.0)),
+        ]);
     }
 
-    fn search_album(&self, words: &[String], into: &mut Vec<AlbumId>) {
-        search::search(&self.words_album, words, into);
+    /// Insert a file with the tags that the index needs, return its id.
+    fn insert_file(
+        tx: &mut db::Transaction,
+        filename: &str,
+        (album_id, album): (u32, &str),
+        (artist_id, artist): (u32, &str),
+        track_number: u32,
+        title: &str,
+    ) -> i64 {
+        let file = db::InsertFile {
+            filename: filename,
+            mtime: 0,
+            imported_at: "2024-03-01T12:00:00.000Z",
+            streaminfo_channels: 2,
+            streaminfo_bits_per_sample: 16,
+            streaminfo_num_samples: Some(44_100),
+            streaminfo_sample_rate: 44_100,
+        };
+        let file_id = db::insert_file(tx, file).unwrap();
+        let album_mbid = format!("{:08x}-0000-0000-0000-{:012x}", album_id, album_id);
+        let artist_mbid = format!("{:08x}-0000-0000-0000-{:012x}", artist_id, artist_id);
+        let track_number = track_number.to_string();
+        let tags = [
+            ("album", album),
+            ("albumartist", artist),
+            ("artist", artist),
+            ("musicbrainz_albumartistid", &artist_mbid),
+            ("musicbrainz_albumid", &album_mbid),
+            ("originaldate", "2024"),
+            ("title", title),
+            ("tracknumber", &track_number),
+        ];
+        for (field_name, value) in tags {
+            db::insert_tag(tx, file_id, field_name, value).unwrap();
+        }
+        file_id
     }
 
-    fn search_track(&self, words: &[String], into: &mut Vec<TrackId>) {
-        search::search(&self.words_track, words, into);
+    #[test]
+    fn from_database_updates_word_indexes_of_changed_albums() {
+        let connection = sqlite::open(":memory:").unwrap();
+        // Deleting a file should delete its tags too.
+        connection.execute("PRAGMA foreign_keys = ON;").unwrap();
+        let mut db = db::Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        db::ensure_schema_exists(&mut tx).unwrap();
+        database_utils::apply_migrations(&mut tx, database_utils::MIGRATIONS).unwrap();
+
+        let whale = (1, "Blue Whale");
+        let moon = (2, "Red Moon");
+        insert_file(&mut tx, "a1.flac", (1, "Deep Water"), whale, 1, "Lemon Tree");
+        insert_file(&mut tx, "a2.flac", (1, "Deep Water"), whale, 2, "Río Seco");
+        let b1 = insert_file(&mut tx, "b1.flac", (2, "Night"), moon, 1, "Lemonade");
+        let (previous, _) = MemoryMetaIndex::from_database(&mut tx, None).unwrap();
+
+        // Remove the only album by one artist, and add one by the other artist.
+        db::delete_file(&mut tx, b1).unwrap();
+        insert_file(&mut tx, "c1.flac", (3, "Shallow Water"), whale, 1, "Blue Lemon");
+
+        let (updated, builder) = MemoryMetaIndex::from_database(&mut tx, Some(&previous)).unwrap();
+        let (rebuilt, _) = MemoryMetaIndex::from_database(&mut tx, None).unwrap();
+        assert!(builder.collect_words);
+
+        let (albums, artists) = changed_albums(&previous, &builder);
+        let album_id = |i: u64| AlbumId((i << 20) | i);
+        let artist_id = |i: u64| ArtistId((i << 32) | i);
+        let mut albums: Vec<AlbumId> = albums.into_iter().collect();
+        let mut artists: Vec<ArtistId> = artists.into_iter().collect();
+        albums.sort();
+        artists.sort();
+        assert_eq!(albums, [album_id(2), album_id(3)]);
+        assert_eq!(artists, [artist_id(1), artist_id(2)]);
+
+        assert_eq!(updated.words_artist.to_bytes(), rebuilt.words_artist.to_bytes());
+        assert_eq!(updated.words_album.to_bytes(), rebuilt.words_album.to_bytes());
+        assert_eq!(updated.words_track.to_bytes(), rebuilt.words_track.to_bytes());
     }
 }
@@ -0,0 +1,274 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A minimal logger for the `log` facade.
+//!
+//! Info and debug messages go to stdout, warnings and errors to stderr. In the
+//! text format, we print the message without level or timestamp, so the output
+//! looks the same as before we had levels. Under systemd, the journal adds the
+//! timestamp. The json format prints one object per line, for log collectors.
+//!
+//! While the server handles a request that carries a request id, messages
+//! logged on that thread include the id, see [`with_request_id`].
+
+use std::cell::RefCell;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Which messages to print: a default level, and levels for specific modules.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogFilter {
+    pub default: LevelFilter,
+
+    /// Levels for modules, such as `player` or `scan`, that override the
+    /// default. A module also matches the modules inside it.
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+/// How to format log messages.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Print only the message.
+    Text,
+    /// Print a json object with the time, level, target, and message.
+    Json,
+}
+
+impl LogFilter {
+    /// Return a filter that uses `level` for all modules.
+    pub fn new(level: LevelFilter) -> LogFilter {
+        LogFilter { default: level, modules: Vec::new() }
+    }
+
+    /// Parse a filter as it occurs in the config file.
+    ///
+    /// This is a comma-separated list, where every element is either a level,
+    /// which sets the default, or `module=level`. For example,
+    /// `info, player=warn`.
+    pub fn parse(spec: &str) -> Option<LogFilter> {
+        let mut result = LogFilter::new(LevelFilter::Info);
+        for part in spec.split(',').map(str::trim) {
+            match part.split_once('=') {
+                None => result.default = parse_level(part)?,
+                Some((module, level)) => {
+                    let module = module.trim();
+                    let module = module.strip_prefix("musium::").unwrap_or(module);
+                    if module.is_empty() {
+                        return None
+                    }
+                    result.modules.push((module.to_string(), parse_level(level.trim())?));
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// Return the level for messages from `target`, a module path.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let target = target.strip_prefix("musium::").unwrap_or(target);
+        let mut result = self.default;
+        let mut match_len = 0;
+        for (module, level) in &self.modules {
+            let is_match = target == module
+                || (target.starts_with(&module[..]) && target[module.len()..].starts_with("::"));
+            // When multiple modules match, the most specific one wins.
+            if is_match && module.len() > match_len {
+                result = *level;
+                match_len = module.len();
+            }
+        }
+        result
+    }
+
+    /// Return the most verbose level of any module.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_level(self.default))?;
+        for (module, level) in &self.modules {
+            write!(f, ", {}={}", module, format_level(*level))?;
+        }
+        Ok(())
+    }
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+struct Logger {
+    state: RwLock<Option<(LogFilter, LogFormat)>>,
+}
+
+static LOGGER: Logger = Logger {
+    state: RwLock::new(None),
+};
+
+thread_local! {
+    /// The id of the request that the current thread is handling, if any.
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.state.read().unwrap().as_ref() {
+            Some((filter, _)) => metadata.level() <= filter.level_for(metadata.target()),
+            None => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        let state = self.state.read().unwrap();
+        let (filter, format) = match state.as_ref() {
+            Some(state) => state,
+            None => return,
+        };
+        if record.level() > filter.level_for(record.target()) {
+            return
+        }
+        let line = REQUEST_ID.with(|id| match (format, id.borrow().as_deref()) {
+            (LogFormat::Text, None) => record.args().to_string(),
+            (LogFormat::Text, Some(id)) => format!("[{}] {}", id, record.args()),
+            (LogFormat::Json, id) => format_json(record, id),
+        });
+        match record.level() {
+            Level::Error | Level::Warn => eprintln!("{}", line),
+            Level::Info | Level::Debug | Level::Trace => println!("{}", line),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn format_json(record: &Record, request_id: Option<&str>) -> String {
+    let mut message = serde_json::json!({
+        "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().as_str().to_lowercase(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let Some(id) = request_id {
+        message["request_id"] = id.into();
+    }
+    message.to_string()
+}
+
+/// Run `f`, and include `request_id` in the messages that it logs.
+pub fn with_request_id<T, F: FnOnce() -> T>(request_id: Option<String>, f: F) -> T {
+    REQUEST_ID.with(|id| *id.borrow_mut() = request_id);
+    let result = f();
+    REQUEST_ID.with(|id| *id.borrow_mut() = None);
+    result
+}
+
+/// Install the logger, and print the messages that `filter` allows from now on.
+pub fn init(filter: LogFilter, format: LogFormat) {
+    // This fails only when a logger is already installed, which is fine, we
+    // can still change the filter.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(filter.max_level());
+    *LOGGER.state.write().unwrap() = Some((filter, format));
+}
+
+/// Return the format that the logger uses.
+pub fn format() -> LogFormat {
+    match LOGGER.state.read().unwrap().as_ref() {
+        Some((_, format)) => *format,
+        None => LogFormat::Text,
+    }
+}
+
+/// Parse a log level as it occurs in the config file.
+pub fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level {
+        "off" | "error" | "warn" | "info" | "debug" | "trace" => LevelFilter::from_str(level).ok(),
+        _ => None,
+    }
+}
+
+/// Format a log level as it occurs in the config file.
+pub fn format_level(level: LevelFilter) -> String {
+    level.to_string().to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use log::LevelFilter;
+
+    use super::{format_json, format_level, parse_level, LogFilter};
+
+    #[test]
+    fn parse_level_roundtrips_format_level() {
+        for level in LevelFilter::iter() {
+            assert_eq!(parse_level(&format_level(level)), Some(level));
+        }
+        assert_eq!(parse_level("INFO"), None);
+        assert_eq!(parse_level("verbose"), None);
+    }
+
+    #[test]
+    fn log_filter_parses_module_levels() {
+        let filter = LogFilter::parse("warn, player=debug, musium::scan=off").unwrap();
+        assert_eq!(filter.default, LevelFilter::Warn);
+        assert_eq!(filter.to_string(), "warn, player=debug, scan=off");
+        assert_eq!(LogFilter::parse(&filter.to_string()), Some(filter));
+
+        assert_eq!(LogFilter::parse("player=debug").unwrap().default, LevelFilter::Info);
+        assert_eq!(LogFilter::parse("player=loud"), None);
+        assert_eq!(LogFilter::parse("=debug"), None);
+    }
+
+    #[test]
+    fn log_filter_picks_most_specific_module() {
+        let filter = LogFilter::parse("info, server=error, server::api=debug").unwrap();
+        assert_eq!(filter.level_for("musium::player"), LevelFilter::Info);
+        assert_eq!(filter.level_for("musium::server"), LevelFilter::Error);
+        assert_eq!(filter.level_for("musium::server::api"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("musium::server::api::v2"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("musium::serverless"), LevelFilter::Info);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn format_json_includes_request_id() {
+        let record = log::Record::builder()
+            .args(format_args!("Database error."))
+            .level(log::Level::Error)
+            .target("musium::server")
+            .build();
+
+        let line: serde_json::Value = serde_json::from_str(&format_json(&record, Some("abc-1"))).unwrap();
+        assert_eq!(line["level"], "error");
+        assert_eq!(line["target"], "musium::server");
+        assert_eq!(line["message"], "Database error.");
+        assert_eq!(line["request_id"], "abc-1");
+
+        let line: serde_json::Value = serde_json::from_str(&format_json(&record, None)).unwrap();
+        assert!(line.get("request_id").is_none());
+    }
+}
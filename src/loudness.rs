@@ -5,7 +5,7 @@
 // you may not use this file except in compliance with the License.
 // A copy of the License has been included in the root of the repository.
 
-//! Computation of track and album loudness, and track waveforms.
+//! Computation of track and album loudness, and track waveforms and spectrograms.
 
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{SyncSender, Receiver, sync_channel};
@@ -14,13 +14,14 @@ use std::sync::{Arc, Mutex};
 use bs1770::{ChannelLoudnessMeter};
 use claxon::FlacReader;
 
-use crate::database_utils;
+use crate::database_utils::{self, Pragmas};
 use crate::database as db;
 use crate::database::Transaction;
 use crate::error;
 use crate::prim::{AlbumId, FileId, TrackId};
 use crate::scan::Status;
-use crate::waveform::Waveform;
+use crate::spectrogram::{Spectrogram, SpectrogramBuilder};
+use crate::waveform::{EnvelopeMeter, Waveform, WaveformDetail};
 use crate::{MetaIndex, MemoryMetaIndex};
 
 /// Tracks the state of loudness analysis for one album.
@@ -87,6 +88,8 @@ struct TrackTask {
     track_id: TrackId,
     file_id: FileId,
     path: PathBuf,
+    /// Whether to compute a spectrogram as well.
+    spectrogram: bool,
 }
 
 impl TrackTask {
@@ -120,6 +123,15 @@ impl TrackTask {
             ChannelLoudnessMeter::new(streaminfo.sample_rate),
             ChannelLoudnessMeter::new(streaminfo.sample_rate),
         ];
+        let mut envelopes = [
+            EnvelopeMeter::new(streaminfo.sample_rate),
+            EnvelopeMeter::new(streaminfo.sample_rate),
+        ];
+
+        let mut spectrogram = match (self.spectrogram, streaminfo.samples) {
+            (true, Some(n)) => SpectrogramBuilder::new(streaminfo.sample_rate, n),
+            _ => None,
+        };
 
         let mut blocks = reader.blocks();
         let mut buffer = Vec::new();
@@ -131,6 +143,12 @@ impl TrackTask {
             for (ch, meter) in meters.iter_mut().enumerate() {
                 meter.push(block.channel(ch as u32).iter().map(|s| *s as f32 * normalizer));
             }
+            for (ch, meter) in envelopes.iter_mut().enumerate() {
+                meter.push(block.channel(ch as u32).iter().map(|s| *s as f32 * normalizer));
+            }
+            if let Some(builder) = spectrogram.as_mut() {
+                builder.push(block.channel(0), block.channel(1), normalizer);
+            }
             buffer = block.into_buffer();
         }
 
@@ -144,7 +162,17 @@ impl TrackTask {
             track_id: self.track_id,
             file_id: self.file_id,
             loudness: bs1770::gated_mean(zipped.as_ref()),
-            waveform: Waveform::from_meters(&meters),
+            waveform: Waveform::from_envelopes(&envelopes),
+            waveform_detail: WaveformDetail::from_meters(&meters),
+            // When enabled, we store an empty spectrogram for tracks that are
+            // too short, so we don't try again on the next scan.
+            spectrogram: match self.spectrogram {
+                true => Some(spectrogram
+                    .and_then(SpectrogramBuilder::finish)
+                    .unwrap_or_else(|| Spectrogram::from_bytes(Vec::new()))
+                ),
+                false => None,
+            },
         }).unwrap();
 
         let result = TrackResult {
@@ -177,6 +205,8 @@ enum Insert {
         file_id: FileId,
         loudness: bs1770::Power,
         waveform: Waveform,
+        waveform_detail: WaveformDetail,
+        spectrogram: Option<Spectrogram>,
     },
     Album {
         album_id: AlbumId,
@@ -187,9 +217,10 @@ enum Insert {
 
 fn process_inserts(
     db_path: &Path,
+    db_pragmas: &Pragmas,
     inserts: Receiver<Insert>,
 ) -> db::Result<()> {
-    let connection = database_utils::connect_read_write(db_path)?;
+    let connection = database_utils::connect_read_write(db_path, db_pragmas)?;
 
     // Reduce the number of fsyncs (and thereby improve performance), at the
     // cost of losing durability (but not consistency). This is fine, if we lose
@@ -207,9 +238,18 @@ fn process_inserts(
 
     for insert in inserts {
         match insert {
-            Insert::Track { track_id, file_id, loudness, waveform } => {
+            Insert::Track { track_id, file_id, loudness, waveform, waveform_detail, spectrogram } => {
                 db::insert_track_loudness(&mut tx, track_id.0 as i64, file_id.0, loudness.loudness_lkfs() as f64)?;
-                db::insert_track_waveform(&mut tx, track_id.0 as i64, file_id.0, waveform.as_bytes())?;
+                db::insert_track_waveform(
+                    &mut tx,
+                    track_id.0 as i64,
+                    file_id.0,
+                    &waveform.to_bytes(),
+                    waveform_detail.as_bytes(),
+                )?;
+                if let Some(spectrogram) = spectrogram {
+                    db::insert_track_spectrogram(&mut tx, track_id.0 as i64, file_id.0, spectrogram.as_bytes())?;
+                }
             }
             Insert::Album { album_id, file_id, loudness } => {
                 db::insert_album_loudness(&mut tx, album_id.0 as i64, file_id.0, loudness.loudness_lkfs() as f64)?;
@@ -255,6 +295,8 @@ impl Task {
 pub struct TaskQueue<'a> {
     index: &'a MemoryMetaIndex,
     tasks: Vec<AlbumTask>,
+    /// Whether to compute spectrograms, see `scan_spectrograms` in the config.
+    spectrograms: bool,
     pub status: &'a mut Status,
     pub status_sender: &'a mut SyncSender<Status>,
 }
@@ -262,12 +304,14 @@ pub struct TaskQueue<'a> {
 impl<'a> TaskQueue<'a> {
     pub fn new(
         index: &'a MemoryMetaIndex,
+        spectrograms: bool,
         status: &'a mut Status,
         status_sender: &'a mut SyncSender<Status>,
     ) -> TaskQueue<'a> {
         TaskQueue {
             tasks: Vec::new(),
             index,
+            spectrograms,
             status,
             status_sender,
         }
@@ -327,7 +371,14 @@ impl<'a> TaskQueue<'a> {
                     continue 'albums
                 }
 
-                if db::select_track_waveform(tx, track_id.0 as i64)?.is_none() {
+                // Tracks that we analyzed before we stored the detailed
+                // waveform have only the overview, analyze those again too.
+                if db::select_track_waveform_detail(tx, track_id.0 as i64)?.is_none() {
+                    self.push_task_album(album_id);
+                    continue 'albums
+                }
+
+                if self.spectrograms && db::select_track_spectrogram(tx, track_id.0 as i64)?.is_none() {
                     self.push_task_album(album_id);
                     continue 'albums
                 }
@@ -390,6 +441,7 @@ impl<'a> TaskQueue<'a> {
                 track_id: track_id,
                 file_id: track.file_id,
                 path: PathBuf::from(fname),
+                spectrogram: self.spectrograms,
             };
             return Some(Task::AnalyzeTrack(task));
         }
@@ -398,18 +450,19 @@ impl<'a> TaskQueue<'a> {
         None
     }
 
-    fn is_done(&self) -> bool {
+    pub fn is_done(&self) -> bool {
         self.tasks.is_empty()
     }
 
     /// Process all loudness analysis on a threadpool.
     ///
-    /// The thread pool more threads than cores on the system, to to ensure that
-    /// we can saturate all cores if IO to read the tracks is slow. This method
-    /// blocks until processing is done.
+    /// The thread pool has `n_threads` threads, by default as many as the CPU
+    /// has threads. This method blocks until processing is done.
     pub fn process_all_in_thread_pool(
         self,
         db_path: &Path,
+        db_pragmas: &Pragmas,
+        n_threads: usize,
     ) -> error::Result<()> {
         // Even if we have nothing to do, we will vacuum the database, which can
         // take a few hundred milliseconds, and we'd rather not do that if it is
@@ -423,10 +476,10 @@ impl<'a> TaskQueue<'a> {
         // TODO: Share this thread pool with the thumbnail generation pool.
 
         crossbeam::scope::<_, error::Result<()>>(|scope| {
-            // Use as many threads as the CPU has threads, so that in theory we
-            // can keep it busy. But in practice, all of this is going to be
-            // severely IO-bound with a fast CPU and a spinning disk.
-            let n_threads = num_cpus::get();
+            // By default we use as many threads as the CPU has threads, so
+            // that in theory we can keep it busy. But in practice, all of this
+            // is going to be severely IO-bound with a fast CPU and a spinning
+            // disk.
             let mut threads:
                 Vec<crossbeam::ScopedJoinHandle<error::Result<()>>> =
                 Vec::with_capacity(n_threads);
@@ -479,7 +532,7 @@ impl<'a> TaskQueue<'a> {
             // We could propagate the error here, but if the thread that has the
             // receiver leaves, then the sending threads will panic anyway, so
             // we might as well panic here.
-            process_inserts(db_path, insert_receiver)
+            process_inserts(db_path, db_pragmas, insert_receiver)
                 .expect("Failed to process database insert.");
 
             for join_handle in threads.drain(..) {
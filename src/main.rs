@@ -20,28 +20,47 @@ use std::env;
 use std::fs;
 use std::io::{BufRead, Write};
 use std::io;
+use std::path::Path;
 use std::process;
 use std::sync::{Arc, Mutex};
 
+use log::{info, warn, LevelFilter};
+
+use musium::cli::{self, Cli, Command, DbCommand, PlaylistCommand};
 use musium::config::Config;
 use musium::database;
 use musium::database_utils;
-use musium::error::Result;
+use musium::error::{Error, Result};
+use musium::logger::{self, LogFilter, LogFormat};
+use musium::matcher::ImportSource;
 use musium::mvar::MVar;
+use musium::reload;
+use musium::search::SearchOptions;
 use musium::server::{MetaServer, serve};
-use musium::string_utils::normalize_words;
+use musium::string_utils::{equals_normalized, normalize_words};
+use musium::systemd;
 use musium::thumb_cache::ThumbCache;
 use musium::user_data::UserData;
 use musium::{MetaIndex, MemoryMetaIndex};
 
 fn make_index(tx: &mut database::Transaction) -> Result<MemoryMetaIndex> {
-    let (index, builder) = MemoryMetaIndex::from_database(tx)?;
-
-    for issue in &builder.issues {
-        println!("{}\n", issue);
-    }
+    // After a scan, the index is saved to the database, loading that is much
+    // faster than building it. We only build it when the library changed since.
+    let index = match MemoryMetaIndex::from_cache(tx)? {
+        Some(index) => {
+            info!("Loaded index from the cache.");
+            index
+        }
+        None => {
+            let (index, builder) = MemoryMetaIndex::from_database(tx, None)?;
+            for issue in &builder.issues {
+                warn!("{}\n", issue);
+            }
+            index
+        }
+    };
 
-    println!(
+    info!(
         "Index has {} artists, {} albums, and {} tracks.",
         index.get_artists().len(),
         index.get_albums().len(),
@@ -58,19 +77,19 @@ fn make_index(tx: &mut database::Transaction) -> Result<MemoryMetaIndex> {
     if track_louds.len() > 0 {
         let track_loud_min = track_louds[0];
         let track_loud_max = track_louds[track_louds.len() - 1];
-        println!(
+        info!(
             "\nSoftest track: {} by {} at {}.",
             index.get_string(index.get_track(track_loud_min.1).unwrap().title),
             index.get_string(index.get_track(track_loud_min.1).unwrap().artist),
             track_loud_min.0,
         );
-        println!(
+        info!(
             "Loudest track: {} by {} at {}.",
             index.get_string(index.get_track(track_loud_max.1).unwrap().title),
             index.get_string(index.get_track(track_loud_max.1).unwrap().artist),
             track_loud_max.0,
         );
-        println!(
+        info!(
             "Track loudness p5, p50, p95: {}, {}, {}",
             track_louds[ 5 * track_louds.len() / 100].0,
             track_louds[50 * track_louds.len() / 100].0,
@@ -88,19 +107,19 @@ fn make_index(tx: &mut database::Transaction) -> Result<MemoryMetaIndex> {
     if album_louds.len() > 0 {
         let album_loud_min = album_louds[0];
         let album_loud_max = album_louds[album_louds.len() - 1];
-        println!(
+        info!(
             "\nSoftest album: {} by {} at {}.",
             index.get_string(index.get_album(album_loud_min.1).unwrap().title),
             index.get_string(index.get_album(album_loud_min.1).unwrap().artist),
             album_loud_min.0,
         );
-        println!(
+        info!(
             "Loudest album: {} by {} at {}.",
             index.get_string(index.get_album(album_loud_max.1).unwrap().title),
             index.get_string(index.get_album(album_loud_max.1).unwrap().artist),
             album_loud_max.0,
         );
-        println!(
+        info!(
             "Album loudness p5, p50, p95: {}, {}, {}\n",
             album_louds[ 5 * album_louds.len() / 100].0,
             album_louds[50 * album_louds.len() / 100].0,
@@ -108,31 +127,32 @@ fn make_index(tx: &mut database::Transaction) -> Result<MemoryMetaIndex> {
         );
     }
 
-    println!("Artist word index: {}", index.words_artist.size());
-    println!("Album word index:  {}", index.words_album.size());
-    println!("Track word index:  {}", index.words_track.size());
+    info!("Artist word index: {}", index.words_artist.size());
+    info!("Album word index:  {}", index.words_album.size());
+    info!("Track word index:  {}", index.words_track.size());
 
     Ok(index)
 }
 
-/// Return whether the two strings are equal after extracting normalized words.
-fn equals_normalized(x1: &str, x2: &str) -> bool {
-    // TODO: Figure out a faster way to do this.
-    let mut w1 = Vec::new();
-    let mut w2 = Vec::new();
-    normalize_words(x1, &mut w1);
-    normalize_words(x2, &mut w2);
-    w1 == w2
-}
-
 fn match_listens(
     index: &MemoryMetaIndex,
     tx: &mut database::Transaction,
+    json: bool,
 ) -> Result<()> {
     let mut total = 0_u32;
     let mut matched = 0_u32;
     let mut missed = 0_u32;
     let mut ambiguous = 0_u32;
+    let mut missed_json = Vec::new();
+    let mut ambiguous_json = Vec::new();
+
+    let listen_json = |listen: &database::LastfmListen| serde_json::json!({
+        "started_at": listen.started_at,
+        "title": listen.title,
+        "track_artist": listen.track_artist,
+        "album": listen.album,
+        "album_mbid": listen.album_mbid,
+    });
 
     for listen_opt in database::iter_lastfm_missing_listens(tx)? {
         let listen = listen_opt?;
@@ -141,12 +161,11 @@ fn match_listens(
         let mut tracks = Vec::new();
         normalize_words(&listen.title, &mut words);
         normalize_words(&listen.track_artist, &mut words);
-        // TODO: Add a way to turn off prefix search for the last word.
-        index.search_track(&words[..], &mut tracks);
+        index.search_track(&words[..], &[], SearchOptions::exact(), &mut tracks);
 
         let mut found = false;
 
-        for track_id in tracks {
+        for track_id in tracks.iter().map(|r| r.id) {
             let track = index.get_track(track_id).expect("Search result should be in index.");
             let album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
             let track_ok = equals_normalized(index.get_string(track.title), &listen.title);
@@ -157,20 +176,41 @@ fn match_listens(
                     found = true;
                     matched += 1;
                 } else {
-                    println!("AMBIGUOUS {listen:?}");
+                    if json {
+                        ambiguous_json.push(listen_json(&listen));
+                    } else {
+                        println!("AMBIGUOUS {listen:?}");
+                    }
                     ambiguous += 1;
                 }
             }
         }
 
         if !found {
-            println!("MISSING: {listen:?}");
+            if json {
+                missed_json.push(listen_json(&listen));
+            } else {
+                println!("MISSING: {listen:?}");
+            }
             missed += 1;
         }
 
         total += 1;
     }
 
+    if json {
+        let result = serde_json::json!({
+            "total": total,
+            "matched": matched,
+            "missed": missed,
+            "ambiguous": ambiguous,
+            "missed_listens": missed_json,
+            "ambiguous_listens": ambiguous_json,
+        });
+        println!("{}", result);
+        return Ok(());
+    }
+
     println!(
         "Matched {} out of {} listens ({:.1}%), missed {} ({:.1}%), ambiguous {} ({:.1}%).",
         matched, total, (matched as f32 * 100.0) / (total as f32),
@@ -197,6 +237,7 @@ fn run_scan(config: &Config) -> Result<()> {
         thumb_cache_var,
     );
 
+    let mut final_status = None;
     {
         let stdout = io::stdout();
         let mut lock = stdout.lock();
@@ -204,6 +245,7 @@ fn run_scan(config: &Config) -> Result<()> {
         write!(lock, "\n\n\n\n\n").unwrap();
 
         for status in rx {
+            final_status = Some(status);
             // Move the cursor up a line, and clear that line. We need to clear
             // it, because "convert" sometimes prints warnings. We could swallow
             // its stderr, but this allows the warning to at least be visible
@@ -215,130 +257,491 @@ fn run_scan(config: &Config) -> Result<()> {
     }
 
     // The unwrap unwraps the join, not the scan's result.
-    scan_thread.join().unwrap()
-}
-
-fn print_usage() {
-    println!("\
-Usage:
-
-  musium scan musium.conf
-  musium serve musium.conf
-  musium match musium.conf
-  musium count musium.conf
-
-SCAN
-
-  Update the file database, generate album art thumbnails.
+    scan_thread.join().unwrap()?;
 
-SERVE
-
-  Start the server. Requires running a scan first for serving an up-to-date
-  library.
+    // Log the timings after the last redraw of the status, so the redraw does
+    // not overwrite them.
+    if let Some(status) = final_status {
+        status.log_stage_durations();
+    }
 
-MATCH
+    Ok(())
+}
 
-  Match listens (see process_listens.py) to tracks.
+/// Return the size of the file in bytes, or 0 if it does not exist.
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
 
-COUNT
+/// Run the `db` subcommands other than `check`.
+fn run_db_maintenance(config: &Config, command: DbCommand) -> Result<()> {
+    let conn = database_utils::connect_read_write(&config.db_path, &config.db_pragmas)?;
+    let mut wal_path = config.db_path.clone().into_os_string();
+    wal_path.push("-wal");
+    let wal_path = Path::new(&wal_path);
+
+    match command {
+        DbCommand::Check { .. } => unreachable!("Check is handled by the caller."),
+        DbCommand::Vacuum => {
+            let size_before = file_size(&config.db_path) + file_size(wal_path);
+            println!("Vacuuming database, this may take a while ...");
+            database_utils::vacuum(&conn)?;
+            // The vacuum goes through the WAL, integrate it so we can see the
+            // effect in the size of the database file.
+            database_utils::checkpoint(&conn)?;
+            let size_after = file_size(&config.db_path) + file_size(wal_path);
+            println!("Database size went from {} to {} bytes.", size_before, size_after);
+        }
+        DbCommand::Analyze => {
+            database_utils::analyze(&conn)?;
+            println!("Updated the query planner statistics.");
+        }
+        DbCommand::Checkpoint => {
+            let result = database_utils::checkpoint(&conn)?;
+            println!(
+                "Wrote {} of {} pages from the WAL into the database.",
+                result.checkpointed_pages, result.wal_pages,
+            );
+            if result.is_busy {
+                println!("The checkpoint could not complete, another connection is using the database.");
+            }
+        }
+        DbCommand::Size => {
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            let mut total = 0;
+            for row in database::iter_table_sizes(&mut tx)? {
+                let (table, size) = row?;
+                println!("{:25} {:>12}", table, size);
+                total += size;
+            }
+            tx.commit()?;
+            println!("{:25} {:>12}", "total", total);
+            println!();
+            println!("{:25} {:>12}", "database file", file_size(&config.db_path));
+            println!("{:25} {:>12}", "write-ahead log", file_size(wal_path));
+        }
+    }
 
-  Print listen count statistics.");
+    Ok(())
 }
 
 fn load_config(config_fname: &str) -> Result<Config> {
     let f = fs::File::open(config_fname)?;
     let buf_reader = io::BufReader::new(f);
     let lines: io::Result<Vec<String>> = buf_reader.lines().collect();
-    Config::parse(lines?.iter())
+    Config::parse_with_env(lines?.iter(), env::vars())
 }
 
-fn main() -> Result<()> {
-    if env::args().len() < 3 {
-        print_usage();
+/// Write a starter config to `config_path`, and create the database.
+fn init(config_path: &str, library_path: &str, db_path: Option<&str>) -> Result<()> {
+    let config_path = Path::new(config_path);
+    if config_path.exists() {
+        println!("{} already exists, not overwriting it.", config_path.to_string_lossy());
         process::exit(1);
     }
 
-    let cmd = env::args().nth(1).unwrap();
-    let config_path = env::args().nth(2).unwrap();
-    let config = load_config(&config_path)?;
+    // Write absolute paths, so the config works from any working directory.
+    let current_dir = env::current_dir()?;
+    let library_path = current_dir.join(library_path);
+    if !library_path.is_dir() {
+        println!("Warning: the library path {} is not a directory.", library_path.to_string_lossy());
+    }
+    let db_path = match db_path {
+        Some(p) => current_dir.join(p),
+        None => current_dir.join(config_path).with_file_name("musium.sqlite3"),
+    };
+
+    let (audio_device, volume_control) = match musium::playback::find_first_device() {
+        Ok(Some(device)) => device,
+        _ => {
+            println!("Warning: no audio device found, edit the [audio] section of the config.");
+            ("HDA Intel PCH".to_string(), "Master".to_string())
+        }
+    };
+
+    let config_str = musium::config::format_starter_config(
+        &library_path.to_string_lossy(),
+        &db_path.to_string_lossy(),
+        &audio_device,
+        &volume_control,
+    );
+    if let Some(config_dir) = config_path.parent() {
+        fs::create_dir_all(current_dir.join(config_dir))?;
+    }
+    fs::write(config_path, config_str)?;
+    println!("Wrote config to {}.", config_path.to_string_lossy());
+
+    if let Some(db_dir) = db_path.parent() {
+        fs::create_dir_all(db_dir)?;
+    }
+    database_utils::ensure_schema_up_to_date(&db_path, &database_utils::Pragmas::default())?;
+    println!("Created database at {}.", db_path.to_string_lossy());
+
+    let config_path = config_path.to_string_lossy();
+    println!("\nNext, check the config, scan the library, and start the server:\n");
+    println!("  musium check {}", config_path);
+    println!("  musium scan {}", config_path);
+    println!("  musium serve {}", config_path);
+
+    Ok(())
+}
+
+/// Load the config and check it for problems, exit with status 1 if any.
+fn check_config(config_path: &str) -> Result<()> {
+    let config = match load_config(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Failed to load the configuration from {}: {:?}", config_path, err);
+            process::exit(1);
+        }
+    };
     println!("Configuration:\n{}\n", config);
 
-    match &cmd[..] {
-        "serve" => {
-            let config_clone = config.clone();
+    let mut problems = musium::check::check_config_paths(&config);
+    problems.extend(musium::check::check_tools(&config));
+    match musium::playback::check_device(&config.audio_device, &config.audio_volume_control) {
+        Ok(None) => {}
+        Ok(Some(problem)) => problems.push(problem),
+        Err(err) => problems.push(format!("Failed to query the audio devices: {}.", err)),
+    }
+
+    if problems.is_empty() {
+        println!("No problems found.");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{}\n", problem);
+    }
+    println!("Found {} problem{}.", problems.len(), if problems.len() == 1 { "" } else { "s" });
+    process::exit(1);
+}
+
+fn main() -> Result<()> {
+    let (config_path, command, json, log_level) = match cli::parse(env::args().skip(1)) {
+        Ok(Cli::Run { config_path, command, json, log_level }) => (config_path, command, json, log_level),
+        Ok(Cli::Help(None)) => {
+            cli::print_usage();
+            return Ok(());
+        }
+        Ok(Cli::Help(Some(command))) => {
+            cli::print_command_usage(command);
+            return Ok(());
+        }
+        Ok(Cli::Version) => {
+            println!("musium {}", cli::VERSION);
+            return Ok(());
+        }
+        Ok(Cli::Completions(shell)) => {
+            cli::print_completions(shell);
+            return Ok(());
+        }
+        Err(msg) => {
+            eprintln!("Error: {}\n\nRun 'musium --help' for usage.", msg);
+            process::exit(1);
+        }
+    };
+
+    // Until we have the config, print at the default level. With --json,
+    // stdout is for the json only, so we print only warnings and errors, to
+    // stderr, unless the log level is set explicitly.
+    let default_level = if json { LevelFilter::Warn } else { LevelFilter::Info };
+    logger::init(LogFilter::new(log_level.unwrap_or(default_level)), LogFormat::Text);
+
+    // The check command reports problems with the config itself, and init
+    // creates the config, so handle them before we load the config.
+    let command = match command {
+        Command::Check => return check_config(&config_path),
+        Command::Init { library_path, db_path } => {
+            return init(&config_path, &library_path, db_path.as_deref());
+        }
+        // The server may run elsewhere, so don't touch the database.
+        Command::Remote(remote_command) => {
+            let config = load_config(&config_path)?;
+            return match musium::remote::main(&config, remote_command) {
+                Err(Error::RemoteError(msg)) => {
+                    eprintln!("{}", msg);
+                    process::exit(1);
+                }
+                result => result,
+            };
+        }
+        command => command,
+    };
 
-            let conn = database_utils::connect_readonly(&config.db_path)?;
+    let config = load_config(&config_path)?;
+    if !json {
+        let filter = match log_level {
+            Some(level) => LogFilter::new(level),
+            None => config.log_level.clone(),
+        };
+        logger::init(filter, config.log_format);
+    }
+    info!("Configuration:\n{}\n", config);
+
+    database_utils::ensure_schema_up_to_date(&config.db_path, &config.db_pragmas)?;
+
+    match command {
+        Command::Serve => {
+            // Block SIGHUP before we spawn any threads, so only the reload
+            // thread receives it.
+            reload::block_sighup();
+            // Taking the socket that systemd passed changes the environment,
+            // which is only safe while there are no other threads.
+            let listener = systemd::take_listen_socket();
+            let config_var = Arc::new(MVar::new(Arc::new(config.clone())));
+
+            // Loading the thumbnails does not depend on the index, so we load
+            // them on a separate thread with their own read transaction, while
+            // this thread loads the index and the user data, which needs it.
+            // When the disk is slow, this saves a lot of time before we listen.
+            let thumb_config = config.clone();
+            let thumb_thread = std::thread::Builder::new()
+                .name("thumb_loader".to_string())
+                .spawn(move || -> Result<ThumbCache> {
+                    let conn = database_utils::connect_readonly(&thumb_config.db_path, &thumb_config.db_pragmas)?;
+                    let mut db = database::Connection::new(&conn);
+                    let mut tx = db.begin()?;
+                    info!("Loading cover art thumbnails ...");
+                    let thumb_cache = ThumbCache::load(&mut tx, thumb_config.thumb_cache_size)?;
+                    tx.commit()?;
+                    info!("Thumb cache size: {}", thumb_cache.size());
+                    Ok(thumb_cache)
+                })
+                .expect("Failed to spawn OS thread.");
+
+            let conn = database_utils::connect_readonly(&config.db_path, &config.db_pragmas)?;
             let mut db = database::Connection::new(&conn);
             let mut tx = db.begin()?;
 
-            println!("Loading index ...");
+            info!("Loading index ...");
             let index = make_index(&mut tx)?;
-            println!("Index loaded.");
+            info!("Index loaded.");
 
-            println!("Loading user data and playcounts ...");
-            let (user_data, counts) = UserData::load_from_database(&index, &mut tx)?;
+            info!("Loading user data and playcounts ...");
+            let (user_data, counts) = UserData::load_from_database(&index, &mut tx, config.playcount.clone())?;
             let user_data_arc = Arc::new(Mutex::new(user_data));
-            println!("User data loaded.");
+            info!("User data loaded.");
 
             let arc_index = Arc::new(index);
             let index_var = Arc::new(MVar::new(arc_index));
 
-            println!("Loading cover art thumbnails ...");
-            let thumb_cache = ThumbCache::load_from_database(&mut tx)?;
-            println!("Thumb cache size: {}", thumb_cache.size());
-            let arc_thumb_cache = Arc::new(thumb_cache);
-            let thumb_cache_var = Arc::new(MVar::new(arc_thumb_cache));
-
             tx.commit()?;
             std::mem::drop(db);
             std::mem::drop(conn);
 
-            println!("Starting server on {}.", config.listen);
+            // The unwrap unwraps the join, not the load's result.
+            let thumb_cache = thumb_thread.join().unwrap()?;
+            let arc_thumb_cache = Arc::new(thumb_cache);
+            let thumb_cache_var = Arc::new(MVar::new(arc_thumb_cache));
+
+            info!("Starting server on {}.", config.listen);
             let player = musium::player::Player::new(
                 index_var.clone(),
                 user_data_arc.clone(),
                 counts.into_counter(),
-                &config,
+                config_var.clone(),
             );
+            reload::spawn_reload_thread(config_var.clone(), move || load_config(&config_path));
             let service = MetaServer::new(
-                config_clone,
+                config_var,
                 index_var,
                 thumb_cache_var,
                 user_data_arc,
                 player,
             );
-            serve(&config.listen, Arc::new(service));
+            serve(&config.listen, listener, Arc::new(service));
         }
-        "scan" => {
+        Command::Scan => {
             run_scan(&config)?;
             Ok(())
         }
-        "count" => {
-            let conn = database_utils::connect_readonly(&config.db_path)?;
+        Command::Count { export, options } => {
+            let conn = database_utils::connect_readonly(&config.db_path, &config.db_pragmas)?;
             let mut db = database::Connection::new(&conn);
             let mut tx = db.begin()?;
             let index = make_index(&mut tx)?;
-            musium::playcount::main(&index, &config.db_path)
+            let export = export.as_ref().map(|(format, path)| (*format, &path[..]));
+            musium::playcount::main(&index, &config, export, &options, json)
         }
-        "match" => {
-            let conn = database_utils::connect_read_write(&config.db_path)?;
+        Command::Info { target } => musium::info::main(&config, &target),
+        Command::Wrapped { year } => {
+            let conn = database_utils::connect_readonly(&config.db_path, &config.db_pragmas)?;
             let mut db = database::Connection::new(&conn);
             let mut tx = db.begin()?;
             let index = make_index(&mut tx)?;
+            musium::wrapped::main(&index, &config, year)
+        }
+        Command::Export { format, path } => {
+            let conn = database_utils::connect_readonly(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            let f = fs::File::create(&path)?;
+            let mut w = io::BufWriter::new(f);
+            musium::history::export_listens(&mut tx, format, &mut w)?;
+            w.flush()?;
             tx.commit()?;
-            match_listens(&index, &mut db.begin()?)
+            println!("Exported listens to {}.", path);
+            Ok(())
         }
-        "match2" => {
-            let conn = database_utils::connect_read_write(&config.db_path)?;
+        Command::ExportIndex { path } => {
+            let conn = database_utils::connect_readonly(&config.db_path, &config.db_pragmas)?;
             let mut db = database::Connection::new(&conn);
             let mut tx = db.begin()?;
             let index = make_index(&mut tx)?;
             tx.commit()?;
-            musium::matcher::match_listens(&index, &mut db.begin()?)
+            let f = fs::File::create(&path)?;
+            let mut w = io::BufWriter::new(f);
+            musium::serialization::write_index_json(&index, &mut w)?;
+            w.flush()?;
+            println!("Exported index to {}.", path);
+            Ok(())
         }
-        _ => {
-            print_usage();
-            process::exit(1);
+        Command::ExportUserdata { path } => {
+            let conn = database_utils::connect_readonly(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            let f = fs::File::create(&path)?;
+            let mut w = io::BufWriter::new(f);
+            musium::backup::export_user_data(&mut tx, &mut w)?;
+            w.flush()?;
+            tx.commit()?;
+            println!("Exported user data to {}.", path);
+            Ok(())
+        }
+        Command::Backup { path } => {
+            let conn = database_utils::connect_readonly(&config.db_path, &config.db_pragmas)?;
+            database_utils::backup(&conn, &path)?;
+            println!("Backed up database to {}.", path);
+            Ok(())
+        }
+        Command::ImportUserdata { path } => {
+            let conn = database_utils::connect_read_write(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            let f = fs::File::open(&path)?;
+            let stats = musium::backup::import_user_data(&mut tx, io::BufReader::new(f))?;
+            tx.commit()?;
+            println!(
+                "Imported {} entries, skipped {} malformed entries.",
+                stats.n_read, stats.n_malformed,
+            );
+            Ok(())
+        }
+        Command::Match => {
+            let conn = database_utils::connect_read_write(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            let index = make_index(&mut tx)?;
+            tx.commit()?;
+            match_listens(&index, &mut db.begin()?, json)
+        }
+        Command::Import { source, csv_path } => {
+            let conn = database_utils::connect_read_write(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            let index = make_index(&mut tx)?;
+            tx.commit()?;
+            let mut tx = db.begin()?;
+            if let Some(csv_path) = csv_path {
+                musium::import::load_lastfm_csv(&mut tx, &csv_path)?;
+            }
+            musium::matcher::import_listens(&index, &mut tx, source, config.match_min_confidence)?;
+            if let ImportSource::Lastfm = source {
+                musium::matcher::import_loved_tracks(&index, &mut tx, config.match_min_confidence)?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+        Command::Resolve { source } => {
+            let conn = database_utils::connect_read_write(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            let index = make_index(&mut tx)?;
+            tx.commit()?;
+            musium::matcher::resolve_interactive(&index, &mut db, source, config.match_min_confidence)
+        }
+        Command::Db(DbCommand::Check { prune }) => {
+            let conn = database_utils::connect_read_write(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+
+            let problems = musium::check::check_integrity(&mut tx)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                println!("Integrity check failed, not checking for dangling rows.");
+                process::exit(1);
+            }
+            println!("Integrity check passed.");
+
+            let index = make_index(&mut tx)?;
+            let dangling = musium::check::find_dangling(&index, &mut tx, prune)?;
+            for d in &dangling {
+                println!(
+                    "{:20} {:>6} missing {}s{}",
+                    d.table,
+                    d.count,
+                    d.target,
+                    if d.pruned { " (pruned)" } else { "" },
+                );
+            }
+            if dangling.is_empty() {
+                println!("No dangling rows.");
+            }
+
+            tx.commit()?;
+            Ok(())
+        }
+        Command::Db(db_command) => run_db_maintenance(&config, db_command),
+        Command::Playlist(playlist_command) => {
+            let conn = database_utils::connect_read_write(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            match playlist_command {
+                PlaylistCommand::List => {
+                    for playlist in database::iter_smart_playlists(&mut tx)? {
+                        let playlist = playlist?;
+                        println!("{:20} {}", playlist.name, playlist.query);
+                    }
+                    for playlist in database::iter_playlists(&mut tx)? {
+                        let playlist = playlist?;
+                        println!("{:20} ({} tracks)", playlist.name, playlist.track_count);
+                    }
+                }
+                PlaylistCommand::Set { name, query } => {
+                    if !musium::playlist::is_valid_name(&name) {
+                        println!("Invalid name, use lowercase letters, digits, and dashes.");
+                        process::exit(1);
+                    }
+                    if let Err(msg) = musium::playlist::Definition::parse(&query) {
+                        println!("Invalid query: {}", msg);
+                        process::exit(1);
+                    }
+                    if database::select_playlist_id(&mut tx, &name)?.is_some() {
+                        println!("A manual playlist with that name exists.");
+                        process::exit(1);
+                    }
+                    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+                    database::insert_or_replace_smart_playlist(&mut tx, &name, &query, &now)?;
+                }
+                PlaylistCommand::Delete { name } => database::delete_smart_playlist(&mut tx, &name)?,
+            }
+            tx.commit()?;
+            Ok(())
+        }
+        Command::Match2 => {
+            let conn = database_utils::connect_read_write(&config.db_path, &config.db_pragmas)?;
+            let mut db = database::Connection::new(&conn);
+            let mut tx = db.begin()?;
+            let index = make_index(&mut tx)?;
+            tx.commit()?;
+            musium::matcher::match_listens(&index, &mut db.begin()?, config.match_min_confidence)
+        }
+        Command::Check | Command::Init { .. } | Command::Remote(..) => {
+            unreachable!("Handled before loading the config.")
         }
     }
 }
@@ -9,11 +9,13 @@
 //!
 //! This is used for importing listening history.
 
-use crate::string_utils::normalize_words;
+use crate::string_utils::{equals_normalized, normalize_words};
 use crate::{MetaIndex, MemoryMetaIndex};
 use crate::build::parse_uuid_52bits;
 use crate::error::Result;
-use crate::prim::{AlbumId, TrackId};
+use crate::prim::{AlbumId, Instant, TrackId};
+use crate::search::SearchOptions;
+use crate::user_data::Rating;
 use crate::{database as db};
 
 #[derive(Copy, Clone)]
@@ -45,6 +47,13 @@ enum Match {
     /// removed.
     SearchFuzzy(TrackId),
 
+    /// Matched on similarity of the sets of words, with the given confidence.
+    ///
+    /// This is the last resort, used when none of the passes above found a
+    /// match. It tolerates words that are missing or extra, at the risk of
+    /// matching a different version of the track.
+    Similar(TrackId, f32),
+
     /// Searching had results, but no exact match.
     SearchFail,
 
@@ -55,13 +64,85 @@ enum Match {
     None,
 }
 
+impl Match {
+    /// Return the matched track, if the match was successful.
+    fn track_id(&self) -> Option<TrackId> {
+        match *self {
+            Match::MbidTitle(id) => Some(id),
+            Match::SearchExact(id) => Some(id),
+            Match::SearchAlbumPrefix(id) => Some(id),
+            Match::SearchNormalized(id) => Some(id),
+            Match::SearchFuzzy(id) => Some(id),
+            Match::Similar(id, _) => Some(id),
+            Match::SearchFail => None,
+            Match::Ambiguous => None,
+            Match::None => None,
+        }
+    }
+}
+
+/// The metadata of an imported listen that we match on.
+///
+/// Listens from Last.fm and Listenbrainz have the same shape, but they live in
+/// separate tables, so they have separate types.
+struct ExternalListen<'a> {
+    started_at: i64,
+    title: &'a str,
+    track_artist: &'a str,
+    album: &'a str,
+    album_mbid: &'a str,
+    duration_seconds: Option<i64>,
+}
+
+impl<'a> From<&'a db::LastfmListen> for ExternalListen<'a> {
+    fn from(listen: &'a db::LastfmListen) -> ExternalListen<'a> {
+        ExternalListen {
+            started_at: listen.started_at,
+            title: &listen.title,
+            track_artist: &listen.track_artist,
+            album: &listen.album,
+            album_mbid: &listen.album_mbid,
+            duration_seconds: None,
+        }
+    }
+}
+
+impl<'a> From<&'a db::ListenbrainzListen> for ExternalListen<'a> {
+    fn from(listen: &'a db::ListenbrainzListen) -> ExternalListen<'a> {
+        ExternalListen {
+            started_at: listen.started_at,
+            title: &listen.title,
+            track_artist: &listen.track_artist,
+            album: &listen.album,
+            album_mbid: &listen.album_mbid,
+            duration_seconds: listen.duration_seconds,
+        }
+    }
+}
+
+impl<'a> From<&'a db::LastfmLovedTrack> for ExternalListen<'a> {
+    fn from(loved: &'a db::LastfmLovedTrack) -> ExternalListen<'a> {
+        // Last.fm does not tell us the album of a loved track, so only the
+        // passes that can do without an album can match these.
+        ExternalListen {
+            started_at: loved.loved_at,
+            title: &loved.title,
+            track_artist: &loved.track_artist,
+            album: "",
+            album_mbid: "",
+            duration_seconds: None,
+        }
+    }
+}
+
 fn match_listen(
     index: &MemoryMetaIndex,
-    listen: &db::LastfmListen,
+    listen: ExternalListen,
+    min_confidence: f32,
 ) -> Match {
     let mut album_id = None;
     if !listen.album_mbid.is_empty() {
-        album_id = parse_uuid_52bits(&listen.album_mbid).map(AlbumId);
+        album_id = parse_uuid_52bits(listen.album_mbid).map(AlbumId);
     }
 
     // If we have an album Musicbrainz id (from which our track ids are
@@ -69,7 +150,7 @@ fn match_listen(
     if let Some(id) = album_id {
         for track_and_id in index.get_album_tracks(id) {
             let title = index.get_string(track_and_id.track.title);
-            if title.eq_ignore_ascii_case(&listen.title) {
+            if title.eq_ignore_ascii_case(listen.title) {
                 return Match::MbidTitle(track_and_id.track_id);
             }
         }
@@ -78,23 +159,22 @@ fn match_listen(
     // If that did not work, we'll try searching.
     let mut words = Vec::new();
     let mut tracks = Vec::new();
-    normalize_words(&listen.title, &mut words);
-    normalize_words(&listen.track_artist, &mut words);
+    normalize_words(listen.title, &mut words);
+    normalize_words(listen.track_artist, &mut words);
 
-    // TODO: Add a way to turn off prefix search for the last word.
-    index.search_track(&words[..], &mut tracks);
+    index.search_track(&words[..], &[], SearchOptions::exact(), &mut tracks);
 
     let n_candidates = tracks.len();
     let mut results = Vec::with_capacity(n_candidates);
 
-    for track_id in tracks {
+    for track_id in tracks.iter().map(|r| r.id) {
         let track = index.get_track(track_id).expect("Search result should be in index.");
         let album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
         let track_title = index.get_string(track.title);
         let album_title = index.get_string(album.title);
 
-        let track_exact = track_title.eq_ignore_ascii_case(&listen.title);
-        let album_exact = album_title.eq_ignore_ascii_case(&listen.album);
+        let track_exact = track_title.eq_ignore_ascii_case(listen.title);
+        let album_exact = album_title.eq_ignore_ascii_case(listen.album);
 
         if track_exact && album_exact {
             results.push(Match::SearchExact(track_id));
@@ -117,8 +197,8 @@ fn match_listen(
         // scrobble contains the straight one. To mitigate this kind of thing,
         // use the same normalizer as the search function. This also makes the
         // match case-insensitive.
-        let track_fuzzy = track_exact || equals_normalized(track_title, &listen.title);
-        let album_fuzzy = album_exact || equals_normalized(album_title, &listen.album);
+        let track_fuzzy = track_exact || equals_normalized(track_title, listen.title);
+        let album_fuzzy = album_exact || equals_normalized(album_title, listen.album);
         if track_fuzzy && album_fuzzy {
             results.push(Match::SearchNormalized(track_id));
             continue;
@@ -135,21 +215,21 @@ fn match_listen(
     // If we get here, then either search did not yield any results, or none of
     // the results were a match. Relax the search criteria a bit and try again.
     words.clear();
-    normalize_words(&listen.title, &mut words);
+    normalize_words(listen.title, &mut words);
     simplify_normalized_words(&mut words);
 
     // As before we combine the title and artist words in one search query,
     // but save the part that was for the title so we don't have to re-normalize
     // it later.
     let title_words_len = words.len();
-    normalize_words(&listen.track_artist, &mut words);
+    normalize_words(listen.track_artist, &mut words);
     simplify_normalized_words(&mut words);
 
     let mut tracks = Vec::new();
-    index.search_track(&words[..], &mut tracks);
+    index.search_track(&words[..], &[], SearchOptions::exact(), &mut tracks);
     let n_candidates = tracks.len();
 
-    for track_id in tracks {
+    for track_id in tracks.iter().map(|r| r.id) {
         let track = index.get_track(track_id).expect("Search result should be in index.");
         let album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
         let track_title = index.get_string(track.title);
@@ -186,7 +266,7 @@ fn match_listen(
             simplify_normalized_words(&mut words_album_entry);
 
             let mut words_album_listen = Vec::new();
-            normalize_words(&listen.album, &mut words_album_listen);
+            normalize_words(listen.album, &mut words_album_listen);
             simplify_normalized_words(&mut words_album_listen);
             words_album_entry == words_album_listen
         };
@@ -196,6 +276,12 @@ fn match_listen(
         }
     }
 
+    if results.is_empty() {
+        if let Some(m) = match_similar(index, &listen, min_confidence) {
+            return m;
+        }
+    }
+
     match results.len() {
         0 if n_candidates > 0 => Match::SearchFail,
         0 => Match::None,
@@ -204,6 +290,113 @@ fn match_listen(
     }
 }
 
+/// Maximum difference in duration between a listen and a matching track.
+///
+/// This only applies to the similarity pass, and only when the listen has a
+/// duration. It prevents matching e.g. a radio edit to an extended mix.
+const DURATION_TOLERANCE_SECONDS: i64 = 10;
+
+/// If the two best candidates are closer than this, the match is ambiguous.
+const MIN_CONFIDENCE_MARGIN: f32 = 0.05;
+
+/// Return the simplified normalized words of the string, sorted and deduplicated.
+fn token_set(x: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    normalize_words(x, &mut words);
+    simplify_normalized_words(&mut words);
+    words.sort();
+    words.dedup();
+    words
+}
+
+/// Return the Jaccard index of two token sets, as returned by [`token_set`].
+fn token_set_similarity(a: &[String], b: &[String]) -> f32 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0
+    }
+    let n_common = a.iter().filter(|w| b.binary_search(w).is_ok()).count();
+    let n_union = a.len() + b.len() - n_common;
+    n_common as f32 / n_union as f32
+}
+
+/// Score tracks on the similarity of the token sets of title, artist, and album.
+///
+/// Candidates are the tracks that a fuzzy search on title and artist finds,
+/// and the tracks on albums by the listen's artist. The confidence is a
+/// weighted mean of the similarities, where the title weighs most. When the
+/// listen has no album, only title and artist count. Returns the candidates
+/// with their confidence, most confident first.
+fn score_candidates(index: &MemoryMetaIndex, listen: &ExternalListen) -> Vec<(f32, TrackId)> {
+    let title = token_set(listen.title);
+    let artist = token_set(listen.track_artist);
+    let album = token_set(listen.album);
+
+    let mut words = title.clone();
+    words.extend(artist.iter().cloned());
+    let mut options = SearchOptions::exact();
+    options.fuzzy = true;
+    let mut track_results = Vec::new();
+    index.search_track(&words[..], &[], options, &mut track_results);
+    let mut candidates: Vec<TrackId> = track_results.iter().map(|r| r.id).collect();
+
+    let mut album_results = Vec::new();
+    index.search_album(&artist[..], &[], SearchOptions::exact(), &mut album_results);
+    for album_id in album_results.iter().map(|r| r.id) {
+        candidates.extend(index.get_album_tracks(album_id).iter().map(|t| t.track_id));
+    }
+
+    candidates.sort();
+    candidates.dedup();
+
+    let (w_title, w_artist, w_album) = if album.is_empty() {
+        (0.7, 0.3, 0.0)
+    } else {
+        (0.6, 0.25, 0.15)
+    };
+
+    let mut scored = Vec::with_capacity(candidates.len());
+
+    for track_id in candidates {
+        let track = index.get_track(track_id).expect("Search result should be in index.");
+        let track_album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
+
+        if let Some(duration) = listen.duration_seconds {
+            if (duration - track.duration_seconds as i64).abs() > DURATION_TOLERANCE_SECONDS {
+                continue
+            }
+        }
+
+        let confidence =
+            w_title * token_set_similarity(&title, &token_set(index.get_string(track.title)))
+            + w_artist * token_set_similarity(&artist, &token_set(index.get_string(track.artist)))
+            + w_album * token_set_similarity(&album, &token_set(index.get_string(track_album.title)));
+
+        scored.push((confidence, track_id));
+    }
+
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    scored
+}
+
+/// Match on the similarity of the token sets, see [`score_candidates`].
+///
+/// Returns `None` when no candidate is confident enough.
+fn match_similar(
+    index: &MemoryMetaIndex,
+    listen: &ExternalListen,
+    min_confidence: f32,
+) -> Option<Match> {
+    let scored = score_candidates(index, listen);
+    let second_confidence = scored.get(1).map(|(c, _)| *c).unwrap_or(0.0);
+
+    match scored.first() {
+        Some(&(c, _)) if c < min_confidence => None,
+        Some(&(c, _)) if c - second_confidence < MIN_CONFIDENCE_MARGIN => Some(Match::Ambiguous),
+        Some(&(c, track_id)) => Some(Match::Similar(track_id, c)),
+        None => None,
+    }
+}
+
 /// Remove words that convey little information and may be preventing matches.
 fn simplify_normalized_words(words: &mut Vec<String>) {
     // Drop uninformative words and punctuation.
@@ -241,62 +434,446 @@ fn simplify_normalized_words(words: &mut Vec<String>) {
     }
 }
 
-fn equals_normalized(x1: &str, x2: &str) -> bool {
-    let mut w1 = Vec::new();
-    let mut w2 = Vec::new();
-    normalize_words(x1, &mut w1);
-    normalize_words(x2, &mut w2);
-    w1 == w2
+/// Counts of the outcomes of matching, to report on the quality of the matcher.
+#[derive(Default)]
+struct MatchStats {
+    misses: u32,
+    ambiguous: u32,
+    match_mbid_title: u32,
+    match_search_exact: u32,
+    match_search_album_prefix: u32,
+    match_search_normalized: u32,
+    match_search_fuzzy: u32,
+    match_similar: u32,
+    search_fail: u32,
 }
 
-pub fn match_listens(
-    index: &MemoryMetaIndex,
-    tx: &mut db::Transaction,
-) -> Result<()> {
-    let mut misses: u32 = 0;
-    let mut ambiguous: u32 = 0;
-    let mut match_mbid_title: u32 = 0;
-    let mut match_search_exact: u32 = 0;
-    let mut match_search_album_prefix: u32 = 0;
-    let mut match_search_normalized: u32 = 0;
-    let mut match_search_fuzzy: u32 = 0;
-    let mut search_fail: u32 = 0;
-
-    for listen_opt in db::iter_lastfm_missing_listens(tx)? {
-        let listen = listen_opt?;
-        match match_listen(index, &listen) {
-            Match::MbidTitle(..) => match_mbid_title += 1,
-            Match::SearchExact(..) => match_search_exact += 1,
-            Match::SearchAlbumPrefix(..) => match_search_album_prefix += 1,
-            Match::SearchNormalized(..) => match_search_normalized += 1,
-            Match::SearchFuzzy(..) => match_search_fuzzy += 1,
+impl MatchStats {
+    /// Count the match, and print the listen if it did not match.
+    fn observe<T: std::fmt::Debug>(&mut self, listen: &T, m: Match) {
+        match m {
+            Match::MbidTitle(..) => self.match_mbid_title += 1,
+            Match::SearchExact(..) => self.match_search_exact += 1,
+            Match::SearchAlbumPrefix(..) => self.match_search_album_prefix += 1,
+            Match::SearchNormalized(..) => self.match_search_normalized += 1,
+            Match::SearchFuzzy(..) => self.match_search_fuzzy += 1,
+            Match::Similar(_, confidence) => {
+                self.match_similar += 1;
+                println!("SIMILAR {confidence:.2} {listen:?}");
+            }
             Match::Ambiguous => {
-                ambiguous += 1;
+                self.ambiguous += 1;
                 println!("AMBIGUOUS {listen:?}");
             }
             Match::SearchFail => {
-                search_fail += 1;
+                self.search_fail += 1;
                 println!("SEARCH_FAIL {listen:?}");
             }
             Match::None => {
-                misses += 1;
+                self.misses += 1;
                 println!("MISS {listen:?}");
             }
         }
     }
 
-    let matched = match_mbid_title + match_search_exact + match_search_album_prefix + match_search_normalized + match_search_fuzzy;
-    let total = matched + misses + ambiguous + search_fail;
+    fn print(&self) {
+        let matched = self.match_mbid_title + self.match_search_exact + self.match_search_album_prefix + self.match_search_normalized + self.match_search_fuzzy + self.match_similar;
+        let total = matched + self.misses + self.ambiguous + self.search_fail;
+        let pct = |n: u32| (n as f32 * 100.0) / total as f32;
+
+        println!("Matched {} of {} ({:.1}%).", matched, total, pct(matched));
+        println!(" - {:6} of {:6} ({:4.1}%) SearchExact", self.match_search_exact, total, pct(self.match_search_exact));
+        println!(" - {:6} of {:6} ({:4.1}%) MbidTitle", self.match_mbid_title, total, pct(self.match_mbid_title));
+        println!(" - {:6} of {:6} ({:4.1}%) SearchAlbumPrefix", self.match_search_album_prefix, total, pct(self.match_search_album_prefix));
+        println!(" - {:6} of {:6} ({:4.1}%) SearchNormalized", self.match_search_normalized, total, pct(self.match_search_normalized));
+        println!(" - {:6} of {:6} ({:4.1}%) SearchFuzzy", self.match_search_fuzzy, total, pct(self.match_search_fuzzy));
+        println!(" - {:6} of {:6} ({:4.1}%) Similar", self.match_similar, total, pct(self.match_similar));
+        println!(" - {:6} of {:6} ({:4.1}%) SearchFail", self.search_fail, total, pct(self.search_fail));
+        println!(" - {:6} of {:6} ({:4.1}%) Ambiguous", self.ambiguous, total, pct(self.ambiguous));
+        println!(" - {:6} of {:6} ({:4.1}%) Miss", self.misses, total, pct(self.misses));
+    }
+}
+
+pub fn match_listens(
+    index: &MemoryMetaIndex,
+    tx: &mut db::Transaction,
+    min_confidence: f32,
+) -> Result<()> {
+    let mut stats = MatchStats::default();
+
+    for listen_opt in db::iter_lastfm_missing_listens(tx)? {
+        let listen = listen_opt?;
+        stats.observe(&listen, match_listen(index, (&listen).into(), min_confidence));
+    }
+
+    stats.print();
+
+    Ok(())
+}
+
+/// An external service that we can import listens from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ImportSource {
+    Lastfm,
+    Listenbrainz,
+}
+
+impl ImportSource {
+    pub fn parse(source: &str) -> Option<ImportSource> {
+        match source {
+            "lastfm" => Some(ImportSource::Lastfm),
+            "listenbrainz" => Some(ImportSource::Listenbrainz),
+            _ => None,
+        }
+    }
+
+    /// The value for the `source` column in the `listens` table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportSource::Lastfm => "lastfm",
+            ImportSource::Listenbrainz => "listenbrainz",
+        }
+    }
+}
+
+/// Match the listens, and collect the started-at time and track of the matches.
+fn match_missing<T>(
+    index: &MemoryMetaIndex,
+    listens: impl Iterator<Item = db::Result<T>>,
+    min_confidence: f32,
+    stats: &mut MatchStats,
+    matches: &mut Vec<(i64, TrackId)>,
+) -> Result<()>
+where
+    T: std::fmt::Debug,
+    for<'a> &'a T: Into<ExternalListen<'a>>,
+{
+    for listen_opt in listens {
+        let listen = listen_opt?;
+        let external: ExternalListen = (&listen).into();
+        let started_at = external.started_at;
+        let m = match_listen(index, external, min_confidence);
+        if let Some(track_id) = m.track_id() {
+            matches.push((started_at, track_id));
+        }
+        stats.observe(&listen, m);
+    }
+    Ok(())
+}
 
-    println!("Matched {} of {} ({:.1}%).", matched, total, (matched as f32 * 100.0) / total as f32);
-    println!(" - {:6} of {:6} ({:4.1}%) SearchExact", match_search_exact, total, (match_search_exact as f32 * 100.0) / total as f32);
-    println!(" - {:6} of {:6} ({:4.1}%) MbidTitle", match_mbid_title, total, (match_mbid_title as f32 * 100.0) / total as f32);
-    println!(" - {:6} of {:6} ({:4.1}%) SearchAlbumPrefix", match_search_album_prefix, total, (match_search_album_prefix as f32 * 100.0) / total as f32);
-    println!(" - {:6} of {:6} ({:4.1}%) SearchNormalized", match_search_normalized, total, (match_search_normalized as f32 * 100.0) / total as f32);
-    println!(" - {:6} of {:6} ({:4.1}%) SearchFuzzy", match_search_fuzzy, total, (match_search_fuzzy as f32 * 100.0) / total as f32);
-    println!(" - {:6} of {:6} ({:4.1}%) SearchFail", search_fail, total, (search_fail as f32 * 100.0) / total as f32);
-    println!(" - {:6} of {:6} ({:4.1}%) Ambiguous", ambiguous, total, (ambiguous as f32 * 100.0) / total as f32);
-    println!(" - {:6} of {:6} ({:4.1}%) Miss", misses, total, (misses as f32 * 100.0) / total as f32);
+/// Add an imported listen of the given track to the `listens` table.
+fn insert_imported_listen(
+    index: &MemoryMetaIndex,
+    tx: &mut db::Transaction,
+    source: ImportSource,
+    started_at: i64,
+    track_id: TrackId,
+) -> db::Result<()> {
+    let track = index.get_track(track_id).expect("Matched track should be in index.");
+    let album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
+    let album_artists = index.get_album_artists(album.artist_ids);
+    // We only know when the listen started, assume that we listened to
+    // the full track.
+    let started_at_iso = Instant { posix_seconds_utc: started_at }.format_iso8601();
+    let completed_at_iso = Instant {
+        posix_seconds_utc: started_at + (track.duration_seconds as i64).max(1),
+    }.format_iso8601();
+    let listen = db::ImportedListen {
+        started_at: &started_at_iso,
+        completed_at: &completed_at_iso,
+        file_id: track.file_id.0,
+        track_id: track_id.0 as i64,
+        album_id: track_id.album_id().0 as i64,
+        // Like for our own listens, record only the first album artist.
+        album_artist_id: album_artists[0].0 as i64,
+        track_title: index.get_string(track.title),
+        track_artist: index.get_string(track.artist),
+        album_title: index.get_string(album.title),
+        album_artist: index.get_string(album.artist),
+        duration_seconds: track.duration_seconds as i64,
+        track_number: track_id.track_number() as i64,
+        disc_number: track_id.disc_number() as i64,
+        source: source.as_str(),
+    };
+    db::insert_listen_imported(tx, listen)
+}
+
+/// Match imported listens, and add the matches to the `listens` table.
+///
+/// The listens themselves get imported into the `lastfm_listens` or
+/// `listenbrainz_listens` table first, by `tools/scrobble.py`, or for Last.fm
+/// also by [`crate::import::load_lastfm_csv`]. Listens that do not match a
+/// track in the library are not added, a later run can pick them up when the
+/// library has changed.
+pub fn import_listens(
+    index: &MemoryMetaIndex,
+    tx: &mut db::Transaction,
+    source: ImportSource,
+    min_confidence: f32,
+) -> Result<()> {
+    let mut stats = MatchStats::default();
+    let mut matches = Vec::new();
+
+    // We can't insert while we iterate, so first match everything, then insert.
+    match source {
+        ImportSource::Lastfm => {
+            let listens = db::iter_lastfm_missing_listens(tx)?;
+            match_missing(index, listens, min_confidence, &mut stats, &mut matches)?;
+        }
+        ImportSource::Listenbrainz => {
+            let listens = db::iter_listenbrainz_missing_listens(tx)?;
+            match_missing(index, listens, min_confidence, &mut stats, &mut matches)?;
+        }
+    }
+
+    for (started_at, track_id) in matches.iter() {
+        insert_imported_listen(index, tx, source, *started_at, *track_id)?;
+    }
+
+    stats.print();
+    println!("Added {} listens.", matches.len());
+
+    Ok(())
+}
+
+/// Match tracks loved on Last.fm, and rate the matches as loved.
+///
+/// The loved tracks get imported into the `lastfm_loved_tracks` table by
+/// `tools/scrobble.py`. We only rate a track when it was loved after its most
+/// recent rating in Musium, so a later change in Musium wins. We record every
+/// matched love in the `lastfm_loved_sync` table, so `tools/scrobble.py` does
+/// not push it back to Last.fm.
+pub fn import_loved_tracks(
+    index: &MemoryMetaIndex,
+    tx: &mut db::Transaction,
+    min_confidence: f32,
+) -> Result<()> {
+    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let mut stats = MatchStats::default();
+    let mut matches = Vec::new();
+
+    for loved_opt in db::iter_lastfm_loved_tracks(tx)? {
+        let loved = loved_opt?;
+        let m = match_listen(index, (&loved).into(), min_confidence);
+        if let Some(track_id) = m.track_id() {
+            matches.push((loved.loved_at, track_id));
+        }
+        stats.observe(&loved, m);
+    }
+
+    let mut n_rated = 0_u32;
+
+    for (loved_at, track_id) in matches {
+        let latest = db::select_latest_rating(tx, track_id.0 as i64)?;
+        let should_rate = match latest {
+            Some(rating) => rating.rating != Rating::Love as i64 && rating.created_at_second < loved_at,
+            None => true,
+        };
+        if should_rate {
+            let loved_at_iso = Instant { posix_seconds_utc: loved_at }.format_iso8601();
+            db::insert_rating_if_free(
+                tx,
+                track_id.0 as i64,
+                &loved_at_iso,
+                Rating::Love as i64,
+                ImportSource::Lastfm.as_str(),
+            )?;
+            n_rated += 1;
+        }
+        if db::select_lastfm_loved(tx, track_id.0 as i64)? != Some(1) {
+            db::insert_or_replace_lastfm_loved(tx, track_id.0 as i64, 1, &now)?;
+        }
+    }
+
+    stats.print();
+    println!("Rated {} loved tracks.", n_rated);
 
     Ok(())
 }
+
+/// The maximum number of candidates to offer for an unresolved listen.
+const MAX_CANDIDATES: usize = 5;
+
+/// An imported listen that the matcher could not match by itself.
+pub struct UnresolvedListen {
+    /// Seconds since epoch.
+    pub started_at: i64,
+    pub title: String,
+    pub track_artist: String,
+    pub album: String,
+
+    /// Tracks that the listen might be, with their confidence, best first.
+    pub candidates: Vec<(f32, TrackId)>,
+}
+
+/// Collect the listens that do not match, with candidates to pick from.
+fn collect_unresolved<T>(
+    index: &MemoryMetaIndex,
+    listens: impl Iterator<Item = db::Result<T>>,
+    min_confidence: f32,
+    out: &mut Vec<UnresolvedListen>,
+) -> Result<()>
+where
+    for<'a> &'a T: Into<ExternalListen<'a>>,
+{
+    for listen_opt in listens {
+        let listen = listen_opt?;
+        let external: ExternalListen = (&listen).into();
+        let started_at = external.started_at;
+        let title = external.title.to_string();
+        let track_artist = external.track_artist.to_string();
+        let album = external.album.to_string();
+        let mut candidates = score_candidates(index, &external);
+        if match_listen(index, external, min_confidence).track_id().is_some() {
+            // A regular import will pick this one up, no need to ask.
+            continue
+        }
+        candidates.truncate(MAX_CANDIDATES);
+        out.push(UnresolvedListen { started_at, title, track_artist, album, candidates });
+    }
+    Ok(())
+}
+
+/// Return the imported listens that neither match, nor have a decision.
+///
+/// These are the listens that `import` reports as ambiguous or missing.
+pub fn list_unresolved(
+    index: &MemoryMetaIndex,
+    tx: &mut db::Transaction,
+    source: ImportSource,
+    min_confidence: f32,
+) -> Result<Vec<UnresolvedListen>> {
+    let mut result = Vec::new();
+    match source {
+        ImportSource::Lastfm => {
+            let listens = db::iter_lastfm_missing_listens(tx)?;
+            collect_unresolved(index, listens, min_confidence, &mut result)?;
+        }
+        ImportSource::Listenbrainz => {
+            let listens = db::iter_listenbrainz_missing_listens(tx)?;
+            collect_unresolved(index, listens, min_confidence, &mut result)?;
+        }
+    }
+    Ok(result)
+}
+
+/// Record the user's decision for an imported listen.
+///
+/// When the user picked a track, this also adds the listen to the `listens`
+/// table. When they skipped it (`track_id` is `None`), the listen will no
+/// longer be matched or listed as unresolved.
+pub fn resolve_listen(
+    index: &MemoryMetaIndex,
+    tx: &mut db::Transaction,
+    source: ImportSource,
+    started_at: i64,
+    track_id: Option<TrackId>,
+    decided_at: &str,
+) -> db::Result<()> {
+    let decision = db::MatchDecision {
+        source: source.as_str(),
+        started_at,
+        track_id: track_id.map(|id| id.0 as i64),
+        decided_at,
+    };
+    db::insert_match_decision(tx, decision)?;
+    if let Some(id) = track_id {
+        insert_imported_listen(index, tx, source, started_at, id)?;
+    }
+    Ok(())
+}
+
+/// Walk the unresolved listens, and ask the user to pick a candidate for each.
+///
+/// Every decision is committed right away, so quitting halfway loses nothing.
+pub fn resolve_interactive(
+    index: &MemoryMetaIndex,
+    db: &mut db::Connection,
+    source: ImportSource,
+    min_confidence: f32,
+) -> Result<()> {
+    use std::io::{BufRead, Write};
+
+    let mut tx = db.begin()?;
+    let unresolved = list_unresolved(index, &mut tx, source, min_confidence)?;
+    tx.commit()?;
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut n_picked = 0_u32;
+    let mut n_skipped = 0_u32;
+
+    'listens: for (i, listen) in unresolved.iter().enumerate() {
+        let started_at = Instant { posix_seconds_utc: listen.started_at }.format_iso8601();
+        println!("\n[{}/{}] {}", i + 1, unresolved.len(), started_at);
+        println!("  {} - {} ({})\n", listen.title, listen.track_artist, listen.album);
+        for (j, (confidence, track_id)) in listen.candidates.iter().enumerate() {
+            let track = index.get_track(*track_id).expect("Candidate should be in index.");
+            let album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
+            println!(
+                "  {} {:.2} {} - {} ({})",
+                j + 1,
+                confidence,
+                index.get_string(track.title),
+                index.get_string(track.artist),
+                index.get_string(album.title),
+            );
+        }
+
+        let track_id = loop {
+            print!("\nPick 1-{}, a track id, s to skip, Enter for later, q to quit: ", listen.candidates.len());
+            std::io::stdout().flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break None,
+            };
+            match line.trim() {
+                "" => break None,
+                "q" => break 'listens,
+                "s" => break Some(None),
+                answer => {
+                    let pick = answer
+                        .parse::<usize>().ok()
+                        .and_then(|n| n.checked_sub(1))
+                        .and_then(|n| listen.candidates.get(n))
+                        .map(|(_, id)| *id)
+                        .or_else(|| TrackId::parse(answer));
+                    match pick {
+                        Some(id) if index.get_track(id).is_some() => break Some(Some(id)),
+                        _ => println!("Not a candidate or track id."),
+                    }
+                }
+            }
+        };
+
+        if let Some(track_id) = track_id {
+            let decided_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            let mut tx = db.begin()?;
+            resolve_listen(index, &mut tx, source, listen.started_at, track_id, &decided_at)?;
+            tx.commit()?;
+            match track_id {
+                Some(..) => n_picked += 1,
+                None => n_skipped += 1,
+            }
+        }
+    }
+
+    println!("\nAdded {} listens, skipped {}.", n_picked, n_skipped);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{token_set, token_set_similarity};
+
+    #[test]
+    fn token_set_similarity_ignores_abbreviations_and_order() {
+        let a = token_set("Symphony No. 5, Pt. 1");
+        let b = token_set("Symphony No 5 Part 1");
+        assert_eq!(token_set_similarity(&a, &b), 1.0);
+
+        let c = token_set("Symphony No 5 Part 2");
+        let sim = token_set_similarity(&a, &c);
+        assert!(0.5 < sim && sim < 1.0);
+    }
+}
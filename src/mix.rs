@@ -0,0 +1,131 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Generated track lists, built from playcounts and ratings.
+
+use std::collections::HashMap;
+
+use crate::playcount::{QuantizedTimeVector, TimeVector};
+use crate::prim::{AlbumId, TrackId};
+use crate::user_data::{Rating, UserData};
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// The maximum number of tracks from a single album in a mix.
+///
+/// Without this limit, a mix is often dominated by a few albums that we played
+/// in full a lot, while a mix is more interesting when it is more varied.
+const MAX_TRACKS_PER_ALBUM: u32 = 2;
+
+/// Take the first `n` tracks from `ranked`, skipping disliked tracks and tracks that no longer exist.
+fn take_mix<I: Iterator<Item = TrackId>>(
+    index: &MemoryMetaIndex,
+    user_data: &UserData,
+    ranked: I,
+    n: usize,
+) -> Vec<TrackId> {
+    let mut per_album: HashMap<AlbumId, u32> = HashMap::new();
+    let mut result = Vec::with_capacity(n);
+
+    for track_id in ranked {
+        if result.len() == n {
+            break
+        }
+        if index.get_track(track_id).is_none() {
+            continue
+        }
+        if user_data.get_track_rating(track_id) == Rating::Dislike {
+            continue
+        }
+        let album_count = per_album.entry(track_id.album_id()).or_default();
+        if *album_count == MAX_TRACKS_PER_ALBUM {
+            continue
+        }
+        *album_count += 1;
+        result.push(track_id);
+    }
+
+    result
+}
+
+/// Tracks that we played a lot in the past, but not recently.
+///
+/// This is the track equivalent of the _discover_ album sorting method. Tracks
+/// on albums by favorite artists score `favorite_boost` times as high.
+pub fn discover(index: &MemoryMetaIndex, user_data: &UserData, n: usize, favorite_boost: f32) -> Vec<TrackId> {
+    let mut ranked: Vec<(TrackId, f32)> = user_data
+        .iter_track_scores()
+        .map(|(track_id, scores)| (track_id, scores.discover_score))
+        .filter(|(_, score)| *score > 0.0)
+        .map(|(track_id, score)| {
+            if user_data.is_by_favorite_artist(index, track_id.album_id()) {
+                (track_id, score * favorite_boost)
+            } else {
+                (track_id, score)
+            }
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    take_mix(index, user_data, ranked.into_iter().map(|(track_id, _)| track_id), n)
+}
+
+/// Tracks that we tend to listen to at this time of the day and week.
+pub fn for_now(index: &MemoryMetaIndex, user_data: &UserData, n: usize) -> Vec<TrackId> {
+    let now = QuantizedTimeVector::from_time_vector(&TimeVector::from_local_time(&chrono::Local::now()));
+    let mut ranked: Vec<(TrackId, i32)> = user_data
+        .iter_track_scores()
+        .map(|(track_id, scores)| (track_id, scores.time_vector.dot(&now)))
+        .filter(|(_, score)| *score > 0)
+        .collect();
+    ranked.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+    take_mix(index, user_data, ranked.into_iter().map(|(track_id, _)| track_id), n)
+}
+
+/// The tracks and albums that the user rated positively.
+pub struct Favorites {
+    /// Tracks rated _like_ or _love_, loved tracks first, then in library order.
+    pub tracks: Vec<TrackId>,
+
+    /// Albums with a positive mean track rating, highest mean first.
+    pub albums: Vec<(f32, AlbumId)>,
+}
+
+/// Collect the positively rated tracks, and the albums with the best ratings.
+///
+/// The mean rating of an album is over all of its tracks, where unrated tracks
+/// count as neutral, so an album with one loved track among ten ranks below an
+/// album where we like every track.
+pub fn favorites(index: &MemoryMetaIndex, user_data: &UserData) -> Favorites {
+    let mut tracks: Vec<(Rating, TrackId)> = user_data
+        .iter_track_ratings()
+        .filter(|(track_id, rating)| *rating > Rating::Neutral && index.get_track(*track_id).is_some())
+        .map(|(track_id, rating)| (rating, track_id))
+        .collect();
+    tracks.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    let mut album_ids: Vec<AlbumId> = tracks.iter().map(|(_, track_id)| track_id.album_id()).collect();
+    album_ids.sort();
+    album_ids.dedup();
+
+    let mut albums: Vec<(f32, AlbumId)> = album_ids
+        .into_iter()
+        .map(|album_id| {
+            let album_tracks = index.get_album_tracks(album_id);
+            let sum: i32 = album_tracks
+                .iter()
+                .map(|t| user_data.get_track_rating(t.track_id) as i32)
+                .sum();
+            (sum as f32 / album_tracks.len() as f32, album_id)
+        })
+        .filter(|(mean, _)| *mean > 0.0)
+        .collect();
+    albums.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    Favorites {
+        tracks: tracks.into_iter().map(|(_, track_id)| track_id).collect(),
+        albums,
+    }
+}
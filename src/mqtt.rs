@@ -0,0 +1,455 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Publish the playback state to an MQTT broker, for home automation.
+//!
+//! We only need a small part of MQTT 3.1.1: we publish at QoS 0, and subscribe
+//! to a single command topic, so we speak the protocol over a plain TCP
+//! connection, like `remote` does for HTTP. The thread polls the player once
+//! per second, and publishes retained messages when something changed:
+//!
+//! * `{prefix}/availability`: `online`, or `offline` after we disconnect.
+//! * `{prefix}/state`: `playing` or `idle`.
+//! * `{prefix}/now_playing`: a json object with the current track.
+//! * `{prefix}/volume`: a json object with the volume, like `/api/volume`.
+//!
+//! For Home Assistant, we also publish discovery messages that describe these
+//! as sensors, and when commands are enabled, as buttons.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_json::json;
+
+use crate::cli::VERSION;
+use crate::mvar::Var;
+use crate::player::{Millibel, Player};
+use crate::serialization;
+use crate::{MemoryMetaIndex, MetaIndex, TrackId};
+
+/// Settings for the MQTT client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MqttConfig {
+    /// The `host:port` of the broker, publishing is disabled when not set.
+    pub address: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The prefix of the topics that we publish to.
+    pub topic_prefix: String,
+    /// The prefix of the Home Assistant discovery topics.
+    pub discovery_prefix: String,
+    /// Whether to subscribe to `{prefix}/command` and act on it.
+    pub commands: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            address: None,
+            username: None,
+            password: None,
+            topic_prefix: "musium".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            commands: false,
+        }
+    }
+}
+
+/// We send a ping when we sent nothing else for this long.
+const KEEP_ALIVE_SECONDS: u16 = 60;
+
+/// Append the remaining length of a packet, in the variable-length encoding.
+fn push_remaining_length(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break
+        }
+    }
+}
+
+/// Append a string, prefixed with its length.
+fn push_str(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+/// Build a packet from the first byte of its fixed header and its body.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![header];
+    push_remaining_length(&mut out, body.len());
+    out.extend_from_slice(body);
+    out
+}
+
+/// Build a CONNECT packet.
+///
+/// The broker publishes `will_payload` to `will_topic` when we disconnect
+/// without saying so. MQTT 3.1.1 forbids a password without a user name, so
+/// we only send the password along with a user name.
+fn connect_packet(
+    client_id: &str,
+    username: Option<&str>,
+    password: Option<&str>,
+    will_topic: &str,
+    will_payload: &[u8],
+) -> Vec<u8> {
+    let password = username.and(password);
+
+    // Clean session, and a retained will at QoS 0.
+    let mut flags = 0b0010_0110;
+    if username.is_some() { flags |= 0b1000_0000 }
+    if password.is_some() { flags |= 0b0100_0000 }
+
+    let mut body = Vec::new();
+    push_str(&mut body, b"MQTT");
+    body.push(4); // Protocol level 4 is MQTT 3.1.1.
+    body.push(flags);
+    body.extend_from_slice(&KEEP_ALIVE_SECONDS.to_be_bytes());
+    push_str(&mut body, client_id.as_bytes());
+    push_str(&mut body, will_topic.as_bytes());
+    push_str(&mut body, will_payload);
+    if let Some(username) = username { push_str(&mut body, username.as_bytes()) }
+    if let Some(password) = password { push_str(&mut body, password.as_bytes()) }
+    packet(0x10, &body)
+}
+
+/// Build a retained PUBLISH packet at QoS 0.
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+    push_str(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(0x31, &body)
+}
+
+/// Build a SUBSCRIBE packet for a single topic at QoS 0.
+fn subscribe_packet(packet_id: u16, topic: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    push_str(&mut body, topic.as_bytes());
+    body.push(0);
+    packet(0x82, &body)
+}
+
+/// Return the topic and payload of the body of a PUBLISH packet.
+fn parse_publish(header: u8, body: &[u8]) -> Option<(&str, &[u8])> {
+    if body.len() < 2 {
+        return None
+    }
+    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let topic = std::str::from_utf8(body.get(2..2 + topic_len)?).ok()?;
+    // At QoS 1 and 2, a packet id follows the topic. We subscribe at QoS 0,
+    // so the broker should not send those, but we can skip the id anyway.
+    let qos = (header >> 1) & 0b11;
+    let payload_start = if qos > 0 { 4 + topic_len } else { 2 + topic_len };
+    Some((topic, body.get(payload_start..)?))
+}
+
+/// Read the body of a packet, after the first byte of the fixed header.
+fn read_body(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = 0;
+    for i in 0..4 {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body)?;
+            return Ok(body)
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid remaining length."))
+}
+
+/// Read the next packet, or return `None` if none arrived within the timeout.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8];
+    match stream.read(&mut header) {
+        Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The broker closed the connection.")),
+        Ok(_) => Ok(Some((header[0], read_body(stream)?))),
+        Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// What we published last, so we only publish changes.
+#[derive(Default)]
+struct Published {
+    track_id: Option<Option<TrackId>>,
+    volume: Option<Millibel>,
+}
+
+struct Session<'a> {
+    config: &'a MqttConfig,
+    stream: TcpStream,
+    last_sent: Instant,
+}
+
+impl<'a> Session<'a> {
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.config.topic_prefix, name)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        self.send(&publish_packet(topic, payload))
+    }
+
+    /// Describe the topics to Home Assistant.
+    fn publish_discovery(&mut self) -> io::Result<()> {
+        // Home Assistant allows only alphanumerics, underscores and dashes in
+        // the node id.
+        let node_id: String = self.config.topic_prefix
+            .chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() || ch == '-' { ch } else { '_' })
+            .collect();
+        let device = json!({
+            "identifiers": [node_id.clone()],
+            "name": "Musium",
+            "sw_version": VERSION,
+        });
+
+        let mut entities = vec![
+            ("sensor", "state", json!({
+                "name": "Playback state",
+                "state_topic": self.topic("state"),
+            })),
+            ("sensor", "now_playing", json!({
+                "name": "Now playing",
+                "state_topic": self.topic("now_playing"),
+                "value_template": "{{ value_json.title }}",
+                "json_attributes_topic": self.topic("now_playing"),
+            })),
+            ("sensor", "volume", json!({
+                "name": "Volume",
+                "state_topic": self.topic("volume"),
+                "value_template": "{{ value_json.volume_db }}",
+                "unit_of_measurement": "dB",
+            })),
+        ];
+        if self.config.commands {
+            for (object_id, name) in [("skip", "Skip"), ("volume_up", "Volume up"), ("volume_down", "Volume down")] {
+                entities.push(("button", object_id, json!({
+                    "name": name,
+                    "command_topic": self.topic("command"),
+                    "payload_press": object_id,
+                })));
+            }
+        }
+
+        for (component, object_id, mut entity) in entities {
+            entity["unique_id"] = format!("{}_{}", node_id, object_id).into();
+            entity["availability_topic"] = self.topic("availability").into();
+            entity["device"] = device.clone();
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                self.config.discovery_prefix, component, node_id, object_id,
+            );
+            self.publish(&topic, entity.to_string().as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish the state of the player, if it changed since last time.
+    fn publish_changes(
+        &mut self,
+        index_var: &Var<MemoryMetaIndex>,
+        player: &Player,
+        published: &mut Published,
+    ) -> io::Result<()> {
+        let track_id = player.get_queue().tracks.first().map(|t| t.track_id);
+        if published.track_id != Some(track_id) {
+            let (state, now_playing) = match track_id {
+                Some(track_id) => ("playing", now_playing_json(&index_var.get(), track_id)),
+                None => ("idle", json!({})),
+            };
+            self.publish(&self.topic("state"), state.as_bytes())?;
+            self.publish(&self.topic("now_playing"), now_playing.to_string().as_bytes())?;
+            published.track_id = Some(track_id);
+        }
+
+        let volume = player.get_volume();
+        if published.volume != Some(volume) {
+            let mut payload = Vec::new();
+            serialization::write_volume_json(&mut payload, volume)?;
+            self.publish(&self.topic("volume"), &payload)?;
+            published.volume = Some(volume);
+        }
+
+        Ok(())
+    }
+}
+
+fn now_playing_json(index: &MemoryMetaIndex, track_id: TrackId) -> serde_json::Value {
+    let track = match index.get_track(track_id) {
+        Some(track) => track,
+        None => return json!({ "track_id": track_id.to_string() }),
+    };
+    let album = index.get_album(track_id.album_id());
+    json!({
+        "track_id": track_id.to_string(),
+        "album_id": track_id.album_id().to_string(),
+        "title": index.get_string(track.title),
+        "artist": index.get_string(track.artist),
+        "album": album.map(|a| index.get_string(a.title)),
+        "album_artist": album.map(|a| index.get_string(a.artist)),
+        "duration_seconds": track.duration_seconds,
+    })
+}
+
+/// Act on a message on the command topic.
+fn handle_command(index_var: &Var<MemoryMetaIndex>, player: &Player, command: &[u8]) {
+    match command {
+        b"skip" => player.skip(&index_var.get()),
+        b"clear" => player.clear_queue(),
+        b"volume_up" => { player.change_volume(Millibel(1_00)); }
+        b"volume_down" => { player.change_volume(Millibel(-1_00)); }
+        _ => warn!("Ignoring unknown MQTT command '{}'.", String::from_utf8_lossy(command)),
+    }
+}
+
+/// Connect to the broker and publish until the connection fails.
+fn run_session(config: &MqttConfig, address: &str, index_var: &Var<MemoryMetaIndex>, player: &Player) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut session = Session { config, stream, last_sent: Instant::now() };
+
+    let client_id = format!("musium-{}", std::process::id());
+    let availability = session.topic("availability");
+    session.send(&connect_packet(
+        &client_id,
+        config.username.as_deref(),
+        config.password.as_deref(),
+        &availability,
+        b"offline",
+    ))?;
+
+    // The broker responds with a CONNACK, with the return code in the second
+    // byte. It might take longer than the read timeout.
+    let connack = loop {
+        if let Some(packet) = read_packet(&mut session.stream)? {
+            break packet
+        }
+    };
+    match connack {
+        (0x20, body) if body.get(1) == Some(&0) => {}
+        (0x20, body) => return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("The broker refused the connection with code {:?}.", body.get(1)),
+        )),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected CONNACK.")),
+    }
+    info!("Connected to MQTT broker at {}.", address);
+
+    session.publish(&availability, b"online")?;
+    session.publish_discovery()?;
+    let command_topic = session.topic("command");
+    if config.commands {
+        session.send(&subscribe_packet(1, &command_topic))?;
+    }
+
+    let mut published = Published::default();
+    loop {
+        session.publish_changes(index_var, player, &mut published)?;
+
+        if session.last_sent.elapsed() >= Duration::from_secs(KEEP_ALIVE_SECONDS as u64 / 2) {
+            session.send(&packet(0xc0, &[]))?;
+        }
+
+        // This waits up to the read timeout, which sets the polling interval.
+        // Apart from publishes, we can ignore what the broker sends, that's
+        // acknowledgements and ping responses.
+        if let Some((header, body)) = read_packet(&mut session.stream)? {
+            if header & 0xf0 == 0x30 {
+                match parse_publish(header, &body) {
+                    Some((topic, payload)) if topic == command_topic => {
+                        handle_command(index_var, player, payload);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Publish the playback state to the broker, and reconnect when that fails.
+pub fn main(config: &MqttConfig, index_var: &Var<MemoryMetaIndex>, player: &Player) -> ! {
+    let address = config.address.as_ref().expect("Only run with an address configured.");
+    loop {
+        if let Err(err) = run_session(config, address, index_var, player) {
+            warn!("MQTT connection to {} failed, retrying in 30 seconds: {}", address, err);
+        }
+        thread::sleep(Duration::from_secs(30));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect_packet, parse_publish, publish_packet, push_remaining_length, subscribe_packet};
+
+    #[test]
+    fn push_remaining_length_uses_variable_length_encoding() {
+        let encode = |len| {
+            let mut out = Vec::new();
+            push_remaining_length(&mut out, len);
+            out
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(127), [0x7f]);
+        assert_eq!(encode(128), [0x80, 0x01]);
+        assert_eq!(encode(16_383), [0xff, 0x7f]);
+        assert_eq!(encode(16_384), [0x80, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn packets_match_the_spec() {
+        assert_eq!(
+            publish_packet("a/b", b"on"),
+            b"\x31\x07\x00\x03a/bon",
+        );
+        assert_eq!(
+            subscribe_packet(1, "a/c"),
+            b"\x82\x08\x00\x01\x00\x03a/c\x00",
+        );
+        assert_eq!(
+            connect_packet("m", Some("u"), None, "w", b"x"),
+            b"\x10\x16\x00\x04MQTT\x04\xa6\x00\x3c\x00\x01m\x00\x01w\x00\x01x\x00\x01u",
+        );
+        assert_eq!(
+            connect_packet("m", Some("u"), Some("p"), "w", b"x"),
+            b"\x10\x19\x00\x04MQTT\x04\xe6\x00\x3c\x00\x01m\x00\x01w\x00\x01x\x00\x01u\x00\x01p",
+        );
+        // A password without a user name is not allowed, we leave it out.
+        assert_eq!(
+            connect_packet("m", None, Some("p"), "w", b"x"),
+            b"\x10\x13\x00\x04MQTT\x04\x26\x00\x3c\x00\x01m\x00\x01w\x00\x01x",
+        );
+    }
+
+    #[test]
+    fn parse_publish_returns_topic_and_payload() {
+        let packet = publish_packet("musium/command", b"skip");
+        assert_eq!(parse_publish(packet[0], &packet[2..]), Some(("musium/command", &b"skip"[..])));
+        // At QoS 1, a packet id follows the topic.
+        assert_eq!(parse_publish(0x32, b"\x00\x01c\x00\x07skip"), Some(("c", &b"skip"[..])));
+        assert_eq!(parse_publish(0x30, b"\x00\x05c"), None);
+    }
+}
@@ -17,12 +17,15 @@ use std::thread;
 use alsa::PollDescriptors;
 use alsa;
 use libc;
+use log::{debug, error, info, warn};
 
 use crate::config::Config;
 use crate::exec_pre_post::QueueEvent;
 use crate::history::PlaybackEvent;
 use crate::player::{Format, Millibel, PlayerState};
 use crate::prim::Hertz;
+use crate::runtime_stats::RuntimeStats;
+use crate::tape::Tape;
 
 const EBUSY: i32 = 16;
 
@@ -92,7 +95,7 @@ fn open_device(card_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
     let pcm = match alsa::PCM::new(&device, alsa::Direction::Playback, non_block) {
         Ok(pcm) => pcm,
         Err(error) if error.errno() == EBUSY => {
-            println!("Could not open audio interface for exclusive access, it is already use.");
+            error!("Could not open audio interface for exclusive access, it is already use.");
             return Err(error);
         }
         Err(error) => return Err(error),
@@ -105,6 +108,93 @@ fn open_device(card_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
     Ok((pcm, mixer))
 }
 
+/// Return the name of the first card, and its first volume control, if any.
+///
+/// This is a starting point for the config that `musium init` writes.
+pub fn find_first_device() -> Result<Option<(String, String)>> {
+    for res_card in alsa::card::Iter::new() {
+        let card = res_card?;
+        let non_block = false;
+        let mixer = alsa::Mixer::new(&format!("hw:{}", card.get_index()), non_block)?;
+        for elem in mixer.iter() {
+            if let Some(selem) = alsa::mixer::Selem::new(elem) {
+                if selem.has_playback_volume() {
+                    let control_name = selem.get_id().get_name()?.to_string();
+                    return Ok(Some((card.get_name()?, control_name)))
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Check that the card and its volume control exist, for `musium check`.
+///
+/// Returns a description of the problem, if there is one.
+pub fn check_device(card_name: &str, volume_control: &str) -> Result<Option<String>> {
+    let mut card_names = Vec::new();
+    let mut opt_card_index = None;
+
+    for res_card in alsa::card::Iter::new() {
+        let card = res_card?;
+        let name = card.get_name()?;
+        if name == card_name {
+            opt_card_index = Some(card.get_index());
+        }
+        card_names.push(name);
+    }
+
+    let card_index = match opt_card_index {
+        Some(i) => i,
+        None if card_names.is_empty() => return Ok(Some(
+            "No audio cards found. You may need to be a member of the 'audio' group.".to_string()
+        )),
+        None => return Ok(Some(format!(
+            "Could not find a card with name '{}'. Set audio_device to one of: {}.",
+            card_name,
+            card_names.join(", "),
+        ))),
+    };
+
+    // If Musium is running, it holds the device, so we can't open it. That is
+    // not a problem with the config, so we only check that opening succeeds
+    // for other reasons.
+    let device = format!("plug:hw:{}", card_index);
+    let non_block = false;
+    match alsa::PCM::new(&device, alsa::Direction::Playback, non_block) {
+        Ok(..) => {}
+        Err(error) if error.errno() == EBUSY => {}
+        Err(error) => return Ok(Some(format!(
+            "Could not open audio device '{}' for playback: {}.",
+            card_name,
+            error,
+        ))),
+    }
+
+    let device = format!("hw:{}", card_index);
+    let mixer = alsa::Mixer::new(&device, non_block)?;
+    let mut control_names = Vec::new();
+    for elem in mixer.iter() {
+        let selem = match alsa::mixer::Selem::new(elem) {
+            Some(selem) if selem.has_playback_volume() => selem,
+            _ => continue,
+        };
+        let name = selem.get_id().get_name()?.to_string();
+        if name == volume_control {
+            return Ok(None)
+        }
+        control_names.push(name);
+    }
+
+    let problem = format!(
+        "Card '{}' has no volume control named '{}'. Set audio_volume_control to one of: {}.",
+        card_name,
+        volume_control,
+        control_names.join(", "),
+    );
+    Ok(Some(problem))
+}
+
 fn get_volume_control<'a>(mixer: &'a alsa::Mixer, name: &str) -> Option<alsa::mixer::Selem<'a>> {
     let mut selem_id = alsa::mixer::SelemId::empty();
     selem_id.set_name(&CString::new(name).expect("Invalid volume control name."));
@@ -184,6 +274,8 @@ fn write_samples(
     current_format: Format,
     io: &mut alsa::pcm::IO<u8>,
     player: &mut PlayerState,
+    tape: Option<&Tape>,
+    device_volume: Option<Millibel>,
 ) -> Result<WriteResult> {
     use alsa::pcm::State;
 
@@ -197,7 +289,7 @@ fn write_samples(
     let n_available = match pcm.avail_update() {
         Ok(n) => n,
         Err(err) => {
-            println!("DEBUG: write_samples was in error, the error was {:?}.", err);
+            debug!("write_samples was in error, the error was {:?}.", err);
             // Previously we used try_recover here, but all it does is call
             // prepare, which we would do anyway below. See [1].
             // [1]: https://git.alsa-project.org/?p=alsa-lib.git;a=blob;f=src/pcm/pcm.c;
@@ -207,6 +299,14 @@ fn write_samples(
     } as usize;
 
     if n_available > 0 {
+        // The device applies the volume in hardware, the tape in software.
+        // Without loudness data for the track, we don't change the device
+        // volume, so the tape uses the volume that we set last, or unity gain
+        // if we did not set one yet.
+        let volume = player
+            .target_volume_full_scale()
+            .or(device_volume)
+            .unwrap_or(Millibel(0));
         n_consumed = match player.peek_mut() {
             Some(ref block) if current_format != block.format() => {
                 // Next block has a different sample rate or bit depth, finish
@@ -221,6 +321,9 @@ fn write_samples(
                     let src = block.slice();
                     let n = dst.len().min(src.len());
                     dst[..n].copy_from_slice(&src[..n]);
+                    if let Some(tape) = tape {
+                        tape.record(current_format, volume, &src[..n]);
+                    }
                     // We have to return the number of frames (count independent
                     // of the number of channels), but we have bytes.
                     n / (num_channels * current_format.bits_per_sample as usize / 8)
@@ -338,12 +441,16 @@ fn ensure_buffers_full(
     format: Format,
     io: &mut alsa::pcm::IO<u8>,
     player: &mut PlayerState,
+    stats: &RuntimeStats,
+    tape: Option<&Tape>,
+    device_volume: Option<Millibel>,
 ) -> FillResult {
     loop {
-        match write_samples(device, format, io, player) {
+        match write_samples(device, format, io, player, tape, device_volume) {
             Err(err) => {
-                println!("Error while writing samples: {:?}", err);
-                println!("Resuming ...");
+                stats.count_playback_error();
+                warn!("Error while writing samples: {:?}", err);
+                warn!("Resuming ...");
                 continue
             }
             Ok(WriteResult::Continue) => continue,
@@ -364,6 +471,8 @@ fn play_queue(
     volume_name: &str,
     state_mutex: &Mutex<PlayerState>,
     decode_thread: &Thread,
+    stats: &RuntimeStats,
+    tape: Option<&Tape>,
 ) {
     let (mut device, mut mixer) = open_device(card_name).expect("TODO: Failed to open device.");
     let mut vc = get_volume_control(&mixer, volume_name).expect("TODO: Failed to get volume control.");
@@ -389,13 +498,14 @@ fn play_queue(
         if let Some(format) = next_format.take() {
             drop(fds);
             drop(device);
+            stats.count_device_reopen();
 
             (device, mixer) = open_device(card_name).expect("TODO: Failed to open device.");
             vc = get_volume_control(&mixer, volume_name).expect("TODO: Failed to get volume control.");
             fds = device.get().expect("TODO: Failed to get fds from device.");
 
             match set_format(&device, format) {
-                Ok(()) => println!("Set format for device {card_name} to format {format:?}"),
+                Ok(()) => info!("Set format for device {card_name} to format {format:?}"),
                 Err(err) => panic!(
                     "Failed to set format for device {} to format {:?}: {:?}",
                     card_name, format, err,
@@ -416,7 +526,10 @@ fn play_queue(
                 &device,
                 current_format,
                 &mut io,
-                &mut state
+                &mut state,
+                stats,
+                tape,
+                volume,
             );
 
             (
@@ -432,7 +545,7 @@ fn play_queue(
 
         if volume != target_volume {
             if let Some(Millibel(v)) = target_volume {
-                println!("Changing volume to {:.1} dB", v as f32 * 0.01);
+                info!("Changing volume to {:.1} dB", v as f32 * 0.01);
                 vc.set_playback_db_all(alsa::mixer::MilliBel(v as i64), alsa::Round::Floor)
                     .expect("Failed to set volume. TODO: Make fn return Alsa error?");
                 volume = target_volume;
@@ -475,11 +588,11 @@ fn try_increase_thread_priority() {
     // Pipewire and Pulseaudio use by default.
     let new_nice = unsafe { libc::nice(-11) };
     if new_nice == -1 {
-        println!("Playback thread likely failed to set niceness.");
-        println!("Consider using setrlimit, granting CAP_SYS_NICE, \
+        warn!("Playback thread likely failed to set niceness.");
+        warn!("Consider using setrlimit, granting CAP_SYS_NICE, \
                   or setting LimitNICE=-11:-11 when using systemd.");
     } else {
-        println!("Playback thread new niceness: {}", new_nice);
+        info!("Playback thread new niceness: {}", new_nice);
     }
 
     let sched_policy = libc::SCHED_RR;
@@ -496,14 +609,14 @@ fn try_increase_thread_priority() {
         )
     };
     match sched_retval {
-        0 => println!(
+        0 => info!(
             "Playback thread is now SCHED_RR (high priority)."
         ),
-        libc::EPERM => println!(
+        libc::EPERM => warn!(
             "Playback thread was not allowed to change its scheduling \
              policy to SCHED_RR. Consider granting CAP_SYS_NICE."
         ),
-        _ => println!(
+        _ => warn!(
             "An unknown error occurred when setting playback thread \
              scheduling policy: {}.",
              sched_retval,
@@ -529,23 +642,26 @@ pub fn main(
     decode_thread: &Thread,
     queue_events: SyncSender<QueueEvent>,
     history_events: SyncSender<PlaybackEvent>,
+    stats: &RuntimeStats,
 ) {
     use std::time::{Instant, Duration};
 
     try_increase_thread_priority();
 
+    let tape = config.tape_path.as_ref().map(|path| Tape::new(path.clone(), config.tape_format));
+
     loop {
-        let has_audio = {
+        let next_track_id = {
             let state = state_mutex.lock().unwrap();
-            !state.is_queue_empty()
+            state.current_track_id()
         };
-        if has_audio {
+        if next_track_id.is_some() {
             // We are resuming playback now from an idle state. Let the exec
             // thread execute the pre-playback program. For simplicity, the
             // exec thread runs even if no such program is configured.
             let is_running_condvar = Arc::new((Mutex::new(true), Condvar::new()));
             queue_events
-                .send(QueueEvent::StartPlayback(is_running_condvar.clone()))
+                .send(QueueEvent::StartPlayback(is_running_condvar.clone(), next_track_id))
                 .expect("Exec thread runs indefinitely, sending does not fail.");
 
             // If a pre-playback program is configured, we should wait for it to
@@ -559,14 +675,20 @@ pub fn main(
                 ).unwrap();
             }
 
-            println!("Starting playback ...");
+            info!("Starting playback ...");
             play_queue(
                 &config.audio_device,
                 &config.audio_volume_control,
                 &state_mutex,
                 decode_thread,
+                stats,
+                tape.as_ref(),
             );
-            println!("Playback done, sleeping ...");
+            info!("Playback done, sleeping ...");
+
+            if let Some(tape) = tape.as_ref() {
+                tape.end();
+            }
 
             // Inform the history thread that the queue ended, so it can
             // checkpoint the WAL.
@@ -576,8 +698,9 @@ pub fn main(
 
             // Signal the exec thread to start the idle timeout and execute the
             // post-idle program afterwards.
+            let last_track_id = state_mutex.lock().unwrap().last_started_track_id();
             queue_events
-                .send(QueueEvent::EndPlayback(Instant::now()))
+                .send(QueueEvent::EndPlayback(Instant::now(), last_track_id))
                 .expect("Exec thread runs indefinitely, sending does not fail.");
         }
         thread::park();
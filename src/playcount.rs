@@ -9,14 +9,20 @@
 
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs;
+use std::io::{self, Write};
 
+use chrono::{DateTime, Datelike, FixedOffset, Local, TimeZone, Timelike};
+use log::info;
+use serde_json::{json, Value};
+
+use crate::config::Config;
 use crate::database::{self, Transaction};
 use crate::database_utils::connect_readonly;
 use crate::prim::{AlbumId, ArtistId, TrackId};
 use crate::{MemoryMetaIndex, MetaIndex};
 use crate::album_table::AlbumTable;
-use crate::user_data::AlbumState;
+use crate::user_data::{AlbumState, ArtistState, Rating, TrackScores, UserData};
 
 /// A point in time with second granularity.
 ///
@@ -110,6 +116,196 @@ impl Epoch {
     }
 }
 
+/// An embedding of the local time of day and the day of the week.
+///
+/// Both are cyclic, so we embed them as points on the unit circle, such that
+/// 23:59 is close to 00:00, and Sunday is close to Monday. The sum of the
+/// embeddings of all listens of a track then indicates at what times we tend
+/// to listen to it, and its dot product with the embedding of the current time
+/// measures how well the track fits the current moment.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TimeVector(pub [f32; 4]);
+
+impl TimeVector {
+    pub fn from_local_time<Tz: TimeZone>(t: &DateTime<Tz>) -> TimeVector {
+        use std::f32::consts::TAU;
+        let day_fraction = t.num_seconds_from_midnight() as f32 / 86400.0;
+        let week_fraction = (t.weekday().num_days_from_monday() as f32 + day_fraction) / 7.0;
+        let (day_sin, day_cos) = (day_fraction * TAU).sin_cos();
+        let (week_sin, week_cos) = (week_fraction * TAU).sin_cos();
+        TimeVector([day_cos, day_sin, week_cos, week_sin])
+    }
+
+    /// Embed the instant, in the local time zone.
+    pub fn from_instant(t: Instant) -> TimeVector {
+        TimeVector::from_local_time(&Local.timestamp(t.to_posix_timestamp(), 0))
+    }
+
+    /// Embed the instant, at the UTC offset that was in effect at the time.
+    ///
+    /// When we don't know the offset, we fall back to the current local time
+    /// zone, which is wrong for listens on the other side of a daylight saving
+    /// time transition, or listens from when we lived elsewhere.
+    pub fn from_instant_at_offset(t: Instant, utc_offset_seconds: Option<i64>) -> TimeVector {
+        let offset = utc_offset_seconds.and_then(|s| FixedOffset::east_opt(s as i32));
+        match offset {
+            Some(tz) => TimeVector::from_local_time(&tz.timestamp(t.to_posix_timestamp(), 0)),
+            None => TimeVector::from_instant(t),
+        }
+    }
+
+    pub fn add(&mut self, other: &TimeVector) {
+        for (x, y) in self.0.iter_mut().zip(other.0.iter()) {
+            *x += y;
+        }
+    }
+
+    pub fn dot(&self, other: &TimeVector) -> f32 {
+        self.0.iter().zip(other.0.iter()).map(|(x, y)| x * y).sum()
+    }
+}
+
+/// A [`TimeVector`] with components in [-1.0, 1.0] quantized to `i8`.
+///
+/// We keep one of these per track in the user data, so the size adds up, and
+/// for the _for now_ mix we compute a dot product for every track. Four bytes
+/// fit in a register, and the integer dot product is easy to vectorize.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QuantizedTimeVector(pub [i8; 4]);
+
+impl QuantizedTimeVector {
+    /// Listens we pretend a track has that were spread evenly over the week.
+    ///
+    /// This shrinks the vectors of tracks with few listens towards zero, so a
+    /// track that we listened to only once does not fit the moment as well as
+    /// a track that we consistently listen to at this time.
+    const PRIOR_LISTENS: f32 = 2.0;
+
+    /// Quantize a vector whose components must be in [-1.0, 1.0].
+    pub fn from_time_vector(v: &TimeVector) -> QuantizedTimeVector {
+        let mut result = [0_i8; 4];
+        for (q, x) in result.iter_mut().zip(v.0.iter()) {
+            *q = (x * 127.0).round().clamp(-127.0, 127.0) as i8;
+        }
+        QuantizedTimeVector(result)
+    }
+
+    /// Quantize the sum of the embeddings of `n` listens.
+    pub fn from_listens(sum: &TimeVector, n: u32) -> QuantizedTimeVector {
+        let scale = 1.0 / (n as f32 + Self::PRIOR_LISTENS);
+        let mut mean = *sum;
+        for x in mean.0.iter_mut() {
+            *x *= scale;
+        }
+        QuantizedTimeVector::from_time_vector(&mean)
+    }
+
+    /// Return the dot product, scaled by 127² relative to [`TimeVector::dot`].
+    pub fn dot(&self, other: &QuantizedTimeVector) -> i32 {
+        self.0.iter().zip(other.0.iter()).map(|(x, y)| *x as i32 * *y as i32).sum()
+    }
+}
+
+/// The duration of an epoch in days.
+const EPOCH_DAYS: f32 = 16384.0 / 86400.0;
+
+/// Tunable parameters for counting and scoring, set from the config file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlaycountConfig {
+    /// Half-life in days for each of the timescales in [`ExpCounter::n`].
+    pub half_life_days: [f32; 5],
+
+    /// Weight of every timescale in the _trending_ score.
+    pub trending_weights: [f32; 5],
+
+    /// Weight of every timescale in the penalty for recent plays in the
+    /// _falling_ score, which is used for the _discover_ sorting method.
+    pub falling_recent_weights: [f32; 5],
+
+    /// How much ratings affect the _trending_ and _falling_ scores.
+    ///
+    /// See [`PlaycountConfig::adjust_for_rating`].
+    pub rating_weight: f32,
+}
+
+impl Default for PlaycountConfig {
+    fn default() -> Self {
+        Self {
+            half_life_days: ExpCounter::DEFAULT_HALF_LIFE_EPOCHS.map(|t| t * EPOCH_DAYS),
+            trending_weights: [0.0, 0.0, 0.1, 0.5, 2.0],
+            falling_recent_weights: [0.0, 0.0, 0.0, 0.25, 1.0],
+            rating_weight: 0.5,
+        }
+    }
+}
+
+impl PlaycountConfig {
+    /// Return the half-lives in epochs rather than days.
+    pub fn half_life_epochs(&self) -> [f32; 5] {
+        self.half_life_days.map(|t| t / EPOCH_DAYS)
+    }
+
+    /// Score for sorting entries by _trending_.
+    ///
+    /// Trending entries (tracks, albums, artists) are entries that have a high
+    /// playcount on a short timescale, while still mixing in a bit of a longer
+    /// time horizon.
+    pub fn score_trending(&self, counter: &ExpCounter) -> f32 {
+        self.trending_weights.iter().zip(counter.n.iter()).map(|(w, n)| w * n).sum()
+    }
+
+    /// Score for sorting entries by _falling_.
+    ///
+    /// Falling entries (tracks, albums, artists) are entries that have a high
+    /// playcount on a long-term timescale, but low playcount recently.
+    pub fn score_falling(&self, counter: &ExpCounter) -> f32 {
+        let age_12 = counter.n[1].ln() - counter.n[2].ln();
+        let age_13 = counter.n[1].ln() - counter.n[3].ln();
+        let recent_plays: f32 = self
+            .falling_recent_weights
+            .iter()
+            .zip(counter.n.iter())
+            .map(|(w, n)| w * n)
+            .sum();
+        // NB: The comment below was true when all half lives were double the
+        // current values, this may need tweaking.
+        // Empirically, age_13 and age_12 tend to correspond best to what I
+        // think of as "forgotten" tracks. But that doesn't discount one when
+        // you listen to it in recent listens, so we mix in counter (the shortest
+        // timescale) as a penalty.
+        // Counts for age_13 tend to be about 5x as large as for age_12, so to get a
+        // balanced mix, we take only 1/10 of age_13.
+        let age_mix = age_12 + age_13 * 0.1 - recent_plays;
+        let countish = (1.5 + counter.n[0]).ln();
+        age_mix * countish
+    }
+
+    /// Convert counts into an equivalent steady rate of plays per week.
+    ///
+    /// If we play something `r` times per day for a long time, the count for a
+    /// half-life of `h` days converges to the integral of the decay, `r * h /
+    /// ln(2)`, see also [`ExpCounter::DEFAULT_HALF_LIFE_EPOCHS`]. Inverting
+    /// that makes counts at different timescales comparable.
+    pub fn plays_per_week(&self, counter: &ExpCounter) -> [f32; 5] {
+        let mut result = [0.0; 5];
+        for ((r, n), h) in result.iter_mut().zip(counter.n.iter()).zip(self.half_life_days.iter()) {
+            *r = n * std::f32::consts::LN_2 / h * 7.0;
+        }
+        result
+    }
+
+    /// Adjust a _trending_ or _falling_ score for a rating.
+    ///
+    /// The rating is the value of a [`Rating`], or the mean rating for an
+    /// album. Every level moves the score by `rating_weight` times its
+    /// magnitude, so with a weight of 0.5 a liked track scores 1.5 times as
+    /// high, and a disliked track half as high. Scores can be negative, hence
+    /// the magnitude, so a dislike always lowers the score.
+    pub fn adjust_for_rating(&self, score: f32, rating: f32) -> f32 {
+        score + self.rating_weight * rating * score.abs()
+    }
+}
+
 /// Configures how the leaky bucket rate limiter behaves.
 ///
 /// Note, the current amount per bucket is stored in [`ExpCounter`], not in this
@@ -194,7 +390,10 @@ impl ExpCounter {
     /// TODO: We can choose for `t_1` the first time at which the album was
     /// seen, then new albums don't have as much of a penalty in the
     /// long-running average.
-    const HALF_LIFE_EPOCHS: [f32; 5] = [
+    ///
+    /// These are the defaults, the half-lives can be configured, see
+    /// [`PlaycountConfig`].
+    pub const DEFAULT_HALF_LIFE_EPOCHS: [f32; 5] = [
         // For the top two buckets we make an exception, that one we keep at 10
         // years.
         19260.0, // 3650 days / 10 years
@@ -207,9 +406,9 @@ impl ExpCounter {
 
     /// Return how much to decay the counters by after the elapsed time.
     #[inline]
-    pub fn decay_factors(duration: EpochDuration) -> [f32; 5] {
+    pub fn decay_factors(half_life_epochs: &[f32; 5], duration: EpochDuration) -> [f32; 5] {
         let dt = duration.epochs as f32;
-        Self::HALF_LIFE_EPOCHS.map(|t| 0.5_f32.powf(dt / t))
+        half_life_epochs.map(|t| 0.5_f32.powf(dt / t))
     }
 
     pub fn new() -> ExpCounter {
@@ -239,14 +438,14 @@ impl ExpCounter {
     ///
     /// This applies decay without incrementing the count.
     #[inline]
-    pub fn advance(&mut self, rate_limit: &RateLimit, t1: Instant) {
+    pub fn advance(&mut self, rate_limit: &RateLimit, half_life_epochs: &[f32; 5], t1: Instant) {
         debug_assert!(t1 >= self.t, "New time must be later than previous time.");
         self.refill_bucket(rate_limit, t1);
 
         // Note, we round to epochs first, and then take the diff, to ensure
         // that the decay gets applied at consistent times across all counters.
         let elapsed_epochs = t1.epoch().duration_since(self.t.epoch());
-        let decay_factors = Self::decay_factors(elapsed_epochs);
+        let decay_factors = Self::decay_factors(half_life_epochs, elapsed_epochs);
 
         for (ni, factor) in self.n.iter_mut().zip(decay_factors) {
             *ni *= factor;
@@ -257,7 +456,7 @@ impl ExpCounter {
 
     /// Advance the time to the given instant and increment the count.
     #[inline]
-    pub fn increment(&mut self, rate_limit: &RateLimit, t1: Instant) {
+    pub fn increment(&mut self, rate_limit: &RateLimit, half_life_epochs: &[f32; 5], t1: Instant) {
         debug_assert!(t1 >= self.t, "New time must be later than previous time.");
         self.refill_bucket(rate_limit, t1);
 
@@ -269,7 +468,7 @@ impl ExpCounter {
         // Apply any decay that has happened since the last update. See also
         // the comment in `advance`.
         let elapsed_epochs = t1.epoch().duration_since(self.t.epoch());
-        let decay_factors = Self::decay_factors(elapsed_epochs);
+        let decay_factors = Self::decay_factors(half_life_epochs, elapsed_epochs);
 
         for (ni, factor) in self.n.iter_mut().zip(decay_factors) {
             *ni = ni.mul_add(factor, count);
@@ -306,6 +505,78 @@ impl Ord for RevNotNan {
     }
 }
 
+/// Counts how often artists are listened to close to each other.
+///
+/// Within a listening session, every artist that we listen to is paired with
+/// the last few distinct artists before it. Artists that are often paired are
+/// likely to be similar, or at least to fit together. A session ends after a
+/// gap in listening of more than [`CoListens::SESSION_GAP_SECONDS`].
+#[derive(Default)]
+struct CoListens {
+    /// The most recent distinct artists in the current session, oldest first.
+    window: Vec<ArtistId>,
+
+    /// The number of times the artist entered the window.
+    counts: HashMap<ArtistId, f32>,
+
+    /// The number of times the pair was in the window together.
+    ///
+    /// The pairs are ordered, the smallest artist id comes first.
+    pairs: HashMap<(ArtistId, ArtistId), f32>,
+}
+
+impl CoListens {
+    /// The number of distinct artists that an artist gets paired with.
+    const WINDOW_LEN: usize = 4;
+
+    /// A gap between listens longer than this starts a new session.
+    const SESSION_GAP_SECONDS: u32 = 3600;
+
+    /// The number of similar artists to keep per artist.
+    const NUM_SIMILAR: usize = 25;
+
+    fn end_session(&mut self) {
+        self.window.clear();
+    }
+
+    fn observe(&mut self, artist_id: ArtistId) {
+        if self.window.contains(&artist_id) {
+            return
+        }
+        for other in &self.window {
+            let pair = (artist_id.min(*other), artist_id.max(*other));
+            *self.pairs.entry(pair).or_default() += 1.0;
+        }
+        *self.counts.entry(artist_id).or_default() += 1.0;
+        if self.window.len() == Self::WINDOW_LEN {
+            self.window.remove(0);
+        }
+        self.window.push(artist_id);
+    }
+
+    /// Return the most similar artists per artist, most similar first.
+    ///
+    /// The similarity is the cosine similarity of the artists’ co-occurrence,
+    /// with a bit of damping, so a pair of rarely played artists that we
+    /// happened to listen to once in the same session does not come out as
+    /// perfectly similar.
+    fn similarities(&self) -> HashMap<ArtistId, Vec<(ArtistId, f32)>> {
+        let mut result: HashMap<ArtistId, Vec<(ArtistId, f32)>> = HashMap::new();
+        for (&(a, b), &n) in self.pairs.iter() {
+            let n_a = self.counts[&a];
+            let n_b = self.counts[&b];
+            let similarity = n / ((n_a * n_b).sqrt() + 1.0);
+            result.entry(a).or_default().push((b, similarity));
+            result.entry(b).or_default().push((a, similarity));
+        }
+        for similar in result.values_mut() {
+            similar.sort_by(|x, y| y.1.total_cmp(&x.1).then(x.0.cmp(&y.0)));
+            similar.truncate(Self::NUM_SIMILAR);
+        }
+        result
+    }
+}
+
 /// A playcounter counts plays.
 ///
 /// Internally it has a counter per entry (artist, album, track) with
@@ -314,11 +585,34 @@ impl Ord for RevNotNan {
 /// comparable, we have to advance all counters to the same timestamp, which is
 /// what [`into_counts`] does.
 pub struct PlayCounter {
+    config: PlaycountConfig,
+
+    /// The half-lives from the config, converted to epochs.
+    half_life_epochs: [f32; 5],
+
     /// The timestamp of the last inserted listen.
     last_counted_at: Instant,
     artists: HashMap<ArtistId, ExpCounter>,
     albums: HashMap<AlbumId, ExpCounter>,
     tracks: HashMap<TrackId, ExpCounter>,
+
+    /// Number of listens and sum of their time embeddings per track.
+    ///
+    /// Unlike the counters, these do not decay.
+    track_times: HashMap<TrackId, (u32, TimeVector)>,
+
+    /// Sum of the time embeddings of the listens per album and per artist.
+    album_times: HashMap<AlbumId, TimeVector>,
+    artist_times: HashMap<ArtistId, TimeVector>,
+
+    /// The time of the most recent listen per track.
+    track_last_listened: HashMap<TrackId, Instant>,
+
+    /// Counts skips per track, as a negative signal.
+    track_skips: HashMap<TrackId, ExpCounter>,
+
+    /// Artists that we listen to in the same session.
+    co_listens: CoListens,
 }
 
 /// Playcounts are the result of using a playcounter.
@@ -333,17 +627,33 @@ pub struct PlayCounts {
 }
 
 impl PlayCounter {
-    pub fn new() -> PlayCounter {
+    pub fn new(config: PlaycountConfig) -> PlayCounter {
         PlayCounter {
+            half_life_epochs: config.half_life_epochs(),
+            config: config,
             last_counted_at: Instant {
                 seconds_since_jan_2000: 0,
             },
             artists: HashMap::new(),
             albums: HashMap::new(),
             tracks: HashMap::new(),
+            track_times: HashMap::new(),
+            album_times: HashMap::new(),
+            artist_times: HashMap::new(),
+            track_last_listened: HashMap::new(),
+            track_skips: HashMap::new(),
+            co_listens: CoListens::default(),
         }
     }
 
+    /// Return an empty counter with the same configuration.
+    ///
+    /// This is needed when listens in the past change, because counting is
+    /// incremental, so then we need to count everything again.
+    pub fn cleared(self) -> PlayCounter {
+        PlayCounter::new(self.config)
+    }
+
     /// For artists, we want some balance between "unique days listened to
     /// this artist" (which would correspond to a capacity of 1 and a fill
     /// rate of 1/day) and "time listened to this artist" (which would
@@ -375,7 +685,14 @@ impl PlayCounter {
         fill_rate_per_second: 1.0,
     };
 
-    pub fn count(&mut self, index: &MemoryMetaIndex, at: Instant, track_id: TrackId) {
+    /// Count a listen of the track, the offset is the UTC offset of the local time.
+    pub fn count(
+        &mut self,
+        index: &MemoryMetaIndex,
+        at: Instant,
+        utc_offset_seconds: Option<i64>,
+        track_id: TrackId,
+    ) {
         debug_assert!(
             at >= self.last_counted_at,
             "Counts must be done in ascending order."
@@ -389,32 +706,60 @@ impl PlayCounter {
         };
 
         let counter_track = self.tracks.entry(track_id).or_default();
-        counter_track.increment(&Self::LIMIT_TRACK, at);
+        counter_track.increment(&Self::LIMIT_TRACK, &self.half_life_epochs, at);
+
+        let time = TimeVector::from_instant_at_offset(at, utc_offset_seconds);
+        let time_track = self.track_times.entry(track_id).or_default();
+        time_track.0 += 1;
+        time_track.1.add(&time);
+        self.track_last_listened.insert(track_id, at);
 
         let counter_album = self.albums.entry(album_id).or_default();
-        counter_album.increment(&Self::LIMIT_ALBUM, at);
+        counter_album.increment(&Self::LIMIT_ALBUM, &self.half_life_epochs, at);
+        self.album_times.entry(album_id).or_default().add(&time);
+
+        let gap_seconds = at.seconds_since_jan_2000.saturating_sub(self.last_counted_at.seconds_since_jan_2000);
+        if gap_seconds > CoListens::SESSION_GAP_SECONDS {
+            self.co_listens.end_session();
+        }
 
         for artist_id in index.get_album_artists(album.artist_ids) {
             let counter_artist = self.artists.entry(*artist_id).or_default();
-            counter_artist.increment(&Self::LIMIT_ARTIST, at);
+            counter_artist.increment(&Self::LIMIT_ARTIST, &self.half_life_epochs, at);
+            self.artist_times.entry(*artist_id).or_default().add(&time);
+            self.co_listens.observe(*artist_id);
         }
 
         self.last_counted_at = at;
     }
 
+    /// Count a skip of the track, which does not count as a listen.
+    pub fn count_skip(&mut self, at: Instant, track_id: TrackId) {
+        debug_assert!(
+            at >= self.last_counted_at,
+            "Counts must be done in ascending order."
+        );
+        let counter_skip = self.track_skips.entry(track_id).or_default();
+        counter_skip.increment(&Self::LIMIT_TRACK, &self.half_life_epochs, at);
+        self.last_counted_at = at;
+    }
+
     /// Advance all counters (without incrementing) to time `t`.
     ///
     /// This enables the `n` value of the counters to be directly compared
     /// between different counters.
     pub fn advance_counters(&mut self, t: Instant) {
         for counter in self.artists.values_mut() {
-            counter.advance(&Self::LIMIT_ARTIST, t);
+            counter.advance(&Self::LIMIT_ARTIST, &self.half_life_epochs, t);
         }
         for counter in self.albums.values_mut() {
-            counter.advance(&Self::LIMIT_ALBUM, t);
+            counter.advance(&Self::LIMIT_ALBUM, &self.half_life_epochs, t);
         }
         for counter in self.tracks.values_mut() {
-            counter.advance(&Self::LIMIT_TRACK, t);
+            counter.advance(&Self::LIMIT_TRACK, &self.half_life_epochs, t);
+        }
+        for counter in self.track_skips.values_mut() {
+            counter.advance(&Self::LIMIT_TRACK, &self.half_life_epochs, t);
         }
     }
 
@@ -429,14 +774,20 @@ impl PlayCounter {
     ) -> database::Result<()> {
         let start_second = self.last_counted_at.to_posix_timestamp();
         let mut n = 0;
+        let mut n_skips = 0;
         for listen_opt in database::iter_listens_since(tx, start_second)? {
             let listen = listen_opt?;
             let at = Instant::from_posix_timestamp(listen.started_at_second);
             let track_id = TrackId(listen.track_id as u64);
-            self.count(index, at.into(), track_id);
-            n += 1;
+            if listen.is_skipped != 0 {
+                self.count_skip(at, track_id);
+                n_skips += 1;
+            } else {
+                self.count(index, at, listen.utc_offset_seconds, track_id);
+                n += 1;
+            }
         }
-        println!("Imported {n} new listens from database.");
+        info!("Imported {n} new listens and {n_skips} skips from database.");
         Ok(())
     }
 
@@ -449,6 +800,62 @@ impl PlayCounter {
     }
 }
 
+/// A method for ranking artists, albums, and tracks.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Chart {
+    /// Rank by playcount at the given timescale, an index into `ExpCounter::n`.
+    Top(usize),
+
+    /// Rank by [`PlaycountConfig::score_trending`].
+    Trending,
+
+    /// Rank by [`PlaycountConfig::score_falling`].
+    Falling,
+
+    /// Rank by how much we listen at the current time of the day and week.
+    ///
+    /// The score is the dot product of the sum of the [`TimeVector`]s of all
+    /// listens with the one of now, so listens at the opposite time count
+    /// negatively.
+    Now,
+}
+
+/// Return the `n_top` keys with the highest value of `expr`, highest first.
+fn get_top_n<K: Copy + Ord, V, F: FnMut(&V) -> RevNotNan>(
+    n_top: usize,
+    expr: &mut F,
+    values: &HashMap<K, V>,
+) -> Vec<(RevNotNan, K)> {
+    let mut result = BinaryHeap::new();
+
+    for (k, value) in values.iter() {
+        let count = expr(value);
+
+        if result.len() < n_top {
+            result.push((count, *k));
+            continue;
+        }
+
+        let should_insert = match result.peek() {
+            None => true,
+            Some((other_count, _)) => count.0 > other_count.0,
+        };
+        if should_insert {
+            result.pop();
+            result.push((count, *k));
+        }
+    }
+
+    result.into_sorted_vec()
+}
+
+/// The top artists, albums, and tracks, with their counts.
+pub type Rankings = (
+    Vec<(RevNotNan, ArtistId)>,
+    Vec<(RevNotNan, AlbumId)>,
+    Vec<(RevNotNan, TrackId)>,
+);
+
 impl PlayCounts {
     pub fn into_counter(self) -> PlayCounter {
         self.counter
@@ -469,42 +876,10 @@ impl PlayCounts {
         &self,
         n_top: usize,
         mut expr: F,
-    ) -> (
-        Vec<(RevNotNan, ArtistId)>,
-        Vec<(RevNotNan, AlbumId)>,
-        Vec<(RevNotNan, TrackId)>,
-    )
+    ) -> Rankings
     where
         F: FnMut(&ExpCounter) -> RevNotNan,
     {
-        fn get_top_n<K: Copy + Ord, F: FnMut(&ExpCounter) -> RevNotNan>(
-            n_top: usize,
-            expr: &mut F,
-            counters: &HashMap<K, ExpCounter>,
-        ) -> Vec<(RevNotNan, K)> {
-            let mut result = BinaryHeap::new();
-
-            for (k, counter) in counters.iter() {
-                let count = expr(counter);
-
-                if result.len() < n_top {
-                    result.push((count, *k));
-                    continue;
-                }
-
-                let should_insert = match result.peek() {
-                    None => true,
-                    Some((other_count, _)) => count.0 > other_count.0,
-                };
-                if should_insert {
-                    result.pop();
-                    result.push((count, *k));
-                }
-            }
-
-            result.into_sorted_vec()
-        }
-
         (
             get_top_n(n_top, &mut expr, &self.counter.artists),
             get_top_n(n_top, &mut expr, &self.counter.albums),
@@ -512,158 +887,622 @@ impl PlayCounts {
         )
     }
 
+    /// Return the plays per week equivalent of the artist at every timescale.
+    ///
+    /// See [`PlaycountConfig::plays_per_week`].
+    pub fn get_artist_plays_per_week(&self, artist_id: ArtistId) -> [f32; 5] {
+        self.plays_per_week(self.counter.artists.get(&artist_id))
+    }
+
+    /// Return the plays per week equivalent of the album at every timescale.
+    pub fn get_album_plays_per_week(&self, album_id: AlbumId) -> [f32; 5] {
+        self.plays_per_week(self.counter.albums.get(&album_id))
+    }
+
+    /// Return the plays per week equivalent of the track at every timescale.
+    pub fn get_track_plays_per_week(&self, track_id: TrackId) -> [f32; 5] {
+        self.plays_per_week(self.counter.tracks.get(&track_id))
+    }
+
+    fn plays_per_week(&self, counter: Option<&ExpCounter>) -> [f32; 5] {
+        match counter {
+            Some(c) => self.counter.config.plays_per_week(c),
+            None => [0.0; 5],
+        }
+    }
+
+    /// Return the top `n` artists, albums, and tracks for the given chart.
+    pub fn get_chart(&self, chart: Chart, n_top: usize) -> Rankings {
+        let config = &self.counter.config;
+        match chart {
+            Chart::Top(timescale) => self.get_top_by(n_top, |c| RevNotNan(c.n[timescale])),
+            Chart::Trending => self.get_top_by(n_top, |c| RevNotNan(config.score_trending(c))),
+            Chart::Falling => self.get_top_by(n_top, |c| RevNotNan(config.score_falling(c))),
+            Chart::Now => {
+                let now = TimeVector::from_local_time(&Local::now());
+                let c = &self.counter;
+                (
+                    get_top_n(n_top, &mut |sum: &TimeVector| RevNotNan(sum.dot(&now)), &c.artist_times),
+                    get_top_n(n_top, &mut |sum: &TimeVector| RevNotNan(sum.dot(&now)), &c.album_times),
+                    get_top_n(n_top, &mut |(_, sum): &(u32, TimeVector)| RevNotNan(sum.dot(&now)), &c.track_times),
+                )
+            }
+        }
+    }
+
+    /// Return up to `n` tracks in the library that were never played, in index order.
+    ///
+    /// Disliked tracks are excluded, those are forgotten for a reason.
+    pub fn get_never_played(&self, index: &MemoryMetaIndex, user_data: &UserData, n: usize) -> Vec<TrackId> {
+        index
+            .get_tracks()
+            .iter()
+            .map(|t| t.track_id)
+            .filter(|track_id| !self.counter.tracks.contains_key(track_id))
+            .filter(|track_id| user_data.get_track_rating(*track_id) != Rating::Dislike)
+            .take(n)
+            .collect()
+    }
+
+    /// Return up to `n` tracks in the library that were played, least played first.
+    ///
+    /// Tracks are ranked by their count at the longest timescale. Disliked
+    /// tracks are excluded, like for [`PlayCounts::get_never_played`].
+    pub fn get_least_played(&self, index: &MemoryMetaIndex, user_data: &UserData, n: usize) -> Vec<TrackId> {
+        let mut ranked: Vec<(f32, TrackId)> = self
+            .counter
+            .tracks
+            .iter()
+            .filter(|(track_id, _)| index.get_track(**track_id).is_some())
+            .filter(|(track_id, _)| user_data.get_track_rating(**track_id) != Rating::Dislike)
+            .map(|(track_id, counter)| (counter.n[0], *track_id))
+            .collect();
+        ranked.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        ranked.into_iter().take(n).map(|(_, track_id)| track_id).collect()
+    }
+
     /// Recompute the albums table for the mutable user data.
-    pub fn compute_album_user_data(&self) -> AlbumTable<AlbumState> {
+    ///
+    /// The scores take into account the rating of the album, or when the album
+    /// itself is not rated, the mean rating of the album's tracks.
+    pub fn compute_album_user_data(&self, index: &MemoryMetaIndex, user_data: &UserData) -> AlbumTable<AlbumState> {
+        let config = &self.counter.config;
         let mut albums = AlbumTable::new(self.counter.albums.len(), AlbumState::default());
         for (album_id, counter) in self.counter.albums.iter() {
+            let rating = match user_data.get_album_rating(*album_id) {
+                Rating::Neutral => {
+                    let tracks = index.get_album_tracks(*album_id);
+                    let rating_sum: f32 = tracks
+                        .iter()
+                        .map(|t| user_data.get_track_rating(t.track_id) as i8 as f32)
+                        .sum();
+                    rating_sum / (tracks.len().max(1) as f32)
+                }
+                album_rating => album_rating as i8 as f32,
+            };
             let state = AlbumState {
-                discover_score: score_falling(counter),
-                trending_score: score_trending(counter),
+                discover_score: config.adjust_for_rating(config.score_falling(counter), rating),
+                trending_score: config.adjust_for_rating(config.score_trending(counter), rating),
+                playcount: counter.n[0],
             };
             albums.insert(*album_id, state);
         }
         albums
     }
+
+    /// Recompute the track scores for the mutable user data.
+    ///
+    /// This includes tracks that were skipped but never played. The scores
+    /// take into account the track's rating.
+    pub fn compute_track_user_data(&self, user_data: &UserData) -> Vec<(TrackId, TrackScores)> {
+        let c = &self.counter;
+        let track_ids = c.tracks.keys().chain(c.track_skips.keys().filter(|k| !c.tracks.contains_key(k)));
+        track_ids
+            .map(|track_id| {
+                let rating = user_data.get_track_rating(*track_id) as i8 as f32;
+                let scores = TrackScores {
+                    discover_score: c
+                        .tracks
+                        .get(track_id)
+                        .map(|tc| c.config.adjust_for_rating(c.config.score_falling(tc), rating))
+                        .unwrap_or_default(),
+                    time_vector: c
+                        .track_times
+                        .get(track_id)
+                        .map(|(n, sum)| QuantizedTimeVector::from_listens(sum, *n))
+                        .unwrap_or_default(),
+                    skip_count: c.track_skips.get(track_id).map(|sc| sc.n[2]).unwrap_or_default(),
+                    last_listened_at: c.track_last_listened.get(track_id).copied(),
+                };
+                (*track_id, scores)
+            })
+            .collect()
+    }
+
+    /// Recompute the artists table for the mutable user data.
+    pub fn compute_artist_user_data(&self) -> HashMap<ArtistId, ArtistState> {
+        self.counter
+            .co_listens
+            .similarities()
+            .into_iter()
+            .map(|(artist_id, similar)| (artist_id, ArtistState { similar }))
+            .collect()
+    }
+}
+
+/// File format for [`export`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<ExportFormat> {
+        match format {
+            "csv" => Some(ExportFormat::Csv),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// A row in the export, the counter of one artist, album, or track.
+struct ExportRow<'a> {
+    kind: &'static str,
+    id: String,
+    name: &'a str,
+    artist: &'a str,
+    counter: &'a ExpCounter,
+}
+
+/// Write a field as csv, quoted only when needed.
+pub(crate) fn write_csv_field<W: Write>(mut w: W, value: &str) -> io::Result<()> {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        write!(w, "\"{}\"", value.replace('"', "\"\""))
+    } else {
+        write!(w, "{}", value)
+    }
+}
+
+/// Write all counters with their rank, counts per timescale, and scores.
+///
+/// Entities are ordered by kind (artists, albums, tracks), and within a kind
+/// by rank. The rank is by playcount at the longest timescale.
+pub fn export<W: Write>(
+    index: &MemoryMetaIndex,
+    counts: &PlayCounts,
+    format: ExportFormat,
+    mut w: W,
+) -> io::Result<()> {
+    let c = &counts.counter;
+    let mut sections: Vec<Vec<ExportRow>> = Vec::with_capacity(3);
+
+    sections.push(c.artists.iter().filter_map(|(artist_id, counter)| {
+        let artist = index.get_artist(*artist_id)?;
+        let name = index.get_string(artist.name);
+        Some(ExportRow { kind: "artist", id: artist_id.to_string(), name, artist: name, counter })
+    }).collect());
+    sections.push(c.albums.iter().filter_map(|(album_id, counter)| {
+        let album = index.get_album(*album_id)?;
+        Some(ExportRow {
+            kind: "album",
+            id: album_id.to_string(),
+            name: index.get_string(album.title),
+            artist: index.get_string(album.artist),
+            counter,
+        })
+    }).collect());
+    sections.push(c.tracks.iter().filter_map(|(track_id, counter)| {
+        let track = index.get_track(*track_id)?;
+        Some(ExportRow {
+            kind: "track",
+            id: track_id.to_string(),
+            name: index.get_string(track.title),
+            artist: index.get_string(track.artist),
+            counter,
+        })
+    }).collect());
+
+    match format {
+        ExportFormat::Csv => writeln!(w, "kind,rank,id,name,artist,n0,n1,n2,n3,n4,trending,falling")?,
+        ExportFormat::Json => write!(w, "[")?,
+    }
+
+    let mut first = true;
+    for rows in sections.iter_mut() {
+        rows.sort_by(|a, b| b.counter.n[0].total_cmp(&a.counter.n[0]).then(a.id.cmp(&b.id)));
+        for (i, row) in rows.iter().enumerate() {
+            let n = &row.counter.n;
+            let trending = c.config.score_trending(row.counter);
+            let falling = c.config.score_falling(row.counter);
+            match format {
+                ExportFormat::Csv => {
+                    write!(w, "{},{},{},", row.kind, i + 1, row.id)?;
+                    write_csv_field(&mut w, row.name)?;
+                    write!(w, ",")?;
+                    write_csv_field(&mut w, row.artist)?;
+                    writeln!(
+                        w,
+                        ",{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4}",
+                        n[0], n[1], n[2], n[3], n[4], trending, falling,
+                    )?;
+                }
+                ExportFormat::Json => {
+                    if !first { write!(w, ",")?; }
+                    write!(w, r#"{{"kind":"{}","rank":{},"id":"{}","name":"#, row.kind, i + 1, row.id)?;
+                    serde_json::to_writer(&mut w, row.name)?;
+                    write!(w, r#","artist":"#)?;
+                    serde_json::to_writer(&mut w, row.artist)?;
+                    write!(
+                        w,
+                        r#","counts":[{:.4},{:.4},{:.4},{:.4},{:.4}],"trending":{:.4},"falling":{}}}"#,
+                        n[0], n[1], n[2], n[3], n[4], trending,
+                        // The falling score can be -inf or NaN for counters
+                        // that have not decayed, which json cannot represent.
+                        if falling.is_finite() { format!("{:.4}", falling) } else { "null".to_string() },
+                    )?;
+                }
+            }
+            first = false;
+        }
+    }
+
+    match format {
+        ExportFormat::Csv => Ok(()),
+        ExportFormat::Json => writeln!(w, "]"),
+    }
 }
 
 fn print_ranking(
     title: &'static str,
     description: String,
     index: &MemoryMetaIndex,
-    top_artists: &[(RevNotNan, ArtistId)],
-    top_albums: &[(RevNotNan, AlbumId)],
-    top_tracks: &[(RevNotNan, TrackId)],
+    scope: Option<Scope>,
+    (top_artists, top_albums, top_tracks): &Rankings,
 ) {
-    println!("\n{title} ARTISTS ({description})\n");
-    for (i, (count, artist_id)) in top_artists.iter().enumerate() {
-        let artist = index.get_artist(*artist_id).unwrap();
-        let artist_name = index.get_string(artist.name);
-
-        println!(
-            "  {:2} {:7.3} {} {}",
-            i + 1,
-            count.0,
-            artist_id,
-            artist_name
-        );
+    if matches!(scope, None | Some(Scope::Artists)) {
+        println!("\n{title} ARTISTS ({description})\n");
+        for (i, (count, artist_id)) in top_artists.iter().enumerate() {
+            let artist = index.get_artist(*artist_id).unwrap();
+            let artist_name = index.get_string(artist.name);
+
+            println!(
+                "  {:2} {:7.3} {} {}",
+                i + 1,
+                count.0,
+                artist_id,
+                artist_name
+            );
+        }
     }
 
-    println!("\n{title} ALBUMS ({description})\n");
-    for (i, (count, album_id)) in top_albums.iter().enumerate() {
-        let album = index.get_album(*album_id).unwrap();
-        let album_title = index.get_string(album.title);
-        let album_artist = index.get_string(album.artist);
+    if matches!(scope, None | Some(Scope::Albums)) {
+        println!("\n{title} ALBUMS ({description})\n");
+        for (i, (count, album_id)) in top_albums.iter().enumerate() {
+            let album = index.get_album(*album_id).unwrap();
+            let album_title = index.get_string(album.title);
+            let album_artist = index.get_string(album.artist);
+
+            println!(
+                "  {:2} {:7.3} {} {:25}  {}",
+                i + 1,
+                count.0,
+                album_id,
+                album_title,
+                album_artist
+            );
+        }
+    }
 
-        println!(
-            "  {:2} {:7.3} {} {:25}  {}",
-            i + 1,
-            count.0,
-            album_id,
-            album_title,
-            album_artist
-        );
+    if matches!(scope, None | Some(Scope::Tracks)) {
+        println!("\n{title} TRACKS ({description})\n");
+        for (i, (count, track_id)) in top_tracks.iter().enumerate() {
+            let track = index.get_track(*track_id).unwrap();
+            let track_title = index.get_string(track.title);
+            let track_artist = index.get_string(track.artist);
+
+            println!(
+                "  {:2} {:7.3} {} {:25}  {}",
+                i + 1,
+                count.0,
+                track_id,
+                track_title,
+                track_artist
+            );
+        }
     }
+}
 
-    println!("\n{title} TRACKS ({description})\n");
-    for (i, (count, track_id)) in top_tracks.iter().enumerate() {
-        let track = index.get_track(*track_id).unwrap();
-        let track_title = index.get_string(track.title);
-        let track_artist = index.get_string(track.artist);
+/// Return the rankings as json, the counterpart of [`print_ranking`].
+fn ranking_json(
+    index: &MemoryMetaIndex,
+    scope: Option<Scope>,
+    (top_artists, top_albums, top_tracks): &Rankings,
+) -> Value {
+    let mut result = serde_json::Map::new();
+
+    if matches!(scope, None | Some(Scope::Artists)) {
+        let artists: Vec<Value> = top_artists.iter().enumerate().map(|(i, (count, artist_id))| {
+            let artist = index.get_artist(*artist_id).unwrap();
+            json!({
+                "rank": i + 1,
+                "score": count.0,
+                "id": artist_id.to_string(),
+                "name": index.get_string(artist.name),
+            })
+        }).collect();
+        result.insert("artists".to_string(), Value::Array(artists));
+    }
 
-        println!(
-            "  {:2} {:7.3} {} {:25}  {}",
-            i + 1,
-            count.0,
-            track_id,
-            track_title,
-            track_artist
-        );
+    if matches!(scope, None | Some(Scope::Albums)) {
+        let albums: Vec<Value> = top_albums.iter().enumerate().map(|(i, (count, album_id))| {
+            let album = index.get_album(*album_id).unwrap();
+            json!({
+                "rank": i + 1,
+                "score": count.0,
+                "id": album_id.to_string(),
+                "title": index.get_string(album.title),
+                "artist": index.get_string(album.artist),
+            })
+        }).collect();
+        result.insert("albums".to_string(), Value::Array(albums));
+    }
+
+    if matches!(scope, None | Some(Scope::Tracks)) {
+        let tracks: Vec<Value> = top_tracks.iter().enumerate().map(|(i, (count, track_id))| {
+            let track = index.get_track(*track_id).unwrap();
+            json!({
+                "rank": i + 1,
+                "score": count.0,
+                "id": track_id.to_string(),
+                "title": index.get_string(track.title),
+                "artist": index.get_string(track.artist),
+            })
+        }).collect();
+        result.insert("tracks".to_string(), Value::Array(tracks));
+    }
+
+    Value::Object(result)
+}
+
+/// Which rankings [`main`] prints.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Scope {
+    Artists,
+    Albums,
+    Tracks,
+}
+
+impl Scope {
+    pub fn parse(scope: &str) -> Option<Scope> {
+        match scope {
+            "artists" => Some(Scope::Artists),
+            "albums" => Some(Scope::Albums),
+            "tracks" => Some(Scope::Tracks),
+            _ => None,
+        }
     }
 }
 
-/// Score for sorting entries by _trending_.
-///
-/// Trending entries (tracks, albums, artists) are entries that have a high
-/// playcount on a short timescale, while still mixing in a bit of a longer
-/// time horizon.
-fn score_trending(counter: &ExpCounter) -> f32 {
-    0.0
-    + (2.0 * counter.n[4])
-    + (0.5 * counter.n[3])
-    + (0.1 * counter.n[2])
+/// What [`main`] prints.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CountOptions {
+    /// The charts to print, in order.
+    pub charts: Vec<Chart>,
+
+    /// The length of the rankings, by default 150 for top charts, 350 for others.
+    pub limit: Option<usize>,
+
+    /// Print only the rankings for artists, albums, or tracks, or all if `None`.
+    pub scope: Option<Scope>,
 }
 
-/// Score for sorting entries by _falling_.
-///
-/// Falling entries (tracks, albums, artists) are entries that have a high
-/// playcount on a long-term timescale, but low playcount recently.
-fn score_falling(counter: &ExpCounter) -> f32 {
-    let age_12 = counter.n[1].ln() - counter.n[2].ln();
-    let age_13 = counter.n[1].ln() - counter.n[3].ln();
-    let recent_plays = counter.n[4] + counter.n[3] * 0.25;
-    // NB: The comment below was true when all half lives were double the
-    // current values, this may need tweaking.
-    // Empirically, age_13 and age_12 tend to correspond best to what I
-    // think of as "forgotten" tracks. But that doesn't discount one when
-    // you listen to it in recent listens, so we mix in counter (the shortest
-    // timescale) as a penalty.
-    // Counts for age_13 tend to be about 5x as large as for age_12, so to get a
-    // balanced mix, we take only 1/10 of age_13.
-    let age_mix = age_12 + age_13 * 0.1 - recent_plays;
-    let countish = (1.5 + counter.n[0]).ln();
-    age_mix * countish
+impl Default for CountOptions {
+    fn default() -> Self {
+        let mut charts: Vec<Chart> = (0..5).map(Chart::Top).collect();
+        charts.push(Chart::Trending);
+        charts.push(Chart::Falling);
+        CountOptions { charts, limit: None, scope: None }
+    }
 }
 
 /// Print playcount statistics about the library.
 ///
 /// This is mostly for debugging and development purposes, playcounts should be
-/// integrated into the application at a later time.
-pub fn main(index: &MemoryMetaIndex, db_path: &Path) -> crate::Result<()> {
-    let conn = connect_readonly(db_path)?;
+/// integrated into the application at a later time. When `export` is set,
+/// write the counts to that file in the given format instead of printing.
+/// Otherwise, `options` determine which rankings to print, as a single json
+/// object when `json` is set.
+pub fn main(
+    index: &MemoryMetaIndex,
+    config: &Config,
+    export: Option<(ExportFormat, &str)>,
+    options: &CountOptions,
+    json: bool,
+) -> crate::Result<()> {
+    let conn = connect_readonly(&config.db_path, &config.db_pragmas)?;
     let mut db = database::Connection::new(&conn);
 
-    let mut counter = PlayCounter::new();
+    let mut counter = PlayCounter::new(config.playcount.clone());
     let mut tx = db.begin()?;
     counter.count_from_database(index, &mut tx)?;
     tx.commit()?;
     let counts = counter.into_counts();
 
-    for timescale in 0..5 {
-        let n_days = ExpCounter::HALF_LIFE_EPOCHS[timescale] * 0.1896;
-        let n_months = ExpCounter::HALF_LIFE_EPOCHS[timescale] * 0.1896 * (12.0 / 365.25);
-
-        let (top_artists, top_albums, top_tracks) =
-            counts.get_top_by(150, |counter: &ExpCounter| RevNotNan(counter.n[timescale]));
-        print_ranking(
-            "TOP",
-            format!("timescale {}, {:.0} days / {:.0} months", timescale, n_days, n_months),
-            index,
-            &top_artists,
-            &top_albums,
-            &top_tracks,
-        );
+    if let Some((format, path)) = export {
+        let f = fs::File::create(path)?;
+        let mut w = io::BufWriter::new(f);
+        self::export(index, &counts, format, &mut w)?;
+        w.flush()?;
+        println!("Exported playcounts to {}.", path);
+        return Ok(())
     }
 
-    let (trending_artists, trending_albums, trending_tracks) =
-        counts.get_top_by(350, |c| RevNotNan(score_trending(c)));
-    print_ranking(
-        "TRENDING",
-        "see code for formula".to_string(),
-        index,
-        &trending_artists,
-        &trending_albums,
-        &trending_tracks,
-    );
-
-    let (falling_artists, falling_albums, falling_tracks) = counts.get_top_by(350, |c| RevNotNan(score_falling(c)));
-    print_ranking(
-        "FALLING",
-        "see code for formula".to_string(),
-        index,
-        &falling_artists,
-        &falling_albums,
-        &falling_tracks,
-    );
+    let mut charts_json = Vec::new();
+
+    for &chart in &options.charts {
+        let (title, description, default_limit) = match chart {
+            Chart::Top(timescale) => {
+                let n_days = config.playcount.half_life_days[timescale];
+                let n_months = n_days * (12.0 / 365.25);
+                let description = format!(
+                    "timescale {}, {:.0} days / {:.0} months",
+                    timescale, n_days, n_months,
+                );
+                ("TOP", description, 150)
+            }
+            Chart::Trending => ("TRENDING", "see code for formula".to_string(), 350),
+            Chart::Falling => ("FALLING", "see code for formula".to_string(), 350),
+            Chart::Now => ("NOW", "at this time of the day and week".to_string(), 350),
+        };
+        let rankings = counts.get_chart(chart, options.limit.unwrap_or(default_limit));
+
+        if json {
+            let mut chart_json = ranking_json(index, options.scope, &rankings);
+            chart_json["chart"] = json!(title.to_lowercase());
+            if let Chart::Top(timescale) = chart {
+                chart_json["timescale"] = json!(timescale);
+                chart_json["half_life_days"] = json!(config.playcount.half_life_days[timescale]);
+            }
+            charts_json.push(chart_json);
+        } else {
+            print_ranking(title, description, index, options.scope, &rankings);
+        }
+    }
+
+    if json {
+        println!("{}", json!({ "charts": charts_json }));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::{FixedOffset, TimeZone};
+
+    use super::{write_csv_field, CoListens, ExpCounter, Instant, PlayCounter, PlaycountConfig};
+    use super::{QuantizedTimeVector, TimeVector};
+    use crate::prim::ArtistId;
+
+    #[test]
+    fn time_vector_is_close_for_nearby_times() {
+        let tz = FixedOffset::east(3600);
+        // 2024-01-01 was a Monday.
+        let monday_morning = TimeVector::from_local_time(&tz.ymd(2024, 1, 1).and_hms(8, 0, 0));
+        let tuesday_morning = TimeVector::from_local_time(&tz.ymd(2024, 1, 2).and_hms(8, 30, 0));
+        let sunday_night = TimeVector::from_local_time(&tz.ymd(2024, 1, 7).and_hms(23, 30, 0));
+        let monday_midnight = TimeVector::from_local_time(&tz.ymd(2024, 1, 8).and_hms(0, 0, 0));
+        let friday_evening = TimeVector::from_local_time(&tz.ymd(2024, 1, 5).and_hms(20, 0, 0));
+
+        assert!(monday_morning.dot(&tuesday_morning) > 1.5);
+        assert!(sunday_night.dot(&monday_midnight) > 1.9);
+        assert!(monday_morning.dot(&friday_evening) < 0.0);
+    }
+
+    #[test]
+    fn time_vector_uses_recorded_utc_offset() {
+        // 2024-07-01 06:00 UTC is 08:00 in Amsterdam summer time.
+        let tz = FixedOffset::east(7200);
+        let local = tz.ymd(2024, 7, 1).and_hms(8, 0, 0);
+        let at = Instant::from_posix_timestamp(local.timestamp());
+        let expected = TimeVector::from_local_time(&local);
+        assert_eq!(TimeVector::from_instant_at_offset(at, Some(7200)), expected);
+
+        let utc = TimeVector::from_instant_at_offset(at, Some(0));
+        assert!(utc.dot(&expected) < 1.99);
+    }
+
+    #[test]
+    fn quantized_time_vector_approximates_dot_product() {
+        let tz = FixedOffset::east(3600);
+        let times = [
+            tz.ymd(2024, 1, 1).and_hms(8, 0, 0),
+            tz.ymd(2024, 1, 3).and_hms(13, 15, 0),
+            tz.ymd(2024, 1, 5).and_hms(20, 0, 0),
+            tz.ymd(2024, 1, 7).and_hms(23, 30, 0),
+        ];
+        for t0 in &times {
+            for t1 in &times {
+                let v0 = TimeVector::from_local_time(t0);
+                let v1 = TimeVector::from_local_time(t1);
+                let q0 = QuantizedTimeVector::from_time_vector(&v0);
+                let q1 = QuantizedTimeVector::from_time_vector(&v1);
+                let expected = v0.dot(&v1);
+                let actual = q0.dot(&q1) as f32 / (127.0 * 127.0);
+                assert!((expected - actual).abs() < 0.02, "{} vs {}", expected, actual);
+            }
+        }
+    }
+
+    #[test]
+    fn quantized_time_vector_shrinks_few_listens() {
+        let tz = FixedOffset::east(3600);
+        let v = TimeVector::from_local_time(&tz.ymd(2024, 1, 1).and_hms(8, 0, 0));
+        let mut sum = TimeVector::default();
+        for _ in 0..10 {
+            sum.add(&v);
+        }
+        let now = QuantizedTimeVector::from_time_vector(&v);
+        let once = QuantizedTimeVector::from_listens(&v, 1);
+        let often = QuantizedTimeVector::from_listens(&sum, 10);
+        assert!(once.dot(&now) > 0);
+        assert!(often.dot(&now) > 2 * once.dot(&now));
+    }
+
+    #[test]
+    fn co_listens_pairs_artists_in_the_same_window() {
+        let mut co = CoListens::default();
+        let (a, b, c, d, e, f) = (ArtistId(1), ArtistId(2), ArtistId(3), ArtistId(4), ArtistId(5), ArtistId(6));
+        for _ in 0..5 {
+            // Relistening to an artist that is still in the window does not
+            // count as a new pair. The window holds four artists, so `a` and
+            // `f` are never together in it.
+            for artist_id in [a, b, a, c, d, e, f] {
+                co.observe(artist_id);
+            }
+            co.end_session();
+        }
+        co.observe(b);
+        co.observe(e);
+
+        let similar = co.similarities();
+        let similar_a: Vec<ArtistId> = similar[&a].iter().map(|p| p.0).collect();
+        let similar_b: Vec<ArtistId> = similar[&b].iter().map(|p| p.0).collect();
+        // Artists `b` and `e` occur more often overall, so their similarity
+        // to `a` is lower than that of `c` and `d`.
+        assert_eq!(similar_a, vec![c, d, b, e]);
+        assert_eq!(similar_b, vec![e, a, c, d, f]);
+        assert!(similar[&b][0].1 < 1.0);
+    }
+
+    #[test]
+    fn plays_per_week_is_comparable_across_timescales() {
+        let config = PlaycountConfig::default();
+        let half_life_epochs = config.half_life_epochs();
+        let mut counter = ExpCounter::new();
+        for day in 1..4000 {
+            let t = Instant { seconds_since_jan_2000: day * 86400 };
+            counter.increment(&PlayCounter::LIMIT_TRACK, &half_life_epochs, t);
+        }
+        // We skip the 10-year timescale, 4000 days is not long enough for
+        // that one to converge.
+        let r = config.plays_per_week(&counter);
+        for plays in &r[1..] {
+            assert!((plays - 7.0).abs() < 0.7, "Expected about 7 plays per week, got {:?}.", r);
+        }
+    }
+
+    #[test]
+    fn write_csv_field_quotes_only_when_needed() {
+        let field = |value: &str| {
+            let mut out = Vec::new();
+            write_csv_field(&mut out, value).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(field("Blue Train"), "Blue Train");
+        assert_eq!(field("Crosby, Stills & Nash"), r#""Crosby, Stills & Nash""#);
+        assert_eq!(field(r#"12" Mix"#), r#""12"" Mix""#);
+    }
+}
@@ -7,6 +7,7 @@
 
 //! Ensures that the right samples are queued for playback.
 
+use std::collections::VecDeque;
 use std::fmt;
 use std::fs;
 use std::mem;
@@ -15,23 +16,31 @@ use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::thread;
+use std::time::Duration;
 
 use claxon;
 use claxon::metadata::StreamInfo;
+use log::{debug, error};
 
 use crate::config::Config;
+use crate::database_utils;
+use crate::discord::Presence;
 use crate::error::Error;
 use crate::exec_pre_post;
 use crate::filter::StateVariableFilter;
 use crate::history::PlaybackEvent;
 use crate::playcount::PlayCounter;
 use crate::history;
+use crate::matcher::ImportSource;
 use crate::mvar::Var;
 use crate::playback;
+use crate::playlist::PlaylistEdit;
 use crate::prim::Hertz;
-use crate::shuffle;
-use crate::user_data::{Rating, UserData};
-use crate::{AlbumId, Lufs, MetaIndex, MemoryMetaIndex, TrackId};
+use crate::radio;
+use crate::runtime_stats::{RuntimeCounts, RuntimeStats};
+use crate::shuffle::{self, ShuffleConfig};
+use crate::user_data::{LabelTarget, Rating, UserData};
+use crate::{AlbumId, ArtistId, Lufs, MetaIndex, MemoryMetaIndex, TrackId};
 
 type FlacReader = claxon::FlacReader<fs::File>;
 
@@ -366,15 +375,16 @@ impl DecodeTask {
     pub fn run(
         self,
         index: &dyn MetaIndex,
+        stats: &RuntimeStats,
         filters: &mut Filters,
         stop_after_bytes: usize,
     ) -> DecodeResult {
         match self {
             DecodeTask::Continue(qid, reader) => {
-                DecodeTask::decode(qid, reader, filters, stop_after_bytes)
+                DecodeTask::decode(qid, reader, stats, filters, stop_after_bytes)
             }
             DecodeTask::Start(qid, track_id) => {
-                DecodeTask::start(index, qid, track_id, filters, stop_after_bytes)
+                DecodeTask::start(index, qid, track_id, stats, filters, stop_after_bytes)
             }
         }
     }
@@ -383,6 +393,7 @@ impl DecodeTask {
         index: &dyn MetaIndex,
         queue_id: QueueId,
         track_id: TrackId,
+        stats: &RuntimeStats,
         filters: &mut Filters,
         stop_after_bytes: usize,
     ) -> DecodeResult {
@@ -391,13 +402,13 @@ impl DecodeTask {
             None => panic!("Track {} does not exist, how did it end up queued?", track_id),
         };
         let fname = index.get_filename(track.filename);
-        // TODO: Add a proper way to do logging.
-        println!("Opening {:?} for decode.", fname);
+        debug!("Opening {:?} for decode.", fname);
 
         let reader = match open_with_readahead(fname) {
             Ok(r) => r,
             Err(err) => {
-                println!("Error in {:?}: {:?}", fname, err);
+                error!("Error in {:?}: {:?}", fname, err);
+                stats.count_skipped_file();
                 return DecodeResult {
                     queue_id: queue_id,
                     block: Block::new(Format::default(), Vec::new()),
@@ -406,14 +417,20 @@ impl DecodeTask {
             }
         };
 
-        DecodeTask::decode(queue_id, reader, filters, stop_after_bytes)
+        DecodeTask::decode(queue_id, reader, stats, filters, stop_after_bytes)
     }
 
-    fn decode(queue_id: QueueId, reader: FlacReader, filters: &mut Filters, stop_after_bytes: usize) -> DecodeResult {
+    fn decode(
+        queue_id: QueueId,
+        reader: FlacReader,
+        stats: &RuntimeStats,
+        filters: &mut Filters,
+        stop_after_bytes: usize,
+    ) -> DecodeResult {
         let streaminfo = reader.streaminfo();
         match streaminfo.bits_per_sample {
-            16 => DecodeTask::decode_i16(queue_id, reader, streaminfo, filters, stop_after_bytes),
-            24 => DecodeTask::decode_i24(queue_id, reader, streaminfo, filters, stop_after_bytes),
+            16 => DecodeTask::decode_i16(queue_id, reader, streaminfo, stats, filters, stop_after_bytes),
+            24 => DecodeTask::decode_i24(queue_id, reader, streaminfo, stats, filters, stop_after_bytes),
             n  => panic!("Unsupported bit depth: {}", n),
         }
     }
@@ -422,6 +439,7 @@ impl DecodeTask {
         queue_id: QueueId,
         mut reader: FlacReader,
         streaminfo: StreamInfo,
+        stats: &RuntimeStats,
         filters: &mut Filters,
         stop_after_bytes: usize,
     ) -> DecodeResult {
@@ -454,7 +472,14 @@ impl DecodeTask {
                         break
                     }
                     Ok(Some(b)) => b,
-                    Err(err) => panic!("TODO: Handle decode error: {:?}", err),
+                    Err(err) => {
+                        // Play what we decoded so far, and treat the rest of
+                        // the file as missing, rather than crashing the decoder.
+                        error!("Error while decoding queue entry {}: {:?}", queue_id, err);
+                        stats.count_decode_error();
+                        is_done = true;
+                        break
+                    }
                 };
 
                 for (l, r) in frame.stereo_samples() {
@@ -488,6 +513,7 @@ impl DecodeTask {
         queue_id: QueueId,
         mut reader: FlacReader,
         streaminfo: StreamInfo,
+        stats: &RuntimeStats,
         filters: &mut Filters,
         stop_after_bytes: usize,
     ) -> DecodeResult {
@@ -520,7 +546,14 @@ impl DecodeTask {
                         break
                     }
                     Ok(Some(b)) => b,
-                    Err(err) => panic!("TODO: Handle decode error: {:?}", err),
+                    Err(err) => {
+                        // Play what we decoded so far, and treat the rest of
+                        // the file as missing, rather than crashing the decoder.
+                        error!("Error while decoding queue entry {}: {:?}", queue_id, err);
+                        stats.count_decode_error();
+                        is_done = true;
+                        break
+                    }
                 };
 
                 for (l, r) in frame.stereo_samples() {
@@ -603,6 +636,22 @@ pub struct PlayerState {
 
     /// Random number generator used for shuffling.
     rng: shuffle::Prng,
+
+    /// In radio mode, the minimum number of tracks to keep in the queue.
+    ///
+    /// When the queue drops below this length, the history thread enqueues
+    /// tracks similar to the recently played ones. `None` when radio mode is
+    /// disabled.
+    radio_queue_len: Option<usize>,
+
+    /// The most recently completed or skipped tracks, most recent last.
+    ///
+    /// These serve as the seeds for selecting tracks in radio mode, which
+    /// does not pick them again.
+    recently_played: VecDeque<TrackId>,
+
+    /// The track that started playing most recently, also if it was skipped.
+    last_started: Option<TrackId>,
 }
 
 
@@ -616,6 +665,9 @@ impl PlayerState {
             queue: Vec::new(),
             events: events,
             rng: shuffle::Prng::new(),
+            radio_queue_len: None,
+            recently_played: VecDeque::new(),
+            last_started: None,
         }
     }
 
@@ -673,6 +725,16 @@ impl PlayerState {
         self.queue.is_empty()
     }
 
+    /// Return the track at the front of the queue, which is playing or about to.
+    pub fn current_track_id(&self) -> Option<TrackId> {
+        self.queue.first().map(|qt| qt.track_id)
+    }
+
+    /// Return the track that started playing most recently, if any.
+    pub fn last_started_track_id(&self) -> Option<TrackId> {
+        self.last_started
+    }
+
     /// Return the desired playback volume relative to full scale.
     ///
     /// This applies loudness normalization on top of the player target volume,
@@ -723,6 +785,21 @@ impl PlayerState {
         self.queue.push(track);
     }
 
+    /// Enqueue the track with the given id at the end of the queue, return its queue id.
+    fn enqueue_track(&mut self, index: &MemoryMetaIndex, track_id: TrackId) -> QueueId {
+        let album_id = track_id.album_id();
+        let track = index.get_track(track_id).expect("Can only enqueue existing tracks.");
+        let album = index.get_album(album_id).expect("Track must belong to album.");
+        let track_loudness = track.loudness.unwrap_or_default();
+        let album_loudness = album.loudness.unwrap_or_default();
+
+        let id = self.next_unused_id;
+        self.next_unused_id = QueueId(id.0 + 1);
+        let qt = QueuedTrack::new(id, track_id, track_loudness, album_loudness);
+        self.enqueue(qt);
+        id
+    }
+
     /// Dequeue the track, if it exists and is not currently playing.
     pub fn dequeue(&mut self, queue_id: QueueId) {
         match self.queue.iter().position(|qt| qt.queue_id == queue_id) {
@@ -735,7 +812,9 @@ impl PlayerState {
     }
 
     /// Shuffle the queue.
-    pub fn shuffle(&mut self, index: &MemoryMetaIndex) {
+    ///
+    /// With a `seed`, the outcome depends only on the seed and the queue.
+    pub fn shuffle(&mut self, index: &MemoryMetaIndex, config: &ShuffleConfig, seed: Option<u64>) {
         if self.queue.len() < 3 {
             // The track at index 0 is being played, we cannot move it, and then
             // we need at least 2 more tracks to be able to shuffle anything at
@@ -743,10 +822,37 @@ impl PlayerState {
             return;
         }
 
+        let mut seeded_rng = seed.map(shuffle::Prng::new_seed);
+        let rng = seeded_rng.as_mut().unwrap_or(&mut self.rng);
         let tracks = &mut self.queue[1..];
-        shuffle::shuffle(index, &mut self.rng, tracks);
+        shuffle::shuffle(index, rng, tracks, config);
+        self.restore_decode_order();
+    }
+
+    /// Shuffle the order of the albums in the queue, see [`shuffle::shuffle_albums`].
+    pub fn shuffle_albums(
+        &mut self,
+        index: &MemoryMetaIndex,
+        tracks_per_album: Option<usize>,
+        seed: Option<u64>,
+    ) {
+        if self.queue.len() < 2 {
+            // The track at index 0 is being played, we cannot move it, so
+            // there is nothing to shuffle.
+            return;
+        }
+
+        let mut seeded_rng = seed.map(shuffle::Prng::new_seed);
+        let rng = seeded_rng.as_mut().unwrap_or(&mut self.rng);
+        let mut tracks = self.queue.split_off(1);
+        shuffle::shuffle_albums(index, rng, &mut tracks, tracks_per_album);
+        self.queue.extend(tracks);
+        self.restore_decode_order();
+    }
 
-        // After the shuffle, the invariant that decoded samples are at the
+    /// Restore the invariant that decoded samples are at the front of the queue.
+    fn restore_decode_order(&mut self) {
+        // After a shuffle, the invariant that decoded samples are at the
         // front of the queue may be violated, so we need to restore that.
         let mut should_clear = false;
         for queued_track in self.queue.iter_mut() {
@@ -778,6 +884,53 @@ impl PlayerState {
         self.queue.truncate(1);
     }
 
+    /// Skip the currently playing track, remove it from the queue.
+    ///
+    /// If the track played long enough to count as a listen (half of its
+    /// duration, or four minutes, the same rule that Last.fm uses for
+    /// scrobbling), then it counts as completed, otherwise as skipped.
+    pub fn skip(&mut self, index: &MemoryMetaIndex) {
+        if self.queue.is_empty() {
+            return
+        }
+
+        let track = self.queue.remove(0);
+
+        // If we did not play anything of the track yet, then we also did not
+        // report that it started, so there is nothing to record.
+        if track.samples_played > 0 {
+            let duration_ms = index
+                .get_track(track.track_id)
+                .map(|t| t.duration_seconds as u64 * 1000)
+                .unwrap_or(0);
+            let position_ms = track.position_ms();
+            let is_listen = position_ms * 2 >= duration_ms || position_ms >= 240_000;
+            let event = match is_listen {
+                true => PlaybackEvent::Completed(track.queue_id, track.track_id),
+                false => PlaybackEvent::Skipped(track.queue_id, track.track_id),
+            };
+            self.events.send(event).expect("Failed to send skip event to history thread.");
+        }
+
+        // Also remember skipped tracks, so radio mode does not pick a track
+        // that we just skipped.
+        self.remember_played(track.track_id);
+
+        let previous_album = track.album_id();
+        self.update_current_track_loudness(previous_album);
+
+        #[cfg(debug)]
+        self.assert_invariants();
+    }
+
+    fn remember_played(&mut self, track_id: TrackId) {
+        // Remember enough tracks to cover roughly an album or two.
+        if self.recently_played.len() == 25 {
+            self.recently_played.pop_front();
+        }
+        self.recently_played.push_back(track_id);
+    }
+
     /// Consume n samples from the peeked block.
     pub fn consume(&mut self, n: usize) {
         assert!(n > 0, "Must consume at least one sample.");
@@ -791,6 +944,7 @@ impl PlayerState {
                 self.events.send(
                     PlaybackEvent::Started(queued_track.queue_id, queued_track.track_id)
                 ).expect("Failed to send completion event to history thread.");
+                self.last_started = Some(queued_track.track_id);
             }
 
             queued_track.samples_played += n as u64;
@@ -814,6 +968,8 @@ impl PlayerState {
             self.events.send(PlaybackEvent::Completed(track.queue_id, track.track_id))
                 .expect("Failed to send completion event to history thread.");
 
+            self.remember_played(track.track_id);
+
             let previous_album = track.album_id();
             self.update_current_track_loudness(previous_album);
         }
@@ -861,7 +1017,18 @@ impl PlayerState {
         let min_buffer_ms = 30_000;
 
         let is_buffer_low = self.pending_duration_ms() < min_buffer_ms;
-        is_buffer_low && self.can_decode()
+        is_buffer_low && (self.can_decode() || self.needs_radio_refill())
+    }
+
+    /// Return whether radio mode is enabled and the queue is below the desired length.
+    ///
+    /// Refilling is done by the decode thread, so when this is true, we need to
+    /// wake it even if there is nothing to decode at the moment.
+    fn needs_radio_refill(&self) -> bool {
+        match self.radio_queue_len {
+            Some(len) => self.queue.len() < len,
+            None => false,
+        }
     }
 
     /// Return a decode task, if there is something to decode.
@@ -944,7 +1111,12 @@ impl PlayerState {
 }
 
 /// Decode the queue until we reach a set memory limit.
-fn decode_burst(index: &MemoryMetaIndex, state_mutex: &Mutex<PlayerState>, filters: &mut Filters) {
+fn decode_burst(
+    index: &MemoryMetaIndex,
+    stats: &RuntimeStats,
+    state_mutex: &Mutex<PlayerState>,
+    filters: &mut Filters,
+) {
     // The decode thread is a trade-off between power consumption and memory
     // usage: decoding a lot in one go and then sleeping for a long time is more
     // efficient than decoding a bit all the time, because the CPU can be
@@ -972,7 +1144,7 @@ fn decode_burst(index: &MemoryMetaIndex, state_mutex: &Mutex<PlayerState>, filte
 
             let bytes_used = state.pending_size_bytes();
             if bytes_used >= stop_after_bytes {
-                println!("Buffer full, stopping decode for now.");
+                debug!("Buffer full, stopping decode for now.");
                 return
             }
 
@@ -1002,8 +1174,8 @@ fn decode_burst(index: &MemoryMetaIndex, state_mutex: &Mutex<PlayerState>, filte
         // to decode as much, because most of the memory is taken up by
         // already-played samples in a large block where the playhead is at the
         // end of the block.
-        let result = task.run(index, filters, bytes_left.min(10_000_000));
-        println!(
+        let result = task.run(index, stats, filters, bytes_left.min(10_000_000));
+        debug!(
             "Buffer: duration={:.3}s, memory={:.3}/{:.3} MB, budget={:.3} MB, decoded={:.3} MB",
             pending_duration_ms as f32 / 1000.0,
             bytes_used as f32 * 1e-6,
@@ -1020,14 +1192,74 @@ fn decode_burst(index: &MemoryMetaIndex, state_mutex: &Mutex<PlayerState>, filte
 /// Decodes until the in-memory buffer is full, then parks itself. When
 /// unparked, if the buffer is running low, it starts a new burst of decode and
 /// then parks itself again, etc.
+/// In radio mode, top up the queue with tracks similar to the recent ones.
+///
+/// Returns whether the queue was empty before, in which case the playback
+/// thread may be parked, and the caller should wake it.
+fn refill_radio(
+    index: &MemoryMetaIndex,
+    user_data: &Mutex<UserData>,
+    state_mutex: &Mutex<PlayerState>,
+    favorite_boost: f32,
+) -> bool {
+    let (seeds, n) = {
+        let state = state_mutex.lock().unwrap();
+        match state.radio_queue_len {
+            Some(len) if state.needs_radio_refill() => {
+                let seeds: Vec<TrackId> = state
+                    .recently_played
+                    .iter()
+                    .cloned()
+                    .chain(state.queue.iter().map(|qt| qt.track_id))
+                    .collect();
+                (seeds, len - state.queue.len())
+            }
+            _ => return false,
+        }
+    };
+
+    // Selecting tracks involves scanning through albums, don't hold the player
+    // lock while we do that.
+    let tracks = {
+        let user_data = user_data.lock().unwrap();
+        let mut rng = shuffle::Prng::new();
+        radio::select_tracks(index, &user_data, &mut rng, &seeds, n, favorite_boost)
+    };
+
+    let mut state = state_mutex.lock().unwrap();
+    let was_empty = state.is_queue_empty();
+
+    // In the meantime radio mode may have been disabled, or something else may
+    // have been enqueued, so check again how many tracks we need.
+    let n = match state.radio_queue_len {
+        Some(len) => len.saturating_sub(state.queue.len()),
+        None => 0,
+    };
+    for track_id in tracks.into_iter().take(n) {
+        state.enqueue_track(index, track_id);
+    }
+
+    was_empty && !state.is_queue_empty()
+}
+
 fn decode_main(
     index: Var<MemoryMetaIndex>,
+    user_data: &Mutex<UserData>,
     state_mutex: &Mutex<PlayerState>,
+    stats: &RuntimeStats,
     high_pass_cutoff: Hertz,
+    config_var: &Var<Config>,
 ) {
     let mut filters = Filters::new(high_pass_cutoff);
 
     loop {
+        // The playback thread wakes us when it is about to run out of decoded
+        // samples, which is a good moment to top up the queue in radio mode.
+        // The playback thread is not parked at this point, so we don't have to
+        // wake it.
+        let favorite_boost = config_var.get().favorite_artist_boost;
+        refill_radio(&index.get(), user_data, state_mutex, favorite_boost);
+
         let should_decode = {
             let state = state_mutex.lock().unwrap();
             state.needs_decode()
@@ -1036,12 +1268,102 @@ fn decode_main(
 
         if should_decode {
             let current_index = index.get();
-            decode_burst(&current_index, state_mutex, &mut filters);
+            decode_burst(&current_index, stats, state_mutex, &mut filters);
         }
 
-        println!("Decoder going to sleep.");
+        debug!("Decoder going to sleep.");
         thread::park();
-        println!("Decoder woken up.");
+        debug!("Decoder woken up.");
+    }
+}
+
+/// Run the history thread, and replace it with a new one when it fails.
+///
+/// The history thread fails when it cannot use the database, which can be
+/// transient, for example when the database is locked for too long. Rather
+/// than stopping playback, we log the failure, and start a new thread, which
+/// re-opens the database and retries the event that failed. We retry only when
+/// the database was busy or locked, and only a few times. Other errors would
+/// fail again, and block all later events, so then we drop the event. Returns
+/// when the history thread exits for a different reason.
+fn supervise_history(
+    config_var: Var<Config>,
+    index_var: Var<MemoryMetaIndex>,
+    user_data: Arc<Mutex<UserData>>,
+    counter: PlayCounter,
+    events: mpsc::Receiver<PlaybackEvent>,
+    stats: Arc<RuntimeStats>,
+) {
+    let min_backoff = Duration::from_secs(1);
+    let max_backoff = Duration::from_secs(60);
+    let max_attempts = 5;
+    let mut backoff = min_backoff;
+    let mut counter = counter;
+    let mut queue = history::EventQueue::new(events);
+
+    // The presence thread outlives restarts of the history thread, it does
+    // not depend on the database.
+    let presence = {
+        let config = config_var.get();
+        config.discord_client_id.clone().map(|client_id| Presence::new(
+            client_id,
+            config.discord_public_url.clone(),
+            index_var.clone(),
+        ))
+    };
+
+    loop {
+        let config_for_history = config_var.clone();
+        let index_for_history = index_var.clone();
+        let user_data_for_history = user_data.clone();
+        let stats_for_history = stats.clone();
+        let presence_for_history = presence.clone();
+        let started_at = std::time::Instant::now();
+        let history_thread = std::thread::Builder::new()
+            .name("history".into())
+            .spawn(move || history::main(
+                config_for_history,
+                index_for_history,
+                user_data_for_history,
+                counter,
+                queue,
+                &stats_for_history,
+                presence_for_history.as_ref(),
+            )).unwrap();
+
+        let (mut failed_queue, err) = match history_thread.join() {
+            Ok((_, Ok(()))) => return,
+            Ok((queue, Err(err))) => (queue, err),
+            // A panic means a bug rather than a transient problem, and we
+            // lost the queue with it, so there is nothing to restart.
+            Err(_) => return,
+        };
+
+        // If the thread ran fine for a while, this is a new problem, and we
+        // can retry quickly again.
+        if started_at.elapsed() > max_backoff {
+            backoff = min_backoff;
+        }
+        error!("History thread failed, restarting in {:?}: {:?}", backoff, err);
+        stats.count_history_restart();
+
+        let is_retryable = database_utils::is_busy_or_locked(&err)
+            && failed_queue.pending_attempts() < max_attempts;
+        if !is_retryable {
+            if let Some(event) = failed_queue.drop_pending() {
+                error!("Dropping playback event {:?} after failing to handle it.", event);
+                stats.count_history_dropped_event();
+            }
+        }
+
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(max_backoff);
+
+        // The counter may be in any state after the failure. A new counter
+        // counts all listens from scratch at the next update, until then the
+        // scores in the user data remain as they were.
+        counter = PlayCounter::new(config_var.get().playcount.clone());
+        queue = failed_queue;
     }
 }
 
@@ -1052,6 +1374,8 @@ pub struct Player {
     history_thread: JoinHandle<()>,
     exec_pre_post_thread: JoinHandle<()>,
     events: SyncSender<PlaybackEvent>,
+    config_var: Var<Config>,
+    stats: Arc<RuntimeStats>,
 }
 
 pub struct TrackSnapshot {
@@ -1086,8 +1410,12 @@ impl Player {
         index_var: Var<MemoryMetaIndex>,
         user_data: Arc<Mutex<UserData>>,
         counter: PlayCounter,
-        config: &Config,
+        config_var: Var<Config>,
     ) -> Player {
+        // Settings that can be reloaded at runtime are read from `config_var`
+        // when they are used, the other ones only here.
+        let config = config_var.get();
+
         // Build the channel to send playback events to the history thread. That
         // thread is expected to process them immediately and be idle most of
         // the time, so pick a small channel size.
@@ -1097,27 +1425,35 @@ impl Player {
         let (queue_events_sender, queue_events_receiver) = mpsc::sync_channel(5);
 
         let state = Arc::new(Mutex::new(PlayerState::new(hist_sender.clone())));
+        let stats = Arc::new(RuntimeStats::new());
 
         // Start the decode thread. It runs indefinitely, but we do need to
         // periodically unpark it when there is new stuff to decode.
         let state_mutex_for_decode = state.clone();
         let index_for_decode = index_var.clone();
+        let user_data_for_decode = user_data.clone();
         let high_pass_cutoff = config.high_pass_cutoff;
+        let config_for_decode = config_var.clone();
+        let stats_for_decode = stats.clone();
         let builder = std::thread::Builder::new();
         let decode_join_handle = builder
             .name("decoder".into())
             .spawn(move || {
                 decode_main(
                     index_for_decode,
+                    &user_data_for_decode,
                     &state_mutex_for_decode,
+                    &stats_for_decode,
                     high_pass_cutoff,
+                    &config_for_decode,
                 );
             }).unwrap();
 
         let state_mutex_for_playback = state.clone();
         let decode_thread_for_playback = decode_join_handle.thread().clone();
-        let config_for_playback = config.clone();
+        let config_for_playback = config.as_ref().clone();
         let hist_sender_for_playback = hist_sender.clone();
+        let stats_for_playback = stats.clone();
 
         let builder = std::thread::Builder::new();
         let playback_join_handle = builder
@@ -1129,35 +1465,40 @@ impl Player {
                     &decode_thread_for_playback,
                     queue_events_sender,
                     hist_sender_for_playback,
+                    &stats_for_playback,
                 );
             }).unwrap();
 
         let builder = std::thread::Builder::new();
-        let index_for_history = index_var;
+        let index_for_history = index_var.clone();
 
-        let db_path = config.db_path.clone();
+        let config_for_history = config_var.clone();
+        let stats_for_history = stats.clone();
         let history_join_handle = builder
-            .name("history".into())
+            .name("history_supervisor".into())
             .spawn(move || {
-                let result = history::main(
-                    &db_path,
+                supervise_history(
+                    config_for_history,
                     index_for_history,
                     user_data,
                     counter,
                     hist_receiver,
+                    stats_for_history,
                 );
                 // The history thread should not exit. When it does, that's a
                 // problem.
-                eprintln!("History thread exited: {:?}", result);
+                error!("History thread exited.");
                 std::process::exit(1);
             }).unwrap();
 
         let builder = std::thread::Builder::new();
-        let config_exec = config.clone();
+        let config_exec = config_var.clone();
+        let index_exec = index_var;
         let exec_pre_post_handle = builder
             .name("exec_pre_post".into())
             .spawn(move || exec_pre_post::main(
                 &config_exec,
+                &index_exec,
                 queue_events_receiver,
             )).unwrap();
 
@@ -1168,6 +1509,8 @@ impl Player {
             history_thread: history_join_handle,
             exec_pre_post_thread: exec_pre_post_handle,
             events: hist_sender,
+            config_var: config_var,
+            stats: stats,
         }
     }
 
@@ -1186,23 +1529,59 @@ impl Player {
         self.events.send(PlaybackEvent::Rated { track_id, rating }).unwrap();
     }
 
+    /// Send an album rating to the history thread for saving to the database.
+    pub fn set_album_rating(&self, album_id: AlbumId, rating: Rating) {
+        self.events.send(PlaybackEvent::AlbumRated { album_id, rating }).unwrap();
+    }
+
+    /// Send a change of the favorite flag of an artist to the history thread.
+    pub fn set_artist_favorite(&self, artist_id: ArtistId, favorite: bool) {
+        self.events.send(PlaybackEvent::ArtistFavorited { artist_id, favorite }).unwrap();
+    }
+
+    /// Send the addition or removal of a label to the history thread.
+    pub fn set_label(&self, label: String, target: LabelTarget, labeled: bool) {
+        self.events.send(PlaybackEvent::Labeled { label, target, labeled }).unwrap();
+    }
+
+    /// Send a new note for the track to the history thread, `None` removes it.
+    pub fn set_track_note(&self, track_id: TrackId, note: Option<String>) {
+        self.events.send(PlaybackEvent::TrackNoteSet { track_id, note }).unwrap();
+    }
+
+    /// Send a new note for the album to the history thread, `None` removes it.
+    pub fn set_album_note(&self, album_id: AlbumId, note: Option<String>) {
+        self.events.send(PlaybackEvent::AlbumNoteSet { album_id, note }).unwrap();
+    }
+
+    /// Send an edit of the manual playlist to the history thread.
+    pub fn edit_playlist(&self, name: String, edit: PlaylistEdit) {
+        self.events.send(PlaybackEvent::PlaylistEdited { name, edit }).unwrap();
+    }
+
+    /// Send a listen deletion to the history thread.
+    pub fn delete_listen(&self, listen_id: i64) {
+        self.events.send(PlaybackEvent::ListenDeleted(listen_id)).unwrap();
+    }
+
+    /// Send a listen correction to the history thread.
+    pub fn correct_listen(&self, listen_id: i64, track_id: TrackId) {
+        self.events.send(PlaybackEvent::ListenCorrected { listen_id, track_id }).unwrap();
+    }
+
+    /// Send the decision for an unresolved imported listen to the history thread.
+    pub fn resolve_match(&self, source: ImportSource, started_at: i64, track_id: Option<TrackId>) {
+        self.events.send(PlaybackEvent::MatchResolved { source, started_at, track_id }).unwrap();
+    }
+
     /// Enqueue the track for playback at the end of the queue.
     pub fn enqueue(&self, index: &MemoryMetaIndex, track_id: TrackId) -> QueueId {
-        let album_id = track_id.album_id();
-        let track = index.get_track(track_id).expect("Can only enqueue existing tracks.");
-        let album = index.get_album(album_id).expect("Track must belong to album.");
-        let track_loudness = track.loudness.unwrap_or_default();
-        let album_loudness = album.loudness.unwrap_or_default();
-
         // If the queue is empty, then the playback thread may be parked,
         // so we may need to wake it after enqueuing something.
         let (queue_id, needs_wake) = {
             let mut state = self.state.lock().unwrap();
             let needs_wake = state.is_queue_empty();
-            let id = state.next_unused_id;
-            state.next_unused_id = QueueId(id.0 + 1);
-            let qt = QueuedTrack::new(id, track_id, track_loudness, album_loudness);
-            state.enqueue(qt);
+            let id = state.enqueue_track(index, track_id);
             (id, needs_wake)
         };
 
@@ -1213,6 +1592,26 @@ impl Player {
         queue_id
     }
 
+    /// Enqueue the tracks for playback at the end of the queue, in order.
+    ///
+    /// Unlike calling [`Player::enqueue`] for every track, this takes the lock
+    /// once, so the tracks end up in the queue together, even when the radio
+    /// refills the queue in the meantime.
+    pub fn enqueue_all(&self, index: &MemoryMetaIndex, track_ids: &[TrackId]) {
+        let needs_wake = {
+            let mut state = self.state.lock().unwrap();
+            let needs_wake = state.is_queue_empty();
+            for &track_id in track_ids {
+                state.enqueue_track(index, track_id);
+            }
+            needs_wake && !track_ids.is_empty()
+        };
+
+        if needs_wake {
+            self.playback_thread.thread().unpark();
+        }
+    }
+
     /// Enqueue the track for playback at the end of the queue.
     pub fn dequeue(&self, queue_id: QueueId) {
         self.state.lock().unwrap().dequeue(queue_id);
@@ -1240,8 +1639,8 @@ impl Player {
     }
 
     /// Shuffle the queue.
-    pub fn shuffle(&self, index: &MemoryMetaIndex) {
-        self.state.lock().unwrap().shuffle(index);
+    pub fn shuffle(&self, index: &MemoryMetaIndex, config: &ShuffleConfig, seed: Option<u64>) {
+        self.state.lock().unwrap().shuffle(index, config, seed);
 
         // After a shuffle, a new track may be following the current one, so
         // even if decoding was caught up before the shuffle, after the shuffle
@@ -1249,11 +1648,90 @@ impl Player {
         self.decode_thread.thread().unpark();
     }
 
+    /// Shuffle the order of albums in the queue, keep their tracks together.
+    pub fn shuffle_albums(&self, index: &MemoryMetaIndex, tracks_per_album: Option<usize>, seed: Option<u64>) {
+        self.state.lock().unwrap().shuffle_albums(index, tracks_per_album, seed);
+
+        // Like after a shuffle, we may need to start decoding right now.
+        self.decode_thread.thread().unpark();
+    }
+
     /// Shuffle the queue.
     pub fn clear_queue(&self) {
         self.state.lock().unwrap().clear_queue();
     }
 
+    /// Replace the queue after the currently playing track with the tracks.
+    pub fn replace_queue(&self, index: &MemoryMetaIndex, track_ids: &[TrackId]) {
+        let needs_wake = {
+            let mut state = self.state.lock().unwrap();
+            state.clear_queue();
+            let needs_wake = state.is_queue_empty();
+            for &track_id in track_ids {
+                state.enqueue_track(index, track_id);
+            }
+            needs_wake && !track_ids.is_empty()
+        };
+
+        if needs_wake {
+            self.playback_thread.thread().unpark();
+        }
+
+        // Like after a shuffle, the track after the current one changed, so
+        // we may need to start decoding right now.
+        self.decode_thread.thread().unpark();
+    }
+
+    /// Send the current queue to the history thread to save under the name.
+    pub fn save_queue(&self, name: String) {
+        let tracks = self.get_queue().tracks.iter().map(|t| t.track_id).collect();
+        self.events.send(PlaybackEvent::QueueSaved { name, tracks }).unwrap();
+    }
+
+    /// Send a deletion of the saved queue to the history thread.
+    pub fn delete_saved_queue(&self, name: String) {
+        self.events.send(PlaybackEvent::SavedQueueDeleted(name)).unwrap();
+    }
+
+    /// Skip the currently playing track.
+    pub fn skip(&self, index: &MemoryMetaIndex) {
+        self.state.lock().unwrap().skip(index);
+
+        // The next track may not have been decoded yet, and even if it was,
+        // in radio mode the queue may need a refill now.
+        self.decode_thread.thread().unpark();
+    }
+
+    /// Return the minimum queue length in radio mode, or `None` if radio mode is disabled.
+    pub fn get_radio(&self) -> Option<usize> {
+        self.state.lock().unwrap().radio_queue_len
+    }
+
+    /// Enable radio mode with the given minimum queue length, or disable it.
+    ///
+    /// When enabling, this immediately fills up the queue.
+    pub fn set_radio(
+        &self,
+        index: &MemoryMetaIndex,
+        user_data: &Mutex<UserData>,
+        queue_len: Option<usize>,
+    ) {
+        self.state.lock().unwrap().radio_queue_len = queue_len;
+
+        if refill_radio(index, user_data, &self.state, self.config_var.get().favorite_artist_boost) {
+            self.playback_thread.thread().unpark();
+        }
+
+        // Even if the queue was not empty, the decoder may be parked after
+        // having decoded everything, and now there is more to decode.
+        self.decode_thread.thread().unpark();
+    }
+
+    /// Return the error counters of the decode, playback, and history threads.
+    pub fn get_runtime_counts(&self) -> RuntimeCounts {
+        self.stats.get()
+    }
+
     /// Return the current playback volume.
     pub fn get_volume(&self) -> Millibel {
         let state = self.state.lock().unwrap();
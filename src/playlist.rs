@@ -0,0 +1,250 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Playlists, named lists of tracks.
+//!
+//! There are two kinds. A smart playlist stores only its query, in the syntax
+//! of [`crate::query`], for example `genre:jazz rating:>=1 played:>90`, or an
+//! expression in the syntax of [`crate::expr`], prefixed with `where`. We
+//! evaluate the query when the playlist is requested, so the tracks follow
+//! changes to the library, the ratings, and the listening history. A manual
+//! playlist stores the tracks that the user put in it, in the order they chose.
+//! Both kinds share a namespace of names.
+
+use crate::database::{self as db, Transaction};
+use crate::expr::Expr;
+use crate::prim::TrackId;
+use crate::query::Query;
+use crate::search::SearchOptions;
+use crate::user_data::UserData;
+use crate::MetaIndex;
+
+/// What a smart playlist selects its tracks by.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Definition {
+    Query(Query),
+    Expr(Expr),
+}
+
+impl Definition {
+    /// Parse a stored definition.
+    ///
+    /// Definitions that start with the keyword `where` are expressions, all
+    /// others are search queries.
+    pub fn parse(raw: &str) -> Result<Definition, String> {
+        let raw = raw.trim_start();
+        let is_expr = match raw.strip_prefix("where") {
+            Some(rest) => rest.starts_with(|ch: char| ch.is_whitespace() || ch == '('),
+            None => false,
+        };
+        match is_expr {
+            true => Expr::parse(&raw[5..])
+                .map(Definition::Expr)
+                .map_err(|err| err.to_string()),
+            false => Query::parse(raw)
+                .map(Definition::Query)
+                .map_err(|msg| msg.to_string()),
+        }
+    }
+
+    fn matches_track(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        tx: &mut Transaction,
+        track_id: TrackId,
+    ) -> db::Result<bool> {
+        match self {
+            Definition::Query(query) => query.matches_track(index, user_data, tx, track_id),
+            Definition::Expr(expr) => expr.matches_track(index, user_data, tx, track_id),
+        }
+    }
+}
+
+/// Return the tracks that match the definition.
+///
+/// When the definition is a query with free text, the tracks are the track
+/// search results in order of descending score. Otherwise all tracks in the
+/// library are candidates, in library order. Returns at most `limit` tracks,
+/// and we stop evaluating filters once we have that many.
+pub fn evaluate(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    tx: &mut Transaction,
+    definition: &Definition,
+    limit: usize,
+) -> db::Result<Vec<TrackId>> {
+    let mut words = Vec::new();
+    let mut phrases = Vec::new();
+    if let Definition::Query(query) = definition {
+        query.words(&mut words, &mut phrases);
+    }
+
+    let candidates: Vec<TrackId> = if words.is_empty() {
+        index.get_tracks().iter().map(|t| t.track_id).collect()
+    } else {
+        let mut results = Vec::new();
+        index.search_track(&words[..], &phrases[..], SearchOptions::exact(), &mut results);
+        results.iter().map(|r| r.id).collect()
+    };
+
+    let mut result = Vec::new();
+    for track_id in candidates {
+        if result.len() == limit {
+            break
+        }
+        if definition.matches_track(index, user_data, tx, track_id)? {
+            result.push(track_id);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Return whether the name is valid for a playlist.
+///
+/// Names are used in urls, so we restrict them to lowercase ASCII letters,
+/// digits, and dashes, like the names of the mixes.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-'))
+}
+
+/// Load the definition of the smart playlist with the given name, if it exists.
+///
+/// Definitions are validated when they are saved, but the syntax can change
+/// between versions, and expressions can contain dates, so this can still fail
+/// to parse.
+pub fn load(tx: &mut Transaction, name: &str) -> db::Result<Option<Result<Definition, String>>> {
+    let raw = db::select_smart_playlist_query(tx, name)?;
+    Ok(raw.map(|q| Definition::parse(&q)))
+}
+
+/// A change to a manual playlist.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PlaylistEdit {
+    /// Create an empty playlist, if it does not exist yet.
+    Create,
+    /// Delete the playlist and its tracks.
+    Delete,
+    /// Insert the track before the entry at the given position, or at the end
+    /// if the position is `None` or past the end.
+    Insert { track_id: TrackId, position: Option<u32> },
+    /// Remove the entry at the given position.
+    Remove { position: u32 },
+    /// Move the entry at position `from` such that it ends up at position `to`.
+    Move { from: u32, to: u32 },
+}
+
+/// Apply the edit to the manual playlist with the given name.
+///
+/// Edits to playlists that do not exist, and edits with positions that are out
+/// of range, are ignored. The server accepts edits before the history thread
+/// applies them, so it cannot validate them against the database reliably.
+pub fn apply_edit(
+    tx: &mut Transaction,
+    name: &str,
+    edit: &PlaylistEdit,
+    now_str: &str,
+) -> db::Result<()> {
+    match edit {
+        PlaylistEdit::Create => return db::insert_playlist(tx, name, now_str),
+        PlaylistEdit::Delete => return db::delete_playlist(tx, name),
+        _ => {}
+    }
+
+    let playlist_id = match db::select_playlist_id(tx, name)? {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    let len = db::count_playlist_tracks(tx, playlist_id)?;
+
+    match *edit {
+        PlaylistEdit::Create | PlaylistEdit::Delete => unreachable!("Handled above."),
+        PlaylistEdit::Insert { track_id, position } => {
+            let position = position.map_or(len, |p| (p as i64).min(len));
+            db::shift_playlist_tracks(tx, playlist_id, position, 1)?;
+            db::insert_playlist_track(tx, playlist_id, position, track_id.0 as i64, now_str)?;
+        }
+        PlaylistEdit::Remove { position } => {
+            let position = position as i64;
+            if position >= len {
+                return Ok(())
+            }
+            db::delete_playlist_track(tx, playlist_id, position)?;
+            db::shift_playlist_tracks(tx, playlist_id, position + 1, -1)?;
+        }
+        PlaylistEdit::Move { from, to } => {
+            let (from, to) = (from as i64, to as i64);
+            if from >= len || to >= len {
+                return Ok(())
+            }
+            // Park the entry at -1 while we close the gap and open a new one.
+            db::update_playlist_track_position(tx, playlist_id, from, -1)?;
+            db::shift_playlist_tracks(tx, playlist_id, from + 1, -1)?;
+            db::shift_playlist_tracks(tx, playlist_id, to, 1)?;
+            db::update_playlist_track_position(tx, playlist_id, -1, to)?;
+        }
+    }
+
+    db::update_playlist_updated_at(tx, playlist_id, now_str)
+}
+
+/// Return the tracks of the manual playlist, or `None` if it does not exist.
+pub fn load_tracks(tx: &mut Transaction, name: &str) -> db::Result<Option<Vec<TrackId>>> {
+    let playlist_id = match db::select_playlist_id(tx, name)? {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    let mut tracks = Vec::new();
+    for entry in db::iter_playlist_tracks(tx, playlist_id)? {
+        tracks.push(TrackId(entry?.track_id as u64));
+    }
+    Ok(Some(tracks))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database as db;
+    use crate::prim::TrackId;
+    use super::{apply_edit, load_tracks, PlaylistEdit};
+
+    #[test]
+    fn apply_edit_keeps_positions_consecutive() {
+        let connection = sqlite::open(":memory:").unwrap();
+        let mut db = db::Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        db::ensure_schema_exists(&mut tx).unwrap();
+
+        let now = "2024-03-01T12:00:00.000Z";
+        let mut edit = |edit: PlaylistEdit| apply_edit(&mut tx, "mix", &edit, now).unwrap();
+        edit(PlaylistEdit::Create);
+        edit(PlaylistEdit::Insert { track_id: TrackId(1), position: None });
+        edit(PlaylistEdit::Insert { track_id: TrackId(2), position: None });
+        edit(PlaylistEdit::Insert { track_id: TrackId(3), position: Some(0) });
+        edit(PlaylistEdit::Insert { track_id: TrackId(4), position: Some(99) });
+        // Now we have 3, 1, 2, 4.
+        edit(PlaylistEdit::Move { from: 0, to: 2 });
+        // Now we have 1, 2, 3, 4.
+        edit(PlaylistEdit::Move { from: 3, to: 0 });
+        // Now we have 4, 1, 2, 3.
+        edit(PlaylistEdit::Remove { position: 1 });
+        edit(PlaylistEdit::Remove { position: 7 });
+
+        let tracks = load_tracks(&mut tx, "mix").unwrap().unwrap();
+        assert_eq!(tracks, vec![TrackId(4), TrackId(2), TrackId(3)]);
+        let positions: Vec<i64> = db::iter_playlist_tracks(&mut tx, 1)
+            .unwrap()
+            .map(|entry| entry.unwrap().position)
+            .collect();
+        assert_eq!(positions, vec![0, 1, 2]);
+
+        apply_edit(&mut tx, "mix", &PlaylistEdit::Delete, now).unwrap();
+        assert_eq!(load_tracks(&mut tx, "mix").unwrap(), None);
+        tx.commit().unwrap();
+    }
+}
@@ -377,12 +377,14 @@ impl fmt::Display for ArtistId {
 ///
 /// Aligned to 32 bytes (same as its size) so these do not straddle cache lines.
 #[repr(align(32))]
+#[derive(Clone)]
 pub struct TrackWithId {
     pub track_id: TrackId,
     pub track: Track,
 }
 
 /// An `(AlbumId, Album)` tuple.
+#[derive(Clone)]
 pub struct AlbumWithId {
     pub album_id: AlbumId,
     pub album: Album,
@@ -392,6 +394,7 @@ pub struct AlbumWithId {
 ///
 /// Aligned to 16 bytes (same as its size) so these do not straddle cache lines.
 #[repr(align(16))]
+#[derive(Clone)]
 pub struct ArtistWithId {
     pub artist_id: ArtistId,
     pub artist: Artist,
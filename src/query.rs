@@ -0,0 +1,476 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Parsing of search queries, and the structured filters they may contain.
+//!
+//! Apart from free text, a search query can contain filters of the form
+//! `key:value`, where the value can be quoted to include spaces, for example
+//! `year:2019`, `genre:jazz`, `artist:"bill evans"`, or `rating:>=1`. Filters
+//! narrow down the results of the free-text search, they are applied after
+//! searching. Smart playlists in [`crate::playlist`] are stored queries.
+
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::database as db;
+use crate::database::Transaction;
+use crate::prim::{AlbumId, ArtistId, TrackId};
+use crate::search::SearchResult;
+use crate::string_utils::normalize_words;
+use crate::user_data::{Rating, UserData};
+use crate::MetaIndex;
+
+/// A filter that search results must satisfy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Filter {
+    /// The original release year is in the inclusive range.
+    Year(u16, u16),
+
+    /// All of the normalized words occur in the artist name.
+    Artist(Vec<String>),
+
+    /// All of the normalized words occur in a genre tag.
+    Genre(Vec<String>),
+
+    /// The track rating is in the inclusive range.
+    Rating(i8, i8),
+
+    /// The track was last listened to less than this many days ago.
+    PlayedWithin(u32),
+
+    /// The track was not listened to in this many days, or never at all.
+    NotPlayedWithin(u32),
+
+    /// All of the normalized words occur in the track title.
+    Title(Vec<String>),
+
+    /// The track duration in seconds is in the inclusive range.
+    Duration(u16, u16),
+
+    /// The track was last listened to after this POSIX timestamp.
+    PlayedAfter(i64),
+}
+
+/// A search query, separated into free text and filters.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Query {
+    /// The query with the filters removed.
+    pub text: String,
+
+    /// Filters that all results must satisfy.
+    pub filters: Vec<Filter>,
+}
+
+/// Parse a year filter value, either a single year or a range `1990-1999`.
+fn parse_year(value: &str) -> Option<Filter> {
+    match value.split_once('-') {
+        None => {
+            let year = u16::from_str(value).ok()?;
+            Some(Filter::Year(year, year))
+        }
+        Some((from, to)) => {
+            let from = u16::from_str(from).ok()?;
+            let to = u16::from_str(to).ok()?;
+            Some(Filter::Year(from, to))
+        }
+    }
+}
+
+/// Parse a rating filter value, a rating optionally preceded by a comparison,
+/// for example `1`, `>=1`, or `<0`.
+fn parse_rating(value: &str) -> Option<Filter> {
+    let (op, n) = [">=", "<=", ">", "<"]
+        .iter()
+        .find_map(|op| value.strip_prefix(op).map(|n| (*op, n)))
+        .unwrap_or(("", value));
+    let n = i8::from_str(n).ok()?;
+    let (min, max) = match op {
+        ">=" => (n, Rating::Love as i8),
+        "<=" => (Rating::Dislike as i8, n),
+        ">" => (n.checked_add(1)?, Rating::Love as i8),
+        "<" => (Rating::Dislike as i8, n.checked_sub(1)?),
+        _ => (n, n),
+    };
+    let is_valid = |r: i8| Rating::try_from(r as i64).is_ok();
+    match min <= max && is_valid(min) && is_valid(max) {
+        true => Some(Filter::Rating(min, max)),
+        false => None,
+    }
+}
+
+/// Parse a last played filter value, `<n` or `>n` for a number of days.
+fn parse_played(value: &str) -> Option<Filter> {
+    if let Some(days) = value.strip_prefix('<') {
+        return Some(Filter::PlayedWithin(u32::from_str(days).ok()?))
+    }
+    if let Some(days) = value.strip_prefix('>') {
+        return Some(Filter::NotPlayedWithin(u32::from_str(days).ok()?))
+    }
+    None
+}
+
+/// Return the normalized words of the value, or `None` if there are none.
+fn parse_words(value: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    normalize_words(value, &mut words);
+    match words.is_empty() {
+        true => None,
+        false => Some(words),
+    }
+}
+
+impl Query {
+    /// Split a raw query string into free text and filters.
+    ///
+    /// Tokens of the form `key:value` for an unknown key are treated as free
+    /// text, so a title that contains a colon can still be found.
+    pub fn parse(raw: &str) -> Result<Query, &'static str> {
+        let mut query = Query::default();
+        let mut rest = raw.trim_start();
+
+        while !rest.is_empty() {
+            let key_len = rest
+                .find(|ch: char| ch == ':' || ch.is_whitespace() || ch == '"')
+                .unwrap_or(rest.len());
+            let key = &rest[..key_len];
+            let is_filter = rest[key_len..].starts_with(':') && matches!(key, "year" | "artist" | "genre" | "rating" | "played");
+
+            if !is_filter {
+                // Not a filter, copy the token into the free text. A quoted
+                // string counts as a single token, even if it contains spaces.
+                let token_len = match rest.strip_prefix('"') {
+                    Some(quoted) => quoted.find('"').map(|i| i + 2).unwrap_or(rest.len()),
+                    None => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+                };
+                if !query.text.is_empty() {
+                    query.text.push(' ');
+                }
+                query.text.push_str(&rest[..token_len]);
+                rest = rest[token_len..].trim_start();
+                continue
+            }
+
+            // Skip over the key and the colon, then read the value, which is
+            // either quoted, or it extends up to the next whitespace.
+            let after_key = &rest[key_len + 1..];
+            let (value, remainder) = match after_key.strip_prefix('"') {
+                Some(quoted) => match quoted.find('"') {
+                    Some(i) => (&quoted[..i], &quoted[i + 1..]),
+                    None => return Err("Unterminated quote in search filter."),
+                },
+                None => {
+                    let i = after_key.find(char::is_whitespace).unwrap_or(after_key.len());
+                    (&after_key[..i], &after_key[i..])
+                }
+            };
+
+            let filter = match key {
+                "year" => parse_year(value).ok_or("Invalid year filter, expected e.g. year:2019 or year:1990-1999.")?,
+                "artist" => Filter::Artist(parse_words(value).ok_or("Empty artist filter.")?),
+                "genre" => Filter::Genre(parse_words(value).ok_or("Empty genre filter.")?),
+                "rating" => parse_rating(value).ok_or("Invalid rating filter, expected e.g. rating:1 or rating:>=1.")?,
+                "played" => parse_played(value).ok_or("Invalid played filter, expected e.g. played:<30 or played:>90.")?,
+                _ => unreachable!("We only get here for known keys."),
+            };
+            query.filters.push(filter);
+            rest = remainder.trim_start();
+        }
+
+        Ok(query)
+    }
+
+    /// Normalize the free text into search words.
+    ///
+    /// Text in double quotes is a phrase: its words must occur adjacent and in
+    /// order. For every phrase of more than one word, pushes the range of its
+    /// words into `phrases`. A quote that is not closed extends to the end of
+    /// the query, so a phrase is already effective while typing it.
+    pub fn words(&self, words: &mut Vec<String>, phrases: &mut Vec<Range<usize>>) {
+        for (i, part) in self.text.split('"').enumerate() {
+            let begin = words.len();
+            normalize_words(part, words);
+            let is_phrase = i % 2 == 1;
+            if is_phrase && words.len() - begin > 1 {
+                phrases.push(begin..words.len());
+            }
+        }
+    }
+
+    pub fn matches_track(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        tx: &mut Transaction,
+        track_id: TrackId,
+    ) -> db::Result<bool> {
+        for filter in &self.filters {
+            if !filter.matches_track(index, user_data, tx, track_id)? {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn matches_album(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        tx: &mut Transaction,
+        album_id: AlbumId,
+    ) -> db::Result<bool> {
+        for filter in &self.filters {
+            if !filter.matches_album(index, user_data, tx, album_id)? {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn matches_artist(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        tx: &mut Transaction,
+        artist_id: ArtistId,
+    ) -> db::Result<bool> {
+        for filter in &self.filters {
+            if !filter.matches_artist(index, user_data, tx, artist_id)? {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Keep the first `limit` results for which `matches` returns true.
+///
+/// Filters can be expensive to evaluate (the genre filter needs to consult the
+/// database), so we stop evaluating once we have enough results.
+pub fn retain_first<T: Copy, F>(
+    results: &mut Vec<SearchResult<T>>,
+    limit: usize,
+    mut matches: F,
+) -> db::Result<()>
+where
+    F: FnMut(T) -> db::Result<bool>,
+{
+    let mut n_kept = 0;
+    for i in 0..results.len() {
+        if n_kept == limit {
+            break
+        }
+        if matches(results[i].id)? {
+            results.swap(n_kept, i);
+            n_kept += 1;
+        }
+    }
+    results.truncate(n_kept);
+    Ok(())
+}
+
+/// Return whether all of the `words` occur in the normalized `text`.
+fn contains_words(text: &str, words: &[String]) -> bool {
+    let mut text_words = Vec::new();
+    normalize_words(text, &mut text_words);
+    words.iter().all(|w| text_words.contains(w))
+}
+
+/// Return whether the file has a genre tag that contains all of the `words`.
+fn file_has_genre(tx: &mut Transaction, file_id: i64, words: &[String]) -> db::Result<bool> {
+    for opt_pair in db::iter_file_tags(tx, file_id)? {
+        let (field_name, value) = opt_pair?;
+        if field_name == "genre" && contains_words(&value, words) {
+            return Ok(true)
+        }
+    }
+    Ok(false)
+}
+
+/// Return whether we listened to the track after the POSIX timestamp.
+fn played_after(user_data: &UserData, track_id: TrackId, after: i64) -> bool {
+    match user_data.get_track_scores(track_id).last_listened_at {
+        Some(t) => t.to_posix_timestamp() > after,
+        None => false,
+    }
+}
+
+/// Return whether we listened to the track in the past `days` days.
+fn played_within(user_data: &UserData, track_id: TrackId, days: u32) -> bool {
+    let now = chrono::Utc::now().timestamp();
+    played_after(user_data, track_id, now - days as i64 * 24 * 3600)
+}
+
+impl Filter {
+    pub fn matches_track(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        tx: &mut Transaction,
+        track_id: TrackId,
+    ) -> db::Result<bool> {
+        let track = match index.get_track(track_id) {
+            Some(t) => t,
+            None => return Ok(false),
+        };
+        let result = match self {
+            Filter::Year(..) => return self.matches_album(index, user_data, tx, track_id.album_id()),
+            Filter::Artist(words) => {
+                contains_words(index.get_string(track.artist), words)
+                    || self.matches_album(index, user_data, tx, track_id.album_id())?
+            }
+            Filter::Genre(words) => file_has_genre(tx, track.file_id.0, words)?,
+            Filter::Rating(min, max) => {
+                let rating = user_data.get_track_rating(track_id) as i8;
+                *min <= rating && rating <= *max
+            }
+            Filter::PlayedWithin(days) => played_within(user_data, track_id, *days),
+            Filter::NotPlayedWithin(days) => !played_within(user_data, track_id, *days),
+            Filter::Title(words) => contains_words(index.get_string(track.title), words),
+            Filter::Duration(min, max) => *min <= track.duration_seconds && track.duration_seconds <= *max,
+            Filter::PlayedAfter(t) => played_after(user_data, track_id, *t),
+        };
+        Ok(result)
+    }
+
+    pub fn matches_album(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        tx: &mut Transaction,
+        album_id: AlbumId,
+    ) -> db::Result<bool> {
+        let album = match index.get_album(album_id) {
+            Some(a) => a,
+            None => return Ok(false),
+        };
+        let result = match self {
+            Filter::Year(from, to) => {
+                let year = album.original_release_date.year;
+                *from <= year && year <= *to
+            }
+            Filter::Artist(words) => contains_words(index.get_string(album.artist), words),
+            Filter::Genre(..)
+            | Filter::Rating(..)
+            | Filter::PlayedWithin(..)
+            | Filter::NotPlayedWithin(..)
+            | Filter::Title(..)
+            | Filter::Duration(..)
+            | Filter::PlayedAfter(..) => {
+                // An album matches if any of its tracks matches.
+                for track in index.get_album_tracks(album_id) {
+                    if self.matches_track(index, user_data, tx, track.track_id)? {
+                        return Ok(true)
+                    }
+                }
+                false
+            }
+        };
+        Ok(result)
+    }
+
+    pub fn matches_artist(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        tx: &mut Transaction,
+        artist_id: ArtistId,
+    ) -> db::Result<bool> {
+        match self {
+            Filter::Artist(words) => {
+                let result = match index.get_artist(artist_id) {
+                    Some(artist) => contains_words(index.get_string(artist.name), words),
+                    None => false,
+                };
+                Ok(result)
+            }
+            // For the other filters, an artist matches if any of their albums
+            // matches.
+            _ => {
+                for &(_, album_id) in index.get_albums_by_artist(artist_id) {
+                    if self.matches_album(index, user_data, tx, album_id)? {
+                        return Ok(true)
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, Query};
+
+    fn words(ws: &[&str]) -> Vec<String> {
+        ws.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_plain_text() {
+        let q = Query::parse("  dancing   queen ").unwrap();
+        assert_eq!(q.text, "dancing queen");
+        assert_eq!(q.filters, vec![]);
+    }
+
+    #[test]
+    fn parse_filters() {
+        let q = Query::parse(r#"waltz year:1961 artist:"Bill Evans" genre:jazz"#).unwrap();
+        assert_eq!(q.text, "waltz");
+        assert_eq!(q.filters, vec![
+            Filter::Year(1961, 1961),
+            Filter::Artist(words(&["bill", "evans"])),
+            Filter::Genre(words(&["jazz"])),
+        ]);
+    }
+
+    #[test]
+    fn parse_year_range() {
+        let q = Query::parse("year:1990-1999").unwrap();
+        assert_eq!(q.text, "");
+        assert_eq!(q.filters, vec![Filter::Year(1990, 1999)]);
+    }
+
+    #[test]
+    fn parse_unknown_key_is_text() {
+        let q = Query::parse(r#"re:member "a b" c"#).unwrap();
+        assert_eq!(q.text, r#"re:member "a b" c"#);
+        assert_eq!(q.filters, vec![]);
+    }
+
+    #[test]
+    fn words_returns_phrases() {
+        let mut ws = Vec::new();
+        let mut phrases = Vec::new();
+        let q = Query::parse(r#"radiohead "in rainbows" "x" "weird fishes"#).unwrap();
+        q.words(&mut ws, &mut phrases);
+        assert_eq!(ws, words(&["radiohead", "in", "rainbows", "x", "weird", "fishes"]));
+        assert_eq!(phrases, vec![1..3, 4..6]);
+    }
+
+    #[test]
+    fn parse_rating_and_played() {
+        let q = Query::parse("rating:>=1 rating:<0 rating:-1 played:>90 played:<30").unwrap();
+        assert_eq!(q.filters, vec![
+            Filter::Rating(1, 2),
+            Filter::Rating(-1, -1),
+            Filter::Rating(-1, -1),
+            Filter::NotPlayedWithin(90),
+            Filter::PlayedWithin(30),
+        ]);
+        assert!(Query::parse("rating:>2").is_err());
+        assert!(Query::parse("rating:3").is_err());
+        assert!(Query::parse("played:90").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_filters() {
+        assert!(Query::parse("year:soon").is_err());
+        assert!(Query::parse("artist:").is_err());
+        assert!(Query::parse(r#"artist:"bill evans"#).is_err());
+    }
+}
@@ -0,0 +1,343 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Selection of tracks for radio mode.
+//!
+//! In radio mode, when the queue runs low, the player enqueues tracks that are
+//! similar to what was played recently, so playback does not stop when the
+//! queued album ends. “Similar” here means: tracks on albums by the same
+//! artists as the recently played tracks, or by artists that we often listen
+//! to in the same session as those artists, favoring albums with a high
+//! playcount or by favorite artists, liked tracks, and tracks that we tend to
+//! listen to at this time of the day and week, demoting tracks that we often
+//! skip, and skipping tracks rated as disliked. When that does not yield
+//! enough tracks, we fall back to the entire library.
+//!
+//! With the same weights, we also sample from the entire library to shuffle it.
+
+use std::collections::{HashMap, HashSet};
+
+use nanorand::Rng;
+
+use crate::playcount::{QuantizedTimeVector, TimeVector};
+use crate::prim::{AlbumId, ArtistId, TrackId};
+use crate::shuffle::Prng;
+use crate::user_data::{Rating, TrackScores, UserData};
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// Relative weight of a track with the given rating.
+fn rating_weight(rating: Rating) -> f32 {
+    match rating {
+        Rating::Dislike => 0.0,
+        Rating::Neutral => 1.0,
+        Rating::Like => 2.0,
+        Rating::Love => 3.0,
+    }
+}
+
+/// Relative weight of a track that we listen to at `track_time`, at time `now`.
+///
+/// The dot product of the time vectors is between -2 and 2, so the weight is
+/// between 1/e and e. Tracks without listens have a zero vector, and weight 1.
+fn time_weight(track_time: &QuantizedTimeVector, now: &QuantizedTimeVector) -> f32 {
+    let similarity = track_time.dot(now) as f32 / (127.0 * 127.0);
+    (0.5 * similarity).exp()
+}
+
+/// Relative weight of a track, apart from the weight of its album.
+///
+/// Liked tracks weigh more, disliked tracks weigh nothing, and tracks that we
+/// skip a lot are apparently not what we want to hear, so they weigh less.
+/// This applies to both radio mode and the weighted library sample.
+fn track_weight(rating: Rating, scores: &TrackScores, now: Option<&QuantizedTimeVector>) -> f32 {
+    let mut weight = rating_weight(rating) / (1.0 + scores.skip_count);
+    if let Some(now) = now {
+        weight *= time_weight(&scores.time_vector, now);
+    }
+    weight
+}
+
+/// How to weigh candidates, next to the album affinity.
+#[derive(Copy, Clone)]
+struct CandidateWeights {
+    /// Albums by a favorite artist weigh this many times as much.
+    favorite_boost: f32,
+    /// When set, favor tracks that we tend to listen to at this time.
+    now: Option<QuantizedTimeVector>,
+}
+
+/// Push the tracks of the album as candidates, with the given album affinity.
+fn push_album_candidates(
+    index: &MemoryMetaIndex,
+    user_data: &UserData,
+    exclude: &HashSet<TrackId>,
+    album_id: AlbumId,
+    affinity: f32,
+    weights: CandidateWeights,
+    candidates: &mut Vec<(f32, TrackId)>,
+) {
+    // Albums that we listen to a lot are more likely to be good picks. Take
+    // the log, so a few heavily played albums do not crowd out everything.
+    let playcount = user_data.get_album_scores(album_id).playcount;
+    let mut album_weight = affinity * (1.0 + playcount.max(0.0).ln_1p());
+    if user_data.is_by_favorite_artist(index, album_id) {
+        album_weight *= weights.favorite_boost;
+    }
+
+    for track in index.get_album_tracks(album_id) {
+        if exclude.contains(&track.track_id) {
+            continue
+        }
+        let weight = album_weight * track_weight(
+            user_data.get_track_rating(track.track_id),
+            &user_data.get_track_scores(track.track_id),
+            weights.now.as_ref(),
+        );
+        if weight > 0.0 {
+            candidates.push((weight, track.track_id));
+        }
+    }
+}
+
+/// Remove up to `n` elements from the candidates, with probability proportional to their weight.
+fn sample_weighted(rng: &mut Prng, candidates: &mut Vec<(f32, TrackId)>, n: usize) -> Vec<TrackId> {
+    let mut result = Vec::with_capacity(n);
+
+    while result.len() < n && !candidates.is_empty() {
+        let total: f32 = candidates.iter().map(|c| c.0).sum();
+        let mut r = rng.generate::<f32>() * total;
+        // Default to the last one, in case rounding errors make us overshoot.
+        let mut i = candidates.len() - 1;
+        for (j, &(weight, _)) in candidates.iter().enumerate() {
+            if r < weight {
+                i = j;
+                break
+            }
+            r -= weight;
+        }
+        result.push(candidates.swap_remove(i).1);
+    }
+
+    result
+}
+
+/// Select `n` tracks to play after the `seeds`, the recently played and queued tracks.
+///
+/// The result contains no seed tracks and no duplicates. It can contain fewer
+/// than `n` tracks if the library is small or mostly disliked.
+pub fn select_tracks(
+    index: &MemoryMetaIndex,
+    user_data: &UserData,
+    rng: &mut Prng,
+    seeds: &[TrackId],
+    n: usize,
+    favorite_boost: f32,
+) -> Vec<TrackId> {
+    let exclude: HashSet<TrackId> = seeds.iter().cloned().collect();
+    let now = TimeVector::from_local_time(&chrono::Local::now());
+    let weights = CandidateWeights {
+        favorite_boost,
+        now: Some(QuantizedTimeVector::from_time_vector(&now)),
+    };
+
+    // Count for every artist how many of the seed tracks are on an album by
+    // that artist, artists we listened to more recently weigh more.
+    let mut artist_affinity: HashMap<ArtistId, f32> = HashMap::new();
+    for track_id in seeds {
+        if let Some(album) = index.get_album(track_id.album_id()) {
+            for artist_id in index.get_album_artists(album.artist_ids) {
+                *artist_affinity.entry(*artist_id).or_default() += 1.0;
+            }
+        }
+    }
+
+    // Extend the affinity to artists that we often listen to together with the
+    // seed artists, so the radio stays in the same genre without only playing
+    // the seed artists. These weigh less than the seed artists themselves.
+    let mut similar_affinity: HashMap<ArtistId, f32> = HashMap::new();
+    for (artist_id, affinity) in artist_affinity.iter() {
+        for &(similar_id, similarity) in user_data.get_similar_artists(*artist_id) {
+            *similar_affinity.entry(similar_id).or_default() += affinity * similarity;
+        }
+    }
+    for (artist_id, affinity) in similar_affinity {
+        *artist_affinity.entry(artist_id).or_default() += affinity;
+    }
+
+    // An album can be by multiple seed artists, then the affinities add up.
+    let mut album_affinity: HashMap<AlbumId, f32> = HashMap::new();
+    for (artist_id, affinity) in artist_affinity.iter() {
+        for &(_, album_id) in index.get_albums_by_artist(*artist_id) {
+            *album_affinity.entry(album_id).or_default() += affinity;
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for (album_id, affinity) in album_affinity.iter() {
+        push_album_candidates(index, user_data, &exclude, *album_id, *affinity, weights, &mut candidates);
+    }
+    let mut result = sample_weighted(rng, &mut candidates, n);
+
+    if result.len() < n {
+        // We ran out of tracks by the seed artists, take anything from the
+        // library instead. Note, this can pick tracks by the seed artists
+        // again, but only the ones that we ruled out already.
+        let mut exclude = exclude;
+        exclude.extend(result.iter().cloned());
+        candidates.clear();
+        for album in index.get_albums() {
+            if !album_affinity.contains_key(&album.album_id) {
+                push_album_candidates(index, user_data, &exclude, album.album_id, 1.0, weights, &mut candidates);
+            }
+        }
+        let n_remaining = n - result.len();
+        result.extend(sample_weighted(rng, &mut candidates, n_remaining));
+    }
+
+    result
+}
+
+/// Select up to `n` tracks from the entire library, to shuffle all of it.
+///
+/// When `weighted`, we weigh tracks like radio mode does for albums by artists
+/// that we did not listen to recently: albums that we play a lot and liked
+/// tracks are more likely, tracks that we often skip are less likely, and
+/// disliked tracks are never picked. Otherwise, every
+/// track is equally likely. The result contains no duplicates, and is in random
+/// order.
+pub fn sample_library(
+    index: &MemoryMetaIndex,
+    user_data: &UserData,
+    rng: &mut Prng,
+    n: usize,
+    weighted: bool,
+    favorite_boost: f32,
+) -> Vec<TrackId> {
+    let mut candidates = Vec::new();
+    if weighted {
+        let exclude = HashSet::new();
+        let weights = CandidateWeights { favorite_boost, now: None };
+        for album in index.get_albums() {
+            push_album_candidates(index, user_data, &exclude, album.album_id, 1.0, weights, &mut candidates);
+        }
+    } else {
+        candidates.extend(index.get_tracks().iter().map(|t| (1.0, t.track_id)));
+    }
+
+    sample_weighted_keys(rng, candidates, n)
+}
+
+/// Select up to `n` candidates with probability proportional to their weight, in random order.
+///
+/// This has the same distribution as `sample_weighted`, but that is linear in
+/// the number of candidates for every pick, which is too slow for the entire
+/// library. Instead we use the method of Efraimidis and Spirakis: give every
+/// candidate the key u^(1/w) for a uniform u, and take the candidates with the
+/// largest keys. We compare ln(u)/w instead, which orders the same and does not
+/// underflow.
+fn sample_weighted_keys(rng: &mut Prng, candidates: Vec<(f32, TrackId)>, n: usize) -> Vec<TrackId> {
+    let mut keyed: Vec<(f32, TrackId)> = candidates
+        .into_iter()
+        .map(|(weight, track_id)| (rng.generate::<f32>().ln() / weight, track_id))
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.truncate(n);
+    keyed.into_iter().map(|(_, track_id)| track_id).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sample_weighted, sample_weighted_keys, time_weight, track_weight};
+    use crate::playcount::QuantizedTimeVector;
+    use crate::prim::TrackId;
+    use crate::shuffle::Prng;
+    use crate::user_data::{Rating, TrackScores};
+
+    #[test]
+    fn sample_weighted_returns_distinct_candidates() {
+        let mut rng = Prng::new_seed(42);
+        for n in 0..5 {
+            let mut candidates = vec![
+                (1.0, TrackId(1)),
+                (0.5, TrackId(2)),
+                (4.0, TrackId(3)),
+            ];
+            let mut result = sample_weighted(&mut rng, &mut candidates, n);
+            assert_eq!(result.len(), n.min(3));
+            assert_eq!(candidates.len(), 3 - result.len());
+            result.sort();
+            result.dedup();
+            assert_eq!(result.len(), n.min(3));
+        }
+    }
+
+    #[test]
+    fn sample_weighted_prefers_heavy_candidates() {
+        let mut rng = Prng::new_seed(42);
+        let mut n_heavy = 0;
+        for _ in 0..1000 {
+            let mut candidates = vec![(1.0, TrackId(1)), (9.0, TrackId(2))];
+            if sample_weighted(&mut rng, &mut candidates, 1) == vec![TrackId(2)] {
+                n_heavy += 1;
+            }
+        }
+        assert!(n_heavy > 800, "Expected ~900 heavy picks, got {}.", n_heavy);
+    }
+
+    #[test]
+    fn sample_weighted_keys_returns_distinct_heavy_candidates() {
+        let mut rng = Prng::new_seed(42);
+        let candidates = vec![(1.0, TrackId(1)), (9.0, TrackId(2)), (0.5, TrackId(3))];
+        let mut n_heavy = 0;
+        for _ in 0..1000 {
+            let mut result = sample_weighted_keys(&mut rng, candidates.clone(), 2);
+            if result[0] == TrackId(2) {
+                n_heavy += 1;
+            }
+            result.sort();
+            result.dedup();
+            assert_eq!(result.len(), 2);
+        }
+        assert!(n_heavy > 800, "Expected ~860 heavy first picks, got {}.", n_heavy);
+        assert_eq!(sample_weighted_keys(&mut rng, candidates, 5).len(), 3);
+    }
+
+    #[test]
+    fn time_weight_favors_tracks_that_fit_the_time() {
+        let now = QuantizedTimeVector([127, 0, 127, 0]);
+        let fits = QuantizedTimeVector([127, 0, 127, 0]);
+        let opposite = QuantizedTimeVector([-127, 0, -127, 0]);
+        let unknown = QuantizedTimeVector::default();
+        assert_eq!(time_weight(&unknown, &now), 1.0);
+        assert!((time_weight(&fits, &now) - 1.0_f32.exp()).abs() < 1e-5);
+        assert!((time_weight(&opposite, &now) - (-1.0_f32).exp()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn track_weight_demotes_skipped_tracks() {
+        let played = TrackScores::default();
+        let skipped = TrackScores { skip_count: 3.0, ..TrackScores::default() };
+        assert_eq!(track_weight(Rating::Neutral, &played, None), 1.0);
+        assert_eq!(track_weight(Rating::Neutral, &skipped, None), 0.25);
+        assert_eq!(track_weight(Rating::Like, &skipped, None), 0.5);
+        assert_eq!(track_weight(Rating::Dislike, &played, None), 0.0);
+
+        // The weighted library sample picks the skipped track less often.
+        let mut rng = Prng::new_seed(42);
+        let candidates = vec![
+            (track_weight(Rating::Neutral, &played, None), TrackId(1)),
+            (track_weight(Rating::Neutral, &skipped, None), TrackId(2)),
+        ];
+        let mut n_skipped = 0;
+        for _ in 0..1000 {
+            if sample_weighted_keys(&mut rng, candidates.clone(), 1) == vec![TrackId(2)] {
+                n_skipped += 1;
+            }
+        }
+        assert!(n_skipped < 300, "Expected ~200 skipped picks, got {}.", n_skipped);
+    }
+}
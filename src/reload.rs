@@ -0,0 +1,81 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Reloading the configuration on SIGHUP.
+//!
+//! Rather than doing work in a signal handler, we block SIGHUP in all threads,
+//! and a dedicated thread waits for it with `sigwait`. For this to work, the
+//! signal must be blocked before any other threads are spawned, because threads
+//! inherit the signal mask of the thread that spawns them.
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use log::{error, info, warn};
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::mvar::Var;
+
+fn sighup_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+/// Block SIGHUP for the calling thread, and threads spawned from it later.
+///
+/// Call this at startup, before spawning any threads.
+pub fn block_sighup() {
+    let set = sighup_set();
+    let result = unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut())
+    };
+    assert_eq!(result, 0, "Failed to block SIGHUP.");
+}
+
+/// Spawn a thread that calls `load_config` on every SIGHUP.
+///
+/// The settings that can change at runtime are then applied to the config in
+/// `config_var`, see `Config::with_reloadable_from`. When the config fails to
+/// load, we print the error and keep the current config.
+pub fn spawn_reload_thread<F>(config_var: Var<Config>, load_config: F) -> JoinHandle<()>
+where
+    F: 'static + Send + Fn() -> Result<Config>,
+{
+    let builder = std::thread::Builder::new();
+    builder
+        .name("reload".into())
+        .spawn(move || {
+            let set = sighup_set();
+            loop {
+                let mut signal = 0;
+                let result = unsafe { libc::sigwait(&set, &mut signal) };
+                assert_eq!(result, 0, "Failed to wait for SIGHUP.");
+
+                info!("Received SIGHUP, reloading configuration ...");
+                match load_config() {
+                    Ok(new_config) => {
+                        let current = config_var.get();
+                        for key in current.changes_needing_restart(&new_config) {
+                            warn!("Setting {} changed, restart Musium to apply it.", key);
+                        }
+                        let config = current.with_reloadable_from(&new_config);
+                        info!("Configuration:\n{}\n", config);
+                        config_var.set(Arc::new(config));
+                    }
+                    Err(err) => error!(
+                        "Failed to reload configuration, keeping the current one: {:?}", err,
+                    ),
+                }
+            }
+        })
+        .unwrap()
+}
@@ -0,0 +1,217 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Control a running server through its HTTP API, for `musium remote`.
+//!
+//! The requests are simple enough that we speak HTTP/1.0 over a plain TCP
+//! connection, the server then closes the connection after the response, and
+//! does not use chunked encoding.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+
+use serde_json::Value;
+
+use crate::cli::RemoteCommand;
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+/// Return the address to connect to, for the address that the server listens on.
+///
+/// A server that listens on all interfaces can be reached on the loopback one.
+fn server_address(listen: &str) -> String {
+    match listen.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED) => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port()).to_string()
+        }
+        Ok(addr) if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port()).to_string()
+        }
+        _ => listen.to_string(),
+    }
+}
+
+/// Return a description of the error from an error response body.
+///
+/// The server responds with a json object with a `message` and optional
+/// `detail`, but we fall back to the raw body, in case we talk to an older
+/// server.
+fn describe_error(body: &[u8]) -> String {
+    let value: Value = match serde_json::from_slice(body) {
+        Ok(value) => value,
+        Err(_) => return String::from_utf8_lossy(body).trim().to_string(),
+    };
+    match (value["message"].as_str(), value["detail"].as_str()) {
+        (Some(message), Some(detail)) => format!("{} {}", message, detail),
+        (Some(message), None) => message.to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Split a raw HTTP response into the status code and the body.
+fn parse_response(response: &[u8]) -> Option<(u16, &[u8])> {
+    let header_end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let status_line = response[..header_end].split(|&b| b == b'\r').next()?;
+    let status_line = std::str::from_utf8(status_line).ok()?;
+    let status = status_line.split(' ').nth(1)?.parse().ok()?;
+    Some((status, &response[header_end + 4..]))
+}
+
+struct Client {
+    address: String,
+}
+
+impl Client {
+    fn request(&self, method: &str, path: &str) -> Result<Vec<u8>> {
+        let mut stream = match TcpStream::connect(&self.address) {
+            Ok(stream) => stream,
+            Err(err) => return Err(Error::RemoteError(format!(
+                "Failed to connect to the server at {}: {}. Is it running?",
+                self.address, err,
+            ))),
+        };
+        write!(
+            stream,
+            "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: 0\r\n\r\n",
+            method, path, self.address,
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+
+        match parse_response(&response) {
+            Some((200..=299, body)) => Ok(body.to_vec()),
+            Some((status, body)) => Err(Error::RemoteError(format!(
+                "{} {} failed with status {}: {}",
+                method, path, status, describe_error(body),
+            ))),
+            None => Err(Error::RemoteError(format!(
+                "{} {} returned a malformed response.", method, path,
+            ))),
+        }
+    }
+
+    fn request_json(&self, method: &str, path: &str) -> Result<Value> {
+        let body = self.request(method, path)?;
+        serde_json::from_slice(&body).map_err(|err| Error::RemoteError(format!(
+            "{} {} returned invalid json: {}", method, path, err,
+        )))
+    }
+}
+
+fn format_seconds(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn print_queue(queue: &Value) {
+    let tracks = queue.as_array().map(|ts| &ts[..]).unwrap_or(&[]);
+    let current = match tracks.first() {
+        Some(track) => track,
+        None => {
+            println!("Not playing, the queue is empty.");
+            return;
+        }
+    };
+    println!(
+        "Playing: {} by {}, from {}",
+        current["title"].as_str().unwrap_or("?"),
+        current["artist"].as_str().unwrap_or("?"),
+        current["album"].as_str().unwrap_or("?"),
+    );
+    println!(
+        "         {} / {}",
+        format_seconds(current["position_seconds"].as_f64().unwrap_or(0.0)),
+        format_seconds(current["duration_seconds"].as_f64().unwrap_or(0.0)),
+    );
+    if let Some(next) = tracks.get(1) {
+        println!(
+            "Next:    {} by {}",
+            next["title"].as_str().unwrap_or("?"),
+            next["artist"].as_str().unwrap_or("?"),
+        );
+    }
+    println!("Queue:   {} tracks", tracks.len());
+}
+
+fn print_volume(volume: &Value) {
+    match volume["volume_db"].as_f64() {
+        Some(db) => println!("Volume:  {:.1} dB", db),
+        None => println!("Volume:  unknown"),
+    }
+}
+
+pub fn main(config: &Config, command: RemoteCommand) -> Result<()> {
+    let client = Client { address: server_address(&config.listen) };
+
+    match command {
+        RemoteCommand::Status => {
+            print_queue(&client.request_json("GET", "/api/queue")?);
+            print_volume(&client.request_json("GET", "/api/volume")?);
+        }
+        RemoteCommand::Enqueue { query } => {
+            let query_encoded: String = url::form_urlencoded::byte_serialize(query.as_bytes()).collect();
+            let results = client.request_json("GET", &format!("/api/search?q={}", query_encoded))?;
+            let track = match results["tracks"].as_array().and_then(|ts| ts.first()) {
+                Some(track) => track,
+                None => return Err(Error::RemoteError(format!("No track matches '{}'.", query))),
+            };
+            let track_id = track["id"].as_str().unwrap_or("");
+            client.request("PUT", &format!("/api/queue/{}", track_id))?;
+            println!(
+                "Enqueued {} by {}.",
+                track["title"].as_str().unwrap_or("?"),
+                track["artist"].as_str().unwrap_or("?"),
+            );
+        }
+        RemoteCommand::Skip => {
+            print_queue(&client.request_json("POST", "/api/queue/skip")?);
+        }
+        RemoteCommand::Volume { change_db: None } => {
+            print_volume(&client.request_json("GET", "/api/volume")?);
+        }
+        RemoteCommand::Volume { change_db: Some(change_db) } => {
+            // The API changes the volume in steps of 1 dB.
+            let path = if change_db > 0 { "/api/volume/up" } else { "/api/volume/down" };
+            let mut volume = client.request_json("GET", "/api/volume")?;
+            for _ in 0..change_db.unsigned_abs() {
+                volume = client.request_json("POST", path)?;
+            }
+            print_volume(&volume);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe_error, parse_response, server_address};
+
+    #[test]
+    fn server_address_uses_loopback_for_unspecified_address() {
+        assert_eq!(server_address("0.0.0.0:8233"), "127.0.0.1:8233");
+        assert_eq!(server_address("[::]:8233"), "[::1]:8233");
+        assert_eq!(server_address("192.168.1.2:8233"), "192.168.1.2:8233");
+        assert_eq!(server_address("localhost:8233"), "localhost:8233");
+    }
+
+    #[test]
+    fn parse_response_splits_status_and_body() {
+        let response = b"HTTP/1.1 201 Created\r\nContent-Length: 4\r\n\r\n\"id\"";
+        assert_eq!(parse_response(response), Some((201, &b"\"id\""[..])));
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\n"), None);
+    }
+
+    #[test]
+    fn describe_error_reads_json_error_body() {
+        let body = br#"{"code":"bad_request","message":"Invalid expression.","detail":"Unexpected ')' at 4."}"#;
+        assert_eq!(describe_error(body), "Invalid expression. Unexpected ')' at 4.");
+        let body = br#"{"code":"not_found","message":"Not found.","detail":null}"#;
+        assert_eq!(describe_error(body), "Not found.");
+        assert_eq!(describe_error(b"Not Found\n"), "Not Found");
+    }
+}
@@ -0,0 +1,136 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Counters for problems that happen while the server runs.
+//!
+//! The decode, playback, and history threads log their errors, but on a box
+//! that runs for months, a log line is easy to miss. These counters make a
+//! flaky disk or audio device show up in `/api/stats/runtime` instead.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters shared between the player threads and the server.
+pub struct RuntimeStats {
+    decode_errors: AtomicU64,
+    skipped_files: AtomicU64,
+    device_reopens: AtomicU64,
+    playback_errors: AtomicU64,
+    history_write_failures: AtomicU64,
+    history_restarts: AtomicU64,
+    history_dropped_events: AtomicU64,
+}
+
+/// The values of the counters at one point in time.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RuntimeCounts {
+    /// Number of times decoding a file failed halfway through.
+    pub decode_errors: u64,
+
+    /// Number of queued files that could not be opened for decoding.
+    pub skipped_files: u64,
+
+    /// Number of times the playback thread closed and opened the audio device
+    /// again, which it does when playback starts, and when the format changes.
+    pub device_reopens: u64,
+
+    /// Number of times writing samples to the audio device failed.
+    pub playback_errors: u64,
+
+    /// Number of playback events that the history thread failed to save.
+    pub history_write_failures: u64,
+
+    /// Number of times the history thread failed, and was replaced.
+    pub history_restarts: u64,
+
+    /// Number of events that the history thread failed to handle, and that
+    /// we gave up on.
+    pub history_dropped_events: u64,
+}
+
+impl RuntimeStats {
+    pub fn new() -> RuntimeStats {
+        RuntimeStats {
+            decode_errors: AtomicU64::new(0),
+            skipped_files: AtomicU64::new(0),
+            device_reopens: AtomicU64::new(0),
+            playback_errors: AtomicU64::new(0),
+            history_write_failures: AtomicU64::new(0),
+            history_restarts: AtomicU64::new(0),
+            history_dropped_events: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count_decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_skipped_file(&self) {
+        self.skipped_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_device_reopen(&self) {
+        self.device_reopens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_playback_error(&self) {
+        self.playback_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_history_write_failure(&self) {
+        self.history_write_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_history_restart(&self) {
+        self.history_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_history_dropped_event(&self) {
+        self.history_dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Return the current values of the counters.
+    ///
+    /// The counters are independent, so this is not an atomic snapshot, but
+    /// they only ever go up, and that is good enough for monitoring.
+    pub fn get(&self) -> RuntimeCounts {
+        RuntimeCounts {
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            skipped_files: self.skipped_files.load(Ordering::Relaxed),
+            device_reopens: self.device_reopens.load(Ordering::Relaxed),
+            playback_errors: self.playback_errors.load(Ordering::Relaxed),
+            history_write_failures: self.history_write_failures.load(Ordering::Relaxed),
+            history_restarts: self.history_restarts.load(Ordering::Relaxed),
+            history_dropped_events: self.history_dropped_events.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RuntimeCounts, RuntimeStats};
+
+    #[test]
+    fn runtime_stats_counts_independently() {
+        let stats = RuntimeStats::new();
+        assert_eq!(stats.get(), RuntimeCounts::default());
+
+        stats.count_decode_error();
+        stats.count_skipped_file();
+        stats.count_skipped_file();
+        stats.count_history_write_failure();
+        stats.count_history_dropped_event();
+
+        let counts = stats.get();
+        assert_eq!(counts.decode_errors, 1);
+        assert_eq!(counts.skipped_files, 2);
+        assert_eq!(counts.device_reopens, 0);
+        assert_eq!(counts.playback_errors, 0);
+        assert_eq!(counts.history_write_failures, 1);
+        assert_eq!(counts.history_restarts, 0);
+        assert_eq!(counts.history_dropped_events, 1);
+    }
+}
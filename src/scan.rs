@@ -34,8 +34,10 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Receiver, SyncSender};
+use std::time::{Duration, Instant};
 
 use walkdir;
+use log::{info, log_enabled, warn, Level};
 
 use crate::config::Config;
 use crate::database_utils;
@@ -43,6 +45,7 @@ use crate::database as db;
 use crate::database::{Connection, Transaction};
 use crate::error;
 use crate::loudness;
+use crate::logger::{self, LogFormat};
 use crate::mvar::{MVar, Var};
 use crate::prim::Mtime;
 use crate::thumb_cache::ThumbCache;
@@ -102,6 +105,36 @@ pub enum ScanStage {
     Done = 9,
 }
 
+impl ScanStage {
+    /// All stages that take time, in order, so excluding `Done`.
+    pub const TIMED: [ScanStage; 9] = [
+        ScanStage::Discovering,
+        ScanStage::PreProcessingMetadata,
+        ScanStage::ExtractingMetadata,
+        ScanStage::IndexingMetadata,
+        ScanStage::PreProcessingLoudness,
+        ScanStage::AnalyzingLoudness,
+        ScanStage::PreProcessingThumbnails,
+        ScanStage::GeneratingThumbnails,
+        ScanStage::LoadingThumbnails,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanStage::Discovering => "discovering",
+            ScanStage::PreProcessingMetadata => "preprocessing_metadata",
+            ScanStage::ExtractingMetadata => "extracting_metadata",
+            ScanStage::IndexingMetadata => "indexing_metadata",
+            ScanStage::PreProcessingLoudness => "preprocessing_loudness",
+            ScanStage::AnalyzingLoudness => "analyzing_loudness",
+            ScanStage::PreProcessingThumbnails => "preprocessing_thumbnails",
+            ScanStage::GeneratingThumbnails => "generating_thumbnails",
+            ScanStage::LoadingThumbnails => "loading_thumbnails",
+            ScanStage::Done => "done",
+        }
+    }
+}
+
 /// Counters to report progress during scanning.
 ///
 /// All of these counters start out at 0 and increase over time. They never
@@ -137,6 +170,14 @@ pub struct Status {
 
     /// Of the `files_to_process_thumbnails`, the number processed so far.
     pub files_processed_thumbnails: u64,
+
+    /// Wall-clock time spent in every stage, indexed by stage.
+    ///
+    /// The duration of a stage is final once the scan moved past it.
+    pub stage_durations: [Duration; 9],
+
+    /// When we entered the current stage.
+    stage_started_at: Instant,
 }
 
 impl Status {
@@ -152,7 +193,40 @@ impl Status {
             albums_processed_loudness: 0,
             files_to_process_thumbnails: 0,
             files_processed_thumbnails: 0,
+            stage_durations: [Duration::ZERO; 9],
+            stage_started_at: Instant::now(),
+        }
+    }
+
+    /// Move to the next stage, and record how long the current stage took.
+    pub fn set_stage(&mut self, stage: ScanStage) {
+        self.set_stage_at(stage, Instant::now());
+    }
+
+    fn set_stage_at(&mut self, stage: ScanStage, now: Instant) {
+        if let Some(duration) = self.stage_durations.get_mut(self.stage as usize) {
+            *duration += now.saturating_duration_since(self.stage_started_at);
         }
+        self.stage = stage;
+        self.stage_started_at = now;
+    }
+
+    /// Return the stages that the scan moved past, and how long they took.
+    pub fn completed_stage_durations(&self) -> impl Iterator<Item = (ScanStage, Duration)> + '_ {
+        ScanStage::TIMED
+            .iter()
+            .filter(move |stage| **stage < self.stage)
+            .map(move |stage| (*stage, self.stage_durations[*stage as usize]))
+    }
+
+    /// Log how long every stage took, when the scan is done.
+    pub fn log_stage_durations(&self) {
+        let total: Duration = self.completed_stage_durations().map(|(_, d)| d).sum();
+        let stages: Vec<String> = self
+            .completed_stage_durations()
+            .map(|(stage, duration)| format!("{} {:.2?}", stage.as_str(), duration))
+            .collect();
+        info!("Scan took {:.2?}: {}.", total, stages.join(", "));
     }
 }
 
@@ -210,12 +284,13 @@ impl fmt::Display for Status {
 pub fn scan(
     connection: &sqlite::Connection,
     library_path: &Path,
+    num_reader_threads: usize,
     status: &mut Status,
     status_sender: &mut SyncSender<Status>,
 ) -> db::Result<()> {
     let mut files_current = enumerate_flac_files(library_path, status_sender, status);
 
-    status.stage = ScanStage::PreProcessingMetadata;
+    status.set_stage(ScanStage::PreProcessingMetadata);
     status_sender.send(*status).unwrap();
 
     // Sort the files in memcmp order. The default Ord instance of PathBuf is
@@ -230,8 +305,12 @@ pub fn scan(
     db::ensure_schema_exists(&mut tx)?;
     tx.commit()?;
 
+    // The server may write to the database while we scan, to record listens
+    // and ratings. To not block it for the duration of the scan, we keep write
+    // transactions short. This is safe because only the scan writes to the
+    // files and tags tables, and we compare against them in `get_updates`, so
+    // if the scan is interrupted, the next scan picks up where it left off.
     let mut tx = db.begin()?;
-
     let mut rows_to_delete = Vec::new();
     let mut paths_to_scan = Vec::new();
     get_updates(
@@ -240,15 +319,19 @@ pub fn scan(
         &mut rows_to_delete,
         &mut paths_to_scan,
     )?;
+    tx.commit()?;
 
-    status.stage = ScanStage::ExtractingMetadata;
+    status.set_stage(ScanStage::ExtractingMetadata);
     status.files_to_process_metadata = paths_to_scan.len() as u64;
     status_sender.send(*status).unwrap();
 
     // Delete rows for outdated files, we will insert new rows below.
-    for file_id in &rows_to_delete {
-        db::delete_file(&mut tx, file_id.0)?;
-    }
+    database_utils::with_write_transaction(&mut db, |tx| {
+        for file_id in &rows_to_delete {
+            db::delete_file(tx, file_id.0)?;
+        }
+        Ok(())
+    })?;
 
     // Format the current time, we store this in the `imported_at` column in the
     // `file_metadata` table.
@@ -257,15 +340,14 @@ pub fn scan(
     let now_str = now.to_rfc3339_opts(chrono::SecondsFormat::Millis, use_zulu_suffix);
 
     insert_file_metadata_for_paths(
-        &mut tx,
+        &mut db,
         &paths_to_scan[..],
         &now_str,
+        num_reader_threads,
         status_sender,
         status,
     )?;
 
-    tx.commit()?;
-
     // If we deleted anything vacuum the database to ensure it's packed tightly
     // again. Deletes are expected to be infrequent and the database is expected
     // to be small (a few megabytes*), so the additional IO is not an issue.
@@ -326,10 +408,10 @@ pub fn enumerate_flac_files(
                     },
                     Ok(_not_flac) => None,
                     // TODO: Add a nicer way to report errors.
-                    Err(err) => { eprintln!("{}", err); None }
+                    Err(err) => { warn!("{}", err); None }
                 }
             }
-            Err(err) => { eprintln!("{}", err); None }
+            Err(err) => { warn!("{}", err); None }
         })
         .collect();
 
@@ -414,9 +496,10 @@ fn get_updates(
 }
 
 pub fn insert_file_metadata_for_paths(
-    tx: &mut Transaction,
+    db: &mut Connection,
     paths_to_scan: &[(PathBuf, Mtime)],
     now_str: &str,
+    num_threads: usize,
     status_sender: &mut SyncSender<Status>,
     status: &mut Status,
 ) -> db::Result<()> {
@@ -427,8 +510,8 @@ pub fn insert_file_metadata_for_paths(
     // some overheads to more threads, but 8 threads vs 64 threads is a
     // difference of maybe 0.05 seconds for 16k tracks, while for the IO-bound
     // case, it can bring down the time from ~140 seconds to ~70 seconds, which
-    // is totally worth it.
-    let num_threads = 64;
+    // is totally worth it. The default is therefore 64 threads, but it can be
+    // set with `scan_reader_threads` in the config.
 
     // We are going to have many threads read files, but only this thread will
     // insert them into the database (because the database is not `Send`). If
@@ -463,9 +546,19 @@ pub fn insert_file_metadata_for_paths(
         // receiving side.
         std::mem::drop(tx_file);
 
-        for (i, flac_reader) in rx_file.iter() {
+        // Commit in batches, rather than holding the write lock for the
+        // entire scan, so other connections can write in between.
+        let batch_size = 256;
+        let mut tx = db.begin()?;
+
+        for (n, (i, flac_reader)) in rx_file.iter().enumerate() {
             let (ref path, mtime) = paths_to_scan[i];
-            insert_file_metadata(tx, now_str, path, mtime, flac_reader)?;
+            insert_file_metadata(&mut tx, now_str, path, mtime, flac_reader)?;
+
+            if (n + 1) % batch_size == 0 {
+                tx.commit()?;
+                tx = db.begin()?;
+            }
 
             // Keep the status up to date, and send it once in a while. We send
             // it more often here than when enumerating files, because reading
@@ -478,6 +571,8 @@ pub fn insert_file_metadata_for_paths(
             }
         }
 
+        tx.commit()?;
+
         // Sanity check: did we get everything? Every thread should have
         // incremented once without sending, and we have one increment per
         // processed file for files that did get processed.
@@ -512,7 +607,7 @@ fn read_files(
         let reader = match claxon::FlacReader::open_ext(path, opts) {
             Ok(r) => r,
             Err(err) => {
-                eprintln!("Failure while reading {:?}: {}", path, err);
+                warn!("Failure while reading {:?}: {}", path, err);
                 continue;
             }
         };
@@ -531,7 +626,7 @@ fn insert_file_metadata(
     let path_utf8 = match path.to_str() {
         Some(s) => s,
         None => {
-            eprintln!("Warning: Path {:?} is not valid UTF-8. Skipping.", path);
+            warn!("Warning: Path {:?} is not valid UTF-8. Skipping.", path);
             return Ok(())
         }
     };
@@ -565,6 +660,7 @@ fn insert_file_metadata(
             | "artist"
             | "date"
             | "discnumber"
+            | "genre"
             | "musicbrainz_albumartistid"
             | "musicbrainz_albumid"
             | "musicbrainz_trackid"
@@ -593,88 +689,123 @@ pub fn run_scan_in_thread(
     let (mut tx, rx) = std::sync::mpsc::sync_channel(15);
 
     let db_path = config.db_path.clone();
+    let db_pragmas = config.db_pragmas.clone();
     let library_path = config.library_path.clone();
+    let num_reader_threads = config.scan_reader_threads;
+    let num_analysis_threads = config.scan_analysis_threads;
+    let spectrograms = config.scan_spectrograms;
+    let thumb_cache_size = config.thumb_cache_size;
+    let thumb_covers_path = config.thumb_covers_path.clone();
 
     let scan_thread = std::thread::Builder::new()
         .name("scan".to_string())
         .spawn(move || {
             let mut status = Status::new();
 
-            let connection = database_utils::connect_read_write(&db_path)?;
+            let connection = database_utils::connect_read_write(&db_path, &db_pragmas)?;
 
             // Scan all files, put the metadata in the database.
             scan(
                 &connection,
                 &library_path,
+                num_reader_threads,
                 &mut status,
                 &mut tx,
             )?;
 
-            status.stage = ScanStage::IndexingMetadata;
+            status.set_stage(ScanStage::IndexingMetadata);
             tx.send(status).unwrap();
 
             // Build a new index from the latest data in the database. Then
             // immediately publish that new index so it can be accessed by the
             // webinterface, even before the thumbnails are ready (because
-            // generating those may take a while).
+            // generating those may take a while). Most scans change only a
+            // few albums, so we update the word indexes of the current index.
             let mut db = Connection::new(&connection);
             let mut db_tx = db.begin()?;
-            let (index, builder) = MemoryMetaIndex::from_database(&mut db_tx)?;
-            let index_arc = Arc::new(index);
+            let (index, builder) = MemoryMetaIndex::from_database(&mut db_tx, Some(&index_var.get()))?;
+            let mut index_arc = Arc::new(index);
             index_var.set(index_arc.clone());
             db_tx.commit()?;
 
+            // If we had to build or update the word indexes, save them, so the next
+            // startup can load them instead of building them again.
+            if builder.collect_words {
+                database_utils::with_write_transaction(&mut db, |tx| index_arc.save_word_indexes(tx))?;
+            }
+
             // TODO: Move issue reporting to a better place. Maybe take the builder and
             // index as an argument to this method.
-            if !builder.issues.is_empty() {
-                eprintln!();
-                for issue in &builder.issues {
-                    eprintln!("{}", issue);
-                }
+            for issue in &builder.issues {
+                warn!("{}\n", issue);
+            }
+            // `musium scan` redraws its status by moving the cursor up, leave
+            // room for that below the issues, so it does not overwrite them.
+            if !builder.issues.is_empty() && log_enabled!(Level::Warn) && logger::format() == LogFormat::Text {
                 eprintln!("\n\n\n");
             }
 
             {
-                status.stage = ScanStage::PreProcessingLoudness;
+                status.set_stage(ScanStage::PreProcessingLoudness);
                 tx.send(status).unwrap();
 
                 let mut loudness_tasks = loudness::TaskQueue::new(
                     &index_arc,
+                    spectrograms,
                     &mut status,
                     &mut tx,
                 );
                 let mut db_tx = db.begin()?;
                 loudness_tasks.push_tasks_missing(&mut db_tx)?;
                 db_tx.commit()?;
-                loudness_tasks.status.stage = ScanStage::AnalyzingLoudness;
+                loudness_tasks.status.set_stage(ScanStage::AnalyzingLoudness);
                 loudness_tasks.status_sender.send(*loudness_tasks.status).unwrap();
 
-                loudness_tasks.process_all_in_thread_pool(&db_path)?;
+                let has_loudness_tasks = !loudness_tasks.is_done();
+                loudness_tasks.process_all_in_thread_pool(&db_path, &db_pragmas, num_analysis_threads)?;
+
+                // The index that we published above lacks the loudness of the
+                // files that we just analyzed, publish one that has it.
+                if has_loudness_tasks {
+                    let mut index = (*index_arc).clone();
+                    let mut db_tx = db.begin()?;
+                    index.reload_loudness(&mut db_tx)?;
+                    db_tx.commit()?;
+                    index_arc = Arc::new(index);
+                    index_var.set(index_arc.clone());
+                }
             }
 
+            // Now that the index has its loudness, save it, so the next startup
+            // can load it instead of building it again.
+            database_utils::with_write_transaction(&mut db, |tx| index_arc.save_cache(tx))?;
+
             // If there are any new or updated albums, regenerate thumbnails for
             // those.
             crate::thumb_gen::generate_thumbnails(
                 &index_arc,
                 &db_path,
+                &db_pragmas,
+                thumb_covers_path.as_deref(),
+                num_analysis_threads,
                 &mut status,
                 &mut tx,
             )?;
 
-            status.stage = ScanStage::LoadingThumbnails;
+            status.set_stage(ScanStage::LoadingThumbnails);
             tx.send(status).unwrap();
 
             // Load the new set of thumbnails, publish them to the webinterface.
             {
                 let mut db = Connection::new(&connection);
                 let mut tx = db.begin()?;
-                let thumb_cache = ThumbCache::load_from_database(&mut tx)?;
+                let thumb_cache = ThumbCache::load(&mut tx, thumb_cache_size)?;
                 tx.commit()?;
                 let thumb_cache_arc = Arc::new(thumb_cache);
                 thumb_cache_var.set(thumb_cache_arc);
             }
 
-            status.stage = ScanStage::Done;
+            status.set_stage(ScanStage::Done);
             tx.send(status).unwrap();
             Ok(())
         })
@@ -730,6 +861,7 @@ impl BackgroundScan {
                     ScanStage::Done,
                     "Final status update should be Done after scan thread exits.",
                 );
+                final_status.log_stage_durations();
             })
         .expect("Failed to spawn scan supervisor thread.");
 
@@ -807,8 +939,9 @@ impl BackgroundScanner {
 #[cfg(test)]
 mod test {
     use crate::database::Connection;
-    use super::{Mtime, FileMetaId, get_updates};
+    use super::{Mtime, FileMetaId, ScanStage, Status, get_updates};
     use std::path::PathBuf;
+    use std::time::Duration;
 
     fn ensure_schema_exists(db: &mut Connection) {
         let mut tx = db.begin().unwrap();
@@ -1062,4 +1195,27 @@ mod test {
         ]);
         assert_eq!(&rows_to_delete[..], &[]);
     }
+
+    #[test]
+    fn status_records_stage_durations() {
+        let mut status = Status::new();
+        let t0 = status.stage_started_at;
+        let ms = Duration::from_millis;
+
+        status.set_stage_at(ScanStage::PreProcessingMetadata, t0 + ms(100));
+        // Skipping over a stage records a zero duration for it.
+        status.set_stage_at(ScanStage::IndexingMetadata, t0 + ms(150));
+        assert_eq!(
+            status.completed_stage_durations().collect::<Vec<_>>(),
+            vec![
+                (ScanStage::Discovering, ms(100)),
+                (ScanStage::PreProcessingMetadata, ms(50)),
+                (ScanStage::ExtractingMetadata, ms(0)),
+            ],
+        );
+
+        status.set_stage_at(ScanStage::Done, t0 + ms(400));
+        assert_eq!(status.completed_stage_durations().count(), 9);
+        assert_eq!(status.stage_durations[ScanStage::IndexingMetadata as usize], ms(250));
+    }
 }
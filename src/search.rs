@@ -9,22 +9,133 @@ use std::cmp;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::iter;
+use std::ops::Range;
 
 use crate::word_index::{Values, WordIndex, WordMeta};
 
+/// How to match the last word of a search query.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum LastWord {
+    /// The last word also matches words in the index that it is a prefix of.
+    ///
+    /// This is what we want for search-as-you-type, where the last word is
+    /// likely incomplete, but the words before it are complete.
+    #[default]
+    Prefix,
+
+    /// The last word must match exactly, like all other words.
+    ///
+    /// This is what we want when matching complete titles programmatically,
+    /// e.g. when importing listening history.
+    Exact,
+}
+
+/// Options that control how a search query is matched against the index.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SearchOptions {
+    /// Whether to also match words that are a small edit distance away from
+    /// the query words, to tolerate typos.
+    ///
+    /// Fuzzy matches always rank below exact and prefix matches.
+    pub fuzzy: bool,
+
+    /// Whether the last word of the query is matched as a prefix.
+    pub last_word: LastWord,
+}
+
+impl SearchOptions {
+    /// Options for matching complete titles: exact matches only, no prefixes.
+    pub fn exact() -> SearchOptions {
+        SearchOptions {
+            fuzzy: false,
+            last_word: LastWord::Exact,
+        }
+    }
+}
+
+/// Return the maximum edit distance at which to match `word` fuzzily.
+///
+/// Short words tolerate fewer typos; for a three-letter word, one edit already
+/// matches a large part of the index.
+fn max_edit_distance(word: &str) -> u32 {
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Where in an item a query word matched, so the matched words can be
+/// highlighted.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WordMatch {
+    /// The rank of the matched word, see `WordMeta::rank`.
+    ///
+    /// In the album and track indexes, 2 means the word is in the title, lower
+    /// ranks mean it is in the artist. In the artist index, it is always 2.
+    pub rank: u8,
+
+    /// The 0-based index of the matched word in the string it occurs in.
+    pub position: u8,
+
+    /// The length in bytes of the normalized word that matched.
+    ///
+    /// For a prefix match, this is longer than the query word.
+    pub len: u8,
+}
+
+impl WordMatch {
+    fn from_meta(meta: WordMeta) -> WordMatch {
+        WordMatch {
+            rank: meta.rank() as u8,
+            position: meta.index() as u8,
+            len: meta.word_len() as u8,
+        }
+    }
+}
+
+/// An item found by `search`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult<T> {
+    pub id: T,
+
+    /// Relevance of the result, see `search` for how to interpret it.
+    pub score: f32,
+
+    /// For every query word, where it matched.
+    pub matches: Vec<WordMatch>,
+}
+
+impl<T> SearchResult<T> {
+    /// Return a result for a query that has no search words, only filters.
+    ///
+    /// Such a query matches every item equally well, so there are no matched
+    /// words, and all results get the maximum score.
+    pub fn unscored(id: T) -> SearchResult<T> {
+        SearchResult {
+            id: id,
+            score: 1.0,
+            matches: Vec::new(),
+        }
+    }
+}
+
 /// Iterator over a value range of a word index.
 struct IndexIter<'a, I: 'a + WordIndex> {
     index: &'a I,
     begin: u32,
     end: u32,
+    /// Edit distance between the query word and the key of this value range.
+    distance: u32,
 }
 
 impl<'a, I: 'a + WordIndex> IndexIter<'a, I> {
-    pub fn new(index: &'a I, values: Values) -> IndexIter<'a, I> {
+    pub fn new(index: &'a I, values: Values, distance: u32) -> IndexIter<'a, I> {
         IndexIter {
             index: index,
             begin: values.offset,
             end: values.offset + values.len,
+            distance: distance,
         }
     }
 
@@ -55,13 +166,15 @@ impl<'a, I: 'a + WordIndex> IndexIter<'a, I> {
 
 // The ordering to put the iters into collections::binary_heap. Note that that
 // heap is a max-heap, so we implement the reverse order here. The heap should
-// not contain empty iterators, in that case we panic.
+// not contain empty iterators, in that case we panic. For equal values, the
+// iterator with the lowest edit distance goes first, so exact matches are
+// reported before fuzzy matches of the same value.
 impl<'a, I: 'a + WordIndex> cmp::Ord for IndexIter<'a, I> where I::Item: cmp::Ord {
     fn cmp(&self, other: &IndexIter<'a, I>) -> cmp::Ordering {
         let v_self = self.peek_value().expect("Only non-empty IndexIters can be compared.");
         let v_other = other.peek_value().expect("Only non-empty IndexIters can be compared.");
         // Note the reversed order for the max-heap.
-        v_other.cmp(v_self)
+        (v_other, other.distance).cmp(&(v_self, self.distance))
     }
 }
 
@@ -75,23 +188,26 @@ impl<'a, I: 'a + WordIndex> cmp::PartialEq for IndexIter<'a, I> where I::Item: c
     fn eq(&self, other: &IndexIter<'a, I>) -> bool {
         let v_self = self.peek_value().expect("Only non-empty IndexIters can be compared.");
         let v_other = other.peek_value().expect("Only non-empty IndexIters can be compared.");
-        *v_self == *v_other
+        *v_self == *v_other && self.distance == other.distance
     }
 }
 
 impl<'a, I: 'a + WordIndex> cmp::Eq for IndexIter<'a, I> where I::Item: cmp::Eq {}
 
 /// Iterator over the union of multiple value ranges of a word index.
+///
+/// Every value range is paired with the edit distance of its key to the query
+/// word, which is 0 for exact and prefix matches.
 struct Union<'a, I: 'a + WordIndex> {
-    value_slices: &'a [Values],
+    value_slices: &'a [(Values, u32)],
     iters: BinaryHeap<IndexIter<'a, I>>
 }
 
 impl<'a, I: 'a + WordIndex> Union<'a, I> where I::Item: cmp::Ord {
-    pub fn new(index: &'a I, value_slices: &'a [Values]) -> Union<'a, I> {
+    pub fn new(index: &'a I, value_slices: &'a [(Values, u32)]) -> Union<'a, I> {
         let mut iters = BinaryHeap::new();
-        for &vs in value_slices {
-            let iter = IndexIter::new(index, vs);
+        for &(vs, distance) in value_slices {
+            let iter = IndexIter::new(index, vs, distance);
             if !iter.is_empty() {
                 iters.push(iter);
             }
@@ -104,7 +220,7 @@ impl<'a, I: 'a + WordIndex> Union<'a, I> where I::Item: cmp::Ord {
 
     /// Return the number of elements in this union.
     pub fn len(&self) -> usize {
-        self.value_slices.iter().map(|v| v.len as usize).sum()
+        self.value_slices.iter().map(|(v, _)| v.len as usize).sum()
     }
 
     /// Peek the value of the next element in the union.
@@ -123,6 +239,11 @@ impl<'a, I: 'a + WordIndex> Union<'a, I> where I::Item: cmp::Ord {
         }
     }
 
+    /// Peek the edit distance of the next element in the union.
+    fn peek_distance(&self) -> Option<u32> {
+        self.iters.peek().map(|iter| iter.distance)
+    }
+
     /// Step ahead to the next element in the union.
     fn advance(&mut self) {
         let mut iter = self.iters.pop().expect("Should only advance if peek was succesful.");
@@ -154,180 +275,211 @@ impl<'a, I: 'a + WordIndex> iter::Iterator for Union<'a, I> where I::Item: cmp::
     }
 }
 
-fn intersect<'a, I: 'a + WordIndex, F: FnMut(&I::Item, &[WordMeta])>(
-    index: &'a I,
-    full_word_slices: &[Values],
-    mut prefix_values: Union<'a, I>,
+/// Find the values that occur in all of the unions, one union per query word.
+///
+/// For every match, calls `on_match` with the value, and for every query word
+/// the metadata and edit distance of all the occurrences of words that match
+/// it, in the order in which the union yields them.
+fn intersect<'a, I: 'a + WordIndex, F: FnMut(&I::Item, &[Vec<(WordMeta, u32)>])>(
+    mut iters: Vec<Union<'a, I>>,
     mut on_match: F,
 ) where
   I::Item: cmp::Ord + Copy
 {
-    let mut iters = Vec::new();
-    let mut values = Vec::new();
-    let mut metas = Vec::new();
-
-    for &vs in full_word_slices {
-        let iter = IndexIter::new(index, vs);
-
-        match iter.peek_value() {
-            // If any of the iterators is empty, the intersection is empty,
-            // so we have nothing to do here.
-            None => return,
-            Some(v) => {
-                iters.push(iter);
-                values.push(v);
+    let mut candidates: Vec<Vec<(WordMeta, u32)>> = iters.iter().map(|_| Vec::new()).collect();
+
+    'values: loop {
+        let mut value = None;
+        for iter in iters.iter() {
+            match iter.peek_value() {
+                // If any of the iterators is exhausted, the remainder is not in
+                // the intersection, so we can stop.
+                None => return,
+                Some(v) => value = cmp::max(value, Some(v)),
             }
         }
-    }
 
-    match prefix_values.peek_value() {
-        None => return,
-        Some(v) => values.push(v),
-    }
-
-    let mut value = *values.iter().max().expect("We have at least the union value.");
-
-    'matches: loop {
-        metas.clear();
+        let value = match value {
+            // Without any query words, there are no matches either.
+            None => return,
+            Some(v) => v,
+        };
 
-        'iters: for iter in iters.iter_mut() {
-            'values: while let Some(v) = iter.peek_value() {
+        for iter in iters.iter_mut() {
+            while let Some(v) = iter.peek_value() {
                 match value.cmp(v) {
-                    Ordering::Greater => {
-                        // This iterator is still less than the maximum, advance
-                        // until we match or pass it.
-                        iter.advance();
-                        continue 'values
-                    }
-                    Ordering::Equal => {
-                        // Looks like we have a match so far, let's collect the
-                        // associated metadata as well.
-                        let meta = iter.peek_meta().expect("Meta must match value.");
-
-                        // If the match is truly new, count it, and move on to
-                        // check the next iterator. But if we already have that
-                        // match, it means that a word occurs twice in the
-                        // search query, and we should not match twice in the
-                        // same location, so advance.
-                        if !metas.contains(meta) {
-                            metas.push(*meta);
-                            continue 'iters
-                        } else {
-                            iter.advance();
-                            continue 'values
-                        }
-                    }
-                    Ordering::Less => {
-                        // We found a new maximum, now we need to start over
-                        // with the other iters to see if they match.
-                        value = v;
-                        continue 'matches
-                    }
+                    // This iterator is still less than the maximum, advance
+                    // until we match or pass it.
+                    Ordering::Greater => iter.advance(),
+                    Ordering::Equal => break,
+                    // We found a new maximum, start over with that one.
+                    Ordering::Less => continue 'values,
                 }
             }
+        }
 
-            // If we get here, then one of the iterators is exhausted, which
-            // means the remainder is not in the intersection, so we can stop.
+        if iters.iter().any(|iter| iter.peek_value() != Some(value)) {
+            // One of the iterators got exhausted while advancing.
             return
         }
 
         // If we get here, then all iterators are currently peeking the same
-        // value, which means we potentially have a match. We still have to
-        // check the final union iterator as well.
-        'final_values: while let Some(v) = prefix_values.peek_value() {
-            match value.cmp(v) {
-                Ordering::Greater => {
-                    prefix_values.advance();
-                    continue 'final_values
-                }
-                Ordering::Equal => {
-                    // We found an element of the intersection!
-                    let meta = prefix_values.peek_meta().expect("Meta must match value.");
-                    if !metas.contains(meta) {
-                        metas.push(*meta);
-                        // Report the match through the callback.
-                        on_match(value, &metas[..]);
-                        // Then advance all iterators to move on to the next match.
-                        for iter in iters.iter_mut() { iter.advance(); }
-                        prefix_values.advance();
-                        continue 'matches
-                    } else {
-                        // We already matched this word, skip over it.
-                        prefix_values.advance();
-                        continue 'final_values
-                    }
-                }
-                Ordering::Less => {
-                    // It was not a match after all, we have a new max now.
-                    value = v;
-                    continue 'matches
-                }
+        // value, so we found an element of the intersection! The same value
+        // may occur multiple times per iterator, if the word occurs multiple
+        // times, so collect all of the occurrences, and advance past them.
+        for (iter, word_candidates) in iters.iter_mut().zip(candidates.iter_mut()) {
+            word_candidates.clear();
+            while iter.peek_value() == Some(value) {
+                let meta = iter.peek_meta().expect("Meta must match value.");
+                let distance = iter.peek_distance().expect("Distance must match value.");
+                word_candidates.push((*meta, distance));
+                iter.advance();
             }
         }
-        // If we get here, then the union iterator is exhausted, and the
-        // intersection also ends.
-        return
+
+        on_match(value, &candidates[..]);
     }
 }
 
+/// Assign an occurrence to every query word, such that no two query words are
+/// assigned the same occurrence, and words in a phrase are adjacent.
+///
+/// `in_phrase[i]` indicates whether word `i` must directly follow word `i - 1`.
+/// Pushes the assigned occurrences into `chosen`, and returns whether an
+/// assignment exists. Occurrences are tried in order, so if there is no
+/// conflict, every word gets its first candidate.
+fn assign(
+    candidates: &[Vec<(WordMeta, u32)>],
+    in_phrase: &[bool],
+    chosen: &mut Vec<(WordMeta, u32)>,
+) -> bool {
+    let i = chosen.len();
+    if i == candidates.len() {
+        return true
+    }
+
+    for &(meta, distance) in &candidates[i] {
+        // If a word occurs twice in the search query, it should not match
+        // twice in the same location.
+        if chosen.iter().any(|&(m, _)| m == meta) {
+            continue
+        }
+
+        // Words in a phrase must be adjacent, and in the same string. We can't
+        // tell the strings apart directly, but the rank and length together
+        // are a good enough proxy.
+        if in_phrase[i] {
+            let (prev, _) = chosen[i - 1];
+            let is_adjacent = meta.rank() == prev.rank()
+                && meta.total_len() == prev.total_len()
+                && meta.index() == prev.index() + 1;
+            if !is_adjacent {
+                continue
+            }
+        }
+
+        chosen.push((meta, distance));
+        if assign(candidates, in_phrase, chosen) {
+            return true
+        }
+        chosen.pop();
+    }
+
+    false
+}
+
+/// Search the index for items that match all of the words.
+///
+/// Every range in `phrases` is a run of consecutive words that must also occur
+/// consecutively, in that order, in a matching item.
+///
+/// Pushes results into `into`, ordered from most relevant to least relevant.
+/// The score is in the range (0.0, 1.0], higher is better. Exact and prefix
+/// matches score above 0.5, and every edit needed for a fuzzy match halves the
+/// score. Scores are comparable across indexes, so results from searching
+/// artists, albums, and tracks can be merged by score.
 pub fn search<'a, I: 'a + WordIndex, W: 'a + AsRef<str>>(
     index: &'a I,
     words: &'a [W],
-    into: &mut Vec<I::Item>
+    phrases: &[Range<usize>],
+    options: SearchOptions,
+    into: &mut Vec<SearchResult<I::Item>>
 ) where I::Item: cmp::Ord + Copy {
     let mut results = Vec::new();
 
-    // Break the search query in words to search only exact matches for, and the
-    // final word, for which we also search for prefix matches. The idea is that
-    // for search-as-you-type, the last word is incomplete, but the others are
-    // complete.
-    let mut words_iter = words.iter().rev();
-    let prefix_word = match words_iter.next() {
-        // If there are no search words at all, then there are no results either.
-        None => return,
-        Some(word) => word.as_ref(),
-    };
+    // If there are no search words at all, then there are no results either.
+    if words.is_empty() {
+        return
+    }
 
-    let mut exact_ranges = Vec::with_capacity(words.len() - 1);
-    for word in words_iter {
-        match index.search_exact(word.as_ref()) {
-            // If any of the query words is not present in the index, then the
-            // result is empty.
-            None => return,
-            Some(range) => exact_ranges.push(range),
+    // For every query word, collect the value ranges that it matches. We search
+    // only exact matches for all words but the final one, for which we may also
+    // search for prefix matches. The idea is that for search-as-you-type, the
+    // last word is incomplete, but the others are complete.
+    let mut word_ranges = Vec::with_capacity(words.len());
+    for (i, word) in words.iter().enumerate() {
+        let word = word.as_ref();
+        let mut ranges = Vec::new();
+
+        if i + 1 == words.len() && options.last_word == LastWord::Prefix {
+            ranges.extend(index.search_prefix(word).iter().map(|&vs| (vs, 0)));
+        } else if let Some(vs) = index.search_exact(word) {
+            ranges.push((vs, 0));
+        }
+
+        if options.fuzzy {
+            index.search_fuzzy(word, max_edit_distance(word), &mut ranges);
+        }
+
+        // If any of the query words is not present in the index, then the
+        // result is empty.
+        if ranges.is_empty() {
+            return
         }
+
+        word_ranges.push(ranges);
     }
 
-    let prefix_ranges = index.search_prefix(prefix_word);
-    let prefix_matches = Union::new(index, prefix_ranges);
+    let unions = word_ranges
+        .iter()
+        .map(|ranges| Union::new(index, &ranges[..]))
+        .collect();
+
+    // For every word, whether it must directly follow the previous word.
+    let mut in_phrase = vec![false; words.len()];
+    for phrase in phrases {
+        for follows in in_phrase.iter_mut().take(phrase.end).skip(phrase.start + 1) {
+            *follows = true;
+        }
+    }
 
-    let mut prev_item = None;
+    let mut chosen = Vec::with_capacity(words.len());
 
     intersect(
-        index,
-        &exact_ranges[..],
-        prefix_matches,
-        |item, metas| {
-            // Skip duplicate matches. These can happen if a query word occurs
-            // multiple times in a title, then we may get a match for each of it
-            // occurrences.
-            if Some(*item) == prev_item {
+        unions,
+        |item, candidates| {
+            chosen.clear();
+            if !assign(candidates, &in_phrase[..], &mut chosen) {
                 return
-            } else {
-                prev_item = Some(*item);
             }
 
-            for (meta, word) in metas.iter().zip(words.iter()) {
+            let distance: u32 = chosen.iter().map(|&(_, d)| d).sum();
+
+            for (&(meta, _), word) in chosen.iter().zip(words.iter()) {
                 if meta.rank() > 0 {
                     // TODO: Take all metas into account when searching.
-                    results.push((*item, word.as_ref(), *meta));
+                    let matches = chosen.iter().map(|&(m, _)| WordMatch::from_meta(m)).collect();
+                    results.push((*item, word.as_ref(), meta, distance, matches));
                     break
                 }
             }
         },
     );
 
-    results.sort_by_key(|&(_, word, meta)| {
+    // The ranking key of a result, lower is more relevant. We rank on integers
+    // rather than on the score, so results that differ in any of the components
+    // can't tie due to rounding.
+    let rank = |word: &str, meta: WordMeta, distance: u32| -> (u32, u32, u32) {
         let mut penalty = 0_i32;
 
         // Add a penalty quadratic in the excess word length. This way we still
@@ -370,11 +522,111 @@ pub fn search<'a, I: 'a + WordIndex, W: 'a + AsRef<str>>(
         // exact matches, but the latter leads to stabler results during
         // search-as-you type, because an extra character does not change the
         // penalty. Because the penalty should already take care of putting
-        // relevant results first, we go for the latter.
-        (penalty, -100 * meta.word_len() as i32 / meta.total_len() as i32)
-    });
+        // relevant results first, we go for the latter. The tie breaker is the
+        // remaining portion in units of 2^-16, it goes after the penalty in the
+        // key, so it cannot overrule a difference in penalty. Lengths are at
+        // most 255, so distinct portions differ by more than 2^-16, and they
+        // map to distinct units.
+        let total_len = meta.total_len().max(1);
+        let remainder = total_len.saturating_sub(meta.word_len());
+        let tie_breaker = (remainder << 16) / total_len;
+
+        // Fuzzy matches go after all exact matches regardless of penalty, the
+        // more edits were needed, the further down.
+        (distance, penalty as u32, tie_breaker)
+    };
+
+    // Map the ranking key into a score in (0.0, 1.0], for clients that merge
+    // results from different indexes. The score decreases with the key.
+    let score = |(distance, penalty, tie_breaker): (u32, u32, u32)| -> f32 {
+        let cost = penalty as f32 + (tie_breaker as f32 / 65536.0) * 0.99;
+        0.5_f32.powi(distance as i32) * (0.5 + 0.5 / (1.0 + cost))
+    };
+
+    let mut ranked: Vec<_> = results
+        .drain(..)
+        .map(|(item, word, meta, distance, matches)| {
+            let key = rank(word, meta, distance);
+            let result = SearchResult {
+                id: item,
+                score: score(key),
+                matches: matches,
+            };
+            (key, result)
+        })
+        .collect();
+
+    // Sort by ascending key, so the most relevant result goes first. The sort
+    // is stable, so equal keys remain in id order.
+    ranked.sort_by_key(|&(key, _)| key);
+
+    into.extend(ranked.into_iter().map(|(_, result)| result));
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use crate::word_index::{MemoryWordIndex, WordMeta};
+    use super::{SearchOptions, search};
+
+    fn build_index(titles: &[(u32, &str)]) -> MemoryWordIndex<u32> {
+        let mut elems = BTreeSet::new();
+        for &(id, title) in titles {
+            for (i, word) in title.split(' ').enumerate() {
+                let meta = WordMeta::new(word.len(), title.len(), i, 2);
+                elems.insert((word.to_string(), id, meta));
+            }
+        }
+        MemoryWordIndex::new(&elems)
+    }
+
+    #[test]
+    fn search_phrase_requires_adjacent_words_in_order() {
+        let index = build_index(&[
+            (1, "in rainbows"),
+            (2, "rainbows in the sky"),
+            (3, "in time in rainbows"),
+            (4, "in the rainbows"),
+        ]);
+        let words = ["in", "rainbows"];
+        let search_ids = |phrase: Option<std::ops::Range<usize>>| {
+            let phrases: Vec<_> = phrase.into_iter().collect();
+            let mut results = Vec::new();
+            search(&index, &words[..], &phrases[..], SearchOptions::exact(), &mut results);
+            let mut ids: Vec<u32> = results.iter().map(|r| r.id).collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(search_ids(None), vec![1, 2, 3, 4]);
+        assert_eq!(search_ids(Some(0..2)), vec![1, 3]);
+    }
+
+    #[test]
+    fn search_reports_match_positions() {
+        let index = build_index(&[(1, "in time in rainbows")]);
+        let mut results = Vec::new();
+        search(&index, &["rainbows", "in"][..], &[], SearchOptions::default(), &mut results);
+        assert_eq!(results.len(), 1);
+        let positions: Vec<(u8, u8)> = results[0].matches.iter().map(|m| (m.position, m.len)).collect();
+        assert_eq!(positions, vec![(3, 8), (0, 2)]);
+    }
 
-    for (item, _word, _meta) in results.drain(..) {
-        into.push(item);
+    #[test]
+    fn search_breaks_ties_on_portion_then_id() {
+        let index = build_index(&[
+            (1, "time after time"),
+            (2, "time is up"),
+            (3, "time out"),
+            (4, "time off"),
+        ]);
+        let mut results = Vec::new();
+        search(&index, &["time"][..], &[], SearchOptions::exact(), &mut results);
+        let ids: Vec<u32> = results.iter().map(|r| r.id).collect();
+        // All match at the first word, so the shortest titles go first, and
+        // titles of equal length remain in id order.
+        assert_eq!(ids, vec![3, 4, 2, 1]);
+        assert!(results.windows(2).all(|w| w[0].score >= w[1].score));
     }
 }
@@ -9,12 +9,22 @@
 
 use serde_json;
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 
+use crate::database as db;
+use crate::matcher::UnresolvedListen;
+use crate::mix::Favorites;
+use crate::playcount::RevNotNan;
 use crate::player::{Millibel, TrackSnapshot};
+use crate::runtime_stats::RuntimeCounts;
 use crate::scan;
-use crate::user_data::UserData;
+use crate::search::SearchResult;
+use crate::user_data::{LabelItems, LabelTarget, UserData};
+use crate::wrapped::YearReport;
+use crate::prim::Lufs;
 use crate::{Album, AlbumId, Artist, ArtistId, MetaIndex, TrackId};
 
 /// Write an album, but only with the album details, not its tracks.
@@ -45,29 +55,82 @@ pub fn write_brief_album_json<W: Write>(
         // to positive, it does not need a lot of precision. The trending score
         // is always between 0 and 1 though, it needs more digits for precision
         // near the end of the ranking.
-        r#","release_date":"{}","first_seen":"{}","discover_score":{:.2},"trending_score":{:.4}}}"#,
+        r#","release_date":"{}","first_seen":"{}","discover_score":{:.2},"trending_score":{:.4},"rating":{}}}"#,
         album.original_release_date,
         album.first_seen.format_iso8601(),
         scores.discover_score,
         scores.trending_score,
+        user_data.get_album_rating(album_id) as i8,
     )?;
     Ok(())
 }
 
-/// Write a json representation of the album list to the writer.
-pub fn write_albums_json<W: Write>(
-    index: &dyn MetaIndex,
-    user_data: &UserData,
-    mut w: W,
-) -> io::Result<()> {
-    write!(w, "[")?;
-    let mut first = true;
-    for kv in index.get_albums() {
-        if !first { write!(w, ",")?; }
-        write_brief_album_json(index, user_data, &mut w, kv.album_id, &kv.album)?;
-        first = false;
+/// Size of the chunks that `JsonArrayReader` serializes at a time.
+const JSON_CHUNK_LEN: usize = 16 * 1024;
+
+/// A response body that serializes the elements of a json array while it is read.
+///
+/// Serializing a long list up front holds all of the json in memory, and the
+/// client receives nothing until it is complete. This reader serializes only a
+/// chunk of elements at a time, when the server is ready to send more, so
+/// responses that have no known length can be sent with chunked encoding.
+pub struct JsonArrayReader<F> {
+    len: usize,
+    next: usize,
+    write_element: F,
+    buffer: Vec<u8>,
+    pos: usize,
+    is_open: bool,
+    is_closed: bool,
+}
+
+impl<F: FnMut(&mut Vec<u8>, usize) -> io::Result<()>> JsonArrayReader<F> {
+    /// Return a reader for an array of `len` elements.
+    ///
+    /// `write_element` writes the element at the given index.
+    pub fn new(len: usize, write_element: F) -> JsonArrayReader<F> {
+        JsonArrayReader {
+            len,
+            next: 0,
+            write_element,
+            buffer: Vec::with_capacity(JSON_CHUNK_LEN),
+            pos: 0,
+            is_open: false,
+            is_closed: false,
+        }
+    }
+
+    /// Serialize the next chunk of elements into the buffer.
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        self.pos = 0;
+        if !self.is_open {
+            self.buffer.push(b'[');
+            self.is_open = true;
+        }
+        while self.next < self.len && self.buffer.len() < JSON_CHUNK_LEN {
+            if self.next > 0 { self.buffer.push(b','); }
+            (self.write_element)(&mut self.buffer, self.next)?;
+            self.next += 1;
+        }
+        if self.next == self.len && !self.is_closed {
+            self.buffer.push(b']');
+            self.is_closed = true;
+        }
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&mut Vec<u8>, usize) -> io::Result<()>> Read for JsonArrayReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() {
+            self.fill_buffer()?;
+        }
+        let n = buf.len().min(self.buffer.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
     }
-    write!(w, "]")
 }
 
 /// Write a json representation of the album and its tracks to the writer.
@@ -92,7 +155,16 @@ pub fn write_album_json<W: Write>(
     }
     write!(w, r#"],"artist":"#)?;
     serde_json::to_writer(&mut w, index.get_string(album.artist))?;
-    write!(w, r#","release_date":"{}","tracks":["#, album.original_release_date)?;
+    write!(
+        w,
+        r#","release_date":"{}","rating":{},"labels":"#,
+        album.original_release_date,
+        user_data.get_album_rating(id) as i8,
+    )?;
+    serde_json::to_writer(&mut w, &user_data.get_target_labels(LabelTarget::Album(id)))?;
+    write!(w, r#","note":"#)?;
+    serde_json::to_writer(&mut w, &user_data.get_album_note(id))?;
+    write!(w, r#","tracks":["#)?;
     let mut first = true;
     for kv in index.get_album_tracks(id) {
         let track_id = kv.track_id;
@@ -109,10 +181,14 @@ pub fn write_album_json<W: Write>(
         serde_json::to_writer(&mut w, index.get_string(kv.track.artist))?;
         write!(
             w,
-            r#","duration_seconds":{},"rating":{}}}"#,
+            r#","duration_seconds":{},"rating":{},"labels":"#,
             kv.track.duration_seconds,
             user_data.get_track_rating(track_id) as i8,
         )?;
+        serde_json::to_writer(&mut w, &user_data.get_target_labels(LabelTarget::Track(track_id)))?;
+        write!(w, r#","note":"#)?;
+        serde_json::to_writer(&mut w, &user_data.get_track_note(track_id))?;
+        write!(w, "}}")?;
         first = false;
     }
     write!(w, "]}}")
@@ -123,6 +199,7 @@ pub fn write_artist_json<W: Write>(
     index: &dyn MetaIndex,
     user_data: &UserData,
     mut w: W,
+    artist_id: ArtistId,
     artist: &Artist,
     albums: &[(ArtistId, AlbumId)],
 ) -> io::Result<()> {
@@ -130,7 +207,7 @@ pub fn write_artist_json<W: Write>(
     serde_json::to_writer(&mut w, index.get_string(artist.name))?;
     write!(w, r#","sort_name":"#)?;
     serde_json::to_writer(&mut w, index.get_string(artist.name_for_sort))?;
-    write!(w, r#","albums":["#)?;
+    write!(w, r#","favorite":{},"albums":["#, user_data.is_favorite_artist(artist_id))?;
     let mut first = true;
     for &(_, album_id) in albums {
         // The unwrap is safe here, in the sense that if the index is
@@ -147,35 +224,59 @@ pub fn write_artist_json<W: Write>(
 pub fn write_search_results_json<W: Write>(
     index: &dyn MetaIndex,
     mut w: W,
-    artists: &[ArtistId],
-    albums: &[AlbumId],
-    tracks: &[TrackId],
+    artists: &[SearchResult<ArtistId>],
+    albums: &[SearchResult<AlbumId>],
+    tracks: &[SearchResult<TrackId>],
 ) -> io::Result<()> {
     write!(w, r#"{{"artists":["#)?;
     let mut first = true;
-    for &aid in artists {
+    for result in artists {
         if !first { write!(w, ",")?; }
-        write_search_artist_json(index, &mut w, aid)?;
+        write_search_artist_json(index, &mut w, result)?;
         first = false;
     }
     write!(w, r#"],"albums":["#)?;
     let mut first = true;
-    for &aid in albums {
+    for result in albums {
         if !first { write!(w, ",")?; }
-        write_search_album_json(index, &mut w, aid)?;
+        write_search_album_json(index, &mut w, result)?;
         first = false;
     }
     write!(w, r#"],"tracks":["#)?;
     let mut first = true;
-    for &tid in tracks {
+    for result in tracks {
         if !first { write!(w, ",")?; }
-        write_search_track_json(index, &mut w, tid)?;
+        write_search_track_json(index, &mut w, result)?;
         first = false;
     }
     write!(w, r#"]}}"#)
 }
 
-pub fn write_search_artist_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: ArtistId) -> io::Result<()> {
+/// Write the score and match locations of a search result.
+///
+/// Words of rank 2 are in the field `primary`, lower ranks are in `artist`.
+fn write_search_score_matches_json<W: Write, T>(
+    mut w: W,
+    result: &SearchResult<T>,
+    primary: &str,
+) -> io::Result<()> {
+    write!(w, r#""score":{},"matches":["#, result.score)?;
+    let mut first = true;
+    for m in &result.matches {
+        if !first { write!(w, ",")?; }
+        let field = if m.rank == 2 { primary } else { "artist" };
+        write!(
+            w,
+            r#"{{"field":"{}","position":{},"len":{}}}"#,
+            field, m.position, m.len,
+        )?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_search_artist_json<W: Write>(index: &dyn MetaIndex, mut w: W, result: &SearchResult<ArtistId>) -> io::Result<()> {
+    let id = result.id;
     let artist = index.get_artist(id).unwrap();
     let albums = index.get_albums_by_artist(id);
     write!(w, r#"{{"id":"{}","name":"#, id)?;
@@ -187,19 +288,24 @@ pub fn write_search_artist_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: A
         write!(w, r#""{}""#, album_id)?;
         first = false;
     }
-    write!(w, r#"]}}"#)
+    write!(w, "],")?;
+    write_search_score_matches_json(&mut w, result, "name")?;
+    write!(w, "}}")
 }
 
-pub fn write_search_album_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: AlbumId) -> io::Result<()> {
-    let album = index.get_album(id).unwrap();
-    write!(w, r#"{{"id":"{}","title":"#, id)?;
+pub fn write_search_album_json<W: Write>(index: &dyn MetaIndex, mut w: W, result: &SearchResult<AlbumId>) -> io::Result<()> {
+    let album = index.get_album(result.id).unwrap();
+    write!(w, r#"{{"id":"{}","title":"#, result.id)?;
     serde_json::to_writer(&mut w, index.get_string(album.title))?;
     write!(w, r#","artist":"#)?;
     serde_json::to_writer(&mut w, index.get_string(album.artist))?;
-    write!(w, r#","release_date":"{}"}}"#, album.original_release_date)
+    write!(w, r#","release_date":"{}","#, album.original_release_date)?;
+    write_search_score_matches_json(&mut w, result, "title")?;
+    write!(w, "}}")
 }
 
-pub fn write_search_track_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: TrackId) -> io::Result<()> {
+pub fn write_search_track_json<W: Write>(index: &dyn MetaIndex, mut w: W, result: &SearchResult<TrackId>) -> io::Result<()> {
+    let id = result.id;
     let track = index.get_track(id).unwrap();
     let album_id = id.album_id();
     let album = index.get_album(album_id).unwrap();
@@ -213,7 +319,77 @@ pub fn write_search_track_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: Tr
     serde_json::to_writer(&mut w, index.get_string(album.title))?;
     write!(w, r#","artist":"#)?;
     serde_json::to_writer(&mut w, index.get_string(track.artist))?;
-    write!(w, r#"}}"#)
+    write!(w, ",")?;
+    write_search_score_matches_json(&mut w, result, "title")?;
+    write!(w, "}}")
+}
+
+/// Write a track with its album title, as an element of a generated track list.
+///
+/// The track is expected to be in the index.
+pub fn write_track_json<W: Write>(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    mut w: W,
+    track_id: TrackId,
+) -> io::Result<()> {
+    let album_id = track_id.album_id();
+    let track = index.get_track(track_id).unwrap();
+    let album = index.get_album(album_id).unwrap();
+    write!(w, r#"{{"id":"{}","title":"#, track_id)?;
+    serde_json::to_writer(&mut w, index.get_string(track.title))?;
+    write!(w, r#","album_id":"{}","album":"#, album_id)?;
+    serde_json::to_writer(&mut w, index.get_string(album.title))?;
+    write!(w, r#","artist":"#)?;
+    serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+    write!(
+        w,
+        r#","release_date":"{}","duration_seconds":{},"rating":{},"note":"#,
+        album.original_release_date,
+        track.duration_seconds,
+        user_data.get_track_rating(track_id) as i8,
+    )?;
+    serde_json::to_writer(&mut w, &user_data.get_track_note(track_id))?;
+    write!(w, "}}")
+}
+
+/// Write a json array of tracks, for generated track lists such as mixes.
+pub fn write_tracks_json<W: Write>(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    mut w: W,
+    track_ids: &[TrackId],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for &track_id in track_ids {
+        if !first { write!(w, ",")?; }
+        write_track_json(index, user_data, &mut w, track_id)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the favorite tracks and albums, see [`crate::mix::favorites`].
+pub fn write_favorites_json<W: Write>(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    mut w: W,
+    favorites: &Favorites,
+) -> io::Result<()> {
+    write!(w, r#"{{"tracks":"#)?;
+    write_tracks_json(index, user_data, &mut w, &favorites.tracks)?;
+    write!(w, r#","albums":["#)?;
+    let mut first = true;
+    for &(mean_rating, album_id) in &favorites.albums {
+        if !first { write!(w, ",")?; }
+        let album = index.get_album(album_id).unwrap();
+        write!(w, r#"{{"mean_rating":{:.2},"album":"#, mean_rating)?;
+        write_brief_album_json(index, user_data, &mut w, album_id, album)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]}}")
 }
 
 fn write_queued_track_json<W: Write>(
@@ -253,11 +429,12 @@ fn write_queued_track_json<W: Write>(
     serde_json::to_writer(&mut w, index.get_string(track.artist))?;
     write!(
         w,
-        r#","release_date":"{}","duration_seconds":{},"rating":{}"#,
+        r#","release_date":"{}","duration_seconds":{},"rating":{},"note":"#,
         album.original_release_date,
         track.duration_seconds,
         user_data.get_track_rating(queued_track.track_id) as i8,
     )?;
+    serde_json::to_writer(&mut w, &user_data.get_track_note(queued_track.track_id))?;
 
     let position_seconds = queued_track.position_ms as f32 * 1e-3;
     let buffered_seconds = queued_track.buffered_ms as f32 * 1e-3;
@@ -287,29 +464,498 @@ pub fn write_volume_json<W: Write>(mut w: W, current_volume: Millibel) -> io::Re
     write!(w, r#"{{"volume_db":{:.02}}}"#, current_volume.0 as f32 * 0.01)
 }
 
+/// Write a chart as a json array of ids with their count.
+///
+/// `plays_per_week` returns the plays per week equivalent at every timescale.
+pub fn write_chart_json<W: Write, K: fmt::Display + Copy, F: Fn(K) -> [f32; 5]>(
+    mut w: W,
+    entries: &[(RevNotNan, K)],
+    plays_per_week: F,
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for (count, id) in entries {
+        if !first { write!(w, ",")?; }
+        let r = plays_per_week(*id);
+        write!(
+            w,
+            r#"{{"id":"{}","count":{:.4},"plays_per_week":[{:.4},{:.4},{:.4},{:.4},{:.4}]}}"#,
+            id, count.0, r[0], r[1], r[2], r[3], r[4],
+        )?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write similar artists as a json array, skipping artists that no longer exist.
+pub fn write_similar_artists_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    similar: &[(ArtistId, f32)],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for &(artist_id, similarity) in similar {
+        let artist = match index.get_artist(artist_id) {
+            Some(a) => a,
+            None => continue,
+        };
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, artist_id)?;
+        serde_json::to_writer(&mut w, index.get_string(artist.name))?;
+        write!(w, r#","similarity":{:.4}}}"#, similarity)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the artists with their id, name, and sort name.
+pub fn write_artists_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    artists: &[ArtistId],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for &artist_id in artists {
+        let artist = match index.get_artist(artist_id) {
+            Some(a) => a,
+            None => continue,
+        };
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, artist_id)?;
+        serde_json::to_writer(&mut w, index.get_string(artist.name))?;
+        write!(w, r#","sort_name":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(artist.name_for_sort))?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the note of a track or album as an object, the note can be null.
+pub fn write_note_json<W: Write>(mut w: W, note: Option<&str>) -> io::Result<()> {
+    write!(w, r#"{{"note":"#)?;
+    serde_json::to_writer(&mut w, &note)?;
+    write!(w, "}}")
+}
+
+/// Write the labels with the number of tracks and albums that carry them.
+pub fn write_labels_json<W: Write>(
+    mut w: W,
+    labels: &BTreeMap<String, LabelItems>,
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for (label, items) in labels {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"label":"#)?;
+        serde_json::to_writer(&mut w, label)?;
+        write!(w, r#","tracks":{},"albums":{}}}"#, items.tracks.len(), items.albums.len())?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the unresolved imported listens with their candidate tracks.
+pub fn write_unresolved_listens_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    listens: &[UnresolvedListen],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for listen in listens {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"started_at":{},"title":"#, listen.started_at)?;
+        serde_json::to_writer(&mut w, &listen.title)?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, &listen.track_artist)?;
+        write!(w, r#","album":"#)?;
+        serde_json::to_writer(&mut w, &listen.album)?;
+        write!(w, r#","candidates":["#)?;
+        let mut first_candidate = true;
+        for &(confidence, track_id) in &listen.candidates {
+            if !first_candidate { write!(w, ",")?; }
+            let album_id = track_id.album_id();
+            let track = index.get_track(track_id).unwrap();
+            let album = index.get_album(album_id).unwrap();
+            write!(w, r#"{{"id":"{}","confidence":{:.3},"title":"#, track_id, confidence)?;
+            serde_json::to_writer(&mut w, index.get_string(track.title))?;
+            write!(w, r#","album_id":"{}","album":"#, album_id)?;
+            serde_json::to_writer(&mut w, index.get_string(album.title))?;
+            write!(w, r#","artist":"#)?;
+            serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+            write!(w, "}}")?;
+            first_candidate = false;
+        }
+        write!(w, "]}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_year_report_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    report: &YearReport,
+) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"year":{},"listens":{},"total_seconds":{},"new_albums":{},"busiest_day":"#,
+        report.year,
+        report.n_listens,
+        report.total_seconds,
+        report.n_new_albums,
+    )?;
+    match report.busiest_day {
+        Some((day, n)) => write!(w, r#"{{"date":"{}","listens":{}}}"#, day, n)?,
+        None => write!(w, "null")?,
+    }
+
+    write!(w, r#","top_artists":["#)?;
+    let mut first = true;
+    for &(n, artist_id) in &report.top_artists {
+        let artist = index.get_artist(artist_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, artist_id)?;
+        serde_json::to_writer(&mut w, index.get_string(artist.name))?;
+        write!(w, r#","listens":{}}}"#, n)?;
+        first = false;
+    }
+
+    write!(w, r#"],"top_albums":["#)?;
+    let mut first = true;
+    for &(n, album_id) in &report.top_albums {
+        let album = index.get_album(album_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","title":"#, album_id)?;
+        serde_json::to_writer(&mut w, index.get_string(album.title))?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(album.artist))?;
+        write!(w, r#","listens":{}}}"#, n)?;
+        first = false;
+    }
+
+    write!(w, r#"],"top_tracks":["#)?;
+    let mut first = true;
+    for &(n, track_id) in &report.top_tracks {
+        let track = index.get_track(track_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","album_id":"{}","title":"#, track_id, track_id.album_id())?;
+        serde_json::to_writer(&mut w, index.get_string(track.title))?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+        write!(w, r#","listens":{}}}"#, n)?;
+        first = false;
+    }
+
+    write!(w, "]}}")
+}
+
+/// Write listens grouped per year, the listens must be ordered by year.
+pub fn write_listens_per_year_json<W: Write>(
+    mut w: W,
+    listens: &[db::ListenOnDay],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut year = None;
+    for listen in listens {
+        if year != Some(listen.year) {
+            if year.is_some() { write!(w, "]}},")?; }
+            write!(w, r#"{{"year":{},"listens":["#, listen.year)?;
+        } else {
+            write!(w, ",")?;
+        }
+        year = Some(listen.year);
+        write!(
+            w,
+            r#"{{"started_at":"{}","track_id":"{}","album_id":"{}","title":"#,
+            listen.started_at,
+            TrackId(listen.track_id as u64),
+            AlbumId(listen.album_id as u64),
+        )?;
+        serde_json::to_writer(&mut w, &listen.track_title)?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, &listen.track_artist)?;
+        write!(w, r#","album":"#)?;
+        serde_json::to_writer(&mut w, &listen.album_title)?;
+        write!(w, "}}")?;
+    }
+    if year.is_some() { write!(w, "]}}")?; }
+    write!(w, "]")
+}
+
+/// Write a page of recent listens, with metadata from the index where we can.
+///
+/// `next_before` is the value for the `before` parameter of the next page, if
+/// there may be one.
+pub fn write_listen_history_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    listens: &[db::ListenRecent],
+    next_before: Option<i64>,
+) -> io::Result<()> {
+    write!(w, r#"{{"listens":["#)?;
+    let mut first = true;
+    for listen in listens {
+        if !first { write!(w, ",")?; }
+        let track_id = TrackId(listen.track_id as u64);
+        let album_id = AlbumId(listen.album_id as u64);
+        write!(
+            w,
+            r#"{{"id":{},"started_at":"{}","completed_at":"#,
+            listen.id,
+            listen.started_at,
+        )?;
+        serde_json::to_writer(&mut w, &listen.completed_at)?;
+        write!(w, r#","skipped_at":"#)?;
+        serde_json::to_writer(&mut w, &listen.skipped_at)?;
+        write!(w, r#","track_id":"{}","album_id":"{}","title":"#, track_id, album_id)?;
+        // Prefer the current metadata, but fall back to the metadata as it was
+        // at the time of the listen when the track is no longer in the library.
+        match (index.get_track(track_id), index.get_album(album_id)) {
+            (Some(track), Some(album)) => {
+                serde_json::to_writer(&mut w, index.get_string(track.title))?;
+                write!(w, r#","album":"#)?;
+                serde_json::to_writer(&mut w, index.get_string(album.title))?;
+                write!(w, r#","artist":"#)?;
+                serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+                write!(w, r#","in_library":true"#)?;
+            }
+            _ => {
+                serde_json::to_writer(&mut w, &listen.track_title)?;
+                write!(w, r#","album":"#)?;
+                serde_json::to_writer(&mut w, &listen.album_title)?;
+                write!(w, r#","artist":"#)?;
+                serde_json::to_writer(&mut w, &listen.track_artist)?;
+                write!(w, r#","in_library":false"#)?;
+            }
+        }
+        write!(
+            w,
+            r#","duration_seconds":{},"source":"#,
+            listen.duration_seconds,
+        )?;
+        serde_json::to_writer(&mut w, &listen.source)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, r#"],"next_before":"#)?;
+    serde_json::to_writer(&mut w, &next_before)?;
+    write!(w, "}}")
+}
+
+/// Write the string with the characters that are special in XML escaped.
+fn write_xml_escaped<W: Write>(mut w: W, s: &str) -> io::Result<()> {
+    let mut rest = s;
+    while let Some(i) = rest.find(&['<', '>', '&', '"', '\''][..]) {
+        w.write_all(&rest.as_bytes()[..i])?;
+        let entity = match rest.as_bytes()[i] {
+            b'<' => "&lt;",
+            b'>' => "&gt;",
+            b'&' => "&amp;",
+            b'"' => "&quot;",
+            _ => "&apos;",
+        };
+        w.write_all(entity.as_bytes())?;
+        rest = &rest[i + 1..];
+    }
+    w.write_all(rest.as_bytes())
+}
+
+/// Write the tracks as an XSPF playlist, see <https://xspf.org/spec>.
+///
+/// Every track has two locations: the file on the server, which works for
+/// players on the same machine, and the track url of the API, which works for
+/// players elsewhere, relative to the host the playlist was served from.
+pub fn write_xspf<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    title: &str,
+    tracks: &[TrackId],
+) -> io::Result<()> {
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(w, r#"<playlist version="1" xmlns="http://xspf.org/ns/0/">"#)?;
+    write!(w, "  <title>")?;
+    write_xml_escaped(&mut w, title)?;
+    writeln!(w, "</title>")?;
+    writeln!(w, "  <trackList>")?;
+    for &track_id in tracks {
+        let track = match index.get_track(track_id) {
+            Some(t) => t,
+            None => continue,
+        };
+        let album_id = track_id.album_id();
+        let album = index.get_album(album_id).expect("Track must belong to album.");
+        writeln!(w, "    <track>")?;
+        if let Ok(file_url) = url::Url::from_file_path(index.get_filename(track.filename)) {
+            write!(w, "      <location>")?;
+            write_xml_escaped(&mut w, file_url.as_str())?;
+            writeln!(w, "</location>")?;
+        }
+        writeln!(w, "      <location>/api/track/{}.flac</location>", track_id)?;
+        write!(w, "      <title>")?;
+        write_xml_escaped(&mut w, index.get_string(track.title))?;
+        writeln!(w, "</title>")?;
+        write!(w, "      <creator>")?;
+        write_xml_escaped(&mut w, index.get_string(track.artist))?;
+        writeln!(w, "</creator>")?;
+        write!(w, "      <album>")?;
+        write_xml_escaped(&mut w, index.get_string(album.title))?;
+        writeln!(w, "</album>")?;
+        writeln!(w, "      <trackNum>{}</trackNum>", track_id.track_number())?;
+        writeln!(w, "      <duration>{}</duration>", track.duration_seconds as u64 * 1000)?;
+        writeln!(w, "      <image>/api/cover/{}</image>", album_id)?;
+        writeln!(w, "    </track>")?;
+    }
+    writeln!(w, "  </trackList>")?;
+    writeln!(w, "</playlist>")
+}
+
+pub fn write_playlists_json<W: Write>(
+    mut w: W,
+    smart: &[db::SmartPlaylist],
+    manual: &[db::Playlist],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for playlist in smart {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"kind":"smart","name":"#)?;
+        serde_json::to_writer(&mut w, &playlist.name)?;
+        write!(w, r#","query":"#)?;
+        serde_json::to_writer(&mut w, &playlist.query)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    for playlist in manual {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"kind":"manual","name":"#)?;
+        serde_json::to_writer(&mut w, &playlist.name)?;
+        write!(w, r#","track_count":{}}}"#, playlist.track_count)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_saved_queues_json<W: Write>(
+    mut w: W,
+    saved_queues: &[db::SavedQueue],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for saved_queue in saved_queues {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"name":"#)?;
+        serde_json::to_writer(&mut w, &saved_queue.name)?;
+        write!(w, r#","created_at":"#)?;
+        serde_json::to_writer(&mut w, &saved_queue.created_at)?;
+        write!(w, r#","track_count":{}}}"#, saved_queue.track_count)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write loudness as a number of LUFS, or null.
+fn write_loudness_json<W: Write>(mut w: W, loudness: Option<Lufs>) -> io::Result<()> {
+    match loudness {
+        Some(lufs) => write!(w, "{:.2}", (lufs.0.get() as f32) * 0.01),
+        None => write!(w, "null"),
+    }
+}
+
+/// Version of the format that `write_index_json` writes, see docs/running.md.
+const INDEX_JSON_VERSION: u32 = 1;
+
+/// Write all artists, albums, and tracks in the index, for `musium export-index`.
+///
+/// Unlike the API responses, this puts every artist, album, and track on its
+/// own line, so the output is easy to process with line-based tools too.
+pub fn write_index_json<W: Write>(index: &dyn MetaIndex, mut w: W) -> io::Result<()> {
+    write!(w, "{{\"version\":{},\n\"artists\":[", INDEX_JSON_VERSION)?;
+    let mut first = true;
+    for kv in index.get_artists() {
+        if !first { write!(w, ",")?; }
+        write!(w, "\n{{\"id\":\"{}\",\"name\":", kv.artist_id)?;
+        serde_json::to_writer(&mut w, index.get_string(kv.artist.name))?;
+        write!(w, r#","name_for_sort":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(kv.artist.name_for_sort))?;
+        write!(w, "}}")?;
+        first = false;
+    }
+
+    write!(w, "\n],\n\"albums\":[")?;
+    let mut first = true;
+    for kv in index.get_albums() {
+        let album = &kv.album;
+        if !first { write!(w, ",")?; }
+        write!(w, "\n{{\"id\":\"{}\",\"title\":", kv.album_id)?;
+        serde_json::to_writer(&mut w, index.get_string(album.title))?;
+        write!(w, r#","artist_ids":["#)?;
+        let mut first_artist = true;
+        for artist_id in index.get_album_artists(album.artist_ids) {
+            if !first_artist { write!(w, ",")?; }
+            write!(w, r#""{}""#, artist_id)?;
+            first_artist = false;
+        }
+        write!(w, r#"],"artist":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(album.artist))?;
+        write!(
+            w,
+            r#","release_date":"{}","first_seen":"{}","loudness_lufs":"#,
+            album.original_release_date,
+            album.first_seen.format_iso8601(),
+        )?;
+        write_loudness_json(&mut w, album.loudness)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+
+    write!(w, "\n],\n\"tracks\":[")?;
+    let mut first = true;
+    for kv in index.get_tracks() {
+        let track = &kv.track;
+        let track_id = kv.track_id;
+        if !first { write!(w, ",")?; }
+        write!(
+            w,
+            "\n{{\"id\":\"{}\",\"album_id\":\"{}\",\"disc_number\":{},\"track_number\":{},\"title\":",
+            track_id,
+            track_id.album_id(),
+            track_id.disc_number(),
+            track_id.track_number(),
+        )?;
+        serde_json::to_writer(&mut w, index.get_string(track.title))?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+        write!(w, r#","duration_seconds":{},"filename":"#, track.duration_seconds)?;
+        serde_json::to_writer(&mut w, index.get_filename(track.filename))?;
+        write!(w, r#","loudness_lufs":"#)?;
+        write_loudness_json(&mut w, track.loudness)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+
+    writeln!(w, "\n]}}")
+}
+
+pub fn write_radio_json<W: Write>(mut w: W, queue_len: Option<usize>) -> io::Result<()> {
+    match queue_len {
+        Some(n) => write!(w, r#"{{"enabled":true,"queue_len":{}}}"#, n),
+        None => write!(w, r#"{{"enabled":false,"queue_len":null}}"#),
+    }
+}
+
 pub fn write_scan_status_json<W: Write>(
     mut w: W,
     status_opt: Option<scan::Status>,
 ) -> io::Result<()> {
-    use scan::ScanStage;
     let status = match status_opt {
         None => return write!(w, "null"),
         Some(s) => s,
     };
 
-    let stage = match status.stage {
-        ScanStage::Discovering => "discovering",
-        ScanStage::PreProcessingMetadata => "preprocessing_metadata",
-        ScanStage::ExtractingMetadata => "extracting_metadata",
-        ScanStage::IndexingMetadata => "indexing_metadata",
-        ScanStage::PreProcessingLoudness => "preprocessing_loudness",
-        ScanStage::AnalyzingLoudness => "analyzing_loudness",
-        ScanStage::PreProcessingThumbnails => "preprocessing_thumbnails",
-        ScanStage::GeneratingThumbnails => "generating_thumbnails",
-        ScanStage::LoadingThumbnails => "loading_thumbnails",
-        ScanStage::Done => "done",
-    };
-
     write!(w,
         "{{\
         \"stage\":\"{}\",\
@@ -321,9 +967,9 @@ pub fn write_scan_status_json<W: Write>(
         \"albums_to_process_loudness\":{},\
         \"albums_processed_loudness\":{},\
         \"files_to_process_thumbnails\":{},\
-        \"files_processed_thumbnails\":{}\
-        }}",
-        stage,
+        \"files_processed_thumbnails\":{},\
+        \"stage_durations_ms\":{{",
+        status.stage.as_str(),
         status.files_discovered,
         status.files_to_process_metadata,
         status.files_processed_metadata,
@@ -333,6 +979,38 @@ pub fn write_scan_status_json<W: Write>(
         status.albums_processed_loudness,
         status.files_to_process_thumbnails,
         status.files_processed_thumbnails,
+    )?;
+    let mut first = true;
+    for (stage, duration) in status.completed_stage_durations() {
+        if !first { write!(w, ",")?; }
+        write!(w, "\"{}\":{}", stage.as_str(), duration.as_millis())?;
+        first = false;
+    }
+    write!(w, "}}}}")
+}
+
+/// Write the error counters of the player as json.
+pub fn write_runtime_stats_json<W: Write>(
+    counts: RuntimeCounts,
+    mut w: W,
+) -> io::Result<()> {
+    write!(w,
+        "{{\
+        \"decode_errors\":{},\
+        \"skipped_files\":{},\
+        \"device_reopens\":{},\
+        \"playback_errors\":{},\
+        \"history_write_failures\":{},\
+        \"history_restarts\":{},\
+        \"history_dropped_events\":{}\
+        }}",
+        counts.decode_errors,
+        counts.skipped_files,
+        counts.device_reopens,
+        counts.playback_errors,
+        counts.history_write_failures,
+        counts.history_restarts,
+        counts.history_dropped_events,
     )
 }
 
@@ -352,3 +1030,70 @@ pub fn write_stats_json<W: Write>(
         index.get_artists().len(),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use super::{JsonArrayReader, JSON_CHUNK_LEN};
+
+    /// Return a reader for `len` elements that are objects with padding, such
+    /// that a few hundred of them fill a chunk.
+    fn padded_reader(len: usize) -> impl Read {
+        JsonArrayReader::new(len, |w, i| write!(w, r#"{{"i":{},"pad":"{}"}}"#, i, "x".repeat(50)))
+    }
+
+    fn padded_json(len: usize) -> String {
+        let elements: Vec<_> = (0..len).map(|i| serde_json::json!({ "i": i, "pad": "x".repeat(50) })).collect();
+        serde_json::to_string(&elements).unwrap()
+    }
+
+    #[test]
+    fn json_array_reader_writes_empty_array() {
+        let mut json = String::new();
+        padded_reader(0).read_to_string(&mut json).unwrap();
+        assert_eq!(json, "[]");
+    }
+
+    #[test]
+    fn json_array_reader_spans_multiple_reads() {
+        let len = 1000;
+        let mut reader = padded_reader(len);
+        let mut json = Vec::new();
+        let mut buf = vec![0_u8; 4096];
+        let mut num_reads = 0;
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 { break }
+            json.extend_from_slice(&buf[..n]);
+            num_reads += 1;
+        }
+        assert!(json.len() > 2 * JSON_CHUNK_LEN);
+        assert!(num_reads > json.len() / buf.len());
+        assert_eq!(String::from_utf8(json).unwrap(), padded_json(len));
+    }
+
+    #[test]
+    fn json_array_reader_returns_zero_after_end() {
+        let mut reader = padded_reader(3);
+        let mut json = String::new();
+        reader.read_to_string(&mut json).unwrap();
+        assert_eq!(json, padded_json(3));
+
+        let mut buf = [0_u8; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn json_array_reader_fills_one_byte_buffer() {
+        let len = 400;
+        let mut reader = padded_reader(len);
+        let mut json = Vec::new();
+        let mut buf = [0_u8; 1];
+        while reader.read(&mut buf).unwrap() == 1 {
+            json.push(buf[0]);
+        }
+        assert_eq!(String::from_utf8(json).unwrap(), padded_json(len));
+    }
+}
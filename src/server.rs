@@ -5,29 +5,47 @@
 // you may not use this file except in compliance with the License.
 // A copy of the License has been included in the root of the repository.
 
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::fs;
 use std::io;
+use std::net::TcpListener;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use tiny_http::{Header, Request, Response, ResponseBox, Server};
-use tiny_http::Method::{Delete, Get, Post, Put, self};
+use log::{debug, error, info, warn};
+use tiny_http::{Header, Request, Response, ResponseBox, Server, StatusCode};
+use tiny_http::Method::{Delete, Get, Patch, Post, Put, self};
 
 use crate::config::Config;
 use crate::database_utils;
 use crate::database as db;
 use crate::database::Connection;
+use crate::expr::Expr;
+use crate::history;
+use crate::logger;
+use crate::matcher::{self, ImportSource};
+use crate::mix;
+use crate::mqtt;
 use crate::mvar::Var;
+use crate::playcount::{Chart, ExportFormat, PlayCounter, PlayCounts};
 use crate::player::{Millibel, Player, QueueId};
+use crate::playlist::{self, PlaylistEdit};
 use crate::prim::{ArtistId, AlbumId, TrackId};
-use crate::scan::BackgroundScanner;
+use crate::query::{self, Query};
+use crate::radio;
+use crate::scan::{BackgroundScanner, ScanStage};
+use crate::search::{LastWord, SearchOptions, SearchResult};
 use crate::serialization;
+use crate::shuffle;
 use crate::string_utils::normalize_words;
 use crate::systemd;
 use crate::thumb_cache::ThumbCache;
-use crate::user_data::{Rating, UserData};
+use crate::thumb_gen::{self, ThumbGenerator};
+use crate::user_data::{self, LabelTarget, Rating, UserData};
+use crate::wrapped::YearReport;
 use crate::{MetaIndex, MemoryMetaIndex};
 
 fn header_content_type(content_type: &str) -> Header {
@@ -44,52 +62,181 @@ fn header_expires_seconds(age_seconds: i64) -> Header {
         .expect("Failed to create content-type header, value is not ascii.")
 }
 
+/// Build a json response with a body that is serialized while it is sent.
+///
+/// The length is not known up front, so for HTTP/1.1 clients, the response
+/// uses chunked encoding.
+fn json_stream_response<R: io::Read + Send + 'static>(body: R) -> ResponseBox {
+    let headers = vec![header_content_type("application/json")];
+    Response::new(StatusCode(200), headers, body, None, None).boxed()
+}
+
+/// Return the machine-readable error code for an HTTP error status.
+fn error_code(status: u16) -> &'static str {
+    match status {
+        400 => "bad_request",
+        404 => "not_found",
+        _ => "internal_error",
+    }
+}
+
+/// Build the response for an error.
+///
+/// All errors go through here, so clients can rely on the body being a json
+/// object with a `code` that follows from the status, a human-readable
+/// `message`, and an optional `detail`.
+fn error_response(status: u16, message: &str, detail: Option<String>) -> ResponseBox {
+    let body = serde_json::json!({
+        "code": error_code(status),
+        "message": message,
+        "detail": detail,
+    });
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header_content_type("application/json"))
+        .boxed()
+}
+
+/// Return the value of the `X-Request-Id` header, if the request has a sensible one.
+///
+/// Clients can set the header to find the log messages for their request. We
+/// echo it in the response, and the logger includes it while we handle the
+/// request. To keep logs readable, we ignore long or non-printable values.
+fn request_id(request: &Request) -> Option<String> {
+    let header = request.headers().iter().find(|h| h.field.equiv("X-Request-Id"))?;
+    let id = header.value.as_str();
+    let is_valid = !id.is_empty()
+        && id.len() <= 64
+        && id.bytes().all(|b| b.is_ascii_graphic());
+    if is_valid { Some(id.to_string()) } else { None }
+}
+
+/// Keep the first `limit` search results that are in the playlist, and for
+/// which `matches` returns true, in order of relevance.
+fn retain_in_playlist<F>(
+    results: &mut Vec<SearchResult<TrackId>>,
+    playlist: &[TrackId],
+    limit: usize,
+    mut matches: F,
+) -> db::Result<()>
+where
+    F: FnMut(TrackId) -> db::Result<bool>,
+{
+    let in_playlist: HashSet<TrackId> = playlist.iter().cloned().collect();
+    query::retain_first(results, limit, |id| Ok(in_playlist.contains(&id) && matches(id)?))
+}
+
+/// Parse the query parameters shared by the search endpoints.
+fn parse_search_params(raw_query: &str) -> Result<(Query, SearchOptions), &'static str> {
+    let mut opt_query = None;
+    let mut options = SearchOptions::default();
+    for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+        match k.as_ref() {
+            "q" => opt_query = Some(v),
+            "fuzzy" => match v.as_ref() {
+                "true" => options.fuzzy = true,
+                "false" => options.fuzzy = false,
+                _ => return Err("Invalid fuzzy value, must be true or false."),
+            },
+            "prefix" => match v.as_ref() {
+                "true" => options.last_word = LastWord::Prefix,
+                "false" => options.last_word = LastWord::Exact,
+                _ => return Err("Invalid prefix value, must be true or false."),
+            },
+            _ => {}
+        }
+    };
+    let query = match opt_query {
+        Some(q) => q,
+        None => return Err("Missing search query."),
+    };
+    Ok((Query::parse(query.as_ref())?, options))
+}
+
 pub struct MetaServer {
-    config: Config,
+    config_var: Var<Config>,
     index_var: Var<MemoryMetaIndex>,
     thumb_cache_var: Var<ThumbCache>,
+    thumb_generator: ThumbGenerator,
     user_data: Arc<Mutex<UserData>>,
     player: Player,
     scanner: BackgroundScanner,
+
+    /// When the server last handled a request, or was last busy playing or
+    /// scanning, for `idle_exit_minutes`.
+    last_activity: Mutex<Instant>,
 }
 
 impl MetaServer {
     pub fn new(
-        config: Config,
+        config_var: Var<Config>,
         index_var: Var<MemoryMetaIndex>,
         thumb_cache_var: Var<ThumbCache>,
         user_data: Arc<Mutex<UserData>>,
         player: Player,
     ) -> MetaServer {
+        let thumb_generator = ThumbGenerator::new(
+            &config_var.get(),
+            index_var.clone(),
+            thumb_cache_var.clone(),
+        );
         MetaServer {
-            config: config,
+            config_var: config_var,
             index_var: index_var.clone(),
             thumb_cache_var: thumb_cache_var.clone(),
+            thumb_generator: thumb_generator,
             user_data: user_data,
             player: player,
             scanner: BackgroundScanner::new(
                 index_var,
                 thumb_cache_var,
             ),
+            last_activity: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Return whether the server has been idle for `idle_exit_minutes`.
+    ///
+    /// The server is idle when the queue is empty, no scan is running, and it
+    /// does not receive requests. If a post-idle program is configured, we
+    /// wait until it had the chance to run.
+    fn is_idle_for_exit(&self) -> bool {
+        let config = self.config_var.get();
+        let mut timeout = match config.idle_exit_minutes {
+            Some(minutes) => Duration::from_secs(minutes * 60),
+            None => return false,
+        };
+        if config.exec_post_idle_path.is_some() {
+            // The post-idle program runs for at most 30 seconds.
+            timeout = timeout.max(Duration::from_secs(config.idle_timeout_seconds + 60));
+        }
+
+        let mut last_activity = self.last_activity.lock().unwrap();
+        let is_playing = !self.player.get_queue().tracks.is_empty();
+        let is_scanning = matches!(self.scanner.get_status(), Some(status) if status.stage != ScanStage::Done);
+        if is_playing || is_scanning {
+            *last_activity = Instant::now();
+            return false
         }
+
+        last_activity.elapsed() >= timeout
     }
 
     fn handle_not_found(&self) -> ResponseBox {
-        Response::from_string("Not Found")
-            .with_status_code(404) // "404 Not Found"
-            .boxed()
+        error_response(404, "Not found.", None)
     }
 
     fn handle_bad_request(&self, reason: &'static str) -> ResponseBox {
-        Response::from_string(reason)
-            .with_status_code(400) // "400 Bad Request"
-            .boxed()
+        error_response(400, reason, None)
+    }
+
+    /// Respond with 400, with `detail` explaining what is wrong about the input.
+    fn handle_bad_request_detail(&self, reason: &'static str, detail: String) -> ResponseBox {
+        error_response(400, reason, Some(detail))
     }
 
     fn handle_error(&self, reason: &'static str) -> ResponseBox {
-        Response::from_string(reason)
-            .with_status_code(500) // "500 Internal Server Error"
-            .boxed()
+        error_response(500, reason, None)
     }
 
     fn handle_static_file(&self, fname: &str, mime_type: &str) -> ResponseBox {
@@ -123,20 +270,38 @@ impl MetaServer {
             Err(..) => return self.handle_error("Failed to open flac file."),
         };
 
-        if let Some(cover) = reader.into_pictures().pop() {
-            let content_type = header_content_type(&cover.mime_type);
-            let data = cover.into_vec();
-            Response::from_data(data)
-                .with_header(content_type)
-                .with_header(header_expires_seconds(3600 * 24 * 30))
-                .boxed()
+        // If the file has no embedded front cover, fall back to an image file
+        // next to it, or to the cover that we fetched from the Cover Art
+        // Archive, which is also what the thumbnail is made of.
+        let external_cover = || {
+            thumb_gen::find_cover_file(fname.as_ref()).or_else(|| {
+                let covers_path = self.config_var.get().thumb_covers_path.clone()?;
+                thumb_gen::find_fetched_cover(&covers_path, album_id)
+            })
+        };
+
+        let (mime_type, data) = if let Some(cover) = reader.into_pictures().pop() {
+            (cover.mime_type.clone(), cover.into_vec())
+        } else if let Some(path) = external_cover() {
+            let mime_type = match path.extension().and_then(|e| e.to_str()) {
+                Some(ext) if ext.eq_ignore_ascii_case("png") => "image/png",
+                _ => "image/jpeg",
+            };
+            match fs::read(&path) {
+                Ok(data) => (mime_type.to_string(), data),
+                Err(..) => return self.handle_error("Failed to read cover file."),
+            }
         } else {
-            // The file has no embedded front cover.
-            self.handle_not_found()
-        }
+            return self.handle_not_found()
+        };
+
+        Response::from_data(data)
+            .with_header(header_content_type(&mime_type))
+            .with_header(header_expires_seconds(3600 * 24 * 30))
+            .boxed()
     }
 
-    fn handle_thumb(&self, id: &str) -> ResponseBox {
+    fn handle_thumb(&self, db: &mut Connection, id: &str) -> ResponseBox {
         // TODO: DRY this track id parsing and loading part.
         let album_id = match AlbumId::parse(id) {
             Some(aid) => aid,
@@ -145,39 +310,63 @@ impl MetaServer {
 
         let thumb_cache = self.thumb_cache_var.get();
 
-        let img = match thumb_cache.get(album_id) {
-            None => return self.handle_not_found(),
-            Some(bytes) => bytes,
+        let img = match thumb_cache.get(db, album_id) {
+            Ok(None) => {
+                // The cover art may have been added since the last scan. If
+                // the generation succeeds, the next request will find it.
+                let is_known = self.index_var.get().get_album(album_id).is_some();
+                if is_known && self.config_var.get().thumb_generate_missing {
+                    self.thumb_generator.request(album_id);
+                }
+                return self.handle_not_found()
+            }
+            Ok(Some(bytes)) => bytes,
+            Err(err) => {
+                error!("Error while loading thumbnail: {:?}", err);
+                return self.handle_error("Database error.");
+            }
         };
 
-        Response::from_data(img)
-            .with_header(header_content_type("image/jpeg"))
-            .with_header(header_expires_seconds(3600 * 24 * 30))
-            .boxed()
+        let headers = vec![
+            header_content_type("image/jpeg"),
+            header_expires_seconds(3600 * 24 * 30),
+        ];
+        let len = img.len();
+        Response::new(StatusCode(200), headers, io::Cursor::new(img), Some(len), None).boxed()
     }
 
-    fn handle_waveform(&self, db: &mut Connection, id: &str) -> ResponseBox {
-        use crate::waveform::Waveform;
+    /// Return the waveform of the full track, or of one 10-second segment.
+    fn handle_waveform(&self, db: &mut Connection, id: &str, segment: Option<&str>) -> ResponseBox {
+        use crate::waveform::{Waveform, WaveformDetail};
 
         // TODO: DRY this track id parsing and loading part.
         let track_id = match TrackId::parse(id) {
             Some(tid) => tid,
             None => return self.handle_bad_request("Invalid track id."),
         };
+        let segment = match segment.map(usize::from_str) {
+            None => None,
+            Some(Ok(i)) => Some(i),
+            Some(Err(_)) => return self.handle_bad_request("Invalid segment, must be a number."),
+        };
 
         let waveform = db
             .begin()
             .and_then(|mut tx| {
-                let result = db::select_track_waveform(&mut tx, track_id.0 as i64)?;
+                let result = match segment {
+                    None => db::select_track_waveform(&mut tx, track_id.0 as i64)?.map(Waveform::from_bytes),
+                    Some(i) => db::select_track_waveform_detail(&mut tx, track_id.0 as i64)?
+                        .and_then(|data| WaveformDetail::from_bytes(data).segment(i)),
+                };
                 tx.commit()?;
                 Ok(result)
             });
 
         let waveform = match waveform {
-            Ok(Some(data)) => Waveform::from_bytes(data),
+            Ok(Some(waveform)) => waveform,
             Ok(None) => return self.handle_not_found(),
             Err(err) => {
-                eprintln!("Error while loading waveform: {:?}", err);
+                error!("Error while loading waveform: {:?}", err);
                 return self.handle_error("Database error.");
             }
         };
@@ -191,6 +380,41 @@ impl MetaServer {
             .boxed()
     }
 
+    fn handle_spectrogram(&self, db: &mut Connection, id: &str) -> ResponseBox {
+        use crate::spectrogram::Spectrogram;
+
+        let track_id = match TrackId::parse(id) {
+            Some(tid) => tid,
+            None => return self.handle_bad_request("Invalid track id."),
+        };
+
+        let spectrogram = db
+            .begin()
+            .and_then(|mut tx| {
+                let result = db::select_track_spectrogram(&mut tx, track_id.0 as i64)?;
+                tx.commit()?;
+                Ok(result)
+            });
+
+        let spectrogram = match spectrogram {
+            // The data is empty for tracks that are too short.
+            Ok(Some(data)) if !data.is_empty() => Spectrogram::from_bytes(data),
+            Ok(_) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading spectrogram: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let mut bmp = Vec::new();
+        spectrogram.write_bmp(&mut bmp).expect("Write to memory does not fail.");
+
+        Response::from_data(bmp)
+            .with_header(header_content_type("image/bmp"))
+            .with_header(header_expires_seconds(3600 * 24 * 30))
+            .boxed()
+    }
+
     fn handle_track(&self, path: &str) -> ResponseBox {
         // Track urls are of the form `/track/f7c153f2b16dc101.flac`.
         if !path.ends_with(".flac") {
@@ -272,6 +496,7 @@ impl MetaServer {
             index,
             &self.user_data.lock().unwrap(),
             &mut w,
+            artist_id,
             artist,
             albums,
         ).unwrap();
@@ -281,14 +506,23 @@ impl MetaServer {
             .boxed()
     }
 
-    fn handle_albums(&self) -> ResponseBox {
+    fn handle_artist_similar(&self, id: &str) -> ResponseBox {
+        let artist_id = match ArtistId::parse(id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid artist id."),
+        };
+
         let index = &*self.index_var.get();
+        if index.get_artist(artist_id).is_none() {
+            return self.handle_not_found();
+        }
+
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        serialization::write_albums_json(
+        serialization::write_similar_artists_json(
             index,
-            &self.user_data.lock().unwrap(),
             &mut w,
+            self.user_data.lock().unwrap().get_similar_artists(artist_id),
         ).unwrap();
 
         Response::from_data(w.into_inner())
@@ -296,6 +530,184 @@ impl MetaServer {
             .boxed()
     }
 
+    fn handle_artist_favorite(&self, id: &str, favorite: bool) -> ResponseBox {
+        let artist_id = match ArtistId::parse(id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid artist id."),
+        };
+
+        let index = &*self.index_var.get();
+        if index.get_artist(artist_id).is_none() {
+            return self.handle_not_found();
+        }
+
+        // As for ratings, the history thread writes to the database and
+        // updates the user data.
+        self.player.set_artist_favorite(artist_id, favorite);
+        Response::empty(202).boxed()
+    }
+
+    fn handle_favorite_artists(&self) -> ResponseBox {
+        let index = &*self.index_var.get();
+        let mut artists: Vec<ArtistId> = self
+            .user_data
+            .lock()
+            .unwrap()
+            .iter_favorite_artists()
+            .filter(|artist_id| index.get_artist(*artist_id).is_some())
+            .collect();
+        artists.sort_by_key(|artist_id| {
+            let artist = index.get_artist(*artist_id).expect("Filtered on presence above.");
+            (index.get_string(artist.name_for_sort), *artist_id)
+        });
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_artists_json(index, &mut w, &artists).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Parse the track or album id, and confirm that it exists.
+    fn parse_target(&self, kind: &str, id: &str) -> Result<LabelTarget, ResponseBox> {
+        let index = &*self.index_var.get();
+        let target = match kind {
+            "track" => match TrackId::parse(id) {
+                Some(tid) if index.get_track(tid).is_some() => LabelTarget::Track(tid),
+                Some(_) => return Err(self.handle_not_found()),
+                None => return Err(self.handle_bad_request("Invalid track id.")),
+            },
+            _ => match AlbumId::parse(id) {
+                Some(aid) if index.get_album(aid).is_some() => LabelTarget::Album(aid),
+                Some(_) => return Err(self.handle_not_found()),
+                None => return Err(self.handle_bad_request("Invalid album id.")),
+            },
+        };
+        Ok(target)
+    }
+
+    fn handle_set_label(&self, kind: &str, id: &str, label: &str, labeled: bool) -> ResponseBox {
+        if !user_data::is_valid_label(label) {
+            return self.handle_bad_request("Invalid label, use only a-z, 0-9, and dashes.");
+        }
+        let target = match self.parse_target(kind, id) {
+            Ok(target) => target,
+            Err(response) => return response,
+        };
+
+        // As for ratings, the history thread writes to the database and
+        // updates the user data.
+        self.player.set_label(label.to_string(), target, labeled);
+        Response::empty(202).boxed()
+    }
+
+    fn handle_get_note(&self, kind: &str, id: &str) -> ResponseBox {
+        let target = match self.parse_target(kind, id) {
+            Ok(target) => target,
+            Err(response) => return response,
+        };
+
+        let user_data = self.user_data.lock().unwrap();
+        let note = match target {
+            LabelTarget::Track(track_id) => user_data.get_track_note(track_id),
+            LabelTarget::Album(album_id) => user_data.get_album_note(album_id),
+        };
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_note_json(&mut w, note).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Set the note to the `text` query parameter, or remove it when `None`.
+    fn handle_set_note(&self, kind: &str, id: &str, raw_query: Option<&str>) -> ResponseBox {
+        let target = match self.parse_target(kind, id) {
+            Ok(target) => target,
+            Err(response) => return response,
+        };
+
+        let mut note = None;
+        if let Some(raw_query) = raw_query {
+            for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+                if k == "text" {
+                    note = Some(v.trim().to_string());
+                }
+            }
+            match note.as_ref() {
+                None => return self.handle_bad_request("Missing text parameter."),
+                Some(text) if text.is_empty() => return self.handle_bad_request("The note must not be empty."),
+                Some(text) if text.len() > user_data::MAX_NOTE_LEN => {
+                    return self.handle_bad_request("The note must be at most 1000 bytes.")
+                }
+                Some(_) => {}
+            }
+        }
+
+        // As for ratings, the history thread writes to the database and
+        // updates the user data.
+        match target {
+            LabelTarget::Track(track_id) => self.player.set_track_note(track_id, note),
+            LabelTarget::Album(album_id) => self.player.set_album_note(album_id, note),
+        }
+        Response::empty(202).boxed()
+    }
+
+    fn handle_labels(&self) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_labels_json(&mut w, self.user_data.lock().unwrap().get_labels()).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_label(&self, method: &Method, label: &str) -> ResponseBox {
+        let index_arc = self.index_var.get();
+        let index = &*index_arc;
+        let user_data = self.user_data.lock().unwrap();
+        let tracks = match user_data.get_labeled_tracks(index, label) {
+            Some(tracks) => tracks,
+            None => return self.handle_not_found(),
+        };
+
+        if method == &Post {
+            // Returning the queue takes the user data lock too.
+            drop(user_data);
+            for track_id in tracks {
+                self.player.enqueue(index, track_id);
+            }
+            return self.handle_queue();
+        }
+
+        self.handle_track_list(index_arc.clone(), tracks)
+    }
+
+    fn handle_albums(&self) -> ResponseBox {
+        let index = self.index_var.get();
+        let user_data = self.user_data.clone();
+        let body = serialization::JsonArrayReader::new(index.get_albums().len(), move |w, i| {
+            let kv = &index.get_albums()[i];
+            let user_data = user_data.lock().unwrap();
+            serialization::write_brief_album_json(&*index, &user_data, w, kv.album_id, &kv.album)
+        });
+        json_stream_response(body)
+    }
+
+    /// Respond with a json array of the tracks, which must be in the index.
+    ///
+    /// We serialize the tracks while we send them, so the response for a long
+    /// list does not have to fit in memory at once.
+    fn handle_track_list(&self, index: Arc<MemoryMetaIndex>, tracks: Vec<TrackId>) -> ResponseBox {
+        let user_data = self.user_data.clone();
+        let body = serialization::JsonArrayReader::new(tracks.len(), move |w, i| {
+            let user_data = user_data.lock().unwrap();
+            serialization::write_track_json(&*index, &user_data, w, tracks[i])
+        });
+        json_stream_response(body)
+    }
+
     fn handle_rating(&self, track_id: &str, rating_str: &str) -> ResponseBox {
         let rating = match i64::from_str(rating_str)
             .map_err(|_| "Failed to parse rating.")
@@ -326,6 +738,177 @@ impl MetaServer {
         Response::empty(202).boxed()
     }
 
+    fn handle_album_rating(&self, album_id: &str, rating_str: &str) -> ResponseBox {
+        let rating = match i64::from_str(rating_str)
+            .map_err(|_| "Failed to parse rating.")
+            .and_then(Rating::try_from)
+        {
+            Ok(r) => r,
+            Err(_) => return self.handle_bad_request("Invalid rating."),
+        };
+
+        let album_id = match AlbumId::parse(album_id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid album id."),
+        };
+
+        let index = &*self.index_var.get();
+        if index.get_album(album_id).is_none() {
+            return self.handle_not_found();
+        }
+
+        // As for track ratings, the history thread writes to the database and
+        // updates the user data.
+        self.player.set_album_rating(album_id, rating);
+        Response::empty(202).boxed()
+    }
+
+    /// Parse the listen id, and confirm that the listen exists.
+    fn parse_listen_id(&self, db: &mut Connection, listen_id: &str) -> Result<i64, ResponseBox> {
+        let listen_id = match i64::from_str(listen_id) {
+            Ok(id) => id,
+            Err(_) => return Err(self.handle_bad_request("Invalid listen id.")),
+        };
+        let exists = db.begin().and_then(|mut tx| {
+            let track_id = db::select_listen_track_id(&mut tx, listen_id)?;
+            tx.commit()?;
+            Ok(track_id.is_some())
+        });
+        match exists {
+            Ok(true) => Ok(listen_id),
+            Ok(false) => Err(self.handle_not_found()),
+            Err(err) => {
+                error!("Error while loading listen: {:?}", err);
+                Err(self.handle_error("Database error."))
+            }
+        }
+    }
+
+    fn handle_listen_delete(&self, db: &mut Connection, listen_id: &str) -> ResponseBox {
+        let listen_id = match self.parse_listen_id(db, listen_id) {
+            Ok(id) => id,
+            Err(response) => return response,
+        };
+
+        // Like for ratings, the history thread writes to the database, and
+        // updates the playcounts afterwards.
+        self.player.delete_listen(listen_id);
+        Response::empty(202).boxed()
+    }
+
+    fn handle_listen_correct(&self, db: &mut Connection, listen_id: &str, raw_query: &str) -> ResponseBox {
+        let mut track_id = None;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k == "track_id" {
+                match TrackId::parse(v.as_ref()) {
+                    Some(tid) => track_id = Some(tid),
+                    None => return self.handle_bad_request("Invalid track id."),
+                }
+            }
+        }
+        let track_id = match track_id {
+            Some(tid) => tid,
+            None => return self.handle_bad_request("Expected a track_id query parameter."),
+        };
+
+        if self.index_var.get().get_track(track_id).is_none() {
+            return self.handle_not_found();
+        }
+
+        let listen_id = match self.parse_listen_id(db, listen_id) {
+            Ok(id) => id,
+            Err(response) => return response,
+        };
+
+        self.player.correct_listen(listen_id, track_id);
+        Response::empty(202).boxed()
+    }
+
+    fn handle_import_unresolved(&self, db: &mut Connection, source: &str) -> ResponseBox {
+        let source = match ImportSource::parse(source) {
+            Some(s) => s,
+            None => return self.handle_bad_request("Invalid source, must be lastfm or listenbrainz."),
+        };
+
+        let index = &*self.index_var.get();
+        let min_confidence = self.config_var.get().match_min_confidence;
+        let unresolved = db.begin().map_err(crate::Error::from).and_then(|mut tx| {
+            let unresolved = matcher::list_unresolved(index, &mut tx, source, min_confidence)?;
+            tx.commit()?;
+            Ok(unresolved)
+        });
+        let unresolved = match unresolved {
+            Ok(u) => u,
+            Err(err) => {
+                error!("Error while matching listens: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_unresolved_listens_json(index, &mut w, &unresolved).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_import_resolve(
+        &self,
+        db: &mut Connection,
+        source: &str,
+        started_at: &str,
+        decision: &str,
+        raw_query: &str,
+    ) -> ResponseBox {
+        let source = match ImportSource::parse(source) {
+            Some(s) => s,
+            None => return self.handle_bad_request("Invalid source, must be lastfm or listenbrainz."),
+        };
+        let started_at = match i64::from_str(started_at) {
+            Ok(t) => t,
+            Err(_) => return self.handle_bad_request("Invalid started_at, must be seconds since epoch."),
+        };
+
+        let track_id = match decision {
+            "skip" => None,
+            _pick => {
+                let mut track_id = None;
+                for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+                    if k == "track_id" {
+                        match TrackId::parse(v.as_ref()) {
+                            Some(tid) => track_id = Some(tid),
+                            None => return self.handle_bad_request("Invalid track id."),
+                        }
+                    }
+                }
+                match track_id {
+                    Some(tid) if self.index_var.get().get_track(tid).is_some() => Some(tid),
+                    Some(_) => return self.handle_not_found(),
+                    None => return self.handle_bad_request("Expected a track_id query parameter."),
+                }
+            }
+        };
+
+        let exists = db.begin().and_then(|mut tx| {
+            let n = db::count_imported_listens_at(&mut tx, source.as_str(), started_at)?;
+            tx.commit()?;
+            Ok(n > 0)
+        });
+        match exists {
+            Ok(true) => {}
+            Ok(false) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading imported listen: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        }
+
+        // Like for corrections, the history thread does the writing.
+        self.player.resolve_match(source, started_at, track_id);
+        Response::empty(202).boxed()
+    }
+
     fn handle_queue(&self) -> ResponseBox {
         let index = &*self.index_var.get();
         let buffer = Vec::new();
@@ -342,6 +925,18 @@ impl MetaServer {
             .boxed()
     }
 
+    fn handle_queue_xspf(&self) -> ResponseBox {
+        let index = &*self.index_var.get();
+        let queue = self.player.get_queue();
+        let tracks: Vec<TrackId> = queue.tracks.iter().map(|t| t.track_id).collect();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_xspf(index, &mut w, "Queue", &tracks).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/xspf+xml"))
+            .boxed()
+    }
+
     fn handle_enqueue(&self, id: &str) -> ResponseBox {
         let track_id = match TrackId::parse(id) {
             Some(tid) => tid,
@@ -374,24 +969,473 @@ impl MetaServer {
         Response::empty(200).boxed()
     }
 
-    fn handle_queue_shuffle(&self) -> ResponseBox {
+    fn handle_queue_shuffle(&self, raw_query: &str) -> ResponseBox {
+        // The query parameters override the gaps from the config.
+        let mut config = self.config_var.get().shuffle;
+        let mut seed = None;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            let gap = match k.as_ref() {
+                "min_artist_gap" => &mut config.min_artist_gap,
+                "min_album_gap" => &mut config.min_album_gap,
+                "seed" => {
+                    match u64::from_str(v.as_ref()) {
+                        Ok(x) => seed = Some(x),
+                        Err(_) => return self.handle_bad_request("Invalid seed, must be a non-negative number."),
+                    }
+                    continue
+                }
+                _ => continue,
+            };
+            match v.parse() {
+                Ok(n) => *gap = n,
+                Err(_) => return self.handle_bad_request("Invalid gap, must be a non-negative number."),
+            }
+        }
+
         let index = &*self.index_var.get();
-        self.player.shuffle(index);
+        self.player.shuffle(index, &config, seed);
         self.handle_queue()
     }
 
-    fn handle_queue_clear(&self) -> ResponseBox {
-        self.player.clear_queue();
+    fn handle_queue_shuffle_albums(&self, raw_query: &str) -> ResponseBox {
+        let mut tracks_per_album = None;
+        let mut seed = None;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "tracks_per_album" => match v.parse() {
+                    Ok(n) if n > 0 => tracks_per_album = Some(n),
+                    _ => return self.handle_bad_request("Invalid tracks_per_album, must be a positive number."),
+                },
+                "seed" => match u64::from_str(v.as_ref()) {
+                    Ok(x) => seed = Some(x),
+                    Err(_) => return self.handle_bad_request("Invalid seed, must be a non-negative number."),
+                },
+                _ => continue,
+            }
+        }
+
+        let index = &*self.index_var.get();
+        self.player.shuffle_albums(index, tracks_per_album, seed);
         self.handle_queue()
     }
 
-    fn handle_get_volume(&self) -> ResponseBox {
-        let buffer = Vec::new();
-        let mut w = io::Cursor::new(buffer);
-        let volume = self.player.get_volume();
-        serialization::write_volume_json(&mut w, volume).unwrap();
-        Response::from_data(w.into_inner())
-            .with_header(header_content_type("application/json"))
+    fn handle_queue_shuffle_all(&self, raw_query: &str) -> ResponseBox {
+        let mut limit = 500;
+        let mut weighted = false;
+        let mut seed = None;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "seed" => match u64::from_str(v.as_ref()) {
+                    Ok(x) => seed = Some(x),
+                    Err(_) => return self.handle_bad_request("Invalid seed, must be a non-negative number."),
+                },
+                "limit" => match usize::from_str(v.as_ref()) {
+                    Ok(n) if n > 0 => limit = n,
+                    _ => return self.handle_bad_request("Invalid limit, must be a positive number."),
+                },
+                "weighted" => match v.as_ref() {
+                    "true" => weighted = true,
+                    "false" => weighted = false,
+                    _ => return self.handle_bad_request("Invalid weighted, must be true or false."),
+                },
+                _ => continue,
+            }
+        }
+
+        let config = self.config_var.get();
+        let index = &*self.index_var.get();
+        let mut rng = seed.map_or_else(shuffle::Prng::new, shuffle::Prng::new_seed);
+        let mut tracks = radio::sample_library(
+            index,
+            &self.user_data.lock().unwrap(),
+            &mut rng,
+            limit,
+            weighted,
+            config.favorite_artist_boost,
+        );
+
+        // The sample is in random order already, but the shuffle also spreads
+        // out artists and albums.
+        shuffle::shuffle(&shuffle::TrackIdShuffler(index), &mut rng, &mut tracks, &config.shuffle);
+        for track_id in tracks {
+            self.player.enqueue(index, track_id);
+        }
+        self.handle_queue()
+    }
+
+    fn handle_queue_skip(&self) -> ResponseBox {
+        let index = &*self.index_var.get();
+        self.player.skip(index);
+        self.handle_queue()
+    }
+
+    fn handle_queue_clear(&self) -> ResponseBox {
+        self.player.clear_queue();
+        self.handle_queue()
+    }
+
+    fn handle_saved_queues(&self, db: &mut Connection) -> ResponseBox {
+        let saved_queues = db.begin().and_then(|mut tx| {
+            let mut saved_queues = Vec::new();
+            for saved_queue in db::iter_saved_queues(&mut tx)? {
+                saved_queues.push(saved_queue?);
+            }
+            tx.commit()?;
+            Ok(saved_queues)
+        });
+        let saved_queues = match saved_queues {
+            Ok(qs) => qs,
+            Err(err) => {
+                error!("Error while loading saved queues: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_saved_queues_json(&mut w, &saved_queues).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_queue_save(&self, raw_query: &str) -> ResponseBox {
+        let mut name = None;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k.as_ref() == "name" {
+                name = Some(v.into_owned());
+            }
+        }
+        let name = match name {
+            Some(name) if playlist::is_valid_name(&name) => name,
+            Some(_) => return self.handle_bad_request("Invalid name, use lowercase letters, digits, and dashes."),
+            None => return self.handle_bad_request("Missing name parameter."),
+        };
+
+        // The history thread writes the saved queue to the database.
+        self.player.save_queue(name);
+        Response::empty(202).boxed()
+    }
+
+    fn handle_queue_restore(&self, db: &mut Connection, name: &str) -> ResponseBox {
+        let tracks = db.begin().and_then(|mut tx| {
+            let id = match db::select_saved_queue_id(&mut tx, name)? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            let mut tracks = Vec::new();
+            for entry in db::iter_saved_queue_tracks(&mut tx, id)? {
+                tracks.push(TrackId(entry?.track_id as u64));
+            }
+            tx.commit()?;
+            Ok(Some(tracks))
+        });
+        let mut tracks = match tracks {
+            Ok(Some(ts)) => ts,
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading saved queue: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let index = &*self.index_var.get();
+        // Tracks may have disappeared from the library since the user saved
+        // the queue.
+        tracks.retain(|&t| index.get_track(t).is_some());
+        self.player.replace_queue(index, &tracks);
+        self.handle_queue()
+    }
+
+    fn handle_saved_queue_delete(&self, name: &str) -> ResponseBox {
+        self.player.delete_saved_queue(name.to_string());
+        Response::empty(202).boxed()
+    }
+
+    /// Return the tracks of a generated mix, or enqueue them if `enqueue` is true.
+    fn handle_mix<F>(&self, method: &Method, raw_query: &str, make_mix: F) -> ResponseBox
+    where
+        F: FnOnce(&MemoryMetaIndex, &UserData, usize) -> Vec<TrackId>,
+    {
+        let mut n = 50;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k.as_ref() == "n" {
+                match usize::from_str(v.as_ref()) {
+                    Ok(x) => n = x,
+                    Err(_) => return self.handle_bad_request("Invalid n, must be a number."),
+                }
+            }
+        }
+
+        let index_arc = self.index_var.get();
+        let index = &*index_arc;
+        let tracks = make_mix(index, &self.user_data.lock().unwrap(), n);
+
+        if method == &Post {
+            for track_id in tracks {
+                self.player.enqueue(index, track_id);
+            }
+            return self.handle_queue();
+        }
+
+        self.handle_track_list(index_arc.clone(), tracks)
+    }
+
+    fn handle_playlists(&self, db: &mut Connection) -> ResponseBox {
+        let playlists = db.begin().and_then(|mut tx| {
+            let mut smart = Vec::new();
+            for playlist in db::iter_smart_playlists(&mut tx)? {
+                smart.push(playlist?);
+            }
+            let mut manual = Vec::new();
+            for playlist in db::iter_playlists(&mut tx)? {
+                manual.push(playlist?);
+            }
+            tx.commit()?;
+            Ok((smart, manual))
+        });
+        let (smart, manual) = match playlists {
+            Ok(ps) => ps,
+            Err(err) => {
+                error!("Error while loading playlists: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_playlists_json(&mut w, &smart, &manual).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Return the tracks of the smart or manual playlist, at most the number
+    /// in the optional query parameter `n`.
+    fn load_playlist(
+        &self,
+        db: &mut Connection,
+        index: &MemoryMetaIndex,
+        user_data: &UserData,
+        name: &str,
+        raw_query: &str,
+    ) -> Result<Vec<TrackId>, ResponseBox> {
+        let mut n = usize::MAX;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k.as_ref() == "n" {
+                match usize::from_str(v.as_ref()) {
+                    Ok(x) => n = x,
+                    Err(_) => return Err(self.handle_bad_request("Invalid n, must be a number.")),
+                }
+            }
+        }
+
+        let tracks = db.begin().and_then(|mut tx| {
+            let tracks = match playlist::load(&mut tx, name)? {
+                Some(Ok(query)) => Some(Ok(playlist::evaluate(index, user_data, &mut tx, &query, n)?)),
+                Some(Err(msg)) => Some(Err(msg)),
+                None => playlist::load_tracks(&mut tx, name)?.map(|mut tracks| {
+                    // Tracks may have disappeared from the library since the
+                    // user added them.
+                    tracks.retain(|&t| index.get_track(t).is_some());
+                    tracks.truncate(n);
+                    Ok(tracks)
+                }),
+            };
+            tx.commit()?;
+            Ok(tracks)
+        });
+        match tracks {
+            Ok(Some(Ok(ts))) => Ok(ts),
+            Ok(Some(Err(msg))) => Err(self.handle_bad_request_detail("Invalid playlist expression.", msg)),
+            Ok(None) => Err(self.handle_not_found()),
+            Err(err) => {
+                error!("Error while loading playlist: {:?}", err);
+                Err(self.handle_error("Database error."))
+            }
+        }
+    }
+
+    fn handle_playlist(
+        &self,
+        db: &mut Connection,
+        method: &Method,
+        name: &str,
+        raw_query: &str,
+    ) -> ResponseBox {
+        let index_arc = self.index_var.get();
+        let index = &*index_arc;
+        let user_data = self.user_data.lock().unwrap();
+        let tracks = match self.load_playlist(db, index, &user_data, name, raw_query) {
+            Ok(tracks) => tracks,
+            Err(response) => return response,
+        };
+
+        if method == &Post {
+            // Returning the queue takes the user data lock too.
+            drop(user_data);
+            self.player.enqueue_all(index, &tracks);
+            return self.handle_queue();
+        }
+
+        self.handle_track_list(index_arc.clone(), tracks)
+    }
+
+    fn handle_playlist_xspf(&self, db: &mut Connection, name: &str, raw_query: &str) -> ResponseBox {
+        let index = &*self.index_var.get();
+        let user_data = self.user_data.lock().unwrap();
+        let tracks = match self.load_playlist(db, index, &user_data, name, raw_query) {
+            Ok(tracks) => tracks,
+            Err(response) => return response,
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_xspf(index, &mut w, name, &tracks).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/xspf+xml"))
+            .boxed()
+    }
+
+    fn handle_query(&self, db: &mut Connection, raw_query: &str) -> ResponseBox {
+        let mut expr = None;
+        let mut n = usize::MAX;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "expr" => expr = Some(v.into_owned()),
+                "n" => match usize::from_str(v.as_ref()) {
+                    Ok(x) => n = x,
+                    Err(_) => return self.handle_bad_request("Invalid n, must be a number."),
+                },
+                _ => {}
+            }
+        }
+        let expr = match expr.as_deref().map(Expr::parse) {
+            Some(Ok(expr)) => expr,
+            Some(Err(err)) => return self.handle_bad_request_detail("Invalid expression.", err.to_string()),
+            None => return self.handle_bad_request("Missing expr parameter."),
+        };
+
+        let index_arc = self.index_var.get();
+        let index = &*index_arc;
+        let user_data = self.user_data.lock().unwrap();
+        let definition = playlist::Definition::Expr(expr);
+        let tracks = db.begin().and_then(|mut tx| {
+            let tracks = playlist::evaluate(index, &user_data, &mut tx, &definition, n)?;
+            tx.commit()?;
+            Ok(tracks)
+        });
+        let tracks = match tracks {
+            Ok(ts) => ts,
+            Err(err) => {
+                error!("Error while evaluating expression: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        self.handle_track_list(index_arc.clone(), tracks)
+    }
+
+    fn handle_playlist_create(&self, db: &mut Connection, name: &str) -> ResponseBox {
+        if !playlist::is_valid_name(name) {
+            return self.handle_bad_request("Invalid name, use lowercase letters, digits, and dashes.");
+        }
+        let smart_query = db.begin().and_then(|mut tx| {
+            let query = db::select_smart_playlist_query(&mut tx, name)?;
+            tx.commit()?;
+            Ok(query)
+        });
+        match smart_query {
+            Ok(None) => {}
+            Ok(Some(_)) => return self.handle_bad_request("A smart playlist with that name exists."),
+            Err(err) => {
+                error!("Error while loading smart playlist: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        }
+        self.player.edit_playlist(name.to_string(), PlaylistEdit::Create);
+        Response::empty(202).boxed()
+    }
+
+    fn handle_playlist_insert(&self, name: &str, track_id: &str, raw_query: &str) -> ResponseBox {
+        let track_id = match TrackId::parse(track_id) {
+            Some(tid) => tid,
+            None => return self.handle_bad_request("Invalid track id."),
+        };
+        if self.index_var.get().get_track(track_id).is_none() {
+            return self.handle_not_found();
+        }
+
+        let mut position = None;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k.as_ref() == "position" {
+                match u32::from_str(v.as_ref()) {
+                    Ok(p) => position = Some(p),
+                    Err(_) => return self.handle_bad_request("Invalid position, must be a number."),
+                }
+            }
+        }
+
+        self.player.edit_playlist(name.to_string(), PlaylistEdit::Insert { track_id, position });
+        Response::empty(202).boxed()
+    }
+
+    fn handle_playlist_delete(&self, name: &str) -> ResponseBox {
+        self.player.edit_playlist(name.to_string(), PlaylistEdit::Delete);
+        Response::empty(202).boxed()
+    }
+
+    fn handle_playlist_remove(&self, name: &str, position: &str) -> ResponseBox {
+        let position = match u32::from_str(position) {
+            Ok(p) => p,
+            Err(_) => return self.handle_bad_request("Invalid position, must be a number."),
+        };
+        self.player.edit_playlist(name.to_string(), PlaylistEdit::Remove { position });
+        Response::empty(202).boxed()
+    }
+
+    fn handle_playlist_move(&self, name: &str, from: &str, raw_query: &str) -> ResponseBox {
+        let from = match u32::from_str(from) {
+            Ok(p) => p,
+            Err(_) => return self.handle_bad_request("Invalid position, must be a number."),
+        };
+        let mut to = None;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k.as_ref() == "to" {
+                match u32::from_str(v.as_ref()) {
+                    Ok(p) => to = Some(p),
+                    Err(_) => return self.handle_bad_request("Invalid to, must be a number."),
+                }
+            }
+        }
+        let to = match to {
+            Some(p) => p,
+            None => return self.handle_bad_request("Missing to parameter."),
+        };
+        self.player.edit_playlist(name.to_string(), PlaylistEdit::Move { from, to });
+        Response::empty(202).boxed()
+    }
+
+    fn handle_favorites(&self) -> ResponseBox {
+        let index = &*self.index_var.get();
+        let user_data = self.user_data.lock().unwrap();
+        let favorites = mix::favorites(index, &user_data);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_favorites_json(index, &user_data, &mut w, &favorites).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_get_volume(&self) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let volume = self.player.get_volume();
+        serialization::write_volume_json(&mut w, volume).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
             .boxed()
     }
 
@@ -405,47 +1449,105 @@ impl MetaServer {
             .boxed()
     }
 
-    fn handle_search(&self, raw_query: &str) -> ResponseBox {
-        let mut opt_query = None;
+    fn handle_get_radio(&self) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let queue_len = self.player.get_radio();
+        serialization::write_radio_json(&mut w, queue_len).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_radio_enable(&self, raw_query: &str) -> ResponseBox {
+        let mut queue_len = 5;
         for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
-            if k == "q" {
-                opt_query = Some(v);
+            if k.as_ref() == "queue_len" {
+                match usize::from_str(v.as_ref()) {
+                    Ok(n) if n > 0 => queue_len = n,
+                    _ => return self.handle_bad_request("Invalid queue_len, must be a positive number."),
+                }
             }
-        };
-        let query = match opt_query {
-            Some(q) => q,
-            None => return self.handle_bad_request("Missing search query."),
+        }
+        let index = &*self.index_var.get();
+        self.player.set_radio(index, &self.user_data, Some(queue_len));
+        self.handle_get_radio()
+    }
+
+    fn handle_radio_disable(&self) -> ResponseBox {
+        let index = &*self.index_var.get();
+        self.player.set_radio(index, &self.user_data, None);
+        self.handle_get_radio()
+    }
+
+    fn handle_search(&self, db: &mut Connection, raw_query: &str) -> ResponseBox {
+        let (query, options) = match parse_search_params(raw_query) {
+            Ok(params) => params,
+            Err(msg) => return self.handle_bad_request(msg),
         };
 
         let mut words = Vec::new();
-        normalize_words(query.as_ref(), &mut words);
+        let mut phrases = Vec::new();
+        query.words(&mut words, &mut phrases);
 
         let mut artists = Vec::new();
         let mut albums = Vec::new();
         let mut tracks = Vec::new();
 
         let index = &*self.index_var.get();
-        index.search_artist(&words[..], &mut artists);
-        index.search_album(&words[..], &mut albums);
-        index.search_track(&words[..], &mut tracks);
+        if words.is_empty() && !query.filters.is_empty() {
+            // A query with only filters, like "year:1999", has no words to look
+            // up, so the filters apply to the entire collection.
+            artists.extend(index.get_artists().iter().map(|a| SearchResult::unscored(a.artist_id)));
+            albums.extend(index.get_albums().iter().map(|a| SearchResult::unscored(a.album_id)));
+            tracks.extend(index.get_tracks().iter().map(|t| SearchResult::unscored(t.track_id)));
+        } else {
+            index.search_artist(&words[..], &phrases[..], options, &mut artists);
+            index.search_album(&words[..], &phrases[..], options, &mut albums);
+            index.search_track(&words[..], &phrases[..], options, &mut tracks);
+        }
 
         // Cap the number of search results we serve. We can easily produce many
         // many results (especially when searching for "t", a prefix of "the",
         // or when searching "a"). Searching is quite fast, but parsing and
         // rendering the results in the frontend is slow, and having this many
-        // results is not useful anyway, so we cap them.
-        let n_artists = artists.len().min(250);
-        let n_albums = albums.len().min(250);
-        let n_tracks = tracks.len().min(250);
+        // results is not useful anyway, so we cap them. The filters are applied
+        // in order of descending score, so we only evaluate them until we have
+        // enough results. Without filters, there is no need to open a
+        // transaction, which is the common case while typing.
+        let limit = 250;
+        let filter_result = match query.filters.is_empty() {
+            true => {
+                artists.truncate(limit);
+                albums.truncate(limit);
+                tracks.truncate(limit);
+                Ok(())
+            }
+            false => {
+                let user_data = &*self.user_data.lock().unwrap();
+                db.begin().and_then(|mut tx| {
+                    query::retain_first(&mut artists, limit, |id| query.matches_artist(index, user_data, &mut tx, id))?;
+                    query::retain_first(&mut albums, limit, |id| query.matches_album(index, user_data, &mut tx, id))?;
+                    query::retain_first(&mut tracks, limit, |id| query.matches_track(index, user_data, &mut tx, id))?;
+                    tx.commit()?;
+                    Ok(())
+                })
+            }
+        };
+
+        if let Err(err) = filter_result {
+            error!("Error while applying search filters: {:?}", err);
+            return self.handle_error("Database error.");
+        }
 
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
         serialization::write_search_results_json(
             index,
             &mut w,
-            &artists[..n_artists],
-            &albums[..n_albums],
-            &tracks[..n_tracks],
+            &artists,
+            &albums,
+            &tracks,
         ).unwrap();
 
         Response::from_data(w.into_inner())
@@ -454,6 +1556,126 @@ impl MetaServer {
             .boxed()
     }
 
+    /// Search the tracks of a smart or manual playlist.
+    fn handle_playlist_search(&self, db: &mut Connection, name: &str, raw_query: &str) -> ResponseBox {
+        // Like in the main search, the last word matches as a prefix by
+        // default, because this is for search-as-you-type too. Only evaluating
+        // the expression of a smart playlist matches words exactly.
+        let (query, options) = match parse_search_params(raw_query) {
+            Ok(params) => params,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let mut words = Vec::new();
+        let mut phrases = Vec::new();
+        query.words(&mut words, &mut phrases);
+
+        let index = &*self.index_var.get();
+        let user_data = &*self.user_data.lock().unwrap();
+        // The `n` parameter of the search query does not apply to the
+        // playlist, we search all of its tracks.
+        let playlist_tracks = match self.load_playlist(db, index, user_data, name, "") {
+            Ok(tracks) => tracks,
+            Err(response) => return response,
+        };
+
+        let mut tracks = Vec::new();
+        if words.is_empty() && !query.filters.is_empty() {
+            // With only filters, the filters apply to the entire playlist.
+            tracks.extend(playlist_tracks.iter().map(|&id| SearchResult::unscored(id)));
+        } else {
+            index.search_track(&words[..], &phrases[..], options, &mut tracks);
+        }
+
+        let limit = 250;
+        let filter_result = db
+            .begin()
+            .and_then(|mut tx| {
+                retain_in_playlist(&mut tracks, &playlist_tracks, limit, |id| {
+                    query.matches_track(index, user_data, &mut tx, id)
+                })?;
+                tx.commit()?;
+                Ok(())
+            });
+
+        if let Err(err) = filter_result {
+            error!("Error while applying search filters: {:?}", err);
+            return self.handle_error("Database error.");
+        }
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_search_results_json(index, &mut w, &[], &[], &tracks).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_status_code(200)
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_search_suggest(&self, raw_query: &str) -> ResponseBox {
+        let mut opt_query = None;
+        let mut n = 10;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "q" => opt_query = Some(v),
+                "n" => match usize::from_str(v.as_ref()) {
+                    Ok(x) => n = x,
+                    Err(_) => return self.handle_bad_request("Invalid n, must be a number."),
+                },
+                _ => {}
+            }
+        };
+        let query = match opt_query {
+            Some(q) => q,
+            None => return self.handle_bad_request("Missing search query."),
+        };
+
+        let mut words = Vec::new();
+        normalize_words(query.as_ref(), &mut words);
+
+        // We complete the last word of the query, which is the one being typed.
+        // A single character is a prefix of a large part of the index, and
+        // suggestions for it are hardly useful, so we wait for a longer prefix.
+        // We also bound the number of words we consider, so we don't weigh
+        // thousands of occurrences while holding the user data lock.
+        let index = &*self.index_var.get();
+        let mut completion_words = Vec::new();
+        let mut completions = Vec::new();
+        if let Some(prefix) = words.last().filter(|w| w.chars().count() >= 2) {
+            index.search_completions(prefix, 100, &mut completion_words, &mut completions);
+        }
+
+        // Weigh every completion by how often it occurs, but occurrences in
+        // albums that we listen to a lot weigh more. Completions are ordered by
+        // word, so equal words are adjacent.
+        let mut weighted: Vec<(f32, &str)> = Vec::new();
+        {
+            let user_data = self.user_data.lock().unwrap();
+            completions.sort_by_key(|&(i, _)| &completion_words[i]);
+            for (i, album_id) in completions {
+                let word = &completion_words[i][..];
+                let weight = 1.0 + user_data.get_album_scores(album_id).playcount;
+                match weighted.last_mut() {
+                    Some((w, prev)) if *prev == word => *w += weight,
+                    _ => weighted.push((weight, word)),
+                }
+            }
+        }
+
+        weighted.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("Weights must not be NaN."));
+        let suggestions: Vec<&str> = weighted.iter().take(n).map(|&(_, word)| word).collect();
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serde_json::to_writer(&mut w, &suggestions).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_status_code(200)
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
     fn handle_get_scan_status(&self) -> ResponseBox {
         // TODO: We could add a long polling query parameter here, and version
         // the status. Then in the request, include the previous version. If the
@@ -472,13 +1694,79 @@ impl MetaServer {
     fn handle_start_scan(&self) -> ResponseBox {
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        let status = self.scanner.start(self.config.clone());
+        let status = self.scanner.start(self.config_var.get().as_ref().clone());
         serialization::write_scan_status_json(&mut w, Some(status)).unwrap();
         Response::from_data(w.into_inner())
             .with_header(header_content_type("application/json"))
             .boxed()
     }
 
+    /// Count all listens in the database.
+    ///
+    /// We count from scratch rather than sharing the counter of the history
+    /// thread. This is a bit wasteful, but it keeps the history thread simple,
+    /// and it's fast enough for an occasional statistics request.
+    fn count_listens(&self, db: &mut Connection, index: &MemoryMetaIndex) -> db::Result<PlayCounts> {
+        db.begin().and_then(|mut tx| {
+            let mut counter = PlayCounter::new(self.config_var.get().playcount.clone());
+            counter.count_from_database(index, &mut tx)?;
+            tx.commit()?;
+            Ok(counter.into_counts())
+        })
+    }
+
+    fn handle_chart(&self, db: &mut Connection, chart: &str, raw_query: &str) -> ResponseBox {
+        let mut scope = "album";
+        let mut timescale = 0;
+        let mut limit = 50;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "scope" => match v.as_ref() {
+                    "artist" => scope = "artist",
+                    "album" => scope = "album",
+                    "track" => scope = "track",
+                    _ => return self.handle_bad_request("Invalid scope, must be artist, album, or track."),
+                },
+                "timescale" => match usize::from_str(v.as_ref()) {
+                    Ok(t) if t < 5 => timescale = t,
+                    _ => return self.handle_bad_request("Invalid timescale, must be in 0..4."),
+                },
+                "limit" => match usize::from_str(v.as_ref()) {
+                    Ok(n) => limit = n,
+                    Err(_) => return self.handle_bad_request("Invalid limit, must be a number."),
+                },
+                _ => {}
+            }
+        }
+        let chart = match chart {
+            "top" => Chart::Top(timescale),
+            "trending" => Chart::Trending,
+            "falling" => Chart::Falling,
+            _ => return self.handle_bad_request("Invalid chart, must be top, trending, or falling."),
+        };
+
+        let index = &*self.index_var.get();
+        let counts = match self.count_listens(db, index) {
+            Ok(c) => c,
+            Err(err) => {
+                error!("Error while counting listens: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let (artists, albums, tracks) = counts.get_chart(chart, limit);
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        match scope {
+            "artist" => serialization::write_chart_json(&mut w, &artists, |id| counts.get_artist_plays_per_week(id)).unwrap(),
+            "album" => serialization::write_chart_json(&mut w, &albums, |id| counts.get_album_plays_per_week(id)).unwrap(),
+            _ => serialization::write_chart_json(&mut w, &tracks, |id| counts.get_track_plays_per_week(id)).unwrap(),
+        }
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
     fn handle_stats(&self) -> ResponseBox {
         let index = &*self.index_var.get();
         let buffer = Vec::new();
@@ -489,6 +1777,193 @@ impl MetaServer {
             .boxed()
     }
 
+    fn handle_stats_runtime(&self) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_runtime_stats_json(self.player.get_runtime_counts(), &mut w).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Handle `/api/stats/never-played` and `/api/stats/least-played`.
+    fn handle_stats_forgotten(&self, db: &mut Connection, stat: &str, raw_query: &str) -> ResponseBox {
+        let mut limit = 50;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k == "limit" {
+                match usize::from_str(v.as_ref()) {
+                    Ok(n) => limit = n,
+                    Err(_) => return self.handle_bad_request("Invalid limit, must be a number."),
+                }
+            }
+        }
+
+        let index_arc = self.index_var.get();
+        let index = &*index_arc;
+        let counts = match self.count_listens(db, index) {
+            Ok(c) => c,
+            Err(err) => {
+                error!("Error while counting listens: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let user_data = self.user_data.lock().unwrap();
+        let track_ids = match stat {
+            "never-played" => counts.get_never_played(index, &user_data, limit),
+            _ => counts.get_least_played(index, &user_data, limit),
+        };
+
+        self.handle_track_list(index_arc.clone(), track_ids)
+    }
+
+    fn handle_wrapped(&self, db: &mut Connection, year: &str, raw_query: &str) -> ResponseBox {
+        let year = match i32::from_str(year) {
+            Ok(y) => y,
+            Err(_) => return self.handle_bad_request("Invalid year."),
+        };
+        let mut limit = 10;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k == "limit" {
+                match usize::from_str(v.as_ref()) {
+                    Ok(n) => limit = n,
+                    Err(_) => return self.handle_bad_request("Invalid limit, must be a number."),
+                }
+            }
+        }
+
+        let index = &*self.index_var.get();
+        let report = db.begin().and_then(|mut tx| {
+            let report = YearReport::compute(index, &mut tx, year, limit)?;
+            tx.commit()?;
+            Ok(report)
+        });
+        let report = match report {
+            Ok(Some(r)) => r,
+            Ok(None) => return self.handle_bad_request("Invalid year."),
+            Err(err) => {
+                error!("Error while computing year report: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_year_report_json(index, &mut w, &report).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_history(&self, db: &mut Connection, raw_query: &str) -> ResponseBox {
+        let mut before = i64::MAX;
+        let mut limit = 50;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "before" => match i64::from_str(v.as_ref()) {
+                    Ok(t) => before = t,
+                    Err(_) => return self.handle_bad_request("Invalid before, must be seconds since epoch."),
+                },
+                "limit" => match i64::from_str(v.as_ref()) {
+                    Ok(n) if (1..=500).contains(&n) => limit = n,
+                    _ => return self.handle_bad_request("Invalid limit, must be a number from 1 to 500."),
+                },
+                _ => continue,
+            }
+        }
+
+        let listens = db.begin().and_then(|mut tx| {
+            let mut listens = Vec::new();
+            for listen in db::iter_listens_before(&mut tx, before, limit)? {
+                listens.push(listen?);
+            }
+            tx.commit()?;
+            Ok(listens)
+        });
+        let listens = match listens {
+            Ok(ls) => ls,
+            Err(err) => {
+                error!("Error while loading listens: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        // Listens start in unique seconds, so the oldest listen of a full page
+        // is the exact boundary for the next page.
+        let next_before = match listens.last() {
+            Some(listen) if listens.len() as i64 == limit => Some(listen.started_at_second),
+            _ => None,
+        };
+
+        let index = &*self.index_var.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_listen_history_json(index, &mut w, &listens, next_before).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_history_on_this_day(&self, db: &mut Connection) -> ResponseBox {
+        use chrono::Datelike;
+        let today = chrono::Local::now();
+        let month_day = today.format("%m-%d").to_string();
+        let listens = db.begin().and_then(|mut tx| {
+            let mut listens = Vec::new();
+            for listen in db::iter_listens_on_day(&mut tx, &month_day, today.year() as i64)? {
+                listens.push(listen?);
+            }
+            tx.commit()?;
+            Ok(listens)
+        });
+        let listens = match listens {
+            Ok(ls) => ls,
+            Err(err) => {
+                error!("Error while loading listens: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_listens_per_year_json(&mut w, &listens).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_listens_export(&self, db: &mut Connection, raw_query: &str) -> ResponseBox {
+        let mut format = ExportFormat::Json;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k == "format" {
+                match ExportFormat::parse(v.as_ref()) {
+                    Some(f) => format = f,
+                    None => return self.handle_bad_request("Invalid format, must be csv or json."),
+                }
+            }
+        }
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let result = db.begin().map_err(crate::Error::from).and_then(|mut tx| {
+            history::export_listens(&mut tx, format, &mut w)?;
+            tx.commit()?;
+            Ok(())
+        });
+        if let Err(err) = result {
+            error!("Error while exporting listens: {:?}", err);
+            return self.handle_error("Database error.");
+        }
+
+        let content_type = match format {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        };
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type(content_type))
+            .boxed()
+    }
+
     /// Router function for all /api/«endpoint» calls.
     fn handle_api_request(
         &self,
@@ -503,36 +1978,144 @@ impl MetaServer {
         match (method, endpoint, arg1) {
             // API endpoints.
             (&Get, "cover",    Some(t)) => self.handle_album_cover(t),
-            (&Get, "thumb",    Some(t)) => self.handle_thumb(t),
-            (&Get, "waveform", Some(t)) => self.handle_waveform(db, t),
-            (&Get, "track",    Some(t)) => self.handle_track(t),
-            (&Get, "album",    Some(a)) => self.handle_album(a),
-            (&Get, "artist",   Some(a)) => self.handle_artist(a),
+            (&Get, "thumb",    Some(t)) => self.handle_thumb(db, t),
+            (&Get, "waveform", Some(t)) => self.handle_waveform(db, t, arg2),
+            (&Get, "spectrogram", Some(t)) => self.handle_spectrogram(db, t),
+            (&Get, "track",    Some(t)) => match arg2 {
+                None => self.handle_track(t),
+                Some("note") => self.handle_get_note("track", t),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+            (&Get, "album",    Some(a)) => match arg2 {
+                None => self.handle_album(a),
+                Some("note") => self.handle_get_note("album", a),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+            (&Get, "artist",   Some(a)) => match arg2 {
+                None => self.handle_artist(a),
+                Some("similar") => self.handle_artist_similar(a),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
             (&Get, "albums",   None)    => self.handle_albums(),
-            (&Get, "search",   None)    => self.handle_search(query),
+            (&Get, "search",   None)    => self.handle_search(db, query),
+            (&Get, "query",    None)    => self.handle_query(db, query),
+            (&Get, "search",   Some("suggest")) => self.handle_search_suggest(query),
             (&Get, "stats",    None)    => self.handle_stats(),
+            (&Get, "stats",    Some("runtime")) => self.handle_stats_runtime(),
+            (&Get, "stats",    Some(s @ ("never-played" | "least-played"))) => self.handle_stats_forgotten(db, s, query),
+            (&Get, "charts",   Some(c)) => self.handle_chart(db, c, query),
+            (&Get, "wrapped",  Some(y)) => self.handle_wrapped(db, y, query),
+            (&Get, "history",  None)    => self.handle_history(db, query),
+            (&Get, "history",  Some("on-this-day")) => self.handle_history_on_this_day(db),
+            (&Get, "listens",  Some("export")) => self.handle_listens_export(db, query),
+            (&Delete, "listens", Some(id)) => self.handle_listen_delete(db, id),
+            (&Patch, "listens", Some(id)) => self.handle_listen_correct(db, id, query),
+
+            // Resolving imported listens that did not match by themselves.
+            (&Get, "import", Some(source)) => match (arg2, arg3) {
+                (Some("unresolved"), None) => self.handle_import_unresolved(db, source),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+            (&Post, "import", Some(source)) => match (arg2, arg3) {
+                (Some(t), Some(d @ ("pick" | "skip"))) => self.handle_import_resolve(db, source, t, d, query),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
 
             // Rating.
             (&Put, "track", Some(t)) => match (arg2, arg3) {
                 (Some("rating"), Some(r)) => self.handle_rating(t, r),
+                (Some("label"), Some(l)) => self.handle_set_label("track", t, l, true),
+                (Some("note"), None) => self.handle_set_note("track", t, Some(query)),
                 _ => {
-                    println!("{arg2:?} {arg3:?}");
+                    debug!("{arg2:?} {arg3:?}");
                     self.handle_bad_request("No such endpoint.")
                 }
             }
+            (&Put | &Delete, "artist", Some(a)) => match (arg2, arg3) {
+                (Some("favorite"), None) => self.handle_artist_favorite(a, method == &Put),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+            (&Put, "album", Some(a)) => match (arg2, arg3) {
+                (Some("rating"), Some(r)) => self.handle_album_rating(a, r),
+                (Some("label"), Some(l)) => self.handle_set_label("album", a, l, true),
+                (Some("note"), None) => self.handle_set_note("album", a, Some(query)),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+            (&Delete, k @ ("track" | "album"), Some(id)) => match (arg2, arg3) {
+                (Some("label"), Some(l)) => self.handle_set_label(k, id, l, false),
+                (Some("note"), None) => self.handle_set_note(k, id, None),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+
+            // Labels.
+            (&Get, "labels", None) => self.handle_labels(),
+            (&Get | &Post, "label", Some(l)) => self.handle_label(method, l),
 
             // Play queue manipulation.
             (&Get,    "queue",  None)            => self.handle_queue(),
+            (&Get,    "queue",  Some("xspf"))    => self.handle_queue_xspf(),
+            (&Get,    "queue",  Some("saved"))   => self.handle_saved_queues(db),
             (&Put,    "queue",  Some(t))         => self.handle_enqueue(t),
+            (&Delete, "queue",  Some("saved"))   => match arg2 {
+                Some(name) => self.handle_saved_queue_delete(name),
+                None => self.handle_bad_request("Expected a saved queue name."),
+            },
             (&Delete, "queue",  Some(t))         => self.handle_dequeue(t),
-            (&Post,   "queue",  Some("shuffle")) => self.handle_queue_shuffle(),
+            (&Post,   "queue",  Some("shuffle")) => self.handle_queue_shuffle(query),
+            (&Post,   "queue",  Some("shuffle-albums")) => self.handle_queue_shuffle_albums(query),
+            (&Post,   "queue",  Some("shuffle-all")) => self.handle_queue_shuffle_all(query),
             (&Post,   "queue",  Some("clear"))   => self.handle_queue_clear(),
+            (&Post,   "queue",  Some("skip"))    => self.handle_queue_skip(),
+            (&Post,   "queue",  Some("save"))    => self.handle_queue_save(query),
+            (&Post,   "queue",  Some("restore")) => match arg2 {
+                Some(name) => self.handle_queue_restore(db, name),
+                None => self.handle_bad_request("Expected a saved queue name."),
+            },
+
+            // Generated mixes, get the tracks, or post to enqueue them.
+            (&Get | &Post, "mix", Some("discover")) => {
+                let boost = self.config_var.get().favorite_artist_boost;
+                self.handle_mix(method, query, |index, user_data, n| mix::discover(index, user_data, n, boost))
+            }
+            (&Get | &Post, "mix", Some("for-now"))  => self.handle_mix(method, query, mix::for_now),
+
+            (&Get, "favorites", None) => self.handle_favorites(),
+            (&Get, "favorites", Some("artists")) => self.handle_favorite_artists(),
+
+            // Smart playlists, get the tracks, or post to enqueue them.
+            (&Get, "playlists", None) => self.handle_playlists(db),
+            (&Get, "playlist", Some(name)) => match (arg2, arg3) {
+                (None, None) => self.handle_playlist(db, method, name, query),
+                (Some("xspf"), None) => self.handle_playlist_xspf(db, name, query),
+                (Some("search"), None) => self.handle_playlist_search(db, name, query),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+            (&Post, "playlist", Some(name)) => match (arg2, arg3) {
+                (None, None) => self.handle_playlist(db, method, name, query),
+                (Some("entry"), Some(i)) => self.handle_playlist_move(name, i, query),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+            (&Put, "playlist", Some(name)) => match (arg2, arg3) {
+                (None, None) => self.handle_playlist_create(db, name),
+                (Some("track"), Some(t)) => self.handle_playlist_insert(name, t, query),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
+            (&Delete, "playlist", Some(name)) => match (arg2, arg3) {
+                (None, None) => self.handle_playlist_delete(name),
+                (Some("entry"), Some(i)) => self.handle_playlist_remove(name, i),
+                _ => self.handle_bad_request("No such endpoint."),
+            },
 
             // Volume control, volume up/down change the volume by 1 dB.
             (&Get,  "volume", None)         => self.handle_get_volume(),
             (&Post, "volume", Some("up"))   => self.handle_change_volume(Millibel( 1_00)),
             (&Post, "volume", Some("down")) => self.handle_change_volume(Millibel(-1_00)),
 
+            // Radio mode, automatically refills the queue.
+            (&Get,  "radio", None)            => self.handle_get_radio(),
+            (&Post, "radio", Some("enable"))  => self.handle_radio_enable(query),
+            (&Post, "radio", Some("disable")) => self.handle_radio_disable(),
+
             // Background library scanning.
             (&Get,  "scan", Some("status")) => self.handle_get_scan_status(),
             (&Post, "scan", Some("start"))  => self.handle_start_scan(),
@@ -542,6 +2125,25 @@ impl MetaServer {
     }
 
     fn handle_request(&self, db: &mut Connection, request: Request) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+        let request_id = request_id(&request);
+        let mut response = logger::with_request_id(request_id.clone(), || self.route_request(db, &request));
+
+        if let Some(id) = request_id {
+            let header = Header::from_bytes(&b"X-Request-Id"[..], id.as_bytes())
+                .expect("Request id is ascii, we checked it.");
+            response.add_header(header);
+        }
+
+        match request.respond(response) {
+            Ok(()) => {},
+            Err(err) => warn!("Error while responding to request: {:?}", err),
+        }
+    }
+
+    fn route_request(&self, db: &mut Connection, request: &Request) -> ResponseBox {
+        debug!("{} {}", request.method(), request.url());
+
         // Break url into the part before the ? and the part after. The part
         // before we split on slashes.
         let mut url_iter = request.url().splitn(2, '?');
@@ -566,7 +2168,7 @@ impl MetaServer {
         let query = url_iter.next().unwrap_or("");
 
         // A very basic router. See also docs/api.md for an overview.
-        let response = match (request.method(), p0, p1) {
+        match (request.method(), p0, p1) {
             // API endpoints go through the API router, to keep this match arm
             // a bit more concise.
             (method, Some("api"), Some(endpoint)) => self.handle_api_request(db, method, endpoint, p2, p3, p4, query),
@@ -585,20 +2187,41 @@ impl MetaServer {
             // Fallback.
             (&Get, _, _) => self.handle_not_found(),
             _ => self.handle_bad_request("Expected a GET request."),
-        };
+        }
+    }
+}
 
-        match request.respond(response) {
-            Ok(()) => {},
-            Err(err) => println!("Error while responding to request: {:?}", err),
+/// Exit the process once the server is idle, see `MetaServer::is_idle_for_exit`.
+///
+/// This is for socket activation, where systemd starts the server again on
+/// the next request.
+fn exit_when_idle(service: &MetaServer) -> ! {
+    loop {
+        thread::sleep(Duration::from_secs(10));
+        if service.is_idle_for_exit() {
+            info!("Idle for idle_exit_minutes, exiting, systemd restarts on the next request.");
+            std::process::exit(0);
         }
     }
 }
 
-pub fn serve(bind: &str, service: Arc<MetaServer>) -> ! {
-    let server = match Server::http(bind) {
+/// Serve the API and webinterface, on `listener` if given, or on `bind`.
+///
+/// The listener is the socket that systemd passed, see
+/// `systemd::take_listen_socket`.
+pub fn serve(bind: &str, listener: Option<TcpListener>, service: Arc<MetaServer>) -> ! {
+    let is_socket_activated = listener.is_some();
+    let server_result = match listener {
+        Some(listener) => {
+            info!("Serving on the socket passed by systemd instead of {}.", bind);
+            Server::from_listener(listener, None)
+        }
+        None => Server::http(bind),
+    };
+    let server = match server_result {
         Ok(s) => s,
         Err(..) => {
-            eprintln!("Failed to start server, could not bind to {}.", bind);
+            error!("Failed to start server, could not bind to {}.", bind);
             std::process::exit(1);
         }
     };
@@ -617,14 +2240,15 @@ pub fn serve(bind: &str, service: Arc<MetaServer>) -> ! {
         let name = format!("http_server_{}", i);
         let builder = thread::Builder::new().name(name);
         let join_handle = builder.spawn(move || {
-            let connection = database_utils::connect_readonly(&service_i.config.db_path)
+            let config = service_i.config_var.get();
+            let connection = database_utils::connect_readonly(&config.db_path, &config.db_pragmas)
                 .expect("Failed to connect to database.");
             let mut db = Connection::new(&connection);
             loop {
                 let request = match server_i.recv() {
                     Ok(rq) => rq,
                     Err(e) => {
-                        println!("Error: {:?}", e);
+                        error!("Error: {:?}", e);
                         break;
                     }
                 };
@@ -634,6 +2258,27 @@ pub fn serve(bind: &str, service: Arc<MetaServer>) -> ! {
         threads.push(join_handle);
     }
 
+    let mqtt_config = service.config_var.get().mqtt.clone();
+    if mqtt_config.address.is_some() {
+        let service_mqtt = service.clone();
+        thread::Builder::new()
+            .name("mqtt".into())
+            .spawn(move || mqtt::main(&mqtt_config, &service_mqtt.index_var, &service_mqtt.player))
+            .unwrap();
+    }
+
+    // Without socket activation, nothing would start the server again after
+    // it exits, so we only exit when idle when socket-activated.
+    if is_socket_activated {
+        let service_idle = service.clone();
+        thread::Builder::new()
+            .name("idle_exit".into())
+            .spawn(move || exit_when_idle(&service_idle))
+            .unwrap();
+    } else if service.config_var.get().idle_exit_minutes.is_some() {
+        warn!("The server is not socket-activated, ignoring idle_exit_minutes.");
+    }
+
     // When running under systemd, the service is ready when the server is
     // accepting connections, which is now.
     systemd::notify_ready_if_can_notify();
@@ -645,3 +2290,43 @@ pub fn serve(bind: &str, service: Arc<MetaServer>) -> ! {
 
     unreachable!("The server runs indefinitely, joins should not return.")
 }
+
+#[cfg(test)]
+mod test {
+    use crate::prim::TrackId;
+    use crate::search::{LastWord, SearchResult};
+    use super::{parse_search_params, retain_in_playlist};
+
+    fn result(id: u64) -> SearchResult<TrackId> {
+        SearchResult { id: TrackId(id), score: 1.0, matches: Vec::new() }
+    }
+
+    #[test]
+    fn retain_in_playlist_keeps_only_playlist_tracks() {
+        let playlist = [TrackId(3), TrackId(1), TrackId(5), TrackId(4)];
+        let ids = |results: &[SearchResult<TrackId>]| results.iter().map(|r| r.id.0).collect::<Vec<_>>();
+
+        let mut results: Vec<_> = (1..=6).map(result).collect();
+        retain_in_playlist(&mut results, &playlist, 10, |_| Ok(true)).unwrap();
+        assert_eq!(ids(&results), [1, 3, 4, 5]);
+
+        // The filters apply too, and we stop at the limit.
+        let mut results: Vec<_> = (1..=6).map(result).collect();
+        retain_in_playlist(&mut results, &playlist, 2, |id| Ok(id != TrackId(3))).unwrap();
+        assert_eq!(ids(&results), [1, 4]);
+    }
+
+    #[test]
+    fn parse_search_params_matches_last_word_as_prefix_by_default() {
+        let (_, options) = parse_search_params("q=lemo").unwrap();
+        assert_eq!(options.last_word, LastWord::Prefix);
+        assert!(!options.fuzzy);
+
+        let (_, options) = parse_search_params("q=lemon&prefix=false&fuzzy=true").unwrap();
+        assert_eq!(options.last_word, LastWord::Exact);
+        assert!(options.fuzzy);
+
+        assert!(parse_search_params("prefix=false").is_err());
+        assert!(parse_search_params("q=lemo&prefix=yes").is_err());
+    }
+}
@@ -8,19 +8,33 @@
 //! Logic for shuffling playlists.
 //!
 //! See also <https://ruudvanasseldonk.com/2023/an-algorithm-for-shuffling-playlists>.
+//!
+//! The shuffles are deterministic given the state of the `Prng`, so with
+//! `Prng::new_seed`, they are reproducible.
 
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::iter;
 
 use nanorand::Rng;
 
 use crate::player::QueuedTrack;
-use crate::prim::{AlbumId, ArtistId};
+use crate::prim::{AlbumId, ArtistId, TrackId};
 use crate::{MemoryMetaIndex, MetaIndex};
 
 pub type Prng = nanorand::WyRand;
 
+/// How far apart `shuffle` places tracks by the same artist or from the same album.
+///
+/// The gaps are the minimum number of other tracks in between. The shuffle
+/// already spreads artists and albums as evenly as it can, so by default the
+/// gaps are 0, and we add no constraints on top of that.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ShuffleConfig {
+    pub min_artist_gap: usize,
+    pub min_album_gap: usize,
+}
+
 /// Trait to decouple metadata lookups from shuffling.
 ///
 /// This is to make the shuffling easier to test without having to construct
@@ -59,6 +73,21 @@ impl Shuffle for MemoryMetaIndex {
     }
 }
 
+/// Shuffle implementation for track ids that are not in the queue (yet).
+pub struct TrackIdShuffler<'a>(pub &'a MemoryMetaIndex);
+
+impl<'a> Shuffle for TrackIdShuffler<'a> {
+    type Track = TrackId;
+
+    fn get_album_id(&self, track_id: &TrackId) -> AlbumId {
+        track_id.album_id()
+    }
+
+    fn get_artist_id(&self, album_id: AlbumId) -> ArtistId {
+        Shuffle::get_artist_id(self.0, album_id)
+    }
+}
+
 /// Shuffler for use in tests.
 ///
 /// In the tests we use a triple of bytes as the track type:
@@ -126,10 +155,17 @@ fn apply_permutation<T>(permutation: &[TrackRef], tracks: &mut [T]) {
     }
 }
 
-pub fn shuffle<Meta: Shuffle>(meta: &Meta, rng: &mut Prng, tracks: &mut [Meta::Track]) {
+pub fn shuffle<Meta: Shuffle>(
+    meta: &Meta,
+    rng: &mut Prng,
+    tracks: &mut [Meta::Track],
+    config: &ShuffleConfig,
+) {
     // First we partition all tracks into albums. Rather than moving around the
     // full QueuedTrack all the time, we store indices into the tracks slice.
-    let mut albums = HashMap::<AlbumId, Vec<TrackRef>>::new();
+    // We use a BTreeMap rather than a HashMap, so the iteration order, and
+    // therefore the outcome for a given seed, is deterministic.
+    let mut albums = BTreeMap::<AlbumId, Vec<TrackRef>>::new();
     for (i, track) in tracks.iter().enumerate() {
         let album_id = meta.get_album_id(track);
         let track_ref = TrackRef {
@@ -149,7 +185,7 @@ pub fn shuffle<Meta: Shuffle>(meta: &Meta, rng: &mut Prng, tracks: &mut [Meta::T
     }
 
     // Then we group everything back on artist.
-    let mut artists = HashMap::<ArtistId, Vec<Vec<TrackRef>>>::new();
+    let mut artists = BTreeMap::<ArtistId, Vec<Vec<TrackRef>>>::new();
     for (album_id, album_tracks) in albums {
         let artist_id = meta.get_artist_id(album_id);
         artists.entry(artist_id).or_default().push(album_tracks);
@@ -171,11 +207,126 @@ pub fn shuffle<Meta: Shuffle>(meta: &Meta, rng: &mut Prng, tracks: &mut [Meta::T
     // Then we merge-shuffle the per-artist partitions once more into the final
     // order.
     let permutation = merge_shuffle(rng, artist_partitions);
+    let permutation = spread(meta, tracks, permutation, config);
 
     // Finally put the right track at the right index.
     apply_permutation(&permutation, tracks);
 }
 
+/// Reorder `permutation` to respect the minimum gaps of `config`, where possible.
+///
+/// At every position, we take the first remaining track that is far enough
+/// from the previous track by the same artist and from the same album. When
+/// there is none, we take the track that is the least too close. Tracks from
+/// the same album are always equally far, so they keep their relative order.
+/// This is quadratic in the worst case, but queues are small enough for that.
+fn spread<Meta: Shuffle>(
+    meta: &Meta,
+    tracks: &[Meta::Track],
+    permutation: Vec<TrackRef>,
+    config: &ShuffleConfig,
+) -> Vec<TrackRef> {
+    if config.min_artist_gap == 0 && config.min_album_gap == 0 {
+        return permutation
+    }
+
+    let keys: Vec<(AlbumId, ArtistId)> = tracks
+        .iter()
+        .map(|track| {
+            let album_id = meta.get_album_id(track);
+            (album_id, meta.get_artist_id(album_id))
+        })
+        .collect();
+
+    // Position in the result of the last track by every artist and album.
+    let mut last_artist = HashMap::<ArtistId, usize>::new();
+    let mut last_album = HashMap::<AlbumId, usize>::new();
+    let mut remaining = permutation;
+    let mut result = Vec::with_capacity(remaining.len());
+
+    while !remaining.is_empty() {
+        let pos = result.len();
+
+        // Return by how many positions the track would be too close to the
+        // previous one of its artist or album, 0 if it is far enough.
+        let too_close = |track: &TrackRef| {
+            let (album_id, artist_id) = keys[track.orig_index as usize];
+            let deficit = |last: Option<&usize>, gap: usize| match last {
+                Some(&p) => (p + gap + 1).saturating_sub(pos),
+                None => 0,
+            };
+            cmp::max(
+                deficit(last_artist.get(&artist_id), config.min_artist_gap),
+                deficit(last_album.get(&album_id), config.min_album_gap),
+            )
+        };
+
+        let mut best_i = 0;
+        let mut best_deficit = usize::MAX;
+        for (i, track) in remaining.iter().enumerate() {
+            let deficit = too_close(track);
+            if deficit < best_deficit {
+                best_i = i;
+                best_deficit = deficit;
+            }
+            if deficit == 0 {
+                break
+            }
+        }
+
+        let track = remaining.remove(best_i);
+        let (album_id, artist_id) = keys[track.orig_index as usize];
+        last_artist.insert(artist_id, pos);
+        last_album.insert(album_id, pos);
+        result.push(track);
+    }
+
+    result
+}
+
+/// Shuffle the order of albums, but keep the tracks of every album together.
+///
+/// This is for listening one album at a time. The tracks of an album end up
+/// adjacent, in the order in which they were in `tracks`. When
+/// `tracks_per_album` is set, we keep only a random selection of that many
+/// tracks of every album, still in their original order, and drop the rest.
+pub fn shuffle_albums<Meta: Shuffle>(
+    meta: &Meta,
+    rng: &mut Prng,
+    tracks: &mut Vec<Meta::Track>,
+    tracks_per_album: Option<usize>,
+) {
+    // Group the tracks by album, in order of first occurrence, so the result
+    // depends only on the rng and not on the hash map iteration order.
+    let mut album_indices = HashMap::<AlbumId, usize>::new();
+    let mut albums: Vec<Vec<u32>> = Vec::new();
+    for (i, track) in tracks.iter().enumerate() {
+        let album_index = *album_indices
+            .entry(meta.get_album_id(track))
+            .or_insert_with(|| {
+                albums.push(Vec::new());
+                albums.len() - 1
+            });
+        albums[album_index].push(i as u32);
+    }
+
+    rng.shuffle(&mut albums);
+
+    if let Some(n) = tracks_per_album {
+        for album_tracks in albums.iter_mut().filter(|ts| ts.len() > n) {
+            rng.shuffle(&mut album_tracks[..]);
+            album_tracks.truncate(n);
+            album_tracks.sort_unstable();
+        }
+    }
+
+    // Take all tracks out, and put back the ones we keep in the new order.
+    let mut slots: Vec<Option<Meta::Track>> = tracks.drain(..).map(Some).collect();
+    tracks.extend(albums.iter().flatten().map(|&i| {
+        slots[i as usize].take().expect("Every track occurs in one album.")
+    }));
+}
+
 /// Join the spans of `long` with an element of `short` as joiner.
 fn join_sep(long: Vec<TrackRef>, short: Vec<TrackRef>, mut span_lens: Vec<usize>) -> Vec<TrackRef> {
     let mut result = Vec::with_capacity(long.len() + short.len());
@@ -319,7 +470,7 @@ fn merge_shuffle(rng: &mut Prng, mut partitions: Vec<Vec<TrackRef>>) -> Vec<Trac
 /// write them as ascii literals for easy visualisation.
 #[cfg(test)]
 mod test {
-    use super::{apply_permutation, shuffle, Prng, TestShuffler, TrackRef};
+    use super::{apply_permutation, shuffle, shuffle_albums, Prng, ShuffleConfig, TestShuffler, TrackRef};
     use nanorand::Rng;
 
     /// Helper to shorten writing `TrackRef` where we don’t care about the partition.
@@ -373,7 +524,7 @@ mod test {
             let mut tracks: Vec<_> = expected[0].into();
             rng.shuffle(&mut tracks);
             let orig = tracks.clone();
-            shuffle(&TestShuffler, &mut rng, &mut tracks);
+            shuffle(&TestShuffler, &mut rng, &mut tracks, &ShuffleConfig::default());
             assert!(
                 expected.contains(&&tracks[..]),
                 "\nUnexpected shuffle:\n\n  {:?}\n\ninto\n\n  {:?}\n\n",
@@ -429,4 +580,156 @@ mod test {
     fn shuffle_fuzz_cases() {
         test_shuffle(&[&[*b"A11", *b"B22", *b"A00"], &[*b"A00", *b"B22", *b"A11"]]);
     }
+
+    #[test]
+    fn shuffle_albums_keeps_albums_together() {
+        let tracks: Vec<[u8; 3]> = vec![
+            *b"AA1", *b"AB1", *b"AA2", *b"BA1", *b"AB2", *b"AA3", *b"BA2",
+        ];
+        let mut rng = Prng::new_seed(42);
+        let mut album_orders = std::collections::HashSet::new();
+
+        for _ in 0..100 {
+            let mut shuffled = tracks.clone();
+            shuffle_albums(&TestShuffler, &mut rng, &mut shuffled, None);
+
+            let mut sorted = shuffled.clone();
+            sorted.sort();
+            let mut expected = tracks.clone();
+            expected.sort();
+            assert_eq!(sorted, expected, "Shuffle should not drop tracks.");
+
+            // Every album is one contiguous run, in the original order.
+            let mut albums: Vec<&[u8]> = shuffled.iter().map(|t| &t[..2]).collect();
+            assert_eq!(albums.len(), 7);
+            albums.dedup();
+            assert_eq!(albums.len(), 3, "Albums should stay together in {:?}.", shuffled);
+            for pair in shuffled.windows(2) {
+                if pair[0][..2] == pair[1][..2] {
+                    assert!(pair[0][2] < pair[1][2]);
+                }
+            }
+
+            album_orders.insert(albums.iter().map(|a| a.to_vec()).collect::<Vec<_>>());
+        }
+
+        // All 6 orders of 3 albums should occur.
+        assert_eq!(album_orders.len(), 6);
+    }
+
+    #[test]
+    fn shuffle_albums_limits_tracks_per_album() {
+        let tracks: Vec<[u8; 3]> = vec![
+            *b"AA1", *b"AA2", *b"AA3", *b"AA4", *b"AB1", *b"BA1", *b"BA2",
+        ];
+        let mut rng = Prng::new_seed(42);
+
+        for _ in 0..100 {
+            let mut shuffled = tracks.clone();
+            shuffle_albums(&TestShuffler, &mut rng, &mut shuffled, Some(2));
+            assert_eq!(shuffled.len(), 5);
+            assert_eq!(shuffled.iter().filter(|t| t[..2] == *b"AA").count(), 2);
+            assert_eq!(shuffled.iter().filter(|t| t[..2] == *b"AB").count(), 1);
+            assert_eq!(shuffled.iter().filter(|t| t[..2] == *b"BA").count(), 2);
+            for pair in shuffled.windows(2) {
+                if pair[0][..2] == pair[1][..2] {
+                    assert!(pair[0][2] < pair[1][2]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_respects_min_artist_gap() {
+        let config = ShuffleConfig { min_artist_gap: 2, min_album_gap: 0 };
+        let mut rng = Prng::new_seed(42);
+
+        for _ in 0..1_000 {
+            let mut tracks: Vec<[u8; 3]> = vec![
+                *b"AA0", *b"AB0", *b"AA1", *b"BA0", *b"BA1", *b"BB0", *b"CA0", *b"CA1", *b"CA2",
+            ];
+            rng.shuffle(&mut tracks);
+            shuffle(&TestShuffler, &mut rng, &mut tracks, &config);
+            for window in tracks.windows(3) {
+                assert_ne!(window[0][0], window[1][0], "Too close in {:?}.", tracks);
+                assert_ne!(window[0][0], window[2][0], "Too close in {:?}.", tracks);
+                assert_ne!(window[1][0], window[2][0], "Too close in {:?}.", tracks);
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_keeps_all_tracks_when_gaps_are_impossible() {
+        let config = ShuffleConfig { min_artist_gap: 3, min_album_gap: 5 };
+        let mut rng = Prng::new_seed(42);
+        let mut expected: Vec<[u8; 3]> = vec![
+            *b"AA0", *b"AA1", *b"AA2", *b"AA3", *b"AB0", *b"BA0", *b"BA1",
+        ];
+
+        for _ in 0..100 {
+            let mut tracks = expected.clone();
+            shuffle(&TestShuffler, &mut rng, &mut tracks, &config);
+            tracks.sort();
+            expected.sort();
+            assert_eq!(tracks, expected);
+        }
+    }
+
+    /// Generate a random queue with up to 6 artists of up to 3 albums each.
+    fn random_tracks(rng: &mut Prng) -> Vec<[u8; 3]> {
+        let mut tracks = Vec::new();
+        for artist in 0..rng.generate_range(1_u8..7) {
+            for album in 0..rng.generate_range(1_u8..4) {
+                for track in 0..rng.generate_range(1_u8..8) {
+                    tracks.push([artist, album, track]);
+                }
+            }
+        }
+        rng.shuffle(&mut tracks);
+        tracks
+    }
+
+    #[test]
+    fn shuffle_is_deterministic_for_seed() {
+        let mut rng = Prng::new_seed(42);
+        for seed in 0..100 {
+            let tracks = random_tracks(&mut rng);
+            let mut tracks_1 = tracks.clone();
+            let mut tracks_2 = tracks.clone();
+            let config = ShuffleConfig::default();
+            shuffle(&TestShuffler, &mut Prng::new_seed(seed), &mut tracks_1, &config);
+            shuffle(&TestShuffler, &mut Prng::new_seed(seed), &mut tracks_2, &config);
+            assert_eq!(tracks_1, tracks_2);
+
+            shuffle_albums(&TestShuffler, &mut Prng::new_seed(seed), &mut tracks_1, Some(2));
+            shuffle_albums(&TestShuffler, &mut Prng::new_seed(seed), &mut tracks_2, Some(2));
+            assert_eq!(tracks_1, tracks_2);
+        }
+    }
+
+    #[test]
+    fn shuffle_spreads_artists_for_many_seeds() {
+        let mut rng = Prng::new_seed(42);
+        for seed in 0..2_000 {
+            let mut tracks = random_tracks(&mut rng);
+            let n = tracks.len();
+            let mut per_artist = [0_usize; 6];
+            for track in tracks.iter() {
+                per_artist[track[0] as usize] += 1;
+            }
+            let m = *per_artist.iter().max().unwrap();
+
+            shuffle(&TestShuffler, &mut Prng::new_seed(seed), &mut tracks, &ShuffleConfig::default());
+
+            // When the largest artist has more tracks than all others plus one,
+            // some of its tracks must be adjacent, but no more than necessary.
+            // Otherwise, no tracks by the same artist are adjacent.
+            let n_adjacent = tracks.windows(2).filter(|w| w[0][0] == w[1][0]).count();
+            assert_eq!(
+                n_adjacent,
+                m.saturating_sub(n - m + 1),
+                "Seed {} produced {:?}.", seed, tracks,
+            );
+        }
+    }
 }
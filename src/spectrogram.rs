@@ -0,0 +1,331 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Small mel spectrograms of tracks.
+//!
+//! Where the waveform shows how loud a track is over time, the spectrogram
+//! also shows which frequencies are loud. We compute it during the loudness
+//! analysis, when we decode the track anyway. Because the spectrograms all
+//! have the same number of bands, they can also serve as a cheap description
+//! of the sound of a track for audio similarity later.
+
+use std::f32::consts::PI;
+use std::io;
+
+/// Number of mel bands, the height of the image.
+pub const NUM_BANDS: usize = 32;
+
+/// Maximum number of columns, the width of the image for tracks that are long enough.
+const MAX_COLUMNS: usize = 256;
+
+/// Number of samples per Fourier transform, about 23ms at 44.1 kHz.
+const FFT_LEN: usize = 1024;
+
+/// Range between the loudest and the quietest value that we can represent.
+const DYNAMIC_RANGE_DB: f32 = 80.0;
+
+/// A mel spectrogram of a track.
+///
+/// Every column is the spectrum of a short window of audio, and the columns
+/// are evenly spread over the track. Every column stores one byte per mel
+/// band, from low to high frequencies, on a logarithmic scale where 255 is the
+/// loudest value in the track, and 0 is `DYNAMIC_RANGE_DB` quieter or less.
+/// The length of the buffer is therefore a multiple of `NUM_BANDS`.
+pub struct Spectrogram {
+    pub data: Vec<u8>,
+}
+
+impl Spectrogram {
+    /// Load the spectrogram from a buffer, for loading from the database.
+    pub fn from_bytes(data: Vec<u8>) -> Spectrogram {
+        assert_eq!(data.len() % NUM_BANDS, 0, "Buffer length must be a multiple of the number of bands.");
+        Spectrogram {
+            data
+        }
+    }
+
+    /// View the spectrogram as a buffer, to be saved in the database.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    /// Render the spectrogram as a grayscale bmp image, low frequencies at the bottom.
+    ///
+    /// Bmp is not a compact format, but the image is small, and it is simple
+    /// enough that we can write it ourselves, with one byte per pixel.
+    pub fn write_bmp<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        let width = self.data.len() / NUM_BANDS;
+        // Rows are padded to a multiple of 4 bytes.
+        let stride = (width + 3) & !3;
+        let header_len = 14 + 40 + 256 * 4;
+        let image_len = stride * NUM_BANDS;
+
+        // File header.
+        out.write_all(b"BM")?;
+        out.write_all(&((header_len + image_len) as u32).to_le_bytes())?;
+        out.write_all(&0_u32.to_le_bytes())?;
+        out.write_all(&(header_len as u32).to_le_bytes())?;
+
+        // Info header. A positive height means that the first row in the file
+        // is the bottom row of the image.
+        out.write_all(&40_u32.to_le_bytes())?;
+        out.write_all(&(width as i32).to_le_bytes())?;
+        out.write_all(&(NUM_BANDS as i32).to_le_bytes())?;
+        out.write_all(&1_u16.to_le_bytes())?;
+        out.write_all(&8_u16.to_le_bytes())?;
+        out.write_all(&0_u32.to_le_bytes())?;
+        out.write_all(&(image_len as u32).to_le_bytes())?;
+        out.write_all(&2835_i32.to_le_bytes())?;
+        out.write_all(&2835_i32.to_le_bytes())?;
+        out.write_all(&256_u32.to_le_bytes())?;
+        out.write_all(&0_u32.to_le_bytes())?;
+
+        // Grayscale palette, in blue, green, red, reserved order.
+        for i in 0..=255_u8 {
+            out.write_all(&[i, i, i, 0])?;
+        }
+
+        let padding = [0_u8; 3];
+        for band in 0..NUM_BANDS {
+            for column in self.data.chunks_exact(NUM_BANDS) {
+                out.write_all(&[column[band]])?;
+            }
+            out.write_all(&padding[..stride - width])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Transform `re` and `im` into their discrete Fourier transform, in place.
+///
+/// This is the iterative radix-2 Cooley-Tukey algorithm, the length must be a
+/// power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    assert!(n.is_power_of_two());
+    assert_eq!(im.len(), n);
+
+    // Put the input in bit-reversed order.
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len *= 2;
+    }
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10.0_f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// Computes a spectrogram from the samples of a track, as we decode it.
+pub struct SpectrogramBuilder {
+    /// Number of samples between the starts of consecutive columns.
+    hop: u64,
+    num_columns: usize,
+    /// Index of the next sample that we will receive.
+    sample_index: u64,
+    /// Mono samples for the column that we are collecting.
+    window: Vec<f32>,
+    /// Lower edge, center, and upper edge of every band, in FFT bins.
+    bands: Vec<[f32; 3]>,
+    /// Band powers in dB, for the columns that are done.
+    columns: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl SpectrogramBuilder {
+    /// Prepare to compute a spectrogram of a track with the given length.
+    ///
+    /// Returns `None` if the track is too short to compute a spectrogram.
+    pub fn new(sample_rate: u32, num_samples: u64) -> Option<SpectrogramBuilder> {
+        let num_columns = MAX_COLUMNS.min((num_samples / FFT_LEN as u64) as usize);
+        if num_columns == 0 {
+            return None
+        }
+
+        // The bands are spaced evenly on the mel scale, and consecutive bands
+        // overlap by half.
+        let mel_min = hz_to_mel(20.0);
+        let mel_max = hz_to_mel(16_000.0_f32.min(sample_rate as f32 * 0.5));
+        let bin_per_hz = FFT_LEN as f32 / sample_rate as f32;
+        let edges: Vec<f32> = (0..NUM_BANDS + 2)
+            .map(|i| mel_min + (mel_max - mel_min) * i as f32 / (NUM_BANDS + 1) as f32)
+            .map(|mel| mel_to_hz(mel) * bin_per_hz)
+            .collect();
+        let bands = edges.windows(3).map(|w| [w[0], w[1], w[2]]).collect();
+
+        let result = SpectrogramBuilder {
+            hop: num_samples / num_columns as u64,
+            num_columns,
+            sample_index: 0,
+            window: Vec::with_capacity(FFT_LEN),
+            bands,
+            columns: Vec::with_capacity(num_columns * NUM_BANDS),
+            im: vec![0.0; FFT_LEN],
+        };
+        Some(result)
+    }
+
+    /// Add the samples of one block of audio.
+    ///
+    /// The spectrogram is computed from the average of the two channels.
+    pub fn push(&mut self, left: &[i32], right: &[i32], normalizer: f32) {
+        for (&l, &r) in left.iter().zip(right) {
+            let columns_done = self.columns.len() / NUM_BANDS;
+            let column_start = columns_done as u64 * self.hop;
+            if columns_done < self.num_columns && self.sample_index >= column_start {
+                self.window.push((l as f32 + r as f32) * 0.5 * normalizer);
+                if self.window.len() == FFT_LEN {
+                    self.finish_column();
+                }
+            }
+            self.sample_index += 1;
+        }
+    }
+
+    fn finish_column(&mut self) {
+        // Apply a Hann window, to reduce leakage between frequency bins.
+        for (i, x) in self.window.iter_mut().enumerate() {
+            *x *= 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_LEN as f32).cos();
+        }
+        self.im.iter_mut().for_each(|x| *x = 0.0);
+        fft(&mut self.window, &mut self.im);
+
+        for &[lower, center, upper] in &self.bands {
+            let power_at = |k: usize| self.window[k] * self.window[k] + self.im[k] * self.im[k];
+            let mut power = 0.0;
+            let mut weight_sum = 0.0;
+            for k in (lower.ceil() as usize)..=(upper.floor() as usize).min(FFT_LEN / 2) {
+                let x = k as f32;
+                let weight = if x <= center {
+                    (x - lower) / (center - lower)
+                } else {
+                    (upper - x) / (upper - center)
+                };
+                power += weight * power_at(k);
+                weight_sum += weight;
+            }
+            // At low frequencies, bands can be narrower than a bin, in that
+            // case take the bin nearest to the center.
+            if weight_sum == 0.0 {
+                power = power_at((center.round() as usize).min(FFT_LEN / 2));
+            }
+            self.columns.push(10.0 * (power + 1e-20).log10());
+        }
+
+        self.window.clear();
+    }
+
+    /// Return the spectrogram, or `None` if we did not receive enough samples.
+    pub fn finish(self) -> Option<Spectrogram> {
+        if self.columns.is_empty() {
+            return None
+        }
+        let max_db = self.columns.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let min_db = max_db - DYNAMIC_RANGE_DB;
+        let data = self
+            .columns
+            .iter()
+            .map(|&db| (255.0 * ((db - min_db) / DYNAMIC_RANGE_DB).max(0.0)) as u8)
+            .collect();
+        Some(Spectrogram::from_bytes(data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fft, SpectrogramBuilder, NUM_BANDS};
+
+    #[test]
+    fn fft_finds_frequency_of_sine() {
+        let n = 64;
+        let mut re: Vec<f32> = (0..n)
+            .map(|i| (2.0 * std::f32::consts::PI * 5.0 * i as f32 / n as f32).cos())
+            .collect();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+        for k in 0..n {
+            let magnitude = (re[k] * re[k] + im[k] * im[k]).sqrt();
+            let expected = if k == 5 || k == n - 5 { n as f32 / 2.0 } else { 0.0 };
+            assert!((magnitude - expected).abs() < 1e-3, "Bin {}: {}", k, magnitude);
+        }
+    }
+
+    #[test]
+    fn spectrogram_highlights_band_of_sine() {
+        let sample_rate = 44_100;
+        let num_samples = 44_100 * 2;
+        let mut builder = SpectrogramBuilder::new(sample_rate, num_samples).unwrap();
+
+        // A 1 kHz sine, the band that contains it is the one whose center is nearest.
+        let bin = 1000.0 * 1024.0 / sample_rate as f32;
+        let expected_band = (0..NUM_BANDS)
+            .min_by(|&i, &j| {
+                let di = (builder.bands[i][1] - bin).abs();
+                let dj = (builder.bands[j][1] - bin).abs();
+                di.partial_cmp(&dj).unwrap()
+            })
+            .unwrap();
+
+        let samples: Vec<i32> = (0..num_samples)
+            .map(|i| (10_000.0 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / sample_rate as f32).sin()) as i32)
+            .collect();
+        for block in samples.chunks(4096) {
+            builder.push(block, block, 1.0 / 32768.0);
+        }
+
+        let spectrogram = builder.finish().unwrap();
+        assert_eq!(spectrogram.data.len(), 86 * NUM_BANDS);
+        for column in spectrogram.data.chunks_exact(NUM_BANDS) {
+            let loudest_band = (0..NUM_BANDS).max_by_key(|&i| column[i]).unwrap();
+            assert_eq!(loudest_band, expected_band);
+        }
+
+        let mut bmp = Vec::new();
+        spectrogram.write_bmp(&mut bmp).unwrap();
+        // The 86 columns are padded to 88 bytes per row.
+        assert_eq!(bmp.len(), 14 + 40 + 1024 + 88 * NUM_BANDS);
+        assert_eq!(&bmp[..2], b"BM");
+    }
+
+    #[test]
+    fn spectrogram_needs_one_window() {
+        assert!(SpectrogramBuilder::new(44_100, 1000).is_none());
+    }
+}
@@ -7,11 +7,13 @@
 
 //! Utilities for string deduplication and word splitting and normalization.
 
-use std::mem;
-
+use std::char::ToLowercase;
 use std::collections::HashMap;
+use std::iter::FlatMap;
+use std::mem;
+use std::str::Chars;
 
-use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::{Decompositions, UnicodeNormalization};
 
 pub struct StringDeduper {
     strings_to_id: HashMap<String, u32>,
@@ -44,9 +46,16 @@ impl StringDeduper {
         next_id
     }
 
-    /// Return the underlying string vector, destroying the deduplicator.
-    pub fn into_vec(self) -> Vec<String> {
-        self.strings
+    /// Return the strings in an arena, destroying the deduplicator.
+    ///
+    /// The strings keep their index, so ids returned by `insert` can be used
+    /// with `StringArena::get`.
+    pub fn into_arena(self) -> StringArena {
+        let mut arena = StringArena::new();
+        for s in &self.strings {
+            arena.push(s);
+        }
+        arena
     }
 
     /// Return the string with the given index. Panics when out of bounds.
@@ -115,6 +124,72 @@ impl StringDeduper {
     }
 }
 
+/// Strings stored back to back in a single buffer, addressed by index.
+///
+/// Compared to a `Vec<String>`, this saves an allocation and a pointer per
+/// string, and strings that are used together, such as the title and artist of
+/// an album, are close together in memory.
+#[derive(Clone)]
+pub struct StringArena {
+    data: String,
+    /// Offset of the start of every string in `data`, and one past the end.
+    offsets: Vec<u32>,
+}
+
+impl StringArena {
+    pub fn new() -> StringArena {
+        StringArena {
+            data: String::new(),
+            offsets: vec![0],
+        }
+    }
+
+    /// Build an arena from its parts, return `None` if they are inconsistent.
+    pub fn from_parts(data: String, offsets: Vec<u32>) -> Option<StringArena> {
+        let is_valid = offsets.first() == Some(&0)
+            && offsets.last() == Some(&(data.len() as u32))
+            && offsets.windows(2).all(|w| w[0] <= w[1])
+            && offsets.iter().all(|&off| data.is_char_boundary(off as usize));
+        match is_valid {
+            true => Some(StringArena { data, offsets }),
+            false => None,
+        }
+    }
+
+    /// Append a string, return its index.
+    pub fn push(&mut self, string: &str) -> u32 {
+        self.data.push_str(string);
+        self.offsets.push(self.data.len() as u32);
+        self.offsets.len() as u32 - 2
+    }
+
+    /// Return the string with the given index. Panics when out of bounds.
+    #[inline]
+    pub fn get(&self, index: u32) -> &str {
+        let i = index as usize;
+        &self.data[self.offsets[i] as usize..self.offsets[i + 1] as usize]
+    }
+
+    /// Return the number of strings in the arena.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the concatenated strings.
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    /// Return the start offset of every string, and one past the end.
+    pub fn offsets(&self) -> &[u32] {
+        &self.offsets
+    }
+}
+
 fn push_word(dest: &mut Vec<String>, word: &mut String) {
     if word.len() == 0 {
         return
@@ -125,26 +200,34 @@ fn push_word(dest: &mut Vec<String>, word: &mut String) {
     dest.push(w);
 }
 
-/// Fills the vector with the words in the string in normalized form.
-///
-/// This first normalizes words to Unicode Normalization Form KD, which
-/// decomposes characters with accents into the character and the accent
-/// separately. The "KD" form, as opposed to the "D" form, also replaces more
-/// things that have the same semantic meaning, such as replacing superscripts
-/// with normal digits. Finally (not part of the KD normalization), everything
-/// is lowercased, and accents and some punctuation are removed.
-pub fn normalize_words(title: &str, dest: &mut Vec<String>) {
-    // We assume that in the majority of the cases, the transformations
-    // below do not change the number of bytes.
-    let mut word = String::new();
-    let mut num_dots = 0;
+/// What to do with a character of a title, after normalization and lowercasing.
+enum Step {
+    /// End the current word.
+    Break,
+    /// End the current word, and add this word of its own.
+    BreakWith(&'static str),
+    /// Append the character to the current word.
+    Push(char),
+    /// Append the string to the current word.
+    PushStr(&'static str),
+    /// A period, which counts towards an ellipsis.
+    Dot,
+    /// Ignore the character.
+    Skip,
+    /// A character that we do not know how to handle.
+    Unknown,
+}
 
+/// Classify a character of a title for word splitting and normalization.
+///
+/// This is shared by `normalize_words` and `NormalizedChars`, so they agree on
+/// what the words are.
+fn classify(ch: char) -> Step {
     // Drop some punctuation characters and accents. We remove punctuation that
     // is unlikely to contain a lot of information about the title. (Deadmau5
     // can go and use some normal titles next time.) We remove accents to make
     // searching easier without having to type the exact accent.
-    // U+309a is a Japanese diacritic.
-    let drop = "“”‘’'\"`()[]«»,❦|\u{300}\u{301}\u{302}\u{303}\u{304}\u{306}\u{307}\u{308}\u{30a}\u{323}\u{327}\u{328}\u{309a}";
+    let drop = "“”‘’'\"`()[]«»,❦|\u{300}\u{301}\u{302}\u{303}\u{304}\u{306}\u{307}\u{308}\u{30a}\u{323}\u{327}\u{328}";
     let keep = "$€#&=*%∆";
 
     // Cut words at the following punctuation characters, but still include them
@@ -154,22 +237,86 @@ pub fn normalize_words(title: &str, dest: &mut Vec<String>) {
     // punctuation as part of a name.
     let cut = "/\\@_+-:;!?<>";
 
-    // Loop over the characters, normalized and lowercased.
-    for ch in title.nfkd().flat_map(|nch| nch.to_lowercase()) {
-        match ch {
-            // Split words at whitespace or at the cut characters.
-            _ if ch.is_whitespace() => {
-                push_word(dest, &mut word);
-            }
-            _ if cut.contains(ch) => {
+    match ch {
+        // Split words at whitespace or at the cut characters. The cut
+        // characters are all ASCII, so we can slice them out of `cut`.
+        _ if ch.is_whitespace() => Step::Break,
+        _ if cut.contains(ch) => {
+            let i = cut.find(ch).expect("Contains the character, checked above.");
+            Step::BreakWith(&cut[i..i + 1])
+        }
+        // The period is special: generally we don't want to include it as a
+        // word, and simply ignore it altogether. (E.g. "S.P.Y" turns into
+        // "spy".) But the ellipisis (...) we do want to keep. There are
+        // even tracks titled "...". So we need to detect the ellipsis.
+        '.' => Step::Dot,
+        // Treat the upside-down question mark as a separator like the
+        // regular one, but then do include the upright one as the word,
+        // so you can search for ¿ by typing ?. Same for exclamation mark.
+        '¿' => Step::BreakWith("?"),
+        '¡' => Step::BreakWith("!"),
+        // Cut on an en- and em-dash just like we cut on a hyphen, but
+        // include the hyphen, so they are equivalent for the purpose of
+        // search.
+        '–' | '—' => Step::BreakWith("-"),
+        // Normalize a few characters to more common ones.
+        // Sometimes used in "n°", map to "no".
+        '°' => Step::Push('o'),
+        '♯' => Step::Push('#'),
+        'ø' => Step::Push('o'),
+        'ð' => Step::Push('d'),
+        '×' => Step::Push('x'),
+        'æ' => Step::PushStr("ae"),
+        'œ' => Step::PushStr("oe"),
+        // A hyphen, use the ascii one instead.
+        '\u{2010}' => Step::Push('-'),
+        // I do want to be able to find my Justice albums with a normal
+        // keyboard.
+        '✝' => Step::BreakWith("cross"),
+        '∞' => Step::BreakWith("infinity"),
+        '¥' => Step::BreakWith("yen"),
+        // The Japanese (semi-)voiced sound marks are not accents, they turn
+        // e.g. "ha" into "ba" or "pa", keep them so we can transliterate.
+        '\u{3099}' | '\u{309a}' => Step::Push(ch),
+        // Drop characters that we don't care for, keep characters that we
+        // definitely care for.
+        _ if drop.contains(ch) => Step::Skip,
+        _ if keep.contains(ch) || ch.is_alphanumeric() => Step::Push(ch),
+        _ => Step::Unknown,
+    }
+}
+
+/// The characters of a title, normalized and lowercased.
+type TitleChars<'a> = FlatMap<Decompositions<Chars<'a>>, ToLowercase, fn(char) -> ToLowercase>;
+
+fn title_chars(title: &str) -> TitleChars<'_> {
+    title.nfkd().flat_map(char::to_lowercase as fn(char) -> ToLowercase)
+}
+
+/// Fills the vector with the words in the string in normalized form.
+///
+/// This first normalizes words to Unicode Normalization Form KD, which
+/// decomposes characters with accents into the character and the accent
+/// separately. The "KD" form, as opposed to the "D" form, also replaces more
+/// things that have the same semantic meaning, such as replacing superscripts
+/// with normal digits. Finally (not part of the KD normalization), everything
+/// is lowercased, and accents and some punctuation are removed.
+pub fn normalize_words(title: &str, dest: &mut Vec<String>) {
+    // We assume that in the majority of the cases, the transformations
+    // below do not change the number of bytes.
+    let mut word = String::new();
+    let mut num_dots = 0;
+
+    for ch in title_chars(title) {
+        match classify(ch) {
+            Step::Break => push_word(dest, &mut word),
+            Step::BreakWith(w) => {
                 push_word(dest, &mut word);
-                dest.push(ch.to_string());
+                dest.push(w.to_string());
             }
-            // The period is special: generally we don't want to include it as a
-            // word, and simply ignore it altogether. (E.g. "S.P.Y" turns into
-            // "spy".) But the ellipisis (...) we do want to keep. There are
-            // even tracks titled "...". So we need to detect the ellipsis.
-            '.' => {
+            Step::Push(c) => word.push(c),
+            Step::PushStr(s) => word.push_str(s),
+            Step::Dot => {
                 num_dots += 1;
                 if num_dots == 3 {
                     dest.push("...".to_string());
@@ -177,67 +324,325 @@ pub fn normalize_words(title: &str, dest: &mut Vec<String>) {
                 }
                 continue
             }
-            // Treat the upside-down question mark as a separator like the
-            // regular one, but then do include the upright one as the word,
-            // so you can search for ¿ by typing ?. Same for exclamation mark.
-            '¿' => {
-                push_word(dest, &mut word);
-                dest.push("?".to_string());
+            Step::Skip => {}
+            Step::Unknown => panic!("Unknown character {} ({}) in title: {}", ch, ch.escape_unicode(), title),
+        }
+
+        // Reset the ellipsis counter after every non-period character.
+        num_dots = 0;
+    }
+
+    // Push the final word.
+    push_word(dest, &mut word);
+}
+
+/// Iterator over the words of a title, as `normalize_words` produces them,
+/// joined by a single space, without allocating.
+///
+/// The words never contain a space, so two titles have the same normalized
+/// words if and only if these iterators yield the same characters.
+pub struct NormalizedChars<'a> {
+    title: &'a str,
+    chars: TitleChars<'a>,
+    num_dots: u32,
+    /// Whether we yielded a word before, so the next one needs a separator.
+    any_word: bool,
+    /// Whether we are inside a word, so a pushed character extends it.
+    in_word: bool,
+    separator: bool,
+    pending_char: Option<char>,
+    pending_str: Chars<'static>,
+}
+
+impl<'a> NormalizedChars<'a> {
+    pub fn new(title: &'a str) -> NormalizedChars<'a> {
+        NormalizedChars {
+            title,
+            chars: title_chars(title),
+            num_dots: 0,
+            any_word: false,
+            in_word: false,
+            separator: false,
+            pending_char: None,
+            pending_str: "".chars(),
+        }
+    }
+
+    fn begin_word(&mut self) {
+        if !self.in_word {
+            self.separator = self.any_word;
+            self.any_word = true;
+            self.in_word = true;
+        }
+    }
+
+    /// Yield `word` as a word of its own.
+    fn push_word(&mut self, word: &'static str) {
+        self.in_word = false;
+        self.begin_word();
+        self.pending_str = word.chars();
+        self.in_word = false;
+    }
+
+    /// Return whether an ellipsis follows before the word that starts here ends.
+    ///
+    /// `normalize_words` discards the word that it is building when it finds
+    /// an ellipsis, so we need to look ahead before we yield anything of it.
+    /// If there is one, this skips to after the ellipsis.
+    fn skip_to_ellipsis(&mut self) -> bool {
+        let mut chars = self.chars.clone();
+        let mut num_dots = 0;
+        for ch in &mut chars {
+            match classify(ch) {
+                Step::Break | Step::BreakWith(..) | Step::Unknown => return false,
+                Step::Dot => {
+                    num_dots += 1;
+                    if num_dots == 3 {
+                        self.chars = chars;
+                        self.num_dots = 3;
+                        return true
+                    }
+                }
+                _ => num_dots = 0,
             }
-            '¡' => {
-                push_word(dest, &mut word);
-                dest.push("!".to_string());
+        }
+        false
+    }
+}
+
+impl<'a> Iterator for NormalizedChars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        loop {
+            if self.separator {
+                self.separator = false;
+                return Some(' ')
             }
-            // Cut on an en- and em-dash just like we cut on a hyphen, but
-            // include the hyphen, so they are equivalent for the purpose of
-            // search.
-            '–' | '—' => {
-                push_word(dest, &mut word);
-                dest.push("-".to_string());
+            if let Some(c) = self.pending_char.take() {
+                return Some(c)
             }
-            // Normalize a few characters to more common ones.
-            // Sometimes used in "n°", map to "no".
-            '°' => word.push('o'),
-            '♯' => word.push('#'),
-            'ø' => word.push('o'),
-            'ð' => word.push('d'),
-            '×' => word.push('x'),
-            'æ' => word.push_str("ae"),
-            'œ' => word.push_str("oe"),
-            // A hyphen, use the ascii one instead.
-            '\u{2010}' => word.push('-'),
-            // I do want to be able to find my Justice albums with a normal
-            // keyboard.
-            '✝' => {
-                push_word(dest, &mut word);
-                dest.push("cross".to_string());
+            if let Some(c) = self.pending_str.next() {
+                return Some(c)
             }
-            '∞' => {
-                push_word(dest, &mut word);
-                dest.push("infinity".to_string());
+
+            let ch = self.chars.next()?;
+            let step = classify(ch);
+            match step {
+                Step::Break => self.in_word = false,
+                Step::BreakWith(w) => self.push_word(w),
+                Step::Push(..) | Step::PushStr(..) if !self.in_word && self.skip_to_ellipsis() => {
+                    self.push_word("...");
+                    continue
+                }
+                Step::Push(c) => {
+                    self.begin_word();
+                    self.pending_char = Some(c);
+                }
+                Step::PushStr(s) => {
+                    self.begin_word();
+                    self.pending_str = s.chars();
+                }
+                Step::Dot => {
+                    self.num_dots += 1;
+                    if self.num_dots == 3 {
+                        self.push_word("...");
+                    }
+                    continue
+                }
+                Step::Skip => {}
+                Step::Unknown => panic!("Unknown character {} ({}) in title: {}", ch, ch.escape_unicode(), self.title),
             }
-            '¥' => {
-                push_word(dest, &mut word);
-                dest.push("yen".to_string());
+
+            // Reset the ellipsis counter after every non-period character.
+            self.num_dots = 0;
+        }
+    }
+}
+
+/// Return whether the two titles have the same normalized words.
+///
+/// This is equivalent to comparing the output of `normalize_words`, but it
+/// does not allocate, and it stops at the first difference.
+pub fn equals_normalized(x1: &str, x2: &str) -> bool {
+    NormalizedChars::new(x1).eq(NormalizedChars::new(x2))
+}
+
+/// Return the Latin transliteration of a Cyrillic or Greek letter.
+///
+/// Expects lowercase letters without diacritics, as produced by
+/// `normalize_words`.
+fn transliterate_letter(ch: char) -> Option<&'static str> {
+    let latin = match ch {
+        // Cyrillic, with the Ukrainian and Serbian additions.
+        'а' => "a", 'б' => "b", 'в' => "v", 'г' => "g", 'д' => "d", 'е' => "e",
+        'ж' => "zh", 'з' => "z", 'и' => "i", 'к' => "k", 'л' => "l", 'м' => "m",
+        'н' => "n", 'о' => "o", 'п' => "p", 'р' => "r", 'с' => "s", 'т' => "t",
+        'у' => "u", 'ф' => "f", 'х' => "kh", 'ц' => "ts", 'ч' => "ch", 'ш' => "sh",
+        'щ' => "shch", 'ъ' => "", 'ы' => "y", 'ь' => "", 'э' => "e", 'ю' => "yu",
+        'я' => "ya", 'є' => "ye", 'і' => "i", 'ґ' => "g", 'ђ' => "dj", 'ј' => "j",
+        'љ' => "lj", 'њ' => "nj", 'ћ' => "c", 'џ' => "dz", 'ѕ' => "dz",
+        // Greek.
+        'α' => "a", 'β' => "v", 'γ' => "g", 'δ' => "d", 'ε' => "e", 'ζ' => "z",
+        'η' => "i", 'θ' => "th", 'ι' => "i", 'κ' => "k", 'λ' => "l", 'μ' => "m",
+        'ν' => "n", 'ξ' => "x", 'ο' => "o", 'π' => "p", 'ρ' => "r", 'σ' => "s",
+        'ς' => "s", 'τ' => "t", 'υ' => "y", 'φ' => "f", 'χ' => "ch", 'ψ' => "ps",
+        'ω' => "o",
+        _ => return None,
+    };
+    Some(latin)
+}
+
+/// Return the Hepburn romanization of a hiragana or katakana character.
+///
+/// Small kana that modify the preceding syllable are handled by the caller.
+fn transliterate_kana(ch: char) -> Option<&'static str> {
+    // Katakana are laid out in the same order as hiragana, 0x60 code points
+    // further.
+    let ch = match ch {
+        '\u{30a1}'..='\u{30f6}' => char::from_u32(ch as u32 - 0x60).expect("Valid kana."),
+        _ => ch,
+    };
+    let romaji = match ch {
+        'あ' => "a", 'い' => "i", 'う' => "u", 'え' => "e", 'お' => "o",
+        'か' => "ka", 'き' => "ki", 'く' => "ku", 'け' => "ke", 'こ' => "ko",
+        'が' => "ga", 'ぎ' => "gi", 'ぐ' => "gu", 'げ' => "ge", 'ご' => "go",
+        'さ' => "sa", 'し' => "shi", 'す' => "su", 'せ' => "se", 'そ' => "so",
+        'ざ' => "za", 'じ' => "ji", 'ず' => "zu", 'ぜ' => "ze", 'ぞ' => "zo",
+        'た' => "ta", 'ち' => "chi", 'つ' => "tsu", 'て' => "te", 'と' => "to",
+        'だ' => "da", 'ぢ' => "ji", 'づ' => "zu", 'で' => "de", 'ど' => "do",
+        'な' => "na", 'に' => "ni", 'ぬ' => "nu", 'ね' => "ne", 'の' => "no",
+        'は' => "ha", 'ひ' => "hi", 'ふ' => "fu", 'へ' => "he", 'ほ' => "ho",
+        'ば' => "ba", 'び' => "bi", 'ぶ' => "bu", 'べ' => "be", 'ぼ' => "bo",
+        'ぱ' => "pa", 'ぴ' => "pi", 'ぷ' => "pu", 'ぺ' => "pe", 'ぽ' => "po",
+        'ま' => "ma", 'み' => "mi", 'む' => "mu", 'め' => "me", 'も' => "mo",
+        'や' => "ya", 'ゆ' => "yu", 'よ' => "yo",
+        'ら' => "ra", 'り' => "ri", 'る' => "ru", 'れ' => "re", 'ろ' => "ro",
+        'わ' => "wa", 'ゐ' => "i", 'ゑ' => "e", 'を' => "o", 'ん' => "n", 'ゔ' => "vu",
+        'ヷ' => "va", 'ヸ' => "vi", 'ヹ' => "ve", 'ヺ' => "vo",
+        _ => return None,
+    };
+    Some(romaji)
+}
+
+/// Return the transliteration into Latin script of a normalized word.
+///
+/// Handles Cyrillic, Greek, and Japanese kana; kanji would need a dictionary,
+/// so they are left as-is. Returns `None` if the word contains nothing to
+/// transliterate. The transliteration is meant for search, not for display:
+/// it favors the spelling that people are likely to type, so “Чайковский”
+/// becomes “chaikovsky”.
+pub fn transliterate(word: &str) -> Option<String> {
+    let mut result = String::with_capacity(word.len());
+    let mut changed = false;
+    // Whether the previous character was a small tsu, which doubles the next
+    // consonant.
+    let mut double_next = false;
+
+    // Recompose, such that the Japanese voiced sound marks that
+    // `normalize_words` keeps combine with their kana again.
+    let chars: Vec<char> = word.nfc().collect();
+
+    for (i, &ch) in chars.iter().enumerate() {
+        // Russian adjectives and surnames end in -ий or -ый, which after
+        // dropping the breve is -ии or -ыи. These are commonly spelled -y.
+        if (ch == 'и' || ch == 'ы') && i + 2 == chars.len() && chars[i + 1] == 'и' {
+            result.push('y');
+            changed = true;
+            break
+        }
+
+        // In Greek, "ου" is pronounced "ou", not "oy".
+        if ch == 'υ' && i > 0 && chars[i - 1] == 'ο' {
+            result.push('u');
+            changed = true;
+            continue
+        }
+
+        if let Some(latin) = transliterate_letter(ch) {
+            result.push_str(latin);
+            changed = true;
+            continue
+        }
+
+        match ch {
+            'っ' | 'ッ' => {
+                double_next = true;
+                changed = true;
+                continue
             }
-            // Drop characters that we don't care for, keep characters that we
-            // definitely care for.
-            _ if drop.contains(ch) => {}
-            _ if keep.contains(ch) || ch.is_alphanumeric() => word.push(ch),
-            _ => panic!("Unknown character {} ({}) in title: {}", ch, ch.escape_unicode(), title),
+            // The long vowel mark. Romanization marks it with a macron, but
+            // for search it is more useful to drop it entirely.
+            'ー' => {
+                changed = true;
+                continue
+            }
+            // Small ya, yu, yo combine with a preceding i-syllable:
+            // ki + ya = kya, shi + ya = sha.
+            'ゃ' | 'ゅ' | 'ょ' | 'ャ' | 'ュ' | 'ョ' => {
+                let vowel = match ch {
+                    'ゃ' | 'ャ' => 'a',
+                    'ゅ' | 'ュ' => 'u',
+                    _ => 'o',
+                };
+                if result.ends_with('i') {
+                    result.pop();
+                    if !(result.ends_with("sh") || result.ends_with("ch") || result.ends_with('j')) {
+                        result.push('y');
+                    }
+                } else {
+                    result.push('y');
+                }
+                result.push(vowel);
+                changed = true;
+                continue
+            }
+            // Small vowels replace the vowel of the preceding syllable, as in
+            // フィ (fi) or ティ (ti).
+            'ぁ' | 'ぃ' | 'ぅ' | 'ぇ' | 'ぉ' | 'ァ' | 'ィ' | 'ゥ' | 'ェ' | 'ォ' => {
+                let vowel = match ch {
+                    'ぁ' | 'ァ' => 'a',
+                    'ぃ' | 'ィ' => 'i',
+                    'ぅ' | 'ゥ' => 'u',
+                    'ぇ' | 'ェ' => 'e',
+                    _ => 'o',
+                };
+                if result.ends_with(|c: char| "aiueo".contains(c)) && result.len() > 1 {
+                    result.pop();
+                }
+                result.push(vowel);
+                changed = true;
+                continue
+            }
+            _ => {}
         }
 
-        // Reset the ellipsis counter after every non-period character.
-        num_dots = 0;
+        match transliterate_kana(ch) {
+            Some(romaji) => {
+                if double_next {
+                    // Small tsu doubles the consonant, but "ch" becomes "tch".
+                    match romaji.strip_prefix("ch") {
+                        Some(_) => result.push('t'),
+                        None => result.push_str(&romaji[..1]),
+                    }
+                }
+                result.push_str(romaji);
+                changed = true;
+            }
+            None => result.push(ch),
+        }
+        double_next = false;
     }
 
-    // Push the final word.
-    push_word(dest, &mut word);
+    match changed {
+        true => Some(result),
+        false => None,
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::normalize_words;
+    use super::{equals_normalized, normalize_words, transliterate, NormalizedChars, StringArena};
 
     fn expect_normalize_words(input: &str, expected_output: &[&str]) {
         let mut words = Vec::new();
@@ -246,9 +651,88 @@ mod test {
         assert_eq!(&words_slice[..], expected_output);
     }
 
+    #[test]
+    pub fn test_string_arena() {
+        let mut arena = StringArena::new();
+        assert!(arena.is_empty());
+        assert_eq!(arena.push("Röyksopp"), 0);
+        assert_eq!(arena.push(""), 1);
+        assert_eq!(arena.push("Eple"), 2);
+        assert_eq!(arena.len(), 3);
+        assert_eq!(arena.get(0), "Röyksopp");
+        assert_eq!(arena.get(1), "");
+        assert_eq!(arena.get(2), "Eple");
+
+        let copy = StringArena::from_parts(arena.data().to_string(), arena.offsets().to_vec()).unwrap();
+        assert_eq!(copy.get(2), "Eple");
+        // Offsets must be in order, and must not split a character.
+        assert!(StringArena::from_parts("Röyksopp".to_string(), vec![0, 2, 9]).is_none());
+        assert!(StringArena::from_parts("Eple".to_string(), vec![0, 3, 2, 4]).is_none());
+        assert!(StringArena::from_parts("Eple".to_string(), vec![0, 3]).is_none());
+    }
+
     #[test]
     pub fn test_normalize_words() {
         expect_normalize_words("Ṣānnu yārru lī", &["sannu", "yarru", "li"]);
         expect_normalize_words("Orð vǫlu", &["ord", "volu"]);
     }
+
+    #[test]
+    pub fn test_normalized_chars_matches_normalize_words() {
+        let titles = [
+            "",
+            "   ",
+            "Around the World",
+            "Ṣānnu yārru lī",
+            "Daft Punk — Harder, Better, Faster, Stronger",
+            "S.P.Y",
+            "...",
+            "Wait...",
+            "Wait ... for it",
+            "a.b...c....d......e",
+            "..x...",
+            "¿Qué?",
+            "Hello/World + You: Part Ⅱ",
+            "Cœur d’Æther",
+            "✝ ∞ ¥",
+            "No°1 (Remix) [Bonus Track]",
+            "ドラゴンボール",
+        ];
+        for title in titles {
+            let mut words = Vec::new();
+            normalize_words(title, &mut words);
+            let chars: String = NormalizedChars::new(title).collect();
+            assert_eq!(chars, words.join(" "), "Title: {}", title);
+        }
+    }
+
+    #[test]
+    pub fn test_equals_normalized() {
+        assert!(equals_normalized("Don't Stop", "Don’t  stop"));
+        assert!(equals_normalized("Harder – Better", "harder - better"));
+        assert!(equals_normalized("Wait...", "..."));
+        assert!(!equals_normalized("Around the World", "Around the Worlds"));
+        assert!(!equals_normalized("Around the", "Around the World"));
+        assert!(!equals_normalized("ab", "a b"));
+    }
+
+    #[test]
+    pub fn test_transliterate() {
+        let transliterate_title = |title: &str| -> Vec<String> {
+            let mut words = Vec::new();
+            normalize_words(title, &mut words);
+            words.iter().map(|w| transliterate(w).unwrap_or_else(|| w.clone())).collect()
+        };
+        assert_eq!(transliterate_title("Пётр Ильич Чайковский"), ["petr", "ilich", "chaikovsky"]);
+        assert_eq!(transliterate_title("Щелкунчик"), ["shchelkunchik"]);
+        assert_eq!(transliterate_title("Μίκης Θεοδωράκης"), ["mikis", "theodorakis"]);
+        assert_eq!(transliterate_title("Ζορμπάς ο Έλληνας"), ["zormpas", "o", "ellinas"]);
+        assert_eq!(transliterate_title("Μουσική"), ["mousiki"]);
+        assert_eq!(transliterate_title("ドラゴンボール"), ["doragonboru"]);
+        assert_eq!(transliterate_title("きゃりーぱみゅぱみゅ"), ["kyaripamyupamyu"]);
+        assert_eq!(transliterate_title("しょうじょ"), ["shoujo"]);
+        assert_eq!(transliterate_title("マッチ ロック"), ["matchi", "rokku"]);
+        assert_eq!(transliterate_title("パーティー"), ["pati"]);
+        assert_eq!(transliterate("abba"), None);
+    }
 }
@@ -8,7 +8,9 @@
 //! Minimal bindings to libsystemd.
 
 use std::os::raw::{c_char, c_int};
+use std::os::unix::io::FromRawFd;
 use std::ffi::CStr;
+use std::net::TcpListener;
 
 #[link(name = "systemd")]
 extern {
@@ -51,3 +53,30 @@ pub fn notify_ready_if_can_notify() {
         notify(message).expect("Failed to notify systemd of readiness.");
     }
 }
+
+/// Take the listening socket that systemd passed, when socket-activated.
+///
+/// Systemd passes sockets as file descriptors starting at 3, and sets
+/// `LISTEN_FDS` to their number, and `LISTEN_PID` to the pid of the process
+/// that they are meant for. We support exactly one socket. We remove the
+/// variables, so the programs that we execute don't try to take the socket.
+///
+/// Changing the environment is not safe while other threads may read it, so
+/// call this at startup, before spawning any threads.
+pub fn take_listen_socket() -> Option<TcpListener> {
+    let pid = std::env::var("LISTEN_PID").ok()?;
+    let n_fds = std::env::var("LISTEN_FDS").ok()?;
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if pid != std::process::id().to_string() || n_fds != "1" {
+        return None
+    }
+
+    // SD_LISTEN_FDS_START in sd-daemon.h.
+    let listen_fds_start = 3;
+    // Safety: Systemd passed us this file descriptor, and nothing else in
+    // this process uses it, because the variables that point to it are gone.
+    Some(unsafe { TcpListener::from_raw_fd(listen_fds_start) })
+}
@@ -0,0 +1,305 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Record what the playback thread plays to a file, the "tape".
+//!
+//! The playback thread sends a copy of every buffer that it writes to the
+//! audio device to the tape thread, which writes them to a wav file. We start
+//! a new file when playback starts, and when the sample format changes, because
+//! a wav file can hold only one format.
+//!
+//! The samples that we send to the device are normalized, but not yet at the
+//! target volume: the device applies that in its hardware volume control. So
+//! the tape thread applies the volume in software, to record what you hear.
+//!
+//! A wav file can hold at most 4 GiB, which is about 3.5 hours at 24 bits and
+//! 96 kHz. When a recording reaches that size, we continue in a new file.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::player::{Format, Millibel};
+
+/// Which file format to record to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TapeFormat {
+    Wav,
+    /// Record to wav, then compress the file with the `flac` program.
+    Flac,
+}
+
+impl TapeFormat {
+    pub fn parse(format: &str) -> Option<TapeFormat> {
+        match format {
+            "wav" => Some(TapeFormat::Wav),
+            "flac" => Some(TapeFormat::Flac),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TapeFormat::Wav => "wav",
+            TapeFormat::Flac => "flac",
+        }
+    }
+}
+
+enum TapeEvent {
+    /// Samples that the playback thread sent to the device at `volume`.
+    Samples { format: Format, volume: Millibel, data: Vec<u8> },
+    /// Playback stopped, finish the current file.
+    End,
+}
+
+/// Handle for the playback thread to send samples to the tape thread.
+pub struct Tape {
+    sender: Sender<TapeEvent>,
+}
+
+impl Tape {
+    /// Start the tape thread, which writes files into the directory `path`.
+    pub fn new(path: PathBuf, format: TapeFormat) -> Tape {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("tape".to_string())
+            .spawn(move || main(&path, format, receiver))
+            .expect("Failed to spawn tape thread.");
+        Tape { sender }
+    }
+
+    /// Record samples, in the byte format that we send to the device.
+    pub fn record(&self, format: Format, volume: Millibel, data: &[u8]) {
+        let event = TapeEvent::Samples { format, volume, data: data.to_vec() };
+        self.sender.send(event).expect("Tape thread runs indefinitely, sending does not fail.");
+    }
+
+    /// Finish the current file, playback stopped.
+    pub fn end(&self) {
+        self.sender.send(TapeEvent::End).expect("Tape thread runs indefinitely, sending does not fail.");
+    }
+}
+
+/// Scale the little-endian interleaved samples in `data` by `volume`.
+///
+/// Samples that would exceed full scale are clipped.
+fn apply_volume(bits_per_sample: u32, volume: Millibel, data: &mut [u8]) {
+    let gain = 10.0_f32.powf(volume.0 as f32 / 2000.0);
+    match bits_per_sample {
+        16 => for sample in data.chunks_exact_mut(2) {
+            let x = i16::from_le_bytes([sample[0], sample[1]]) as f32 * gain;
+            let y = x.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            sample.copy_from_slice(&y.to_le_bytes());
+        }
+        24 => for sample in data.chunks_exact_mut(3) {
+            // Put the sample in the upper three bytes, so the sign extends.
+            let x = (i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8) as f32 * gain;
+            let y = x.round().clamp(-8_388_608.0, 8_388_607.0) as i32;
+            sample.copy_from_slice(&y.to_le_bytes()[..3]);
+        }
+        n => unreachable!("The playback thread does not play {} bits per sample.", n),
+    }
+}
+
+/// Size of the wav header that counts towards the RIFF chunk size.
+const WAV_HEADER_LEN: u32 = 36;
+
+/// Maximum number of sample bytes in a wav file, so the RIFF size fits a `u32`.
+const MAX_DATA_LEN: u32 = u32::MAX - WAV_HEADER_LEN;
+
+/// Write the header of a wav file with two channels of integer samples.
+fn write_wav_header<W: Write>(mut w: W, format: Format, data_len: u32) -> io::Result<()> {
+    let riff_len = match WAV_HEADER_LEN.checked_add(data_len) {
+        Some(n) => n,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Recording is too large for a wav file.")),
+    };
+    let num_channels = 2;
+    let block_align = num_channels * format.bits_per_sample / 8;
+    w.write_all(b"RIFF")?;
+    w.write_all(&riff_len.to_le_bytes())?;
+    w.write_all(b"WAVEfmt ")?;
+    w.write_all(&16_u32.to_le_bytes())?;
+    // Format 1 is integer PCM.
+    w.write_all(&1_u16.to_le_bytes())?;
+    w.write_all(&(num_channels as u16).to_le_bytes())?;
+    w.write_all(&format.sample_rate.0.to_le_bytes())?;
+    w.write_all(&(format.sample_rate.0 * block_align).to_le_bytes())?;
+    w.write_all(&(block_align as u16).to_le_bytes())?;
+    w.write_all(&(format.bits_per_sample as u16).to_le_bytes())?;
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    Ok(())
+}
+
+/// A wav file that we are writing to.
+struct Recording {
+    path: PathBuf,
+    format: Format,
+    writer: BufWriter<File>,
+    data_len: u32,
+}
+
+impl Recording {
+    fn create(dir: &Path, format: Format) -> io::Result<Recording> {
+        let fname = format!("musium-{}.wav", Utc::now().format("%Y-%m-%dT%H%M%S%.3fZ"));
+        let path = dir.join(fname);
+        let mut writer = BufWriter::new(File::create(&path)?);
+        // We don't know the length yet, we fill it in when we finish.
+        write_wav_header(&mut writer, format, 0)?;
+        info!("Recording playback to {}.", path.to_string_lossy());
+        Ok(Recording { path, format, writer, data_len: 0 })
+    }
+
+    /// Return whether `n` more bytes fit in the file.
+    fn has_room(&self, n: usize) -> bool {
+        (MAX_DATA_LEN - self.data_len) as usize >= n
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        debug_assert!(self.has_room(data.len()), "Caller should have started a new file.");
+        self.writer.write_all(data)?;
+        self.data_len += data.len() as u32;
+        Ok(())
+    }
+
+    /// Fill in the length in the header, and close the file.
+    fn finish(mut self) -> io::Result<PathBuf> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.writer, self.format, self.data_len)?;
+        self.writer.flush()?;
+        Ok(self.path)
+    }
+}
+
+/// Compress the wav file to flac in the background, and delete the wav file.
+fn compress_flac(wav_path: PathBuf) {
+    let result = std::thread::Builder::new()
+        .name("tape_flac".to_string())
+        .spawn(move || {
+            let status = Command::new("flac")
+                .arg("--silent")
+                .arg("--delete-input-file")
+                .arg(&wav_path)
+                .stdin(Stdio::null())
+                .status();
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("Compressing {} with 'flac' failed: {}", wav_path.to_string_lossy(), status),
+                Err(err) => warn!("Failed to spawn 'flac' to compress {}: {}", wav_path.to_string_lossy(), err),
+            }
+        });
+    if let Err(err) = result {
+        error!("Failed to spawn tape compression thread: {:?}", err);
+    }
+}
+
+fn finish(recording: Option<Recording>, tape_format: TapeFormat) {
+    let recording = match recording {
+        Some(r) => r,
+        None => return,
+    };
+    match recording.finish() {
+        Ok(path) if tape_format == TapeFormat::Flac => compress_flac(path),
+        Ok(..) => {}
+        Err(err) => error!("Failed to finish the recording: {}", err),
+    }
+}
+
+fn main(dir: &Path, tape_format: TapeFormat, events: Receiver<TapeEvent>) {
+    let mut recording: Option<Recording> = None;
+
+    // When writing fails, we stop recording until playback starts again,
+    // rather than logging an error for every buffer.
+    let mut failed = false;
+
+    for event in events {
+        let (format, volume, mut data) = match event {
+            TapeEvent::Samples { format, volume, data } => (format, volume, data),
+            TapeEvent::End => {
+                finish(recording.take(), tape_format);
+                failed = false;
+                continue
+            }
+        };
+
+        if failed {
+            continue
+        }
+
+        let needs_new_file = match recording.as_ref() {
+            Some(r) => r.format != format || !r.has_room(data.len()),
+            None => true,
+        };
+        if needs_new_file {
+            finish(recording.take(), tape_format);
+            match Recording::create(dir, format) {
+                Ok(r) => recording = Some(r),
+                Err(err) => {
+                    error!("Failed to start recording in {}: {}", dir.to_string_lossy(), err);
+                    failed = true;
+                    continue
+                }
+            }
+        }
+
+        apply_volume(format.bits_per_sample, volume, &mut data);
+        if let Some(r) = recording.as_mut() {
+            if let Err(err) = r.write(&data) {
+                error!("Failed to write recording, stopping until playback resumes: {}", err);
+                finish(recording.take(), tape_format);
+                failed = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::player::{Format, Millibel};
+    use crate::prim::Hertz;
+    use super::{apply_volume, write_wav_header, MAX_DATA_LEN};
+
+    #[test]
+    fn apply_volume_scales_and_clips_samples() {
+        // At -20 dB, 1000 becomes 100.
+        let mut data = [0xe8, 0x03, 0x18, 0xfc];
+        apply_volume(16, Millibel(-20_00), &mut data);
+        assert_eq!(data, [0x64, 0x00, 0x9c, 0xff]);
+
+        let mut data = [0xe8, 0x03, 0x00, 0x18, 0xfc, 0xff];
+        apply_volume(24, Millibel(-20_00), &mut data);
+        assert_eq!(data, [0x64, 0x00, 0x00, 0x9c, 0xff, 0xff]);
+
+        let mut data = [0x00, 0x60, 0x00, 0xa0];
+        apply_volume(16, Millibel(600), &mut data);
+        assert_eq!(data, [0xff, 0x7f, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn write_wav_header_writes_44_bytes() {
+        let format = Format { sample_rate: Hertz(44_100), bits_per_sample: 24 };
+        let mut header = Vec::new();
+        write_wav_header(&mut header, format, 600).unwrap();
+        assert_eq!(header.len(), 44);
+        assert_eq!(&header[..4], b"RIFF");
+        assert_eq!(&header[4..8], &636_u32.to_le_bytes());
+        assert_eq!(&header[28..32], &264_600_u32.to_le_bytes());
+        assert_eq!(&header[32..36], &[6, 0, 24, 0]);
+        assert_eq!(&header[40..], &600_u32.to_le_bytes());
+
+        let mut header = Vec::new();
+        write_wav_header(&mut header, format, MAX_DATA_LEN).unwrap();
+        assert_eq!(&header[4..8], &u32::MAX.to_le_bytes());
+        assert!(write_wav_header(&mut Vec::new(), format, MAX_DATA_LEN + 1).is_err());
+    }
+}
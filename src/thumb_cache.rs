@@ -7,12 +7,14 @@
 
 //! Defines an in-memory thumbnail cache.
 
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::sync::{Arc, Mutex};
 
 use crate::AlbumId;
 use crate::album_table::AlbumTable;
 use crate::database as db;
-use crate::database::Transaction;
+use crate::database::{Connection, Transaction};
 
 /// References a single image in the larger concatenated array.
 #[derive(Copy, Clone, Debug)]
@@ -21,6 +23,34 @@ struct ImageReference {
     end: u32,
 }
 
+/// A thumbnail, that shares the buffer it is stored in.
+///
+/// Thumbnails are served often, so rather than copying one out of the cache
+/// for every request, the response reads it straight from the cache buffer.
+#[derive(Clone, Debug)]
+pub struct Thumbnail {
+    data: Arc<[u8]>,
+    begin: usize,
+    end: usize,
+}
+
+impl Thumbnail {
+    fn new(data: Arc<[u8]>) -> Thumbnail {
+        let end = data.len();
+        Thumbnail { data, begin: 0, end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.begin
+    }
+}
+
+impl AsRef<[u8]> for Thumbnail {
+    fn as_ref(&self) -> &[u8] {
+        &self.data[self.begin..self.end]
+    }
+}
+
 /// A memory-backed dictionary of album id to cover art thumbnail.
 ///
 /// We want to store all thumbnails in memory to be able to serve them quickly.
@@ -49,35 +79,151 @@ struct ImageReference {
 /// browser queues new requests, even if the search requests could immediately
 /// be served from memory. This was a real bad user experience, so therefore I
 /// want to make sure that we can serve thumbnails without hitting the disk.
+///
+/// On a device with little memory, the `thumb_cache_size` setting limits the
+/// cache to the most recently requested thumbnails. Thumbnails that are not
+/// in memory are then read from the database when they are requested. A size
+/// of 0 disables the cache entirely.
 pub struct ThumbCache {
-    data: Box<[u8]>,
-    references: AlbumTable<ImageReference>,
+    mode: Mode,
+}
+
+enum Mode {
+    /// All thumbnails, in one buffer.
+    Full {
+        data: Arc<[u8]>,
+        references: AlbumTable<ImageReference>,
+        /// Thumbnails generated on demand after we loaded the buffer.
+        generated: Mutex<HashMap<AlbumId, Arc<[u8]>>>,
+    },
+    /// Only the most recently used thumbnails.
+    Lru(Mutex<Lru>),
+}
+
+/// The thumbnail of an album, or `None` if we know that the album has none.
+type MaybeThumb = Option<Arc<[u8]>>;
+
+/// Thumbnails keyed by album, that evicts the least recently used one when full.
+struct Lru {
+    capacity: usize,
+
+    /// Counter that increments on every use, to order the entries by recency.
+    clock: u64,
+
+    /// The thumbnails, with the clock value of their last use. We also
+    /// remember albums without thumbnail, so we don't query the database for
+    /// them on every request.
+    entries: HashMap<AlbumId, (u64, MaybeThumb)>,
+
+    /// The album of every entry, by the clock value of its last use.
+    by_use: BTreeMap<u64, AlbumId>,
+
+    /// Total size of the thumbnails in `entries`.
+    data_bytes: usize,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Lru {
+        Lru {
+            capacity,
+            clock: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            data_bytes: 0,
+        }
+    }
+
+    /// Look up an album, and mark it as most recently used.
+    ///
+    /// Returns `None` when the album is not in the cache, and `Some(None)`
+    /// when the cache knows that the album has no thumbnail.
+    fn get(&mut self, album_id: AlbumId) -> Option<MaybeThumb> {
+        let (last_use, data) = self.entries.get_mut(&album_id)?;
+        self.by_use.remove(last_use);
+        self.clock += 1;
+        *last_use = self.clock;
+        self.by_use.insert(self.clock, album_id);
+        Some(data.clone())
+    }
+
+    /// Insert a thumbnail, or the absence of one, evicting the least recently
+    /// used entry if needed.
+    fn insert(&mut self, album_id: AlbumId, data: MaybeThumb) {
+        if self.capacity == 0 {
+            return
+        }
+        if let Some((last_use, old)) = self.entries.remove(&album_id) {
+            self.by_use.remove(&last_use);
+            self.data_bytes -= old.map_or(0, |d| d.len());
+        }
+        while self.entries.len() >= self.capacity {
+            let (_, evict_id) = match self.by_use.pop_first() {
+                Some(entry) => entry,
+                None => break,
+            };
+            let (_, evicted) = self.entries.remove(&evict_id).expect("Entries and by_use must agree.");
+            self.data_bytes -= evicted.map_or(0, |d| d.len());
+        }
+        self.clock += 1;
+        self.data_bytes += data.as_ref().map_or(0, |d| d.len());
+        self.by_use.insert(self.clock, album_id);
+        self.entries.insert(album_id, (self.clock, data));
+    }
 }
 
-pub struct ThumbCacheSize {
-    image_data_bytes: usize,
-    table_bytes: usize,
-    max_probe_len: usize,
+pub enum ThumbCacheSize {
+    Full {
+        image_data_bytes: usize,
+        table_bytes: usize,
+        max_probe_len: usize,
+    },
+    Lru {
+        capacity: usize,
+        len: usize,
+        image_data_bytes: usize,
+    },
 }
 
 impl fmt::Display for ThumbCacheSize {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f,
-            "{:4} kB ({:3} kB images, {:3} kB table), max probe length: {}",
-            (self.image_data_bytes + self.table_bytes) / 1000,
-            self.image_data_bytes / 1000,
-            self.table_bytes / 1000,
-            self.max_probe_len,
-        )
+        match self {
+            ThumbCacheSize::Full { image_data_bytes, table_bytes, max_probe_len } => write!(f,
+                "{:4} kB ({:3} kB images, {:3} kB table), max probe length: {}",
+                (image_data_bytes + table_bytes) / 1000,
+                image_data_bytes / 1000,
+                table_bytes / 1000,
+                max_probe_len,
+            ),
+            ThumbCacheSize::Lru { capacity, len, image_data_bytes } => write!(f,
+                "{:4} kB in {} of at most {} thumbnails, the rest is loaded on demand",
+                image_data_bytes / 1000,
+                len,
+                capacity,
+            ),
+        }
     }
 }
 
 impl ThumbCache {
     /// Return an empty thumb cache, for use as placeholder when loading.
     pub fn new_empty() -> ThumbCache {
-        Self {
-            data: Box::new([]),
+        let mode = Mode::Full {
+            data: Arc::new([]),
             references: AlbumTable::new(0, ImageReference { begin: 0, end: 0 }),
+            generated: Mutex::new(HashMap::new()),
+        };
+        Self { mode }
+    }
+
+    /// Return a cache for the `thumb_cache_size` setting.
+    ///
+    /// Without a limit, this loads all thumbnails, see `load_from_database`.
+    /// With a limit, the cache starts out empty, and loads thumbnails when
+    /// they are requested.
+    pub fn load(tx: &mut Transaction, max_thumbnails: Option<usize>) -> db::Result<ThumbCache> {
+        match max_thumbnails {
+            None => ThumbCache::load_from_database(tx),
+            Some(n) => Ok(ThumbCache { mode: Mode::Lru(Mutex::new(Lru::new(n))) }),
         }
     }
 
@@ -112,27 +258,130 @@ impl ThumbCache {
             "We should have gotten as much data out of the database as expected.",
         );
 
-        let result = ThumbCache {
-            data: buffer.into_boxed_slice(),
-            references: references
+        let mode = Mode::Full {
+            data: buffer.into(),
+            references: references,
+            generated: Mutex::new(HashMap::new()),
         };
 
-        Ok(result)
+        Ok(ThumbCache { mode })
+    }
+
+    /// Return the thumbnail for the album, if it has one.
+    ///
+    /// When the cache does not hold all thumbnails, and this one is not in
+    /// memory, this reads it from the database.
+    pub fn get(&self, db: &mut Connection, album_id: AlbumId) -> db::Result<Option<Thumbnail>> {
+        let lru = match &self.mode {
+            Mode::Full { data, references, generated } => {
+                let img = match references.get(album_id) {
+                    Some(img_ref) => Some(Thumbnail {
+                        data: data.clone(),
+                        begin: img_ref.begin as usize,
+                        end: img_ref.end as usize,
+                    }),
+                    None => generated.lock().unwrap().get(&album_id).cloned().map(Thumbnail::new),
+                };
+                return Ok(img)
+            }
+            Mode::Lru(lru) => lru,
+        };
+
+        if let Some(img) = lru.lock().unwrap().get(album_id) {
+            return Ok(img.map(Thumbnail::new))
+        }
+
+        // Don't hold the lock while we read, the disk may need to spin up.
+        let mut tx = db.begin()?;
+        let img: MaybeThumb = db::select_thumbnail(&mut tx, album_id.0 as i64)?.map(Arc::from);
+        tx.commit()?;
+
+        lru.lock().unwrap().insert(album_id, img.clone());
+        Ok(img.map(Thumbnail::new))
     }
 
-    pub fn get(&self, album_id: AlbumId) -> Option<&[u8]> {
-        let img_ref = self.references.get(album_id)?;
-        let img = &self.data[img_ref.begin as usize..img_ref.end as usize];
-        Some(img)
+    /// Add a thumbnail that was generated after we loaded the cache.
+    pub fn insert(&self, album_id: AlbumId, data: Vec<u8>) {
+        match &self.mode {
+            Mode::Full { generated, .. } => {
+                generated.lock().unwrap().insert(album_id, data.into());
+            }
+            Mode::Lru(lru) => lru.lock().unwrap().insert(album_id, Some(data.into())),
+        }
     }
 
     pub fn size(&self) -> ThumbCacheSize {
         use std::mem;
-        assert_eq!(mem::size_of::<(AlbumId, ImageReference)>(), 16);
-        ThumbCacheSize {
-            image_data_bytes: self.data.len(),
-            table_bytes: self.references.capacity() * 16,
-            max_probe_len: self.references.max_probe_len(),
+        match &self.mode {
+            Mode::Full { data, references, .. } => {
+                assert_eq!(mem::size_of::<(AlbumId, ImageReference)>(), 16);
+                ThumbCacheSize::Full {
+                    image_data_bytes: data.len(),
+                    table_bytes: references.capacity() * 16,
+                    max_probe_len: references.max_probe_len(),
+                }
+            }
+            Mode::Lru(lru) => {
+                let lru = lru.lock().unwrap();
+                ThumbCacheSize::Lru {
+                    capacity: lru.capacity,
+                    len: lru.entries.len(),
+                    image_data_bytes: lru.data_bytes,
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::AlbumId;
+    use super::{Lru, MaybeThumb};
+
+    fn thumb(data: &[u8]) -> MaybeThumb {
+        Some(data.into())
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.insert(AlbumId(1), thumb(&[1]));
+        lru.insert(AlbumId(2), thumb(&[2, 2]));
+        assert_eq!(lru.data_bytes, 3);
+
+        // Using 1 makes 2 the least recently used, so 2 gets evicted.
+        assert_eq!(lru.get(AlbumId(1)), Some(thumb(&[1])));
+        lru.insert(AlbumId(3), thumb(&[3, 3, 3]));
+        assert_eq!(lru.get(AlbumId(2)), None);
+        assert_eq!(lru.get(AlbumId(1)), Some(thumb(&[1])));
+        assert_eq!(lru.get(AlbumId(3)), Some(thumb(&[3, 3, 3])));
+        assert_eq!(lru.data_bytes, 4);
+
+        // Inserting an album that is present replaces it.
+        lru.insert(AlbumId(3), thumb(&[3]));
+        assert_eq!(lru.entries.len(), 2);
+        assert_eq!(lru.by_use.len(), 2);
+        assert_eq!(lru.data_bytes, 2);
+    }
+
+    #[test]
+    fn lru_remembers_missing_thumbnails() {
+        let mut lru = Lru::new(2);
+        lru.insert(AlbumId(1), None);
+        assert_eq!(lru.get(AlbumId(1)), Some(None));
+        assert_eq!(lru.data_bytes, 0);
+
+        // A thumbnail generated later replaces the missing one.
+        lru.insert(AlbumId(1), thumb(&[1]));
+        assert_eq!(lru.get(AlbumId(1)), Some(thumb(&[1])));
+        assert_eq!(lru.data_bytes, 1);
+    }
+
+    #[test]
+    fn lru_with_zero_capacity_is_disabled() {
+        let mut lru = Lru::new(0);
+        lru.insert(AlbumId(1), thumb(&[1]));
+        assert_eq!(lru.get(AlbumId(1)), None);
+        assert_eq!(lru.data_bytes, 0);
+    }
+}
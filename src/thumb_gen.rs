@@ -6,25 +6,154 @@
 // A copy of the License has been included in the root of the repository.
 
 //! Utilities for extracting thumbnails from flac files.
-
+//!
+//! The cover art is the front cover embedded in the first file of the album,
+//! or when that has none, an image file next to it, see [`find_cover_file`].
+//! When the `thumb_covers_path` setting is set and there is neither, we
+//! download the front cover from the [Cover Art Archive][caa] into that
+//! directory.
+//!
+//! [caa]: https://coverartarchive.org/
+
+use std::collections::HashSet;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 use std::process::{Command, Stdio};
-use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::{Receiver, SyncSender};
 use std::sync::Mutex;
 
+use log::{info, warn};
+
+use crate::config::Config;
 use crate::database;
 use crate::database::{Connection, Transaction};
-use crate::database_utils;
+use crate::database_utils::{self, Pragmas};
 use crate::error::{Error, Result};
+use crate::mvar::Var;
 use crate::prim::{AlbumId, FileId};
 use crate::scan::{ScanStage, Status};
+use crate::thumb_cache::ThumbCache;
 use crate::{MemoryMetaIndex, MetaIndex};
 
+/// Names of image files that we use as cover art, in order of preference.
+///
+/// We compare them to the file names in the album directory case-insensitively.
+const COVER_FILE_NAMES: &[&str] = &[
+    "cover.jpg",
+    "cover.jpeg",
+    "cover.png",
+    "folder.jpg",
+    "folder.jpeg",
+    "folder.png",
+    "front.jpg",
+    "front.jpeg",
+    "front.png",
+];
+
+/// Return the cover image file in the directory of the flac file, if there is one.
+pub fn find_cover_file(flac_filename: &Path) -> Option<PathBuf> {
+    let dir = flac_filename.parent()?;
+    let entries: Vec<(String, PathBuf)> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| (entry.file_name().to_string_lossy().to_lowercase(), entry.path()))
+        .collect();
+    COVER_FILE_NAMES.iter().find_map(|cover_name| {
+        entries
+            .iter()
+            .find(|(name, _)| name == cover_name)
+            .map(|(_, path)| path.clone())
+    })
+}
+
+/// Return whether `id` looks like a MusicBrainz id, so it is safe to put in a url.
+fn is_valid_mbid(id: &str) -> bool {
+    id.len() == 36 && id.bytes().enumerate().all(|(i, b)| match i {
+        8 | 13 | 18 | 23 => b == b'-',
+        _ => b.is_ascii_hexdigit(),
+    })
+}
+
+/// Return the file extension for an image, based on its first bytes.
+fn sniff_image_extension(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("jpg")
+    } else if data.starts_with(b"\x89PNG") {
+        Some("png")
+    } else {
+        None
+    }
+}
+
+/// Return the cover that we downloaded before for the album, if any.
+pub fn find_fetched_cover(covers_path: &Path, album_id: AlbumId) -> Option<PathBuf> {
+    ["jpg", "png"]
+        .iter()
+        .map(|ext| covers_path.join(format!("{}.{}", album_id, ext)))
+        .find(|path| path.is_file())
+}
+
+/// Download the front cover of the release from the Cover Art Archive.
+///
+/// Stores the image in `covers_path`, named after the album id, and returns
+/// its path. When the archive has no front cover for the release, we write an
+/// empty `.missing` file instead, so we don't ask again on the next scan, and
+/// return `None`.
+fn fetch_cover(covers_path: &Path, album_id: AlbumId, release_id: &str) -> Result<Option<PathBuf>> {
+    let missing_path = covers_path.join(format!("{}.missing", album_id));
+    if !is_valid_mbid(release_id) || missing_path.exists() {
+        return Ok(None)
+    }
+
+    let tmp_path = covers_path.join(format!("{}.tmp", album_id));
+    let output = Command::new("curl")
+        .args(["--silent", "--location", "--max-time", "60"])
+        // Print only the status code, so we can tell a missing cover apart
+        // from other errors.
+        .args(["--write-out", "%{http_code}"])
+        .arg("--output")
+        .arg(&tmp_path)
+        .arg(format!("https://coverartarchive.org/release/{}/front", release_id))
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| Error::CommandError("Failed to spawn 'curl'.", Some(e)))?;
+
+    let io_error = |e| Error::CommandError("Failed to store the cover in the covers path.", Some(e));
+    match &output.stdout[..] {
+        b"200" => {}
+        b"404" => {
+            let _rm_result_ignored = std::fs::remove_file(&tmp_path);
+            std::fs::write(&missing_path, b"").map_err(io_error)?;
+            return Ok(None)
+        }
+        _ => {
+            let _rm_result_ignored = std::fs::remove_file(&tmp_path);
+            return Err(Error::CommandError("Failed to download from the Cover Art Archive.", None))
+        }
+    }
+
+    let data = std::fs::read(&tmp_path).map_err(io_error)?;
+    let ext = match sniff_image_extension(&data) {
+        Some(ext) => ext,
+        None => {
+            let _rm_result_ignored = std::fs::remove_file(&tmp_path);
+            return Err(Error::CommandError("The Cover Art Archive returned an unknown image format.", None))
+        }
+    };
+    let out_path = covers_path.join(format!("{}.{}", album_id, ext));
+    std::fs::rename(&tmp_path, &out_path).map_err(io_error)?;
+
+    Ok(Some(out_path))
+}
+
 /// Tracks the process of generating a thumbnail.
 struct GenThumb<'a> {
     album_id: AlbumId,
+    /// Where to store covers from the Cover Art Archive, if we fetch them.
+    covers_path: Option<&'a Path>,
+    /// The MusicBrainz release id, only when we fetch covers.
+    release_id: Option<String>,
     state: GenThumbState<'a>,
 }
 
@@ -63,21 +192,53 @@ impl<'a> GenThumb<'a> {
         album_id: AlbumId,
         file_id: FileId,
         flac_filename: &'a Path,
+        covers_path: Option<&'a Path>,
     ) -> Result<Option<GenThumb<'a>>> {
+        if database::select_thumbnail_exists(tx, album_id.0 as i64)? != 0 {
+            return Ok(None)
+        }
+
+        let release_id = match covers_path {
+            Some(..) => database::select_file_musicbrainz_albumid(tx, file_id.0)?,
+            None => None,
+        };
+
         let task = GenThumb {
             album_id: album_id,
+            covers_path: covers_path,
+            release_id: release_id,
             state: GenThumbState::Pending { flac_filename, file_id },
         };
 
-        match database::select_thumbnail_exists(tx, album_id.0 as i64)? {
-            0 => Ok(Some(task)),
-            _ => Ok(None),
+        Ok(Some(task))
+    }
+
+    /// Return the cover art that is not embedded in the flac file, if any.
+    ///
+    /// This is an image file next to the flac file, or a cover from the Cover
+    /// Art Archive, which we download if we did not do so before.
+    fn read_external_cover(&self, flac_filename: &Path) -> Result<Option<Vec<u8>>> {
+        let mut path = find_cover_file(flac_filename);
+
+        if let (None, Some(covers_path)) = (&path, self.covers_path) {
+            path = find_fetched_cover(covers_path, self.album_id);
+            if let (None, Some(release_id)) = (&path, &self.release_id) {
+                path = fetch_cover(covers_path, self.album_id, release_id)?;
+            }
+        }
+
+        match path {
+            Some(path) => std::fs::read(path)
+                .map(Some)
+                .map_err(|e| Error::CommandError("Failed to read cover art file.", Some(e))),
+            None => Ok(None),
         }
     }
 
     /// From `Pending` state, read a picture, and start resizing it.
     ///
-    /// Returns `None` if the input file does not contain any pictures.
+    /// Returns `None` if the input file does not contain any pictures, and
+    /// there is no external cover art either.
     fn start_resize(
         mut self,
         album_id: AlbumId,
@@ -93,8 +254,11 @@ impl<'a> GenThumb<'a> {
             .map_err(|err| Error::from_claxon(PathBuf::from(flac_filename), err))?;
 
         let cover = match reader.into_pictures().pop() {
-            Some(c) => c,
-            None => return Ok(None),
+            Some(c) => c.into_vec(),
+            None => match self.read_external_cover(flac_filename)? {
+                Some(data) => data,
+                None => return Ok(None),
+            },
         };
 
         let out_path = get_tmp_fname(album_id);
@@ -148,7 +312,7 @@ impl<'a> GenThumb<'a> {
                 .stdin
                 .as_mut()
                 .expect("Stdin should be there, we piped it.");
-            stdin.write_all(cover.data()).unwrap();
+            stdin.write_all(&cover).unwrap();
         }
 
         self.state = GenThumbState::Resizing {
@@ -234,11 +398,9 @@ impl<'a> GenThumb<'a> {
                 let mut jpeg_bytes = Vec::new();
                 stdout.read_to_end(&mut jpeg_bytes)?;
 
-                {
-                    let mut tx = db.begin()?;
-                    database::insert_album_thumbnail(&mut tx, album_id.0 as i64, file_id.0, &jpeg_bytes[..])?;
-                    tx.commit()?;
-                }
+                database_utils::with_write_transaction(db, |tx| {
+                    database::insert_album_thumbnail(tx, album_id.0 as i64, file_id.0, &jpeg_bytes[..])
+                })?;
 
                 Ok(None)
             }
@@ -268,13 +430,16 @@ impl<'a> GenThumbs<'a> {
 pub fn generate_thumbnails(
     index: &MemoryMetaIndex,
     db_path: &Path,
+    db_pragmas: &Pragmas,
+    covers_path: Option<&Path>,
+    n_threads: usize,
     status: &mut Status,
     status_sender: &mut SyncSender<Status>,
 ) -> Result<()> {
-    status.stage = ScanStage::PreProcessingThumbnails;
+    status.set_stage(ScanStage::PreProcessingThumbnails);
     status_sender.send(*status).unwrap();
 
-    let raw_conn = database_utils::connect_readonly(db_path)?;
+    let raw_conn = database_utils::connect_readonly(db_path, db_pragmas)?;
     let mut conn = Connection::new(&raw_conn);
     let mut tx = conn.begin()?;
 
@@ -286,7 +451,7 @@ pub fn generate_thumbnails(
         let album_id = track_id.album_id();
         if album_id != prev_album_id {
             let fname = index.get_filename(kv.track.filename);
-            if let Some(task) = GenThumb::new(&mut tx, album_id, kv.track.file_id, fname.as_ref())? {
+            if let Some(task) = GenThumb::new(&mut tx, album_id, kv.track.file_id, fname.as_ref(), covers_path)? {
                 pending_tasks.push(task);
                 status.files_to_process_thumbnails += 1;
 
@@ -302,7 +467,7 @@ pub fn generate_thumbnails(
     drop(conn);
     drop(raw_conn);
 
-    status.stage = ScanStage::GeneratingThumbnails;
+    status.set_stage(ScanStage::GeneratingThumbnails);
     status_sender.send(*status).unwrap();
 
     let queue = GenThumbs {
@@ -313,21 +478,21 @@ pub fn generate_thumbnails(
     let mutex = Mutex::new(queue);
     let mutex_ref = &mutex;
 
-    // Start `num_cpus` worker threads. All these threads will do is block and
+    // Start `n_threads` worker threads, by default `num_cpus`, but it can be
+    // set with `scan_analysis_threads`. All these threads will do is block and
     // wait on IO or the external process, but both `convert` and `cjpegli`
     // are CPU-bound, so this should keep the CPU busy. When thumbnailing many
     // albums with a cold page cache, IO to read the thumb from the file can be
     // a factor too, so add one additional thread to ensure we can keep the CPU
     // busy. Edit: Or not, usually it's not needed.
     crossbeam::scope::<_, Result<()>>(|scope| {
-        let n_threads = num_cpus::get();
         let mut threads: Vec<crossbeam::ScopedJoinHandle<Result<()>>> =
             Vec::with_capacity(n_threads);
 
         for i in 0..n_threads {
             let db_path_ref = db_path;
             let drain = move || {
-                let raw_conn = database_utils::connect_read_write(db_path_ref)?;
+                let raw_conn = database_utils::connect_read_write(db_path_ref, db_pragmas)?;
                 let mut conn = Connection::new(&raw_conn);
 
                 let mut next_task = {
@@ -349,8 +514,8 @@ pub fn generate_thumbnails(
                         }
                         Err(Error::CommandError(msg, detail)) => {
                             match detail {
-                                None => eprintln!("Thumbnail generation failed: {msg}"),
-                                Some(err) => eprintln!("Thumbnail generation failed: {msg} {err:?}"),
+                                None => warn!("Thumbnail generation failed: {msg}"),
+                                Some(err) => warn!("Thumbnail generation failed: {msg} {err:?}"),
                             }
 
                             // We count failing as progress, because we did look
@@ -385,3 +550,148 @@ pub fn generate_thumbnails(
         Ok(())
     })
 }
+
+/// Generate the thumbnail for one album, if it does not have one yet.
+///
+/// Returns the thumbnail, or `None` if the album does not exist, or if we
+/// found no cover art for it.
+pub fn generate_thumbnail(
+    index: &MemoryMetaIndex,
+    db: &mut Connection,
+    covers_path: Option<&Path>,
+    album_id: AlbumId,
+) -> Result<Option<Vec<u8>>> {
+    let track = match index.get_album_tracks(album_id).first() {
+        Some(kv) => &kv.track,
+        None => return Ok(None),
+    };
+    let fname = index.get_filename(track.filename);
+
+    let mut tx = db.begin()?;
+    let mut next_task = GenThumb::new(&mut tx, album_id, track.file_id, fname.as_ref(), covers_path)?;
+    tx.commit()?;
+
+    while let Some(task) = next_task {
+        next_task = task.advance(db)?;
+    }
+
+    // When there was no cover art, `advance` completes without inserting a
+    // thumbnail, so we check the database for the outcome.
+    let mut tx = db.begin()?;
+    let thumbnail = database::select_thumbnail(&mut tx, album_id.0 as i64)?;
+    tx.commit()?;
+
+    Ok(thumbnail)
+}
+
+/// Generates thumbnails in the background for albums that have none.
+///
+/// A thumbnail can be missing because the cover art was added after the last
+/// scan, or because generation failed. Rather than returning 404 until the
+/// next scan, the server can request a new attempt. We try every album at most
+/// once per run, so albums that really have no cover art do not keep spawning
+/// processes.
+pub struct ThumbGenerator {
+    sender: SyncSender<AlbumId>,
+    attempted: Mutex<HashSet<AlbumId>>,
+}
+
+impl ThumbGenerator {
+    /// Start the background thread that generates the requested thumbnails.
+    pub fn new(
+        config: &Config,
+        index_var: Var<MemoryMetaIndex>,
+        thumb_cache_var: Var<ThumbCache>,
+    ) -> ThumbGenerator {
+        // When the albums in view have no cover art, the webinterface requests
+        // many thumbnails at once. We drop requests beyond what fits in the
+        // channel, a later request for the thumbnail can try again.
+        let (sender, receiver) = std::sync::mpsc::sync_channel(64);
+        let db_path = config.db_path.clone();
+        let db_pragmas = config.db_pragmas.clone();
+        let covers_path = config.thumb_covers_path.clone();
+
+        std::thread::Builder::new()
+            .name("thumb_generator".to_string())
+            .spawn(move || {
+                let result = ThumbGenerator::run(
+                    &db_path,
+                    &db_pragmas,
+                    covers_path.as_deref(),
+                    receiver,
+                    index_var,
+                    thumb_cache_var,
+                );
+                if let Err(err) = result {
+                    warn!("On-demand thumbnail generation stopped: {:?}", err);
+                }
+            })
+            .expect("Failed to spawn thumbnail generator thread.");
+
+        ThumbGenerator {
+            sender,
+            attempted: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn run(
+        db_path: &Path,
+        db_pragmas: &Pragmas,
+        covers_path: Option<&Path>,
+        receiver: Receiver<AlbumId>,
+        index_var: Var<MemoryMetaIndex>,
+        thumb_cache_var: Var<ThumbCache>,
+    ) -> Result<()> {
+        let raw_conn = database_utils::connect_read_write(db_path, db_pragmas)?;
+        let mut conn = Connection::new(&raw_conn);
+
+        for album_id in receiver {
+            let index = index_var.get();
+            match generate_thumbnail(&index, &mut conn, covers_path, album_id) {
+                Ok(Some(thumbnail)) => {
+                    info!("Generated missing thumbnail for album {}.", album_id);
+                    thumb_cache_var.get().insert(album_id, thumbnail);
+                }
+                Ok(None) => info!("Album {} has no cover art.", album_id),
+                Err(err) => warn!("Thumbnail generation for album {} failed: {:?}", album_id, err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Try to generate the thumbnail for the album in the background.
+    ///
+    /// Does nothing if we tried before.
+    pub fn request(&self, album_id: AlbumId) {
+        let mut attempted = self.attempted.lock().unwrap();
+        if !attempted.insert(album_id) {
+            return
+        }
+        if self.sender.try_send(album_id).is_err() {
+            // The queue is full, allow a later request to try again.
+            attempted.remove(&album_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_valid_mbid, sniff_image_extension};
+
+    #[test]
+    fn is_valid_mbid_accepts_only_uuids() {
+        assert!(is_valid_mbid("76df3287-6cda-33eb-8e9a-044b5e15ffdd"));
+        assert!(!is_valid_mbid("76df3287-6cda-33eb-8e9a-044b5e15ffd"));
+        assert!(!is_valid_mbid("76df3287x6cda-33eb-8e9a-044b5e15ffdd"));
+        assert!(!is_valid_mbid("../../../../../../../../../../etc/x"));
+    }
+
+    #[test]
+    fn sniff_image_extension_recognizes_jpeg_and_png() {
+        assert_eq!(sniff_image_extension(b"\xff\xd8\xff\xe0\x00\x10JFIF"), Some("jpg"));
+        assert_eq!(sniff_image_extension(b"\x89PNG\r\n\x1a\n"), Some("png"));
+        assert_eq!(sniff_image_extension(b"<html>"), None);
+        assert_eq!(sniff_image_extension(b""), None);
+    }
+}
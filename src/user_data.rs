@@ -22,12 +22,12 @@
 // TODO: Remove once we add playcounts.
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
 
-use crate::MemoryMetaIndex;
+use crate::{MemoryMetaIndex, MetaIndex};
 use crate::album_table::AlbumTable;
-use crate::playcount::{PlayCounter, PlayCounts};
+use crate::playcount::{Instant, PlayCounter, PlayCounts, PlaycountConfig, QuantizedTimeVector};
 use crate::prim::{AlbumId, ArtistId, TrackId};
 use crate::{database as db};
 
@@ -70,9 +70,26 @@ impl TryFrom<i64> for Rating {
 #[derive(Default)]
 pub struct TrackState {
     rating: Rating,
+    scores: TrackScores,
     // TODO: Add playcount.
 }
 
+/// Track scores derived from the playcounts.
+#[derive(Copy, Clone, Default)]
+pub struct TrackScores {
+    /// Ranking for the _discover_ sorting method, see also [`AlbumState`].
+    pub discover_score: f32,
+
+    /// When we tend to listen to this track, see [`QuantizedTimeVector::from_listens`].
+    pub time_vector: QuantizedTimeVector,
+
+    /// Number of skips, with a half-life of about four months.
+    pub skip_count: f32,
+
+    /// When we last listened to this track, if ever.
+    pub last_listened_at: Option<Instant>,
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct AlbumState {
     /// Ranking for the _discover_ sorting method.
@@ -83,18 +100,70 @@ pub struct AlbumState {
 
     // Playcount on the shortest timescale.
     pub trending_score: f32,
+
+    // Playcount on the longest timescale.
+    pub playcount: f32,
 }
 
 #[derive(Default)]
 pub struct ArtistState {
     // TODO: Add playcount.
+
+    /// Artists that we often listen to in the same session, most similar first.
+    pub similar: Vec<(ArtistId, f32)>,
+}
+
+/// Something that the user can put a label on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LabelTarget {
+    Track(TrackId),
+    Album(AlbumId),
 }
 
+/// The tracks and albums that carry a label.
+#[derive(Default)]
+pub struct LabelItems {
+    pub tracks: BTreeSet<TrackId>,
+    pub albums: BTreeSet<AlbumId>,
+}
+
+impl LabelItems {
+    fn contains(&self, target: LabelTarget) -> bool {
+        match target {
+            LabelTarget::Track(track_id) => self.tracks.contains(&track_id),
+            LabelTarget::Album(album_id) => self.albums.contains(&album_id),
+        }
+    }
+}
+
+/// Return whether the label is valid.
+///
+/// Labels are used in urls, so like the names of smart playlists, we restrict
+/// them to lowercase ASCII letters, digits, and dashes.
+pub fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.bytes().all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-'))
+}
+
+/// The maximum length of a note, in bytes.
+pub const MAX_NOTE_LEN: usize = 1000;
+
 /// Mutable metadata for tracks, albums, and artists, stemming from user usage.
 pub struct UserData {
     tracks: HashMap<TrackId, TrackState>,
     albums: AlbumTable<AlbumState>,
+    /// Album ratings, separate from [`AlbumState`] because the scores there get
+    /// replaced whenever we recompute them.
+    album_ratings: HashMap<AlbumId, Rating>,
     artists: HashMap<ArtistId, ArtistState>,
+    /// Artists that the user marked as favorite. Like the album ratings, these
+    /// live outside [`ArtistState`], which gets replaced on recomputation.
+    favorite_artists: HashSet<ArtistId>,
+    /// User-defined labels, with the tracks and albums that carry them.
+    labels: BTreeMap<String, LabelItems>,
+    /// Free-text notes, such as “skip the intro”.
+    track_notes: HashMap<TrackId, String>,
+    album_notes: HashMap<AlbumId, String>,
 }
 
 impl Default for UserData {
@@ -105,7 +174,12 @@ impl Default for UserData {
             // TODO: Use a cheaper hasher.
             tracks: HashMap::with_hasher(s.clone()),
             albums: AlbumTable::new(0, AlbumState::default()),
-            artists: HashMap::with_hasher(s),
+            album_ratings: HashMap::with_hasher(s.clone()),
+            artists: HashMap::with_hasher(s.clone()),
+            favorite_artists: HashSet::with_hasher(s.clone()),
+            labels: BTreeMap::new(),
+            track_notes: HashMap::with_hasher(s.clone()),
+            album_notes: HashMap::with_hasher(s),
         }
     }
 
@@ -120,6 +194,7 @@ impl UserData {
     pub fn load_from_database(
         index: &MemoryMetaIndex,
         tx: &mut db::Transaction,
+        playcount_config: PlaycountConfig,
     ) -> db::Result<(Self, PlayCounts)> {
         let mut stats = Self::default();
 
@@ -130,10 +205,49 @@ impl UserData {
             stats.set_track_rating(tid, rating);
         }
 
-        let mut counter = PlayCounter::new();
+        for opt_rating in db::iter_album_ratings(tx)? {
+            let rating = opt_rating?;
+            let aid = AlbumId(rating.album_id as u64);
+            let rating = Rating::try_from(rating.rating).expect("Invalid rating value in the database.");
+            stats.set_album_rating(aid, rating);
+        }
+
+        for opt_favorite in db::iter_favorite_artists(tx)? {
+            let favorite = opt_favorite?;
+            stats.set_artist_favorite(ArtistId(favorite.artist_id as u64), true);
+        }
+
+        for opt_label in db::iter_track_labels(tx)? {
+            let label = opt_label?;
+            let target = LabelTarget::Track(TrackId(label.track_id as u64));
+            stats.set_label(&label.label, target, true);
+        }
+
+        for opt_label in db::iter_album_labels(tx)? {
+            let label = opt_label?;
+            let target = LabelTarget::Album(AlbumId(label.album_id as u64));
+            stats.set_label(&label.label, target, true);
+        }
+
+        for opt_note in db::iter_track_notes(tx)? {
+            let note = opt_note?;
+            stats.set_track_note(TrackId(note.track_id as u64), Some(note.note));
+        }
+
+        for opt_note in db::iter_album_notes(tx)? {
+            let note = opt_note?;
+            stats.set_album_note(AlbumId(note.album_id as u64), Some(note.note));
+        }
+
+        let mut counter = PlayCounter::new(playcount_config);
         counter.count_from_database(index, tx)?;
         let counts = counter.into_counts();
-        stats.set_albums(counts.compute_album_user_data());
+        // The scores depend on the ratings, so we need to load those first.
+        let albums = counts.compute_album_user_data(index, &stats);
+        let tracks = counts.compute_track_user_data(&stats);
+        stats.set_albums(albums);
+        stats.set_tracks(tracks);
+        stats.set_artists(counts.compute_artist_user_data());
 
         Ok((stats, counts))
     }
@@ -146,6 +260,32 @@ impl UserData {
         self.tracks.get(&track_id).map(|t| t.rating).unwrap_or_default()
     }
 
+    /// Return the ratings of all tracks that have any user data.
+    pub fn iter_track_ratings(&self) -> impl Iterator<Item = (TrackId, Rating)> + '_ {
+        self.tracks.iter().map(|(track_id, state)| (*track_id, state.rating))
+    }
+
+    pub fn set_album_rating(&mut self, album_id: AlbumId, rating: Rating) {
+        self.album_ratings.insert(album_id, rating);
+    }
+
+    /// Return the rating of the album itself, not derived from its tracks.
+    pub fn get_album_rating(&self, album_id: AlbumId) -> Rating {
+        self.album_ratings.get(&album_id).copied().unwrap_or_default()
+    }
+
+    pub fn get_track_scores(&self, track_id: TrackId) -> TrackScores {
+        self.tracks.get(&track_id).map(|t| t.scores).unwrap_or_default()
+    }
+
+    /// Return the scores of all tracks that have any user data.
+    ///
+    /// This includes tracks that were rated but never played, their scores are
+    /// the default.
+    pub fn iter_track_scores(&self) -> impl Iterator<Item = (TrackId, &TrackScores)> + '_ {
+        self.tracks.iter().map(|(track_id, state)| (*track_id, &state.scores))
+    }
+
     pub fn get_album_scores(&self, album_id: AlbumId) -> AlbumState {
         // If an album is not present, we don't have playcounts, so it is
         // ranked as low as possible for all scores.
@@ -158,4 +298,134 @@ impl UserData {
     pub fn set_albums(&mut self, albums: AlbumTable<AlbumState>) {
         self.albums = albums;
     }
+
+    /// Replace the track scores with new scores.
+    ///
+    /// This should be tied to the computations [`PlayCounts::compute_track_user_data`].
+    pub fn set_tracks(&mut self, tracks: Vec<(TrackId, TrackScores)>) {
+        for (track_id, scores) in tracks {
+            self.tracks.entry(track_id).or_default().scores = scores;
+        }
+    }
+
+    /// Return the artists most similar to the given artist, with their similarity.
+    pub fn get_similar_artists(&self, artist_id: ArtistId) -> &[(ArtistId, f32)] {
+        match self.artists.get(&artist_id) {
+            Some(state) => &state.similar[..],
+            None => &[],
+        }
+    }
+
+    pub fn set_artist_favorite(&mut self, artist_id: ArtistId, favorite: bool) {
+        if favorite {
+            self.favorite_artists.insert(artist_id);
+        } else {
+            self.favorite_artists.remove(&artist_id);
+        }
+    }
+
+    pub fn is_favorite_artist(&self, artist_id: ArtistId) -> bool {
+        self.favorite_artists.contains(&artist_id)
+    }
+
+    /// Return the favorite artists, in no particular order.
+    pub fn iter_favorite_artists(&self) -> impl Iterator<Item = ArtistId> + '_ {
+        self.favorite_artists.iter().cloned()
+    }
+
+    /// Return whether any of the album artists is a favorite artist.
+    pub fn is_by_favorite_artist(&self, index: &MemoryMetaIndex, album_id: AlbumId) -> bool {
+        match index.get_album(album_id) {
+            Some(album) => index
+                .get_album_artists(album.artist_ids)
+                .iter()
+                .any(|artist_id| self.favorite_artists.contains(artist_id)),
+            None => false,
+        }
+    }
+
+    /// Add the label to the target, or remove it.
+    pub fn set_label(&mut self, label: &str, target: LabelTarget, labeled: bool) {
+        if labeled {
+            let items = self.labels.entry(label.to_string()).or_default();
+            match target {
+                LabelTarget::Track(track_id) => items.tracks.insert(track_id),
+                LabelTarget::Album(album_id) => items.albums.insert(album_id),
+            };
+        } else if let Some(items) = self.labels.get_mut(label) {
+            match target {
+                LabelTarget::Track(track_id) => items.tracks.remove(&track_id),
+                LabelTarget::Album(album_id) => items.albums.remove(&album_id),
+            };
+            // A label only exists as long as something carries it.
+            if items.tracks.is_empty() && items.albums.is_empty() {
+                self.labels.remove(label);
+            }
+        }
+    }
+
+    /// Return all labels with the items that carry them, ordered by label.
+    pub fn get_labels(&self) -> &BTreeMap<String, LabelItems> {
+        &self.labels
+    }
+
+    /// Return the labels of the track or album, ordered by label.
+    pub fn get_target_labels(&self, target: LabelTarget) -> Vec<&str> {
+        self.labels
+            .iter()
+            .filter(|(_, items)| items.contains(target))
+            .map(|(label, _)| &label[..])
+            .collect()
+    }
+
+    /// Return the tracks that carry the label, directly or through their album.
+    ///
+    /// The tracks are in library order, tracks that no longer exist are
+    /// skipped. Returns `None` if no item carries the label.
+    pub fn get_labeled_tracks(&self, index: &MemoryMetaIndex, label: &str) -> Option<Vec<TrackId>> {
+        let items = self.labels.get(label)?;
+        let mut tracks: Vec<TrackId> = items
+            .tracks
+            .iter()
+            .filter(|track_id| index.get_track(**track_id).is_some())
+            .cloned()
+            .collect();
+        for album_id in items.albums.iter() {
+            tracks.extend(index.get_album_tracks(*album_id).iter().map(|t| t.track_id));
+        }
+        tracks.sort();
+        tracks.dedup();
+        Some(tracks)
+    }
+
+    /// Set the note of the track, or remove it when the note is `None`.
+    pub fn set_track_note(&mut self, track_id: TrackId, note: Option<String>) {
+        match note {
+            Some(note) => self.track_notes.insert(track_id, note),
+            None => self.track_notes.remove(&track_id),
+        };
+    }
+
+    pub fn get_track_note(&self, track_id: TrackId) -> Option<&str> {
+        self.track_notes.get(&track_id).map(|note| &note[..])
+    }
+
+    /// Set the note of the album, or remove it when the note is `None`.
+    pub fn set_album_note(&mut self, album_id: AlbumId, note: Option<String>) {
+        match note {
+            Some(note) => self.album_notes.insert(album_id, note),
+            None => self.album_notes.remove(&album_id),
+        };
+    }
+
+    pub fn get_album_note(&self, album_id: AlbumId) -> Option<&str> {
+        self.album_notes.get(&album_id).map(|note| &note[..])
+    }
+
+    /// Replace the artist data with new data.
+    ///
+    /// This should be tied to the computations [`PlayCounts::compute_artist_user_data`].
+    pub fn set_artists(&mut self, artists: HashMap<ArtistId, ArtistState>) {
+        self.artists = artists;
+    }
 }
@@ -7,19 +7,73 @@
 
 //! Track visualisation, as a “waveform”.
 //!
-//! Actually we visualize loudness.
+//! Actually we visualize the amplitude envelope, the RMS and peak amplitude
+//! over time. We store two resolutions: an overview of
+//! the full track, and a detailed version in segments of 10 seconds, for
+//! zooming in on a part of a long track.
 
 use std::io;
 
 use bs1770::{ChannelLoudnessMeter};
 
+/// Format version of a waveform that stores both RMS and peak amplitudes.
+///
+/// Version 1 has no version byte, it stores only the RMS amplitudes, so its
+/// length is even. From version 2 on, the buffer starts with the version byte,
+/// and the amplitudes that follow have a length divisible by four, so the
+/// length of the buffer is odd, which is how we tell the formats apart.
+const VERSION_2: u8 = 2;
+
+/// Measures the RMS and peak amplitude of a channel, in 100ms windows.
+///
+/// Unlike the loudness meter, this does not apply K-weighting, so the RMS and
+/// peak amplitude are on the same scale, and we can compare them to show the
+/// dynamics of a track.
+pub struct EnvelopeMeter {
+    samples_per_window: u32,
+    count: u32,
+    sum_squares: f32,
+    peak: f32,
+    /// Mean square and peak amplitude of every complete 100ms window.
+    windows: Vec<(f32, f32)>,
+}
+
+impl EnvelopeMeter {
+    pub fn new(sample_rate_hz: u32) -> EnvelopeMeter {
+        EnvelopeMeter {
+            samples_per_window: sample_rate_hz / 10,
+            count: 0,
+            sum_squares: 0.0,
+            peak: 0.0,
+            windows: Vec::new(),
+        }
+    }
+
+    /// Feed samples, normalized to the range -1.0 to 1.0, into the meter.
+    pub fn push<I: Iterator<Item = f32>>(&mut self, samples: I) {
+        for sample in samples {
+            self.sum_squares += sample * sample;
+            self.peak = self.peak.max(sample.abs());
+            self.count += 1;
+
+            if self.count == self.samples_per_window {
+                let mean_square = self.sum_squares / self.count as f32;
+                self.windows.push((mean_square, self.peak));
+                self.count = 0;
+                self.sum_squares = 0.0;
+                self.peak = 0.0;
+            }
+        }
+    }
+}
+
 /// A “waveform” of a track.
 ///
-/// The amplitudes are the square roots of the power over a 0.5s window of
-/// audio. They are spaced at 0.2s distance (therefore windows overlap).
-/// Amplitudes are stored in one byte per value. This is more precision than we
-/// really need (4 bits is too few, 6 is sufficient), but it makes processing
-/// easier, so for now we spend the extra space.
+/// The amplitudes are the root mean square and the peak amplitude over a 0.5s
+/// window of audio. They are spaced at 0.2s distance (therefore windows
+/// overlap). Amplitudes are stored in one byte per value. This is more
+/// precision than we really need (4 bits is too few, 6 is sufficient), but it
+/// makes processing easier, so for now we spend the extra space.
 ///
 /// A window of 0.5s provides a good trade off between graphs that are too
 /// spiky to see the track’s structure at a glance, and graphs that are too
@@ -28,62 +82,91 @@ use bs1770::{ChannelLoudnessMeter};
 /// Sampling at 5 Hz (0.2s apart) seems to be sufficient; visually no
 /// significant detail is lost compared to sampling at 10 Hz.
 ///
-/// The buffer stores all amplitudes for the left channel first, then all
-/// amplitudes for the right channel. This means that the length of the buffer
-/// must be even.
+/// Both buffers store all amplitudes for the left channel first, then all
+/// amplitudes for the right channel. This means that their length must be
+/// even. Waveforms in version 1 of the format have no peak amplitudes, and
+/// their “RMS” amplitudes are the square roots of the K-weighted power, as
+/// measured by the loudness meter.
 pub struct Waveform {
-    pub amplitudes: Vec<u8>,
+    pub rms: Vec<u8>,
+    pub peak: Option<Vec<u8>>,
 }
 
 impl Waveform {
-    /// Construct a waveform from a left and right channel loudness meter.
-    pub fn from_meters(meters: &[ChannelLoudnessMeter; 2]) -> Waveform {
+    /// Construct a waveform from a left and right channel envelope meter.
+    pub fn from_envelopes(meters: &[EnvelopeMeter; 2]) -> Waveform {
         // We will need slightly fewer due to windowing, but this is is a good
         // size to allocate. We need only half the values because we sample at
         // 200ms, but the source is at 100ms. But we need double that because we
         // have two channels.
-        let values_per_channel = meters[0].as_100ms_windows().len() / 2;
-        let mut powers = Vec::with_capacity(values_per_channel * 2);
-        let mut max_power = 0.0;
+        let values_per_channel = meters[0].windows.len() / 2;
+        let mut rms = Vec::with_capacity(values_per_channel * 2);
+        let mut peak = Vec::with_capacity(values_per_channel * 2);
 
         for channel_meter in meters.iter() {
             // Step by 2, so we sample every 200ms; the source is at 100ms.
-            for window_500ms in channel_meter.as_100ms_windows().inner.windows(5).step_by(2) {
-                let power = 0.2 * window_500ms.iter().map(|p| p.0).sum::<f32>();
-                if power > max_power {
-                    max_power = power;
-                }
-                powers.push(power);
+            for window_500ms in channel_meter.windows.windows(5).step_by(2) {
+                let mean_square = 0.2 * window_500ms.iter().map(|w| w.0).sum::<f32>();
+                rms.push(mean_square.sqrt());
+                peak.push(window_500ms.iter().map(|w| w.1).fold(0.0, f32::max));
             }
         }
 
-        let amplitudes = powers
-            .iter()
-            .map(|&p| (255.0 * (p / max_power + 1e-10).sqrt()) as u8)
-            .collect();
+        // We scale both to the loudest peak, so the RMS amplitude is always
+        // below the peak amplitude.
+        let max_peak = peak.iter().cloned().fold(f32::MIN_POSITIVE, f32::max);
+        let to_byte = |x: &f32| (255.0 * x / max_peak) as u8;
 
         Waveform {
-            amplitudes
+            rms: rms.iter().map(to_byte).collect(),
+            peak: Some(peak.iter().map(to_byte).collect()),
         }
     }
 
     /// Load the waveform from a buffer, for loading from the database.
-    pub fn from_bytes(data: Vec<u8>) -> Waveform {
-        assert_eq!(data.len() % 2, 0, "Buffer length must be even.");
+    pub fn from_bytes(mut data: Vec<u8>) -> Waveform {
+        // Version 1 has an even length, see `VERSION_2`.
+        if data.len() % 2 != 1 {
+            return Waveform {
+                rms: data,
+                peak: None,
+            }
+        }
+
+        assert_eq!(data[0], VERSION_2, "Unsupported waveform version.");
+        let n = data.len() / 2;
+        assert_eq!(n % 2, 0, "Amplitude buffer length must be even.");
+        let peak = data[1 + n..].to_vec();
+        data.truncate(1 + n);
+        data.remove(0);
+
         Waveform {
-            amplitudes: data,
+            rms: data,
+            peak: Some(peak),
         }
     }
 
-    /// View the waveform as a buffer, to be saved in the database.
-    pub fn as_bytes(&self) -> &[u8] {
-        self.amplitudes.as_ref()
+    /// Serialize the waveform to a buffer, to be saved in the database.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.peak {
+            None => self.rms.clone(),
+            Some(peak) => {
+                let mut result = Vec::with_capacity(1 + self.rms.len() + peak.len());
+                result.push(VERSION_2);
+                result.extend_from_slice(&self.rms);
+                result.extend_from_slice(peak);
+                result
+            }
+        }
     }
 
     /// Render the waveform to svg.
+    ///
+    /// When the waveform has peak amplitudes, we draw them translucent behind
+    /// the RMS amplitudes, so the svg works as a mask image.
     pub fn write_svg<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
-        let num_samples = self.amplitudes.len() / 2;
-        assert_eq!(num_samples * 2, self.amplitudes.len(), "Buffer length must be even.");
+        let num_samples = self.rms.len() / 2;
+        assert_eq!(num_samples * 2, self.rms.len(), "Buffer length must be even.");
 
         writeln!(
             out,
@@ -92,19 +175,160 @@ impl Waveform {
             // (which are 200ms apart) 10 units, for ~500 units on the y axis.
             num_samples * 10,
         )?;
-        writeln!(out, r#"<path d="M 0 255 "#)?;
+        if let Some(peak) = &self.peak {
+            write_path(out, peak, r#"fill="black" fill-opacity="0.4""#)?;
+        }
+        write_path(out, &self.rms, r#"fill="black""#)?;
+        writeln!(out, "</svg>")
+    }
+}
+
+/// Write an svg path for the amplitudes of a left and right channel.
+fn write_path<W: io::Write>(out: &mut W, amplitudes: &[u8], attributes: &str) -> io::Result<()> {
+    let num_samples = amplitudes.len() / 2;
+    writeln!(out, r#"<path d="M 0 255 "#)?;
+
+    // Pass 1, left channel, from left to right. The y-axis goes down from
+    // 0 to 510, with 255 in the middle.
+    for (i, &amplitude) in amplitudes[..num_samples].iter().enumerate() {
+        write!(out, "L {} {} ", i * 10, 255_i32 - amplitude as i32)?;
+    }
+
+    // Past 2, right channel, from right to left.
+    for (i, &amplitude) in amplitudes[num_samples..].iter().enumerate().rev() {
+        write!(out, "L {} {} ", i * 10, 255_i32 + amplitude as i32)?;
+    }
+
+    writeln!(out, r#"" {}/>"#, attributes)
+}
 
-        // Pass 1, left channel, from left to right. The y-axis goes down from
-        // 0 to 510, with 255 in the middle.
-        for (i, &amplitude) in self.amplitudes[..num_samples].iter().enumerate() {
-            write!(out, "L {} {} ", i * 10, 255_i32 - amplitude as i32)?;
+/// Number of detail amplitudes per channel in one segment, 10 seconds.
+const SEGMENT_LEN: usize = 100;
+
+/// A detailed “waveform” of a track, in segments of 10 seconds.
+///
+/// The amplitudes are the square roots of the power over 0.1s windows of
+/// audio, without overlap. This is the resolution of the loudness meter, so it
+/// is the most detail we can get without decoding the track again.
+///
+/// The buffer stores the segments in order. Every segment stores the
+/// amplitudes for the left channel first, then for the right channel, like
+/// the RMS amplitudes of a version 1 `Waveform`. All segments have `SEGMENT_LEN`
+/// amplitudes per channel, except for the last one, which can be shorter.
+pub struct WaveformDetail {
+    pub amplitudes: Vec<u8>,
+}
+
+impl WaveformDetail {
+    /// Construct a detailed waveform from a left and right channel loudness meter.
+    pub fn from_meters(meters: &[ChannelLoudnessMeter; 2]) -> WaveformDetail {
+        let left = &meters[0].as_100ms_windows().inner;
+        let right = &meters[1].as_100ms_windows().inner;
+        let n = left.len().min(right.len());
+        let max_power = left[..n]
+            .iter()
+            .chain(&right[..n])
+            .map(|p| p.0)
+            .fold(0.0, f32::max);
+
+        let mut amplitudes = Vec::with_capacity(n * 2);
+        for start in (0..n).step_by(SEGMENT_LEN) {
+            let end = n.min(start + SEGMENT_LEN);
+            for channel in [left, right].iter() {
+                amplitudes.extend(
+                    channel[start..end]
+                        .iter()
+                        .map(|p| (255.0 * (p.0 / max_power + 1e-10).sqrt()) as u8)
+                );
+            }
+        }
+
+        WaveformDetail {
+            amplitudes
+        }
+    }
+
+    /// Load the detailed waveform from a buffer, for loading from the database.
+    pub fn from_bytes(data: Vec<u8>) -> WaveformDetail {
+        assert_eq!(data.len() % 2, 0, "Buffer length must be even.");
+        WaveformDetail {
+            amplitudes: data,
+        }
+    }
+
+    /// View the detailed waveform as a buffer, to be saved in the database.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.amplitudes.as_ref()
+    }
+
+    /// Return segment `i`, which covers seconds `10 * i` up to `10 * (i + 1)`.
+    ///
+    /// The segment has the same format as a version 1 `Waveform`, so it can be
+    /// rendered in the same way.
+    pub fn segment(&self, i: usize) -> Option<Waveform> {
+        let start = i.checked_mul(SEGMENT_LEN * 2)?;
+        if start >= self.amplitudes.len() {
+            return None
         }
+        let end = self.amplitudes.len().min(start + SEGMENT_LEN * 2);
+        Some(Waveform::from_bytes(self.amplitudes[start..end].to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EnvelopeMeter, Waveform, WaveformDetail};
 
-        // Past 2, right channel, from right to left.
-        for (i, &amplitude) in self.amplitudes[num_samples..].iter().enumerate().rev() {
-            write!(out, "L {} {} ", i * 10, 255_i32 + amplitude as i32)?;
+    #[test]
+    fn waveform_detail_splits_segments() {
+        // Two full segments and a partial one of 3 amplitudes per channel.
+        let mut data = Vec::new();
+        for &(segment, len) in [(0_u8, 100), (1, 100), (2, 3)].iter() {
+            data.resize(data.len() + len, segment * 10);
+            data.resize(data.len() + len, segment * 10 + 1);
         }
+        let detail = WaveformDetail::from_bytes(data);
+        assert_eq!(detail.segment(0).unwrap().rms[99..101], [0, 1]);
+
+        let segment = detail.segment(1).unwrap();
+        assert_eq!(segment.rms.len(), 200);
+        assert_eq!(&segment.rms[99..101], &[10, 11]);
+
+        let segment = detail.segment(2).unwrap();
+        assert_eq!(segment.rms, [20, 20, 20, 21, 21, 21]);
+
+        assert!(detail.segment(3).is_none());
+        assert!(detail.segment(usize::MAX).is_none());
+        assert!(WaveformDetail::from_bytes(Vec::new()).segment(0).is_none());
+    }
+
+    #[test]
+    fn waveform_from_envelopes_scales_to_peak() {
+        // At 100 Hz, a window has 10 samples, we push 10 windows. The left
+        // channel is a square wave, the right channel alternates 0.25 and 0.
+        let mut meters = [EnvelopeMeter::new(100), EnvelopeMeter::new(100)];
+        meters[0].push((0..100).map(|i| if i % 2 == 0 { 0.5 } else { -0.5 }));
+        meters[1].push((0..100).map(|i| if i % 2 == 0 { 0.25 } else { 0.0 }));
+
+        let waveform = Waveform::from_envelopes(&meters);
+        assert_eq!(waveform.rms, [255, 255, 255, 90, 90, 90]);
+        assert_eq!(waveform.peak, Some(vec![255, 255, 255, 127, 127, 127]));
+    }
+
+    #[test]
+    fn waveform_from_bytes_reads_both_versions() {
+        let v1 = Waveform::from_bytes(vec![1, 2, 3, 4]);
+        assert_eq!(v1.rms, [1, 2, 3, 4]);
+        assert_eq!(v1.peak, None);
+        assert_eq!(v1.to_bytes(), [1, 2, 3, 4]);
+
+        let v2 = Waveform::from_bytes(vec![2, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(v2.rms, [1, 2, 3, 4]);
+        assert_eq!(v2.peak, Some(vec![5, 6, 7, 8]));
+        assert_eq!(v2.to_bytes(), [2, 1, 2, 3, 4, 5, 6, 7, 8]);
 
-        writeln!(out, r#"" fill="black"/></svg>"#)
+        let empty = Waveform::from_bytes(vec![2]);
+        assert!(empty.rms.is_empty());
+        assert_eq!(empty.peak, Some(Vec::new()));
     }
 }
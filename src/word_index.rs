@@ -10,18 +10,21 @@
 //! The word index consists of several packed arrays of data, together with
 //! arrays of indexes into those.
 //!
-//! * The **key data** is a single long string (UTF-8) of all of the words in
-//!   the index concatenated.
-//! * The **key slices array** is an array of (offset, len) pairs that slice a
-//!   word out of the key data. Key slices are sorted in memcmp order to
-//!   facilitate a binary search for matching prefixes.
+//! * The **key data** holds all of the words in the index, sorted in memcmp
+//!   order, and front-coded: the keys are grouped in buckets of
+//!   `KEYS_PER_BUCKET`, the first key of a bucket is stored in full, and the
+//!   others as the length of the prefix they share with the key before them,
+//!   followed by the remaining bytes. Adjacent sorted words often share a
+//!   prefix, so this is smaller than storing every word in full.
+//! * The **key buckets array** holds the offset into the key data of the first
+//!   key of every bucket. Those keys can be read without decoding anything, so
+//!   we can binary search them, and then decode at most one bucket.
 //! * The **value data** is an array of values (the value type is a generic
 //!   type, instantiated to track id, album id, or artist id).
 //! * The **value slices array** is an array of (offset, len) pairs that slice
 //!   one or more values out of the value data. The length of the value slices
-//!   array is the same as that of the key slices array: for the key at index
-//!   _i_, the value slice at index _i_ lists all values associated with that
-//!   key.
+//!   array is the number of keys: for the key at index _i_, the value slice at
+//!   index _i_ lists all values associated with that key.
 //! * The **medatada array** is an array of match metadata, the same length as
 //!   the value data array. For the value at index _i_, the match metadata at
 //!   index _i_ contains metadata used to rank the matched value among other
@@ -29,14 +32,24 @@
 //!
 //! Typically a search works like this:
 //!
-//! * Perform two binary searches on the key slices to find the range of keys
-//!   that have the search needle as prefix.
+//! * Perform two binary searches on the keys to find the range of keys that
+//!   have the search needle as prefix.
 //! * For each matching key, gather associated values and match metadata.
 //! * Use match metadata to rank the matches.
+//!
+//! For typo-tolerant search, the index additionally stores the **deletion
+//! variants** of the keys: for every key, the hashes of the strings obtained by
+//! deleting one character from it. A word that is within a small edit distance
+//! of a key shares at least one deletion variant with it, so we can find the
+//! candidate keys with a few binary searches, and then verify the candidates
+//! with a full edit distance computation.
 
 use std::cmp;
-use std::mem;
 use std::fmt;
+use std::iter;
+use std::mem;
+
+use crate::prim::{AlbumId, ArtistId, TrackId};
 
 /// Packed metadata about a an entry in the word index.
 ///
@@ -135,13 +148,11 @@ impl WordMeta {
         // The 6-bit log-frequency is at offset 24.
         WordMeta(self.0 | (log2_frequency << 24))
     }
-}
 
-#[repr(align(8))]
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-struct Key {
-    offset: u32,
-    len: u32,
+    /// Return a copy of the word meta, with the log-frequency cleared.
+    fn clear_frequency(self) -> WordMeta {
+        WordMeta(self.0 & !(0b0011_1111 << 24))
+    }
 }
 
 /// A slice of values in the word index, usually all values associated with a key.
@@ -165,6 +176,18 @@ pub trait WordIndex {
     /// Return the value ranges for all keys of which `prefix` is a prefix.
     fn search_prefix(&self, prefix: &str) -> &[Values];
 
+    /// Push the value ranges of keys near `word` into `into`.
+    ///
+    /// Pushes `(values, distance)` pairs for all keys that are at an edit
+    /// distance of at least 1 and at most `max_distance` from `word`. The key
+    /// equal to `word` itself is not included, use `search_exact` for that.
+    ///
+    /// The index only stores single-character deletions of the keys, so at a
+    /// distance of 2, at most one of the edits can be a missing, substituted,
+    /// or transposed character; the other one must be an excess character in
+    /// `word`.
+    fn search_fuzzy(&self, word: &str, max_distance: u32, into: &mut Vec<(Values, u32)>);
+
     /// Return the values for a value range returned from a search.
     fn get_values(&self, range: Values) -> &[Self::Item];
 
@@ -178,10 +201,13 @@ pub trait WordIndex {
     fn get_meta(&self, offset: u32) -> &WordMeta;
 }
 
+#[derive(Clone)]
 pub struct MemoryWordIndex<T> {
-    key_slices: Vec<Key>,
+    /// Front-coded keys, see the module documentation.
+    key_data: Vec<u8>,
+    /// For every bucket of `KEYS_PER_BUCKET` keys, the offset of its first key.
+    key_buckets: Vec<u32>,
     value_slices: Vec<Values>,
-    key_data: String,
     // TODO: Benchmark (Vec<T>, Vec<WordMeta>) against Vec<(T, WordMeta)>. It is
     // not obvious which will be better for locality: in an intersection query,
     // we expect most values to be out of the intersection, so there we avoid
@@ -191,6 +217,143 @@ pub struct MemoryWordIndex<T> {
     // are adjacent.
     value_data: Vec<T>,
     meta_data: Vec<WordMeta>,
+    /// Pairs of (deletion variant hash, key index), sorted by hash.
+    ///
+    /// Contains an entry for every way of deleting a single character from a
+    /// key, for keys of at least `FUZZY_MIN_CHARS` characters.
+    deletions: Vec<(u32, u32)>,
+}
+
+/// The number of keys in a bucket of front-coded keys.
+///
+/// Looking up a key means decoding on average half a bucket, so larger buckets
+/// take less memory, but make lookups slower.
+const KEYS_PER_BUCKET: usize = 16;
+
+/// Append `x` to `out` as a LEB128 varint.
+fn push_varint(out: &mut Vec<u8>, mut x: u32) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+/// Read a LEB128 varint at `pos` and advance `pos` past it.
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut x: u32 = 0;
+    for shift in (0..35).step_by(7) {
+        let b = *data.get(*pos)?;
+        *pos += 1;
+        x |= ((b & 0x7f) as u32).checked_shl(shift)?;
+        if b < 0x80 {
+            return Some(x)
+        }
+    }
+    None
+}
+
+/// Decodes front-coded keys one by one.
+///
+/// Because the first key of a bucket shares no prefix with the key before it,
+/// a cursor that starts at a bucket can continue into the next buckets.
+struct KeyCursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    key: Vec<u8>,
+}
+
+impl<'a> KeyCursor<'a> {
+    /// Decode the next key, return `None` if the data is invalid.
+    fn try_next(&mut self) -> Option<&[u8]> {
+        let shared = read_varint(self.data, &mut self.pos)? as usize;
+        let len = read_varint(self.data, &mut self.pos)? as usize;
+        let suffix = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        if shared > self.key.len() {
+            return None
+        }
+        self.pos += len;
+        self.key.truncate(shared);
+        self.key.extend_from_slice(suffix);
+        Some(&self.key)
+    }
+
+    fn next(&mut self) -> &[u8] {
+        self.try_next().expect("Key data is validated when the index is built or loaded.")
+    }
+
+    fn next_str(&mut self) -> &str {
+        std::str::from_utf8(self.next()).expect("Keys are validated to be UTF-8.")
+    }
+}
+
+/// Compare `prefix` to the same-length prefix of `key`.
+fn cmp_prefix(prefix: &str, key: &[u8]) -> cmp::Ordering {
+    // Compare bytes, limiting the key if it is longer.
+    let n = cmp::min(prefix.len(), key.len());
+    prefix.as_bytes().cmp(&key[..n])
+}
+
+/// Keys shorter than this are not considered for fuzzy matching.
+///
+/// With short words, nearly every word is within edit distance 1 of some other
+/// word, so fuzzy matches would be mostly noise.
+pub const FUZZY_MIN_CHARS: usize = 4;
+
+/// Return the FNV-1a hash of `word` with the byte range `skip` removed.
+fn hash_deletion(word: &str, skip: std::ops::Range<usize>) -> u32 {
+    let bytes = word.as_bytes();
+    let mut h: u32 = 0x811c_9dc5;
+    for &b in bytes[..skip.start].iter().chain(&bytes[skip.end..]) {
+        h ^= b as u32;
+        h = h.wrapping_mul(0x0100_0193);
+    }
+    h
+}
+
+/// Push the deletion variants of the `i`-th key, if it is long enough.
+fn push_deletions(into: &mut Vec<(u32, u32)>, i: usize, word: &str) {
+    if word.chars().count() < FUZZY_MIN_CHARS {
+        return
+    }
+    for (j, ch) in word.char_indices() {
+        into.push((hash_deletion(word, j..j + ch.len_utf8()), i as u32));
+    }
+}
+
+/// Return the optimal string alignment distance between `a` and `b`.
+///
+/// This is the Levenshtein distance (the number of single-character insertions,
+/// deletions, and substitutions needed to turn one string into the other), but
+/// a transposition of two adjacent characters also counts as a single edit.
+pub fn edit_distance(a: &str, b: &str) -> u32 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let n = b.len() + 1;
+
+    // We keep three rows of the dynamic programming matrix: the current one,
+    // and the two before it, which we need for transpositions.
+    let mut prev2: Vec<u32> = vec![0; n];
+    let mut prev: Vec<u32> = (0..n as u32).collect();
+    let mut curr: Vec<u32> = vec![0; n];
+
+    for i in 1..a.len() + 1 {
+        curr[0] = i as u32;
+        for j in 1..n {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut d = (prev[j] + 1)
+                .min(curr[j - 1] + 1)
+                .min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(prev2[j - 2] + 1);
+            }
+            curr[j] = d;
+        }
+        mem::swap(&mut prev2, &mut prev);
+        mem::swap(&mut prev, &mut curr);
+    }
+
+    prev[n - 1]
 }
 
 pub struct WordIndexSize {
@@ -198,6 +361,7 @@ pub struct WordIndexSize {
     value_data_bytes: usize,
     meta_data_bytes: usize,
     slice_bytes: usize,
+    fuzzy_bytes: usize,
     num_keys: usize,
     num_values: usize,
 }
@@ -205,14 +369,15 @@ pub struct WordIndexSize {
 impl fmt::Display for WordIndexSize {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f,
-            "{:5} keys, {:5} values, {:4} kB ({:3} kB keys, {:3} kB values, {:3} kB meta, {:3} kB slices)",
+            "{:5} keys, {:5} values, {:4} kB ({:3} kB keys, {:3} kB values, {:3} kB meta, {:3} kB slices, {:3} kB fuzzy)",
             self.num_keys,
             self.num_values,
-            (self.key_data_bytes + self.value_data_bytes + self.meta_data_bytes + self.slice_bytes) / 1000,
+            (self.key_data_bytes + self.value_data_bytes + self.meta_data_bytes + self.slice_bytes + self.fuzzy_bytes) / 1000,
             self.key_data_bytes / 1000,
             self.value_data_bytes / 1000,
             self.meta_data_bytes / 1000,
             self.slice_bytes / 1000,
+            self.fuzzy_bytes / 1000,
         )
     }
 }
@@ -224,14 +389,68 @@ impl<T> MemoryWordIndex<T> {
         I: IntoIterator<Item = &'a (String, T, WordMeta)>,
         T: 'a + Copy
     {
-        let mut key_data = String::new();
+        MemoryWordIndex::from_entries(
+            elements.into_iter().map(|&(ref word, value, meta)| (&word[..], value, meta))
+        )
+    }
+
+    /// Return a copy of the index with values removed and new entries added.
+    ///
+    /// Drops the values for which `remove` returns true, and inserts the sorted
+    /// `elements`. For example, when some albums changed, remove the values
+    /// that belong to those albums, and insert the entries for their new
+    /// versions. Unlike building a new index, this does not need the entries
+    /// of the albums that did not change.
+    pub fn update<'a, F, I>(&self, mut remove: F, elements: I) -> MemoryWordIndex<T>
+    where
+        F: FnMut(&T) -> bool,
+        I: IntoIterator<Item = &'a (String, T, WordMeta)>,
+        T: 'a + Copy + Ord,
+    {
+        let mut cursor = self.cursor_at(0);
+        let keys: Vec<String> = self.value_slices.iter().map(|_| cursor.next_str().to_string()).collect();
+
+        // The frequencies of the old entries are recomputed when we build the
+        // new index, and they should not affect the order when we merge.
+        let old = iter::zip(&keys, &self.value_slices)
+            .flat_map(|(word, values)| {
+                let from = values.offset as usize;
+                let to = from + values.len as usize;
+                iter::zip(&self.value_data[from..to], &self.meta_data[from..to])
+                    .map(move |(&value, &meta)| (&word[..], value, meta.clear_frequency()))
+            })
+            .filter(|(_, value, _)| !remove(value));
+        let new = elements
+            .into_iter()
+            .map(|&(ref word, value, meta)| (&word[..], value, meta));
+
+        // Both sequences are sorted, so we can merge them.
+        let mut old = old.peekable();
+        let mut new = new.peekable();
+        let merged = iter::from_fn(|| match (old.peek(), new.peek()) {
+            (Some(a), Some(b)) if a <= b => old.next(),
+            (Some(_), Some(_)) => new.next(),
+            (Some(_), None) => old.next(),
+            (None, _) => new.next(),
+        });
+
+        MemoryWordIndex::from_entries(merged)
+    }
+
+    /// Build a memory word index from a sorted sequence of (word, value) pairs.
+    fn from_entries<'a, I>(elements: I) -> MemoryWordIndex<T>
+    where
+        I: IntoIterator<Item = (&'a str, T, WordMeta)>,
+    {
+        let mut key_data = Vec::new();
+        let mut key_buckets = Vec::new();
         let mut value_data = Vec::new();
         let mut meta_data = Vec::new();
 
-        let mut key_slices = Vec::new();
         let mut value_slices = Vec::new();
 
         let mut prev_word = "";
+        let mut num_keys = 0;
         let mut values = Values {
             offset: 0,
             len: 0,
@@ -245,21 +464,29 @@ impl<T> MemoryWordIndex<T> {
             }
         }
 
-        for &(ref word, value, meta) in elements {
-            if word != prev_word {
+        let mut deletions = Vec::new();
+
+        for (word, value, meta) in elements {
+            if word != prev_word || num_keys == 0 {
                 // Finish up the previous value slice, if any.
                 if values.len > 0 {
                     fixup_meta_frequency(&mut meta_data[..], values);
                     value_slices.push(values);
                 }
 
-                let word_slice = Key {
-                    offset: key_data.len() as u32,
-                    len: word.len() as u32,
+                // The first key of a bucket is stored in full, the others share
+                // a prefix with the key before them.
+                let shared = if num_keys % KEYS_PER_BUCKET == 0 {
+                    key_buckets.push(key_data.len() as u32);
+                    0
+                } else {
+                    iter::zip(prev_word.bytes(), word.bytes()).take_while(|(a, b)| a == b).count()
                 };
-
-                key_data.push_str(word);
-                key_slices.push(word_slice);
+                push_varint(&mut key_data, shared as u32);
+                push_varint(&mut key_data, (word.len() - shared) as u32);
+                key_data.extend_from_slice(&word.as_bytes()[shared..]);
+                push_deletions(&mut deletions, num_keys, word);
+                num_keys += 1;
 
                 values = Values {
                     offset: value_data.len() as u32,
@@ -275,15 +502,35 @@ impl<T> MemoryWordIndex<T> {
         }
 
         // Finish up the last word.
-        fixup_meta_frequency(&mut meta_data[..], values);
-        value_slices.push(values);
+        if values.len > 0 {
+            fixup_meta_frequency(&mut meta_data[..], values);
+            value_slices.push(values);
+        }
+
+        // Deleting either of a pair of repeated characters yields the same
+        // variant, we only need to store it once.
+        deletions.sort_unstable();
+        deletions.dedup();
 
         MemoryWordIndex {
-            key_slices: key_slices,
-            value_slices: value_slices,
             key_data: key_data,
+            key_buckets: key_buckets,
+            value_slices: value_slices,
             value_data: value_data,
             meta_data: meta_data,
+            deletions: deletions,
+        }
+    }
+
+    /// Call `f` with the keys that start with `prefix`, and their values.
+    ///
+    /// Calls `f` for at most `max_keys` keys, the first ones in order.
+    pub fn search_prefix_keys<F: FnMut(&str, Values)>(&self, prefix: &str, max_keys: usize, mut f: F) {
+        let min = self.find_lower(prefix);
+        let max = cmp::min(self.find_upper(prefix), min.saturating_add(max_keys));
+        let mut cursor = self.cursor_at(min);
+        for &values in &self.value_slices[min..max] {
+            f(cursor.next_str(), values);
         }
     }
 
@@ -293,58 +540,136 @@ impl<T> MemoryWordIndex<T> {
             value_data_bytes: self.value_data.len() * mem::size_of::<T>(),
             meta_data_bytes: self.meta_data.len() * mem::size_of::<WordMeta>(),
             slice_bytes:
-                self.key_slices.len() * mem::size_of::<Key>() +
+                self.key_buckets.len() * mem::size_of::<u32>() +
                 self.value_slices.len() * mem::size_of::<Values>(),
-            num_keys: self.key_slices.len(),
+            fuzzy_bytes: self.deletions.len() * mem::size_of::<(u32, u32)>(),
+            num_keys: self.value_slices.len(),
             num_values: self.value_data.len(),
         }
     }
 
+    /// Return a cursor that decodes the keys, starting at the given bucket.
+    fn cursor(&self, bucket: usize) -> KeyCursor<'_> {
+        KeyCursor {
+            data: &self.key_data,
+            pos: self.key_buckets[bucket] as usize,
+            key: Vec::new(),
+        }
+    }
 
-    fn get_key(&self, key: Key) -> &str {
-        &self.key_data[key.offset as usize..key.offset as usize + key.len as usize]
+    /// Return the first key of the given bucket, which is stored in full.
+    fn bucket_head(&self, bucket: usize) -> &[u8] {
+        // The first key in a bucket shares no prefix with the key before it,
+        // so we can take its bytes directly from the key data.
+        let mut pos = self.key_buckets[bucket] as usize;
+        let shared = read_varint(&self.key_data, &mut pos);
+        debug_assert_eq!(shared, Some(0));
+        let len = read_varint(&self.key_data, &mut pos).expect("Key data is validated.") as usize;
+        &self.key_data[pos..pos + len]
     }
 
-    /// Compare `prefix` to the same-length prefix of the `index`-th key.
-    fn cmp_prefix(&self, prefix: &str, index: usize) -> cmp::Ordering {
-        let key = self.get_key(self.key_slices[index]);
+    /// Return a cursor for which `next` returns the `index`-th key.
+    fn cursor_at(&self, index: usize) -> KeyCursor<'_> {
+        let mut cursor = KeyCursor { data: &self.key_data, pos: 0, key: Vec::new() };
+        self.seek(&mut cursor, index);
+        cursor
+    }
 
-        // Compare bytes, limiting the key if it is longer.
-        let n = cmp::min(prefix.len(), key.len());
-        prefix.as_bytes().cmp(&key.as_bytes()[..n])
+    /// Move the cursor such that `next` returns the `index`-th key.
+    ///
+    /// This reuses the key buffer of the cursor, so looking up many keys with
+    /// one cursor does not allocate.
+    fn seek(&self, cursor: &mut KeyCursor<'_>, index: usize) {
+        if index >= self.value_slices.len() {
+            cursor.pos = self.key_data.len();
+            return
+        }
+        cursor.pos = self.key_buckets[index / KEYS_PER_BUCKET] as usize;
+        for _ in 0..index % KEYS_PER_BUCKET {
+            cursor.next();
+        }
     }
 
-    /// Return the index of the first key that has the given prefix.
+    /// Return the number of buckets at the start for which `pred` is true.
     ///
-    /// If no key has the prefix, returns the index of the key before which to
-    /// insert to keep the order sorted.
-    fn find_lower(&self, prefix: &str) -> usize {
-        // Invariant: keys[min] <= prefix <= keys[max].
+    /// Like `slice::partition_point`, `pred` must be true for all bucket heads
+    /// before that point, and false for all heads after it.
+    fn partition_point_buckets<P: FnMut(&[u8]) -> bool>(&self, mut pred: P) -> usize {
         let mut min = 0;
-        let mut max = self.key_slices.len();
-        while max - min > 1 {
-            let i = (min + max) / 2;
-            match self.cmp_prefix(prefix, i) {
-                // The prefix goes before key i, we learned a tighter upper bound.
-                cmp::Ordering::Less => max = i,
-                // We are in the key range of the prefix. Because we look for
-                // the start of that range, we learned a tighter upper bound.
-                cmp::Ordering::Equal => max = i,
-                // The prefix goes after key i, we learned a tighter lower bound.
-                cmp::Ordering::Greater => min = i,
+        let mut max = self.key_buckets.len();
+        while min < max {
+            let b = (min + max) / 2;
+            match pred(self.bucket_head(b)) {
+                true => min = b + 1,
+                false => max = b,
             }
         }
+        min
+    }
 
-        // From the invariant alone, we can't tell if min or max is the answer,
-        // we need to check min itself too (max can't be checked, it may be out
-        // of bounds). If prefix would sort before the min, return 0 for the
-        // lower bound, not -1. The upper bound will also be 0, so the slice has
-        // length zero.
-        match self.cmp_prefix(prefix, min) {
-            cmp::Ordering::Less => 0,
-            cmp::Ordering::Equal => min,
-            cmp::Ordering::Greater => max,
+    /// Return the index of the first key for which `pred` is false.
+    ///
+    /// Like `slice::partition_point`, `pred` must be true for all keys before
+    /// that index, and false for all keys after it. We binary search the
+    /// bucket heads, and then scan the one bucket that can contain the index.
+    fn partition_point_keys<P: FnMut(&[u8]) -> bool>(&self, mut pred: P) -> usize {
+        // Bucket `bucket` starts after the partition point, so the point is in
+        // the bucket before it, or it is the start of bucket `bucket`.
+        let bucket = self.partition_point_buckets(&mut pred);
+        if bucket == 0 {
+            return 0
         }
+
+        let begin = (bucket - 1) * KEYS_PER_BUCKET;
+        let end = cmp::min(begin + KEYS_PER_BUCKET, self.value_slices.len());
+        let mut cursor = self.cursor(bucket - 1);
+        for i in begin..end {
+            if !pred(cursor.next()) {
+                return i
+            }
+        }
+        end
+    }
+
+    /// Return the index of the key equal to `word`, if there is one.
+    fn find_exact(&self, word: &str) -> Option<usize> {
+        let word = word.as_bytes();
+
+        // Only the last bucket that starts at or before the word can contain
+        // it, so we decode only that bucket, and only up to the word.
+        let bucket = self.partition_point_buckets(|head| head <= word);
+        if bucket == 0 {
+            return None
+        }
+
+        let begin = (bucket - 1) * KEYS_PER_BUCKET;
+        let end = cmp::min(begin + KEYS_PER_BUCKET, self.value_slices.len());
+        let mut cursor = self.cursor(bucket - 1);
+        for i in begin..end {
+            match cursor.next().cmp(word) {
+                cmp::Ordering::Less => continue,
+                cmp::Ordering::Equal => return Some(i),
+                cmp::Ordering::Greater => return None,
+            }
+        }
+        None
+    }
+
+    /// Push the indices of all keys that have a deletion variant with the given hash.
+    fn find_deletions(&self, hash: u32, into: &mut Vec<u32>) {
+        let begin = self.deletions.partition_point(|&(h, _)| h < hash);
+        for &(h, key_index) in &self.deletions[begin..] {
+            if h != hash { break }
+            into.push(key_index);
+        }
+    }
+
+    /// Return the index of the first key that has the given prefix.
+    ///
+    /// If no key has the prefix, returns the index of the key before which to
+    /// insert to keep the order sorted.
+    fn find_lower(&self, prefix: &str) -> usize {
+        self.partition_point_keys(|key| cmp_prefix(prefix, key) == cmp::Ordering::Greater)
     }
 
     /// Return the index of the first key after those with the given prefix.
@@ -352,39 +677,215 @@ impl<T> MemoryWordIndex<T> {
     /// If no key has the prefix, returns the index of the key before which to
     /// insert to keep the order sorted.
     fn find_upper(&self, prefix: &str) -> usize {
-        // Invariant: keys[min] < keys[max], prefix < keys[max].
-        let mut min = 0;
-        let mut max = self.key_slices.len();
-        while max - min > 1 {
-            let i = (min + max) / 2;
-            match self.cmp_prefix(prefix, i) {
-                // The prefix goes before key i, we learned a tighter upper bound.
-                cmp::Ordering::Less => max = i,
-                // We are in the key range of the prefix. Because we look for
-                // the end of that range, we learned a tighter lower bound.
-                cmp::Ordering::Equal => min = i,
-                // The prefix goes after key i, we learned a tighter lower bound.
-                cmp::Ordering::Greater => min = i,
-            }
+        self.partition_point_keys(|key| cmp_prefix(prefix, key) != cmp::Ordering::Less)
+    }
+}
+
+/// A value that can be stored in a serialized word index.
+pub trait SerializableValue: Copy {
+    fn to_u64(self) -> u64;
+    fn from_u64(x: u64) -> Self;
+}
+
+impl SerializableValue for ArtistId {
+    fn to_u64(self) -> u64 { self.0 }
+    fn from_u64(x: u64) -> Self { ArtistId(x) }
+}
+
+impl SerializableValue for AlbumId {
+    fn to_u64(self) -> u64 { self.0 }
+    fn from_u64(x: u64) -> Self { AlbumId(x) }
+}
+
+impl SerializableValue for TrackId {
+    fn to_u64(self) -> u64 { self.0 }
+    fn from_u64(x: u64) -> Self { TrackId(x) }
+}
+
+/// Version of the format that `MemoryWordIndex::to_bytes` writes.
+///
+/// Bump this when the format changes, or when the way we build the index
+/// changes, for example when word normalization changes, so we don't load an
+/// index that is stale.
+pub const SERIALIZATION_VERSION: u32 = 2;
+
+/// Reads little-endian integers from a byte slice, for deserialization.
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Some(head)
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Option<u32> {
+        let mut buf = [0_u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(buf))
+    }
+
+    pub(crate) fn read_u64(&mut self) -> Option<u64> {
+        let mut buf = [0_u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(buf))
+    }
+
+    pub(crate) fn read_len(&mut self) -> Option<usize> {
+        self.read_u32().map(|n| n as usize)
+    }
+
+    pub(crate) fn read_pairs(&mut self) -> Option<Vec<(u32, u32)>> {
+        let n = self.read_len()?;
+        // Check the length before allocating, the input could be garbage.
+        if self.bytes.len() < n * 8 {
+            return None
+        }
+        (0..n).map(|_| Some((self.read_u32()?, self.read_u32()?))).collect()
+    }
+}
+
+impl<T: SerializableValue> MemoryWordIndex<T> {
+    /// Serialize the index, so it can be loaded with `from_bytes` later.
+    ///
+    /// Integers are little-endian, every array is prefixed with its length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn push_u32(out: &mut Vec<u8>, x: u32) {
+            out.extend_from_slice(&x.to_le_bytes());
+        }
+
+        let mut out = Vec::new();
+
+        push_u32(&mut out, SERIALIZATION_VERSION);
+
+        push_u32(&mut out, self.key_data.len() as u32);
+        out.extend_from_slice(&self.key_data);
+
+        push_u32(&mut out, self.key_buckets.len() as u32);
+        for &offset in &self.key_buckets {
+            push_u32(&mut out, offset);
+        }
+
+        push_u32(&mut out, self.value_slices.len() as u32);
+        for values in &self.value_slices {
+            push_u32(&mut out, values.offset);
+            push_u32(&mut out, values.len);
+        }
+
+        push_u32(&mut out, self.value_data.len() as u32);
+        for (value, meta) in self.value_data.iter().zip(&self.meta_data) {
+            out.extend_from_slice(&value.to_u64().to_le_bytes());
+            push_u32(&mut out, meta.0);
+        }
+
+        push_u32(&mut out, self.deletions.len() as u32);
+        for &(hash, i) in &self.deletions {
+            push_u32(&mut out, hash);
+            push_u32(&mut out, i);
         }
 
-        // From the invariant alone, we can't tell if min or max is the answer,
-        // we need to check min itself too (max can't be checked, it may be out
-        // of bounds). If prefix would sort before the min, return 0 for the
-        // upper bound, so the slice has length zero.
-        match self.cmp_prefix(prefix, min) {
-            cmp::Ordering::Less => 0,
-            cmp::Ordering::Equal => max,
-            cmp::Ordering::Greater => max,
+        out
+    }
+
+    /// Deserialize an index that `to_bytes` wrote.
+    ///
+    /// Returns `None` if the data is not valid, or if it was written with a
+    /// different version of the format.
+    pub fn from_bytes(bytes: &[u8]) -> Option<MemoryWordIndex<T>> {
+        let mut r = Reader { bytes };
+
+        if r.read_u32()? != SERIALIZATION_VERSION {
+            return None
         }
+
+        let n = r.read_len()?;
+        let key_data = r.take(n)?.to_vec();
+
+        let n = r.read_len()?;
+        if r.bytes.len() < n * 4 {
+            return None
+        }
+        let key_buckets: Vec<u32> = (0..n).map(|_| r.read_u32()).collect::<Option<_>>()?;
+        let value_slices: Vec<Values> = r
+            .read_pairs()?
+            .into_iter()
+            .map(|(offset, len)| Values { offset, len })
+            .collect();
+
+        let n = r.read_len()?;
+        if r.bytes.len() < n * 12 {
+            return None
+        }
+        let mut value_data = Vec::with_capacity(n);
+        let mut meta_data = Vec::with_capacity(n);
+        for _ in 0..n {
+            value_data.push(T::from_u64(r.read_u64()?));
+            meta_data.push(WordMeta(r.read_u32()?));
+        }
+
+        let deletions = r.read_pairs()?;
+
+        // Validate the offsets, so that lookups cannot go out of bounds.
+        let keys_valid = validate_keys(&key_data, &key_buckets, value_slices.len());
+        let values_valid = value_slices.iter().all(|v| {
+            v.offset as usize + v.len as usize <= value_data.len()
+        });
+        let deletions_valid = deletions.iter().all(|&(_, i)| (i as usize) < value_slices.len());
+        let is_valid = r.bytes.is_empty()
+            && keys_valid
+            && values_valid
+            && deletions_valid
+            && value_data.len() == meta_data.len();
+        if !is_valid {
+            return None
+        }
+
+        let result = MemoryWordIndex {
+            key_data,
+            key_buckets,
+            value_slices,
+            value_data,
+            meta_data,
+            deletions,
+        };
+        Some(result)
     }
 }
 
+/// Check that the front-coded keys decode to `num_keys` UTF-8 strings.
+fn validate_keys(key_data: &[u8], key_buckets: &[u32], num_keys: usize) -> bool {
+    // There must be one bucket for every bucket head.
+    if key_buckets.len() != (0..num_keys).step_by(KEYS_PER_BUCKET).len() {
+        return false
+    }
+    let mut cursor = KeyCursor { data: key_data, pos: 0, key: Vec::new() };
+    for i in 0..num_keys {
+        // Bucket offsets must point at the keys, and those must be stored in
+        // full, otherwise lookups that start at the bucket would be wrong.
+        if i % KEYS_PER_BUCKET == 0 {
+            let mut pos = cursor.pos;
+            if key_buckets[i / KEYS_PER_BUCKET] as usize != pos || read_varint(key_data, &mut pos) != Some(0) {
+                return false
+            }
+        }
+        match cursor.try_next() {
+            Some(key) if std::str::from_utf8(key).is_ok() => continue,
+            _ => return false,
+        }
+    }
+    cursor.pos == key_data.len()
+}
+
 impl<T> WordIndex for MemoryWordIndex<T> {
     type Item = T;
 
     fn len(&self) -> usize {
-        self.key_slices.len()
+        self.value_slices.len()
     }
 
     fn get_values(&self, range: Values) -> &[T] {
@@ -404,15 +905,7 @@ impl<T> WordIndex for MemoryWordIndex<T> {
     }
 
     fn search_exact(&self, word: &str) -> Option<Values> {
-        let index = self.find_lower(word);
-
-        if index >= self.key_slices.len() { return None }
-        let key = self.get_key(self.key_slices[index]);
-
-        match key == word {
-            true  => Some(self.value_slices[index]),
-            false => None,
-        }
+        self.find_exact(word).map(|index| self.value_slices[index])
     }
 
     fn search_prefix(&self, prefix: &str) -> &[Values] {
@@ -420,16 +913,72 @@ impl<T> WordIndex for MemoryWordIndex<T> {
         let max = self.find_upper(prefix);
         &self.value_slices[min..max]
     }
+
+    fn search_fuzzy(&self, word: &str, max_distance: u32, into: &mut Vec<(Values, u32)>) {
+        if max_distance == 0 || word.chars().count() < FUZZY_MIN_CHARS {
+            return
+        }
+
+        // Collect the deletion variants of the query word. For a distance of
+        // 2, we also delete two characters, so we can find keys with two
+        // excess characters in the query.
+        let mut variants = vec![word.to_string()];
+        let mut frontier = 0;
+        for _ in 0..max_distance {
+            let previous = variants[frontier..].to_vec();
+            frontier = variants.len();
+            for w in &previous {
+                for (j, ch) in w.char_indices() {
+                    let mut v = String::with_capacity(w.len());
+                    v.push_str(&w[..j]);
+                    v.push_str(&w[j + ch.len_utf8()..]);
+                    variants.push(v);
+                }
+            }
+        }
+        variants.sort_unstable();
+        variants.dedup();
+
+        // A key is a candidate if it is equal to one of the variants (the query
+        // has excess characters), or if one of its deletion variants is (the
+        // query has a substituted, transposed, or missing character).
+        let mut candidates = Vec::new();
+        for v in &variants {
+            if let Some(index) = self.find_exact(v) {
+                candidates.push(index as u32);
+            }
+            self.find_deletions(hash_deletion(v, 0..0), &mut candidates);
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        // The hashes may collide, and not every candidate is within the
+        // maximum distance, so verify them all.
+        let mut cursor = self.cursor_at(0);
+        for index in candidates {
+            self.seek(&mut cursor, index as usize);
+            let distance = edit_distance(word, cursor.next_str());
+            if distance > 0 && distance <= max_distance {
+                into.push((self.value_slices[index as usize], distance));
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{MemoryWordIndex, Key, Values, WordIndex, WordMeta};
+    use super::{MemoryWordIndex, Values, WordIndex, WordMeta, edit_distance};
     use std::collections::BTreeSet;
 
     /// Dummy word metadata for use in these tests.
     const M0: WordMeta = WordMeta(0);
 
+    /// Decode the keys of the index, starting at the `index`-th key.
+    fn keys<T>(index: &MemoryWordIndex<T>, from: usize) -> Vec<String> {
+        let mut cursor = index.cursor_at(from);
+        (from..index.len()).map(|_| cursor.next_str().to_string()).collect()
+    }
+
     #[test]
     fn test_word_meta_fits_u32() {
         use std::mem;
@@ -445,12 +994,11 @@ mod test {
 
         let index = MemoryWordIndex::new(&elems);
 
-        assert_eq!(&index.key_data, "ABBC");
+        assert_eq!(&index.key_data, b"\x00\x01A\x00\x02BB\x00\x01C");
+        assert_eq!(&index.key_buckets, &[0]);
         assert_eq!(&index.value_data, &[2, 3, 5]);
 
-        assert_eq!(index.key_slices[0], Key { offset: 0, len: 1});
-        assert_eq!(index.key_slices[1], Key { offset: 1, len: 2});
-        assert_eq!(index.key_slices[2], Key { offset: 3, len: 1});
+        assert_eq!(keys(&index, 0), ["A", "BB", "C"]);
 
         assert_eq!(index.value_slices[0], Values { offset: 0, len: 1});
         assert_eq!(index.value_slices[1], Values { offset: 1, len: 1});
@@ -469,12 +1017,10 @@ mod test {
 
         let index = MemoryWordIndex::new(&elems);
 
-        assert_eq!(&index.key_data, "ABC");
+        assert_eq!(&index.key_data, b"\x00\x01A\x00\x01B\x00\x01C");
         assert_eq!(&index.value_data, &[2, 5, 2, 5, 7, 11]);
 
-        assert_eq!(index.key_slices[0], Key { offset: 0, len: 1});
-        assert_eq!(index.key_slices[1], Key { offset: 1, len: 1});
-        assert_eq!(index.key_slices[2], Key { offset: 2, len: 1});
+        assert_eq!(keys(&index, 0), ["A", "B", "C"]);
 
         assert_eq!(index.value_slices[0], Values { offset: 0, len: 2});
         assert_eq!(index.value_slices[1], Values { offset: 2, len: 3});
@@ -680,4 +1226,165 @@ mod test {
         assert_eq!(index.get_values(index.search_exact("as").unwrap()),  &[2]);
         assert_eq!(index.get_values(index.search_exact("the").unwrap()), &[4]);
     }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("", "ab"), 2);
+        assert_eq!(edit_distance("queen", "queen"), 0);
+        assert_eq!(edit_distance("queen", "quen"), 1);
+        assert_eq!(edit_distance("queen", "queens"), 1);
+        assert_eq!(edit_distance("queen", "qveen"), 1);
+        assert_eq!(edit_distance("radiohaed", "radiohead"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("björk", "bjork"), 1);
+    }
+
+    #[test]
+    fn test_search_fuzzy() {
+        let mut elems = BTreeSet::new();
+        elems.insert(("abba".to_string(),      1, M0));
+        elems.insert(("bear".to_string(),      2, M0));
+        elems.insert(("beat".to_string(),      3, M0));
+        elems.insert(("radiohead".to_string(), 4, M0));
+        elems.insert(("the".to_string(),       5, M0));
+
+        let index = MemoryWordIndex::new(&elems);
+
+        let search = |word: &str, max_distance: u32| {
+            let mut result = Vec::new();
+            index.search_fuzzy(word, max_distance, &mut result);
+            result
+                .iter()
+                .flat_map(|&(v, d)| index.get_values(v).iter().map(move |&x| (x, d)))
+                .collect::<Vec<_>>()
+        };
+
+        // Transposition, substitution, deletion, and insertion.
+        assert_eq!(search("radiohaed", 1), vec![(4, 1)]);
+        assert_eq!(search("radiohxad", 1), vec![(4, 1)]);
+        assert_eq!(search("radiohad", 1), vec![(4, 1)]);
+        assert_eq!(search("radioohead", 1), vec![(4, 1)]);
+
+        // Two edits need a larger maximum distance.
+        assert_eq!(search("raddiohaed", 1), vec![]);
+        assert_eq!(search("raddiohaed", 2), vec![(4, 2)]);
+        assert_eq!(search("radiohea", 2), vec![(4, 1)]);
+
+        // The exact match itself is not included.
+        assert_eq!(search("bear", 1), vec![(3, 1)]);
+        assert_eq!(search("beer", 1), vec![(2, 1)]);
+
+        // Short words do not match fuzzily.
+        assert_eq!(search("thx", 1), vec![]);
+        assert_eq!(search("abb", 1), vec![]);
+    }
+
+    #[test]
+    fn test_front_coded_keys_span_buckets() {
+        let mut elems = BTreeSet::new();
+        for i in 0..50 {
+            elems.insert((format!("word{:03}", i), i, M0));
+        }
+        // Shared prefixes that end halfway through a multibyte character.
+        elems.insert(("naïve".to_string(), 50, M0));
+        elems.insert(("naïvety".to_string(), 51, M0));
+        elems.insert(("naño".to_string(), 52, M0));
+
+        let index = MemoryWordIndex::new(&elems);
+        let words: Vec<String> = elems.iter().map(|e| e.0.clone()).collect();
+        assert_eq!(keys(&index, 0), words);
+        assert_eq!(index.cursor_at(17).next_str(), words[17]);
+        assert_eq!(index.key_buckets.len(), 4);
+        assert!(index.key_data.len() < words.iter().map(|w| w.len()).sum());
+
+        for (i, word) in words.iter().enumerate() {
+            assert_eq!(index.find_exact(word), Some(i));
+        }
+        assert_eq!(index.find_exact("word0"), None);
+        assert_eq!(index.find_exact("word999"), None);
+
+        let vs: Vec<_> = index
+            .search_prefix("word01")
+            .iter()
+            .flat_map(|&v| index.get_values(v).iter().cloned())
+            .collect();
+        assert_eq!(vs, (10..20).collect::<Vec<_>>());
+        assert_eq!(index.search_prefix("naï").len(), 2);
+        assert_eq!(index.search_prefix("zzz"), &[]);
+
+        let mut prefix_keys = Vec::new();
+        index.search_prefix_keys("word01", 3, |key, _| prefix_keys.push(key.to_string()));
+        assert_eq!(prefix_keys, ["word010", "word011", "word012"]);
+    }
+
+    #[test]
+    fn test_search_empty_index() {
+        use crate::prim::TrackId;
+
+        let index = MemoryWordIndex::<TrackId>::new(std::iter::empty());
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.search_prefix("a"), &[]);
+        assert_eq!(index.search_exact("a"), None);
+        assert!(MemoryWordIndex::<TrackId>::from_bytes(&index.to_bytes()).is_some());
+    }
+
+    #[test]
+    fn test_word_index_bytes_round_trip() {
+        use crate::prim::TrackId;
+
+        let mut elems = BTreeSet::new();
+        elems.insert(("beer".to_string(),      TrackId(2), M0));
+        elems.insert(("naïve".to_string(),     TrackId(3), M0));
+        elems.insert(("radiohead".to_string(), TrackId(4), M0));
+        elems.insert(("radiohead".to_string(), TrackId(1 << 40), M0));
+
+        let index = MemoryWordIndex::new(&elems);
+        let bytes = index.to_bytes();
+        let loaded = MemoryWordIndex::<TrackId>::from_bytes(&bytes).unwrap();
+
+        assert_eq!(loaded.key_data, index.key_data);
+        assert_eq!(loaded.key_buckets, index.key_buckets);
+        assert_eq!(loaded.value_slices, index.value_slices);
+        assert_eq!(loaded.value_data, index.value_data);
+        assert_eq!(loaded.deletions, index.deletions);
+
+        // Truncated or trailing data is rejected rather than loaded.
+        assert!(MemoryWordIndex::<TrackId>::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(MemoryWordIndex::<TrackId>::from_bytes(&longer).is_none());
+    }
+
+    #[test]
+    fn test_update_word_index_matches_rebuild() {
+        let meta = WordMeta::new(4, 10, 0, 2);
+
+        let mut elems = BTreeSet::new();
+        for &(word, value) in &[("beer", 1), ("blue", 2), ("blue", 3), ("moon", 3), ("whale", 4)] {
+            elems.insert((word.to_string(), value, meta));
+        }
+        let index = MemoryWordIndex::new(&elems);
+
+        // Replace value 3 with new entries, for an existing word, which
+        // changes its frequency, and for a word that was not in the index.
+        let mut updates = BTreeSet::new();
+        for v in 5..8 {
+            updates.insert(("blue".to_string(), v, meta));
+        }
+        updates.insert(("lemon".to_string(), 5, meta));
+        let updated = index.update(|&v| v == 3, &updates);
+
+        elems.retain(|&(_, v, _)| v != 3);
+        elems.extend(updates);
+        let rebuilt = MemoryWordIndex::new(&elems);
+
+        assert_eq!(keys(&updated, 0), ["beer", "blue", "lemon", "whale"]);
+        assert_eq!(updated.key_data, rebuilt.key_data);
+        assert_eq!(updated.key_buckets, rebuilt.key_buckets);
+        assert_eq!(updated.value_slices, rebuilt.value_slices);
+        assert_eq!(updated.value_data, rebuilt.value_data);
+        assert_eq!(updated.meta_data, rebuilt.meta_data);
+        assert_eq!(updated.deletions, rebuilt.deletions);
+    }
 }
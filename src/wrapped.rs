@@ -0,0 +1,202 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2024 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! The yearly listening report, a summary of the listens in a calendar year.
+//!
+//! Unlike the playcounts in [`crate::playcount`], these are plain counts, there
+//! is no decay or rate limiting: every completed listen counts as one.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use chrono::{Local, NaiveDate, TimeZone};
+
+use crate::config::Config;
+use crate::database::{self, Transaction};
+use crate::database_utils::connect_readonly;
+use crate::prim::{AlbumId, ArtistId, Instant, TrackId};
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// Summary of the listens in a calendar year, in local time.
+pub struct YearReport {
+    pub year: i32,
+
+    /// The number of completed listens.
+    pub n_listens: u64,
+
+    /// The sum of the durations of all listened tracks.
+    pub total_seconds: u64,
+
+    /// The day with the most listens, with its number of listens.
+    pub busiest_day: Option<(NaiveDate, u32)>,
+
+    /// The number of albums that we listened to for the first time this year.
+    pub n_new_albums: u32,
+
+    pub top_artists: Vec<(u32, ArtistId)>,
+    pub top_albums: Vec<(u32, AlbumId)>,
+    pub top_tracks: Vec<(u32, TrackId)>,
+}
+
+/// Return the POSIX timestamp of the start of the year in local time.
+fn year_start_second(year: i32) -> Option<i64> {
+    Local.ymd_opt(year, 1, 1).single().map(|d| d.and_hms(0, 0, 0).timestamp())
+}
+
+/// Return the `n` keys with the highest count, highest count first.
+fn top_n<K: Copy + Eq + Hash + Ord>(counts: HashMap<K, u32>, n: usize) -> Vec<(u32, K)> {
+    let mut ranked: Vec<(u32, K)> = counts.into_iter().map(|(k, count)| (count, k)).collect();
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    ranked.truncate(n);
+    ranked
+}
+
+impl YearReport {
+    /// Aggregate the listens in the given year, keeping the top `n_top` entries.
+    ///
+    /// Listens of tracks that are no longer in the library count towards the
+    /// totals, but not towards the top artists, albums, and tracks. Returns
+    /// `None` if the year cannot be represented.
+    pub fn compute(
+        index: &MemoryMetaIndex,
+        tx: &mut Transaction,
+        year: i32,
+        n_top: usize,
+    ) -> database::Result<Option<YearReport>> {
+        let (begin, end) = match (year_start_second(year), year_start_second(year + 1)) {
+            (Some(begin), Some(end)) => (begin, end),
+            _ => return Ok(None),
+        };
+
+        let mut n_listens = 0;
+        let mut total_seconds = 0;
+        let mut days: HashMap<NaiveDate, u32> = HashMap::new();
+        let mut artists: HashMap<ArtistId, u32> = HashMap::new();
+        let mut albums: HashMap<AlbumId, u32> = HashMap::new();
+        let mut tracks: HashMap<TrackId, u32> = HashMap::new();
+
+        for listen_opt in database::iter_listens_between(tx, begin, end)? {
+            let listen = listen_opt?;
+            n_listens += 1;
+            total_seconds += listen.duration_seconds.max(0) as u64;
+
+            let day = Local.timestamp(listen.started_at_second, 0).naive_local().date();
+            *days.entry(day).or_default() += 1;
+
+            let track_id = TrackId(listen.track_id as u64);
+            if index.get_track(track_id).is_some() {
+                *tracks.entry(track_id).or_default() += 1;
+            }
+
+            let album_id = AlbumId(listen.album_id as u64);
+            if let Some(album) = index.get_album(album_id) {
+                *albums.entry(album_id).or_default() += 1;
+                for artist_id in index.get_album_artists(album.artist_ids) {
+                    *artists.entry(*artist_id).or_default() += 1;
+                }
+            }
+        }
+
+        let mut n_new_albums = 0;
+        for row in database::iter_album_first_listens(tx)? {
+            let (_album_id, started_at_iso8601) = row?;
+            let is_new = match Instant::from_iso8601(&started_at_iso8601) {
+                Some(t) => begin <= t.posix_seconds_utc && t.posix_seconds_utc < end,
+                None => false,
+            };
+            if is_new {
+                n_new_albums += 1;
+            }
+        }
+
+        let busiest_day = top_n(days, 1).first().map(|&(n, day)| (day, n));
+
+        let result = YearReport {
+            year,
+            n_listens,
+            total_seconds,
+            busiest_day,
+            n_new_albums,
+            top_artists: top_n(artists, n_top),
+            top_albums: top_n(albums, n_top),
+            top_tracks: top_n(tracks, n_top),
+        };
+        Ok(Some(result))
+    }
+}
+
+/// Print the yearly listening report.
+pub fn main(index: &MemoryMetaIndex, config: &Config, year: i32) -> crate::Result<()> {
+    let conn = connect_readonly(&config.db_path, &config.db_pragmas)?;
+    let mut db = database::Connection::new(&conn);
+
+    let mut tx = db.begin()?;
+    let report = YearReport::compute(index, &mut tx, year, 25)?;
+    tx.commit()?;
+
+    let report = match report {
+        Some(r) => r,
+        None => {
+            println!("Cannot make a report for year {}.", year);
+            return Ok(())
+        }
+    };
+
+    println!("\nLISTENING REPORT {}\n", report.year);
+    println!("  Listens:     {}", report.n_listens);
+    println!("  Hours:       {:.1}", report.total_seconds as f32 / 3600.0);
+    println!("  New albums:  {}", report.n_new_albums);
+    if let Some((day, n)) = report.busiest_day {
+        println!("  Busiest day: {} ({} listens)", day, n);
+    }
+
+    println!("\nTOP ARTISTS\n");
+    for (i, (n, artist_id)) in report.top_artists.iter().enumerate() {
+        let artist = index.get_artist(*artist_id).unwrap();
+        println!("  {:2} {:4} {}", i + 1, n, index.get_string(artist.name));
+    }
+
+    println!("\nTOP ALBUMS\n");
+    for (i, (n, album_id)) in report.top_albums.iter().enumerate() {
+        let album = index.get_album(*album_id).unwrap();
+        println!(
+            "  {:2} {:4} {:25}  {}",
+            i + 1,
+            n,
+            index.get_string(album.title),
+            index.get_string(album.artist),
+        );
+    }
+
+    println!("\nTOP TRACKS\n");
+    for (i, (n, track_id)) in report.top_tracks.iter().enumerate() {
+        let track = index.get_track(*track_id).unwrap();
+        println!(
+            "  {:2} {:4} {:25}  {}",
+            i + 1,
+            n,
+            index.get_string(track.title),
+            index.get_string(track.artist),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::top_n;
+
+    #[test]
+    fn top_n_orders_by_count_then_key() {
+        let counts: HashMap<u32, u32> = [(1, 5), (2, 7), (3, 5), (4, 1)].iter().cloned().collect();
+        assert_eq!(top_n(counts.clone(), 3), vec![(7, 2), (5, 1), (5, 3)]);
+        assert_eq!(top_n(counts, 10).len(), 4);
+    }
+}
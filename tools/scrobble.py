@@ -17,6 +17,7 @@ Last.fm Usage
     scrobble.py lastfm scrobble musium.sqlite3
     scrobble.py lastfm import <full|incremental> musium.sqlite3 <username>
     scrobble.py lastfm sync musium.sqlite3 <username>
+    scrobble.py lastfm now-playing
 
 The following environment variables are expected to be set:
 
@@ -26,17 +27,32 @@ The following environment variables are expected to be set:
 
 You can create an API key and secret at https://www.last.fm/api/account/create.
 
+Besides listens, scrobble pushes loved-track changes from the ratings in
+Musium, and import fetches the loved tracks, which 'musium import musium.conf
+lastfm' then turns into ratings.
+
+The now-playing commands are meant to be called from the program configured as
+'exec_now_playing_path', they read the track from the MUSIUM_* environment
+variables that Musium sets for that program.
+
 
 Listenbrainz Usage
 ------------------
 
-    scrobble.py listenbrainz submit-listens musium.sqlite
+    scrobble.py listenbrainz submit-listens musium.conf
+    scrobble.py listenbrainz import musium.conf <username>
+    scrobble.py listenbrainz now-playing musium.conf
 
-The following environment variables are expected to be set:
+The database path and the Listenbrainz user token are read from the Musium
+config file, from the 'db_path' and 'listenbrainz_user_token' settings. When
+the token is not set in the config file, it is read from the environment:
 
     LISTENBRAINZ_USER_TOKEN  Listenbrainz user token
 
-You can obtain a user token at https://listenbrainz.org/profile/.
+You can obtain a user token at https://listenbrainz.org/settings/. Importing
+listening history does not need a token. The import only fills the staging
+table, run 'musium import musium.conf listenbrainz' afterwards to match the
+listens to tracks.
 
 """
 
@@ -55,7 +71,7 @@ from datetime import datetime, timedelta, timezone
 from enum import Enum
 from http.client import HTTPSConnection
 from typing import Any, Dict, Iterator, List, Optional, Union, Tuple, TypeVar
-from urllib.error import HTTPError
+from urllib.error import HTTPError, URLError
 from urllib.parse import urlencode
 from urllib.request import Request, urlopen
 
@@ -70,6 +86,9 @@ LISTENBRAINZ_USER_TOKEN = os.getenv("LISTENBRAINZ_USER_TOKEN", "")
 # anchor #listenbrainz.webserver.views.api_tools.MAX_LISTEN_SIZE.
 LISTENBRAINZ_MAX_BODY_BYTES = 10240
 
+# The maximum page size that the Listenbrainz listens endpoint allows.
+LISTENBRAINZ_MAX_LISTENS_PER_PAGE = 1000
+
 
 @dataclass(frozen=True)
 class Listen:
@@ -83,6 +102,9 @@ class Listen:
     duration_seconds: int
     track_number: int
     disc_number: int
+    # Musicbrainz ids from the tags of the file, if we still have the file.
+    recording_mbid: Optional[str]
+    release_mbid: Optional[str]
 
     def __post_init__(self) -> None:
         assert self.started_at.tzinfo is not None
@@ -116,20 +138,21 @@ class Listen:
         Format as a dict that can be submitted as json to the Listenbrainz API.
         See also https://listenbrainz.readthedocs.io/en/production/dev/json/#json-doc.
         """
+        additional_info: Dict[str, Any] = {
+            "listening_from": "Musium",
+            "tracknumber": self.track_number,
+        }
+        # TODO: Also include artist_mbids, track_mbid, and ISRC, once we store
+        # the tags for those.
+        if self.recording_mbid is not None:
+            additional_info["recording_mbid"] = self.recording_mbid
+        if self.release_mbid is not None:
+            additional_info["release_mbid"] = self.release_mbid
+
         return {
             "listened_at": int(self.started_at.timestamp()),
             "track_metadata": {
-                "additional_info": {
-                    "listening_from": "Musium",
-                    "tracknumber": self.track_number,
-                    # TODO: Include Musicbrainz ids, once we track those in the
-                    # listens database. In particular:
-                    # * artist_mbids
-                    # * release_mbid
-                    # * recording_mbid
-                    # * track_mbid
-                    # * ISRC
-                },
+                "additional_info": additional_info,
                 "artist_name": self.track_artist,
                 "track_name": self.track_title,
                 "release_name": self.album_title,
@@ -137,19 +160,31 @@ class Listen:
         }
 
 
+class Service(Enum):
+    """
+    A service to submit listens to. The value is the column in the listens
+    table that records when we submitted the listen to that service.
+    """
+
+    LASTFM = "scrobbled_at"
+    LISTENBRAINZ = "listenbrainz_submitted_at"
+
+
 def get_listens_to_scrobble(
     connection: sqlite3.Connection,
+    service: Service,
     *,
     since: Optional[datetime] = None,
 ) -> Iterator[Listen]:
     """
-    Iterate unscrobbled listens that are eligible for scrobbling. When 'since'
-    is set, we select only listens that happened within after that instant.
-    This is needed for Last.fm, which does not allow backdating scrobbles further.
+    Iterate listens not yet submitted to the service that are eligible for
+    submission. When 'since' is set, we select only listens that happened within
+    after that instant. This is needed for Last.fm, which does not allow
+    backdating scrobbles further.
     """
     assert since is None or since.tzinfo is not None, "since must have tzinfo"
 
-    common = """
+    common = f"""
         select
           id,
           started_at,
@@ -160,12 +195,20 @@ def get_listens_to_scrobble(
           album_artist,
           duration_seconds,
           track_number,
-          disc_number
+          disc_number,
+          (
+            select value from tags
+            where tags.file_id = listens.file_id and field_name = 'musicbrainz_trackid'
+          ),
+          (
+            select value from tags
+            where tags.file_id = listens.file_id and field_name = 'musicbrainz_albumid'
+          )
         from
           listens
         where
           -- Select all listens originating from us that still need to be scrobbled.
-          scrobbled_at is null
+          {service.value} is null
           and source = 'musium'
 
           -- Last.fm guidelines say to only scrobble after playing for at least
@@ -199,18 +242,19 @@ def get_listens_to_scrobble(
 
 def set_scrobbled(
     connection: sqlite3.Connection,
+    service: Service,
     now: datetime,
     row_ids: List[int],
 ) -> None:
     """
-    Update the rows to set scrobbled_at.
+    Update the rows to record that we submitted them to the service.
     """
     assert now.tzinfo is not None
     now_str = now.isoformat()
     params = [(now_str, row_id) for row_id in row_ids]
     connection.executemany(
-        """
-        update listens set scrobbled_at = ? where id = ?;
+        f"""
+        update listens set {service.value} = ? where id = ?;
         """,
         params,
     )
@@ -237,6 +281,80 @@ def iter_chunks(events: Iterator[T], n: int) -> Iterator[List[T]]:
         yield result
 
 
+def urlopen_with_retry(req: Request, max_attempts: int = 6) -> Any:
+    """
+    Like urlopen, but retry with exponential backoff when the request fails in a
+    way that is likely temporary: the network is down, or the server is
+    overloaded. Errors that will not go away by retrying are raised immediately.
+
+    Listens that we fail to submit remain unsubmitted in the database, so after
+    the last attempt we give up, and the next run of the script retries them.
+    """
+    sleep_seconds = 2.0
+    for attempt in range(1, max_attempts + 1):
+        try:
+            return urlopen(req)
+        except HTTPError as err:
+            is_temporary = err.code == 429 or err.code >= 500
+            if not is_temporary or attempt == max_attempts:
+                raise
+            print(f"Got status {err.code}, retrying in {sleep_seconds:.0f}s.")
+        except URLError as err:
+            if attempt == max_attempts:
+                raise
+            print(f"Request failed ({err.reason}), retrying in {sleep_seconds:.0f}s.")
+
+        # Flush so the message shows up in the journal before we sleep.
+        sys.stdout.flush()
+        time.sleep(sleep_seconds)
+        sleep_seconds *= 2.0
+
+    assert False, "Unreachable, the last attempt returns or raises."
+
+
+def get_now_playing_listen(connection: Optional[sqlite3.Connection]) -> Listen:
+    """
+    Read the track that just started playing from the environment variables
+    that Musium sets for the now-playing program. When we have a database
+    connection, include the Musicbrainz ids from the file's tags.
+    """
+    now = datetime.now(tz=timezone.utc)
+    file_id = int(os.environ["MUSIUM_FILE_ID"])
+    recording_mbid: Optional[str] = None
+    release_mbid: Optional[str] = None
+
+    if connection is not None:
+        recording_mbid, release_mbid = connection.execute(
+            """
+            select
+              (
+                select value from tags
+                where file_id = ? and field_name = 'musicbrainz_trackid'
+              ),
+              (
+                select value from tags
+                where file_id = ? and field_name = 'musicbrainz_albumid'
+              );
+            """,
+            (file_id, file_id),
+        ).fetchone()
+
+    return Listen(
+        id=0,
+        started_at=now,
+        completed_at=now,
+        track_title=os.environ["MUSIUM_TRACK_TITLE"],
+        album_title=os.environ["MUSIUM_ALBUM_TITLE"],
+        track_artist=os.environ["MUSIUM_TRACK_ARTIST"],
+        album_artist=os.environ["MUSIUM_ALBUM_ARTIST"],
+        duration_seconds=int(os.environ["MUSIUM_DURATION_SECONDS"]),
+        track_number=int(os.environ["MUSIUM_TRACK_NUMBER"]),
+        disc_number=0,
+        recording_mbid=recording_mbid,
+        release_mbid=release_mbid,
+    )
+
+
 def format_batch_request_last_fm(listens: List[Listen]) -> Request:
     """
     Format a POST request to scrobble the given listens to Last.fm.
@@ -301,12 +419,16 @@ def cmd_scrobble(db_file: str) -> None:
     with sqlite3.connect(db_file) as connection:
         # Last.fm allows submitting scrobbles up to 14 days after their timestamp.
         # Any later, there is no point in submitting the scrobble any more.
-        listens = get_listens_to_scrobble(connection, since=now - timedelta(days=14))
+        listens = get_listens_to_scrobble(
+            connection,
+            Service.LASTFM,
+            since=now - timedelta(days=14),
+        )
 
         # Last.fm allows submitting batches of at most 50 scrobbles at once.
         for batch in iter_chunks(listens, n=50):
             req = format_batch_request_last_fm(batch)
-            response = json.load(urlopen(req))
+            response = json.load(urlopen_with_retry(req))
 
             num_accepted = response["scrobbles"]["@attr"]["accepted"]
             ids_accepted = []
@@ -336,13 +458,145 @@ def cmd_scrobble(db_file: str) -> None:
                     )
 
             # Store that these listens have been scrobbled now.
-            set_scrobbled(connection, now, ids_accepted)
+            set_scrobbled(connection, Service.LASTFM, now, ids_accepted)
 
             assert len(ids_accepted) == num_accepted
             # Flush, even when stdout is not a terminal, such as when running
             # under systemd, so we get accurate timestamps in the journal.
             print(f"Scrobbled {num_accepted} listens.", flush=True)
 
+        push_lastfm_loved_tracks(connection)
+
+
+def get_loved_changes(connection: sqlite3.Connection) -> List[Tuple[int, bool, str, str]]:
+    """
+    Return the tracks where the latest rating disagrees with the loved state
+    that Last.fm has as far as we know, as (track id, loved, title, artist).
+    A track is loved when its latest rating is 2, the "love" rating. We take
+    the title and artist from the most recent listen, or from the tags when
+    there is no listen.
+    """
+    results = connection.execute(
+        """
+        with latest_ratings as (
+          -- SQLite takes the bare column from the row where the max occurs.
+          select track_id, rating, max(cast(strftime('%s', created_at) as integer))
+          from ratings
+          group by track_id
+        ),
+        changes as (
+          select
+            latest_ratings.track_id as track_id,
+            latest_ratings.rating = 2 as loved
+          from
+            latest_ratings
+            left join lastfm_loved_sync using (track_id)
+          where
+            (latest_ratings.rating = 2) != coalesce(lastfm_loved_sync.loved, 0)
+        )
+        select
+          track_id,
+          loved,
+          coalesce(
+            (
+              select track_title from listens
+              where listens.track_id = changes.track_id
+              order by id desc limit 1
+            ),
+            (
+              select value from tags, track_loudness
+              where track_loudness.track_id = changes.track_id
+                and tags.file_id = track_loudness.file_id
+                and field_name = 'title'
+            )
+          ),
+          coalesce(
+            (
+              select track_artist from listens
+              where listens.track_id = changes.track_id
+              order by id desc limit 1
+            ),
+            (
+              select value from tags, track_loudness
+              where track_loudness.track_id = changes.track_id
+                and tags.file_id = track_loudness.file_id
+                and field_name = 'artist'
+            )
+          )
+        from
+          changes;
+        """
+    )
+    return [
+        (track_id, bool(loved), title, artist)
+        for track_id, loved, title, artist in results
+        if title is not None and artist is not None
+    ]
+
+
+def push_lastfm_loved_tracks(connection: sqlite3.Connection) -> None:
+    """
+    Love tracks on Last.fm that we rated as loved, and unlove tracks whose
+    rating is no longer loved, for the rating changes since the last push.
+    """
+    if LAST_FM_SESSION_KEY == "":
+        print("LAST_FM_SESSION_KEY is not set, not pushing loved tracks.")
+        return
+
+    n_pushed = 0
+
+    for track_id, loved, title, artist in get_loved_changes(connection):
+        params = {
+            "method": "track.love" if loved else "track.unlove",
+            "sk": LAST_FM_SESSION_KEY,
+            "track": title,
+            "artist": artist,
+        }
+        req = format_signed_request(http_method="POST", data=params)
+        response = json.load(urlopen_with_retry(req))
+
+        if "error" in response:
+            print(f"ERROR: Last.fm rejected {params['method']} of {title}:", response)
+            continue
+
+        now = datetime.now(tz=timezone.utc).isoformat()
+        connection.execute(
+            """
+            insert or replace into lastfm_loved_sync (track_id, loved, synced_at)
+            values (?, ?, ?);
+            """,
+            (track_id, int(loved), now),
+        )
+        connection.commit()
+        n_pushed += 1
+
+    print(f"Pushed {n_pushed} loved track changes.", flush=True)
+
+
+def cmd_lastfm_now_playing() -> None:
+    listen = get_now_playing_listen(connection=None)
+
+    # The now-playing update takes the same parameters as a scrobble, except
+    # that they are not indexed, and there is no timestamp.
+    params = {
+        "method": "track.updateNowPlaying",
+        "sk": LAST_FM_SESSION_KEY,
+        "artist": listen.track_artist,
+        "track": listen.track_title,
+        "album": listen.album_title,
+        "albumArtist": listen.album_artist,
+        "trackNumber": str(listen.track_number),
+        "duration": str(listen.duration_seconds),
+    }
+
+    # A now-playing update is only relevant while the track plays, so we do
+    # not retry, if it fails we just skip the update.
+    try:
+        urlopen(format_signed_request(http_method="POST", data=params))
+        print(f"Updated now playing to {listen.track_title}.")
+    except URLError as err:
+        print(f"Failed to update now playing: {err}.")
+
 
 def cmd_authenticate() -> None:
     req = format_signed_request(
@@ -454,6 +708,110 @@ def import_lastfm_page(
     )
 
 
+def import_lastfm_loved_tracks(
+    client: HTTPSConnection,
+    db_conn: sqlite3.Connection,
+    username: str,
+) -> None:
+    """
+    Replace the contents of the lastfm_loved_tracks table with the tracks that
+    the user currently loves on Last.fm. Musium matches them to tracks in
+    'musium import'. When fetching fails, we leave the table untouched.
+    """
+    page = 1
+    n_errors = 0
+    loved: List[Tuple[str, str, int]] = []
+
+    while True:
+        params = {
+            "method": "user.getLovedTracks",
+            "user": username,
+            "page": page,
+            "limit": 200,
+            "api_key": LAST_FM_API_KEY,
+            "format": "json",
+        }
+        params_str = urlencode(params, quote_via=urllib.parse.quote, safe="")
+        client.request("GET", "/2.0/?" + params_str)
+        response = client.getresponse()
+
+        if response.status != 200:
+            print(f"Got {response.status}:", response.read())
+            n_errors += 1
+            assert n_errors < 10, "Bailing after 10 consecutive errors."
+            time.sleep(5.0)
+            continue
+
+        n_errors = 0
+        data = json.load(response)["lovedtracks"]
+
+        # As for scrobbles, a single track is not wrapped in a list.
+        tracks = data["track"]
+        if not isinstance(tracks, list):
+            tracks = [tracks]
+
+        for track in tracks:
+            loved.append(
+                (
+                    fix_misencodings(track["name"]),
+                    fix_misencodings(track["artist"]["name"]),
+                    int(track["date"]["uts"]),
+                )
+            )
+
+        if page >= int(data["@attr"]["totalPages"]):
+            break
+
+        page += 1
+
+    tx = db_conn.cursor()
+    tx.execute("delete from lastfm_loved_tracks;")
+    tx.executemany(
+        """
+        insert into lastfm_loved_tracks (title, track_artist, loved_at)
+        values (?, ?, ?)
+        on conflict do nothing;
+        """,
+        loved,
+    )
+    db_conn.commit()
+    print(f"Imported {len(loved):,} loved tracks.")
+
+
+def get_imported_until(tx: sqlite3.Cursor, source: str) -> Optional[int]:
+    """
+    Return the timestamp up to which we fetched the full history from the given
+    source, or None if we never completed an import from it.
+    """
+    row = tx.execute(
+        "select imported_until from import_progress where source = ?;",
+        (source,),
+    ).fetchone()
+    return None if row is None else int(row[0])
+
+
+def set_imported_until(tx: sqlite3.Cursor, source: str, table: str) -> None:
+    """
+    Record that we have the full history from the given source, up to the newest
+    listen in its table. Call this only after the import completed.
+    """
+    (newest_listen,) = tx.execute(f"select max(started_at) from {table};").fetchone()
+    if newest_listen is None:
+        return
+
+    now = datetime.now(tz=timezone.utc).isoformat()
+    tx.execute(
+        """
+        insert into import_progress (source, imported_until, updated_at)
+        values (?, ?, ?)
+        on conflict (source) do update set
+          imported_until = max(imported_until, excluded.imported_until),
+          updated_at = excluded.updated_at;
+        """,
+        (source, newest_listen, now),
+    )
+
+
 def cmd_lastfm_import(is_full: bool, db_file: str, username: str) -> None:
     now = datetime.now(tz=timezone.utc)
 
@@ -473,8 +831,9 @@ def cmd_lastfm_import(is_full: bool, db_file: str, username: str) -> None:
         # never done if we always did a full import. (Maybe Last.fm allows
         # duplicates on a timestamp?) For an incremental import, we take the
         # past 2 weeks (because that is how long you can still submit to Last.fm
-        # plus some slack), or if the most recent listen we have is older, then
-        # we use that as the lower bound.
+        # plus some slack), or if the last completed import is older, then we
+        # use that as the lower bound. Databases from before we tracked import
+        # progress fall back to the most recent listen we have.
         after_timestamp: Optional[datetime]
         if is_full:
             after_timestamp = None
@@ -482,14 +841,16 @@ def cmd_lastfm_import(is_full: bool, db_file: str, username: str) -> None:
         else:
             after_timestamp = now - timedelta(days=16)
             tx = db_conn.cursor()
-            (newest_listen,) = tx.execute(
-                "select max(started_at) from lastfm_listens;"
-            ).fetchone()
+            imported_until = get_imported_until(tx, "lastfm")
+            if imported_until is None:
+                (imported_until,) = tx.execute(
+                    "select max(started_at) from lastfm_listens;"
+                ).fetchone()
             db_conn.commit()
-            if newest_listen is not None:
+            if imported_until is not None:
                 after_timestamp = min(
                     after_timestamp,
-                    datetime.fromtimestamp(newest_listen, tz=timezone.utc),
+                    datetime.fromtimestamp(imported_until, tz=timezone.utc),
                 )
                 print(
                     f"Doing an incremental import of listens as of {after_timestamp}."
@@ -542,8 +903,17 @@ def cmd_lastfm_import(is_full: bool, db_file: str, username: str) -> None:
 
             page += 1
 
+        tx = db_conn.cursor()
+        set_imported_until(tx, "lastfm", "lastfm_listens")
+        db_conn.commit()
+
+        import_lastfm_loved_tracks(client, db_conn, username)
 
-def format_batch_request_listenbrainz(listens: List[Listen]) -> Optional[Request]:
+
+def format_batch_request_listenbrainz(
+    listens: List[Listen],
+    user_token: str,
+) -> Optional[Request]:
     """
     Format a POST request to submit the given listens to Listenbrainz.
 
@@ -563,7 +933,7 @@ def format_batch_request_listenbrainz(listens: List[Listen]) -> Optional[Request
         url="https://api.listenbrainz.org/1/submit-listens",
         method="POST",
         headers={
-            "Authorization": f"Token {LISTENBRAINZ_USER_TOKEN}",
+            "Authorization": f"Token {user_token}",
             "Content-Type": "application/json; charset=utf-8",
         },
         data=body_bytes,
@@ -578,15 +948,16 @@ class ListenbrainzBatch:
 
 def iter_requests_listenbrainz(
     listens: Iterator[Listen],
+    user_token: str,
 ) -> Iterator[ListenbrainzBatch]:
     """
     Break up the stream of listens into submission requests.
     """
-    # At the time of writing (when listens do not include Musicbrainz
-    # identifiers), sizes of individual listens are around 190-240 bytes.
-    # So as a first guess, we are going to create batches that are expected to
-    # fit in one request, assuming 215 bytes per listen.
-    listens_per_batch = LISTENBRAINZ_MAX_BODY_BYTES // 215
+    # Without Musicbrainz identifiers, sizes of individual listens are around
+    # 190-240 bytes, the two identifiers add about 100 bytes. So as a first
+    # guess, we are going to create batches that are expected to fit in one
+    # request, assuming 315 bytes per listen.
+    listens_per_batch = LISTENBRAINZ_MAX_BODY_BYTES // 315
 
     batches = iter_chunks(listens, n=listens_per_batch)
     listens_remaining: List[Listen] = []
@@ -613,7 +984,7 @@ def iter_requests_listenbrainz(
         # Slice out a batch of size n from the buffer.
         batch = listens_remaining[:n]
         listens_remaining = listens_remaining[n:]
-        request = format_batch_request_listenbrainz(batch)
+        request = format_batch_request_listenbrainz(batch, user_token)
 
         if request is not None:
             assert len(batch) > 0
@@ -627,21 +998,63 @@ def iter_requests_listenbrainz(
             n = n - 1
 
 
-def cmd_submit_listens(db_file: str) -> None:
+def read_config(config_file: str) -> Dict[str, str]:
+    """
+    Read the key-value pairs from a Musium config file, in either the TOML or
+    the old format, and apply MUSIUM_* environment overrides. Keys are returned
+    by their name in the old format.
+    See also docs/configuration.md.
+    """
+    toml_keys = {
+        "database.path": "db_path",
+        "listenbrainz.user_token": "listenbrainz_user_token",
+    }
+    result = {}
+    section = ""
+    with open(config_file, "r", encoding="utf-8") as f:
+        for line in f:
+            line = line.strip()
+            if line == "" or line.startswith("#"):
+                continue
+            if line.startswith("["):
+                section = line.split("]")[0].strip("[ ") + "."
+                continue
+            key, _, value = line.partition("=")
+            key = section + key.strip()
+            value = value.strip()
+            # TOML strings are quoted, values in the old format never are.
+            if value[:1] in ('"', "'"):
+                value = value[1 : value.index(value[0], 1)]
+            result[toml_keys.get(key, key)] = value
+
+    # Like Musium itself, allow overriding settings in the environment.
+    for key in toml_keys.values():
+        env_value = os.getenv("MUSIUM_" + key.upper())
+        if env_value is not None:
+            result[key] = env_value
+
+    return result
+
+
+def cmd_submit_listens(config_file: str) -> None:
     now = datetime.now(tz=timezone.utc)
 
-    if LISTENBRAINZ_USER_TOKEN == "":
-        print("LISTENBRAINZ_USER_TOKEN is not set, authorization will fail.")
+    config = read_config(config_file)
+    db_file = config["db_path"]
+    user_token = config.get("listenbrainz_user_token", LISTENBRAINZ_USER_TOKEN)
+
+    if user_token == "":
+        print("listenbrainz_user_token is not set, authorization will fail.")
 
     with sqlite3.connect(db_file) as connection:
-        listens = get_listens_to_scrobble(connection)
+        listens = get_listens_to_scrobble(connection, Service.LISTENBRAINZ)
 
-        for batch in iter_requests_listenbrainz(listens):
+        for batch in iter_requests_listenbrainz(listens, user_token):
             try:
-                response = urlopen(batch.request)
+                response = urlopen_with_retry(batch.request)
                 assert response.status == 200
                 ids_accepted = [listen.id for listen in batch.listens]
-                set_scrobbled(connection, now, ids_accepted)
+                set_scrobbled(connection, Service.LISTENBRAINZ, now, ids_accepted)
                 # Flush, even when stdout is not a terminal, such as when running
                 # under systemd, so we get accurate timestamps in the journal.
                 print(f"Submitted {len(batch.listens)} listens.", flush=True)
@@ -665,6 +1078,155 @@ def cmd_submit_listens(db_file: str) -> None:
                 sys.exit(1)
 
 
+def import_listenbrainz_page(
+    tx: sqlite3.Cursor,
+    username: str,
+    min_ts: int,
+    max_ts: Optional[int],
+) -> Optional[int]:
+    """
+    Import one page of listens from Listenbrainz, newest first, with
+    `min_ts < listened_at < max_ts`. Returns the timestamp of the oldest listen
+    in the page, or None if there are no more listens to import.
+    """
+    params: Dict[str, Any] = {"count": LISTENBRAINZ_MAX_LISTENS_PER_PAGE}
+    if min_ts > 0:
+        params["min_ts"] = min_ts
+    if max_ts is not None:
+        params["max_ts"] = max_ts
+
+    url = (
+        "https://api.listenbrainz.org/1/user/"
+        + urllib.parse.quote(username, safe="")
+        + "/listens?"
+        + urlencode(params)
+    )
+    response = urlopen_with_retry(Request(url))
+    listens = json.load(response)["payload"]["listens"]
+
+    # Try to avoid exceeding the rate limit, like for submitting.
+    if int(response.headers.get("X-RateLimit-Remaining", "10")) <= 1:
+        time.sleep(float(response.headers.get("X-RateLimit-Reset-In", "1")))
+
+    if len(listens) == 0:
+        return None
+
+    for listen in listens:
+        meta = listen["track_metadata"]
+        info = meta.get("additional_info") or {}
+        mapping = meta.get("mbid_mapping") or {}
+        # Prefer the release that the listen was submitted with, and fall back
+        # to the one that Listenbrainz mapped the listen to.
+        album_mbid = info.get("release_mbid") or mapping.get("release_mbid") or ""
+        # Players submit the duration in one of two fields, if at all.
+        duration_seconds: Optional[int] = None
+        if info.get("duration_ms") is not None:
+            duration_seconds = int(info["duration_ms"]) // 1000
+        elif info.get("duration") is not None:
+            duration_seconds = int(info["duration"])
+        tx.execute(
+            """
+            insert into listenbrainz_listens
+              (started_at, title, track_artist, album, album_mbid, duration_seconds)
+            values
+              (?, ?, ?, ?, ?, ?)
+            on conflict
+              do nothing;
+            """,
+            (
+                int(listen["listened_at"]),
+                meta["track_name"],
+                meta["artist_name"],
+                meta.get("release_name") or "",
+                album_mbid,
+                duration_seconds,
+            ),
+        )
+
+    return min(int(listen["listened_at"]) for listen in listens)
+
+
+def cmd_listenbrainz_import(config_file: str, username: str) -> None:
+    config = read_config(config_file)
+    db_file = config["db_path"]
+
+    with sqlite3.connect(db_file) as db_conn:
+        # The import is incremental: we only fetch listens newer than the last
+        # completed import. We fetch newest first, so if an import gets
+        # interrupted, the newest listen we have is not a safe lower bound.
+        # The first import is a full import.
+        tx = db_conn.cursor()
+        imported_until = get_imported_until(tx, "listenbrainz")
+        db_conn.commit()
+
+        min_ts = imported_until if imported_until is not None else 0
+        if min_ts > 0:
+            since = datetime.fromtimestamp(min_ts, tz=timezone.utc)
+            print(f"Doing an incremental import of listens after {since}.")
+        else:
+            print("Doing a full import.")
+
+        # Import one page at a time, starting with the most recent listens,
+        # and continue below the oldest listen of the previous page.
+        max_ts: Optional[int] = None
+        n_pages = 0
+        while True:
+            tx = db_conn.cursor()
+            try:
+                max_ts = import_listenbrainz_page(tx, username, min_ts, max_ts)
+            except HTTPError as err:
+                db_conn.rollback()
+                print(f"Unexpected response, status {err.status}.")
+                sys.exit(1)
+
+            db_conn.commit()
+            if max_ts is None:
+                break
+
+            n_pages += 1
+            oldest = datetime.fromtimestamp(max_ts, tz=timezone.utc)
+            print(f"[{n_pages}] Imported listens back to {oldest}.", flush=True)
+
+        tx = db_conn.cursor()
+        set_imported_until(tx, "listenbrainz", "listenbrainz_listens")
+        db_conn.commit()
+
+        (n_have,) = db_conn.execute(
+            "select count(1) from listenbrainz_listens;"
+        ).fetchone()
+        print(f"Import is complete, database has {n_have:,} Listenbrainz listens.")
+
+
+def cmd_listenbrainz_now_playing(config_file: str) -> None:
+    config = read_config(config_file)
+    user_token = config.get("listenbrainz_user_token", LISTENBRAINZ_USER_TOKEN)
+
+    with sqlite3.connect(config["db_path"]) as connection:
+        listen = get_now_playing_listen(connection)
+
+    payload = listen.format_listenbrainz_listen()
+    # A playing-now listen must not have a timestamp.
+    del payload["listened_at"]
+    body_dict = {"listen_type": "playing_now", "payload": [payload]}
+
+    req = Request(
+        url="https://api.listenbrainz.org/1/submit-listens",
+        method="POST",
+        headers={
+            "Authorization": f"Token {user_token}",
+            "Content-Type": "application/json; charset=utf-8",
+        },
+        data=json.dumps(body_dict).encode("utf-8"),
+    )
+
+    # Like for Last.fm, we do not retry now-playing updates.
+    try:
+        urlopen(req)
+        print(f"Updated playing now to {listen.track_title}.")
+    except URLError as err:
+        print(f"Failed to update playing now: {err}.")
+
+
 if __name__ == "__main__":
     command = []
     if len(sys.argv) > 2:
@@ -686,9 +1248,18 @@ if __name__ == "__main__":
         cmd_scrobble(sys.argv[3])
         cmd_lastfm_import(is_full=False, db_file=sys.argv[3], username=sys.argv[4])
 
+    elif command == ["lastfm", "now-playing"] and len(sys.argv) == 3:
+        cmd_lastfm_now_playing()
+
     elif command == ["listenbrainz", "submit-listens"] and len(sys.argv) == 4:
         cmd_submit_listens(sys.argv[3])
 
+    elif command == ["listenbrainz", "now-playing"] and len(sys.argv) == 4:
+        cmd_listenbrainz_now_playing(sys.argv[3])
+
+    elif command == ["listenbrainz", "import"] and len(sys.argv) == 5:
+        cmd_listenbrainz_import(sys.argv[3], username=sys.argv[4])
+
     else:
         print(__doc__)
         sys.exit(1)